@@ -0,0 +1,331 @@
+use crate::common::{Span, Symbol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ident {
+    pub name: Symbol,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub decls: Vec<Decl>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decl {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: DeclKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeclKind {
+    Const(ConstDecl),
+    Type(TypeDecl),
+    Struct(StructDecl),
+    Func(FuncDecl),
+    Instance(InstanceDecl),
+    Import(ImportDecl),
+    Export(ExportDecl),
+    Expr(Expr),
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstDecl {
+    pub name: Ident,
+    pub ty: Option<TypeExpr>,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeDecl {
+    pub name: Ident,
+    pub params: Vec<Ident>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub id: NodeId,
+    pub name: Ident,
+    pub fields: Vec<TypeExpr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructDecl {
+    pub name: Ident,
+    pub params: Vec<Ident>,
+    pub fields: Vec<FieldDecl>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDecl {
+    pub name: Ident,
+    pub ty: TypeExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncDecl {
+    pub name: Ident,
+    pub params: Vec<Param>,
+    pub ret: Option<TypeExpr>,
+    pub body: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub id: NodeId,
+    pub pattern: Pattern,
+    pub ty: Option<TypeExpr>,
+}
+
+// `instance Num Vector`, the methods of a builtin trait for a user type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceDecl {
+    pub trait_name: Ident,
+    pub ty: TypeExpr,
+    pub methods: Vec<FuncDecl>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportDecl {
+    pub path: Vec<Ident>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportDecl {
+    pub names: Vec<Ident>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeExpr {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: TypeExprKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExprKind {
+    Name(Symbol),
+    // `List a`, a generic type applied to arguments.
+    App(Symbol, Vec<TypeExpr>),
+    Func(Box<TypeExpr>, Box<TypeExpr>),
+    Tuple(Vec<TypeExpr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Mod,
+    BitAnd,
+    Add,
+    Sub,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+}
+
+impl UnaryOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+            UnaryOp::BitNot => "~",
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Eq => "==",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    // Binding power, higher binds tighter. Mirrors the table in docs/syntax.md.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::BitAnd => 5,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::BitOr | BinaryOp::BitXor
+            | BinaryOp::Shl | BinaryOp::Shr => 4,
+            BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq
+            | BinaryOp::Gt | BinaryOp::GtEq => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Or => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: ExprKind,
+}
+
+impl Expr {
+    // Whether the expression is `-9223372036854775808`, the one integer
+    // literal only in range once negated.
+    pub fn is_min_int(&self) -> bool {
+        match &self.kind {
+            ExprKind::Unary(UnaryOp::Neg, e) => matches!(e.kind, ExprKind::Int(val) if val == 1 << 63),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Int(u64),
+    Float(f64),
+    Char(char),
+    Str(String),
+    Bool(bool),
+    Template(Vec<TemplatePart>),
+    Name(Symbol),
+    // `foo::greet`, a member of an imported module.
+    Path(Ident, Ident),
+    Field(Box<Expr>, Ident),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    // `f a b` is stored flattened as Call(f, [a, b]). Application is curried:
+    // it means `(f a) b`, and a call with fewer arguments than the callee's
+    // parameters is a partial application.
+    Call(Box<Expr>, Vec<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Let(Vec<LetBinding>, Box<Expr>),
+    StructLit(StructLit),
+    Tuple(Vec<Expr>),
+    // `\x y -> body`, parameters are irrefutable patterns.
+    Lambda(Vec<Pattern>, Box<Expr>),
+    Case(Box<Expr>, Vec<CaseArm>),
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseArm {
+    pub pattern: Pattern,
+    pub body: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub id: NodeId,
+    pub span: Span,
+    pub kind: PatternKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatternKind {
+    Wildcard,
+    Binding(Symbol),
+    Int(i64),
+    Char(char),
+    Str(String),
+    Bool(bool),
+    Variant(Ident, Vec<Pattern>),
+    Tuple(Vec<Pattern>),
+    // `{a, b}`, `{x = a}` or `Vector {a, b}`, the struct counterpart of a
+    // struct literal.
+    Struct(Option<Ident>, Vec<FieldPattern>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldPattern {
+    pub name: Option<Ident>,
+    pub pattern: Pattern,
+    pub span: Span,
+}
+
+
+// `Rect {pos = p, size = s}`, `Rect {p, s}` or, when the type comes from the
+// context, just `{p, s}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructLit {
+    pub name: Option<Ident>,
+    pub fields: Vec<FieldInit>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldInit {
+    pub name: Option<Ident>,
+    pub expr: Expr,
+    pub span: Span,
+}
+
+impl StructLit {
+    pub fn is_named(&self) -> bool {
+        self.fields.iter().all(|f| f.name.is_some())
+    }
+
+    pub fn is_positional(&self) -> bool {
+        self.fields.iter().all(|f| f.name.is_none())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Str(String),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LetBinding {
+    pub id: NodeId,
+    pub pattern: Pattern,
+    pub ty: Option<TypeExpr>,
+    pub expr: Expr,
+}
+
+// Splits nested applications `((f a) b) c` into the head and its arguments.
+pub fn flatten_call(expr: &Expr) -> (&Expr, Vec<&Expr>) {
+    let mut head = expr;
+    let mut groups = vec![];
+    while let ExprKind::Call(func, args) = &head.kind {
+        groups.push(args);
+        head = func;
+    }
+    let mut args = vec![];
+    for group in groups.iter().rev() {
+        args.extend(group.iter());
+    }
+    (head, args)
+}
@@ -0,0 +1,204 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::common::Span;
+
+// Bytecode of a whole program, every module compiled together. Functions,
+// constants and types are referred to by their index in the program tables.
+// Every function runs in a frame of `locals` slots on the value stack: the
+// captured values of a closure come first, then the arguments, then the
+// let and case bindings; the operand stack grows above them.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Char(char),
+    Str(Rc<str>),
+    Bool(bool),
+    Tuple(Rc<Vec<Value>>),
+    // Type index and fields in declaration order.
+    Struct(u32, Rc<Vec<Value>>),
+    // Type index, variant index and fields.
+    Variant(u32, u32, Rc<Vec<Value>>),
+    Closure(Rc<Closure>),
+}
+
+// A function with its captured values and the arguments it was partially
+// applied to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub func: u32,
+    pub captured: Vec<Value>,
+    pub args: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    // Pushes a constant of the program.
+    Const(u32),
+    Load(u32),
+    Store(u32),
+    Dup,
+    Pop,
+    // Pops the given number of captured values and pushes a closure of the
+    // function over them.
+    Closure(u32, u32),
+    // Pops the arguments then the callee. Calls with too few arguments build
+    // a partial application, the extra ones go to the returned function.
+    Call(u32),
+    TailCall(u32),
+    // Calls a known function with exactly its arity.
+    CallFunc(u32, u32),
+    TailCallFunc(u32, u32),
+    Return,
+    Jump(u32),
+    JumpIfFalse(u32),
+    // `&&` and `||` compile to jumps.
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    // Pops the elements or fields and builds the value.
+    Tuple(u32),
+    Struct(u32, u32),
+    Variant(u32, u32, u32),
+    // Field of a tuple, struct or variant.
+    Field(u32),
+    // Whether the variant on top of the stack has the given index.
+    TestTag(u32),
+    // Replaces a value by its string form, as in templates.
+    Show,
+    Concat(u32),
+    // Writes the string on top of the stack as a line of output.
+    Print,
+    // Reached when no case arm matches.
+    MatchFail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub module: u32,
+    pub arity: u32,
+    pub captures: u32,
+    pub locals: u32,
+    pub code: Vec<Op>,
+    // Source span of every instruction, for runtime errors.
+    pub spans: Vec<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeInfo {
+    pub name: String,
+    pub is_struct: bool,
+    // Variant names and field counts, or the single constructor of a struct
+    // named after it.
+    pub ctors: Vec<(String, u32)>,
+    // Field names of a struct.
+    pub fields: Vec<String>,
+    // Instance methods by name, such as `add` or `show`.
+    pub methods: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleInfo {
+    pub name: String,
+    // File the module was loaded from, and the hash of its source then, to
+    // tell whether it changed since.
+    pub path: PathBuf,
+    pub hash: u64,
+    // Exported functions by name.
+    pub exports: Vec<(String, u32)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    pub constants: Vec<Value>,
+    pub types: Vec<TypeInfo>,
+    pub modules: Vec<ModuleInfo>,
+    // Evaluates and prints the expression statements of the root module.
+    pub main: u32,
+}
+
+impl TypeInfo {
+    pub fn method(&self, name: &str) -> Option<u32> {
+        self.methods.iter().find(|(n, _)| n == name).map(|(_, f)| *f)
+    }
+}
+
+fn constant_to_string(value: &Value, program: &Program) -> String {
+    match value {
+        Value::Int(val) => val.to_string(),
+        Value::Float(val) => format!("{:?}", val),
+        Value::Char(val) => format!("{:?}", val),
+        Value::Str(val) => format!("{:?}", val),
+        Value::Bool(val) => val.to_string(),
+        Value::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(|e| constant_to_string(e, program)).collect();
+            format!("({})", elems.join(", "))
+        }
+        Value::Struct(ty, fields) | Value::Variant(ty, _, fields) => {
+            let info = &program.types[*ty as usize];
+            let name = match value {
+                Value::Variant(_, index, _) => &info.ctors[*index as usize].0,
+                _ => &info.name,
+            };
+            if fields.is_empty() {
+                return name.clone();
+            }
+            let fields: Vec<String> = fields.iter().map(|e| constant_to_string(e, program)).collect();
+            format!("({} {})", name, fields.join(" "))
+        }
+        Value::Closure(closure) => format!("<fn {}>", program.functions[closure.func as usize].name),
+    }
+}
+
+fn op_to_string(op: &Op, program: &Program) -> String {
+    let func = |f: &u32| &program.functions[*f as usize].name;
+    match op {
+        Op::Const(c) => format!("const {}  ; {}", c, constant_to_string(&program.constants[*c as usize], program)),
+        Op::Load(slot) => format!("load {}", slot),
+        Op::Store(slot) => format!("store {}", slot),
+        Op::Dup => String::from("dup"),
+        Op::Pop => String::from("pop"),
+        Op::Closure(f, n) => format!("closure {} {}  ; {}", f, n, func(f)),
+        Op::Call(argc) => format!("call {}", argc),
+        Op::TailCall(argc) => format!("tail_call {}", argc),
+        Op::CallFunc(f, argc) => format!("call_func {} {}  ; {}", f, argc, func(f)),
+        Op::TailCallFunc(f, argc) => format!("tail_call_func {} {}  ; {}", f, argc, func(f)),
+        Op::Return => String::from("return"),
+        Op::Jump(target) => format!("jump {:04}", target),
+        Op::JumpIfFalse(target) => format!("jump_if_false {:04}", target),
+        Op::Unary(op) => format!("unary {}", op.as_str()),
+        Op::Binary(op) => format!("binary {}", op.as_str()),
+        Op::Tuple(n) => format!("tuple {}", n),
+        Op::Struct(ty, n) => format!("struct {} {}  ; {}", ty, n, program.types[*ty as usize].name),
+        Op::Variant(ty, index, n) => {
+            format!("variant {} {} {}  ; {}", ty, index, n, program.types[*ty as usize].ctors[*index as usize].0)
+        }
+        Op::Field(index) => format!("field {}", index),
+        Op::TestTag(index) => format!("test_tag {}", index),
+        Op::Show => String::from("show"),
+        Op::Concat(n) => format!("concat {}", n),
+        Op::Print => String::from("print"),
+        Op::MatchFail => String::from("match_fail"),
+    }
+}
+
+// Readable listing of every function, one instruction per line.
+pub fn disassemble(program: &Program) -> String {
+    let mut s = String::new();
+    for (i, func) in program.functions.iter().enumerate() {
+        let _ = writeln!(
+            s,
+            "fn {} {} (arity {}, captures {}, locals {})",
+            i, func.name, func.arity, func.captures, func.locals
+        );
+        for (ip, op) in func.code.iter().enumerate() {
+            let _ = writeln!(s, "  {:04}  {}", ip, op_to_string(op, program));
+        }
+    }
+    s
+}
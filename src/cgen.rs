@@ -0,0 +1,804 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::process::Command;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::ir::{self, BlockId, Const, Def, FuncKind, Inst, Term, Ty, TypeKind, ValueId};
+
+// Compiles the IR of a program to a single C99 file. Every value is an
+// `sp_value`; structs become C structs and sum types tagged unions of one
+// struct per variant, both laid out as a header followed by the fields so
+// the runtime can walk them. Functions of module `foo::bar` are named
+// `sp_foo__bar__name`. Blocks become labels and phis variables assigned
+// before the jumps. Calls of a function to itself in tail position become
+// jumps back to its entry, other calls use the C stack.
+
+const RUNTIME: &str = include_str!("runtime.c");
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "as",
+];
+
+// Names of the instance methods, indexed like the runtime's method table.
+const METHODS: [(&str, &str); 8] = [
+    ("add", "SP_ADD"),
+    ("sub", "SP_SUB"),
+    ("mul", "SP_MUL"),
+    ("div", "SP_DIV"),
+    ("neg", "SP_NEG"),
+    ("eq", "SP_EQ"),
+    ("lt", "SP_LT"),
+    ("show", "SP_SHOW"),
+];
+
+pub fn c_ident(name: &str) -> String {
+    if C_KEYWORDS.contains(&name) {
+        format!("{}_", name)
+    } else {
+        String::from(name)
+    }
+}
+
+// `foo::bar` becomes `foo__bar`, other characters of file names that can't
+// appear in C identifiers become `_`.
+pub fn mangle_module(name: &str) -> String {
+    name.replace("::", "__").chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+// C string literal, with octal escapes so following digits can't extend
+// them.
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' if byte != b'?' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\{:03o}", byte);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Values as the array arguments of the runtime.
+fn c_array(values: &[String]) -> String {
+    if values.is_empty() {
+        String::from("NULL")
+    } else {
+        format!("(sp_value[]){{{}}}", values.join(", "))
+    }
+}
+
+// Body of the C function being generated.
+struct FnState<'p> {
+    f: &'p ir::Function,
+    index: u32,
+    body: String,
+    indent: usize,
+    // C expression of every value.
+    names: Vec<String>,
+    uses: Vec<u32>,
+    // Blocks a `goto` jumps to, which need a label.
+    targets: Vec<bool>,
+}
+
+impl<'p> FnState<'p> {
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    fn open(&mut self, line: &str) {
+        self.line(line);
+        self.indent += 1;
+    }
+
+    fn close(&mut self, line: &str) {
+        self.indent -= 1;
+        self.line(line);
+    }
+
+    fn name(&self, value: ValueId) -> String {
+        self.names[value.0 as usize].clone()
+    }
+
+    fn goto(&mut self, target: BlockId, next: Option<BlockId>) {
+        if next != Some(target) {
+            self.targets[target.0 as usize] = true;
+            self.line(&format!("goto b{};", target.0));
+        }
+    }
+
+    // Assigns all the values at once: through temporaries when a variable
+    // assigned is read by a later assignment.
+    fn assign(&mut self, pairs: Vec<(String, String)>) {
+        let pairs: Vec<(String, String)> = pairs.into_iter().filter(|(dest, src)| dest != src).collect();
+        let clash = pairs.iter().enumerate().any(|(i, (dest, _))| pairs[i + 1..].iter().any(|(_, src)| src == dest));
+        if !clash {
+            for (dest, src) in &pairs {
+                self.line(&format!("{} = {};", dest, src));
+            }
+            return;
+        }
+        self.open("{");
+        for (i, (_, src)) in pairs.iter().enumerate() {
+            self.line(&format!("sp_value m{} = {};", i, src));
+        }
+        for (i, (dest, _)) in pairs.iter().enumerate() {
+            self.line(&format!("{} = m{};", dest, i));
+        }
+        self.close("}");
+    }
+
+    // Assigns the phis of `to` the values coming from `from`.
+    fn moves(&mut self, from: BlockId, to: BlockId) {
+        let f = self.f;
+        let mut pairs = vec![];
+        for phi in &f.block(to).phis {
+            if let Def::Phi(incoming) = f.def(*phi) {
+                let (_, value) = incoming.iter().find(|(b, _)| *b == from).unwrap();
+                pairs.push((self.name(*phi), self.name(*value)));
+            }
+        }
+        self.assign(pairs);
+    }
+}
+
+pub struct CGen<'a> {
+    ir: &'a ir::Program,
+    // C names of the types and functions, by index.
+    types: Vec<String>,
+    funcs: Vec<String>,
+    type_decls: String,
+    protos: String,
+    defs: String,
+}
+
+impl<'a> CGen<'a> {
+    pub fn new(ir: &'a ir::Program) -> CGen<'a> {
+        CGen {
+            ir,
+            types: vec![],
+            funcs: vec![],
+            type_decls: String::new(),
+            protos: String::new(),
+            defs: String::new(),
+        }
+    }
+
+    fn module_name(&self, module: u32) -> &'a str {
+        &self.ir.modules[module as usize].name
+    }
+
+    fn make_name(&self, ty: u32, variant: Option<u32>) -> String {
+        let c_name = &self.types[ty as usize][3..];
+        match (variant, &self.ir.types[ty as usize].kind) {
+            (Some(variant), TypeKind::Sum(variants)) => format!("sp_make_{}__{}", c_name, variants[variant as usize].0),
+            _ => format!("sp_make_{}", c_name),
+        }
+    }
+
+    // Type and variant a constructor function builds.
+    fn ctor_of(f: &ir::Function) -> (u32, u32) {
+        f.values
+            .iter()
+            .find_map(|value| match &value.def {
+                Def::Inst(Inst::Variant(ty, index, _)) => Some((*ty, *index)),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn collect(&mut self) {
+        let ir = self.ir;
+        for ty in &ir.types {
+            self.types.push(format!("sp_{}__{}", mangle_module(self.module_name(ty.module)), ty.name));
+        }
+        let mut lambdas = 0;
+        for f in &ir.funcs {
+            let c_name = match f.kind {
+                FuncKind::Func => format!("sp_{}__{}", mangle_module(self.module_name(f.module)), f.name),
+                FuncKind::Lambda => {
+                    lambdas += 1;
+                    format!("sp_lambda{}", lambdas)
+                }
+                FuncKind::Ctor => {
+                    let (ty, index) = CGen::ctor_of(f);
+                    self.make_name(ty, Some(index))
+                }
+                FuncKind::Main => String::from("sp_main"),
+                // Named after their type below.
+                FuncKind::Method => String::new(),
+            };
+            self.funcs.push(c_name);
+        }
+        for (i, ty) in ir.types.iter().enumerate() {
+            for (method, func) in &ty.methods {
+                self.funcs[*func as usize] = format!("{}__{}", self.types[i], method);
+            }
+        }
+    }
+
+    pub fn generate(mut self) -> String {
+        self.collect();
+        self.struct_decls();
+        self.constructors();
+        for i in 0..self.ir.funcs.len() {
+            self.function(i as u32);
+        }
+        let root = self.ir.funcs[self.ir.main as usize].module;
+        let mut out = format!("/* Generated by the Silver Pancake compiler from `{}`. */\n\n", self.module_name(root));
+        out.push_str(RUNTIME);
+        out.push_str("\n/* User types */\n\n");
+        out.push_str(&self.type_decls);
+        out.push_str("\n/* Functions */\n\n");
+        out.push_str(&self.protos);
+        out.push('\n');
+        out.push_str(&self.type_table());
+        out.push('\n');
+        out.push_str(&self.defs);
+        out.push_str("int main(void) {\n    sp_types = sp_type_defs;\n    sp_main();\n    return 0;\n}\n");
+        out
+    }
+
+    // Structs list their fields after the object header; sum types hold a
+    // union of one struct per variant with fields.
+    fn struct_decls(&mut self) {
+        for (i, ty) in self.ir.types.iter().enumerate() {
+            let mut decl = format!("struct {} {{\n    SP_OBJ_HEADER\n", self.types[i]);
+            match &ty.kind {
+                TypeKind::Struct(fields) => {
+                    for (name, _) in fields {
+                        let _ = writeln!(decl, "    sp_value {};", c_ident(name));
+                    }
+                }
+                TypeKind::Sum(variants) => {
+                    let variants: Vec<&(String, Vec<Ty>)> = variants.iter().filter(|(_, fields)| !fields.is_empty()).collect();
+                    if !variants.is_empty() {
+                        decl.push_str("    union {\n");
+                        for (name, fields) in variants {
+                            let fields: Vec<String> = (0..fields.len()).map(|i| format!("sp_value _{};", i)).collect();
+                            let _ = writeln!(decl, "        struct {{ {} }} {};", fields.join(" "), c_ident(name));
+                        }
+                        decl.push_str("    } as;\n");
+                    }
+                }
+            }
+            decl.push_str("};\n\n");
+            self.type_decls.push_str(&decl);
+        }
+    }
+
+    // Every struct and variant gets a function building it, with an entry
+    // point for the variants used as function values.
+    fn constructors(&mut self) {
+        for (index, ty) in self.ir.types.iter().enumerate() {
+            let struct_name = format!("struct {}", self.types[index]);
+            let ctors: Vec<(Option<u32>, Vec<String>)> = match &ty.kind {
+                TypeKind::Struct(fields) => vec![(None, fields.iter().map(|(name, _)| c_ident(name)).collect())],
+                TypeKind::Sum(variants) => variants
+                    .iter()
+                    .enumerate()
+                    .map(|(tag, (name, fields))| {
+                        let variant = c_ident(name);
+                        (Some(tag as u32), (0..fields.len()).map(|i| format!("as.{}._{}", variant, i)).collect())
+                    })
+                    .collect(),
+            };
+            for (variant, fields) in ctors {
+                let name = self.make_name(index as u32, variant);
+                let params: Vec<String> = (0..fields.len()).map(|i| format!("sp_value p{}", i)).collect();
+                let params = if params.is_empty() { String::from("void") } else { params.join(", ") };
+                let _ = writeln!(self.protos, "static sp_value {}({});", name, params);
+                let _ = writeln!(self.defs, "static sp_value {}({}) {{", name, params);
+                let _ = writeln!(self.defs, "    sp_value v = sp_obj_new({}, {}, {});", index, variant.unwrap_or(0), fields.len());
+                for (i, field) in fields.iter().enumerate() {
+                    let _ = writeln!(self.defs, "    (({} *)v.as.o)->{} = p{};", struct_name, field, i);
+                }
+                self.defs.push_str("    return v;\n}\n\n");
+                if variant.is_some() && !fields.is_empty() {
+                    self.entry(&name, fields.len());
+                }
+            }
+        }
+    }
+
+    // Calling convention of function values: captured values and arguments
+    // as arrays.
+    fn entry(&mut self, c_name: &str, arity: usize) {
+        let args: Vec<String> = (0..arity).map(|i| format!("args[{}]", i)).collect();
+        let _ = writeln!(self.protos, "static SP_UNUSED sp_value {}__entry(sp_value *env, sp_value *args);", c_name);
+        let _ = writeln!(
+            self.defs,
+            "static SP_UNUSED sp_value {}__entry(sp_value *env, sp_value *args) {{\n    (void)env;\n    return {}({});\n}}\n",
+            c_name,
+            c_name,
+            args.join(", ")
+        );
+    }
+
+    fn type_table(&self) -> String {
+        let mut out = String::new();
+        let mut entries = vec![];
+        for (i, ty) in self.ir.types.iter().enumerate() {
+            let c_name = &self.types[i];
+            let (names, is_struct): (Vec<&String>, bool) = match &ty.kind {
+                TypeKind::Struct(fields) => (fields.iter().map(|(name, _)| name).collect(), true),
+                TypeKind::Sum(variants) => (variants.iter().map(|(name, _)| name).collect(), false),
+            };
+            let names: Vec<String> = names.iter().map(|n| c_string(n)).collect();
+            let list = if is_struct { "fields" } else { "ctors" };
+            if !names.is_empty() {
+                let _ = writeln!(out, "static const char *const {}__{}[] = {{{}}};", c_name, list, names.join(", "));
+            }
+            let mut entry = format!("    {{{}, {}, {}, ", c_string(&ty.name), is_struct, if is_struct { 1 } else { names.len() });
+            let table = if names.is_empty() { String::from("NULL") } else { format!("{}__{}", c_name, list) };
+            if is_struct {
+                let _ = write!(entry, "NULL, {}, {{", table);
+            } else {
+                let _ = write!(entry, "{}, NULL, {{", table);
+            }
+            let methods: Vec<String> = ty
+                .methods
+                .iter()
+                .map(|(method, func)| {
+                    let index = METHODS.iter().find(|(name, _)| name == method).unwrap().1;
+                    format!("[{}] = {}__entry", index, self.funcs[*func as usize])
+                })
+                .collect();
+            let methods = if methods.is_empty() { String::from("NULL") } else { methods.join(", ") };
+            let _ = write!(entry, "{}}}}},", methods);
+            entries.push(entry);
+        }
+        // The sentinel keeps the array non-empty.
+        entries.push(String::from("    {NULL, false, 0, NULL, NULL, {NULL}},"));
+        let _ = writeln!(out, "static const sp_type sp_type_defs[] = {{\n{}\n}};", entries.join("\n"));
+        out
+    }
+
+    // Lambdas take their captured values in `env` and their arguments in
+    // `args`, other functions one C parameter per parameter.
+    fn function(&mut self, index: u32) {
+        let ir = self.ir;
+        let f = &ir.funcs[index as usize];
+        if f.kind == FuncKind::Ctor {
+            return;
+        }
+        let lambda = f.kind == FuncKind::Lambda;
+        let mut s = FnState {
+            f,
+            index,
+            body: String::new(),
+            indent: 1,
+            names: (0..f.values.len()).map(|i| format!("v{}", i)).collect(),
+            uses: f.use_counts(),
+            targets: vec![false; f.blocks.len()],
+        };
+        for (i, param) in f.params.iter().enumerate() {
+            let i = i as u32;
+            s.names[param.0 as usize] = match lambda {
+                false => format!("p{}", i),
+                true if i < f.captures => format!("env[{}]", i),
+                true => format!("args[{}]", i - f.captures),
+            };
+        }
+        let order = f.reverse_postorder();
+        let mut blocks = vec![];
+        for (i, block) in order.iter().enumerate() {
+            self.block(&mut s, *block, order.get(i + 1).copied());
+            blocks.push((*block, std::mem::take(&mut s.body)));
+        }
+        let c_name = &self.funcs[index as usize];
+        let params = if lambda {
+            String::from("sp_value *env, sp_value *args")
+        } else if f.params.is_empty() {
+            String::from("void")
+        } else {
+            (0..f.params.len()).map(|i| format!("sp_value p{}", i)).collect::<Vec<String>>().join(", ")
+        };
+        let _ = writeln!(self.protos, "static sp_value {}({});", c_name, params);
+        let _ = writeln!(self.defs, "static sp_value {}({}) {{", c_name, params);
+        if lambda {
+            self.defs.push_str("    (void)env;\n    (void)args;\n");
+        }
+        self.defs.push_str("    sp_enter();\n");
+        for block in &f.blocks {
+            for phi in &block.phis {
+                let _ = writeln!(self.defs, "    sp_value {};", s.names[phi.0 as usize]);
+            }
+        }
+        for (block, body) in blocks {
+            if s.targets[block.0 as usize] {
+                let _ = writeln!(self.defs, "b{}:;", block.0);
+            }
+            self.defs.push_str(&body);
+        }
+        self.defs.push_str("}\n\n");
+        if matches!(f.kind, FuncKind::Func | FuncKind::Method) && !f.params.is_empty() {
+            let c_name = c_name.clone();
+            self.entry(&c_name, f.params.len());
+        }
+    }
+
+    fn block(&self, s: &mut FnState, id: BlockId, next: Option<BlockId>) {
+        let f = s.f;
+        let block = f.block(id);
+        for value in &block.insts {
+            let used = s.uses[value.0 as usize] > 0;
+            let inst = f.inst(*value).unwrap();
+            if let Inst::Print(arg) = inst {
+                s.line(&format!("sp_print({});", s.name(*arg)));
+                if used {
+                    s.line(&format!("sp_value {} = sp_tuple(0, NULL);", s.name(*value)));
+                }
+                continue;
+            }
+            let expr = self.inst(s, inst);
+            if used {
+                s.line(&format!("sp_value {} = {};", s.name(*value), expr));
+            } else {
+                s.line(&format!("(void){};", expr));
+            }
+        }
+        match &block.term {
+            Term::Jump(target) => {
+                s.moves(id, *target);
+                s.goto(*target, next);
+            }
+            Term::Branch(cond, then_block, else_block) => {
+                let cond = s.name(*cond);
+                let phis = |b: &BlockId| !f.block(*b).phis.is_empty();
+                if phis(then_block) || phis(else_block) {
+                    s.open(&format!("if ({}.as.b) {{", cond));
+                    s.moves(id, *then_block);
+                    s.goto(*then_block, None);
+                    s.close("}");
+                    s.moves(id, *else_block);
+                    s.goto(*else_block, next);
+                } else if next == Some(*then_block) {
+                    s.targets[else_block.0 as usize] = true;
+                    s.line(&format!("if (!{}.as.b) goto b{};", cond, else_block.0));
+                } else {
+                    s.targets[then_block.0 as usize] = true;
+                    s.line(&format!("if ({}.as.b) goto b{};", cond, then_block.0));
+                    s.goto(*else_block, next);
+                }
+            }
+            Term::Return(value) => {
+                s.line("sp_depth--;");
+                s.line(&format!("return {};", s.name(*value)));
+            }
+            Term::TailCall(func, args) if *func == s.index && matches!(f.kind, FuncKind::Func | FuncKind::Method) => {
+                let pairs = args.iter().enumerate().map(|(i, arg)| (format!("p{}", i), s.name(*arg))).collect();
+                s.assign(pairs);
+                s.goto(BlockId(0), None);
+            }
+            Term::TailCall(func, args) => {
+                let args: Vec<String> = args.iter().map(|arg| s.name(*arg)).collect();
+                let call = self.call(*func, &args);
+                s.line("sp_depth--;");
+                s.line(&format!("return {};", call));
+            }
+            Term::TailCallValue(callee, args) => {
+                let args: Vec<String> = args.iter().map(|arg| s.name(*arg)).collect();
+                s.line("sp_depth--;");
+                s.line(&format!("return sp_apply({}, {}, {});", s.name(*callee), args.len(), c_array(&args)));
+            }
+            Term::MatchFail | Term::Unreachable => s.line("return sp_match_fail();"),
+        }
+    }
+
+    // Calls of known functions and constructors are direct C calls.
+    fn call(&self, func: u32, args: &[String]) -> String {
+        let f = &self.ir.funcs[func as usize];
+        let c_name = &self.funcs[func as usize];
+        match f.kind {
+            FuncKind::Lambda => {
+                let (captures, args) = args.split_at(f.captures as usize);
+                format!("{}({}, {})", c_name, c_array(captures), c_array(args))
+            }
+            _ => format!("{}({})", c_name, args.join(", ")),
+        }
+    }
+
+    // C expression of the value of an instruction.
+    fn inst(&self, s: &FnState, inst: &Inst) -> String {
+        let names = |values: &[ValueId]| -> Vec<String> { values.iter().map(|v| s.name(*v)).collect() };
+        match inst {
+            Inst::Const(value) => self.const_value(value),
+            Inst::Unary(op, value) => {
+                let value = s.name(*value);
+                match op {
+                    UnaryOp::Neg => format!("sp_neg({})", value),
+                    UnaryOp::Not => format!("sp_bool(!{}.as.b)", value),
+                    UnaryOp::BitNot => format!("sp_int(~{}.as.i)", value),
+                }
+            }
+            Inst::Binary(op, l, r) => {
+                let (l, r) = (s.name(*l), s.name(*r));
+                match op {
+                    BinaryOp::Add => format!("sp_add({}, {})", l, r),
+                    BinaryOp::Sub => format!("sp_sub({}, {})", l, r),
+                    BinaryOp::Mul => format!("sp_mul({}, {})", l, r),
+                    BinaryOp::Div => format!("sp_div({}, {})", l, r),
+                    BinaryOp::Mod => format!("sp_mod({}, {})", l, r),
+                    BinaryOp::BitAnd => format!("sp_int({}.as.i & {}.as.i)", l, r),
+                    BinaryOp::BitOr => format!("sp_int({}.as.i | {}.as.i)", l, r),
+                    BinaryOp::BitXor => format!("sp_int({}.as.i ^ {}.as.i)", l, r),
+                    BinaryOp::Shl => format!("sp_shift({}, {}, true)", l, r),
+                    BinaryOp::Shr => format!("sp_shift({}, {}, false)", l, r),
+                    BinaryOp::Eq => format!("sp_bool(sp_eq({}, {}))", l, r),
+                    BinaryOp::NotEq => format!("sp_bool(!sp_eq({}, {}))", l, r),
+                    BinaryOp::Lt => format!("sp_bool(sp_lt({}, {}))", l, r),
+                    BinaryOp::LtEq => format!("sp_bool(!sp_lt({}, {}))", r, l),
+                    BinaryOp::Gt => format!("sp_bool(sp_lt({}, {}))", r, l),
+                    BinaryOp::GtEq => format!("sp_bool(!sp_lt({}, {}))", l, r),
+                    BinaryOp::And | BinaryOp::Or => unreachable!("`{:?}` is lowered to branches", op),
+                }
+            }
+            Inst::Call(func, args) => self.call(*func, &names(args)),
+            Inst::CallValue(callee, args) => {
+                format!("sp_apply({}, {}, {})", s.name(*callee), args.len(), c_array(&names(args)))
+            }
+            Inst::Closure(func, captures) => {
+                let f = &self.ir.funcs[*func as usize];
+                let code = match f.kind {
+                    FuncKind::Lambda => self.funcs[*func as usize].clone(),
+                    _ => format!("{}__entry", self.funcs[*func as usize]),
+                };
+                format!("sp_closure_new({}, {}, {}, {})", code, f.arity(), captures.len(), c_array(&names(captures)))
+            }
+            Inst::Tuple(elems) => format!("sp_tuple({}, {})", elems.len(), c_array(&names(elems))),
+            Inst::Struct(ty, fields) => format!("{}({})", self.make_name(*ty, None), names(fields).join(", ")),
+            Inst::Variant(ty, index, fields) => {
+                format!("{}({})", self.make_name(*ty, Some(*index)), names(fields).join(", "))
+            }
+            Inst::Field(value, index) => match s.f.ty(*value) {
+                Ty::Struct(ty, _) => match &self.ir.types[*ty as usize].kind {
+                    TypeKind::Struct(fields) => {
+                        let field = c_ident(&fields[*index as usize].0);
+                        format!("((struct {} *){}.as.o)->{}", self.types[*ty as usize], s.name(*value), field)
+                    }
+                    TypeKind::Sum(_) => unreachable!("struct type of a sum"),
+                },
+                _ => format!("{}.as.o->fields[{}]", s.name(*value), index),
+            },
+            Inst::IsVariant(value, index) => format!("sp_bool({}.as.o->tag == {})", s.name(*value), index),
+            Inst::Show(value) => format!("sp_show({})", s.name(*value)),
+            Inst::Concat(parts) if parts.is_empty() => String::from("sp_str_new(\"\", 0)"),
+            Inst::Concat(parts) => format!("sp_concat({}, {})", parts.len(), c_array(&names(parts))),
+            Inst::Print(_) => unreachable!("printing is a statement"),
+        }
+    }
+
+    fn const_value(&self, value: &Const) -> String {
+        let list = |values: &[Const]| -> Vec<String> { values.iter().map(|v| self.const_value(v)).collect() };
+        match value {
+            Const::Int(val) => int_literal(*val),
+            Const::Float(val) => format!("sp_float({:?})", val),
+            Const::Char(val) => format!("sp_char({})", *val as u32),
+            Const::Str(val) => format!("sp_str_new({}, {})", c_string(val), val.len()),
+            Const::Bool(val) => format!("sp_bool({})", val),
+            Const::Tuple(elems) => format!("sp_tuple({}, {})", elems.len(), c_array(&list(elems))),
+            Const::Struct(ty, values) => format!("{}({})", self.make_name(*ty, None), list(values).join(", ")),
+            Const::Variant(ty, index, values) => format!("{}({})", self.make_name(*ty, Some(*index)), list(values).join(", ")),
+        }
+    }
+}
+
+// The most negative integer has no literal in C.
+fn int_constant(val: i64) -> String {
+    if val == i64::MIN {
+        String::from("INT64_MIN")
+    } else {
+        format!("INT64_C({})", val)
+    }
+}
+
+fn int_literal(val: i64) -> String {
+    format!("sp_int({})", int_constant(val))
+}
+
+pub fn generate_c_ir(program: &ir::Program) -> String {
+    CGen::new(program).generate()
+}
+
+// Builds a native executable from a generated C file with the system C
+// compiler, or the one named by `CC`.
+pub fn build_native(c_file: &Path, exe: &Path) -> Result<(), String> {
+    let cc = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+    let output = Command::new(&cc)
+        .args(["-std=c99", "-O2", "-o"])
+        .arg(exe)
+        .arg(c_file)
+        .arg("-lm")
+        .output()
+        .map_err(|err| format!("Cannot run '{}': {}", cc, err))?;
+    if !output.status.success() {
+        return Err(format!("'{}' failed:\n{}", cc, String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::run_program;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+
+    // Compiles a program to a native executable and runs it, checking the
+    // interpreter agrees. Errors are what the program wrote to stderr.
+    fn run_native(name: &str, src: &str, foo: &str) -> Result<String, String> {
+        native(name, src, foo, true)
+    }
+
+    fn native(name: &str, src: &str, foo: &str, compare: bool) -> Result<String, String> {
+        let (graph, checked, interner) = check_test_program(src, foo);
+        let c_src = generate_c_ir(&lower_program(&graph, &checked, &interner));
+        let dir = std::env::temp_dir().join(format!("sp_cgen_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (c_file, exe) = (dir.join("main.c"), dir.join("main"));
+        std::fs::write(&c_file, &c_src).unwrap();
+        if let Err(err) = build_native(&c_file, &exe) {
+            println!("{}", err);
+            panic!("the generated C doesn't compile");
+        }
+        let output = Command::new(&exe).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let result = if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).trim().replace("error: ", ""))
+        };
+        println!("{:?}", result);
+        if compare {
+            let expected = run_program(&graph, &checked, &interner).map_err(|err| err.diag.msg);
+            assert!(result == expected);
+        }
+        result
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(mangle_module("foo::bar") == "foo__bar");
+        assert!(mangle_module("my-app") == "my_app");
+        assert!(c_ident("double") == "double_" && c_ident("x") == "x");
+        assert!(c_string("a\"b\\c\n1") == "\"a\\\"b\\\\c\\0121\"");
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        let src = "
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+adder n: int -> (int -> int)
+    \\x -> x + n
+
+fact_rec 20
+twice (adder 10) 1
+(\\x y -> x * y) 2.5 0.5
+`${fact_rec 3}, ${1 < 2 && 2 > 3 || true}, ${'é'}, ${\"tab\\there\"}`
+(1e16, 0.0001, 1e-5, 0.1 + 0.2, -0.0, 1.0 / 3.0, 100.0)
+";
+        let expected = "2432902008176640000\n21\n1.25\n6, true, é, tab\there\n\
+                        (1e16, 0.0001, 1e-5, 0.30000000000000004, -0.0, 0.3333333333333333, 100.0)\n";
+        assert!(run_native("functions", src, "") == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // Too deep for the interpreter, self tail calls run as loops.
+        let src = "
+count n, acc: int -> int
+    if n == 0
+        acc
+    else
+        count (n - 1) (acc + 1)
+
+count 1000000 0
+";
+        assert!(native("tail_calls", src, "", false) == Ok(String::from("1000000\n")));
+    }
+
+    #[test]
+    fn test_types_and_modules() {
+        let foo = "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Rect w h -> w * h
+        Empty -> 0.0
+
+greet str: string -> string
+    `Hello ${str}`
+
+export =
+    area
+    greet
+    Shape
+";
+        let src = format!(
+            "{}
+import foo::area
+import foo::greet
+import foo::Shape
+
+struct Vector =
+    x, y: float
+
+instance Num Vector =
+    add a, b = Vector {{a.x + b.x, a.y + b.y}}
+    sub a, b = Vector {{a.x - b.x, a.y - b.y}}
+    mul a, b = Vector {{a.x * b.x, a.y * b.y}}
+    div a, b = Vector {{a.x / b.x, a.y / b.y}}
+    neg v = Vector {{-v.x, -v.y}}
+
+instance Show Vector =
+    show v = `<${{v.x}}, ${{v.y}}>`
+
+struct Point =
+    x, y: int
+
+describe p: (int, string) -> string
+    case p of
+        (0, _) -> \"zero\"
+        (n, \"one\") -> `one ${{n}}`
+        (n, s) -> `${{s}} ${{n}}`
+
+const ORIGIN = Point {{x = 0, y = 0}}
+
+area (Rect 2.0 3.0)
+map area (Cons (Circle 1.0) (Cons Empty Nil))
+greet \"Silver pancake\"
+Vector {{1.0, 2.0}} + {{3.0, 4.0}}
+-(Vector {{1.0, 2.0}})
+(ORIGIN, ORIGIN == Point {{0, 0}}, (1, \"b\") < (1, \"c\"))
+(describe (0, \"a\"), describe (1, \"one\"), describe (2, \"two\"))
+Just (Rect 1.0 2.0)
+(Rect 1.0)
+",
+            crate::prelude::PRELUDE
+        );
+        let expected = "6.0\nCons 3.0 (Cons 0.0 Nil)\nHello Silver pancake\n<4.0, 6.0>\n<-1.0, -2.0>\n\
+                        (Point {x = 0, y = 0}, true, true)\n(zero, one 1, two 2)\nJust (Rect 1.0 2.0)\n<function>\n";
+        assert!(run_native("types", &src, foo) == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+div x, y: int -> int
+    x / y
+
+div 7 2
+div 1 0
+";
+        assert!(run_native("division", src, "") == Err(String::from("Division by zero")));
+        assert!(run_native("overflow", "9223372036854775807 + 1\n", "") == Err(String::from("Integer overflow")));
+        let src = "deep n: int -> int\n    1 + deep (n + 1)\n\ndeep 0\n";
+        assert!(native("deep", src, "", false) == Err(String::from("Stack overflow: too many nested calls")));
+    }
+}
@@ -0,0 +1,956 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ast::Module;
+use crate::bytecode::{self, disassemble};
+use crate::cgen::{build_native, generate_c_ir};
+use crate::common::Interner;
+use crate::compile::compile_ir;
+use crate::diagnostic::{has_errors, line_col, render, Diagnostic, Level};
+use crate::fmt::format_source;
+use crate::interp::{Interp, RuntimeError};
+use crate::ir::Program;
+use crate::json::Json;
+use crate::lexer::{tokenize, Token, TokenKind, TokenVal};
+use crate::lower::lower_program;
+use crate::lsp::serve;
+use crate::modules::{check_program, load_program, read_file, read_module_file, CheckedModule, ModuleGraph, ModuleId};
+use crate::native::compile_native_ir;
+use crate::opt::{optimize, OptLevel};
+use crate::parser::parse_module;
+use crate::repl::{is_complete, Session};
+use crate::sexpr::module_to_sexpr;
+use crate::spc::{changed_modules, read_program, spc_file, write_program};
+use crate::vm::Vm;
+use crate::wasm::compile_wasm_ir;
+
+// Command line driver of the `sp` binary. Every command reads `.sp` files
+// and reports diagnostics on stderr, its output goes to stdout.
+
+pub const USAGE: &str = "Usage: sp <command> [options] <file>...
+
+Commands:
+    lex      Print the tokens of a file
+    parse    Parse a file and print its AST
+    check    Check a program for errors
+    run      Run a program, or a compiled .spc file
+    build    Compile a program
+    fmt      Format files in place
+    repl     Evaluate expressions and definitions interactively
+    lsp      Serve the Language Server Protocol on stdin and stdout
+
+Options:
+    --emit=STAGES          Print stages of the compilation: tokens, ast, sexpr, ir, passes, bytecode
+    --format=human|json    Output format of tokens
+    --error-format=human|json
+                           Format of diagnostics
+    -O0, -O1, -O2          Optimisation level of run and build (default -O1)
+    --backend=vm|interp    Backend of run (default vm)
+    --target=TARGET        Output of build: exe (default), c, obj, wasm, wat, spc
+    -o FILE                Output file of build
+    -I DIR                 Look for imported modules in DIR too
+    --check                Fail instead of formatting files that are not formatted
+    -h, --help             Print this message
+";
+
+// Exit codes.
+pub const EXIT_OK: i32 = 0;
+// The program has errors, or a file isn't formatted under `fmt --check`.
+pub const EXIT_ERRORS: i32 = 1;
+// Bad arguments or a file that can't be read or written.
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_RUNTIME: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Lex,
+    Parse,
+    Check,
+    Run,
+    Build,
+    Fmt,
+    Repl,
+    Lsp,
+    Help,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Sexpr,
+    Ir,
+    // The IR after every optimisation pass.
+    Passes,
+    // The code of the bytecode machine.
+    Bytecode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Vm,
+    Interp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Exe,
+    C,
+    Obj,
+    Wasm,
+    Wat,
+    Spc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub files: Vec<PathBuf>,
+    pub emit: Vec<Emit>,
+    pub format: Format,
+    pub error_format: Format,
+    pub opt: OptLevel,
+    pub backend: Backend,
+    pub target: Target,
+    pub output: Option<PathBuf>,
+    pub search_paths: Vec<PathBuf>,
+    pub check: bool,
+}
+
+fn parse_format(value: &str) -> Result<Format, String> {
+    match value {
+        "human" => Ok(Format::Human),
+        "json" => Ok(Format::Json),
+        _ => Err(format!("Unknown format '{}', expected human or json", value)),
+    }
+}
+
+fn parse_emit(value: &str) -> Result<Vec<Emit>, String> {
+    value
+        .split(',')
+        .map(|stage| match stage {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "sexpr" => Ok(Emit::Sexpr),
+            "ir" => Ok(Emit::Ir),
+            "passes" => Ok(Emit::Passes),
+            "bytecode" => Ok(Emit::Bytecode),
+            _ => Err(format!("Unknown stage '{}', expected tokens, ast, sexpr, ir, passes or bytecode", stage)),
+        })
+        .collect()
+}
+
+pub fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        command: Command::Help,
+        files: vec![],
+        emit: vec![],
+        format: Format::Human,
+        error_format: Format::Human,
+        opt: OptLevel::O1,
+        backend: Backend::Vm,
+        target: Target::Exe,
+        output: None,
+        search_paths: vec![],
+        check: false,
+    };
+    let mut args = args.iter();
+    opts.command = match args.next().map(|s| s.as_str()) {
+        Some("lex") => Command::Lex,
+        Some("parse") => Command::Parse,
+        Some("check") => Command::Check,
+        Some("run") => Command::Run,
+        Some("build") => Command::Build,
+        Some("fmt") => Command::Fmt,
+        Some("repl") => Command::Repl,
+        Some("lsp") => Command::Lsp,
+        Some("help") | Some("-h") | Some("--help") | None => return Ok(opts),
+        Some(command) => return Err(format!("Unknown command '{}'", command)),
+    };
+    while let Some(arg) = args.next() {
+        // `--name=value` or `--name value`.
+        let (name, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (&arg[..i], Some(&arg[i + 1..])),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || match inline {
+            Some(value) => Ok(String::from(value)),
+            None => args.next().cloned().ok_or_else(|| format!("Missing value after '{}'", name)),
+        };
+        match name {
+            "--emit" => opts.emit.extend(parse_emit(&value()?)?),
+            "--format" => opts.format = parse_format(&value()?)?,
+            "--error-format" => opts.error_format = parse_format(&value()?)?,
+            "--backend" => {
+                opts.backend = match value()?.as_str() {
+                    "vm" => Backend::Vm,
+                    "interp" => Backend::Interp,
+                    other => return Err(format!("Unknown backend '{}', expected vm or interp", other)),
+                }
+            }
+            "--target" => {
+                opts.target = match value()?.as_str() {
+                    "exe" => Target::Exe,
+                    "c" => Target::C,
+                    "obj" => Target::Obj,
+                    "wasm" => Target::Wasm,
+                    "wat" => Target::Wat,
+                    "spc" => Target::Spc,
+                    other => return Err(format!("Unknown target '{}', expected exe, c, obj, wasm, wat or spc", other)),
+                }
+            }
+            "-o" => opts.output = Some(PathBuf::from(value()?)),
+            "-I" => opts.search_paths.push(PathBuf::from(value()?)),
+            "--check" => opts.check = true,
+            "-h" | "--help" => opts.command = Command::Help,
+            _ if name.starts_with("-O") => {
+                opts.opt = OptLevel::parse(&name[2..]).ok_or_else(|| format!("Unknown optimisation level '{}'", name))?
+            }
+            _ if name.starts_with('-') && name.len() > 1 => return Err(format!("Unknown option '{}'", name)),
+            _ => opts.files.push(PathBuf::from(arg)),
+        }
+    }
+    if opts.command == Command::Help {
+        return Ok(opts);
+    }
+    if matches!(opts.command, Command::Repl | Command::Lsp) {
+        if !opts.files.is_empty() {
+            return Err(String::from("repl and lsp take no input file"));
+        }
+        return Ok(opts);
+    }
+    if opts.files.is_empty() {
+        return Err(String::from("Missing input file"));
+    }
+    let whole_program = matches!(opts.command, Command::Check | Command::Run | Command::Build);
+    if opts.emit.iter().any(|e| matches!(e, Emit::Ir | Emit::Passes | Emit::Bytecode)) && !whole_program {
+        return Err(String::from("--emit=ir, --emit=passes and --emit=bytecode need check, run or build"));
+    }
+    if matches!(opts.command, Command::Run | Command::Build) && opts.files.len() > 1 {
+        return Err(String::from("Expected a single input file"));
+    }
+    Ok(opts)
+}
+
+pub fn diagnostic_json(diag: &Diagnostic, path: &Path, src: &str) -> Json {
+    let (line, column) = line_col(src, diag.span.start);
+    let (end_line, end_column) = line_col(src, diag.span.end);
+    let level = match diag.level {
+        Level::Error => "error",
+        Level::Warning => "warning",
+    };
+    Json::object(vec![
+        ("level", Json::str(level)),
+        ("message", Json::str(diag.msg.as_str())),
+        ("file", Json::str(path.display().to_string())),
+        ("line", Json::Int(line as i64)),
+        ("column", Json::Int(column as i64)),
+        ("end_line", Json::Int(end_line as i64)),
+        ("end_column", Json::Int(end_column as i64)),
+        ("notes", Json::Array(diag.notes.iter().map(|n| Json::str(n.as_str())).collect())),
+    ])
+}
+
+// Kind of a token without the char of LAST_CHAR, which is in its text.
+fn kind_name(kind: &TokenKind) -> String {
+    match kind {
+        TokenKind::LAST_CHAR(_) => String::from("LAST_CHAR"),
+        kind => format!("{:?}", kind),
+    }
+}
+
+// One token per line in fixed width columns, so that dumps of a file by two
+// versions of the lexer can be diffed line by line. Values are printed as
+// the lexer stores them.
+pub fn token_table(tokens: &[Token], src: &str) -> String {
+    let mut s = format!("{:<12} {:<9} {:<15} {:<13} {:<5} {}\n", "span", "line:col", "kind", "mod", "space", "value");
+    for token in tokens {
+        let (line, column) = line_col(src, token.span.start);
+        let token_mod = token.token_mod.as_ref().map_or(String::from("-"), |m| format!("{:?}", m));
+        let value = token.val.as_ref().map_or(String::from("-"), |val| format!("{:?}", val));
+        s.push_str(&format!(
+            "{:<12} {:<9} {:<15} {:<13} {:<5} {}\n",
+            format!("{}..{}", token.span.start, token.span.end),
+            format!("{}:{}", line, column),
+            format!("{:?}", token.token_kind),
+            token_mod,
+            if token.space_before { "yes" } else { "no" },
+            value
+        ));
+    }
+    s
+}
+
+// JSON Lines, one object per token. Spans are byte offsets, columns count
+// chars from 1 and `text` is the source of the token.
+pub fn token_json(token: &Token, path: &Path, src: &str) -> Json {
+    let (line, column) = line_col(src, token.span.start);
+    let value = match &token.val {
+        None => Json::Null,
+        Some(TokenVal::Int(val)) if *val <= i64::MAX as u64 => Json::Int(*val as i64),
+        // Beyond the range of JSON readers that parse integers as i64.
+        Some(TokenVal::Int(val)) => Json::str(val.to_string()),
+        Some(TokenVal::Float(val)) => Json::Float(*val),
+        Some(TokenVal::Char(val)) => Json::str(val.to_string()),
+        Some(TokenVal::Str(val)) => Json::str(val.as_str()),
+    };
+    Json::object(vec![
+        ("file", Json::str(path.display().to_string())),
+        ("kind", Json::str(kind_name(&token.token_kind))),
+        ("mod", token.token_mod.as_ref().map_or(Json::Null, |m| Json::str(format!("{:?}", m)))),
+        ("value", value),
+        ("text", Json::str(&src[token.span.start.min(src.len())..token.span.end.min(src.len())])),
+        ("start", Json::Int(token.span.start as i64)),
+        ("end", Json::Int(token.span.end as i64)),
+        ("line", Json::Int(line as i64)),
+        ("column", Json::Int(column as i64)),
+        ("space_before", Json::Bool(token.space_before)),
+    ])
+}
+
+// What went wrong besides the program having errors: such commands exit
+// with EXIT_USAGE.
+type Outcome = Result<i32, String>;
+
+fn io_error(err: std::io::Error) -> String {
+    format!("Cannot write output: {}", err)
+}
+
+fn read_source(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|err| format!("Cannot read '{}': {}", path.display(), err))
+}
+
+fn write_output(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    std::fs::write(path, bytes).map_err(|err| format!("Cannot write '{}': {}", path.display(), err))
+}
+
+// Saves a compiled program with the absolute paths of its sources, so that
+// they are found again from any directory.
+fn write_spc(path: &Path, program: &bytecode::Program) -> Result<(), String> {
+    let mut program = program.clone();
+    for module in &mut program.modules {
+        if let Ok(abs) = std::fs::canonicalize(&module.path) {
+            module.path = abs;
+        }
+    }
+    write_output(path, &write_program(&program))
+}
+
+struct Driver<'a> {
+    opts: Options,
+    input: &'a mut dyn BufRead,
+    out: &'a mut dyn Write,
+    err: &'a mut dyn Write,
+    interner: Interner,
+}
+
+impl<'a> Driver<'a> {
+    fn print(&mut self, s: &str) -> Result<(), String> {
+        self.out.write_all(s.as_bytes()).map_err(io_error)
+    }
+
+    fn report(&mut self, diag: &Diagnostic, path: &Path, src: &str) -> Result<(), String> {
+        let text = match self.opts.error_format {
+            Format::Human => render(diag, &path.display().to_string(), src),
+            Format::Json => format!("{}\n", diagnostic_json(diag, path, src)),
+        };
+        self.err.write_all(text.as_bytes()).map_err(io_error)
+    }
+
+    fn report_module(&mut self, graph: &ModuleGraph, module: ModuleId, diag: &Diagnostic) -> Result<(), String> {
+        let module = graph.module(module);
+        self.report(diag, &module.path, &module.src)
+    }
+
+    // Prints the tokens of `src` and returns the lexical errors.
+    fn print_tokens(&mut self, path: &Path, src: &str) -> Result<Vec<Diagnostic>, String> {
+        let mut stream = src;
+        let (tokens, errors) = tokenize(&mut stream);
+        let dump = match self.opts.format {
+            Format::Human => token_table(&tokens, src),
+            Format::Json => tokens.iter().map(|token| format!("{}\n", token_json(token, path, src))).collect(),
+        };
+        self.print(&dump)?;
+        Ok(errors)
+    }
+
+    // The parse stages of `--emit`, for the root file.
+    fn emit_parsed(&mut self, path: &Path, src: &str, module: &Module) -> Result<(), String> {
+        for emit in self.opts.emit.clone() {
+            match emit {
+                // The parse reports the lexical errors.
+                Emit::Tokens => {
+                    self.print_tokens(path, src)?;
+                }
+                Emit::Ast => self.print(&format!("{:#?}\n", module))?,
+                Emit::Sexpr => self.print(&module_to_sexpr(module, &self.interner))?,
+                Emit::Ir | Emit::Passes | Emit::Bytecode => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn lex(&mut self) -> Outcome {
+        let mut code = EXIT_OK;
+        for path in self.opts.files.clone() {
+            let src = read_source(&path)?;
+            if self.opts.files.len() > 1 && self.opts.format == Format::Human {
+                self.print(&format!("{}:\n", path.display()))?;
+            }
+            let errors = self.print_tokens(&path, &src)?;
+            for err in &errors {
+                self.report(err, &path, &src)?;
+            }
+            if has_errors(&errors) {
+                code = EXIT_ERRORS;
+            }
+        }
+        Ok(code)
+    }
+
+    fn parse(&mut self) -> Outcome {
+        if self.opts.emit.is_empty() {
+            self.opts.emit.push(Emit::Sexpr);
+        }
+        let mut code = EXIT_OK;
+        for path in self.opts.files.clone() {
+            let src = read_source(&path)?;
+            let (module, errors) = parse_module(&src, &mut self.interner);
+            self.emit_parsed(&path, &src, &module)?;
+            for err in &errors {
+                self.report(err, &path, &src)?;
+            }
+            if has_errors(&errors) {
+                code = EXIT_ERRORS;
+            }
+        }
+        Ok(code)
+    }
+
+    // Loads and checks the program rooted at `path`, reporting its
+    // diagnostics. None when it has errors.
+    fn load(&mut self, path: &Path) -> Result<Option<(ModuleGraph, Vec<CheckedModule>)>, String> {
+        let src = read_source(path)?;
+        let graph = load_program(path, src, &self.opts.search_paths, &read_file, &mut self.interner);
+        let checked = check_program(&graph, &self.interner);
+        let root = graph.module(graph.root);
+        self.emit_parsed(&root.path, &root.src, &root.ast)?;
+        let mut failed = false;
+        for (i, module) in checked.iter().enumerate() {
+            for err in &module.errors {
+                self.report_module(&graph, ModuleId(i), err)?;
+            }
+            failed |= has_errors(&module.errors);
+        }
+        Ok(if failed { None } else { Some((graph, checked)) })
+    }
+
+    // Lowers a checked program and optimises it, printing the IR stages
+    // asked for.
+    fn lower(&mut self, graph: &ModuleGraph, checked: &[CheckedModule]) -> Result<Program, String> {
+        let mut program = lower_program(graph, checked, &self.interner);
+        let passes = self.opts.emit.contains(&Emit::Passes);
+        let mut dumps = String::new();
+        if passes {
+            dumps.push_str(&format!("; lowered\n{}", program.dump()));
+        }
+        optimize(&mut program, self.opts.opt, &mut |name, program| {
+            if passes {
+                dumps.push_str(&format!("; after {}\n{}", name, program.dump()));
+            }
+        });
+        if self.opts.emit.contains(&Emit::Ir) {
+            dumps.push_str(&program.dump());
+        }
+        self.print(&dumps)?;
+        Ok(program)
+    }
+
+    // Compiles an optimised program to bytecode, printing it if asked.
+    fn compile(&mut self, program: &Program) -> Result<crate::bytecode::Program, String> {
+        let program = compile_ir(program);
+        self.emit_bytecode(&program)?;
+        Ok(program)
+    }
+
+    fn emit_bytecode(&mut self, program: &crate::bytecode::Program) -> Result<(), String> {
+        if self.opts.emit.contains(&Emit::Bytecode) {
+            self.print(&disassemble(program))?;
+        }
+        Ok(())
+    }
+
+    fn check(&mut self) -> Outcome {
+        let mut code = EXIT_OK;
+        for path in self.opts.files.clone() {
+            match self.load(&path)? {
+                Some((graph, checked)) => {
+                    if self.opts.emit.contains(&Emit::Bytecode) {
+                        let program = self.lower(&graph, &checked)?;
+                        self.compile(&program)?;
+                    } else if self.opts.emit.iter().any(|e| *e == Emit::Ir || *e == Emit::Passes) {
+                        self.lower(&graph, &checked)?;
+                    }
+                }
+                None => code = EXIT_ERRORS,
+            }
+        }
+        Ok(code)
+    }
+
+    fn run(&mut self) -> Outcome {
+        let path = self.opts.files[0].clone();
+        if path.extension().is_some_and(|ext| ext == "spc") {
+            return self.run_spc(&path);
+        }
+        let (graph, checked) = match self.load(&path)? {
+            Some(loaded) => loaded,
+            None => return Ok(EXIT_ERRORS),
+        };
+        let result = match self.opts.backend {
+            Backend::Interp => Interp::new(&graph, &checked, &self.interner).run(&mut *self.out),
+            Backend::Vm => {
+                let program = self.lower(&graph, &checked)?;
+                let program = self.compile(&program)?;
+                Vm::new(&program).run(&mut *self.out)
+            }
+        };
+        match result {
+            Ok(()) => Ok(EXIT_OK),
+            Err(RuntimeError { module, diag }) => {
+                self.report_module(&graph, module, &diag)?;
+                Ok(EXIT_RUNTIME)
+            }
+        }
+    }
+
+    // Runs a saved program. The file records the source of every module:
+    // when one of them changed since the build and the root one is still
+    // around, the whole program is rebuilt from them first. Runtime errors
+    // point into the recorded sources.
+    fn run_spc(&mut self, path: &Path) -> Outcome {
+        let bytes = std::fs::read(path).map_err(|err| format!("Cannot read '{}': {}", path.display(), err))?;
+        let mut program = read_program(&bytes).map_err(|err| format!("Cannot load '{}': {}", path.display(), err))?;
+        // The root module comes first.
+        let root = program.modules[0].path.clone();
+        if !changed_modules(&program, &read_module_file).is_empty() && root.is_file() {
+            let (graph, checked) = match self.load(&root)? {
+                Some(loaded) => loaded,
+                None => return Ok(EXIT_ERRORS),
+            };
+            program = compile_ir(&self.lower(&graph, &checked)?);
+            write_spc(path, &program)?;
+        }
+        self.emit_bytecode(&program)?;
+        let RuntimeError { module, diag } = match Vm::new(&program).run(&mut *self.out) {
+            Ok(()) => return Ok(EXIT_OK),
+            Err(err) => err,
+        };
+        let src_path = program.modules[module.0].path.clone();
+        let src = read_module_file(&src_path).unwrap_or_default();
+        self.report(&diag, &src_path, &src)?;
+        Ok(EXIT_RUNTIME)
+    }
+
+    fn build(&mut self) -> Outcome {
+        let path = self.opts.files[0].clone();
+        let (graph, checked) = match self.load(&path)? {
+            Some(loaded) => loaded,
+            None => return Ok(EXIT_ERRORS),
+        };
+        let program = self.lower(&graph, &checked)?;
+        let output = |ext: &str| self.opts.output.clone().unwrap_or_else(|| path.with_extension(ext));
+        let skipped = match self.opts.target {
+            Target::Exe => {
+                let exe = output("");
+                let c_file = exe.with_extension("c");
+                write_output(&c_file, generate_c_ir(&program).as_bytes())?;
+                let built = build_native(&c_file, &exe);
+                let _ = std::fs::remove_file(&c_file);
+                built?;
+                vec![]
+            }
+            Target::C => {
+                write_output(&output("c"), generate_c_ir(&program).as_bytes())?;
+                vec![]
+            }
+            Target::Obj => {
+                let object = compile_native_ir(&program);
+                let obj = output("o");
+                write_output(&obj, &object.object)?;
+                write_output(&obj.with_extension("h"), object.header.as_bytes())?;
+                object.skipped
+            }
+            Target::Wasm | Target::Wat => {
+                let wasm = compile_wasm_ir(&program);
+                if self.opts.target == Target::Wasm {
+                    write_output(&output("wasm"), &wasm.module.encode())?;
+                } else {
+                    write_output(&output("wat"), wasm.module.to_text().as_bytes())?;
+                }
+                wasm.skipped
+            }
+            Target::Spc => {
+                let spc = self.opts.output.clone().unwrap_or_else(|| spc_file(&path));
+                let program = self.compile(&program)?;
+                write_spc(&spc, &program)?;
+                vec![]
+            }
+        };
+        for (module, diag) in &skipped {
+            self.report_module(&graph, *module, diag)?;
+        }
+        Ok(EXIT_OK)
+    }
+
+    fn fmt(&mut self) -> Outcome {
+        let mut code = EXIT_OK;
+        for path in self.opts.files.clone() {
+            let src = read_source(&path)?;
+            let formatted = match format_source(&src, &mut self.interner) {
+                Ok(formatted) => formatted,
+                Err(errors) => {
+                    for err in &errors {
+                        self.report(err, &path, &src)?;
+                    }
+                    code = EXIT_ERRORS;
+                    continue;
+                }
+            };
+            if formatted == src {
+                continue;
+            }
+            if self.opts.check {
+                self.print(&format!("{} is not formatted\n", path.display()))?;
+                code = EXIT_ERRORS;
+            } else {
+                write_output(&path, formatted.as_bytes())?;
+            }
+        }
+        Ok(code)
+    }
+
+    // Reads a line of input, None at its end.
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>, String> {
+        write!(self.err, "{}", prompt).and_then(|_| self.err.flush()).map_err(io_error)?;
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line)),
+            Err(err) => Err(format!("Cannot read input: {}", err)),
+        }
+    }
+
+    // Prompts go to stderr so the values on stdout can be piped. An entry
+    // continues on the next lines while it's incomplete, and an empty line
+    // ends it.
+    fn repl(&mut self) -> Outcome {
+        let mut session = Session::new(self.opts.search_paths.clone());
+        loop {
+            let mut entry = String::new();
+            while let Some(line) = self.read_line(if entry.is_empty() { "> " } else { "... " })? {
+                if entry.is_empty() {
+                    match line.trim() {
+                        "" => continue,
+                        ":quit" | ":q" => return Ok(EXIT_OK),
+                        ":reset" => {
+                            session.reset();
+                            continue;
+                        }
+                        _ => {}
+                    }
+                } else if line.trim().is_empty() {
+                    break;
+                }
+                entry.push_str(&line);
+                if !entry.ends_with('\n') {
+                    entry.push('\n');
+                }
+                if is_complete(&entry, &mut self.interner) {
+                    break;
+                }
+            }
+            if entry.is_empty() {
+                break;
+            }
+            let reply = session.eval(&entry, &mut self.interner);
+            self.print(&reply.output)?;
+            for (diag, path, src) in &reply.diags {
+                self.report(diag, path, src)?;
+            }
+        }
+        let _ = writeln!(self.err);
+        Ok(EXIT_OK)
+    }
+}
+
+// Runs `sp` with the arguments after the program name and returns its exit
+// code.
+pub fn run(args: &[String], input: &mut dyn BufRead, out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    let opts = match parse_args(args) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            let _ = write!(err, "error: {}\n\n{}", msg, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let mut driver = Driver {
+        opts,
+        input,
+        out,
+        err,
+        interner: Interner::new(),
+    };
+    let outcome = match driver.opts.command {
+        Command::Lex => driver.lex(),
+        Command::Parse => driver.parse(),
+        Command::Check => driver.check(),
+        Command::Run => driver.run(),
+        Command::Build => driver.build(),
+        Command::Fmt => driver.fmt(),
+        Command::Repl => driver.repl(),
+        Command::Lsp => serve(driver.input, driver.out, driver.opts.search_paths.clone()),
+        Command::Help => driver.print(USAGE).map(|_| EXIT_OK),
+    };
+    match outcome {
+        Ok(code) => code,
+        Err(msg) => {
+            let _ = writeln!(driver.err, "error: {}", msg);
+            EXIT_USAGE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    // A directory for the files of a test, removed with everything in it
+    // when the test ends, whether it passes or not.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("sp_cli_{}_{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Writes the files in `dir` and runs `sp` from there with the file paths
+    // in `line` relative to it.
+    fn sp(dir: &TempDir, files: &[(&str, &str)], line: &str) -> (i32, String, String) {
+        sp_input(dir, files, line, "")
+    }
+
+    fn sp_input(dir: &TempDir, files: &[(&str, &str)], line: &str, input: &str) -> (i32, String, String) {
+        let dir = &dir.0;
+        for (file, src) in files {
+            std::fs::write(dir.join(file), src).unwrap();
+        }
+        let args: Vec<String> = args(line)
+            .into_iter()
+            .map(|arg| if arg.ends_with(".sp") || arg.ends_with(".spc") { dir.join(arg).display().to_string() } else { arg })
+            .collect();
+        let (mut out, mut err) = (vec![], vec![]);
+        let code = run(&args, &mut input.as_bytes(), &mut out, &mut err);
+        let prefix = format!("{}/", dir.display());
+        let out = String::from_utf8(out).unwrap().replace(&prefix, "");
+        let err = String::from_utf8(err).unwrap().replace(&prefix, "");
+        println!("{}\n{}\n{}", code, out, err);
+        (code, out, err)
+    }
+
+    #[test]
+    fn test_args() {
+        let opts = parse_args(&args("run --emit=tokens,ir -O2 --backend interp -I lib main.sp")).unwrap();
+        assert!(opts.command == Command::Run);
+        assert!(opts.emit == vec![Emit::Tokens, Emit::Ir]);
+        assert!(opts.opt == OptLevel::O2);
+        assert!(opts.backend == Backend::Interp);
+        assert!(opts.search_paths == vec![PathBuf::from("lib")]);
+        assert!(opts.files == vec![PathBuf::from("main.sp")]);
+        assert!(parse_args(&args("build --target=wat -o out.wat main.sp")).unwrap().target == Target::Wat);
+        assert!(parse_args(&args("")).unwrap().command == Command::Help);
+        assert!(parse_args(&args("run")).unwrap_err() == "Missing input file");
+        assert!(parse_args(&args("parse --emit=ir main.sp")).is_err());
+        assert!(parse_args(&args("parse --emit=bytecode main.sp")).is_err());
+        assert!(parse_args(&args("check --emit=bytes main.sp")).is_err());
+        assert!(parse_args(&args("run -O3 main.sp")).is_err());
+        assert!(parse_args(&args("frobnicate main.sp")).is_err());
+        assert!(parse_args(&args("lsp -I lib")).unwrap().command == Command::Lsp);
+        assert!(parse_args(&args("repl main.sp")).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = TempDir::new("run");
+        let prelude = TempDir::new("run_prelude");
+        let spc = TempDir::new("run_spc");
+        let main = "fact n: int -> int\n    if n == 0\n        1\n    else\n        n * fact n-1\n\nfact 5\n";
+        let (code, out, _) = sp(&dir, &[("main.sp", main)], "run main.sp");
+        assert!(code == EXIT_OK && out == "120\n");
+        let (code, out, _) = sp(&dir, &[], "run --backend=interp main.sp");
+        assert!(code == EXIT_OK && out == "120\n");
+        let (code, out, _) = sp(&dir, &[], "run -O2 --emit=ir main.sp");
+        assert!(code == EXIT_OK && out.starts_with("fn 0 main::fact(v0: int) -> int\n") && out.ends_with("120\n"));
+        let (code, out, _) = sp(&dir, &[], "check --emit=passes main.sp");
+        assert!(code == EXIT_OK && out.starts_with("; lowered\n") && out.contains("; after dce\n"));
+        let (code, out, _) = sp(&dir, &[], "check --emit=bytecode main.sp");
+        assert!(code == EXIT_OK && out.starts_with("fn 0 fact (arity 1, captures 0, locals 1)\n  0000  load 0\n"));
+        assert!(out.contains("fn 1 <main> (arity 0, captures 0, locals 0)\n"));
+        let main = "import prelude::List\nimport prelude\n\nprelude::reverse (Cons 1 (Cons 2 Nil))\n";
+        let (code, out, _) = sp(&prelude, &[("main.sp", main)], "run main.sp");
+        assert!(code == EXIT_OK && out == "Cons 2 (Cons 1 Nil)\n");
+
+        let (code, out, err) = sp(&dir, &[("overflow.sp", "fact 30\nfact n: int -> int\n    if n == 0 then 1 else n * fact n-1\n")], "run overflow.sp");
+        assert!(code == EXIT_RUNTIME && out.is_empty());
+        assert!(err.starts_with("error: Integer overflow\n --> overflow.sp:3:"));
+
+        let (code, _, _) = sp(&dir, &[], "build --target=spc main.sp");
+        assert!(code == EXIT_OK);
+        let (code, out, _) = sp(&dir, &[], "run main.spc");
+        assert!(code == EXIT_OK && out == "120\n");
+        // An edited source is rebuilt rather than running the stale program.
+        let (code, out, _) = sp(&dir, &[("main.sp", "fact 6\nfact n: int -> int\n    if n == 0 then 1 else n * fact n-1\n")], "run main.spc");
+        assert!(code == EXIT_OK && out == "720\n");
+        let (code, out, _) = sp(&dir, &[], "run main.spc");
+        assert!(code == EXIT_OK && out == "720\n");
+
+        // Imported modules are found where the build loaded them from.
+        let util = "half n: int -> int\n    100 / n\n\nexport =\n    half\n";
+        let files = [("app.sp", "import util\n\nutil::half 10\n"), ("util.sp", util)];
+        let (code, _, _) = sp(&spc, &files, "build --target=spc app.sp");
+        assert!(code == EXIT_OK);
+        let (code, out, _) = sp(&spc, &[("util.sp", &util.replace("100", "1000"))], "run app.spc");
+        assert!(code == EXIT_OK && out == "100\n");
+        let (code, _, err) = sp(&spc, &[("app.sp", "import util\n\nutil::half 0\n")], "run app.spc");
+        assert!(code == EXIT_RUNTIME && err.starts_with("error: Division by zero\n --> util.sp:2:"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = TempDir::new("errors");
+        let main = "f x: int -> int\n    x + true\n";
+        let (code, _, err) = sp(&dir, &[("main.sp", main)], "check main.sp");
+        assert!(code == EXIT_ERRORS && err.starts_with("error: ") && err.contains(" --> main.sp:2:"));
+        let (code, out, err) = sp(&dir, &[], "check --error-format=json main.sp");
+        assert!(code == EXIT_ERRORS && out.is_empty());
+        assert!(err.lines().count() == 1);
+        assert!(err.starts_with("{\"level\":\"error\",\"message\":"));
+        assert!(err.contains("\"file\":\"main.sp\",\"line\":2,\"column\":9,\"end_line\":2,\"end_column\":13,\"notes\":[]}"));
+        let (code, out, err) = sp(&dir, &[("syntax.sp", "f x: int -> int\n    x * * 2\n")], "parse syntax.sp");
+        assert!(code == EXIT_ERRORS && out == "(func f (x int) int\n  (return (error)))\n");
+        assert!(err.starts_with("error: Expected expression, found '*'\n"));
+        let (code, _, err) = sp(&dir, &[], "check missing.sp");
+        assert!(code == EXIT_USAGE && err.starts_with("error: Cannot read 'missing.sp': "));
+        // Lexical errors are diagnostics like the others.
+        let (code, out, err) = sp(&dir, &[("hex.sp", "const A = 0x\nA\n")], "run hex.sp");
+        assert!(code == EXIT_ERRORS && out.is_empty());
+        assert!(err.starts_with("error: Expected digit after integer literal prefix\n --> hex.sp:1:11\n"));
+        let (code, _, err) = sp(&dir, &[("str.sp", "const S = \"abc\nconst B = 2\n")], "check --error-format=json str.sp");
+        assert!(code == EXIT_ERRORS && err.lines().all(|line| line.starts_with("{\"level\":\"error\",")));
+        assert!(err.contains("\"message\":\"Unexpected end of file within string literal\""));
+        let (code, out, err) = sp(&dir, &[("char.sp", "const A = 1 \u{a7} 2\n")], "lex char.sp");
+        assert!(code == EXIT_ERRORS && out.contains("Int(2)"));
+        assert!(err.starts_with("error: Invalid character '\u{a7}'\n --> char.sp:1:13\n"));
+    }
+
+    #[test]
+    fn test_lex() {
+        let dir = TempDir::new("lex");
+        let src = "f 0xff 0o17 0b101 (n-1) 'a' \"\\t\" 1.5e3 18446744073709551615\n";
+        let (code, out, _) = sp(&dir, &[("main.sp", src)], "lex main.sp");
+        assert!(code == EXIT_OK);
+        assert!(out == r#"span         line:col  kind            mod           space value
+0..1         1:1       NAME            -             yes   Str("f")
+2..6         1:3       INT             TOKENMOD_HEX  yes   Int(255)
+7..11        1:8       INT             TOKENMOD_OCT  yes   Int(15)
+12..17       1:13      INT             TOKENMOD_BIN  yes   Int(5)
+18..19       1:19      LAST_CHAR('(')  -             yes   -
+19..20       1:20      NAME            -             no    Str("n")
+20..21       1:21      LAST_CHAR('-')  -             no    -
+21..22       1:22      INT             -             no    Int(1)
+22..23       1:23      LAST_CHAR(')')  -             no    -
+24..27       1:25      CHAR            TOKENMOD_CHAR yes   Char('a')
+28..32       1:29      STR             -             yes   Str("\t")
+33..38       1:34      FLOAT           -             yes   Float(1500.0)
+39..59       1:40      INT             -             yes   Int(18446744073709551615)
+59..59       1:60      NEWLINE         -             yes   -
+60..60       2:1       EOF             -             yes   -
+"#);
+        let (code, out, _) = sp(&dir, &[], "lex --format=json main.sp");
+        assert!(code == EXIT_OK);
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.len() == 15);
+        assert!(lines[1] == r#"{"file":"main.sp","kind":"INT","mod":"TOKENMOD_HEX","value":255,"text":"0xff","start":2,"end":6,"line":1,"column":3,"space_before":true}"#);
+        assert!(lines[6].starts_with(r#"{"file":"main.sp","kind":"LAST_CHAR","mod":null,"value":null,"text":"-","#));
+        assert!(lines[10].contains(r#""kind":"STR","mod":null,"value":"\t","text":"\"\\t\"""#));
+        assert!(lines[11].contains(r#""value":1500.0,"#));
+        assert!(lines[12].contains(r#""value":"18446744073709551615","#));
+    }
+
+    #[test]
+    fn test_parse() {
+        let dir = TempDir::new("parse");
+        let (code, out, _) = sp(&dir, &[("main.sp", "greet \"x\"\n")], "parse main.sp");
+        assert!(code == EXIT_OK && out == "(greet \"x\")\n");
+        let (_, out, _) = sp(&dir, &[], "parse --emit=ast main.sp");
+        assert!(out.starts_with("Module {\n    decls: [\n"));
+        let (_, out, _) = sp(&dir, &[], "parse --emit=tokens,sexpr main.sp");
+        assert!(out.starts_with("span ") && out.ends_with("2:1       EOF             -             yes   -\n(greet \"x\")\n"));
+    }
+
+    #[test]
+    fn test_fmt() {
+        let dir = TempDir::new("fmt");
+        let src = "double   x: int -> int = x*2\ndouble 4\n";
+        let (code, out, _) = sp(&dir, &[("main.sp", src)], "fmt --check main.sp");
+        assert!(code == EXIT_ERRORS && out.ends_with("main.sp is not formatted\n"));
+        let (code, _, _) = sp(&dir, &[], "fmt main.sp");
+        assert!(code == EXIT_OK);
+        let formatted = std::fs::read_to_string(dir.0.join("main.sp")).unwrap();
+        assert!(formatted == "double x: int -> int\n    x * 2\ndouble 4\n");
+        let (code, out, _) = sp(&dir, &[], "fmt --check main.sp");
+        assert!(code == EXIT_OK && out.is_empty());
+    }
+
+    #[test]
+    fn test_repl() {
+        let dir = TempDir::new("repl");
+        let input = "fact n: int -> int =
+    if n == 0 then 1 else n * fact (n - 1)
+
+fact 5
+(1 +
+  2, \"two\")
+fact
+fact \"5\"
+double x: int -> int = x * 2
+double (fact 3)
+:reset
+double 1
+";
+        let (code, out, err) = sp_input(&dir, &[], "repl", input);
+        assert!(code == EXIT_OK);
+        assert!(out == "120 : int\n(3, two) : (int, string)\n<function> : int -> int\n12 : int\n");
+        assert!(err.contains("<repl>:1:6") && err.contains("... "));
+        assert!(err.matches("error").count() == 2);
+    }
+}
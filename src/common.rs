@@ -1,4 +1,4 @@
-use std::ptr;
+use std::collections::HashMap;
 
 struct InternStr<'a> {
     len: usize,
@@ -8,33 +8,83 @@ struct InternStr<'a> {
 #[allow(dead_code)]
 fn str_intern_range<'a>(interns: &mut Vec<InternStr<'a>>, str: &'a str) -> &'a str {
     let len = str.len();
-    for i in 0..interns.len() {
-        if (interns[i].len == len) & (interns[i].str == str) {
-            return &interns[i].str;
+    for intern in interns.iter() {
+        if (intern.len == len) & (intern.str == str) {
+            return intern.str;
         }
     }
     let intern = InternStr {
         len: str.len(),
-        str: &str,
+        str,
     };
     interns.push(intern);
-    &str
+    str
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(pub u32);
+
+// Owning interner used past the lexer: every name in the AST is a Symbol.
+#[derive(Debug, Default)]
+pub struct Interner {
+    map: HashMap<String, Symbol>,
+    strs: Vec<String>,
 }
 
 #[allow(dead_code)]
-pub fn fatal_error(err: &str, c: Option<&char>) {
-    if c != None {
-        panic!("Fatal error: {} caused by char: {}", err, c.unwrap());
-    } else {
-        panic!("Fatal error: {}", err);
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    pub fn intern(&mut self, str: &str) -> Symbol {
+        if let Some(sym) = self.map.get(str) {
+            return *sym;
+        }
+        let sym = Symbol(self.strs.len() as u32);
+        self.strs.push(String::from(str));
+        self.map.insert(String::from(str), sym);
+        sym
+    }
+
+    pub fn get(&self, sym: Symbol) -> &str {
+        &self.strs[sym.0 as usize]
+    }
+
+    pub fn lookup(&self, str: &str) -> Option<Symbol> {
+        self.map.get(str).copied()
+    }
+}
+
+// Byte offsets into the source text, end exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+// FNV-1a, stable across runs and platforms unlike the std hasher.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
     }
+    hash
 }
 
-pub fn syntax_error(err: &str, c: Option<&char>) {
-    if c != None {
-        eprintln!("Syntax error: {} caused by char: {}", err, c.unwrap());
+#[allow(dead_code)]
+pub fn fatal_error(err: &str, c: Option<&char>) {
+    if let Some(c) = c {
+        panic!("Fatal error: {} caused by char: {}", err, c);
     } else {
-        eprintln!("Syntax error: {}", err);
+        panic!("Fatal error: {}", err);
     }
 }
 
@@ -51,6 +101,7 @@ impl<I: std::iter::Iterator> PeekableIterator for std::iter::Peekable<I> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ptr;
 
     #[test]
     fn test_string_interning() {
@@ -65,4 +116,16 @@ mod tests {
         let pz = str_intern_range(&mut interns, z);
         assert!(!ptr::eq(pz, px));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let x = interner.intern("fact_rec");
+        let y = interner.intern(&String::from("fact_rec"));
+        let z = interner.intern("fact");
+        assert!(x == y);
+        assert!(x != z);
+        assert!(interner.get(z) == "fact");
+        assert!(interner.lookup("missing").is_none());
+    }
+}
@@ -0,0 +1,297 @@
+use std::rc::Rc;
+
+use crate::bytecode::{Function, ModuleInfo, Op, Program, TypeInfo, Value};
+use crate::common::Span;
+use crate::ir::{self, BlockId, Const, Def, Inst, Term, TypeKind, ValueId};
+
+// Compiles the IR of a program to bytecode. A value used once, by a later
+// instruction of its block with nothing computed in between, stays on the
+// operand stack; the other values get a slot of the frame. Phis are slots
+// the predecessors store to before jumping.
+
+// Code of the function being compiled.
+struct Builder<'p> {
+    f: &'p ir::Function,
+    code: Vec<Op>,
+    spans: Vec<Span>,
+    slots: Vec<Option<u32>>,
+    locals: u32,
+    uses: Vec<u32>,
+    // Values pushed right before their use instead of getting a slot.
+    stacked: Vec<bool>,
+    // Start of every block and the jumps to patch once all are known.
+    starts: Vec<u32>,
+    jumps: Vec<(usize, BlockId)>,
+}
+
+impl<'p> Builder<'p> {
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    fn slot(&mut self, value: ValueId) -> u32 {
+        if let Some(slot) = self.slots[value.0 as usize] {
+            return slot;
+        }
+        self.slots[value.0 as usize] = Some(self.locals);
+        self.locals += 1;
+        self.locals - 1
+    }
+
+    fn jump(&mut self, op: Op, target: BlockId, span: Span) {
+        let at = self.emit(op, span);
+        self.jumps.push((at, target));
+    }
+
+    fn operand(&mut self, compiler: &mut Compiler, value: ValueId) {
+        if self.stacked[value.0 as usize] {
+            self.inst(compiler, value);
+        } else {
+            let slot = self.slot(value);
+            let span = self.f.values[value.0 as usize].span;
+            self.emit(Op::Load(slot), span);
+        }
+    }
+
+    fn inst(&mut self, compiler: &mut Compiler, value: ValueId) {
+        let data = &self.f.values[value.0 as usize];
+        let span = data.span;
+        let inst = match &data.def {
+            Def::Inst(inst) => inst,
+            _ => unreachable!("v{} isn't an instruction", value.0),
+        };
+        for operand in inst.operands() {
+            self.operand(compiler, operand);
+        }
+        let op = match inst {
+            Inst::Const(value) => Op::Const(compiler.constant(value)),
+            Inst::Unary(op, _) => Op::Unary(*op),
+            Inst::Binary(op, _, _) => Op::Binary(*op),
+            Inst::Call(func, args) => Op::CallFunc(*func, args.len() as u32),
+            Inst::CallValue(_, args) => Op::Call(args.len() as u32),
+            Inst::Closure(func, captures) => Op::Closure(*func, captures.len() as u32),
+            Inst::Tuple(elems) => Op::Tuple(elems.len() as u32),
+            Inst::Struct(ty, fields) => Op::Struct(*ty, fields.len() as u32),
+            Inst::Variant(ty, index, fields) => Op::Variant(*ty, *index, fields.len() as u32),
+            Inst::Field(_, index) => Op::Field(*index),
+            Inst::IsVariant(_, index) => Op::TestTag(*index),
+            Inst::Show(_) => Op::Show,
+            Inst::Concat(parts) => Op::Concat(parts.len() as u32),
+            Inst::Print(_) => Op::Print,
+        };
+        self.emit(op, span);
+    }
+
+    // Stores the values of the phis of `to` coming from `from`.
+    fn moves(&mut self, from: BlockId, to: BlockId, span: Span) {
+        let phis = &self.f.block(to).phis;
+        for phi in phis {
+            if let Def::Phi(incoming) = self.f.def(*phi) {
+                let (_, value) = incoming.iter().find(|(b, _)| *b == from).unwrap();
+                let slot = self.slot(*value);
+                self.emit(Op::Load(slot), span);
+            }
+        }
+        for phi in phis.iter().rev() {
+            let slot = self.slot(*phi);
+            self.emit(Op::Store(slot), span);
+        }
+    }
+
+    fn has_moves(&self, to: BlockId) -> bool {
+        !self.f.block(to).phis.is_empty()
+    }
+
+    fn block(&mut self, compiler: &mut Compiler, id: BlockId, next: Option<BlockId>) {
+        let f = self.f;
+        let block = f.block(id);
+        for value in &block.insts {
+            if self.stacked[value.0 as usize] {
+                continue;
+            }
+            self.inst(compiler, *value);
+            let span = f.values[value.0 as usize].span;
+            let used = self.uses[value.0 as usize] > 0;
+            if let Some(Inst::Print(_)) = f.inst(*value) {
+                if !used {
+                    continue;
+                }
+                self.emit(Op::Tuple(0), span);
+            }
+            if used {
+                let slot = self.slot(*value);
+                self.emit(Op::Store(slot), span);
+            } else {
+                self.emit(Op::Pop, span);
+            }
+        }
+        let span = block.span;
+        match &block.term {
+            Term::Jump(target) => {
+                self.moves(id, *target, span);
+                if next != Some(*target) {
+                    self.jump(Op::Jump(0), *target, span);
+                }
+            }
+            Term::Branch(cond, then_block, else_block) => {
+                self.operand(compiler, *cond);
+                if self.has_moves(*then_block) || self.has_moves(*else_block) {
+                    let to_else = self.emit(Op::JumpIfFalse(0), span);
+                    self.moves(id, *then_block, span);
+                    self.jump(Op::Jump(0), *then_block, span);
+                    self.code[to_else] = Op::JumpIfFalse(self.code.len() as u32);
+                    self.moves(id, *else_block, span);
+                    if next != Some(*else_block) {
+                        self.jump(Op::Jump(0), *else_block, span);
+                    }
+                } else {
+                    self.jump(Op::JumpIfFalse(0), *else_block, span);
+                    if next != Some(*then_block) {
+                        self.jump(Op::Jump(0), *then_block, span);
+                    }
+                }
+            }
+            Term::Return(value) => {
+                self.operand(compiler, *value);
+                self.emit(Op::Return, span);
+            }
+            Term::TailCall(func, args) => {
+                for arg in args {
+                    self.operand(compiler, *arg);
+                }
+                self.emit(Op::TailCallFunc(*func, args.len() as u32), span);
+            }
+            Term::TailCallValue(callee, args) => {
+                self.operand(compiler, *callee);
+                for arg in args {
+                    self.operand(compiler, *arg);
+                }
+                self.emit(Op::TailCall(args.len() as u32), span);
+            }
+            Term::MatchFail | Term::Unreachable => {
+                self.emit(Op::MatchFail, span);
+            }
+        }
+    }
+}
+
+pub struct Compiler<'a> {
+    ir: &'a ir::Program,
+    program: Program,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(ir: &'a ir::Program) -> Compiler<'a> {
+        Compiler {
+            ir,
+            program: Program {
+                functions: vec![],
+                constants: vec![],
+                types: vec![],
+                modules: vec![],
+                main: ir.main,
+            },
+        }
+    }
+
+    fn constant(&mut self, value: &Const) -> u32 {
+        let value = const_value(value);
+        self.program.constants.push(value);
+        (self.program.constants.len() - 1) as u32
+    }
+
+    pub fn compile(mut self) -> Program {
+        let ir = self.ir;
+        for ty in &ir.types {
+            let (is_struct, ctors, fields) = match &ty.kind {
+                TypeKind::Struct(fields) => (
+                    true,
+                    vec![(ty.name.clone(), fields.len() as u32)],
+                    fields.iter().map(|(name, _)| name.clone()).collect(),
+                ),
+                TypeKind::Sum(variants) => {
+                    (false, variants.iter().map(|(name, fields)| (name.clone(), fields.len() as u32)).collect(), vec![])
+                }
+            };
+            self.program.types.push(TypeInfo {
+                name: ty.name.clone(),
+                is_struct,
+                ctors,
+                fields,
+                methods: ty.methods.clone(),
+            });
+        }
+        for f in &ir.funcs {
+            let func = self.function(f);
+            self.program.functions.push(func);
+        }
+        for module in &ir.modules {
+            self.program.modules.push(ModuleInfo {
+                name: module.name.clone(),
+                path: module.path.clone(),
+                hash: module.hash,
+                exports: module.exports.clone(),
+            });
+        }
+        self.program
+    }
+
+    fn function(&mut self, f: &ir::Function) -> Function {
+        let mut b = Builder {
+            f,
+            code: vec![],
+            spans: vec![],
+            slots: vec![None; f.values.len()],
+            locals: 0,
+            uses: f.use_counts(),
+            stacked: f.stacked_values(),
+            starts: vec![0; f.blocks.len()],
+            jumps: vec![],
+        };
+        for param in &f.params {
+            b.slot(*param);
+        }
+        let order = f.reverse_postorder();
+        for (i, block) in order.iter().enumerate() {
+            b.starts[block.0 as usize] = b.code.len() as u32;
+            b.block(self, *block, order.get(i + 1).copied());
+        }
+        for (at, target) in std::mem::take(&mut b.jumps) {
+            let target = b.starts[target.0 as usize];
+            b.code[at] = match b.code[at] {
+                Op::Jump(_) => Op::Jump(target),
+                Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+                op => unreachable!("patching {:?}", op),
+            };
+        }
+        Function {
+            name: f.name.clone(),
+            module: f.module,
+            arity: f.arity(),
+            captures: f.captures,
+            locals: b.locals,
+            code: b.code,
+            spans: b.spans,
+        }
+    }
+}
+
+fn const_value(value: &Const) -> Value {
+    let values = |values: &[Const]| Rc::new(values.iter().map(const_value).collect());
+    match value {
+        Const::Int(val) => Value::Int(*val),
+        Const::Float(val) => Value::Float(*val),
+        Const::Char(val) => Value::Char(*val),
+        Const::Str(val) => Value::Str(Rc::from(val.as_str())),
+        Const::Bool(val) => Value::Bool(*val),
+        Const::Tuple(elems) => Value::Tuple(values(elems)),
+        Const::Struct(ty, fields) => Value::Struct(*ty, values(fields)),
+        Const::Variant(ty, index, fields) => Value::Variant(*ty, *index, values(fields)),
+    }
+}
+
+pub fn compile_ir(program: &ir::Program) -> Program {
+    Compiler::new(program).compile()
+}
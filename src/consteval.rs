@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::diagnostic::Diagnostic;
+use crate::resolve::{DefKind, Resolutions};
+use crate::typeck::TypeckResults;
+use crate::types::Type;
+
+// Compile time evaluation of `const` initializers. Runs after type checking,
+// and initializers with errors are skipped, so operands are known to have the
+// right types and only the values can go wrong: overflow, division by zero, or
+// something that needs the program to run such as a function call.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstValue {
+    Int(i64),
+    Float(f64),
+    Char(char),
+    Str(String),
+    Bool(bool),
+    Tuple(Vec<ConstValue>),
+    // Fields in declaration order.
+    Struct(Symbol, Vec<ConstValue>),
+    Variant(Symbol, Vec<ConstValue>),
+}
+
+// How a value reads in a string template, for the types with a builtin Show.
+pub fn show_value(value: &ConstValue) -> String {
+    match value {
+        ConstValue::Int(val) => val.to_string(),
+        ConstValue::Float(val) => format!("{:?}", val),
+        ConstValue::Char(val) => val.to_string(),
+        ConstValue::Str(val) => val.clone(),
+        ConstValue::Bool(val) => val.to_string(),
+        ConstValue::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(show_value).collect();
+            format!("({})", elems.join(", "))
+        }
+        // User types are shown by their instance, which can't run here.
+        ConstValue::Struct(_, _) | ConstValue::Variant(_, _) => String::from("?"),
+    }
+}
+
+// Errors an operator can raise on values of the right type.
+pub const OVERFLOW: &str = "Integer overflow";
+pub const DIVISION_BY_ZERO: &str = "Division by zero";
+
+pub fn unary_op(op: UnaryOp, value: &ConstValue) -> Result<ConstValue, &'static str> {
+    match (op, value) {
+        (UnaryOp::Neg, ConstValue::Int(val)) => val.checked_neg().map(ConstValue::Int).ok_or(OVERFLOW),
+        (UnaryOp::Neg, ConstValue::Float(val)) => Ok(ConstValue::Float(-val)),
+        (UnaryOp::Not, ConstValue::Bool(val)) => Ok(ConstValue::Bool(!val)),
+        (UnaryOp::BitNot, ConstValue::Int(val)) => Ok(ConstValue::Int(!val)),
+        _ => unreachable!("ill-typed operand of '{}'", op.as_str()),
+    }
+}
+
+// Operators other than `&&` and `||`, which don't evaluate their right operand
+// when the left one decides.
+pub fn binary_op(op: BinaryOp, l: &ConstValue, r: &ConstValue) -> Result<ConstValue, &'static str> {
+    use ConstValue::*;
+    let value = match (l, r) {
+        (Int(a), Int(b)) => {
+            let (a, b) = (*a, *b);
+            let int = |val: Option<i64>| val.map(Int).ok_or(OVERFLOW);
+            match op {
+                BinaryOp::Add => int(a.checked_add(b))?,
+                BinaryOp::Sub => int(a.checked_sub(b))?,
+                BinaryOp::Mul => int(a.checked_mul(b))?,
+                BinaryOp::Div | BinaryOp::Mod if b == 0 => return Err(DIVISION_BY_ZERO),
+                BinaryOp::Div => int(a.checked_div(b))?,
+                BinaryOp::Mod => int(a.checked_rem(b))?,
+                BinaryOp::BitAnd => Int(a & b),
+                BinaryOp::BitOr => Int(a | b),
+                BinaryOp::BitXor => Int(a ^ b),
+                BinaryOp::Shl | BinaryOp::Shr if !(0..64).contains(&b) => return Err(OVERFLOW),
+                BinaryOp::Shl => Int(a << b),
+                BinaryOp::Shr => Int(a >> b),
+                _ => return compare(op, l, r),
+            }
+        }
+        (Float(a), Float(b)) => match op {
+            BinaryOp::Add => Float(a + b),
+            BinaryOp::Sub => Float(a - b),
+            BinaryOp::Mul => Float(a * b),
+            BinaryOp::Div | BinaryOp::Mod if *b == 0.0 => return Err(DIVISION_BY_ZERO),
+            BinaryOp::Div => Float(a / b),
+            BinaryOp::Mod => Float(a % b),
+            _ => return compare(op, l, r),
+        },
+        _ => return compare(op, l, r),
+    };
+    Ok(value)
+}
+
+fn compare(op: BinaryOp, l: &ConstValue, r: &ConstValue) -> Result<ConstValue, &'static str> {
+    let result = match op {
+        BinaryOp::Eq => l == r,
+        BinaryOp::NotEq => l != r,
+        BinaryOp::Lt => lt(l, r),
+        BinaryOp::LtEq => !lt(r, l),
+        BinaryOp::Gt => lt(r, l),
+        BinaryOp::GtEq => !lt(l, r),
+        _ => unreachable!("ill-typed operands of '{}'", op.as_str()),
+    };
+    Ok(ConstValue::Bool(result))
+}
+
+fn lt(l: &ConstValue, r: &ConstValue) -> bool {
+    match (l, r) {
+        (ConstValue::Int(a), ConstValue::Int(b)) => a < b,
+        (ConstValue::Float(a), ConstValue::Float(b)) => a < b,
+        (ConstValue::Char(a), ConstValue::Char(b)) => a < b,
+        (ConstValue::Str(a), ConstValue::Str(b)) => a < b,
+        // Tuples compare lexicographically.
+        (ConstValue::Tuple(a), ConstValue::Tuple(b)) => {
+            for (x, y) in a.iter().zip(b.iter()) {
+                if lt(x, y) {
+                    return true;
+                }
+                if lt(y, x) {
+                    return false;
+                }
+            }
+            false
+        }
+        _ => unreachable!("ill-typed operands of '<'"),
+    }
+}
+
+#[derive(Clone)]
+enum State {
+    InProgress,
+    Done(Option<ConstValue>),
+}
+
+pub struct ConstEval<'a> {
+    interner: &'a Interner,
+    res: &'a Resolutions,
+    types: &'a TypeckResults,
+    decls: HashMap<Symbol, (&'a ConstDecl, Span)>,
+    // Field names of every struct, in declaration order.
+    fields: HashMap<Symbol, Vec<Symbol>>,
+    state: HashMap<Symbol, State>,
+    // Constants being evaluated, innermost last.
+    stack: Vec<Symbol>,
+    pub values: HashMap<Symbol, ConstValue>,
+    pub errors: Vec<Diagnostic>,
+}
+
+impl<'a> ConstEval<'a> {
+    pub fn new(module: &'a Module, res: &'a Resolutions, types: &'a TypeckResults, interner: &'a Interner) -> ConstEval<'a> {
+        let mut decls = HashMap::new();
+        let mut fields = HashMap::new();
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Const(c) => {
+                    decls.insert(c.name.name, (c, c.name.span));
+                }
+                DeclKind::Struct(st) => {
+                    fields.insert(st.name.name, st.fields.iter().map(|f| f.name.name).collect());
+                }
+                _ => {}
+            }
+        }
+        ConstEval {
+            interner,
+            res,
+            types,
+            decls,
+            fields,
+            state: HashMap::new(),
+            stack: vec![],
+            values: HashMap::new(),
+            errors: vec![],
+        }
+    }
+
+    fn name(&self, sym: Symbol) -> &'a str {
+        self.interner.get(sym)
+    }
+
+    pub fn eval_module(&mut self, module: &Module) {
+        for decl in &module.decls {
+            if let DeclKind::Const(c) = &decl.kind {
+                self.eval_const(c.name.name);
+            }
+        }
+    }
+
+    // Value of a constant, evaluating the ones it refers to first. A constant
+    // whose evaluation failed has no value and isn't reported again.
+    fn eval_const(&mut self, name: Symbol) -> Option<ConstValue> {
+        match self.state.get(&name) {
+            Some(State::Done(value)) => return value.clone(),
+            Some(State::InProgress) => {
+                let start = self.stack.iter().position(|n| *n == name).unwrap();
+                let mut path: Vec<&str> = self.stack[start..].iter().map(|n| self.name(*n)).collect();
+                path.push(self.name(name));
+                let span = self.decls[&name].1;
+                self.errors.push(Diagnostic::error(span, format!("Cycle in constant definitions: {}", path.join(" -> "))));
+                // Every constant on the cycle fails without further errors.
+                for n in &self.stack[start..] {
+                    self.state.insert(*n, State::Done(None));
+                }
+                return None;
+            }
+            None => {}
+        }
+        let decl = self.decls[&name].0;
+        self.state.insert(name, State::InProgress);
+        self.stack.push(name);
+        let value = self.eval(&decl.expr);
+        self.stack.pop();
+        // Members of a cycle were already settled when it was found.
+        if let Some(State::InProgress) = self.state.get(&name) {
+            self.state.insert(name, State::Done(value.clone()));
+            if let Some(value) = &value {
+                self.values.insert(name, value.clone());
+            }
+            return value;
+        }
+        None
+    }
+
+    fn not_constant(&mut self, span: Span) -> Option<ConstValue> {
+        self.errors.push(
+            Diagnostic::error(span, "Cannot evaluate this expression at compile time")
+                .with_note("constants can use literals, operators, other constants, struct literals and constructors"),
+        );
+        None
+    }
+
+    fn fail(&mut self, span: Span, msg: &str) -> Option<ConstValue> {
+        self.errors.push(Diagnostic::error(span, format!("{} in constant expression", msg)));
+        None
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Option<ConstValue> {
+        // Operators on user types run their instance methods.
+        if self.types.overloads.contains_key(&expr.id) {
+            return self.not_constant(expr.span);
+        }
+        match &expr.kind {
+            ExprKind::Int(val) => match i64::try_from(*val) {
+                Ok(val) => Some(ConstValue::Int(val)),
+                Err(_) => self.fail(expr.span, OVERFLOW),
+            },
+            ExprKind::Float(val) => Some(ConstValue::Float(*val)),
+            ExprKind::Char(val) => Some(ConstValue::Char(*val)),
+            ExprKind::Str(val) => Some(ConstValue::Str(val.clone())),
+            ExprKind::Bool(val) => Some(ConstValue::Bool(*val)),
+            ExprKind::Template(parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    match part {
+                        TemplatePart::Str(val) => s.push_str(val),
+                        TemplatePart::Expr(e) => s.push_str(&show_value(&self.eval(e)?)),
+                    }
+                }
+                Some(ConstValue::Str(s))
+            }
+            ExprKind::Name(_) => {
+                let def = match self.res.names.get(&expr.id) {
+                    Some(id) => self.res.def(*id),
+                    None => return None,
+                };
+                match def.kind {
+                    DefKind::Const => self.eval_const(def.name.name),
+                    DefKind::Variant if self.variant_arity(expr) == Some(0) => Some(ConstValue::Variant(def.name.name, vec![])),
+                    _ => self.not_constant(expr.span),
+                }
+            }
+            // Constants of other modules aren't folded.
+            ExprKind::Path(_, _) => self.not_constant(expr.span),
+            ExprKind::Field(e, field) => match self.eval(e)? {
+                ConstValue::Struct(name, values) => {
+                    let index = self.fields[&name].iter().position(|f| *f == field.name)?;
+                    Some(values[index].clone())
+                }
+                _ => None,
+            },
+            _ if expr.is_min_int() => Some(ConstValue::Int(i64::MIN)),
+            ExprKind::Unary(op, e) => {
+                let value = self.eval(e)?;
+                match unary_op(*op, &value) {
+                    Ok(value) => Some(value),
+                    Err(msg) => self.fail(expr.span, msg),
+                }
+            }
+            ExprKind::Binary(op @ (BinaryOp::And | BinaryOp::Or), l, r) => {
+                let l = self.eval(l)?;
+                match (op, l) {
+                    (BinaryOp::And, ConstValue::Bool(false)) => Some(ConstValue::Bool(false)),
+                    (BinaryOp::Or, ConstValue::Bool(true)) => Some(ConstValue::Bool(true)),
+                    _ => self.eval(r),
+                }
+            }
+            ExprKind::Binary(op, l, r) => {
+                let l = self.eval(l);
+                let r = self.eval(r);
+                match binary_op(*op, &l?, &r?) {
+                    Ok(value) => Some(value),
+                    Err(msg) => self.fail(expr.span, msg),
+                }
+            }
+            ExprKind::Call(func, args) => {
+                let def = match &func.kind {
+                    ExprKind::Name(_) => self.res.names.get(&func.id).map(|id| self.res.def(*id)),
+                    _ => None,
+                };
+                match def {
+                    Some(def) if def.kind == DefKind::Variant && self.variant_arity(func) == Some(args.len()) => {
+                        let values: Vec<Option<ConstValue>> = args.iter().map(|arg| self.eval(arg)).collect();
+                        Some(ConstValue::Variant(def.name.name, values.into_iter().collect::<Option<_>>()?))
+                    }
+                    _ => self.not_constant(expr.span),
+                }
+            }
+            ExprKind::If(cond, then_expr, else_expr) => match self.eval(cond)? {
+                ConstValue::Bool(true) => self.eval(then_expr),
+                _ => self.eval(else_expr),
+            },
+            ExprKind::StructLit(lit) => {
+                let name = *self.types.struct_lits.get(&expr.id)?;
+                let values: Vec<Option<ConstValue>> = lit.fields.iter().map(|f| self.eval(&f.expr)).collect();
+                let mut values: Vec<ConstValue> = values.into_iter().collect::<Option<_>>()?;
+                if lit.is_named() && !lit.fields.is_empty() {
+                    // Put the fields back in declaration order.
+                    let order = &self.fields[&name];
+                    let mut named: Vec<(usize, ConstValue)> = lit
+                        .fields
+                        .iter()
+                        .zip(values)
+                        .map(|(f, v)| (order.iter().position(|n| *n == f.name.unwrap().name).unwrap_or(0), v))
+                        .collect();
+                    named.sort_by_key(|(i, _)| *i);
+                    values = named.into_iter().map(|(_, v)| v).collect();
+                }
+                Some(ConstValue::Struct(name, values))
+            }
+            ExprKind::Tuple(elems) => {
+                let values: Vec<Option<ConstValue>> = elems.iter().map(|e| self.eval(e)).collect();
+                Some(ConstValue::Tuple(values.into_iter().collect::<Option<_>>()?))
+            }
+            ExprKind::Let(_, _) | ExprKind::Lambda(_, _) | ExprKind::Case(_, _) => self.not_constant(expr.span),
+            ExprKind::Error => None,
+        }
+    }
+
+    // Number of fields of the variant a name refers to, from its type.
+    fn variant_arity(&self, expr: &Expr) -> Option<usize> {
+        let mut ty = self.types.node_types.get(&expr.id)?;
+        let mut arity = 0;
+        while let Type::Func(_, ret) = ty {
+            arity += 1;
+            ty = ret;
+        }
+        Some(arity)
+    }
+}
+
+// `errors` are the ones found so far in the module: a constant whose
+// initializer has one gets no value, like the constants referring to it.
+pub fn eval_consts(
+    module: &Module,
+    res: &Resolutions,
+    types: &TypeckResults,
+    errors: &[Diagnostic],
+    interner: &Interner,
+) -> (HashMap<Symbol, ConstValue>, Vec<Diagnostic>) {
+    let mut eval = ConstEval::new(module, res, types, interner);
+    for (name, (decl, _)) in &eval.decls {
+        let span = decl.expr.span;
+        if errors.iter().any(|err| span.start <= err.span.start && err.span.end <= span.end) {
+            eval.state.insert(*name, State::Done(None));
+        }
+    }
+    eval.eval_module(module);
+    (eval.values, eval.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_module;
+    use crate::resolve::resolve_module;
+    use crate::typeck::check_module;
+
+    fn eval(src: &str) -> (Vec<(String, ConstValue)>, Vec<Diagnostic>) {
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        assert!(errors.is_empty());
+        let (res, errors) = resolve_module(&module, &interner);
+        assert!(errors.is_empty());
+        let (types, errors) = check_module(&module, &res, &interner);
+        assert!(errors.is_empty());
+        let (values, errors) = eval_consts(&module, &res, &types, &[], &interner);
+        for err in &errors {
+            println!("{:?}", err);
+        }
+        let mut values: Vec<(String, ConstValue)> =
+            values.into_iter().map(|(name, value)| (String::from(interner.get(name)), value)).collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        (values, errors)
+    }
+
+    fn value<'a>(values: &'a [(String, ConstValue)], name: &str) -> &'a ConstValue {
+        &values.iter().find(|(n, _)| n == name).unwrap().1
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let (values, errors) = eval("
+struct Vector = x, y: float
+
+type Shape = Dot | Circle: float
+
+const TAU = PI * 2.0
+const PI = 3.5
+const BITS = (1 << 10) | 3 ^ 1
+const NAME = `tau is ${TAU}, ${(BITS, 'c')}`
+const ORIGIN = Vector {y = -PI, x = 0.0}
+const UNIT = Vector {ORIGIN.x + 1.0, 1.0}
+const BIG = if BITS > 1000 && !false then Circle UNIT.x else Dot
+");
+        assert!(errors.is_empty());
+        assert!(*value(&values, "TAU") == ConstValue::Float(7.0));
+        assert!(*value(&values, "BITS") == ConstValue::Int(1026));
+        assert!(*value(&values, "NAME") == ConstValue::Str(String::from("tau is 7.0, (1026, c)")));
+        match value(&values, "ORIGIN") {
+            ConstValue::Struct(_, fields) => assert!(*fields == vec![ConstValue::Float(0.0), ConstValue::Float(-3.5)]),
+            other => panic!("{:?}", other),
+        }
+        match value(&values, "BIG") {
+            ConstValue::Variant(_, args) => assert!(*args == vec![ConstValue::Float(1.0)]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        let (values, errors) = eval("
+const A = B + 1
+const B = C * 2
+const C = A
+const D = C + 1
+const MAX = 9223372036854775807
+const E = MAX + 1
+const F = 10 / (MAX - MAX)
+const G = 1.5 % 0.0
+const H = 1 << 64
+const I = (\\x -> x) 1
+const J = -9223372036854775808
+const K = false && 1 / 0 == 0
+
+f x: int -> int = x
+const L = f 1
+");
+        assert!(errors.len() == 7);
+        assert!(errors[0].msg == "Cycle in constant definitions: A -> B -> C -> A");
+        assert!(errors[1].msg == "Integer overflow in constant expression");
+        assert!(errors[2].msg == "Division by zero in constant expression");
+        assert!(errors[3].msg == "Division by zero in constant expression");
+        assert!(errors[4].msg == "Integer overflow in constant expression");
+        assert!(errors[5].msg == "Cannot evaluate this expression at compile time");
+        assert!(errors[6].msg == "Cannot evaluate this expression at compile time");
+        assert!(*value(&values, "J") == ConstValue::Int(i64::MIN));
+        assert!(*value(&values, "K") == ConstValue::Bool(false));
+        assert!(values.len() == 3);
+    }
+
+    #[test]
+    fn test_ill_typed_constants() {
+        let src = "
+const A = 1 + 1.0
+const B = \"foo\" + \"bar\"
+const C = -\"x\" < 1
+const D = A * 2
+const E = 3
+";
+        let mut interner = Interner::new();
+        let (module, _) = parse_module(src, &mut interner);
+        let (res, _) = resolve_module(&module, &interner);
+        let (types, type_errors) = check_module(&module, &res, &interner);
+        assert!(type_errors.len() >= 3);
+        let (values, errors) = eval_consts(&module, &res, &types, &type_errors, &interner);
+        assert!(errors.is_empty());
+        assert!(values.len() == 1 && values[&interner.lookup("E").unwrap()] == ConstValue::Int(3));
+    }
+}
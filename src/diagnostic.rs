@@ -0,0 +1,108 @@
+use crate::common::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub span: Span,
+    pub msg: String,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error<S: Into<String>>(span: Span, msg: S) -> Diagnostic {
+        Diagnostic {
+            level: Level::Error,
+            span,
+            msg: msg.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn warning<S: Into<String>>(span: Span, msg: S) -> Diagnostic {
+        Diagnostic {
+            level: Level::Warning,
+            span,
+            msg: msg.into(),
+            notes: vec![],
+        }
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+}
+
+pub fn has_errors(diags: &[Diagnostic]) -> bool {
+    diags.iter().any(|d| d.level == Level::Error)
+}
+
+// 1-based line and column (in chars) of a byte offset.
+pub fn line_col(src: &str, pos: usize) -> (usize, usize) {
+    let pos = pos.min(src.len());
+    let before = &src[..pos];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let col = src[line_start..pos].chars().count() + 1;
+    (line, col)
+}
+
+pub fn render(diag: &Diagnostic, path: &str, src: &str) -> String {
+    let level = match diag.level {
+        Level::Error => "error",
+        Level::Warning => "warning",
+    };
+    let (line, col) = line_col(src, diag.span.start);
+    let text = src.lines().nth(line - 1).unwrap_or("");
+    let gutter = " ".repeat(line.to_string().len());
+    let line_len = text.chars().count();
+    let width = src[diag.span.start.min(src.len())..diag.span.end.min(src.len())]
+        .chars()
+        .take_while(|c| *c != '\n')
+        .count()
+        .max(1)
+        .min(line_len + 1 - (col - 1).min(line_len));
+    let mut s = format!("{}: {}\n", level, diag.msg);
+    s.push_str(&format!("{}--> {}:{}:{}\n", gutter, path, line, col));
+    s.push_str(&format!("{} |\n", gutter));
+    s.push_str(&format!("{} | {}\n", line, text));
+    s.push_str(&format!("{} | {}{}\n", gutter, " ".repeat(col - 1), "^".repeat(width)));
+    for note in &diag.notes {
+        s.push_str(&format!("{} = note: {}\n", gutter, note));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let src = "a\nbc\n  d";
+        assert!(line_col(src, 0) == (1, 1));
+        assert!(line_col(src, 3) == (2, 2));
+        assert!(line_col(src, 7) == (3, 3));
+    }
+
+    #[test]
+    fn test_render() {
+        let src = "fact_rec n: int -> int\n    n * * 2\n";
+        let diag = Diagnostic::error(Span::new(31, 32), "Expected expression, found '*'").with_note("in function 'fact_rec'");
+        let s = render(&diag, "fact.sp", src);
+        println!("{}", s);
+        assert!(s == "error: Expected expression, found '*'
+ --> fact.sp:2:9
+  |
+2 |     n * * 2
+  |         ^
+  = note: in function 'fact_rec'
+");
+    }
+}
@@ -0,0 +1,305 @@
+use crate::x86::Reloc;
+
+// Writes ELF64 relocatable objects for x86-64, the `.o` files the system
+// linker takes. The object has code, read-only data and thread-local data
+// sections; relocations of the code refer to the functions, to the
+// sections, to the thread-local variables or to functions of other objects
+// by name.
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+const SHF_TLS: u64 = 0x400;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_TLS: u8 = 6;
+
+const R_X86_64_PC32: u64 = 2;
+const R_X86_64_PLT32: u64 = 4;
+const R_X86_64_GOTTPOFF: u64 = 22;
+
+const EM_X86_64: u16 = 62;
+const ET_REL: u16 = 1;
+
+// Sections with contents, in order after the null section. Symbols of the
+// first three stand for their address in relocations.
+const TEXT: u32 = 1;
+const RODATA: u32 = 2;
+const TBSS: u32 = 3;
+#[cfg(test)]
+const RELA_TEXT: u32 = 4;
+const SYMTAB: u32 = 5;
+const STRTAB: u32 = 6;
+const SHSTRTAB: u32 = 7;
+const SECTIONS: [&str; 8] = [
+    ".text",
+    ".rodata",
+    ".tbss",
+    ".rela.text",
+    ".symtab",
+    ".strtab",
+    ".shstrtab",
+    // Marks the stack as not executable.
+    ".note.GNU-stack",
+];
+
+// A global function: name, offset in `.text` and size. A weak one gives way
+// to a function of the same name in another object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    pub weak: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub rodata: Vec<u8>,
+    // Thread-local variables of 8 bytes, zero initialized.
+    pub tls: Vec<String>,
+    pub functions: Vec<Function>,
+    pub relocs: Vec<Reloc>,
+}
+
+struct StrTab {
+    bytes: Vec<u8>,
+}
+
+impl StrTab {
+    fn new() -> StrTab {
+        StrTab { bytes: vec![0] }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+fn u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn u64(out: &mut Vec<u8>, val: u64) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+fn align(out: &mut Vec<u8>, to: usize) {
+    while !out.len().is_multiple_of(to) {
+        out.push(0);
+    }
+}
+
+fn symbol(out: &mut Vec<u8>, name: u32, info: u8, section: u32, value: u64, size: u64) {
+    u32(out, name);
+    out.push(info);
+    out.push(0);
+    u16(out, section as u16);
+    u64(out, value);
+    u64(out, size);
+}
+
+// Symbols that relocations name but the object doesn't define are left to
+// the linker to find in other objects.
+pub fn write_object(obj: &Object) -> Vec<u8> {
+    let mut strtab = StrTab::new();
+    let mut symtab = vec![0; 24];
+    for section in [TEXT, RODATA, TBSS] {
+        symbol(&mut symtab, 0, STT_SECTION, section, 0, 0);
+    }
+    for (i, var) in obj.tls.iter().enumerate() {
+        let name = strtab.add(var);
+        symbol(&mut symtab, name, STB_LOCAL << 4 | STT_TLS, TBSS, 8 * i as u64, 8);
+    }
+    let first_global = 4 + obj.tls.len();
+    for func in &obj.functions {
+        let name = strtab.add(&func.name);
+        let bind = if func.weak { STB_WEAK } else { STB_GLOBAL };
+        symbol(&mut symtab, name, bind << 4 | STT_FUNC, TEXT, func.offset as u64, func.size as u64);
+    }
+    let mut external: Vec<&str> = vec![];
+    let mut rela = vec![];
+    for reloc in &obj.relocs {
+        // Calls go through the procedure linkage table, so that functions
+        // may come from shared libraries, and thread-local variables are
+        // found through the offset the loader stores in the global offset
+        // table.
+        let (index, kind) = match reloc.symbol.as_str() {
+            ".text" => (TEXT as usize, R_X86_64_PC32),
+            ".rodata" => (RODATA as usize, R_X86_64_PC32),
+            name => match (obj.tls.iter().position(|v| v == name), obj.functions.iter().position(|f| f.name == name)) {
+                (Some(i), _) => (4 + i, R_X86_64_GOTTPOFF),
+                (None, Some(i)) => (first_global + i, R_X86_64_PLT32),
+                (None, None) => {
+                    let i = external.iter().position(|e| *e == name).unwrap_or_else(|| {
+                        let name_at = strtab.add(name);
+                        symbol(&mut symtab, name_at, STB_GLOBAL << 4 | STT_NOTYPE, 0, 0, 0);
+                        external.push(name);
+                        external.len() - 1
+                    });
+                    (first_global + obj.functions.len() + i, R_X86_64_PLT32)
+                }
+            },
+        };
+        u64(&mut rela, reloc.offset as u64);
+        u64(&mut rela, (index as u64) << 32 | kind);
+        u64(&mut rela, reloc.addend as u64);
+    }
+    let mut shstrtab = StrTab::new();
+    let names: Vec<u32> = SECTIONS.iter().map(|name| shstrtab.add(name)).collect();
+
+    let mut out = vec![0; 64];
+    let mut offsets = vec![];
+    for (contents, alignment) in [
+        (&obj.text, 16),
+        (&obj.rodata, 1),
+        (&vec![], 8),
+        (&rela, 8),
+        (&symtab, 8),
+        (&strtab.bytes, 1),
+        (&shstrtab.bytes, 1),
+    ] {
+        align(&mut out, alignment);
+        offsets.push((out.len(), contents.len()));
+        out.extend_from_slice(contents);
+    }
+    offsets.push((out.len(), 0));
+    // Takes no room in the file.
+    offsets[TBSS as usize - 1].1 = 8 * obj.tls.len();
+    align(&mut out, 8);
+    let section_headers = out.len();
+
+    // The null section.
+    out.extend_from_slice(&[0; 64]);
+    let headers = [
+        (SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, 0, 0, 16, 0),
+        (SHT_PROGBITS, SHF_ALLOC, 0, 0, 1, 0),
+        (SHT_NOBITS, SHF_ALLOC | SHF_WRITE | SHF_TLS, 0, 0, 8, 0),
+        (SHT_RELA, SHF_INFO_LINK, SYMTAB, TEXT, 8, 24),
+        (SHT_SYMTAB, 0, STRTAB, first_global as u32, 8, 24),
+        (SHT_STRTAB, 0, 0, 0, 1, 0),
+        (SHT_STRTAB, 0, 0, 0, 1, 0),
+        (SHT_PROGBITS, 0, 0, 0, 1, 0),
+    ];
+    for (i, &(kind, flags, link, info, alignment, entsize)) in headers.iter().enumerate() {
+        u32(&mut out, names[i]);
+        u32(&mut out, kind);
+        u64(&mut out, flags);
+        u64(&mut out, 0);
+        u64(&mut out, offsets[i].0 as u64);
+        u64(&mut out, offsets[i].1 as u64);
+        u32(&mut out, link);
+        u32(&mut out, info);
+        u64(&mut out, alignment);
+        u64(&mut out, entsize);
+    }
+
+    let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    header.resize(16, 0);
+    u16(&mut header, ET_REL);
+    u16(&mut header, EM_X86_64);
+    u32(&mut header, 1);
+    // No entry point and no program headers.
+    u64(&mut header, 0);
+    u64(&mut header, 0);
+    u64(&mut header, section_headers as u64);
+    u32(&mut header, 0);
+    u16(&mut header, 64);
+    u16(&mut header, 0);
+    u16(&mut header, 0);
+    u16(&mut header, 64);
+    u16(&mut header, SECTIONS.len() as u16 + 1);
+    u16(&mut header, SHSTRTAB as u16);
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    fn read_u64(bytes: &[u8], at: usize) -> u64 {
+        read_u32(bytes, at) as u64 | (read_u32(bytes, at + 4) as u64) << 32
+    }
+
+    #[test]
+    fn test_object_layout() {
+        let obj = Object {
+            text: vec![0xc3, 0xc3],
+            rodata: b"hi".to_vec(),
+            tls: vec![String::from("depth")],
+            functions: vec![
+                Function { name: String::from("first"), offset: 0, size: 1, weak: false },
+                Function { name: String::from("second"), offset: 1, size: 1, weak: true },
+            ],
+            relocs: vec![
+                Reloc { offset: 1, symbol: String::from("second"), addend: -4 },
+                Reloc { offset: 1, symbol: String::from("exit"), addend: -4 },
+                Reloc { offset: 1, symbol: String::from(".rodata"), addend: 0 },
+                Reloc { offset: 1, symbol: String::from("depth"), addend: -4 },
+            ],
+        };
+        let bytes = write_object(&obj);
+        assert!(bytes[..7] == [0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        assert!(read_u16(&bytes, 16) == ET_REL && read_u16(&bytes, 18) == EM_X86_64);
+        let shoff = read_u64(&bytes, 40) as usize;
+        assert!(read_u16(&bytes, 60) == 9 && read_u16(&bytes, 62) == 7);
+        assert!(bytes.len() == shoff + 9 * 64);
+        // Section names, through the section name table.
+        let shstrtab = shoff + SHSTRTAB as usize * 64;
+        let (names_at, names_len) = (read_u64(&bytes, shstrtab + 24) as usize, read_u64(&bytes, shstrtab + 32) as usize);
+        let names = &bytes[names_at..names_at + names_len];
+        for (i, section) in SECTIONS.iter().enumerate() {
+            let header = shoff + (i + 1) * 64;
+            let name = read_u32(&bytes, header) as usize;
+            let end = names[name..].iter().position(|b| *b == 0).unwrap();
+            assert!(&names[name..name + end] == section.as_bytes());
+        }
+        let text = read_u64(&bytes, shoff + 64 + 24) as usize;
+        assert!(text.is_multiple_of(16) && bytes[text..text + 2] == [0xc3, 0xc3]);
+        // The thread-local section takes no room in the file.
+        let tbss = shoff + TBSS as usize * 64;
+        assert!(read_u32(&bytes, tbss + 4) == SHT_NOBITS && read_u64(&bytes, tbss + 32) == 8);
+        // The relocations refer to the second global symbol, to the
+        // undefined one after it, to a section and to the thread-local
+        // variable.
+        let rela = read_u64(&bytes, shoff + RELA_TEXT as usize * 64 + 24) as usize;
+        assert!(read_u64(&bytes, rela + 8) == (6 << 32 | R_X86_64_PLT32));
+        assert!(read_u64(&bytes, rela + 16) as i64 == -4);
+        assert!(read_u64(&bytes, rela + 32) == (7 << 32 | R_X86_64_PLT32));
+        assert!(read_u64(&bytes, rela + 56) == (RODATA as u64) << 32 | R_X86_64_PC32);
+        assert!(read_u64(&bytes, rela + 80) == (4 << 32 | R_X86_64_GOTTPOFF));
+        let symtab = read_u64(&bytes, shoff + SYMTAB as usize * 64 + 24) as usize;
+        assert!(bytes[symtab + 4 * 24 + 4] == STB_LOCAL << 4 | STT_TLS);
+        assert!(bytes[symtab + 6 * 24 + 4] == STB_WEAK << 4 | STT_FUNC);
+        assert!(bytes[symtab + 7 * 24 + 4] == STB_GLOBAL << 4 | STT_NOTYPE && read_u16(&bytes, symtab + 7 * 24 + 6) == 0);
+    }
+}
@@ -0,0 +1,706 @@
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::diagnostic::Diagnostic;
+use crate::parser::parse_module;
+use crate::sexpr::module_to_sexpr;
+
+// Source formatter behind `sp fmt`. Declarations are printed back from the
+// AST with blocks indented by four spaces. The lexer drops comments, so a
+// declaration holding one is kept as written, and the comments between
+// declarations are kept in place.
+
+// Constructs that can go either on one line or in a block stay on one line
+// when it is at most this long.
+const WIDTH: usize = 100;
+
+fn pad(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+fn tuple(elems: Vec<String>) -> String {
+    if elems.len() == 1 {
+        format!("({},)", elems[0])
+    } else {
+        format!("({})", elems.join(", "))
+    }
+}
+
+// `if`, `let`, lambdas and `case` extend as far right as they can, so they
+// are parenthesized when something follows them.
+fn is_open(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::If(..) | ExprKind::Let(..) | ExprKind::Lambda(..) | ExprKind::Case(..))
+}
+
+fn is_atom(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Char(_) | ExprKind::Str(_) | ExprKind::Bool(_)
+            | ExprKind::Template(_) | ExprKind::Name(_) | ExprKind::Path(..) | ExprKind::Field(..)
+            | ExprKind::StructLit(_) | ExprKind::Tuple(_)
+    )
+}
+
+// Operands of an arithmetic operator written without spaces in an argument,
+// as in `fact_rec n-1`.
+fn is_tight_operand(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Name(_) | ExprKind::Path(..) | ExprKind::Int(_) | ExprKind::Float(_) => true,
+        ExprKind::Field(base, _) => is_tight_operand(base),
+        _ => false,
+    }
+}
+
+fn is_type_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+struct Printer<'a> {
+    src: &'a str,
+    interner: &'a Interner,
+}
+
+impl<'a> Printer<'a> {
+    fn name(&self, name: Symbol) -> &'a str {
+        self.interner.get(name)
+    }
+
+    // Literals are printed as written, keeping their escapes and bases. The
+    // span of a parenthesized literal covers the parentheses.
+    fn literal(&self, span: Span) -> &'a str {
+        let mut text = self.src[span.start..span.end].trim();
+        while text.starts_with('(') && text.ends_with(')') {
+            text = text[1..text.len() - 1].trim();
+        }
+        text
+    }
+
+    fn ty(&self, ty: &TypeExpr) -> String {
+        match &ty.kind {
+            TypeExprKind::Name(name) => String::from(self.name(*name)),
+            TypeExprKind::App(name, args) => {
+                let mut s = String::from(self.name(*name));
+                for arg in args {
+                    s.push(' ');
+                    s.push_str(&self.ty_atom(arg));
+                }
+                s
+            }
+            TypeExprKind::Func(param, ret) => {
+                let param = match param.kind {
+                    TypeExprKind::Func(..) => format!("({})", self.ty(param)),
+                    _ => self.ty(param),
+                };
+                format!("{} -> {}", param, self.ty(ret))
+            }
+            TypeExprKind::Tuple(elems) => tuple(elems.iter().map(|e| self.ty(e)).collect()),
+        }
+    }
+
+    fn ty_atom(&self, ty: &TypeExpr) -> String {
+        match ty.kind {
+            TypeExprKind::App(..) | TypeExprKind::Func(..) => format!("({})", self.ty(ty)),
+            _ => self.ty(ty),
+        }
+    }
+
+    fn pattern(&self, pattern: &Pattern) -> String {
+        match &pattern.kind {
+            PatternKind::Variant(name, args) if !args.is_empty() => {
+                let mut s = String::from(self.name(name.name));
+                for arg in args {
+                    s.push(' ');
+                    s.push_str(&self.pattern_atom(arg));
+                }
+                s
+            }
+            _ => self.pattern_atom(pattern),
+        }
+    }
+
+    fn pattern_atom(&self, pattern: &Pattern) -> String {
+        match &pattern.kind {
+            PatternKind::Wildcard => String::from("_"),
+            PatternKind::Binding(name) => String::from(self.name(*name)),
+            PatternKind::Int(val) if *val < 0 => {
+                format!("-{}", self.literal(pattern.span).trim_start_matches('-').trim_start())
+            }
+            PatternKind::Int(_) | PatternKind::Char(_) | PatternKind::Str(_) => String::from(self.literal(pattern.span)),
+            PatternKind::Bool(val) => format!("{}", val),
+            PatternKind::Variant(name, args) if args.is_empty() => String::from(self.name(name.name)),
+            PatternKind::Variant(..) => format!("({})", self.pattern(pattern)),
+            PatternKind::Tuple(elems) => tuple(elems.iter().map(|e| self.pattern(e)).collect()),
+            PatternKind::Struct(name, fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| match &field.name {
+                        Some(name) => format!("{} = {}", self.name(name.name), self.pattern(&field.pattern)),
+                        None => self.pattern(&field.pattern),
+                    })
+                    .collect();
+                match name {
+                    Some(name) => format!("{} {{{}}}", self.name(name.name), fields.join(", ")),
+                    None => format!("{{{}}}", fields.join(", ")),
+                }
+            }
+        }
+    }
+
+    // An expression on a single line, except for the arms of a `case`, which
+    // go in a block under the line indented by `indent`.
+    fn expr(&self, expr: &Expr, indent: usize) -> String {
+        match &expr.kind {
+            ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Char(_) | ExprKind::Str(_) | ExprKind::Template(_) => {
+                String::from(self.literal(expr.span))
+            }
+            ExprKind::Bool(val) => format!("{}", val),
+            ExprKind::Name(name) => String::from(self.name(*name)),
+            ExprKind::Path(module, member) => format!("{}::{}", self.name(module.name), self.name(member.name)),
+            ExprKind::Field(base, field) => {
+                let base = match base.kind {
+                    ExprKind::Int(_) | ExprKind::Float(_) => format!("({})", self.expr(base, indent)),
+                    _ if is_atom(base) => self.expr(base, indent),
+                    _ => format!("({})", self.expr(base, indent)),
+                };
+                format!("{}.{}", base, self.name(field.name))
+            }
+            ExprKind::Unary(op, operand) => format!("{}{}", op.as_str(), self.atom(operand, indent)),
+            ExprKind::Binary(op, lhs, rhs) => format!(
+                "{} {} {}",
+                self.operand(lhs, op.precedence(), indent),
+                op.as_str(),
+                self.operand(rhs, op.precedence() + 1, indent)
+            ),
+            ExprKind::Call(func, args) => {
+                let mut s = match &func.kind {
+                    ExprKind::Unary(..) => self.expr(func, indent),
+                    _ => self.atom(func, indent),
+                };
+                let type_head = matches!(&func.kind, ExprKind::Name(name) if is_type_name(self.name(*name)));
+                for (i, arg) in args.iter().enumerate() {
+                    s.push(' ');
+                    // `Some {a, b}` would read as a struct literal.
+                    if i == 0 && type_head && matches!(&arg.kind, ExprKind::StructLit(lit) if lit.name.is_none()) {
+                        s.push_str(&format!("({})", self.expr(arg, indent)));
+                    } else {
+                        s.push_str(&self.arg(arg, indent));
+                    }
+                }
+                s
+            }
+            ExprKind::If(cond, then_expr, else_expr) => format!(
+                "if {} then {} else {}",
+                self.closed(cond, indent),
+                self.closed(then_expr, indent),
+                self.expr(else_expr, indent)
+            ),
+            ExprKind::Let(bindings, body) => {
+                let bindings: Vec<String> = bindings.iter().map(|b| format!("{} {}", self.binding_head(b), self.expr(&b.expr, indent))).collect();
+                format!("let {} in {}", bindings.join(", "), self.expr(body, indent))
+            }
+            ExprKind::StructLit(lit) => {
+                let fields: Vec<String> = lit
+                    .fields
+                    .iter()
+                    .map(|field| match &field.name {
+                        Some(name) => format!("{} = {}", self.name(name.name), self.expr(&field.expr, indent)),
+                        None => self.expr(&field.expr, indent),
+                    })
+                    .collect();
+                match &lit.name {
+                    Some(name) => format!("{} {{{}}}", self.name(name.name), fields.join(", ")),
+                    None => format!("{{{}}}", fields.join(", ")),
+                }
+            }
+            ExprKind::Tuple(elems) => tuple(elems.iter().map(|e| self.expr(e, indent)).collect()),
+            ExprKind::Lambda(params, body) => {
+                let params: Vec<String> = params.iter().map(|p| self.pattern_atom(p)).collect();
+                format!("\\{} -> {}", params.join(" "), self.expr(body, indent))
+            }
+            ExprKind::Case(scrutinee, arms) => {
+                let mut s = format!("case {} of", self.closed(scrutinee, indent));
+                for arm in arms {
+                    let head = format!("{} ->", self.pattern(&arm.pattern));
+                    let used = indent + 4 + head.len();
+                    s.push_str(&format!("\n{}{}{}", pad(indent + 4), head, self.tail(&arm.body, used, indent + 4)));
+                }
+                s
+            }
+            ExprKind::Error => String::new(),
+        }
+    }
+
+    fn closed(&self, expr: &Expr, indent: usize) -> String {
+        if is_open(expr) {
+            format!("({})", self.expr(expr, indent))
+        } else {
+            self.expr(expr, indent)
+        }
+    }
+
+    fn atom(&self, expr: &Expr, indent: usize) -> String {
+        if is_atom(expr) {
+            self.expr(expr, indent)
+        } else {
+            format!("({})", self.expr(expr, indent))
+        }
+    }
+
+    fn operand(&self, expr: &Expr, min_prec: u8, indent: usize) -> String {
+        match &expr.kind {
+            ExprKind::Binary(op, ..) if op.precedence() < min_prec => format!("({})", self.expr(expr, indent)),
+            _ => self.closed(expr, indent),
+        }
+    }
+
+    // Arguments are atoms, unary operators written tight such as `-1`, or
+    // tight arithmetic.
+    fn arg(&self, expr: &Expr, indent: usize) -> String {
+        match &expr.kind {
+            ExprKind::Unary(..) => self.expr(expr, indent),
+            // `Cons (Pair {x, y}) xs` rather than two arguments at a glance.
+            ExprKind::StructLit(StructLit { name: Some(_), .. }) => format!("({})", self.expr(expr, indent)),
+            ExprKind::Binary(op, lhs, rhs) if op.precedence() >= 4 && is_tight_operand(lhs) && is_tight_operand(rhs) => {
+                format!("{}{}{}", self.expr(lhs, indent), op.as_str(), self.expr(rhs, indent))
+            }
+            _ => self.atom(expr, indent),
+        }
+    }
+
+    fn binding_head(&self, binding: &LetBinding) -> String {
+        match &binding.ty {
+            Some(ty) => format!("{}: {} =", self.pattern(&binding.pattern), self.ty(ty)),
+            None => format!("{} =", self.pattern(&binding.pattern)),
+        }
+    }
+
+    // What follows an `=` or `->` ending a line already `used` long: the
+    // expression on the same line when it fits, otherwise an indented block.
+    fn tail(&self, expr: &Expr, used: usize, indent: usize) -> String {
+        let inline = self.expr(expr, indent);
+        let fits = !inline.contains('\n') && used + 1 + inline.len() <= WIDTH;
+        match expr.kind {
+            ExprKind::If(..) | ExprKind::Let(..) if !fits => format!("\n{}{}", pad(indent + 4), self.stmt(expr, indent + 4)),
+            _ => format!(" {}", inline),
+        }
+    }
+
+    // An expression starting a line indented by `indent`, such as a function
+    // body. `if` and `let` are laid out in blocks.
+    fn stmt(&self, expr: &Expr, indent: usize) -> String {
+        match &expr.kind {
+            ExprKind::If(cond, then_expr, else_expr) => {
+                let mut s = format!(
+                    "if {}\n{}{}\n{}else",
+                    self.closed(cond, indent),
+                    pad(indent + 4),
+                    self.stmt(then_expr, indent + 4),
+                    pad(indent)
+                );
+                if let ExprKind::If(..) = else_expr.kind {
+                    s.push(' ');
+                    s.push_str(&self.stmt(else_expr, indent));
+                } else {
+                    s.push_str(&format!("\n{}{}", pad(indent + 4), self.stmt(else_expr, indent + 4)));
+                }
+                s
+            }
+            ExprKind::Let(bindings, body) => {
+                let mut s = String::from("let");
+                for binding in bindings {
+                    let head = self.binding_head(binding);
+                    let used = indent + 4 + head.len();
+                    s.push_str(&format!("\n{}{}{}", pad(indent + 4), head, self.tail(&binding.expr, used, indent + 4)));
+                }
+                s.push_str(&format!("\n{}in\n{}{}", pad(indent), pad(indent), self.stmt(body, indent)));
+                s
+            }
+            ExprKind::Lambda(params, body) => {
+                let params: Vec<String> = params.iter().map(|p| self.pattern_atom(p)).collect();
+                let head = format!("\\{} ->", params.join(" "));
+                let used = indent + head.len();
+                format!("{}{}", head, self.tail(body, used, indent))
+            }
+            _ => self.expr(expr, indent),
+        }
+    }
+
+    // `x, y: int f: (int -> int)`, consecutive parameters of the same type
+    // share their annotation.
+    fn params(&self, params: &[Param]) -> String {
+        let mut groups: Vec<(Vec<String>, Option<String>, bool)> = vec![];
+        for param in params {
+            let ty = param.ty.as_ref().map(|ty| match ty.kind {
+                TypeExprKind::Func(..) => format!("({})", self.ty(ty)),
+                _ => self.ty(ty),
+            });
+            let pattern = self.pattern_atom(&param.pattern);
+            match groups.last_mut() {
+                Some((names, last, _)) if *last == ty => names.push(pattern),
+                _ => {
+                    let is_app = matches!(param.ty.as_ref().map(|ty| &ty.kind), Some(TypeExprKind::App(..)));
+                    groups.push((vec![pattern], ty, is_app));
+                }
+            }
+        }
+        let mut s = String::new();
+        let mut after_app = false;
+        for (names, ty, is_app) in groups {
+            // The arguments of `List a` would take a following parameter.
+            s.push_str(if after_app { ", " } else { " " });
+            s.push_str(&names.join(", "));
+            if let Some(ty) = ty {
+                s.push_str(": ");
+                s.push_str(&ty);
+            }
+            after_app = is_app;
+        }
+        s
+    }
+
+    fn func(&self, func: &FuncDecl, indent: usize) -> String {
+        let mut s = format!("{}{}", self.name(func.name.name), self.params(&func.params));
+        match &func.ret {
+            Some(ret) => format!("{} -> {}\n{}{}", s, self.ty(ret), pad(indent + 4), self.stmt(&func.body, indent + 4)),
+            None => {
+                s.push_str(" =");
+                let used = indent + s.len();
+                s.push_str(&self.tail(&func.body, used, indent));
+                s
+            }
+        }
+    }
+
+    // Type, struct and export declarations keep their layout, on one line or
+    // one item per line, unless one line gets too long.
+    fn items(&self, head: String, items: Vec<String>, sep: &str, multiline: bool) -> String {
+        let inline = format!("{} {}", head, items.join(sep));
+        if !multiline && inline.len() <= WIDTH {
+            return inline;
+        }
+        let mut s = head;
+        for item in items {
+            s.push_str("\n    ");
+            s.push_str(&item);
+        }
+        s
+    }
+
+    fn type_head(&self, keyword: &str, name: &Ident, params: &[Ident]) -> String {
+        let mut s = format!("{} {}", keyword, self.name(name.name));
+        for param in params {
+            s.push(' ');
+            s.push_str(self.name(param.name));
+        }
+        s.push_str(" =");
+        s
+    }
+
+    fn decl(&self, decl: &Decl) -> String {
+        let multiline = self.src[decl.span.start..decl.span.end].contains('\n');
+        match &decl.kind {
+            DeclKind::Const(c) => {
+                let mut s = format!("const {}", self.name(c.name.name));
+                if let Some(ty) = &c.ty {
+                    s.push_str(&format!(": {}", self.ty(ty)));
+                }
+                s.push_str(" =");
+                let used = s.len();
+                s.push_str(&self.tail(&c.expr, used, 0));
+                s
+            }
+            DeclKind::Type(t) => {
+                let variants = t
+                    .variants
+                    .iter()
+                    .map(|v| {
+                        let fields: Vec<String> = v.fields.iter().map(|f| self.ty(f)).collect();
+                        if fields.is_empty() {
+                            String::from(self.name(v.name.name))
+                        } else {
+                            format!("{}: {}", self.name(v.name.name), fields.join(", "))
+                        }
+                    })
+                    .collect();
+                self.items(self.type_head("type", &t.name, &t.params), variants, " | ", multiline)
+            }
+            DeclKind::Struct(st) => {
+                let mut groups: Vec<(Vec<&str>, String)> = vec![];
+                for field in &st.fields {
+                    let ty = self.ty(&field.ty);
+                    match groups.last_mut() {
+                        Some((names, last)) if *last == ty => names.push(self.name(field.name.name)),
+                        _ => groups.push((vec![self.name(field.name.name)], ty)),
+                    }
+                }
+                let groups = groups.into_iter().map(|(names, ty)| format!("{}: {}", names.join(", "), ty)).collect();
+                self.items(self.type_head("struct", &st.name, &st.params), groups, ", ", multiline)
+            }
+            DeclKind::Func(func) => self.func(func, 0),
+            DeclKind::Instance(inst) => {
+                let mut s = format!("instance {} {} =", self.name(inst.trait_name.name), self.ty(&inst.ty));
+                for method in &inst.methods {
+                    s.push_str("\n    ");
+                    s.push_str(&self.func(method, 4));
+                }
+                s
+            }
+            DeclKind::Import(import) => {
+                let path: Vec<&str> = import.path.iter().map(|p| self.name(p.name)).collect();
+                format!("import {}", path.join("::"))
+            }
+            DeclKind::Export(export) => {
+                let names = export.names.iter().map(|n| String::from(self.name(n.name))).collect();
+                self.items(String::from("export ="), names, ", ", multiline)
+            }
+            DeclKind::Expr(expr) => self.stmt(expr, 0),
+            DeclKind::Error => String::from(self.src[decl.span.start..decl.span.end].trim_end()),
+        }
+    }
+}
+
+// Spans of the comments of a file, found again by skipping over string,
+// char and template literals.
+pub fn comments(src: &str) -> Vec<Span> {
+    let bytes = src.as_bytes();
+    let mut spans = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            quote @ (b'"' | b'\'' | b'`') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != quote && bytes[i] != b'\n' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                let end = src[i..].find('\n').map_or(src.len(), |n| i + n);
+                spans.push(Span::new(i, end));
+                i = end;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = src[i + 2..].find("*/").map_or(src.len(), |n| i + n + 4);
+                spans.push(Span::new(i, end));
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    spans
+}
+
+fn line_start(src: &str, pos: usize) -> usize {
+    src[..pos].rfind('\n').map_or(0, |i| i + 1)
+}
+
+fn line_end(src: &str, pos: usize) -> usize {
+    src[pos..].find('\n').map_or(src.len(), |i| pos + i)
+}
+
+// Copies the comments between two declarations, with at most two blank lines
+// in a row. `text` runs from the end of the last line printed to the start of
+// the next one.
+fn push_gap(out: &mut String, text: &str, first: bool, last: bool) {
+    let mut lines: Vec<&str> = text.split('\n').map(|l| l.trim_end()).collect();
+    if !first {
+        lines.remove(0);
+    }
+    lines.pop();
+    let mut blank = 0;
+    for line in lines {
+        if line.is_empty() {
+            blank += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(&"\n".repeat(blank.min(2)));
+        }
+        out.push_str(line);
+        out.push('\n');
+        blank = 0;
+    }
+    if !last && !out.is_empty() {
+        out.push_str(&"\n".repeat(blank.min(2)));
+    }
+}
+
+pub fn format_module(src: &str, module: &Module, interner: &Interner) -> String {
+    let printer = Printer { src, interner };
+    let comments = comments(src);
+    let mut out = String::new();
+    let mut pos = 0;
+    for decl in &module.decls {
+        if decl.span.start < pos {
+            // Shares its line with the end of a comment kept with the
+            // declaration before it.
+            let end = line_end(src, decl.span.end.max(pos));
+            out.pop();
+            out.push_str(src[pos..end].trim_end());
+            out.push('\n');
+            pos = end;
+            continue;
+        }
+        let start = line_start(src, decl.span.start);
+        let mut end = line_end(src, decl.span.end);
+        let mut verbatim = false;
+        for comment in &comments {
+            if comment.start >= start && comment.start < end {
+                verbatim = true;
+                end = end.max(line_end(src, comment.end));
+            }
+        }
+        push_gap(&mut out, &src[pos..start], pos == 0, false);
+        if verbatim {
+            out.push_str(src[start..end].trim_end());
+        } else {
+            out.push_str(&printer.decl(decl));
+        }
+        out.push('\n');
+        pos = end;
+    }
+    push_gap(&mut out, &src[pos..], pos == 0, true);
+    out
+}
+
+// Formats a file. Files with syntax errors are left alone, and so are the
+// ones the formatter would change the meaning of, which is a bug.
+pub fn format_source(src: &str, interner: &mut Interner) -> Result<String, Vec<Diagnostic>> {
+    let (module, errors) = parse_module(src, interner);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let out = format_module(src, &module, interner);
+    let (formatted, errors) = parse_module(&out, interner);
+    if !errors.is_empty() || module_to_sexpr(&formatted, interner) != module_to_sexpr(&module, interner) {
+        return Err(vec![Diagnostic::error(Span::default(), "The formatted file would not parse the same, it was left unchanged")]);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(src: &str) -> String {
+        let mut interner = Interner::new();
+        let out = format_source(src, &mut interner).unwrap();
+        println!("{}", out);
+        assert!(format_source(&out, &mut interner).unwrap() == out);
+        out
+    }
+
+    #[test]
+    fn test_layout() {
+        let src = "
+fact_rec   n : int->int
+  if n==0 then 1 else n * fact_rec(n - 1)
+
+
+add_one x: int -> int = let y: int = 1 in x+y
+
+twice f: (int -> int) x: int -> int = f (f x)
+
+first xs: List a, n: int -> Maybe a
+    case xs of
+      Cons x _ -> if n == 0 then Some x else first xs (n-1)
+      Nil -> None
+
+type Shape = Circle: float | Rect: float, float
+type TokenKind =
+  FLOAT
+  INT
+struct Vector = x: float, y: float
+instance Num Vector =
+  add a , b = Vector {a.x + b.x, a.y + b.y}
+  neg v = Vector {-v.x, -v.y}
+const LIMIT: int = 1 << 10
+export = fact_rec,add_one
+print (\\x y -> x + y) (-(fact_rec 3)) (1, 2).first (a ? b : c) (Some ({1, 2})) Vector {1.0, 2.0}
+";
+        let out = format(src);
+        assert!(out == "fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+
+add_one x: int -> int
+    let
+        y: int = 1
+    in
+    x + y
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+first xs: List a, n: int -> Maybe a
+    case xs of
+        Cons x _ -> if n == 0 then Some x else first xs n-1
+        Nil -> None
+
+type Shape = Circle: float | Rect: float, float
+type TokenKind =
+    FLOAT
+    INT
+struct Vector = x, y: float
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    neg v = Vector {-v.x, -v.y}
+const LIMIT: int = 1 << 10
+export = fact_rec, add_one
+print (\\x y -> x + y) -(fact_rec 3) (1, 2).first (if a then b else c) (Some ({1, 2})) (Vector {1.0, 2.0})
+");
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = "/*
+* Header
+*/
+
+const PI = 3.14 // kept as written
+// Before main
+main   =   greet  \"x // not a comment\"
+f x: int -> int
+    // inside
+    x  +  1
+
+
+// Trailing
+";
+        let out = format(src);
+        assert!(out == "/*
+* Header
+*/
+
+const PI = 3.14 // kept as written
+// Before main
+main = greet \"x // not a comment\"
+f x: int -> int
+    // inside
+    x  +  1
+
+
+// Trailing
+");
+    }
+
+    #[test]
+    fn test_example() {
+        // Every construct of the syntax reference formats to a file parsing
+        // the same, and formatting is idempotent.
+        format(include_str!("../docs/example.sp"));
+        format(include_str!("prelude.sp"));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let mut interner = Interner::new();
+        let errors = format_source("f x: int -> int\n    x * * 2\n", &mut interner).unwrap_err();
+        assert!(errors.len() == 1);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use crate::types::{Scheme, Type};
+
+// Unification variables for Hindley-Milner inference. Let-polymorphism uses
+// levels: a variable created while checking a let binding's expression has a
+// deeper level than anything bound outside it, and only variables deeper than
+// the current level are generalized.
+
+#[derive(Default)]
+pub struct InferCtx {
+    subst: Vec<Option<Type>>,
+    levels: Vec<u32>,
+    pub level: u32,
+}
+
+impl InferCtx {
+    pub fn new() -> InferCtx {
+        InferCtx::default()
+    }
+
+    pub fn fresh(&mut self) -> Type {
+        let var = self.subst.len() as u32;
+        self.subst.push(None);
+        self.levels.push(self.level);
+        Type::Var(var)
+    }
+
+    // Follows solved variables at the top of the type only.
+    pub fn shallow(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(var) = ty {
+            match &self.subst[var as usize] {
+                Some(solved) => ty = solved.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    // Replaces every solved variable in the type.
+    pub fn zonk(&self, ty: &Type) -> Type {
+        match self.shallow(ty) {
+            Type::Func(param, ret) => Type::Func(Box::new(self.zonk(&param)), Box::new(self.zonk(&ret))),
+            Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| self.zonk(e)).collect()),
+            Type::Struct(name, args) => Type::Struct(name, args.iter().map(|a| self.zonk(a)).collect()),
+            Type::Sum(name, args) => Type::Sum(name, args.iter().map(|a| self.zonk(a)).collect()),
+            ty => ty,
+        }
+    }
+
+    // Checks that `var` doesn't occur in `ty` and pulls the variables of `ty`
+    // up to the level of `var`, since they are now reachable from it.
+    fn occurs_adjust(&mut self, var: u32, ty: &Type) -> bool {
+        match self.shallow(ty) {
+            Type::Var(other) => {
+                let level = self.levels[var as usize];
+                let other_level = &mut self.levels[other as usize];
+                *other_level = (*other_level).min(level);
+                other == var
+            }
+            Type::Func(param, ret) => self.occurs_adjust(var, &param) || self.occurs_adjust(var, &ret),
+            Type::Tuple(elems) | Type::Struct(_, elems) | Type::Sum(_, elems) => {
+                elems.iter().any(|e| self.occurs_adjust(var, e))
+            }
+            _ => false,
+        }
+    }
+
+    // Error unifies with everything so one mistake is reported once.
+    pub fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let a = self.shallow(a);
+        let b = self.shallow(b);
+        match (&a, &b) {
+            (Type::Error, _) | (_, Type::Error) => true,
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(var), ty) | (ty, Type::Var(var)) => {
+                if self.occurs_adjust(*var, ty) {
+                    return false;
+                }
+                self.subst[*var as usize] = Some(ty.clone());
+                true
+            }
+            (Type::Func(p1, r1), Type::Func(p2, r2)) => self.unify(p1, p2) && self.unify(r1, r2),
+            (Type::Tuple(e1), Type::Tuple(e2)) => {
+                e1.len() == e2.len() && e1.iter().zip(e2.iter()).all(|(a, b)| self.unify(a, b))
+            }
+            (Type::Struct(n1, a1), Type::Struct(n2, a2)) | (Type::Sum(n1, a1), Type::Sum(n2, a2)) => {
+                n1 == n2 && a1.len() == a2.len() && a1.iter().zip(a2.iter()).all(|(a, b)| self.unify(a, b))
+            }
+            _ => a == b,
+        }
+    }
+
+    pub fn free_vars(&self, ty: &Type, vars: &mut Vec<u32>) {
+        match self.shallow(ty) {
+            Type::Var(var) if !vars.contains(&var) => vars.push(var),
+            Type::Func(param, ret) => {
+                self.free_vars(&param, vars);
+                self.free_vars(&ret, vars);
+            }
+            Type::Tuple(elems) | Type::Struct(_, elems) | Type::Sum(_, elems) => {
+                for elem in &elems {
+                    self.free_vars(elem, vars);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Quantifies the variables created deeper than the current level, except
+    // `fixed` ones that must stay shared.
+    pub fn generalize(&self, ty: &Type, fixed: &[u32]) -> Scheme {
+        let ty = self.zonk(ty);
+        let mut vars = vec![];
+        self.free_vars(&ty, &mut vars);
+        vars.retain(|var| self.levels[*var as usize] > self.level && !fixed.contains(var));
+        Scheme { vars, ty }
+    }
+
+    pub fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        if scheme.vars.is_empty() {
+            return scheme.ty.clone();
+        }
+        let fresh: HashMap<u32, Type> = scheme.vars.iter().map(|var| (*var, self.fresh())).collect();
+        substitute(&self.zonk(&scheme.ty), &fresh)
+    }
+
+    // Instantiates a scheme closed by another context, such as the type of a
+    // value imported from another module. All its variables are quantified.
+    pub fn instantiate_foreign(&mut self, scheme: &Scheme) -> Type {
+        let fresh: HashMap<u32, Type> = scheme.vars.iter().map(|var| (*var, self.fresh())).collect();
+        substitute(&scheme.ty, &fresh)
+    }
+}
+
+fn substitute(ty: &Type, map: &HashMap<u32, Type>) -> Type {
+    match ty {
+        Type::Var(var) => map.get(var).cloned().unwrap_or(Type::Var(*var)),
+        Type::Func(param, ret) => Type::Func(Box::new(substitute(param, map)), Box::new(substitute(ret, map))),
+        Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| substitute(e, map)).collect()),
+        Type::Struct(name, args) => Type::Struct(*name, args.iter().map(|a| substitute(a, map)).collect()),
+        Type::Sum(name, args) => Type::Sum(*name, args.iter().map(|a| substitute(a, map)).collect()),
+        ty => ty.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unify() {
+        let mut ctx = InferCtx::new();
+        let a = ctx.fresh();
+        let b = ctx.fresh();
+        let f = Type::func(vec![a.clone()], b.clone());
+        assert!(ctx.unify(&f, &Type::func(vec![Type::Int], Type::Bool)));
+        assert!(ctx.zonk(&f) == Type::func(vec![Type::Int], Type::Bool));
+        assert!(!ctx.unify(&a, &Type::Float));
+        assert!(ctx.unify(&a, &Type::Error));
+
+        // t2 = t2 -> int has no solution.
+        let c = ctx.fresh();
+        assert!(!ctx.unify(&c, &Type::func(vec![c.clone()], Type::Int)));
+    }
+
+    #[test]
+    fn test_generalize_and_instantiate() {
+        let mut ctx = InferCtx::new();
+        let outer = ctx.fresh();
+        ctx.level += 1;
+        let a = ctx.fresh();
+        let id = Type::func(vec![a.clone()], a);
+        let with_outer = Type::func(vec![outer.clone()], Type::Int);
+        ctx.level -= 1;
+        let scheme = ctx.generalize(&id, &[]);
+        assert!(scheme.vars == vec![1]);
+        assert!(ctx.generalize(&with_outer, &[]).vars.is_empty());
+        assert!(ctx.generalize(&id, &[1]).vars.is_empty());
+
+        let i1 = ctx.instantiate(&scheme);
+        let i2 = ctx.instantiate(&scheme);
+        assert!(ctx.unify(&i1, &Type::func(vec![Type::Int], Type::Int)));
+        assert!(ctx.unify(&i2, &Type::func(vec![Type::Str], Type::Str)));
+    }
+}
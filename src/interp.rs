@@ -0,0 +1,884 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::consteval::{binary_op, show_value, unary_op, ConstValue, OVERFLOW};
+use crate::diagnostic::Diagnostic;
+use crate::modules::{CheckedModule, ModuleGraph, ModuleId};
+use crate::resolve::{DefId, DefKind};
+use crate::traits::Trait;
+
+// Tree-walking interpreter over the checked modules of a program. Running a
+// program evaluates the expression statements of the root module in order
+// and writes the value of each one on its own line. Type checking already
+// ruled out ill-typed operations, so only the values can go wrong at run
+// time: overflow, division by zero or too deep a recursion.
+
+#[derive(Clone)]
+pub enum Value<'a> {
+    Int(i64),
+    Float(f64),
+    Char(char),
+    Str(String),
+    Bool(bool),
+    Tuple(Vec<Value<'a>>),
+    // Fields in declaration order.
+    Struct(Symbol, Vec<Value<'a>>),
+    // Sum type, variant index and fields.
+    Variant(Symbol, usize, Vec<Value<'a>>),
+    Func(Rc<Closure<'a>>),
+}
+
+// A function value with the arguments it was partially applied to.
+pub struct Closure<'a> {
+    callable: Rc<Callable<'a>>,
+    args: Vec<Value<'a>>,
+}
+
+enum Callable<'a> {
+    Func(ModuleId, &'a FuncDecl),
+    // Parameters, body and the locals visible where the lambda was written.
+    Lambda(ModuleId, &'a [Pattern], &'a Expr, Locals<'a>),
+    // Sum type, variant index and arity.
+    Variant(Symbol, usize, usize),
+}
+
+type Locals<'a> = HashMap<DefId, Value<'a>>;
+
+struct Frame<'a> {
+    module: ModuleId,
+    locals: Locals<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub module: ModuleId,
+    pub diag: Diagnostic,
+}
+
+// Calls deeper than this abort the program instead of overflowing the
+// interpreter's own stack, which is STACK_SIZE bytes. Calls with large
+// bodies can run out of it before, what's left is checked on every call too.
+pub const MAX_DEPTH: usize = 10_000;
+// Stack kept free for the evaluation of a call body.
+const STACK_RESERVE: usize = 1 << 20;
+// Room for MAX_DEPTH calls of a few KiB each, about ten times that in
+// unoptimized builds. Tail calls take none.
+pub const STACK_SIZE: usize = if cfg!(debug_assertions) { 1 << 29 } else { 1 << 26 };
+
+pub struct Interp<'a> {
+    graph: &'a ModuleGraph,
+    checked: &'a [CheckedModule],
+    interner: &'a Interner,
+    // Top level functions of every module.
+    funcs: Vec<HashMap<Symbol, &'a FuncDecl>>,
+    // Variants of every module: sum type, index and arity.
+    variants: Vec<HashMap<Symbol, (Symbol, usize, usize)>>,
+    // Variant names of every sum type, for printing.
+    sums: HashMap<Symbol, Vec<Symbol>>,
+    // Field names of every struct, in declaration order.
+    fields: HashMap<Symbol, Vec<Symbol>>,
+    // Methods of every instance, with the module declaring it.
+    instances: HashMap<(Trait, Symbol), (ModuleId, Vec<&'a FuncDecl>)>,
+    depth: usize,
+    // Address of the bottom of the interpreter thread's stack, which grows
+    // down from it.
+    stack_base: usize,
+}
+
+fn stack_address() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+// Whether a call at `depth` would overflow, `used` bytes of the stack being
+// taken already.
+fn out_of_stack(depth: usize, used: usize) -> bool {
+    depth >= MAX_DEPTH || used > STACK_SIZE - STACK_RESERVE
+}
+
+type Eval<'a> = Result<Value<'a>, RuntimeError>;
+
+// What is left of an expression in tail position: nothing but its value, or
+// a call that can take the place of the caller's.
+enum Tail<'a> {
+    Done(Value<'a>),
+    Call(Span, Value<'a>, Vec<Value<'a>>),
+}
+
+impl<'a> Interp<'a> {
+    pub fn new(graph: &'a ModuleGraph, checked: &'a [CheckedModule], interner: &'a Interner) -> Interp<'a> {
+        let mut interp = Interp {
+            graph,
+            checked,
+            interner,
+            funcs: vec![],
+            variants: vec![],
+            sums: HashMap::new(),
+            fields: HashMap::new(),
+            instances: HashMap::new(),
+            depth: 0,
+            stack_base: 0,
+        };
+        for (i, module) in graph.modules.iter().enumerate() {
+            let mut funcs = HashMap::new();
+            let mut variants = HashMap::new();
+            for decl in &module.ast.decls {
+                match &decl.kind {
+                    DeclKind::Func(func) => {
+                        funcs.insert(func.name.name, func);
+                    }
+                    DeclKind::Type(t) => {
+                        for (index, variant) in t.variants.iter().enumerate() {
+                            variants.insert(variant.name.name, (t.name.name, index, variant.fields.len()));
+                        }
+                        interp.sums.insert(t.name.name, t.variants.iter().map(|v| v.name.name).collect());
+                    }
+                    DeclKind::Struct(st) => {
+                        interp.fields.insert(st.name.name, st.fields.iter().map(|f| f.name.name).collect());
+                    }
+                    DeclKind::Instance(inst) => {
+                        let key = checked[i].types.instances.iter().find(|(_, id)| **id == decl.id).map(|(key, _)| *key);
+                        if let Some(key) = key {
+                            interp.instances.insert(key, (ModuleId(i), inst.methods.iter().collect()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            interp.funcs.push(funcs);
+            interp.variants.push(variants);
+        }
+        interp
+    }
+
+    fn name(&self, sym: Symbol) -> &'a str {
+        self.interner.get(sym)
+    }
+
+    fn error(&self, frame: &Frame, span: Span, msg: &str) -> RuntimeError {
+        RuntimeError {
+            module: frame.module,
+            diag: Diagnostic::error(span, msg),
+        }
+    }
+
+    // Evaluates the expression statements of the root module, writing their
+    // values to `out`. The program must have checked without errors.
+    pub fn run(&mut self, out: &mut dyn Write) -> Result<(), RuntimeError> {
+        self.eval_statements(&mut |_, text| writeln!(out, "{}", text))
+    }
+
+    // Evaluates the expression statements of the root module in order,
+    // giving every one with the text of its value to `emit`. Calls of the
+    // program nest as calls of the interpreter, so it runs on a thread with a
+    // stack of STACK_SIZE bytes, sending the values back as they come.
+    pub fn eval_statements(&mut self, emit: &mut dyn FnMut(&'a Expr, &str) -> std::io::Result<()>) -> Result<(), RuntimeError> {
+        let root = self.graph.root;
+        let (sender, receiver) = mpsc::channel::<(&'a Expr, String)>();
+        thread::scope(|scope| {
+            let evaluator = thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, move || self.statements(sender))
+                .expect("cannot start the interpreter thread");
+            let mut failed = None;
+            for (expr, text) in receiver {
+                if emit(expr, &text).is_err() {
+                    failed = Some(expr.span);
+                    break;
+                }
+            }
+            let result = evaluator.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            match failed {
+                Some(span) => Err(RuntimeError {
+                    module: root,
+                    diag: Diagnostic::error(span, "Cannot write the program output"),
+                }),
+                None => result,
+            }
+        })
+    }
+
+    fn statements(&mut self, sender: mpsc::Sender<(&'a Expr, String)>) -> Result<(), RuntimeError> {
+        self.stack_base = stack_address();
+        let root = self.graph.root;
+        for decl in &self.graph.module(root).ast.decls {
+            if let DeclKind::Expr(expr) = &decl.kind {
+                let mut frame = Frame {
+                    module: root,
+                    locals: HashMap::new(),
+                };
+                let value = self.eval(&mut frame, expr)?;
+                let text = self.show(&frame, expr.span, &value)?;
+                // The output was closed, the caller reports it.
+                if sender.send((expr, text)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Values show as in string templates, consistently with constants. User
+    // types without a Show instance show as they would be written.
+    fn show(&mut self, frame: &Frame, span: Span, value: &Value<'a>) -> Result<String, RuntimeError> {
+        if let Some(method) = self.method(Trait::Show, value, "show") {
+            return match self.call_method(frame, span, method, vec![value.clone()])? {
+                Value::Str(s) => Ok(s),
+                _ => unreachable!("show returns a string"),
+            };
+        }
+        let s = match value {
+            Value::Tuple(elems) => {
+                let mut parts = vec![];
+                for elem in elems {
+                    parts.push(self.show(frame, span, elem)?);
+                }
+                format!("({})", parts.join(", "))
+            }
+            Value::Struct(name, values) => {
+                let mut parts = vec![];
+                for (field, value) in self.fields[name].clone().iter().zip(values.iter()) {
+                    parts.push(format!("{} = {}", self.name(*field), self.show_nested(frame, span, value)?));
+                }
+                format!("{} {{{}}}", self.name(*name), parts.join(", "))
+            }
+            Value::Variant(sum, index, values) => {
+                let mut s = String::from(self.name(self.sums[sum][*index]));
+                for value in values {
+                    s.push(' ');
+                    s.push_str(&self.show_nested(frame, span, value)?);
+                }
+                s
+            }
+            Value::Func(_) => String::from("<function>"),
+            value => show_value(&to_const(value)),
+        };
+        Ok(s)
+    }
+
+    // Constructor arguments are parenthesized when they have arguments too.
+    fn show_nested(&mut self, frame: &Frame, span: Span, value: &Value<'a>) -> Result<String, RuntimeError> {
+        let s = self.show(frame, span, value)?;
+        match value {
+            Value::Variant(_, _, values) if !values.is_empty() && self.method(Trait::Show, value, "show").is_none() => {
+                Ok(format!("({})", s))
+            }
+            _ => Ok(s),
+        }
+    }
+
+    fn eval(&mut self, frame: &mut Frame<'a>, expr: &'a Expr) -> Eval<'a> {
+        let value = match &expr.kind {
+            ExprKind::Int(val) => match i64::try_from(*val) {
+                Ok(val) => Value::Int(val),
+                Err(_) => return Err(self.error(frame, expr.span, OVERFLOW)),
+            },
+            ExprKind::Float(val) => Value::Float(*val),
+            ExprKind::Char(val) => Value::Char(*val),
+            ExprKind::Str(val) => Value::Str(val.clone()),
+            ExprKind::Bool(val) => Value::Bool(*val),
+            ExprKind::Template(parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    match part {
+                        TemplatePart::Str(val) => s.push_str(val),
+                        TemplatePart::Expr(e) => {
+                            let value = self.eval(frame, e)?;
+                            s.push_str(&self.show(frame, e.span, &value)?);
+                        }
+                    }
+                }
+                Value::Str(s)
+            }
+            ExprKind::Name(_) | ExprKind::Path(_, _) => {
+                let id = self.checked[frame.module.0].res.names[&expr.id];
+                match frame.locals.get(&id) {
+                    Some(value) => value.clone(),
+                    None => self.global(frame, expr.span, id)?,
+                }
+            }
+            ExprKind::Field(e, field) => match self.eval(frame, e)? {
+                Value::Struct(name, values) => {
+                    let index = self.fields[&name].iter().position(|f| *f == field.name).unwrap();
+                    values[index].clone()
+                }
+                _ => unreachable!("field access on a non-struct value"),
+            },
+            _ if expr.is_min_int() => Value::Int(i64::MIN),
+            ExprKind::Unary(op, e) => {
+                let value = self.eval(frame, e)?;
+                if let Some(method) = self.method(Trait::Num, &value, "neg") {
+                    return self.call_method(frame, expr.span, method, vec![value]);
+                }
+                match unary_op(*op, &to_const(&value)) {
+                    Ok(value) => from_const(value),
+                    Err(msg) => return Err(self.error(frame, expr.span, msg)),
+                }
+            }
+            ExprKind::Binary(BinaryOp::And, l, r) => match self.eval(frame, l)? {
+                Value::Bool(false) => Value::Bool(false),
+                _ => self.eval(frame, r)?,
+            },
+            ExprKind::Binary(BinaryOp::Or, l, r) => match self.eval(frame, l)? {
+                Value::Bool(true) => Value::Bool(true),
+                _ => self.eval(frame, r)?,
+            },
+            ExprKind::Binary(op, l, r) => {
+                let l = self.eval(frame, l)?;
+                let r = self.eval(frame, r)?;
+                self.binary(frame, expr.span, *op, l, r)?
+            }
+            ExprKind::Call(_, _) | ExprKind::If(_, _, _) | ExprKind::Let(_, _) | ExprKind::Case(_, _) => {
+                match self.eval_tail(frame, expr)? {
+                    Tail::Done(value) => value,
+                    Tail::Call(span, func, args) => self.apply(frame, span, func, args)?,
+                }
+            }
+            ExprKind::StructLit(lit) => {
+                let name = self.checked[frame.module.0].types.struct_lits[&expr.id];
+                let names = self.fields[&name].clone();
+                let mut values = vec![None; names.len()];
+                for (i, field) in lit.fields.iter().enumerate() {
+                    let index = match field.name {
+                        Some(field_name) => names.iter().position(|f| *f == field_name.name).unwrap(),
+                        None => i,
+                    };
+                    values[index] = Some(self.eval(frame, &field.expr)?);
+                }
+                Value::Struct(name, values.into_iter().map(|v| v.unwrap()).collect())
+            }
+            ExprKind::Tuple(elems) => {
+                let mut values = vec![];
+                for elem in elems {
+                    values.push(self.eval(frame, elem)?);
+                }
+                Value::Tuple(values)
+            }
+            ExprKind::Lambda(params, body) => Value::Func(Rc::new(Closure {
+                callable: Rc::new(Callable::Lambda(frame.module, params, body, frame.locals.clone())),
+                args: vec![],
+            })),
+            ExprKind::Error => unreachable!("evaluating an erroneous expression"),
+        };
+        Ok(value)
+    }
+
+    // Evaluates an expression up to the call it ends with, if any, which
+    // the caller makes. Calls in tail position of a function body so don't
+    // nest, as in the compiled backends.
+    fn eval_tail(&mut self, frame: &mut Frame<'a>, expr: &'a Expr) -> Result<Tail<'a>, RuntimeError> {
+        match &expr.kind {
+            ExprKind::Call(func, args) => {
+                let func = self.eval(frame, func)?;
+                let mut values = vec![];
+                for arg in args {
+                    values.push(self.eval(frame, arg)?);
+                }
+                Ok(Tail::Call(expr.span, func, values))
+            }
+            ExprKind::If(cond, then_expr, else_expr) => match self.eval(frame, cond)? {
+                Value::Bool(true) => self.eval_tail(frame, then_expr),
+                _ => self.eval_tail(frame, else_expr),
+            },
+            ExprKind::Let(bindings, body) => {
+                for binding in bindings {
+                    let value = self.eval(frame, &binding.expr)?;
+                    let module = frame.module;
+                    self.match_pattern(module, &binding.pattern, &value, &mut frame.locals);
+                }
+                self.eval_tail(frame, body)
+            }
+            ExprKind::Case(scrutinee, arms) => {
+                let value = self.eval(frame, scrutinee)?;
+                for arm in arms {
+                    let mut bound = HashMap::new();
+                    if self.match_pattern(frame.module, &arm.pattern, &value, &mut bound) {
+                        frame.locals.extend(bound);
+                        return self.eval_tail(frame, &arm.body);
+                    }
+                }
+                Err(self.error(frame, expr.span, "No case arm matches the value"))
+            }
+            _ => self.eval(frame, expr).map(Tail::Done),
+        }
+    }
+
+    // Value of a top level name, or of a name imported from another module.
+    fn global(&mut self, frame: &Frame, span: Span, id: DefId) -> Eval<'a> {
+        let res = &self.checked[frame.module.0].res;
+        let def = res.def(id);
+        let (module, name) = match def.kind {
+            DefKind::Import => res.imports[&id],
+            _ => (frame.module, def.name.name),
+        };
+        let res = &self.checked[module.0].res;
+        match res.def(res.module[&name]).kind {
+            DefKind::Func => {
+                let func = self.funcs[module.0][&name];
+                let callable = Rc::new(Callable::Func(module, func));
+                // Functions without parameters are plain values.
+                if func.params.is_empty() {
+                    return self.invoke(frame, span, &callable, vec![]);
+                }
+                Ok(Value::Func(Rc::new(Closure { callable, args: vec![] })))
+            }
+            DefKind::Const => Ok(self.const_value(module, &self.checked[module.0].consts[&name])),
+            DefKind::Variant => {
+                let (sum, index, arity) = self.variants[module.0][&name];
+                if arity == 0 {
+                    return Ok(Value::Variant(sum, index, vec![]));
+                }
+                Ok(Value::Func(Rc::new(Closure {
+                    callable: Rc::new(Callable::Variant(sum, index, arity)),
+                    args: vec![],
+                })))
+            }
+            _ => unreachable!("'{}' is not a top level value", self.name(name)),
+        }
+    }
+
+    fn const_value(&self, module: ModuleId, value: &ConstValue) -> Value<'a> {
+        match value {
+            ConstValue::Tuple(elems) => Value::Tuple(elems.iter().map(|e| self.const_value(module, e)).collect()),
+            ConstValue::Struct(name, values) => {
+                Value::Struct(*name, values.iter().map(|v| self.const_value(module, v)).collect())
+            }
+            ConstValue::Variant(name, values) => {
+                let (sum, index, _) = self.variants[module.0][name];
+                Value::Variant(sum, index, values.iter().map(|v| self.const_value(module, v)).collect())
+            }
+            value => from_const(value.clone()),
+        }
+    }
+
+    // Binds the names of `pattern` in `locals` if it matches `value`.
+    fn match_pattern(&self, module: ModuleId, pattern: &Pattern, value: &Value<'a>, locals: &mut Locals<'a>) -> bool {
+        match (&pattern.kind, value) {
+            (PatternKind::Wildcard, _) => true,
+            (PatternKind::Binding(_), _) => {
+                let id = self.checked[module.0].res.names[&pattern.id];
+                locals.insert(id, value.clone());
+                true
+            }
+            (PatternKind::Int(a), Value::Int(b)) => a == b,
+            (PatternKind::Char(a), Value::Char(b)) => a == b,
+            (PatternKind::Str(a), Value::Str(b)) => a == b,
+            (PatternKind::Bool(a), Value::Bool(b)) => a == b,
+            (PatternKind::Variant(_, args), Value::Variant(sum, index, values)) => {
+                let res = &self.checked[module.0].res;
+                let id = res.names[&pattern.id];
+                let (module_of, name) = match res.def(id).kind {
+                    DefKind::Import => res.imports[&id],
+                    _ => (module, res.def(id).name.name),
+                };
+                let (want_sum, want_index, _) = self.variants[module_of.0][&name];
+                want_sum == *sum
+                    && want_index == *index
+                    && args.iter().zip(values.iter()).all(|(arg, value)| self.match_pattern(module, arg, value, locals))
+            }
+            (PatternKind::Tuple(elems), Value::Tuple(values)) => {
+                elems.iter().zip(values.iter()).all(|(elem, value)| self.match_pattern(module, elem, value, locals))
+            }
+            (PatternKind::Struct(_, fields), Value::Struct(name, values)) => {
+                let names = &self.fields[name];
+                fields.iter().enumerate().all(|(i, field)| {
+                    let index = match field.name {
+                        Some(field_name) => names.iter().position(|f| *f == field_name.name).unwrap(),
+                        None => i,
+                    };
+                    self.match_pattern(module, &field.pattern, &values[index], locals)
+                })
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&mut self, frame: &Frame, span: Span, func: Value<'a>, mut args: Vec<Value<'a>>) -> Eval<'a> {
+        let mut func = func;
+        loop {
+            let closure = match func {
+                Value::Func(closure) => closure,
+                _ => unreachable!("calling a non-function value"),
+            };
+            let mut all = closure.args.clone();
+            all.append(&mut args);
+            let arity = match &*closure.callable {
+                Callable::Func(_, func) => func.params.len(),
+                Callable::Lambda(_, params, _, _) => params.len(),
+                Callable::Variant(_, _, arity) => *arity,
+            };
+            if all.len() < arity {
+                return Ok(Value::Func(Rc::new(Closure {
+                    callable: closure.callable.clone(),
+                    args: all,
+                })));
+            }
+            // Extra arguments go to the function returned by the call.
+            let mut rest = all.split_off(arity);
+            let result = self.invoke(frame, span, &closure.callable, all)?;
+            if rest.is_empty() {
+                return Ok(result);
+            }
+            func = result;
+            args.append(&mut rest);
+        }
+    }
+
+    fn invoke(&mut self, frame: &Frame, span: Span, callable: &Callable<'a>, args: Vec<Value<'a>>) -> Eval<'a> {
+        if let Callable::Variant(sum, index, _) = callable {
+            return Ok(Value::Variant(*sum, *index, args));
+        }
+        if out_of_stack(self.depth, self.stack_base.saturating_sub(stack_address())) {
+            return Err(self.error(frame, span, "Stack overflow: too many nested calls"));
+        }
+        self.depth += 1;
+        let result = self.run_body(callable, args);
+        self.depth -= 1;
+        result
+    }
+
+    // Runs the body of a function, then the bodies of the functions it
+    // calls with all their arguments in tail position, in the same frame.
+    fn run_body(&mut self, callable: &Callable<'a>, args: Vec<Value<'a>>) -> Eval<'a> {
+        let (mut callee, mut body) = self.enter(callable, args);
+        loop {
+            let (span, func, args) = match self.eval_tail(&mut callee, body)? {
+                Tail::Done(value) => return Ok(value),
+                Tail::Call(span, func, args) => (span, func, args),
+            };
+            let closure = match &func {
+                Value::Func(closure) => closure.clone(),
+                _ => unreachable!("calling a non-function value"),
+            };
+            let arity = match &*closure.callable {
+                Callable::Func(_, func) => func.params.len(),
+                Callable::Lambda(_, params, _, _) => params.len(),
+                Callable::Variant(_, _, _) => return self.apply(&callee, span, func, args),
+            };
+            if closure.args.len() + args.len() != arity {
+                return self.apply(&callee, span, func, args);
+            }
+            let mut all = closure.args.clone();
+            all.extend(args);
+            let (next, next_body) = self.enter(&closure.callable, all);
+            callee = next;
+            body = next_body;
+        }
+    }
+
+    // The frame of a call of a function or lambda, with its parameters
+    // bound, and its body.
+    fn enter(&self, callable: &Callable<'a>, args: Vec<Value<'a>>) -> (Frame<'a>, &'a Expr) {
+        let (module, params, body, locals): (ModuleId, Vec<&'a Pattern>, &'a Expr, Locals<'a>) = match callable {
+            Callable::Func(module, func) => (*module, func.params.iter().map(|p| &p.pattern).collect(), &func.body, HashMap::new()),
+            Callable::Lambda(module, params, body, locals) => (*module, params.iter().collect(), *body, locals.clone()),
+            Callable::Variant(_, _, _) => unreachable!("entering a variant constructor"),
+        };
+        let mut frame = Frame { module, locals };
+        for (param, arg) in params.iter().zip(args.iter()) {
+            self.match_pattern(module, param, arg, &mut frame.locals);
+        }
+        (frame, body)
+    }
+
+    // The instance method implementing an operation on a user type value.
+    fn method(&self, trait_: Trait, value: &Value<'a>, name: &str) -> Option<(ModuleId, &'a FuncDecl)> {
+        let type_name = match value {
+            Value::Struct(name, _) | Value::Variant(name, _, _) => *name,
+            _ => return None,
+        };
+        let (module, methods) = self.instances.get(&(trait_, type_name))?;
+        let func = methods.iter().find(|m| self.name(m.name.name) == name)?;
+        Some((*module, *func))
+    }
+
+    fn call_method(&mut self, frame: &Frame, span: Span, method: (ModuleId, &'a FuncDecl), args: Vec<Value<'a>>) -> Eval<'a> {
+        self.invoke(frame, span, &Callable::Func(method.0, method.1), args)
+    }
+
+    fn binary(&mut self, frame: &Frame, span: Span, op: BinaryOp, l: Value<'a>, r: Value<'a>) -> Eval<'a> {
+        let value = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                let name = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    BinaryOp::Mul => "mul",
+                    _ => "div",
+                };
+                match self.method(Trait::Num, &l, name) {
+                    Some(method) => return self.call_method(frame, span, method, vec![l, r]),
+                    None => self.primitive(frame, span, op, &l, &r)?,
+                }
+            }
+            BinaryOp::Eq => Value::Bool(self.equal(frame, span, &l, &r)?),
+            BinaryOp::NotEq => Value::Bool(!self.equal(frame, span, &l, &r)?),
+            BinaryOp::Lt => Value::Bool(self.less(frame, span, &l, &r)?),
+            BinaryOp::LtEq => Value::Bool(!self.less(frame, span, &r, &l)?),
+            BinaryOp::Gt => Value::Bool(self.less(frame, span, &r, &l)?),
+            BinaryOp::GtEq => Value::Bool(!self.less(frame, span, &l, &r)?),
+            _ => self.primitive(frame, span, op, &l, &r)?,
+        };
+        Ok(value)
+    }
+
+    fn primitive(&self, frame: &Frame, span: Span, op: BinaryOp, l: &Value<'a>, r: &Value<'a>) -> Eval<'a> {
+        match binary_op(op, &to_const(l), &to_const(r)) {
+            Ok(value) => Ok(from_const(value)),
+            Err(msg) => Err(self.error(frame, span, msg)),
+        }
+    }
+
+    // Types without an Eq instance compare field by field.
+    fn equal(&mut self, frame: &Frame, span: Span, l: &Value<'a>, r: &Value<'a>) -> Result<bool, RuntimeError> {
+        if let Some(method) = self.method(Trait::Eq, l, "eq") {
+            let result = self.call_method(frame, span, method, vec![l.clone(), r.clone()])?;
+            return Ok(matches!(result, Value::Bool(true)));
+        }
+        let (a, b) = match (l, r) {
+            (Value::Tuple(a), Value::Tuple(b)) | (Value::Struct(_, a), Value::Struct(_, b)) => (a, b),
+            (Value::Variant(_, i, a), Value::Variant(_, j, b)) if i == j => (a, b),
+            (Value::Variant(_, _, _), Value::Variant(_, _, _)) => return Ok(false),
+            _ => return Ok(matches!(self.primitive(frame, span, BinaryOp::Eq, l, r)?, Value::Bool(true))),
+        };
+        for (x, y) in a.iter().zip(b.iter()) {
+            if !self.equal(frame, span, x, y)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Tuples compare lexicographically.
+    fn less(&mut self, frame: &Frame, span: Span, l: &Value<'a>, r: &Value<'a>) -> Result<bool, RuntimeError> {
+        if let Some(method) = self.method(Trait::Ord, l, "lt") {
+            let result = self.call_method(frame, span, method, vec![l.clone(), r.clone()])?;
+            return Ok(matches!(result, Value::Bool(true)));
+        }
+        if let (Value::Tuple(a), Value::Tuple(b)) = (l, r) {
+            for (x, y) in a.iter().zip(b.iter()) {
+                if self.less(frame, span, x, y)? {
+                    return Ok(true);
+                }
+                if self.less(frame, span, y, x)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(false);
+        }
+        Ok(matches!(self.primitive(frame, span, BinaryOp::Lt, l, r)?, Value::Bool(true)))
+    }
+}
+
+// Builtin operators are shared with constant evaluation.
+fn to_const(value: &Value) -> ConstValue {
+    match value {
+        Value::Int(val) => ConstValue::Int(*val),
+        Value::Float(val) => ConstValue::Float(*val),
+        Value::Char(val) => ConstValue::Char(*val),
+        Value::Str(val) => ConstValue::Str(val.clone()),
+        Value::Bool(val) => ConstValue::Bool(*val),
+        _ => unreachable!("builtin operator on a user value"),
+    }
+}
+
+fn from_const<'a>(value: ConstValue) -> Value<'a> {
+    match value {
+        ConstValue::Int(val) => Value::Int(val),
+        ConstValue::Float(val) => Value::Float(val),
+        ConstValue::Char(val) => Value::Char(val),
+        ConstValue::Str(val) => Value::Str(val),
+        ConstValue::Bool(val) => Value::Bool(val),
+        _ => unreachable!("user values are converted by the interpreter"),
+    }
+}
+
+// Runs a checked program, returning what it wrote.
+#[cfg(test)]
+pub fn run_program(graph: &ModuleGraph, checked: &[CheckedModule], interner: &Interner) -> Result<String, RuntimeError> {
+    let mut out = vec![];
+    Interp::new(graph, checked, interner).run(&mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::check_test_program;
+
+    fn run(src: &str) -> Result<String, String> {
+        let (graph, checked, interner) = check_test_program(src, "");
+        let result = run_program(&graph, &checked, &interner);
+        println!("{:?}", result);
+        result.map_err(|err| err.diag.msg)
+    }
+
+    #[test]
+    fn test_functions_and_recursion() {
+        let src = "
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+multiply x, y: int -> int
+    x * y
+
+add_one x: int -> int
+    let
+        y: int = 1
+    in
+    x + y
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+greet name: string -> string
+    `Hello ${name}`
+
+fact_rec 10
+multiply 6 7
+twice add_one 5
+twice (multiply 3) 2
+(\\x -> x * 2.5) 2.0
+greet \"Silver pancake\"
+`${fact_rec 3}, ${1 < 2}, ${'c'}`
+";
+        assert!(run(src) == Ok(String::from("3628800\n42\n7\n18\n5.0\nHello Silver pancake\n6, true, c\n")));
+    }
+
+    #[test]
+    fn test_structs_sum_types_and_instances() {
+        let src = "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+struct Vector =
+    x, y: float
+
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    sub a, b = Vector {a.x - b.x, a.y - b.y}
+    mul a, b = Vector {a.x * b.x, a.y * b.y}
+    div a, b = Vector {a.x / b.x, a.y / b.y}
+    neg v = Vector {-v.x, -v.y}
+
+instance Show Vector =
+    show v = `<${v.x}, ${v.y}>`
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Rect w h -> w * h
+        Empty -> 0.0
+
+length {x, y}: Vector -> float
+    x * x + y * y
+
+const ORIGIN = Vector {x = 0.0, y = 0.0}
+
+area (Circle 1.0)
+area (Rect 2.0 3.0)
+area Empty
+Rect 1.0
+Vector {1.0, 2.0} + {3.0, 4.0}
+-(Vector {1.0, 2.0})
+length {y = 2.0, x = 1.0}
+ORIGIN == Vector {0.0, 0.0}
+(Empty, Circle 2.0)
+";
+        let expected = "3.0\n6.0\n0.0\n<function>\n<4.0, 6.0>\n<-1.0, -2.0>\n5.0\ntrue\n(Empty, Circle 2.0)\n";
+        assert!(run(src) == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_generic_functions() {
+        let src = format!(
+            "{}
+range n: int -> List int
+    if n == 0
+        Nil
+    else
+        Cons n (range (n - 1))
+
+sum xs: List int -> int
+    foldl (\\acc x -> acc + x) 0 xs
+
+sum (range 10)
+length (filter (\\x -> x % 2 == 0) (range 10))
+with_default 0 (head (reverse (range 3)))
+map (\\x -> x * x) (range 3)
+",
+            crate::prelude::PRELUDE
+        );
+        assert!(run(&src) == Ok(String::from("55\n5\n1\nCons 9 (Cons 4 (Cons 1 Nil))\n")));
+    }
+
+    #[test]
+    fn test_modules() {
+        let foo = "
+greet str: string -> string
+    `Hello ${str}`
+
+export =
+    greet
+";
+        let src = "
+import foo::greet
+import foo
+
+greet \"Silver pancake\"
+foo::greet \"again\"
+";
+        let (graph, checked, interner) = check_test_program(src, foo);
+        let output = run_program(&graph, &checked, &interner);
+        assert!(output == Ok(String::from("Hello Silver pancake\nHello again\n")));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+loop n: int -> int
+    loop (n + 1)
+
+div x, y: int -> int
+    x / y
+
+div 7 2
+div 1 0
+";
+        assert!(run(src) == Err(String::from("Division by zero")));
+        assert!(run("9223372036854775807 + 1\n") == Err(String::from("Integer overflow")));
+        assert!(run("-9223372036854775808\n") == Ok(String::from("-9223372036854775808\n")));
+        assert!(run("-9223372036854775808 - 1\n") == Err(String::from("Integer overflow")));
+
+        assert!(run("deep n: int -> int\n    1 + deep (n + 1)\n\ndeep 0\n") == Err(String::from("Stack overflow: too many nested calls")));
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        // Up to MAX_DEPTH nested calls fit in the interpreter's stack.
+        let count = "count n: int -> int\n    if n == 0 then 0 else 1 + count (n - 1)\n\n";
+        assert!(run(&format!("{}count 9999\n", count)) == Ok(String::from("9999\n")));
+        let overflow = Err(String::from("Stack overflow: too many nested calls"));
+        assert!(run(&format!("{}count 10000\n", count)) == overflow);
+        // Calls in tail position don't nest, as on the VM.
+        let count = "count n, acc: int -> int\n    if n == 0 then acc else count (n - 1) (acc + 1)\n\n";
+        assert!(run(&format!("{}count 100000 0\n", count)) == Ok(String::from("100000\n")));
+        let count = "count n, acc: int -> int\n    case n of\n        0 -> acc\n        _ -> let f = \\m -> count m (acc + 1) in f (n - 1)\n\n";
+        assert!(run(&format!("{}count 100000 0\n", count)) == Ok(String::from("100000\n")));
+        // Large bodies can run out of stack before, which stops the program
+        // the same way.
+        assert!(!out_of_stack(MAX_DEPTH - 1, STACK_SIZE - STACK_RESERVE));
+        assert!(out_of_stack(1, STACK_SIZE - STACK_RESERVE + 1));
+        assert!(out_of_stack(MAX_DEPTH, 0));
+    }
+}
@@ -0,0 +1,1085 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::common::Span;
+
+// Intermediate representation the backends compile from. A function is a
+// graph of basic blocks in SSA form: every value is defined once, by a
+// parameter, a phi at the start of a block or an instruction, and has an
+// explicit type. Values of generic code whose type is a parameter are `Any`.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Int,
+    Float,
+    Bool,
+    Char,
+    Str,
+    Tuple(Vec<Ty>),
+    // Index of the type in the program and its type arguments.
+    Struct(u32, Vec<Ty>),
+    Sum(u32, Vec<Ty>),
+    Func(Box<Ty>, Box<Ty>),
+    // Parameter of a generic type, only in the fields of a `TypeDef`.
+    Param(u32),
+    Any,
+}
+
+impl Ty {
+    pub fn unit() -> Ty {
+        Ty::Tuple(vec![])
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        matches!(self, Ty::Int | Ty::Float | Ty::Bool | Ty::Char)
+    }
+
+    // Replaces the parameters of a generic type by its arguments.
+    pub fn subst(&self, args: &[Ty]) -> Ty {
+        match self {
+            Ty::Param(i) => args.get(*i as usize).cloned().unwrap_or(Ty::Any),
+            Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| t.subst(args)).collect()),
+            Ty::Struct(ty, tys) => Ty::Struct(*ty, tys.iter().map(|t| t.subst(args)).collect()),
+            Ty::Sum(ty, tys) => Ty::Sum(*ty, tys.iter().map(|t| t.subst(args)).collect()),
+            Ty::Func(param, ret) => Ty::Func(Box::new(param.subst(args)), Box::new(ret.subst(args))),
+            _ => self.clone(),
+        }
+    }
+
+    // Whether values of the two types can flow into each other, `Any` being
+    // compatible with everything.
+    pub fn fits(&self, other: &Ty) -> bool {
+        match (self, other) {
+            (Ty::Any, _) | (_, Ty::Any) => true,
+            (Ty::Tuple(a), Ty::Tuple(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.fits(b)),
+            (Ty::Struct(a, a_args), Ty::Struct(b, b_args)) | (Ty::Sum(a, a_args), Ty::Sum(b, b_args)) => {
+                a == b && a_args.len() == b_args.len() && a_args.iter().zip(b_args).all(|(a, b)| a.fits(b))
+            }
+            (Ty::Func(a_param, a_ret), Ty::Func(b_param, b_ret)) => a_param.fits(b_param) && a_ret.fits(b_ret),
+            _ => self == other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeKind {
+    // Field names and types.
+    Struct(Vec<(String, Ty)>),
+    // Variant names and field types.
+    Sum(Vec<(String, Vec<Ty>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeDef {
+    pub name: String,
+    pub module: u32,
+    pub params: u32,
+    pub kind: TypeKind,
+    // Instance methods and the functions implementing them.
+    pub methods: Vec<(String, u32)>,
+}
+
+impl TypeDef {
+    pub fn method(&self, name: &str) -> Option<u32> {
+        self.methods.iter().find(|(n, _)| n == name).map(|(_, func)| *func)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Const {
+    Int(i64),
+    Float(f64),
+    Char(char),
+    Str(String),
+    Bool(bool),
+    Tuple(Vec<Const>),
+    Struct(u32, Vec<Const>),
+    Variant(u32, u32, Vec<Const>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ValueId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub u32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Const(Const),
+    Unary(UnaryOp, ValueId),
+    // Never `&&` or `||`, which branch.
+    Binary(BinaryOp, ValueId, ValueId),
+    // Call of a function with all its parameters.
+    Call(u32, Vec<ValueId>),
+    // Application of a function value, maybe partial or over-saturated.
+    CallValue(ValueId, Vec<ValueId>),
+    // Function value of `func` holding the values it captures.
+    Closure(u32, Vec<ValueId>),
+    Tuple(Vec<ValueId>),
+    Struct(u32, Vec<ValueId>),
+    Variant(u32, u32, Vec<ValueId>),
+    // Field of a tuple, struct or variant, in declaration order.
+    Field(ValueId, u32),
+    // Whether a sum type value is the variant with the index.
+    IsVariant(ValueId, u32),
+    // The string a value reads as in a template.
+    Show(ValueId),
+    Concat(Vec<ValueId>),
+    // Writes a string and a newline to the output.
+    Print(ValueId),
+}
+
+impl Inst {
+    pub fn operands(&self) -> Vec<ValueId> {
+        match self {
+            Inst::Const(_) => vec![],
+            Inst::Unary(_, v) | Inst::Field(v, _) | Inst::IsVariant(v, _) | Inst::Show(v) | Inst::Print(v) => vec![*v],
+            Inst::Binary(_, l, r) => vec![*l, *r],
+            Inst::CallValue(callee, args) => {
+                let mut all = vec![*callee];
+                all.extend(args);
+                all
+            }
+            Inst::Call(_, args) | Inst::Closure(_, args) | Inst::Tuple(args) | Inst::Struct(_, args)
+            | Inst::Variant(_, _, args) | Inst::Concat(args) => args.clone(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Inst::Const(_) => vec![],
+            Inst::Unary(_, v) | Inst::Field(v, _) | Inst::IsVariant(v, _) | Inst::Show(v) | Inst::Print(v) => vec![v],
+            Inst::Binary(_, l, r) => vec![l, r],
+            Inst::CallValue(callee, args) => {
+                let mut all = vec![callee];
+                all.extend(args.iter_mut());
+                all
+            }
+            Inst::Call(_, args) | Inst::Closure(_, args) | Inst::Tuple(args) | Inst::Struct(_, args)
+            | Inst::Variant(_, _, args) | Inst::Concat(args) => args.iter_mut().collect(),
+        }
+    }
+
+    // Instructions whose only effect is their value. Calls may not return
+    // and the arithmetic may fail at run time.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Inst::Binary(op, _, _) => !matches!(
+                op,
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Shl | BinaryOp::Shr
+            ),
+            Inst::Unary(op, _) => *op != UnaryOp::Neg,
+            Inst::Call(_, _) | Inst::CallValue(_, _) | Inst::Show(_) | Inst::Print(_) => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Jump(BlockId),
+    Branch(ValueId, BlockId, BlockId),
+    Return(ValueId),
+    // Calls in tail position run in constant stack space.
+    TailCall(u32, Vec<ValueId>),
+    TailCallValue(ValueId, Vec<ValueId>),
+    // No case arm matches the value.
+    MatchFail,
+    // End of a block never reached.
+    Unreachable,
+}
+
+impl Term {
+    pub fn operands(&self) -> Vec<ValueId> {
+        match self {
+            Term::Branch(v, _, _) | Term::Return(v) => vec![*v],
+            Term::TailCall(_, args) => args.clone(),
+            Term::TailCallValue(callee, args) => {
+                let mut all = vec![*callee];
+                all.extend(args);
+                all
+            }
+            Term::Jump(_) | Term::MatchFail | Term::Unreachable => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut ValueId> {
+        match self {
+            Term::Branch(v, _, _) | Term::Return(v) => vec![v],
+            Term::TailCall(_, args) => args.iter_mut().collect(),
+            Term::TailCallValue(callee, args) => {
+                let mut all = vec![callee];
+                all.extend(args.iter_mut());
+                all
+            }
+            Term::Jump(_) | Term::MatchFail | Term::Unreachable => vec![],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Term::Jump(target) => vec![*target],
+            Term::Branch(_, then_block, else_block) => vec![*then_block, *else_block],
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Def {
+    // Captures come first, then the arguments.
+    Param(u32),
+    // The value coming from each predecessor.
+    Phi(Vec<(BlockId, ValueId)>),
+    Inst(Inst),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueData {
+    pub ty: Ty,
+    pub def: Def,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub phis: Vec<ValueId>,
+    pub insts: Vec<ValueId>,
+    pub term: Term,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FuncKind {
+    Func,
+    Method,
+    Lambda,
+    // Constructor used as a function value.
+    Ctor,
+    // Prints the expression statements of the root module.
+    Main,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub module: u32,
+    pub kind: FuncKind,
+    // Span of the function's name.
+    pub span: Span,
+    pub captures: u32,
+    pub params: Vec<ValueId>,
+    pub param_names: Vec<Option<String>>,
+    pub ret: Ty,
+    pub values: Vec<ValueData>,
+    // The first block is the entry.
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub fn new(name: &str, module: u32, kind: FuncKind, span: Span) -> Function {
+        Function {
+            name: String::from(name),
+            module,
+            kind,
+            span,
+            captures: 0,
+            params: vec![],
+            param_names: vec![],
+            ret: Ty::Any,
+            values: vec![],
+            blocks: vec![],
+        }
+    }
+
+    pub fn arity(&self) -> u32 {
+        self.params.len() as u32 - self.captures
+    }
+
+    pub fn ty(&self, value: ValueId) -> &Ty {
+        &self.values[value.0 as usize].ty
+    }
+
+    pub fn def(&self, value: ValueId) -> &Def {
+        &self.values[value.0 as usize].def
+    }
+
+    pub fn inst(&self, value: ValueId) -> Option<&Inst> {
+        match self.def(value) {
+            Def::Inst(inst) => Some(inst),
+            _ => None,
+        }
+    }
+
+    pub fn block(&self, block: BlockId) -> &Block {
+        &self.blocks[block.0 as usize]
+    }
+
+    pub fn new_block(&mut self, span: Span) -> BlockId {
+        self.blocks.push(Block {
+            phis: vec![],
+            insts: vec![],
+            term: Term::Unreachable,
+            span,
+        });
+        BlockId(self.blocks.len() as u32 - 1)
+    }
+
+    fn value(&mut self, ty: Ty, def: Def, span: Span) -> ValueId {
+        self.values.push(ValueData { ty, def, span });
+        ValueId(self.values.len() as u32 - 1)
+    }
+
+    pub fn param(&mut self, ty: Ty, name: Option<String>, span: Span) -> ValueId {
+        let value = self.value(ty, Def::Param(self.params.len() as u32), span);
+        self.params.push(value);
+        self.param_names.push(name);
+        value
+    }
+
+    pub fn push(&mut self, block: BlockId, inst: Inst, ty: Ty, span: Span) -> ValueId {
+        let value = self.value(ty, Def::Inst(inst), span);
+        self.blocks[block.0 as usize].insts.push(value);
+        value
+    }
+
+    pub fn phi(&mut self, block: BlockId, incoming: Vec<(BlockId, ValueId)>, ty: Ty, span: Span) -> ValueId {
+        let value = self.value(ty, Def::Phi(incoming), span);
+        self.blocks[block.0 as usize].phis.push(value);
+        value
+    }
+
+    pub fn terminate(&mut self, block: BlockId, term: Term, span: Span) {
+        let block = &mut self.blocks[block.0 as usize];
+        block.term = term;
+        block.span = span;
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for (i, block) in self.blocks.iter().enumerate() {
+            for succ in block.term.successors() {
+                if !preds[succ.0 as usize].contains(&BlockId(i as u32)) {
+                    preds[succ.0 as usize].push(BlockId(i as u32));
+                }
+            }
+        }
+        preds
+    }
+
+    // Blocks reachable from the entry in reverse postorder, the first
+    // successor of a branch right after it when possible.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut order = vec![];
+        let mut visited = vec![false; self.blocks.len()];
+        // Blocks with the index of the next successor to visit.
+        let mut stack = vec![(BlockId(0), 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            let mut succs = self.block(block).term.successors();
+            succs.reverse();
+            if next < succs.len() {
+                stack.push((block, next + 1));
+                let succ = succs[next];
+                if !visited[succ.0 as usize] {
+                    visited[succ.0 as usize] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(block);
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Immediate dominator of every reachable block, the entry being its own.
+    // The iterative algorithm of Cooper, Harvey and Kennedy.
+    pub fn dominators(&self) -> Vec<Option<BlockId>> {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[block.0 as usize] = i;
+        }
+        let preds = self.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        idom[0] = Some(BlockId(0));
+        let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+            while a != b {
+                while position[a.0 as usize] > position[b.0 as usize] {
+                    a = idom[a.0 as usize].unwrap();
+                }
+                while position[b.0 as usize] > position[a.0 as usize] {
+                    b = idom[b.0 as usize].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in order.iter().skip(1) {
+                let mut new_idom = None;
+                for pred in &preds[block.0 as usize] {
+                    if idom[pred.0 as usize].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => *pred,
+                        Some(other) => intersect(&idom, *pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[block.0 as usize] != new_idom {
+                    idom[block.0 as usize] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom
+    }
+
+    // Number of uses of every value.
+    pub fn use_counts(&self) -> Vec<u32> {
+        let mut counts = vec![0; self.values.len()];
+        for block in &self.blocks {
+            for value in block.phis.iter().chain(block.insts.iter()) {
+                let operands = match self.def(*value) {
+                    Def::Phi(incoming) => incoming.iter().map(|(_, v)| *v).collect(),
+                    Def::Inst(inst) => inst.operands(),
+                    Def::Param(_) => vec![],
+                };
+                for operand in operands {
+                    counts[operand.0 as usize] += 1;
+                }
+            }
+            for operand in block.term.operands() {
+                counts[operand.0 as usize] += 1;
+            }
+        }
+        counts
+    }
+
+    // Values stack machines can leave on the stack: used once, by a later
+    // instruction of their block or its terminator, when computing every
+    // value right before its use keeps the order of the block. Prints have
+    // no value to leave.
+    pub fn stacked_values(&self) -> Vec<bool> {
+        fn tree(f: &Function, stacked: &[bool], value: ValueId, out: &mut Vec<ValueId>) {
+            for operand in f.inst(value).map(Inst::operands).unwrap_or_default() {
+                if stacked[operand.0 as usize] {
+                    tree(f, stacked, operand, out);
+                }
+            }
+            out.push(value);
+        }
+        let uses = self.use_counts();
+        let mut users = vec![None; self.values.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for value in &block.phis {
+                if let Def::Phi(incoming) = self.def(*value) {
+                    for (_, operand) in incoming {
+                        users[operand.0 as usize] = Some(usize::MAX);
+                    }
+                }
+            }
+            let operands = block.insts.iter().flat_map(|v| self.inst(*v).map(Inst::operands).unwrap_or_default());
+            for operand in operands.chain(block.term.operands()) {
+                users[operand.0 as usize] = Some(b);
+            }
+        }
+        let mut stacked = vec![false; self.values.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for value in &block.insts {
+                let print = matches!(self.inst(*value), Some(Inst::Print(_)));
+                stacked[value.0 as usize] = !print && uses[value.0 as usize] == 1 && users[value.0 as usize] == Some(b);
+            }
+            // Values computed out of order go back to their place.
+            loop {
+                let mut order = vec![];
+                for value in &block.insts {
+                    if !stacked[value.0 as usize] {
+                        tree(self, &stacked, *value, &mut order);
+                    }
+                }
+                for operand in block.term.operands() {
+                    if stacked[operand.0 as usize] {
+                        tree(self, &stacked, operand, &mut order);
+                    }
+                }
+                match order.iter().zip(&block.insts).find(|(a, b)| a != b) {
+                    Some((_, expected)) => stacked[expected.0 as usize] = false,
+                    None => break,
+                }
+            }
+        }
+        stacked
+    }
+}
+
+pub fn dominates(idom: &[Option<BlockId>], a: BlockId, mut b: BlockId) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        match idom[b.0 as usize] {
+            Some(parent) if parent != b => b = parent,
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub path: PathBuf,
+    pub hash: u64,
+    // Exported functions, by name.
+    pub exports: Vec<(String, u32)>,
+    // Exported constants, by name.
+    pub consts: Vec<(String, Const)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub types: Vec<TypeDef>,
+    pub funcs: Vec<Function>,
+    pub modules: Vec<ModuleInfo>,
+    pub main: u32,
+}
+
+impl Program {
+    pub fn func_name(&self, func: u32) -> String {
+        let func = &self.funcs[func as usize];
+        match self.modules.get(func.module as usize) {
+            Some(module) if func.kind != FuncKind::Main => format!("{}::{}", module.name, func.name),
+            _ => func.name.clone(),
+        }
+    }
+
+    // Types of the fields of a struct, or of a variant of a sum type.
+    pub fn field_types(&self, ty: &Ty, variant: Option<u32>) -> Option<Vec<Ty>> {
+        match (ty, variant) {
+            (Ty::Tuple(elems), _) => Some(elems.clone()),
+            (Ty::Struct(index, args), _) => match &self.types[*index as usize].kind {
+                TypeKind::Struct(fields) => Some(fields.iter().map(|(_, t)| t.subst(args)).collect()),
+                TypeKind::Sum(_) => None,
+            },
+            (Ty::Sum(index, args), Some(variant)) => match &self.types[*index as usize].kind {
+                TypeKind::Sum(variants) => {
+                    variants.get(variant as usize).map(|(_, fields)| fields.iter().map(|t| t.subst(args)).collect())
+                }
+                TypeKind::Struct(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn ty_to_string(&self, ty: &Ty) -> String {
+        match ty {
+            Ty::Int => String::from("int"),
+            Ty::Float => String::from("float"),
+            Ty::Bool => String::from("bool"),
+            Ty::Char => String::from("char"),
+            Ty::Str => String::from("string"),
+            Ty::Tuple(elems) => {
+                let elems: Vec<String> = elems.iter().map(|e| self.ty_to_string(e)).collect();
+                format!("({})", elems.join(", "))
+            }
+            Ty::Struct(index, args) | Ty::Sum(index, args) => {
+                let mut s = self.types[*index as usize].name.clone();
+                for arg in args {
+                    let nested = match arg {
+                        Ty::Struct(_, args) | Ty::Sum(_, args) => !args.is_empty(),
+                        Ty::Func(_, _) => true,
+                        _ => false,
+                    };
+                    if nested {
+                        s.push_str(&format!(" ({})", self.ty_to_string(arg)));
+                    } else {
+                        s.push_str(&format!(" {}", self.ty_to_string(arg)));
+                    }
+                }
+                s
+            }
+            Ty::Func(param, ret) => {
+                let param = match **param {
+                    Ty::Func(_, _) => format!("({})", self.ty_to_string(param)),
+                    _ => self.ty_to_string(param),
+                };
+                format!("{} -> {}", param, self.ty_to_string(ret))
+            }
+            Ty::Param(i) => format!("p{}", i),
+            Ty::Any => String::from("any"),
+        }
+    }
+
+    pub fn const_to_string(&self, value: &Const) -> String {
+        let list = |values: &[Const]| values.iter().map(|v| self.const_to_string(v)).collect::<Vec<String>>().join(", ");
+        match value {
+            Const::Int(val) => val.to_string(),
+            Const::Float(val) => format!("{:?}", val),
+            Const::Char(val) => format!("{:?}", val),
+            Const::Str(val) => format!("{:?}", val),
+            Const::Bool(val) => val.to_string(),
+            Const::Tuple(elems) => format!("({})", list(elems)),
+            Const::Struct(ty, fields) => format!("{} {{{}}}", self.types[*ty as usize].name, list(fields)),
+            Const::Variant(ty, index, fields) => {
+                let name = match &self.types[*ty as usize].kind {
+                    TypeKind::Sum(variants) => variants[*index as usize].0.clone(),
+                    TypeKind::Struct(_) => String::from("?"),
+                };
+                if fields.is_empty() {
+                    name
+                } else {
+                    format!("{}({})", name, list(fields))
+                }
+            }
+        }
+    }
+
+    fn variant_name(&self, ty: u32, index: u32) -> String {
+        match &self.types[ty as usize].kind {
+            TypeKind::Sum(variants) => match variants.get(index as usize) {
+                Some((name, _)) => name.clone(),
+                None => String::from("?"),
+            },
+            TypeKind::Struct(_) => String::from("?"),
+        }
+    }
+
+    // Text form of a function, as shown by `--emit=ir`.
+    pub fn dump_function(&self, index: u32) -> String {
+        let f = &self.funcs[index as usize];
+        let mut out = String::new();
+        let params: Vec<String> = f.params.iter().map(|p| format!("v{}: {}", p.0, self.ty_to_string(f.ty(*p)))).collect();
+        let (captures, args) = params.split_at(f.captures as usize);
+        write!(out, "fn {} {}", index, self.func_name(index)).unwrap();
+        if !captures.is_empty() {
+            write!(out, " [{}]", captures.join(", ")).unwrap();
+        }
+        writeln!(out, "({}) -> {}", args.join(", "), self.ty_to_string(&f.ret)).unwrap();
+        let preds = f.predecessors();
+        for (i, block) in f.blocks.iter().enumerate() {
+            write!(out, "  b{}:", i).unwrap();
+            if !preds[i].is_empty() {
+                let preds: Vec<String> = preds[i].iter().map(|b| format!("b{}", b.0)).collect();
+                write!(out, "  ; preds {}", preds.join(", ")).unwrap();
+            }
+            out.push('\n');
+            for value in block.phis.iter().chain(block.insts.iter()) {
+                let text = match f.def(*value) {
+                    Def::Param(_) => String::from("param"),
+                    Def::Phi(incoming) => {
+                        let incoming: Vec<String> = incoming.iter().map(|(b, v)| format!("b{} v{}", b.0, v.0)).collect();
+                        format!("phi {}", incoming.join(", "))
+                    }
+                    Def::Inst(inst) => self.inst_to_string(inst),
+                };
+                writeln!(out, "    v{}: {} = {}", value.0, self.ty_to_string(f.ty(*value)), text).unwrap();
+            }
+            let term = match &block.term {
+                Term::Jump(target) => format!("jump b{}", target.0),
+                Term::Branch(cond, then_block, else_block) => format!("branch v{} b{} b{}", cond.0, then_block.0, else_block.0),
+                Term::Return(value) => format!("return v{}", value.0),
+                Term::TailCall(func, args) => {
+                    format!("{}  ; {}", with_operands(format!("tail_call {}", func), args), self.func_name(*func))
+                }
+                Term::TailCallValue(callee, args) => with_operands(format!("tail_call_value v{}", callee.0), args),
+                Term::MatchFail => String::from("match_fail"),
+                Term::Unreachable => String::from("unreachable"),
+            };
+            writeln!(out, "    {}", term).unwrap();
+        }
+        out
+    }
+
+    fn inst_to_string(&self, inst: &Inst) -> String {
+        match inst {
+            Inst::Const(value) => format!("const {}", self.const_to_string(value)),
+            Inst::Unary(op, v) => format!("unary {} v{}", op.as_str(), v.0),
+            Inst::Binary(op, l, r) => format!("binary {} v{}, v{}", op.as_str(), l.0, r.0),
+            Inst::Call(func, args) => format!("{}  ; {}", with_operands(format!("call {}", func), args), self.func_name(*func)),
+            Inst::CallValue(callee, args) => with_operands(format!("call_value v{}", callee.0), args),
+            Inst::Closure(func, captures) => {
+                format!("{}  ; {}", with_operands(format!("closure {}", func), captures), self.func_name(*func))
+            }
+            Inst::Tuple(elems) => with_operands(String::from("tuple"), elems),
+            Inst::Struct(ty, fields) => {
+                format!("{}  ; {}", with_operands(format!("struct {}", ty), fields), self.types[*ty as usize].name)
+            }
+            Inst::Variant(ty, index, fields) => format!(
+                "{}  ; {}",
+                with_operands(format!("variant {} {}", ty, index), fields),
+                self.variant_name(*ty, *index)
+            ),
+            Inst::Field(v, index) => format!("field v{} {}", v.0, index),
+            Inst::IsVariant(v, index) => format!("is_variant v{} {}", v.0, index),
+            Inst::Show(v) => format!("show v{}", v.0),
+            Inst::Concat(parts) => with_operands(String::from("concat"), parts),
+            Inst::Print(v) => format!("print v{}", v.0),
+        }
+    }
+
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for i in 0..self.funcs.len() {
+            out.push_str(&self.dump_function(i as u32));
+        }
+        out
+    }
+
+    // The functions a backend can't compile, with the reason: those `check`
+    // rejects, then the callers of rejected functions in turn. `check` lists
+    // the functions a function calls.
+    pub fn rejected_funcs<F>(&self, check: F) -> HashMap<u32, String>
+    where
+        F: Fn(&Function, &mut Vec<u32>) -> Result<(), String>,
+    {
+        let mut rejected = HashMap::new();
+        let mut calls = vec![];
+        for (i, f) in self.funcs.iter().enumerate() {
+            if f.kind != FuncKind::Func {
+                continue;
+            }
+            let mut callees = vec![];
+            match check(f, &mut callees) {
+                Ok(()) => calls.push((i as u32, callees)),
+                Err(reason) => {
+                    rejected.insert(i as u32, reason);
+                }
+            }
+        }
+        loop {
+            let mut changed = false;
+            for (func, callees) in &calls {
+                if rejected.contains_key(func) {
+                    continue;
+                }
+                if let Some(callee) = callees.iter().find(|callee| rejected.contains_key(callee)) {
+                    let reason = format!("calls '{}', which isn't compiled", self.funcs[*callee as usize].name);
+                    rejected.insert(*func, reason);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        rejected
+    }
+}
+
+// `op v1, v2`, or just `op` without operands.
+fn with_operands(op: String, operands: &[ValueId]) -> String {
+    if operands.is_empty() {
+        return op;
+    }
+    let operands: Vec<String> = operands.iter().map(|v| format!("v{}", v.0)).collect();
+    format!("{} {}", op, operands.join(", "))
+}
+
+// Checks the invariants every pass keeps: a terminator on every reachable
+// block, phis agreeing with the predecessors, definitions dominating their
+// uses and operand types matching the instructions.
+pub fn verify(program: &Program) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    for i in 0..program.funcs.len() {
+        verify_function(program, i as u32, &mut errors);
+    }
+    if (program.main as usize) >= program.funcs.len() {
+        errors.push(String::from("the main function doesn't exist"));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Verifies the program in debug builds, so a pass breaking an invariant
+// fails right after `stage` rather than in a backend.
+pub fn debug_verify(program: &Program, stage: &str) {
+    if cfg!(debug_assertions) {
+        if let Err(errors) = verify(program) {
+            panic!("Invalid IR after {}: {:#?}", stage, errors);
+        }
+    }
+}
+
+fn verify_function(program: &Program, index: u32, errors: &mut Vec<String>) {
+    let f = &program.funcs[index as usize];
+    let name = program.func_name(index);
+    let mut error = |msg: String| errors.push(format!("{}: {}", name, msg));
+    if f.blocks.is_empty() {
+        error(String::from("no entry block"));
+        return;
+    }
+    let value_count = f.values.len() as u32;
+    let block_count = f.blocks.len() as u32;
+    // Block and position of every definition, parameters coming before the
+    // entry's phis.
+    let mut sites: Vec<Option<(BlockId, usize)>> = vec![None; f.values.len()];
+    for (i, param) in f.params.iter().enumerate() {
+        if param.0 >= value_count || f.def(*param) != &Def::Param(i as u32) {
+            error(format!("parameter {} isn't defined as one", i));
+            return;
+        }
+        sites[param.0 as usize] = Some((BlockId(0), 0));
+    }
+    for (b, block) in f.blocks.iter().enumerate() {
+        for (pos, value) in block.phis.iter().chain(block.insts.iter()).enumerate() {
+            if value.0 >= value_count {
+                error(format!("b{} defines v{}, which doesn't exist", b, value.0));
+                return;
+            }
+            if sites[value.0 as usize].is_some() {
+                error(format!("v{} is defined twice", value.0));
+                continue;
+            }
+            let is_phi = pos < block.phis.len();
+            match f.def(*value) {
+                Def::Phi(_) if is_phi => {}
+                Def::Inst(_) if !is_phi => {}
+                _ => error(format!("v{} is in the wrong list of b{}", value.0, b)),
+            }
+            sites[value.0 as usize] = Some((BlockId(b as u32), pos + 1));
+        }
+        for succ in block.term.successors() {
+            if succ.0 >= block_count {
+                error(format!("b{} jumps to b{}, which doesn't exist", b, succ.0));
+                return;
+            }
+        }
+    }
+    let idom = f.dominators();
+    let preds = f.predecessors();
+    let check_use = |value: ValueId, block: BlockId, pos: usize, error: &mut dyn FnMut(String)| {
+        if value.0 >= value_count {
+            error(format!("b{} uses v{}, which doesn't exist", block.0, value.0));
+            return false;
+        }
+        match sites[value.0 as usize] {
+            None => {
+                error(format!("v{} is used but never defined", value.0));
+                false
+            }
+            Some((def_block, def_pos)) => {
+                let ok = if def_block == block { def_pos < pos } else { dominates(&idom, def_block, block) };
+                if !ok {
+                    error(format!("v{} doesn't dominate its use in b{}", value.0, block.0));
+                }
+                ok
+            }
+        }
+    };
+    for (b, block) in f.blocks.iter().enumerate() {
+        let id = BlockId(b as u32);
+        // Code that can't run can't break anything.
+        if idom[b].is_none() {
+            continue;
+        }
+        for (pos, value) in block.phis.iter().chain(block.insts.iter()).enumerate() {
+            let data = &f.values[value.0 as usize];
+            match &data.def {
+                Def::Phi(incoming) => {
+                    let mut from: Vec<BlockId> = incoming.iter().map(|(b, _)| *b).collect();
+                    from.sort();
+                    let mut expected: Vec<BlockId> = preds[b].iter().copied().filter(|p| idom[p.0 as usize].is_some()).collect();
+                    expected.sort();
+                    let reachable: Vec<BlockId> = from.iter().copied().filter(|p| idom[p.0 as usize].is_some()).collect();
+                    if reachable != expected || from.iter().collect::<HashSet<_>>().len() != from.len() {
+                        error(format!("the phi v{} of b{} doesn't match the predecessors", value.0, b));
+                    }
+                    for (pred, incoming) in incoming {
+                        if idom.get(pred.0 as usize).is_none_or(|d| d.is_none()) {
+                            continue;
+                        }
+                        // A value coming from a predecessor is used at its end.
+                        if check_use(*incoming, *pred, usize::MAX, &mut error) && !f.ty(*incoming).fits(&data.ty) {
+                            error(format!("v{} of the phi v{} has the wrong type", incoming.0, value.0));
+                        }
+                    }
+                }
+                Def::Inst(inst) => {
+                    let mut ok = true;
+                    for operand in inst.operands() {
+                        ok &= check_use(operand, id, pos + 1, &mut error);
+                    }
+                    if ok {
+                        if let Err(msg) = check_inst(program, f, inst, &data.ty) {
+                            error(format!("v{}: {}", value.0, msg));
+                        }
+                    }
+                }
+                Def::Param(_) => error(format!("v{} is a parameter in b{}", value.0, b)),
+            }
+        }
+        let mut ok = true;
+        for operand in block.term.operands() {
+            ok &= check_use(operand, id, usize::MAX, &mut error);
+        }
+        if ok {
+            if let Err(msg) = check_term(program, f, &block.term) {
+                error(format!("b{}: {}", b, msg));
+            }
+        }
+    }
+}
+
+fn check_args(program: &Program, f: &Function, func: u32, args: &[ValueId], captures: bool) -> Result<(), String> {
+    let callee = match program.funcs.get(func as usize) {
+        Some(callee) => callee,
+        None => return Err(format!("function {} doesn't exist", func)),
+    };
+    let params = if captures {
+        if args.len() != callee.captures as usize {
+            return Err(format!("{} takes {} captures", program.func_name(func), callee.captures));
+        }
+        &callee.params[..args.len()]
+    } else {
+        if callee.captures != 0 || args.len() != callee.params.len() {
+            return Err(format!("{} takes {} arguments", program.func_name(func), callee.arity()));
+        }
+        &callee.params[..]
+    };
+    for (arg, param) in args.iter().zip(params) {
+        if !f.ty(*arg).fits(callee.ty(*param)) {
+            return Err(format!("v{} doesn't fit the parameter of {}", arg.0, program.func_name(func)));
+        }
+    }
+    Ok(())
+}
+
+// Result type of applying a value of type `callee` to `argc` arguments.
+fn apply_type(callee: &Ty, argc: usize) -> Option<Ty> {
+    let mut ty = callee.clone();
+    for _ in 0..argc {
+        ty = match ty {
+            Ty::Func(_, ret) => *ret,
+            Ty::Any => return Some(Ty::Any),
+            _ => return None,
+        };
+    }
+    Some(ty)
+}
+
+fn const_fits(value: &Const, ty: &Ty) -> bool {
+    match (value, ty) {
+        (_, Ty::Any) => true,
+        (Const::Int(_), Ty::Int) | (Const::Float(_), Ty::Float) | (Const::Char(_), Ty::Char) | (Const::Str(_), Ty::Str)
+        | (Const::Bool(_), Ty::Bool) => true,
+        (Const::Tuple(elems), Ty::Tuple(tys)) => elems.len() == tys.len() && elems.iter().zip(tys).all(|(e, t)| const_fits(e, t)),
+        (Const::Struct(a, _), Ty::Struct(b, _)) | (Const::Variant(a, _, _), Ty::Sum(b, _)) => a == b,
+        _ => false,
+    }
+}
+
+fn check_inst(program: &Program, f: &Function, inst: &Inst, ty: &Ty) -> Result<(), String> {
+    let expect = |ok: bool, msg: &str| if ok { Ok(()) } else { Err(String::from(msg)) };
+    let ty_of = |v: &ValueId| f.ty(*v);
+    match inst {
+        Inst::Const(value) => expect(const_fits(value, ty), "the constant doesn't have the value's type"),
+        Inst::Unary(UnaryOp::Not, v) => expect(ty_of(v).fits(&Ty::Bool) && ty.fits(&Ty::Bool), "`!` takes a bool"),
+        Inst::Unary(_, v) => expect(ty_of(v).fits(ty), "the operand doesn't have the result's type"),
+        Inst::Binary(op, l, r) => {
+            expect(ty_of(l).fits(ty_of(r)), "the operands have different types")?;
+            match op {
+                BinaryOp::And | BinaryOp::Or => Err(String::from("`&&` and `||` are branches")),
+                BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq => {
+                    expect(ty.fits(&Ty::Bool), "comparisons are bool")
+                }
+                _ => expect(ty_of(l).fits(ty), "the operands don't have the result's type"),
+            }
+        }
+        Inst::Call(func, args) => {
+            check_args(program, f, *func, args, false)?;
+            expect(program.funcs[*func as usize].ret.fits(ty), "the result doesn't have the callee's return type")
+        }
+        Inst::CallValue(callee, args) => match apply_type(ty_of(callee), args.len()) {
+            Some(result) => expect(result.fits(ty), "the result doesn't have the callee's return type"),
+            None => Err(String::from("calls a value that isn't a function")),
+        },
+        Inst::Closure(func, captures) => {
+            check_args(program, f, *func, captures, true)?;
+            let callee = &program.funcs[*func as usize];
+            let func_ty = callee.params[captures.len()..]
+                .iter()
+                .rev()
+                .fold(callee.ret.clone(), |ret, p| Ty::Func(Box::new(callee.ty(*p).clone()), Box::new(ret)));
+            expect(func_ty.fits(ty), "the closure doesn't have the function's type")
+        }
+        Inst::Tuple(elems) => {
+            let tuple = Ty::Tuple(elems.iter().map(|e| ty_of(e).clone()).collect());
+            expect(tuple.fits(ty), "the tuple doesn't have the value's type")
+        }
+        Inst::Struct(index, fields) => {
+            let tys = match ty {
+                Ty::Struct(t, _) if t == index => program.field_types(ty, None),
+                Ty::Any => return Ok(()),
+                _ => None,
+            };
+            match tys {
+                Some(tys) if tys.len() == fields.len() => {
+                    expect(fields.iter().zip(&tys).all(|(v, t)| ty_of(v).fits(t)), "a field has the wrong type")
+                }
+                _ => Err(String::from("the struct doesn't have the value's type")),
+            }
+        }
+        Inst::Variant(index, variant, fields) => {
+            let tys = match ty {
+                Ty::Sum(t, _) if t == index => program.field_types(ty, Some(*variant)),
+                Ty::Any => return Ok(()),
+                _ => None,
+            };
+            match tys {
+                Some(tys) if tys.len() == fields.len() => {
+                    expect(fields.iter().zip(&tys).all(|(v, t)| ty_of(v).fits(t)), "a field has the wrong type")
+                }
+                _ => Err(String::from("the variant doesn't have the value's type")),
+            }
+        }
+        Inst::Field(v, index) => match ty_of(v) {
+            Ty::Any => Ok(()),
+            Ty::Sum(t, _) => match &program.types[*t as usize].kind {
+                TypeKind::Sum(variants) => {
+                    expect(variants.iter().any(|(_, fields)| fields.len() > *index as usize), "no variant has the field")
+                }
+                TypeKind::Struct(_) => Err(String::from("the type isn't a sum type")),
+            },
+            operand => match program.field_types(operand, None) {
+                Some(tys) if (*index as usize) < tys.len() => {
+                    expect(tys[*index as usize].fits(ty), "the field doesn't have the value's type")
+                }
+                _ => Err(String::from("the operand has no such field")),
+            },
+        },
+        Inst::IsVariant(v, _) => {
+            expect(matches!(ty_of(v), Ty::Sum(_, _) | Ty::Any) && ty.fits(&Ty::Bool), "tests the variant of a non-sum value")
+        }
+        Inst::Show(_) => expect(ty.fits(&Ty::Str), "shows are strings"),
+        Inst::Concat(parts) => expect(parts.iter().all(|p| ty_of(p).fits(&Ty::Str)) && ty.fits(&Ty::Str), "concatenates non-strings"),
+        Inst::Print(v) => expect(ty_of(v).fits(&Ty::Str) && ty.fits(&Ty::unit()), "prints a non-string"),
+    }
+}
+
+fn check_term(program: &Program, f: &Function, term: &Term) -> Result<(), String> {
+    match term {
+        Term::Branch(cond, _, _) if !f.ty(*cond).fits(&Ty::Bool) => Err(String::from("branches on a non-bool value")),
+        Term::Return(value) if !f.ty(*value).fits(&f.ret) => Err(String::from("returns a value of the wrong type")),
+        Term::TailCall(func, args) => {
+            check_args(program, f, *func, args, false)?;
+            if program.funcs[*func as usize].ret.fits(&f.ret) {
+                Ok(())
+            } else {
+                Err(String::from("the tail call returns a value of the wrong type"))
+            }
+        }
+        Term::TailCallValue(callee, args) => match apply_type(f.ty(*callee), args.len()) {
+            Some(result) if result.fits(&f.ret) => Ok(()),
+            _ => Err(String::from("the tail call returns a value of the wrong type")),
+        },
+        _ => Ok(()),
+    }
+}
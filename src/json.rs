@@ -0,0 +1,321 @@
+use std::fmt;
+
+// JSON values for the machine readable output of the command line driver.
+// Objects keep their keys in insertion order so the output is stable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    // Written as null when not finite, which JSON can't represent.
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn str<S: Into<String>>(s: S) -> Json {
+        Json::Str(s.into())
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (String::from(k), v)).collect())
+    }
+
+    // Field of an object, None for other values too.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(elems) => Some(elems),
+            _ => None,
+        }
+    }
+
+    // Numbers without a fraction or exponent that fit in an i64 are read as
+    // Int, the other ones as Float.
+    pub fn parse(src: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { src: src.as_bytes(), pos: 0 };
+        let value = parser.value()?;
+        parser.skip_space();
+        if parser.pos < parser.src.len() {
+            return Err(parser.error("Expected end of input"));
+        }
+        Ok(value)
+    }
+}
+
+struct JsonParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("{} at offset {}", msg, self.pos)
+    }
+
+    fn skip_space(&mut self) {
+        while self.pos < self.src.len() && matches!(self.src[self.pos], b' ' | b'\t' | b'\n' | b'\r') {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.src.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("Expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.src[self.pos..].starts_with(word.as_bytes()) {
+            return Err(self.error("Expected a value"));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::Str(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elems = vec![];
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(elems));
+                }
+                loop {
+                    elems.push(self.value()?);
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                        continue;
+                    }
+                    self.expect(b']')?;
+                    return Ok(Json::Array(elems));
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("Expected a key"));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                        continue;
+                    }
+                    self.expect(b'}')?;
+                    return Ok(Json::Object(fields));
+                }
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        let mut integral = true;
+        while let Some(&byte) = self.src.get(self.pos) {
+            match byte {
+                b'0'..=b'9' | b'-' | b'+' => {}
+                b'.' | b'e' | b'E' => integral = false,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        if integral {
+            if let Ok(n) = text.parse::<i64>() {
+                return Ok(Json::Int(n));
+            }
+        }
+        text.parse::<f64>().map(Json::Float).map_err(|_| self.error("Invalid number"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.src.get(self.pos..self.pos + 4).and_then(|d| std::str::from_utf8(d).ok());
+        let code = digits.and_then(|d| u32::from_str_radix(d, 16).ok()).ok_or_else(|| self.error("Invalid escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let byte = match self.src.get(self.pos) {
+                Some(&byte) => byte,
+                None => return Err(self.error("Unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.src.get(self.pos).copied();
+                    self.pos += 1;
+                    let c = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let mut code = self.hex4()?;
+                            // A surrogate pair encodes a char outside the BMP.
+                            if (0xd800..0xdc00).contains(&code) && self.src[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err(self.error("Invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("Invalid UTF-8"))
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+// Compact, on a single line.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            Json::Float(x) if x.is_finite() => write!(f, "{:?}", x),
+            Json::Float(_) => f.write_str("null"),
+            Json::Str(s) => write_str(f, s),
+            Json::Array(elems) => {
+                f.write_str("[")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_string() {
+        let json = Json::object(vec![
+            ("level", Json::str("error")),
+            ("message", Json::str("Expected '\"', found end of line\n\t\u{1}")),
+            ("line", Json::Int(-3)),
+            ("notes", Json::Array(vec![Json::Null, Json::Bool(true)])),
+            ("floats", Json::Array(vec![Json::Float(1.5), Json::Float(1e100), Json::Float(f64::INFINITY)])),
+            ("empty", Json::Object(vec![])),
+        ]);
+        let s = json.to_string();
+        println!("{}", s);
+        assert!(s == r#"{"level":"error","message":"Expected '\"', found end of line\n\t\u0001","line":-3,"notes":[null,true],"floats":[1.5,1e100,null],"empty":{}}"#);
+    }
+
+    #[test]
+    fn test_parse() {
+        let src = r#" {"jsonrpc": "2.0", "id": 1, "params": {"text": "a\n\"b\" \u00e9\ud83d\ude00", "list": [1.5, -2, true, null, [], {}], "big": 1e3}} "#;
+        let json = Json::parse(src).unwrap();
+        assert!(json.get("id").and_then(Json::as_i64) == Some(1));
+        let params = json.get("params").unwrap();
+        assert!(params.get("text").and_then(Json::as_str) == Some("a\n\"b\" \u{e9}\u{1f600}"));
+        let list = params.get("list").and_then(Json::as_array).unwrap();
+        assert!(list == [Json::Float(1.5), Json::Int(-2), Json::Bool(true), Json::Null, Json::Array(vec![]), Json::Object(vec![])]);
+        assert!(params.get("big") == Some(&Json::Float(1000.0)));
+        // Round trip through Display.
+        assert!(Json::parse(&json.to_string()).unwrap() == json);
+        for bad in ["", "{", "[1,]", "{\"a\" 1}", "\"abc", "tru", "1 2"].iter() {
+            println!("{:?}", bad);
+            assert!(Json::parse(bad).is_err());
+        }
+    }
+}
@@ -1,16 +1,22 @@
-#[allow(unused_imports)]
-use crate::common::{fatal_error, syntax_error, PeekableIterator};
+use crate::common::{PeekableIterator, Span};
+use crate::diagnostic::Diagnostic;
 
 #[allow(non_camel_case_types)]
+#[allow(clippy::upper_case_acronyms)]
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
-enum TokenKind {
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
     EOF,
+    NEWLINE,
+    INDENT,
+    DEDENT,
     INT,
     FLOAT,
     STR,
+    TEMPLATE,
     CHAR,
     NAME,
+    KEYWORD,
     LSHIFT,
     RSHIFT,
     EQ,
@@ -21,6 +27,8 @@ enum TokenKind {
     OR,
     INC,
     DEC,
+    ARROW,
+    COLON_COLON,
     COLON_ASSIGN,
     ADD_ASSIGN,
     SUB_ASSIGN,
@@ -36,8 +44,9 @@ enum TokenKind {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
-enum TokenMod {
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenMod {
     TOKENMOD_HEX,
     TOKENMOD_BIN,
     TOKENMOD_OCT,
@@ -45,19 +54,61 @@ enum TokenMod {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
-enum TokenVal {
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenVal {
     Int(u64),
     Float(f64),
     Char(char),
     Str(String)
 }
 
-#[derive(Debug, PartialEq)]
-struct Token {
-    token_kind: TokenKind,
-    token_mod: Option<TokenMod>,
-    val: Option<TokenVal>
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub token_kind: TokenKind,
+    pub token_mod: Option<TokenMod>,
+    pub val: Option<TokenVal>,
+    pub span: Span,
+    // Whether whitespace (or a line break) precedes the token. The parser uses it
+    // to tell tight operators (`n-1`) from spaced ones (`n - 1`).
+    pub space_before: bool,
+}
+
+pub const KEYWORDS: [&str; 15] = [
+    "const", "type", "struct", "instance", "import", "export",
+    "if", "then", "else", "let", "in", "case", "of", "true", "false",
+];
+
+// Char iterator that keeps track of the byte offset of the next char so the
+// scan_* functions can stay position agnostic.
+#[derive(Clone)]
+pub struct SourceChars<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    pos: usize,
+}
+
+impl<'a> SourceChars<'a> {
+    pub fn new(s: &'a str) -> SourceChars<'a> {
+        SourceChars {
+            chars: s.chars().peekable(),
+            pos: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for SourceChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+}
+
+impl<'a> PeekableIterator for SourceChars<'a> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
 }
 
 #[allow(dead_code)]
@@ -84,8 +135,8 @@ fn char_to_digit(c: &char) -> u64 {
 }
 
 #[allow(dead_code)]
-fn scan_int<I>(chars: &mut I) -> Token
-where 
+fn scan_int<I>(chars: &mut I, errors: &mut Vec<String>) -> Token
+where
     I: PeekableIterator<Item = char>,
 {
     let mut base = 10;
@@ -96,17 +147,17 @@ where
                 chars.next();
                 match chars.peek() {
                     Some(c) => {
-                        if c.to_ascii_lowercase() == 'x' {
+                        if c.eq_ignore_ascii_case(&'x') {
                             chars.next();
                             token_mod = Some(TokenMod::TOKENMOD_HEX);
                             base = 16;
                             break;
-                        } else if c.to_ascii_lowercase() == 'b' {
+                        } else if c.eq_ignore_ascii_case(&'b') {
                             chars.next();
                             token_mod = Some(TokenMod::TOKENMOD_BIN);
                             base = 2;
                             break;
-                        } else if c.to_ascii_lowercase() == 'o' {
+                        } else if c.eq_ignore_ascii_case(&'o') {
                             chars.next();
                             token_mod = Some(TokenMod::TOKENMOD_OCT);
                             base = 8;
@@ -124,151 +175,165 @@ where
         }
     }
     let mut val = 0;
+    let mut digits = 0;
     while let Some(c) = chars.peek() {
         let mut digit = char_to_digit(c);
-        if digit == 0 && c.to_ascii_lowercase() != '0' {
+        if digit == 0 && *c != '0' {
             break;
         }
+        digits += 1;
         if digit >= base {
-            syntax_error("Digit out of range", Some(c));
+            errors.push(format!("Digit '{}' out of range", c));
             digit = 0;
         }
-        if val > (u64::max_value() - digit) / base {
-            syntax_error("Integer literal overflow", Some(c));
+        if val > (u64::MAX - digit) / base {
+            errors.push(String::from("Integer literal overflow"));
             while let Some(c) = chars.peek() {
-                if c.is_digit(10) {
+                if c.is_ascii_hexdigit() {
                     chars.next();
+                } else {
+                    break;
                 }
             }
             val = 0;
+            break;
         }
         val = val * base + digit;
         chars.next();
     }
+    if digits == 0 && token_mod.is_some() {
+        errors.push(String::from("Expected digit after integer literal prefix"));
+    }
     Token {
         token_kind: TokenKind::INT,
-        token_mod: token_mod,
-        val: Some(TokenVal::Int(val))
+        token_mod,
+        val: Some(TokenVal::Int(val)),
+        span: Span::default(),
+        space_before: false,
     }
 }
 
 #[allow(dead_code)]
-fn scan_float<I>(chars: &mut I) -> Token
+fn scan_float<I>(chars: &mut I, errors: &mut Vec<String>) -> Token
 where
     I: PeekableIterator<Item = char>,
 {
     let mut val_str = String::from("");
     while let Some(c) = chars.peek() {
-        if c.is_digit(10) || c.to_ascii_lowercase() == '.' {
+        if c.is_ascii_digit() || *c == '.' {
             val_str.push(*c);
             chars.next();
         } else {
             break;
         }
     }
-    while let Some(c) = chars.peek() {
-        if c.to_ascii_lowercase() == 'e' {
+    if let Some(c) = chars.peek() {
+        if c.eq_ignore_ascii_case(&'e') {
             val_str.push(*c);
             chars.next();
-            while let Some(c1) = chars.peek() {
-                match c1 {
-                    '+' | '-' => {
-                        val_str.push(*c1);
-                        chars.next();
-                    },
-                    _ => {
-                        while let Some(c2) = chars.peek() {
-                            if c2.is_digit(10) {
-                                val_str.push(*c2);
-                                chars.next();
-                            } else {
-                                syntax_error("Expected digit after float literal exponent, found: ", Some(c2));
-                                break;
-                            }
-                        }
-                        break;
-                    }
+            if let Some(c1) = chars.peek() {
+                if *c1 == '+' || *c1 == '-' {
+                    val_str.push(*c1);
+                    chars.next();
                 }
             }
-        } else {
-            break;
+            let mut digits = 0;
+            while let Some(c2) = chars.peek() {
+                if c2.is_ascii_digit() {
+                    val_str.push(*c2);
+                    chars.next();
+                    digits += 1;
+                } else {
+                    break;
+                }
+            }
+            if digits == 0 {
+                errors.push(String::from("Expected digit after float literal exponent"));
+                val_str.push('0');
+            }
         }
     }
-    let val: f64 = val_str.parse().unwrap();
+    let val: f64 = match val_str.parse() {
+        Ok(val) => val,
+        Err(_) => {
+            errors.push(String::from("Invalid float literal"));
+            0.0
+        }
+    };
     Token {
         token_kind: TokenKind::FLOAT,
         token_mod: None,
-        val: Some(TokenVal::Float(val))
+        val: Some(TokenVal::Float(val)),
+        span: Span::default(),
+        space_before: false,
     }
 }
 
 #[allow(dead_code)]
-fn escape_to_char(c: char) -> char {
+pub fn escape_to_char(c: char) -> char {
     match c {
         'n' => '\n',
         'r' => '\r',
+        't' => '\t',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        '`' => '`',
         _ => '0'
     }
 }
 
 #[allow(dead_code)]
-fn scan_char<I>(chars: &mut I) -> Token
+fn scan_char<I>(chars: &mut I, errors: &mut Vec<String>) -> Token
 where
     I: PeekableIterator<Item= char>,
 {
     let mut val = ' ';
     chars.next();
-    while let Some(c) = chars.peek() {
+    if let Some(c) = chars.peek() {
         match c {
             '\'' =>  {
-                syntax_error("Char literal cannot be empty", None);
+                errors.push(String::from("Char literal cannot be empty"));
                 chars.next();
-                break;
             }
             '\n' => {
-                syntax_error("Char literal cannot contain newline", None);
-                break;
+                errors.push(String::from("Char literal cannot contain newline"));
             }
             '\\' => {
                 chars.next();
-                while let Some(c1) = chars.peek() {
+                if let Some(c1) = chars.peek() {
                     val = escape_to_char(*c1);
                     if val == '0' {
-                        syntax_error("Invalid char literal escape,", Some(c1));
+                        errors.push(format!("Invalid char literal escape '\\{}'", c1));
                     }
                     chars.next();
-                    break;
                 }
-                break;
             }
             _ => {
                 val = *c;
                 chars.next();
-                break;
             }
         }
     }
 
-    while let Some(c) = chars.peek() {
-        if *c != '\'' {
-            syntax_error("Expected closing char quote,", Some(c));
-            break;
-        } else {
-            chars.next();
-            break;
-        }
+    if chars.peek() == Some(&'\'') {
+        chars.next();
+    } else {
+        errors.push(String::from("Expected closing char quote"));
     }
     Token {
         token_kind: TokenKind::CHAR,
         token_mod: Some(TokenMod::TOKENMOD_CHAR),
-        val: Some(TokenVal::Char(val))
+        val: Some(TokenVal::Char(val)),
+        span: Span::default(),
+        space_before: false,
     }
 }
 
 #[allow(dead_code)]
-fn scan_str<I>(chars: &mut I) -> Token
+fn scan_str<I>(chars: &mut I, errors: &mut Vec<String>) -> Token
 where
-    I: PeekableIterator<Item = char>, 
+    I: PeekableIterator<Item = char>,
 {
     chars.next();
     let mut str = String::from("");
@@ -276,12 +341,15 @@ where
         if *c != '"' {
             let mut val = *c;
             if val == '\n' {
-                syntax_error("String literal cannot contain newline", None)
+                if !str.contains('\n') {
+                    errors.push(String::from("String literal cannot contain newline"));
+                }
             } else if val == '\\' {
-                if let Some(c) = chars.next() {
-                    val = escape_to_char(c);
+                chars.next();
+                if let Some(c) = chars.peek() {
+                    val = escape_to_char(*c);
                     if val == '0' {
-                        syntax_error("Invalid string literal escape, ", Some(&c));
+                        errors.push(format!("Invalid string literal escape '\\{}'", c));
                     }
                 }
             }
@@ -291,177 +359,417 @@ where
             break;
         }
     }
-    if let Some(c) = chars.peek() {
-        if *c == '"' {
-            chars.next();
-        } else {
-            syntax_error("Unexpected end of file within string literal", None);
-        }
+    if chars.peek() == Some(&'"') {
+        chars.next();
     } else {
-        syntax_error("Unexpected end of file within string literal", None);
+        errors.push(String::from("Unexpected end of file within string literal"));
     }
     Token {
         token_kind: TokenKind::STR,
         token_mod: None,
-        val: Some(TokenVal::Str(str))
+        val: Some(TokenVal::Str(str)),
+        span: Span::default(),
+        space_before: false,
+    }
+}
+
+// Template strings keep their raw text: the parser splits out the `${...}` parts.
+#[allow(dead_code)]
+fn scan_template<I>(chars: &mut I, errors: &mut Vec<String>) -> Token
+where
+    I: PeekableIterator<Item = char>,
+{
+    chars.next();
+    let mut str = String::from("");
+    let mut closed = false;
+    while let Some(c) = chars.next() {
+        if c == '`' {
+            closed = true;
+            break;
+        }
+        str.push(c);
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                str.push(c);
+            }
+        }
+    }
+    if !closed {
+        errors.push(String::from("Unexpected end of file within template string"));
+    }
+    Token {
+        token_kind: TokenKind::TEMPLATE,
+        token_mod: None,
+        val: Some(TokenVal::Str(str)),
+        span: Span::default(),
+        space_before: false,
     }
 }
 
 macro_rules! CASE1 {
-    ($tokens:expr, $chars:expr, $c:expr, $c1:expr, $k1:expr) => {
+    ($chars:expr, $c:expr, $c1:expr, $k1:expr) => {
         {
-            let mut token_kind = TokenKind::LAST_CHAR($c);
             $chars.next();
-            if let Some(c) = $chars.next() {
-                if c == $c1 {
-                    token_kind = $k1;
-                    $chars.next();
-                }
-            } 
-            $tokens.push(Token {
-                token_kind: token_kind,
-                token_mod: None,
-                val: None
-            });
+            if $chars.peek() == Some(&$c1) {
+                $chars.next();
+                $k1
+            } else {
+                TokenKind::LAST_CHAR($c)
+            }
         }
     };
 }
 
 macro_rules! CASE2 {
-    ($tokens:expr, $chars:expr, $c:expr, $c1:expr, $k1:expr, $c2:expr, $k2:expr) => {
+    ($chars:expr, $c:expr, $c1:expr, $k1:expr, $c2:expr, $k2:expr) => {
         {
-            let mut token_kind = TokenKind::LAST_CHAR($c);
             $chars.next();
-            if let Some(c) = $chars.next() {
-                if c == $c1 {
-                    token_kind = $k1;
-                    $chars.next();
-                } else if c == $c2 {
-                    token_kind = $k2;
-                    $chars.next();
-                }
-            } 
-            $tokens.push(Token {
-                token_kind: token_kind,
-                token_mod: None,
-                val: None
-            });
+            if $chars.peek() == Some(&$c1) {
+                $chars.next();
+                $k1
+            } else if $chars.peek() == Some(&$c2) {
+                $chars.next();
+                $k2
+            } else {
+                TokenKind::LAST_CHAR($c)
+            }
         }
     };
 }
 
-#[allow(dead_code)]
-fn tokenize(s: &mut &str) -> Vec<Token> {
+fn peek2(chars: &SourceChars) -> Option<char> {
+    let mut clone = chars.clone();
+    clone.next();
+    clone.next()
+}
+
+fn layout_token(token_kind: TokenKind, pos: usize) -> Token {
+    Token {
+        token_kind,
+        token_mod: None,
+        val: None,
+        span: Span::new(pos, pos),
+        space_before: true,
+    }
+}
+
+// Whether the last line ends with a comma or an opening bracket, so the next
+// one can only continue it.
+fn ends_open(tokens: &[Token]) -> bool {
+    matches!(
+        tokens.last().map(|t| &t.token_kind),
+        Some(TokenKind::LAST_CHAR(',' | '(' | '[' | '{'))
+    )
+}
+
+// Whether the last line ends with a binary operator, so the next one can
+// continue the expression.
+fn ends_operator(tokens: &[Token]) -> bool {
+    matches!(
+        tokens.last().map(|t| &t.token_kind),
+        Some(
+            TokenKind::LAST_CHAR('*' | '/' | '%' | '&' | '+' | '-' | '|' | '^' | '<' | '>')
+                | TokenKind::LSHIFT
+                | TokenKind::RSHIFT
+                | TokenKind::EQ
+                | TokenKind::NOTEQ
+                | TokenKind::LTEQ
+                | TokenKind::GTEQ
+                | TokenKind::AND
+                | TokenKind::OR
+        )
+    )
+}
+
+// Whether a line starts a declaration or the next clause of an expression,
+// rather than the operand of an operator left dangling.
+fn starts_clause(line: &str) -> bool {
+    let word: String = line.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+    matches!(
+        word.as_str(),
+        "const" | "type" | "struct" | "instance" | "import" | "export" | "then" | "else" | "in" | "of"
+    )
+}
+
+// Turns the indentation of a new line into NEWLINE, INDENT and DEDENT tokens.
+fn push_layout(tokens: &mut Vec<Token>, indents: &mut Vec<usize>, col: usize, span: Span, errors: &mut Vec<Diagnostic>) {
+    let pos = span.end;
+    if tokens.is_empty() {
+        indents[0] = col;
+        return;
+    }
+    if tokens.last().map(|t| &t.token_kind) != Some(&TokenKind::NEWLINE) {
+        let end = tokens.last().map(|t| t.span.end).unwrap_or(pos);
+        tokens.push(layout_token(TokenKind::NEWLINE, end));
+    }
+    let top = *indents.last().unwrap();
+    if col > top {
+        indents.push(col);
+        tokens.push(layout_token(TokenKind::INDENT, pos));
+    } else {
+        while col < *indents.last().unwrap() {
+            let level = indents.pop().unwrap();
+            if col > *indents.last().unwrap() {
+                // Between two levels: the line stays in the block it's
+                // dedented from, so the INDENTs and DEDENTs still pair up.
+                errors.push(Diagnostic::error(span, "Inconsistent dedent"));
+                indents.push(level);
+                break;
+            }
+            tokens.push(layout_token(TokenKind::DEDENT, pos));
+        }
+    }
+}
+
+// Lexical errors don't stop the scan: the faulty token is kept with a
+// placeholder value and the errors are returned with the tokens.
+pub fn tokenize(s: &mut &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let src: &str = s;
     let mut tokens = vec![];
-    let mut iter = s.chars().peekable();
+    let mut errors = vec![];
+    let mut token_errors = vec![];
+    let mut iter = SourceChars::new(src);
+    let mut indents = vec![0];
+    let mut depth = 0;
+    let mut line_start = 0;
+    let mut at_line_start = true;
+    let mut space_before = true;
 
     while let Some(c) = iter.peek() {
-        //println!("{}", c);
-        match *c {
-            ' ' | '\\' | '\r' => {
+        let c = *c;
+        match c {
+            ' ' | '\t' | '\r' => {
                 iter.next();
+                space_before = true;
+                continue;
             }
-            '\'' => {
-                tokens.push(scan_char(&mut iter));
+            '\n' => {
+                iter.next();
+                space_before = true;
+                at_line_start = true;
+                line_start = iter.pos;
+                continue;
+            }
+            '/' if peek2(&iter) == Some('/') => {
+                while let Some(c) = iter.peek() {
+                    if *c == '\n' {
+                        break;
+                    }
+                    iter.next();
+                }
+                space_before = true;
+                continue;
             }
-            '"' => {
-                tokens.push(scan_str(&mut iter));
+            '/' if peek2(&iter) == Some('*') => {
+                let start = iter.pos;
+                iter.next();
+                iter.next();
+                let mut closed = false;
+                while let Some(c) = iter.next() {
+                    if c == '*' && iter.peek() == Some(&'/') {
+                        iter.next();
+                        closed = true;
+                        break;
+                    }
+                    if c == '\n' {
+                        at_line_start = true;
+                        line_start = iter.pos;
+                    }
+                }
+                if !closed {
+                    errors.push(Diagnostic::error(
+                        Span::new(start, iter.pos),
+                        "Unexpected end of file within block comment",
+                    ));
+                }
+                space_before = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        let start = iter.pos;
+        if at_line_start {
+            let col = src[line_start..start].chars().count();
+            // Lines inside brackets don't count for the layout, unless one
+            // starts at column 0 after a line that looks complete: the bracket
+            // was left open and a new declaration begins.
+            // Neither do lines continuing an operator at the end of the last
+            // one, as long as they stay in its block.
+            let continued = ends_operator(&tokens)
+                && col >= *indents.last().unwrap_or(&0)
+                && !starts_clause(&src[start..]);
+            if depth > 0 && col == 0 && !matches!(c, ')' | ']' | '}') && !ends_open(&tokens) && !continued {
+                depth = 0;
             }
-            '.' => {
-                tokens.push(scan_float(&mut iter));
+            if depth == 0 && !continued {
+                push_layout(&mut tokens, &mut indents, col, Span::new(line_start, start), &mut errors);
             }
+            at_line_start = false;
+        }
+
+        let mut token = match c {
+            '\'' => scan_char(&mut iter, &mut token_errors),
+            '"' => scan_str(&mut iter, &mut token_errors),
+            '`' => scan_template(&mut iter, &mut token_errors),
+            '.' if peek2(&iter).map(|c| c.is_ascii_digit()) == Some(true) => scan_float(&mut iter, &mut token_errors),
             '0'..='9' => {
-                let mut clone = iter.clone();
-                while let Some(c) = clone.peek() {
-                    if c.is_digit(10) {
-                        clone.next();
+                let clone = iter.clone();
+                let mut is_float = false;
+                for c in clone {
+                    if c.is_ascii_digit() {
                         continue;
                     }
-                    if c.to_ascii_lowercase() == '.' || c.to_ascii_lowercase() == 'e' {
-                        tokens.push(scan_float(&mut iter));
-                    } else {
-                        tokens.push(scan_int(&mut iter));
-                    }
+                    is_float = c == '.' || c.eq_ignore_ascii_case(&'e');
                     break;
                 }
+                if is_float {
+                    scan_float(&mut iter, &mut token_errors)
+                } else {
+                    scan_int(&mut iter, &mut token_errors)
+                }
             }
             'A'..='Z' | 'a'..='z' | '_' => {
                 let mut name = String::from("");
                 while let Some(c) = iter.peek() {
-                    if c.is_alphabetic() || c.is_digit(10) {
+                    if c.is_alphanumeric() || *c == '_' {
                         name.push(*c);
                         iter.next();
                     } else {
                         break;
                     }
                 }
-                tokens.push(Token {
-                    token_kind: TokenKind::NAME,
+                let token_kind = if KEYWORDS.contains(&name.as_str()) {
+                    TokenKind::KEYWORD
+                } else {
+                    TokenKind::NAME
+                };
+                Token {
+                    token_kind,
                     token_mod: None,
-                    val: Some(TokenVal::Str(name))
-                });
+                    val: Some(TokenVal::Str(name)),
+                    span: Span::default(),
+                    space_before: false,
+                }
             }
-            '<' => {
-                let mut token_kind = TokenKind::LAST_CHAR(*c);
-                iter.next();
-                if let Some(c) = iter.next() {
-                    if c == '<' {
-                        token_kind = TokenKind::LSHIFT;
-                        if let Some(c) = iter.next() {
-                            if c == '=' {
-                                token_kind = TokenKind::LSHIFT_ASSIGN;
+            _ => {
+                let token_kind = match c {
+                    '<' => {
+                        iter.next();
+                        if iter.peek() == Some(&'<') {
+                            iter.next();
+                            if iter.peek() == Some(&'=') {
                                 iter.next();
-
+                                TokenKind::LSHIFT_ASSIGN
+                            } else {
+                                TokenKind::LSHIFT
                             }
+                        } else if iter.peek() == Some(&'=') {
+                            iter.next();
+                            TokenKind::LTEQ
+                        } else {
+                            TokenKind::LAST_CHAR(c)
                         }
-                    } else if c == '=' {
-                        token_kind = TokenKind::LTEQ;
-                        iter.next();
                     }
-                }
-                tokens.push(Token {
-                    token_kind: token_kind,
-                    token_mod: None,
-                    val: None
-                });
-            }
-            '>' => {
-                let mut token_kind = TokenKind::LAST_CHAR(*c);
-                iter.next();
-                if let Some(c) = iter.next() {
-                    if c == '>' {
-                        token_kind = TokenKind::RSHIFT;
-                        if let Some(c) = iter.next() {
-                            if c == '=' {
-                                token_kind = TokenKind::RSHIFT_ASSIGN;
+                    '>' => {
+                        iter.next();
+                        if iter.peek() == Some(&'>') {
+                            iter.next();
+                            if iter.peek() == Some(&'=') {
                                 iter.next();
-
+                                TokenKind::RSHIFT_ASSIGN
+                            } else {
+                                TokenKind::RSHIFT
                             }
+                        } else if iter.peek() == Some(&'=') {
+                            iter.next();
+                            TokenKind::GTEQ
+                        } else {
+                            TokenKind::LAST_CHAR(c)
+                        }
+                    }
+                    '-' => {
+                        iter.next();
+                        if iter.peek() == Some(&'>') {
+                            iter.next();
+                            TokenKind::ARROW
+                        } else if iter.peek() == Some(&'=') {
+                            iter.next();
+                            TokenKind::SUB_ASSIGN
+                        } else if iter.peek() == Some(&'-') {
+                            iter.next();
+                            TokenKind::DEC
+                        } else {
+                            TokenKind::LAST_CHAR(c)
                         }
-                    } else if c == '=' {
-                        token_kind = TokenKind::GTEQ;
+                    }
+                    '=' => CASE1!(iter, c, '=', TokenKind::EQ),
+                    '!' => CASE1!(iter, c, '=', TokenKind::NOTEQ),
+                    '^' => CASE1!(iter, c, '=', TokenKind::XOR_ASSIGN),
+                    ':' => CASE2!(iter, c, '=', TokenKind::COLON_ASSIGN, ':', TokenKind::COLON_COLON),
+                    '*' => CASE1!(iter, c, '=', TokenKind::MUL_ASSIGN),
+                    '/' => CASE1!(iter, c, '=', TokenKind::DIV_ASSIGN),
+                    '%' => CASE1!(iter, c, '=', TokenKind::MOD_ASSIGN),
+                    '+' => CASE2!(iter, c, '=', TokenKind::ADD_ASSIGN, '+', TokenKind::INC),
+                    '&' => CASE2!(iter, c, '=', TokenKind::AND_ASSIGN, '&', TokenKind::AND),
+                    '|' => CASE2!(iter, c, '=', TokenKind::OR_ASSIGN, '|', TokenKind::OR),
+                    '(' | '[' | '{' => {
                         iter.next();
+                        depth += 1;
+                        TokenKind::LAST_CHAR(c)
                     }
-                }
-                tokens.push(Token {
-                    token_kind: token_kind,
+                    ')' | ']' | '}' => {
+                        iter.next();
+                        if depth > 0 {
+                            depth -= 1;
+                        }
+                        TokenKind::LAST_CHAR(c)
+                    }
+                    ',' | '.' | ';' | '?' | '~' | '@' | '$' | '#' | '\\' => {
+                        iter.next();
+                        TokenKind::LAST_CHAR(c)
+                    }
+                    _ => {
+                        iter.next();
+                        errors.push(Diagnostic::error(
+                            Span::new(start, iter.pos),
+                            format!("Invalid character '{}'", c),
+                        ));
+                        space_before = true;
+                        continue;
+                    }
+                };
+                Token {
+                    token_kind,
                     token_mod: None,
-                    val: None
-                });
+                    val: None,
+                    span: Span::default(),
+                    space_before: false,
+                }
             }
-            c @ '^' => CASE1!(tokens, iter, c, '=', TokenKind::XOR_ASSIGN),
-            c @ ':' => CASE1!(tokens, iter, c, '=', TokenKind::COLON_ASSIGN),
-            c @ '*' => CASE1!(tokens, iter, c, '=', TokenKind::MUL_ASSIGN),
-            c @ '/' => CASE1!(tokens, iter, c, '=', TokenKind::DIV_ASSIGN),
-            c @ '%' => CASE1!(tokens, iter, c, '=', TokenKind::MOD_ASSIGN),
-            c @ '+' => CASE2!(tokens, iter, c, '=', TokenKind::ADD_ASSIGN, '+', TokenKind::INC),
-            c @ '-' => CASE2!(tokens, iter, c, '=', TokenKind::SUB_ASSIGN, '-', TokenKind::DEC),
-            c @ '&' => CASE2!(tokens, iter, c, '=', TokenKind::AND_ASSIGN, '&', TokenKind::AND),
-            c @ '|' => CASE2!(tokens, iter, c, '=', TokenKind::OR_ASSIGN, '|', TokenKind::OR),
-            _ => {}
+        };
+        token.span = Span::new(start, iter.pos);
+        for msg in token_errors.drain(..) {
+            errors.push(Diagnostic::error(token.span, msg));
         }
+        token.space_before = space_before;
+        space_before = false;
+        tokens.push(token);
     }
-    tokens
+
+    let end = src.len();
+    if let Some(last) = tokens.last() {
+        if last.token_kind != TokenKind::NEWLINE {
+            let last_end = last.span.end;
+            tokens.push(layout_token(TokenKind::NEWLINE, last_end));
+        }
+    }
+    while indents.len() > 1 {
+        indents.pop();
+        tokens.push(layout_token(TokenKind::DEDENT, end));
+    }
+    tokens.push(layout_token(TokenKind::EOF, end));
+    (tokens, errors)
 }
 
 #[cfg(test)]
@@ -471,16 +779,16 @@ mod tests {
     #[test]
     fn test_tokenize() {
         let mut test_case = "\"foo\" toto 12.56 0x123F '\\n' >>= >= &= ++";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens);
         assert!(tokens[0].token_kind == TokenKind::STR);
-        assert!(tokens[0].token_mod == None);
+        assert!(tokens[0].token_mod.is_none());
         assert!(tokens[0].val == Some(TokenVal::Str(String::from("foo"))));
         assert!(tokens[1].token_kind == TokenKind::NAME);
-        assert!(tokens[1].token_mod == None);
+        assert!(tokens[1].token_mod.is_none());
         assert!(tokens[1].val == Some(TokenVal::Str(String::from("toto"))));
         assert!(tokens[2].token_kind == TokenKind::FLOAT);
-        assert!(tokens[2].token_mod == None);
+        assert!(tokens[2].token_mod.is_none());
         assert!(tokens[2].val == Some(TokenVal::Float(12.56)));
         assert!(tokens[3].token_kind == TokenKind::INT);
         assert!(tokens[3].token_mod == Some(TokenMod::TOKENMOD_HEX));
@@ -489,87 +797,87 @@ mod tests {
         assert!(tokens[4].token_mod == Some(TokenMod::TOKENMOD_CHAR));
         assert!(tokens[4].val == Some(TokenVal::Char('\n')));
         assert!(tokens[5].token_kind == TokenKind::RSHIFT_ASSIGN);
-        assert!(tokens[5].token_mod == None);
-        assert!(tokens[5].val == None);
+        assert!(tokens[5].token_mod.is_none());
+        assert!(tokens[5].val.is_none());
         assert!(tokens[6].token_kind == TokenKind::GTEQ);
-        assert!(tokens[6].token_mod == None);
-        assert!(tokens[6].val == None);
+        assert!(tokens[6].token_mod.is_none());
+        assert!(tokens[6].val.is_none());
         assert!(tokens[7].token_kind == TokenKind::AND_ASSIGN);
-        assert!(tokens[7].token_mod == None);
-        assert!(tokens[7].val == None);
+        assert!(tokens[7].token_mod.is_none());
+        assert!(tokens[7].val.is_none());
         assert!(tokens[8].token_kind == TokenKind::INC);
-        assert!(tokens[8].token_mod == None);
-        assert!(tokens[8].val == None);
+        assert!(tokens[8].token_mod.is_none());
+        assert!(tokens[8].val.is_none());
     }
 
     #[test]
     fn test_gt() {
         let mut test_case = ">";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::LAST_CHAR('>'));
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_rshift() {
         let mut test_case = ">>";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::RSHIFT);
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_rshift_assign() {
         let mut test_case = ">>=";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::RSHIFT_ASSIGN);
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_lt() {
         let mut test_case = "<";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::LAST_CHAR('<'));
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_lshift() {
         let mut test_case = "<<";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::LSHIFT);
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_lshift_assign() {
         let mut test_case = "<<=";
-        let tokens = tokenize(&mut test_case);
+        let (tokens, _) = tokenize(&mut test_case);
         println!("{:?}", tokens[0]);
         assert!(tokens[0].token_kind == TokenKind::LSHIFT_ASSIGN);
-        assert!(tokens[0].token_mod == None);
-        assert!(tokens[0].val == None);
+        assert!(tokens[0].token_mod.is_none());
+        assert!(tokens[0].val.is_none());
     }
 
     #[test]
     fn test_scan_str_simple() {
         let test_case = "\"foo\"";
         let mut iter = test_case.chars().peekable();
-        let token = scan_str(&mut iter);
+        let token = scan_str(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::STR);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Str(String::from("foo"))));
     }
 
@@ -577,10 +885,10 @@ mod tests {
     fn test_scan_str_escaped() {
         let test_case = "\"a\nb\"";
         let mut iter = test_case.chars().peekable();
-        let token = scan_str(&mut iter);
+        let token = scan_str(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::STR);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Str(String::from("a\nb"))));
     }
 
@@ -588,7 +896,7 @@ mod tests {
     fn test_scan_char_simple() {
         let test_case = "'a'";
         let mut iter = test_case.chars().peekable();
-        let token = scan_char(&mut iter);
+        let token = scan_char(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::CHAR);
         assert!(token.token_mod == Some(TokenMod::TOKENMOD_CHAR));
@@ -599,7 +907,7 @@ mod tests {
     fn test_scan_char_simple_escaped() {
         let test_case = "'\\n'";
         let mut iter = test_case.chars().peekable();
-        let token = scan_char(&mut iter);
+        let token = scan_char(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::CHAR);
         assert!(token.token_mod == Some(TokenMod::TOKENMOD_CHAR));
@@ -610,10 +918,10 @@ mod tests {
     fn test_scan_float_simple() {
         let test_case = "1.56";
         let mut iter = test_case.chars().peekable();
-        let token = scan_float(&mut iter);
+        let token = scan_float(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::FLOAT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Float(1.56)));
     }
 
@@ -621,10 +929,10 @@ mod tests {
     fn test_scan_float_simple2() {
         let test_case = ".34";
         let mut iter = test_case.chars().peekable();
-        let token = scan_float(&mut iter);
+        let token = scan_float(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::FLOAT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Float(0.34)));
     }
 
@@ -632,10 +940,10 @@ mod tests {
     fn test_scan_float_simple3() {
         let test_case = "45.";
         let mut iter = test_case.chars().peekable();
-        let token = scan_float(&mut iter);
+        let token = scan_float(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::FLOAT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Float(45.)));
     }
 
@@ -643,10 +951,10 @@ mod tests {
     fn test_scan_float_negative_power_of() {
         let test_case = "2.5e-2";
         let mut iter = test_case.chars().peekable();
-        let token = scan_float(&mut iter);
+        let token = scan_float(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::FLOAT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Float(0.025)));
     }
 
@@ -654,10 +962,10 @@ mod tests {
     fn test_scan_float_positive_power_of() {
         let test_case = "2e2";
         let mut iter = test_case.chars().peekable();
-        let token = scan_float(&mut iter);
+        let token = scan_float(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::FLOAT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Float(200.0)));
     }
 
@@ -665,10 +973,10 @@ mod tests {
     fn test_scan_int_dec() {
         let test_case = "1234";
         let mut iter = test_case.chars().peekable();
-        let token = scan_int(&mut iter);
+        let token = scan_int(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::INT);
-        assert!(token.token_mod == None);
+        assert!(token.token_mod.is_none());
         assert!(token.val == Some(TokenVal::Int(1234)));
     }
 
@@ -676,7 +984,7 @@ mod tests {
     fn test_scan_int_hexa() {
         let test_case = "0x123F";
         let mut iter = test_case.chars().peekable();
-        let token = scan_int(&mut iter);
+        let token = scan_int(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::INT);
         assert!(token.token_mod == Some(TokenMod::TOKENMOD_HEX));
@@ -687,7 +995,7 @@ mod tests {
     fn test_scan_int_bin() {
         let test_case = "0b0111001";
         let mut iter = test_case.chars().peekable();
-        let token = scan_int(&mut iter);
+        let token = scan_int(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::INT);
         assert!(token.token_mod == Some(TokenMod::TOKENMOD_BIN));
@@ -698,10 +1006,134 @@ mod tests {
     fn test_scan_int_oct() {
         let test_case = "0o756";
         let mut iter = test_case.chars().peekable();
-        let token = scan_int(&mut iter);
+        let token = scan_int(&mut iter, &mut vec![]);
         println!("{:?}", token);
         assert!(token.token_kind == TokenKind::INT);
         assert!(token.token_mod == Some(TokenMod::TOKENMOD_OCT));
         assert!(token.val == Some(TokenVal::Int(494)));
     }
-}
\ No newline at end of file
+
+    fn kinds(src: &str) -> Vec<TokenKind> {
+        let mut test_case = src;
+        tokenize(&mut test_case).0.into_iter().map(|t| t.token_kind).collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let kinds = kinds("fact_rec n: int -> int\n    if n == 0\n        1\n    else\n        n\n");
+        println!("{:?}", kinds);
+        assert!(kinds == vec![
+            TokenKind::NAME, TokenKind::NAME, TokenKind::LAST_CHAR(':'), TokenKind::NAME,
+            TokenKind::ARROW, TokenKind::NAME, TokenKind::NEWLINE,
+            TokenKind::INDENT, TokenKind::KEYWORD, TokenKind::NAME, TokenKind::EQ, TokenKind::INT,
+            TokenKind::NEWLINE,
+            TokenKind::INDENT, TokenKind::INT, TokenKind::NEWLINE,
+            TokenKind::DEDENT, TokenKind::KEYWORD, TokenKind::NEWLINE,
+            TokenKind::INDENT, TokenKind::NAME, TokenKind::NEWLINE,
+            TokenKind::DEDENT, TokenKind::DEDENT, TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_layout_ignores_blank_lines_comments_and_brackets() {
+        let kinds = kinds("a = (1,\n  2) // comment\n\n/* block\n comment */\nb");
+        println!("{:?}", kinds);
+        assert!(kinds == vec![
+            TokenKind::NAME, TokenKind::LAST_CHAR('='), TokenKind::LAST_CHAR('('), TokenKind::INT,
+            TokenKind::LAST_CHAR(','), TokenKind::INT, TokenKind::LAST_CHAR(')'), TokenKind::NEWLINE,
+            TokenKind::NAME, TokenKind::NEWLINE, TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_layout_continues_after_operator() {
+        let kinds = kinds("a = 1 +\n2 *\n    3\nb");
+        println!("{:?}", kinds);
+        assert!(kinds == vec![
+            TokenKind::NAME, TokenKind::LAST_CHAR('='), TokenKind::INT, TokenKind::LAST_CHAR('+'), TokenKind::INT,
+            TokenKind::LAST_CHAR('*'), TokenKind::INT, TokenKind::NEWLINE,
+            TokenKind::NAME, TokenKind::NEWLINE, TokenKind::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_layout_inconsistent_dedent() {
+        let mut test_case = "a\n    b\n  c\nd\n";
+        let (tokens, errors) = tokenize(&mut test_case);
+        let kinds: Vec<TokenKind> = tokens.into_iter().map(|t| t.token_kind).collect();
+        println!("{:?}", kinds);
+        assert!(kinds == vec![
+            TokenKind::NAME, TokenKind::NEWLINE,
+            TokenKind::INDENT, TokenKind::NAME, TokenKind::NEWLINE,
+            TokenKind::NAME, TokenKind::NEWLINE,
+            TokenKind::DEDENT, TokenKind::NAME, TokenKind::NEWLINE, TokenKind::EOF,
+        ]);
+        assert!(errors.len() == 1 && errors[0].msg == "Inconsistent dedent");
+        assert!(errors[0].span == Span::new(8, 10));
+    }
+
+    #[test]
+    fn test_spans_and_spacing() {
+        let mut test_case = "fact_rec n-1";
+        let (tokens, _) = tokenize(&mut test_case);
+        println!("{:?}", tokens);
+        assert!(tokens[0].val == Some(TokenVal::Str(String::from("fact_rec"))));
+        assert!(tokens[0].span == Span::new(0, 8));
+        assert!(tokens[1].span == Span::new(9, 10));
+        assert!(tokens[1].space_before);
+        assert!(tokens[2].token_kind == TokenKind::LAST_CHAR('-'));
+        assert!(!tokens[2].space_before);
+        assert!(tokens[3].token_kind == TokenKind::INT);
+        assert!(!tokens[3].space_before);
+        assert!(tokens[3].span == Span::new(11, 12));
+    }
+
+    #[test]
+    fn test_operators_do_not_swallow_next_char() {
+        let kinds = kinds("a>=b+c==d");
+        assert!(kinds[1] == TokenKind::GTEQ);
+        assert!(kinds[2] == TokenKind::NAME);
+        assert!(kinds[3] == TokenKind::LAST_CHAR('+'));
+        assert!(kinds[5] == TokenKind::EQ);
+        assert!(kinds[6] == TokenKind::NAME);
+    }
+
+    #[test]
+    fn test_template() {
+        let mut test_case = "`Hello ${str}`";
+        let (tokens, _) = tokenize(&mut test_case);
+        assert!(tokens[0].token_kind == TokenKind::TEMPLATE);
+        assert!(tokens[0].val == Some(TokenVal::Str(String::from("Hello ${str}"))));
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("0x", "Expected digit after integer literal prefix", Span::new(0, 2)),
+            ("0b12", "Digit '2' out of range", Span::new(0, 4)),
+            ("a \u{a7} b", "Invalid character '\u{a7}'", Span::new(2, 4)),
+            ("\"abc\nd", "String literal cannot contain newline", Span::new(0, 6)),
+            ("'ab'", "Expected closing char quote", Span::new(0, 2)),
+            ("\"\\q\"", "Invalid string literal escape '\\q'", Span::new(0, 4)),
+            ("/* open", "Unexpected end of file within block comment", Span::new(0, 7)),
+            ("`open", "Unexpected end of file within template string", Span::new(0, 5)),
+        ];
+        for (src, msg, span) in cases.iter() {
+            let mut test_case = *src;
+            let (_, errors) = tokenize(&mut test_case);
+            println!("{:?} {:?}", src, errors);
+            assert!(errors[0].msg == *msg);
+            assert!(errors[0].span == *span);
+        }
+        let mut test_case = "a = 0x1F // fine\n";
+        assert!(tokenize(&mut test_case).1.is_empty());
+    }
+
+    #[test]
+    fn test_scan_str_escape_sequence() {
+        let test_case = "\"a\\tb\\\"\"";
+        let mut iter = test_case.chars().peekable();
+        let token = scan_str(&mut iter, &mut vec![]);
+        assert!(token.val == Some(TokenVal::Str(String::from("a\tb\""))));
+    }
+}
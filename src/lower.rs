@@ -0,0 +1,1228 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::consteval::ConstValue;
+use crate::ir::*;
+use crate::modules::{source_hash, CheckedModule, ModuleGraph, ModuleId};
+use crate::pattern::{Ctor, Decision, Occurrence};
+use crate::resolve::{DefId, DefKind, Resolutions};
+use crate::types::{builtin_type, Type};
+
+// Lowers the checked modules of a program to the IR. Case expressions follow
+// the decision trees built by the type checker, operators on user types call
+// their instance methods, lambdas become functions taking the locals they
+// capture first, and calls in tail position become tail calls.
+
+// The function being lowered.
+struct FnState {
+    f: Function,
+    module: ModuleId,
+    // Block instructions are added to.
+    block: BlockId,
+    locals: HashMap<DefId, ValueId>,
+}
+
+impl FnState {
+    fn push(&mut self, inst: Inst, ty: Ty, span: Span) -> ValueId {
+        self.f.push(self.block, inst, ty, span)
+    }
+
+    fn terminate(&mut self, term: Term, span: Span) {
+        self.f.terminate(self.block, term, span);
+    }
+}
+
+// Arms of the case expression being lowered.
+struct CaseState<'c> {
+    arms: &'c [CaseArm],
+    defs: Vec<HashMap<Symbol, DefId>>,
+    // Number of leaves of the decision tree reaching every arm. Arms reached
+    // from several leaves get a block of their own, with a phi for each
+    // binding.
+    leaves: Vec<usize>,
+    blocks: Vec<Option<BlockId>>,
+    incoming: Vec<Vec<(BlockId, Vec<ValueId>)>>,
+    // Block the arms jump to with their result, unless in tail position.
+    join: Option<BlockId>,
+    results: Vec<(BlockId, ValueId)>,
+}
+
+pub struct Lowerer<'a> {
+    graph: &'a ModuleGraph,
+    checked: &'a [CheckedModule],
+    interner: &'a Interner,
+    program: Program,
+    // Index of every top level function.
+    funcs: HashMap<(ModuleId, Symbol), u32>,
+    types: HashMap<Symbol, u32>,
+    // Variants of every module: type index, variant index and arity.
+    variants: Vec<HashMap<Symbol, (u32, u32, u32)>>,
+    // Field names of every struct, in declaration order.
+    fields: HashMap<Symbol, Vec<Symbol>>,
+    // Functions wrapping the constructors used as values.
+    ctor_funcs: HashMap<(u32, u32), u32>,
+    // Declarations of the reserved functions, lowered once every index is
+    // known.
+    bodies: Vec<(u32, ModuleId, &'a FuncDecl)>,
+}
+
+// Names bound by a pattern, with their definitions.
+fn pattern_defs(pattern: &Pattern, res: &Resolutions, out: &mut HashMap<Symbol, DefId>) {
+    match &pattern.kind {
+        PatternKind::Binding(name) => {
+            out.insert(*name, res.names[&pattern.id]);
+        }
+        PatternKind::Variant(_, args) | PatternKind::Tuple(args) => {
+            for arg in args {
+                pattern_defs(arg, res, out);
+            }
+        }
+        PatternKind::Struct(_, fields) => {
+            for field in fields {
+                pattern_defs(&field.pattern, res, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Parameters and local bindings referred to in `expr`, in order of use.
+fn used_locals(expr: &Expr, res: &Resolutions, out: &mut Vec<DefId>) {
+    let mut visit = |e: &Expr| used_locals(e, res, out);
+    match &expr.kind {
+        ExprKind::Name(_) => {
+            if let Some(id) = res.names.get(&expr.id) {
+                if matches!(res.def(*id).kind, DefKind::Param | DefKind::Local) && !out.contains(id) {
+                    out.push(*id);
+                }
+            }
+        }
+        ExprKind::Field(e, _) | ExprKind::Unary(_, e) | ExprKind::Lambda(_, e) => visit(e),
+        ExprKind::Binary(_, l, r) => {
+            visit(l);
+            visit(r);
+        }
+        ExprKind::Call(func, args) => {
+            visit(func);
+            args.iter().for_each(visit);
+        }
+        ExprKind::If(cond, then_expr, else_expr) => {
+            visit(cond);
+            visit(then_expr);
+            visit(else_expr);
+        }
+        ExprKind::Let(bindings, body) => {
+            bindings.iter().for_each(|b| visit(&b.expr));
+            visit(body);
+        }
+        ExprKind::Case(scrutinee, arms) => {
+            visit(scrutinee);
+            arms.iter().for_each(|arm| visit(&arm.body));
+        }
+        ExprKind::StructLit(lit) => lit.fields.iter().for_each(|f| visit(&f.expr)),
+        ExprKind::Tuple(elems) => elems.iter().for_each(visit),
+        ExprKind::Template(parts) => {
+            for part in parts {
+                if let TemplatePart::Expr(e) = part {
+                    visit(e);
+                }
+            }
+        }
+        ExprKind::Path(_, _) | ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Char(_) | ExprKind::Str(_)
+        | ExprKind::Bool(_) | ExprKind::Error => {}
+    }
+}
+
+// Parameters of a generic type are unknown outside of its fields.
+fn erase_params(ty: &Ty) -> Ty {
+    match ty {
+        Ty::Param(_) => Ty::Any,
+        Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(erase_params).collect()),
+        Ty::Struct(index, args) => Ty::Struct(*index, args.iter().map(erase_params).collect()),
+        Ty::Sum(index, args) => Ty::Sum(*index, args.iter().map(erase_params).collect()),
+        Ty::Func(param, ret) => Ty::Func(Box::new(erase_params(param)), Box::new(erase_params(ret))),
+        _ => ty.clone(),
+    }
+}
+
+impl<'a> Lowerer<'a> {
+    pub fn new(graph: &'a ModuleGraph, checked: &'a [CheckedModule], interner: &'a Interner) -> Lowerer<'a> {
+        Lowerer {
+            graph,
+            checked,
+            interner,
+            program: Program {
+                types: vec![],
+                funcs: vec![],
+                modules: vec![],
+                main: 0,
+            },
+            funcs: HashMap::new(),
+            types: HashMap::new(),
+            variants: vec![],
+            fields: HashMap::new(),
+            ctor_funcs: HashMap::new(),
+            bodies: vec![],
+        }
+    }
+
+    fn name(&self, sym: Symbol) -> &'a str {
+        self.interner.get(sym)
+    }
+
+    fn res(&self, module: ModuleId) -> &'a Resolutions {
+        &self.checked[module.0].res
+    }
+
+    fn ty(&self, ty: &Type) -> Ty {
+        match ty {
+            Type::Int => Ty::Int,
+            Type::Float => Ty::Float,
+            Type::Char => Ty::Char,
+            Type::Str => Ty::Str,
+            Type::Bool => Ty::Bool,
+            Type::Struct(name, args) => match self.types.get(name) {
+                Some(index) => Ty::Struct(*index, args.iter().map(|a| self.ty(a)).collect()),
+                None => Ty::Any,
+            },
+            Type::Sum(name, args) => match self.types.get(name) {
+                Some(index) => Ty::Sum(*index, args.iter().map(|a| self.ty(a)).collect()),
+                None => Ty::Any,
+            },
+            Type::Func(param, ret) => Ty::Func(Box::new(self.ty(param)), Box::new(self.ty(ret))),
+            Type::Tuple(elems) => Ty::Tuple(elems.iter().map(|e| self.ty(e)).collect()),
+            Type::Param(_) | Type::Var(_) | Type::Error => Ty::Any,
+        }
+    }
+
+    fn node_ty(&self, module: ModuleId, id: NodeId) -> Ty {
+        match self.checked[module.0].types.node_types.get(&id) {
+            Some(ty) => self.ty(ty),
+            None => Ty::Any,
+        }
+    }
+
+    // Type written in a declaration, where `params` are the parameters of
+    // the type declared.
+    fn type_expr(&self, ty: &TypeExpr, params: &[Symbol]) -> Ty {
+        let named = |name: Symbol, args: Vec<Ty>| {
+            if let Some(i) = params.iter().position(|p| *p == name) {
+                return Ty::Param(i as u32);
+            }
+            if let Some(ty) = builtin_type(self.name(name)) {
+                return self.ty(&ty);
+            }
+            match self.types.get(&name) {
+                Some(index) => match self.program.types[*index as usize].kind {
+                    TypeKind::Struct(_) => Ty::Struct(*index, args),
+                    TypeKind::Sum(_) => Ty::Sum(*index, args),
+                },
+                // Type parameters of generic functions.
+                None => Ty::Any,
+            }
+        };
+        match &ty.kind {
+            TypeExprKind::Name(name) => named(*name, vec![]),
+            TypeExprKind::App(name, args) => named(*name, args.iter().map(|a| self.type_expr(a, params)).collect()),
+            TypeExprKind::Func(param, ret) => {
+                Ty::Func(Box::new(self.type_expr(param, params)), Box::new(self.type_expr(ret, params)))
+            }
+            TypeExprKind::Tuple(elems) => Ty::Tuple(elems.iter().map(|e| self.type_expr(e, params)).collect()),
+        }
+    }
+
+    // Reserves the index of a function lowered later, with its parameters.
+    fn reserve(&mut self, name: &str, module: ModuleId, kind: FuncKind, func: &'a FuncDecl) -> u32 {
+        let mut f = Function::new(name, module.0 as u32, kind, func.name.span);
+        for param in &func.params {
+            let (ty, name) = match param.pattern.kind {
+                PatternKind::Binding(name) => (self.node_ty(module, param.pattern.id), Some(String::from(self.name(name)))),
+                _ => match &param.ty {
+                    Some(ty) => (erase_params(&self.type_expr(ty, &[])), None),
+                    None => (Ty::Any, None),
+                },
+            };
+            f.param(ty, name, param.pattern.span);
+        }
+        f.ret = self.node_ty(module, func.body.id);
+        let id = self.program.funcs.len() as u32;
+        self.bodies.push((id, module, func));
+        self.program.funcs.push(f);
+        id
+    }
+
+    // Types and function indices come first so bodies can refer to any of
+    // them.
+    fn collect(&mut self) {
+        let graph = self.graph;
+        let mut decls = vec![];
+        for (i, module) in graph.modules.iter().enumerate() {
+            let mut variants = HashMap::new();
+            for decl in &module.ast.decls {
+                let (name, kind) = match &decl.kind {
+                    DeclKind::Struct(st) => {
+                        self.fields.insert(st.name.name, st.fields.iter().map(|f| f.name.name).collect());
+                        (st.name.name, TypeKind::Struct(vec![]))
+                    }
+                    DeclKind::Type(t) => {
+                        let ty = self.program.types.len() as u32;
+                        for (index, variant) in t.variants.iter().enumerate() {
+                            variants.insert(variant.name.name, (ty, index as u32, variant.fields.len() as u32));
+                        }
+                        (t.name.name, TypeKind::Sum(vec![]))
+                    }
+                    _ => continue,
+                };
+                self.types.insert(name, self.program.types.len() as u32);
+                self.program.types.push(TypeDef {
+                    name: String::from(self.name(name)),
+                    module: i as u32,
+                    params: 0,
+                    kind,
+                    methods: vec![],
+                });
+                decls.push(decl);
+            }
+            self.variants.push(variants);
+        }
+        // Fields can refer to any type.
+        for (index, decl) in decls.into_iter().enumerate() {
+            let (params, kind) = match &decl.kind {
+                DeclKind::Struct(st) => {
+                    let params: Vec<Symbol> = st.params.iter().map(|p| p.name).collect();
+                    let fields = st.fields.iter().map(|f| (String::from(self.name(f.name.name)), self.type_expr(&f.ty, &params)));
+                    (params.len(), TypeKind::Struct(fields.collect()))
+                }
+                DeclKind::Type(t) => {
+                    let params: Vec<Symbol> = t.params.iter().map(|p| p.name).collect();
+                    let variants = t.variants.iter().map(|v| {
+                        (String::from(self.name(v.name.name)), v.fields.iter().map(|f| self.type_expr(f, &params)).collect())
+                    });
+                    (params.len(), TypeKind::Sum(variants.collect()))
+                }
+                _ => unreachable!(),
+            };
+            self.program.types[index].params = params as u32;
+            self.program.types[index].kind = kind;
+        }
+        for (i, module) in graph.modules.iter().enumerate() {
+            for decl in &module.ast.decls {
+                if let DeclKind::Func(func) = &decl.kind {
+                    let id = self.reserve(self.name(func.name.name), ModuleId(i), FuncKind::Func, func);
+                    self.funcs.insert((ModuleId(i), func.name.name), id);
+                }
+            }
+        }
+        for (i, module) in graph.modules.iter().enumerate() {
+            for decl in &module.ast.decls {
+                if let DeclKind::Instance(inst) = &decl.kind {
+                    let instances = &self.checked[i].types.instances;
+                    let type_name = match instances.iter().find(|(_, id)| **id == decl.id) {
+                        Some(((_, type_name), _)) => *type_name,
+                        None => continue,
+                    };
+                    let ty = self.types[&type_name] as usize;
+                    for method in &inst.methods {
+                        let method_name = self.name(method.name.name);
+                        let name = format!("{}.{}", self.name(type_name), method_name);
+                        let id = self.reserve(&name, ModuleId(i), FuncKind::Method, method);
+                        self.program.types[ty].methods.push((String::from(method_name), id));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn lower(mut self) -> Program {
+        self.collect();
+        for (id, module, func) in std::mem::take(&mut self.bodies) {
+            let params: Vec<&Pattern> = func.params.iter().map(|p| &p.pattern).collect();
+            // Recursive calls need the parameters of the shell.
+            let f = self.program.funcs[id as usize].clone();
+            self.program.funcs[id as usize] = self.body(f, module, HashMap::new(), &params, &func.body);
+        }
+        self.lower_main();
+        self.collect_modules();
+        self.program
+    }
+
+    fn collect_modules(&mut self) {
+        for (i, module) in self.graph.modules.iter().enumerate() {
+            let checked = &self.checked[i];
+            let mut exports = vec![];
+            let mut consts = vec![];
+            for (name, kind) in &checked.res.exports.values {
+                let label = String::from(self.name(*name));
+                match kind {
+                    DefKind::Func => exports.push((label, self.funcs[&(ModuleId(i), *name)])),
+                    DefKind::Const => {
+                        if let Some(value) = checked.consts.get(name) {
+                            consts.push((label, self.const_value(ModuleId(i), value)));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            exports.sort();
+            consts.sort_by(|a, b| a.0.cmp(&b.0));
+            self.program.modules.push(ModuleInfo {
+                name: module.name.clone(),
+                path: module.path.clone(),
+                hash: source_hash(&module.src),
+                exports,
+                consts,
+            });
+        }
+    }
+
+    // Lowers the body of `f`, whose parameters are already defined.
+    fn body(&mut self, f: Function, module: ModuleId, locals: HashMap<DefId, ValueId>, params: &[&Pattern], body: &Expr) -> Function {
+        let mut s = FnState {
+            f,
+            module,
+            block: BlockId(0),
+            locals,
+        };
+        s.block = s.f.new_block(body.span);
+        let args: Vec<ValueId> = s.f.params[s.f.captures as usize..].to_vec();
+        for (param, value) in params.iter().zip(args) {
+            self.bind(&mut s, param, value);
+        }
+        self.tail(&mut s, body);
+        s.f
+    }
+
+    // Prints the value of every expression statement of the root module.
+    fn lower_main(&mut self) {
+        let root = self.graph.root;
+        let mut s = FnState {
+            f: Function::new("<main>", root.0 as u32, FuncKind::Main, Span::default()),
+            module: root,
+            block: BlockId(0),
+            locals: HashMap::new(),
+        };
+        s.f.ret = Ty::unit();
+        s.block = s.f.new_block(Span::default());
+        let mut span = Span::default();
+        for decl in &self.graph.module(root).ast.decls {
+            if let DeclKind::Expr(expr) = &decl.kind {
+                let value = self.expr(&mut s, expr);
+                let shown = s.push(Inst::Show(value), Ty::Str, expr.span);
+                s.push(Inst::Print(shown), Ty::unit(), expr.span);
+                span = expr.span;
+            }
+        }
+        let unit = s.push(Inst::Tuple(vec![]), Ty::unit(), span);
+        s.terminate(Term::Return(unit), span);
+        self.program.main = self.program.funcs.len() as u32;
+        self.program.funcs.push(s.f);
+    }
+
+    // A top level name of `module`, or one it imports: module and name of
+    // the definition.
+    fn global_def(&self, module: ModuleId, id: DefId) -> (ModuleId, Symbol) {
+        let res = self.res(module);
+        let def = res.def(id);
+        match def.kind {
+            DefKind::Import => res.imports[&id],
+            _ => (module, def.name.name),
+        }
+    }
+
+    fn global_kind(&self, module: ModuleId, name: Symbol) -> DefKind {
+        let res = self.res(module);
+        res.def(res.module[&name]).kind
+    }
+
+    fn const_value(&self, module: ModuleId, value: &ConstValue) -> Const {
+        match value {
+            ConstValue::Int(val) => Const::Int(*val),
+            ConstValue::Float(val) => Const::Float(*val),
+            ConstValue::Char(val) => Const::Char(*val),
+            ConstValue::Str(val) => Const::Str(val.clone()),
+            ConstValue::Bool(val) => Const::Bool(*val),
+            ConstValue::Tuple(elems) => Const::Tuple(elems.iter().map(|e| self.const_value(module, e)).collect()),
+            ConstValue::Struct(name, values) => {
+                Const::Struct(self.types[name], values.iter().map(|v| self.const_value(module, v)).collect())
+            }
+            ConstValue::Variant(name, values) => {
+                let (ty, index, _) = self.variants[module.0][name];
+                Const::Variant(ty, index, values.iter().map(|v| self.const_value(module, v)).collect())
+            }
+        }
+    }
+
+    fn ctor_func(&mut self, ty: u32, index: u32) -> u32 {
+        if let Some(func) = self.ctor_funcs.get(&(ty, index)) {
+            return *func;
+        }
+        let def = &self.program.types[ty as usize];
+        let (name, fields) = match &def.kind {
+            TypeKind::Sum(variants) => variants[index as usize].clone(),
+            TypeKind::Struct(_) => unreachable!("constructor of a struct"),
+        };
+        let mut f = Function::new(&name, def.module, FuncKind::Ctor, Span::default());
+        f.ret = Ty::Sum(ty, vec![Ty::Any; def.params as usize]);
+        let args: Vec<ValueId> = fields.iter().map(|field| f.param(erase_params(field), None, Span::default())).collect();
+        let entry = f.new_block(Span::default());
+        let ret = f.ret.clone();
+        let value = f.push(entry, Inst::Variant(ty, index, args), ret, Span::default());
+        f.terminate(entry, Term::Return(value), Span::default());
+        let func = self.program.funcs.len() as u32;
+        self.program.funcs.push(f);
+        self.ctor_funcs.insert((ty, index), func);
+        func
+    }
+
+    // Value of a top level name. Functions without parameters are plain
+    // values, computed by calling them.
+    fn global(&mut self, s: &mut FnState, expr: &Expr, id: DefId) -> ValueId {
+        let (module, name) = self.global_def(s.module, id);
+        let ty = self.node_ty(s.module, expr.id);
+        let span = expr.span;
+        match self.global_kind(module, name) {
+            DefKind::Func => {
+                let func = self.funcs[&(module, name)];
+                if self.program.funcs[func as usize].arity() == 0 {
+                    s.push(Inst::Call(func, vec![]), ty, span)
+                } else {
+                    s.push(Inst::Closure(func, vec![]), ty, span)
+                }
+            }
+            DefKind::Const => {
+                let value = self.const_value(module, &self.checked[module.0].consts[&name]);
+                s.push(Inst::Const(value), ty, span)
+            }
+            DefKind::Variant => {
+                let (index, variant, arity) = self.variants[module.0][&name];
+                if arity == 0 {
+                    s.push(Inst::Variant(index, variant, vec![]), ty, span)
+                } else {
+                    let func = self.ctor_func(index, variant);
+                    s.push(Inst::Closure(func, vec![]), ty, span)
+                }
+            }
+            _ => unreachable!("'{}' is not a top level value", self.name(name)),
+        }
+    }
+
+    // Binds the names of an irrefutable pattern to parts of `value`.
+    fn bind(&mut self, s: &mut FnState, pattern: &Pattern, value: ValueId) {
+        let module = s.module;
+        let parts: Vec<(&Pattern, u32)> = match &pattern.kind {
+            PatternKind::Binding(_) => {
+                s.locals.insert(self.res(module).names[&pattern.id], value);
+                return;
+            }
+            PatternKind::Variant(_, args) | PatternKind::Tuple(args) => args.iter().zip(0..).collect(),
+            PatternKind::Struct(_, fields) => {
+                let name = self.checked[module.0].types.struct_lits[&pattern.id];
+                fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| {
+                        let index = match field.name {
+                            Some(field_name) => self.fields[&name].iter().position(|f| *f == field_name.name).unwrap(),
+                            None => i,
+                        };
+                        (&field.pattern, index as u32)
+                    })
+                    .collect()
+            }
+            _ => return,
+        };
+        let variant = match &pattern.kind {
+            PatternKind::Variant(_, _) => self.res(module).names.get(&pattern.id).map(|id| {
+                let (def_module, name) = self.global_def(module, *id);
+                self.variants[def_module.0][&name].1
+            }),
+            _ => None,
+        };
+        let tys = self.program.field_types(s.f.ty(value), variant);
+        for (part, index) in parts {
+            if let PatternKind::Wildcard = part.kind {
+                continue;
+            }
+            let ty = match (&part.kind, &tys) {
+                (PatternKind::Binding(_), _) => self.node_ty(module, part.id),
+                (_, Some(tys)) => tys[index as usize].clone(),
+                _ => Ty::Any,
+            };
+            let field = s.push(Inst::Field(value, index), ty, part.span);
+            self.bind(s, part, field);
+        }
+    }
+
+    // Lowers an expression in tail position, ending the current block.
+    fn tail(&mut self, s: &mut FnState, expr: &Expr) {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::If(cond, then_expr, else_expr) => {
+                let cond = self.expr(s, cond);
+                let then_block = s.f.new_block(span);
+                let else_block = s.f.new_block(span);
+                s.terminate(Term::Branch(cond, then_block, else_block), span);
+                s.block = then_block;
+                self.tail(s, then_expr);
+                s.block = else_block;
+                self.tail(s, else_expr);
+            }
+            ExprKind::Let(bindings, body) => {
+                self.let_bindings(s, bindings);
+                self.tail(s, body);
+            }
+            ExprKind::Case(scrutinee, arms) => {
+                self.case(s, expr, scrutinee, arms, true);
+            }
+            ExprKind::Call(_, _) => {
+                let (head, args) = flatten_call(expr);
+                if let Some((func, args)) = self.known_call(s, head, &args) {
+                    s.terminate(Term::TailCall(func, args), span);
+                } else if let Some(value) = self.variant_call(s, expr, head, &args) {
+                    s.terminate(Term::Return(value), span);
+                } else {
+                    let callee = self.expr(s, head);
+                    let args = args.iter().map(|arg| self.expr(s, arg)).collect();
+                    s.terminate(Term::TailCallValue(callee, args), span);
+                }
+            }
+            ExprKind::Name(_) | ExprKind::Path(_, _) => {
+                let id = self.res(s.module).names[&expr.id];
+                if !s.locals.contains_key(&id) {
+                    let (module, name) = self.global_def(s.module, id);
+                    if self.global_kind(module, name) == DefKind::Func {
+                        let func = self.funcs[&(module, name)];
+                        if self.program.funcs[func as usize].arity() == 0 {
+                            s.terminate(Term::TailCall(func, vec![]), span);
+                            return;
+                        }
+                    }
+                }
+                let value = self.expr(s, expr);
+                s.terminate(Term::Return(value), span);
+            }
+            _ => {
+                let value = self.expr(s, expr);
+                s.terminate(Term::Return(value), span);
+            }
+        }
+    }
+
+    fn let_bindings(&mut self, s: &mut FnState, bindings: &[LetBinding]) {
+        for binding in bindings {
+            let value = self.expr(s, &binding.expr);
+            self.bind(s, &binding.pattern, value);
+        }
+    }
+
+    // Calls of known functions with all their arguments skip building a
+    // closure: the function and the arguments.
+    fn known_call(&mut self, s: &mut FnState, head: &Expr, args: &[&Expr]) -> Option<(u32, Vec<ValueId>)> {
+        let (module, name) = self.known_global(s, head)?;
+        if self.global_kind(module, name) != DefKind::Func {
+            return None;
+        }
+        let func = self.funcs[&(module, name)];
+        if self.program.funcs[func as usize].arity() as usize != args.len() {
+            return None;
+        }
+        Some((func, args.iter().map(|arg| self.expr(s, arg)).collect()))
+    }
+
+    // A constructor applied to all its fields.
+    fn variant_call(&mut self, s: &mut FnState, expr: &Expr, head: &Expr, args: &[&Expr]) -> Option<ValueId> {
+        let (module, name) = self.known_global(s, head)?;
+        if self.global_kind(module, name) != DefKind::Variant {
+            return None;
+        }
+        let (ty, index, arity) = self.variants[module.0][&name];
+        if arity as usize != args.len() {
+            return None;
+        }
+        let args = args.iter().map(|arg| self.expr(s, arg)).collect();
+        Some(s.push(Inst::Variant(ty, index, args), self.node_ty(s.module, expr.id), expr.span))
+    }
+
+    fn known_global(&self, s: &FnState, head: &Expr) -> Option<(ModuleId, Symbol)> {
+        match &head.kind {
+            ExprKind::Name(_) | ExprKind::Path(_, _) => {
+                let id = self.res(s.module).names[&head.id];
+                if s.locals.contains_key(&id) {
+                    None
+                } else {
+                    Some(self.global_def(s.module, id))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // The instance method an operator on a user type dispatches to.
+    fn overload(&self, s: &FnState, expr: &Expr, method: &str) -> Option<u32> {
+        let (_, type_name) = self.checked[s.module.0].types.overloads.get(&expr.id)?;
+        self.program.types[*self.types.get(type_name)? as usize].method(method)
+    }
+
+    fn expr(&mut self, s: &mut FnState, expr: &Expr) -> ValueId {
+        let span = expr.span;
+        let ty = self.node_ty(s.module, expr.id);
+        match &expr.kind {
+            ExprKind::Int(val) => {
+                let val = i64::try_from(*val).expect("the checker rejects integer literals out of range");
+                s.push(Inst::Const(Const::Int(val)), Ty::Int, span)
+            }
+            ExprKind::Float(val) => s.push(Inst::Const(Const::Float(*val)), Ty::Float, span),
+            ExprKind::Char(val) => s.push(Inst::Const(Const::Char(*val)), Ty::Char, span),
+            ExprKind::Str(val) => s.push(Inst::Const(Const::Str(val.clone())), Ty::Str, span),
+            ExprKind::Bool(val) => s.push(Inst::Const(Const::Bool(*val)), Ty::Bool, span),
+            ExprKind::Template(parts) => {
+                let mut values = vec![];
+                for part in parts {
+                    values.push(match part {
+                        TemplatePart::Str(val) => s.push(Inst::Const(Const::Str(val.clone())), Ty::Str, span),
+                        TemplatePart::Expr(e) => {
+                            let value = self.expr(s, e);
+                            s.push(Inst::Show(value), Ty::Str, e.span)
+                        }
+                    });
+                }
+                s.push(Inst::Concat(values), Ty::Str, span)
+            }
+            ExprKind::Name(_) | ExprKind::Path(_, _) => {
+                let id = self.res(s.module).names[&expr.id];
+                match s.locals.get(&id) {
+                    Some(value) => *value,
+                    None => self.global(s, expr, id),
+                }
+            }
+            ExprKind::Field(e, field) => {
+                let name = match &self.checked[s.module.0].types.node_types[&e.id] {
+                    Type::Struct(name, _) => *name,
+                    ty => unreachable!("field access on {:?}", ty),
+                };
+                let index = self.fields[&name].iter().position(|f| *f == field.name).unwrap();
+                let value = self.expr(s, e);
+                s.push(Inst::Field(value, index as u32), ty, span)
+            }
+            _ if expr.is_min_int() => s.push(Inst::Const(Const::Int(i64::MIN)), Ty::Int, span),
+            ExprKind::Unary(op, e) => {
+                let value = self.expr(s, e);
+                match self.overload(s, expr, "neg") {
+                    Some(method) if *op == UnaryOp::Neg => s.push(Inst::Call(method, vec![value]), ty, span),
+                    _ => s.push(Inst::Unary(*op, value), ty, span),
+                }
+            }
+            ExprKind::Binary(op @ BinaryOp::And, l, r) | ExprKind::Binary(op @ BinaryOp::Or, l, r) => {
+                // The left operand is the result when it decides it.
+                let left = self.expr(s, l);
+                let from = s.block;
+                let right_block = s.f.new_block(span);
+                let join = s.f.new_block(span);
+                let term = match op {
+                    BinaryOp::And => Term::Branch(left, right_block, join),
+                    _ => Term::Branch(left, join, right_block),
+                };
+                s.terminate(term, span);
+                s.block = right_block;
+                let right = self.expr(s, r);
+                s.terminate(Term::Jump(join), span);
+                let right_from = s.block;
+                s.block = join;
+                s.f.phi(join, vec![(from, left), (right_from, right)], Ty::Bool, span)
+            }
+            ExprKind::Binary(op, l, r) => {
+                let left = self.expr(s, l);
+                let right = self.expr(s, r);
+                self.binary(s, expr, *op, left, right, ty)
+            }
+            ExprKind::Call(_, _) => {
+                let (head, args) = flatten_call(expr);
+                if let Some((func, args)) = self.known_call(s, head, &args) {
+                    return s.push(Inst::Call(func, args), ty, span);
+                }
+                if let Some(value) = self.variant_call(s, expr, head, &args) {
+                    return value;
+                }
+                let callee = self.expr(s, head);
+                let args = args.iter().map(|arg| self.expr(s, arg)).collect();
+                s.push(Inst::CallValue(callee, args), ty, span)
+            }
+            ExprKind::If(cond, then_expr, else_expr) => {
+                let cond = self.expr(s, cond);
+                let then_block = s.f.new_block(span);
+                let else_block = s.f.new_block(span);
+                let join = s.f.new_block(span);
+                s.terminate(Term::Branch(cond, then_block, else_block), span);
+                let mut incoming = vec![];
+                for (block, e) in [(then_block, then_expr), (else_block, else_expr)].iter() {
+                    s.block = *block;
+                    let value = self.expr(s, e);
+                    s.terminate(Term::Jump(join), span);
+                    incoming.push((s.block, value));
+                }
+                s.block = join;
+                s.f.phi(join, incoming, ty, span)
+            }
+            ExprKind::Let(bindings, body) => {
+                self.let_bindings(s, bindings);
+                self.expr(s, body)
+            }
+            ExprKind::StructLit(lit) => {
+                let name = self.checked[s.module.0].types.struct_lits[&expr.id];
+                let names = self.fields[&name].clone();
+                // Fields are evaluated in source order, then put in
+                // declaration order.
+                let mut values = vec![None; names.len()];
+                for (i, field) in lit.fields.iter().enumerate() {
+                    let index = match field.name {
+                        Some(field_name) => names.iter().position(|n| *n == field_name.name).unwrap(),
+                        None => i,
+                    };
+                    values[index] = Some(self.expr(s, &field.expr));
+                }
+                let values = values.into_iter().map(|v| v.unwrap()).collect();
+                s.push(Inst::Struct(self.types[&name], values), ty, span)
+            }
+            ExprKind::Tuple(elems) => {
+                let values = elems.iter().map(|e| self.expr(s, e)).collect();
+                s.push(Inst::Tuple(values), ty, span)
+            }
+            ExprKind::Lambda(params, body) => self.lambda(s, expr, params, body),
+            ExprKind::Case(scrutinee, arms) => self.case(s, expr, scrutinee, arms, false).unwrap(),
+            ExprKind::Error => unreachable!("lowering an erroneous expression"),
+        }
+    }
+
+    // Comparisons of user types derive from `eq` and `lt`.
+    fn binary(&mut self, s: &mut FnState, expr: &Expr, op: BinaryOp, left: ValueId, right: ValueId, ty: Ty) -> ValueId {
+        let span = expr.span;
+        let (method, args, negate) = match op {
+            BinaryOp::Add => ("add", (left, right), false),
+            BinaryOp::Sub => ("sub", (left, right), false),
+            BinaryOp::Mul => ("mul", (left, right), false),
+            BinaryOp::Div => ("div", (left, right), false),
+            BinaryOp::Eq => ("eq", (left, right), false),
+            BinaryOp::NotEq => ("eq", (left, right), true),
+            BinaryOp::Lt => ("lt", (left, right), false),
+            BinaryOp::Gt => ("lt", (right, left), false),
+            BinaryOp::LtEq => ("lt", (right, left), true),
+            BinaryOp::GtEq => ("lt", (left, right), true),
+            _ => return s.push(Inst::Binary(op, left, right), ty, span),
+        };
+        let method = match self.overload(s, expr, method) {
+            Some(method) => method,
+            None => return s.push(Inst::Binary(op, left, right), ty, span),
+        };
+        if !negate {
+            return s.push(Inst::Call(method, vec![args.0, args.1]), ty, span);
+        }
+        let value = s.push(Inst::Call(method, vec![args.0, args.1]), Ty::Bool, span);
+        s.push(Inst::Unary(UnaryOp::Not, value), Ty::Bool, span)
+    }
+
+    // Lambdas take the locals they use as their first parameters.
+    fn lambda(&mut self, s: &mut FnState, expr: &Expr, params: &[Pattern], body: &Expr) -> ValueId {
+        let ty = self.node_ty(s.module, expr.id);
+        let mut used = vec![];
+        used_locals(body, self.res(s.module), &mut used);
+        let captures: Vec<DefId> = used.into_iter().filter(|id| s.locals.contains_key(id)).collect();
+        let mut f = Function::new("lambda", s.module.0 as u32, FuncKind::Lambda, expr.span);
+        let mut locals = HashMap::new();
+        for id in &captures {
+            let value = s.locals[id];
+            let name = self.name(self.res(s.module).def(*id).name.name);
+            let param = f.param(s.f.ty(value).clone(), Some(String::from(name)), s.f.values[value.0 as usize].span);
+            locals.insert(*id, param);
+        }
+        f.captures = captures.len() as u32;
+        let mut rest = ty.clone();
+        for param in params {
+            let param_ty = match rest {
+                Ty::Func(param_ty, ret) => {
+                    rest = *ret;
+                    *param_ty
+                }
+                _ => Ty::Any,
+            };
+            let name = match param.kind {
+                PatternKind::Binding(name) => Some(String::from(self.name(name))),
+                _ => None,
+            };
+            f.param(param_ty, name, param.span);
+        }
+        f.ret = rest;
+        let params: Vec<&Pattern> = params.iter().collect();
+        let f = self.body(f, s.module, locals, &params, body);
+        self.program.funcs.push(f);
+        let func = self.program.funcs.len() as u32 - 1;
+        let captured = captures.iter().map(|id| s.locals[id]).collect();
+        s.push(Inst::Closure(func, captured), ty, expr.span)
+    }
+
+    // Lowers a case expression following its decision tree. In tail position
+    // every arm ends the function and there is no result.
+    fn case(&mut self, s: &mut FnState, expr: &Expr, scrutinee: &Expr, arms: &[CaseArm], tail: bool) -> Option<ValueId> {
+        let value = self.expr(s, scrutinee);
+        let tree = &self.checked[s.module.0].types.case_trees[&expr.id];
+        let res = self.res(s.module);
+        let mut c = CaseState {
+            arms,
+            defs: arms
+                .iter()
+                .map(|arm| {
+                    let mut defs = HashMap::new();
+                    pattern_defs(&arm.pattern, res, &mut defs);
+                    defs
+                })
+                .collect(),
+            leaves: vec![0; arms.len()],
+            blocks: vec![None; arms.len()],
+            incoming: vec![vec![]; arms.len()],
+            join: if tail { None } else { Some(s.f.new_block(expr.span)) },
+            results: vec![],
+        };
+        count_leaves(tree, &mut c.leaves);
+        for (arm, leaves) in c.leaves.iter().enumerate() {
+            if *leaves > 1 {
+                c.blocks[arm] = Some(s.f.new_block(arms[arm].body.span));
+            }
+        }
+        let mut known = HashMap::new();
+        known.insert(vec![], value);
+        self.decision(s, &mut c, tree, &known, &HashMap::new(), expr.span);
+        // Arms reached from several leaves.
+        for arm in 0..arms.len() {
+            let block = match c.blocks[arm] {
+                Some(block) => block,
+                None => continue,
+            };
+            s.block = block;
+            let incoming = std::mem::take(&mut c.incoming[arm]);
+            let mut names: Vec<&Symbol> = c.defs[arm].keys().collect();
+            names.sort();
+            for (i, name) in names.into_iter().enumerate() {
+                let values: Vec<(BlockId, ValueId)> = incoming.iter().map(|(b, values)| (*b, values[i])).collect();
+                let mut ty = s.f.ty(values[0].1).clone();
+                if values.iter().any(|(_, v)| *s.f.ty(*v) != ty) {
+                    ty = Ty::Any;
+                }
+                let phi = s.f.phi(block, values, ty, expr.span);
+                s.locals.insert(c.defs[arm][name], phi);
+            }
+            self.arm(s, &mut c, arm);
+        }
+        let join = c.join?;
+        s.block = join;
+        let first = c.results[0].1;
+        if c.results.iter().all(|(_, v)| *v == first) {
+            return Some(first);
+        }
+        let ty = self.node_ty(s.module, expr.id);
+        Some(s.f.phi(join, c.results, ty, expr.span))
+    }
+
+    fn arm(&mut self, s: &mut FnState, c: &mut CaseState, arm: usize) {
+        let body = &c.arms[arm].body;
+        match c.join {
+            None => self.tail(s, body),
+            Some(join) => {
+                let value = self.expr(s, body);
+                s.terminate(Term::Jump(join), body.span);
+                c.results.push((s.block, value));
+            }
+        }
+    }
+
+    // Value at an occurrence, from the closest one already extracted on the
+    // way to the current block.
+    fn occurrence(
+        &mut self,
+        s: &mut FnState,
+        known: &mut HashMap<Occurrence, ValueId>,
+        ctors: &HashMap<Occurrence, Ctor>,
+        occurrence: &[usize],
+        span: Span,
+    ) -> ValueId {
+        if let Some(value) = known.get(occurrence) {
+            return *value;
+        }
+        let (last, parent_occ) = occurrence.split_last().unwrap();
+        let parent = self.occurrence(s, known, ctors, parent_occ, span);
+        let variant = match ctors.get(parent_occ) {
+            Some(Ctor::Variant(_, index)) => Some(*index as u32),
+            _ => None,
+        };
+        let ty = match self.program.field_types(s.f.ty(parent), variant) {
+            Some(tys) => tys.get(*last).cloned().unwrap_or(Ty::Any),
+            None => Ty::Any,
+        };
+        let value = s.push(Inst::Field(parent, *last as u32), ty, span);
+        known.insert(occurrence.to_vec(), value);
+        value
+    }
+
+    fn decision(
+        &mut self,
+        s: &mut FnState,
+        c: &mut CaseState,
+        tree: &Decision,
+        known: &HashMap<Occurrence, ValueId>,
+        ctors: &HashMap<Occurrence, Ctor>,
+        span: Span,
+    ) {
+        let mut known = known.clone();
+        match tree {
+            Decision::Fail => s.terminate(Term::MatchFail, span),
+            Decision::Leaf { arm, bindings } => {
+                let mut bindings: Vec<&(Symbol, Occurrence)> = bindings.iter().collect();
+                bindings.sort_by_key(|(name, _)| *name);
+                let values: Vec<ValueId> =
+                    bindings.iter().map(|(_, occurrence)| self.occurrence(s, &mut known, ctors, occurrence, span)).collect();
+                match c.blocks[*arm] {
+                    Some(block) => {
+                        s.terminate(Term::Jump(block), span);
+                        c.incoming[*arm].push((s.block, values));
+                    }
+                    None => {
+                        for ((name, _), value) in bindings.iter().zip(values) {
+                            s.locals.insert(c.defs[*arm][name], value);
+                        }
+                        self.arm(s, c, *arm);
+                    }
+                }
+            }
+            Decision::Switch { occurrence, cases, default } => {
+                let value = self.occurrence(s, &mut known, ctors, occurrence, span);
+                for (ctor, sub) in cases {
+                    let mut sub_ctors = ctors.clone();
+                    sub_ctors.insert(occurrence.clone(), ctor.clone());
+                    let constant = match ctor {
+                        // Products have a single constructor.
+                        Ctor::Tuple(_) | Ctor::Struct(_, _) => return self.decision(s, c, sub, &known, &sub_ctors, span),
+                        Ctor::Variant(_, index) => {
+                            let test = s.push(Inst::IsVariant(value, *index as u32), Ty::Bool, span);
+                            self.test(s, c, test, sub, &known, &sub_ctors, span);
+                            continue;
+                        }
+                        Ctor::Bool(true) => {
+                            self.test(s, c, value, sub, &known, &sub_ctors, span);
+                            continue;
+                        }
+                        Ctor::Bool(false) => {
+                            let test = s.push(Inst::Unary(UnaryOp::Not, value), Ty::Bool, span);
+                            self.test(s, c, test, sub, &known, &sub_ctors, span);
+                            continue;
+                        }
+                        Ctor::Int(val) => (Const::Int(*val), Ty::Int),
+                        Ctor::Char(val) => (Const::Char(*val), Ty::Char),
+                        Ctor::Str(val) => (Const::Str(val.clone()), Ty::Str),
+                    };
+                    let constant = s.push(Inst::Const(constant.0), constant.1, span);
+                    let test = s.push(Inst::Binary(BinaryOp::Eq, value, constant), Ty::Bool, span);
+                    self.test(s, c, test, sub, &known, &sub_ctors, span);
+                }
+                match default {
+                    Some(default) => self.decision(s, c, default, &known, ctors, span),
+                    None => s.terminate(Term::MatchFail, span),
+                }
+            }
+        }
+    }
+
+    // Branches to `sub` when `test` holds, continuing with the next test
+    // otherwise.
+    #[allow(clippy::too_many_arguments)]
+    fn test(
+        &mut self,
+        s: &mut FnState,
+        c: &mut CaseState,
+        test: ValueId,
+        sub: &Decision,
+        known: &HashMap<Occurrence, ValueId>,
+        ctors: &HashMap<Occurrence, Ctor>,
+        span: Span,
+    ) {
+        let then_block = s.f.new_block(span);
+        let else_block = s.f.new_block(span);
+        s.terminate(Term::Branch(test, then_block, else_block), span);
+        s.block = then_block;
+        self.decision(s, c, sub, known, ctors, span);
+        s.block = else_block;
+    }
+}
+
+fn count_leaves(tree: &Decision, leaves: &mut Vec<usize>) {
+    match tree {
+        Decision::Fail => {}
+        Decision::Leaf { arm, .. } => leaves[*arm] += 1,
+        Decision::Switch { cases, default, .. } => {
+            for (_, sub) in cases {
+                count_leaves(sub, leaves);
+            }
+            if let Some(default) = default {
+                count_leaves(default, leaves);
+            }
+        }
+    }
+}
+
+pub fn lower_program(graph: &ModuleGraph, checked: &[CheckedModule], interner: &Interner) -> Program {
+    let program = Lowerer::new(graph, checked, interner).lower();
+    debug_verify(&program, "lowering");
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::check_test_program;
+
+    fn lower(src: &str) -> Program {
+        let (graph, checked, interner) = check_test_program(src, "");
+        let program = lower_program(&graph, &checked, &interner);
+        println!("{}", program.dump());
+        if let Err(errors) = verify(&program) {
+            panic!("{:#?}", errors);
+        }
+        program
+    }
+
+    #[test]
+    fn test_dump() {
+        let program = lower(
+            "
+max a, b: int -> int
+    if a > b && a != 0
+        a
+    else
+        b
+
+max 1 2
+",
+        );
+        let expected = "fn 0 main::max(v0: int, v1: int) -> int
+  b0:
+    v2: bool = binary > v0, v1
+    branch v2 b1 b2
+  b1:  ; preds b0
+    v3: int = const 0
+    v4: bool = binary != v0, v3
+    jump b2
+  b2:  ; preds b0, b1
+    v5: bool = phi b0 v2, b1 v4
+    branch v5 b3 b4
+  b3:  ; preds b2
+    return v0
+  b4:  ; preds b2
+    return v1
+";
+        assert!(program.dump_function(0) == expected);
+    }
+
+    #[test]
+    fn test_case() {
+        // The second arm is reached from two leaves, so its binding is a phi.
+        let program = lower(
+            "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+pick a, b: int -> int
+    case (a, b) of
+        (1, 1) -> 0
+        (_, y) -> y
+
+area s: Shape -> float
+    let
+        scale = case s of
+            Empty -> 0.0
+            _ -> 1.0
+    in
+    case s of
+        Circle r -> scale * r * r * 3.0
+        Rect w h -> scale * w * h
+        Empty -> 0.0
+
+pick 1 0
+area (Rect 2.0 3.0)
+",
+        );
+        let phis = program.funcs[0].values.iter().filter(|v| matches!(v.def, Def::Phi(_))).count();
+        assert!(phis == 1);
+        let area = &program.funcs[1];
+        let fields: Vec<&Ty> = area.values.iter().filter(|v| matches!(v.def, Def::Inst(Inst::Field(_, _)))).map(|v| &v.ty).collect();
+        assert!(fields == vec![&Ty::Float, &Ty::Float, &Ty::Float]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let src = String::from(crate::prelude::PRELUDE)
+            + "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+struct Vector =
+    x, y: float
+
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    sub a, b = Vector {a.x - b.x, a.y - b.y}
+    mul a, b = Vector {a.x * b.x, a.y * b.y}
+    div a, b = Vector {a.x / b.x, a.y / b.y}
+    neg v = Vector {-v.x, -v.y}
+
+instance Ord Vector =
+    lt a, b = a.x < b.x
+
+norm {x, y}: Vector -> float
+    x * x + y * y
+
+adder n: int -> (int -> int)
+    \\x -> x + n
+
+range n: int -> List int
+    if n == 0
+        Nil
+    else
+        Cons n (range (n - 1))
+
+const ORIGIN = Vector {x = 0.0, y = 0.0}
+
+foldl (\\acc x -> acc + x) 0 (range 10)
+map (adder 2) (range 3)
+Vector {1.0, 2.0} + {3.0, 4.0} >= ORIGIN
+-(Vector {1.0, 2.0})
+norm {y = 2.0, x = 1.0}
+map Circle (Cons 1.0 Nil)
+`${norm ORIGIN} ${1 < 2 || false}`
+";
+        let program = lower(&src);
+        // Comparisons of vectors call `lt` and negate it.
+        let main = &program.funcs[program.main as usize];
+        let lt = program.types.iter().find(|t| t.name == "Vector").unwrap().method("lt").unwrap();
+        assert!(main.values.iter().any(|v| matches!(&v.def, Def::Inst(Inst::Call(func, _)) if *func == lt)));
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let mut program = lower("inc n: int -> int\n    n + 1\n\ninc 41\n");
+        // Uses the constant before defining it, and returns the wrong type
+        // to its caller.
+        let f = &mut program.funcs[0];
+        f.blocks[0].insts.reverse();
+        f.ret = Ty::Bool;
+        let errors = verify(&program).unwrap_err();
+        println!("{:?}", errors);
+        assert!(errors == vec![
+            String::from("main::inc: v1 doesn't dominate its use in b0"),
+            String::from("main::inc: b0: returns a value of the wrong type"),
+            String::from("<main>: v1: the result doesn't have the callee's return type"),
+        ]);
+    }
+}
@@ -0,0 +1,1262 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ast::*;
+use crate::common::{Interner, Span};
+use crate::diagnostic::{Diagnostic, Level};
+use crate::json::Json;
+use crate::lexer::KEYWORDS;
+use crate::modules::{check_program, load_program, read_file, CheckedModule, ModuleGraph, ModuleId};
+use crate::resolve::{DefId, DefKind};
+use crate::semantic::{encode, semantic_tokens, token_edit, TOKEN_MODIFIERS, TOKEN_TYPES};
+use crate::types::{name_vars, type_to_string};
+
+// Language server of `sp lsp`, speaking JSON-RPC over stdio. Every open
+// document is checked as the root of a program, with the imported modules
+// read from the open documents first, then from disk. Documents are synced
+// whole on every change, semantic tokens are sent whole or as an edit of the
+// previous ones.
+
+// JSON-RPC error codes.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+// Symbol and completion kinds of the protocol.
+const SYMBOL_METHOD: i64 = 6;
+const SYMBOL_FIELD: i64 = 8;
+const SYMBOL_ENUM: i64 = 10;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_CONSTANT: i64 = 14;
+const SYMBOL_OBJECT: i64 = 19;
+const SYMBOL_ENUM_MEMBER: i64 = 22;
+const SYMBOL_STRUCT: i64 = 23;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_MODULE: i64 = 9;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_ENUM_MEMBER: i64 = 20;
+const COMPLETION_CONSTANT: i64 = 21;
+const COMPLETION_STRUCT: i64 = 22;
+
+const BUILTIN_TYPES: [&str; 5] = ["int", "float", "char", "string", "bool"];
+
+type RpcResult = Result<Json, (i64, String)>;
+
+// Reads the body of the next message, None at the end of the input.
+pub fn read_message(input: &mut dyn BufRead) -> Result<Option<String>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) if length.is_none() => return Ok(None),
+            Ok(0) => return Err(String::from("Unexpected end of input in message header")),
+            Ok(_) => {}
+            Err(err) => return Err(format!("Cannot read input: {}", err)),
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| String::from("Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input
+        .read_exact(&mut body)
+        .map_err(|err| format!("Cannot read input: {}", err))?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| String::from("Message is not UTF-8"))
+}
+
+pub fn write_message(out: &mut dyn Write, msg: &Json) -> std::io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+// `file:///a%20b/c.sp` is `/a b/c.sp`. Other URIs are kept as paths.
+pub fn uri_path(uri: &str) -> PathBuf {
+    let path = match uri.strip_prefix("file://") {
+        Some(path) => path,
+        None => return PathBuf::from(uri),
+    };
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+pub fn path_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.display().to_string().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+// Positions count UTF-16 code units from the start of a line, both 0-based.
+pub fn offset_position(src: &str, offset: usize) -> Json {
+    let offset = offset.min(src.len());
+    let line_start = src[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = src[..offset].matches('\n').count();
+    let character = src[line_start..offset].encode_utf16().count();
+    Json::object(vec![
+        ("line", Json::Int(line as i64)),
+        ("character", Json::Int(character as i64)),
+    ])
+}
+
+pub fn position_offset(src: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line").and_then(Json::as_i64)?;
+    let character = position.get("character").and_then(Json::as_i64)? as usize;
+    let mut start = 0;
+    for _ in 0..line {
+        start += src[start..].find('\n')? + 1;
+    }
+    let mut units = 0;
+    for (i, c) in src[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(src.len())
+}
+
+pub fn span_range(src: &str, span: Span) -> Json {
+    Json::object(vec![
+        ("start", offset_position(src, span.start)),
+        ("end", offset_position(src, span.end)),
+    ])
+}
+
+fn contains(span: Span, offset: usize) -> bool {
+    span.start <= offset && offset <= span.end
+}
+
+// The expressions and patterns, the nodes names and types are recorded for.
+#[derive(Clone, Copy)]
+pub enum Node<'a> {
+    Expr(&'a Expr),
+    Pattern(&'a Pattern),
+}
+
+impl<'a> Node<'a> {
+    pub fn id(self) -> NodeId {
+        match self {
+            Node::Expr(e) => e.id,
+            Node::Pattern(p) => p.id,
+        }
+    }
+
+    pub fn span(self) -> Span {
+        match self {
+            Node::Expr(e) => e.span,
+            Node::Pattern(p) => p.span,
+        }
+    }
+
+    // Span of the name the node refers to, such as `greet` in `foo::greet`.
+    pub fn name_span(self) -> Span {
+        match self {
+            Node::Expr(Expr {
+                kind: ExprKind::Path(_, member),
+                ..
+            }) => member.span,
+            Node::Pattern(Pattern {
+                kind: PatternKind::Variant(name, _),
+                ..
+            }) => name.span,
+            _ => self.span(),
+        }
+    }
+}
+
+fn pattern_nodes<'a>(pattern: &'a Pattern, nodes: &mut Vec<Node<'a>>) {
+    nodes.push(Node::Pattern(pattern));
+    match &pattern.kind {
+        PatternKind::Variant(_, elems) | PatternKind::Tuple(elems) => {
+            for elem in elems {
+                pattern_nodes(elem, nodes);
+            }
+        }
+        PatternKind::Struct(_, fields) => {
+            for field in fields {
+                pattern_nodes(&field.pattern, nodes);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn expr_nodes<'a>(expr: &'a Expr, nodes: &mut Vec<Node<'a>>) {
+    nodes.push(Node::Expr(expr));
+    match &expr.kind {
+        ExprKind::Field(e, _) | ExprKind::Unary(_, e) => expr_nodes(e, nodes),
+        ExprKind::Binary(_, l, r) => {
+            expr_nodes(l, nodes);
+            expr_nodes(r, nodes);
+        }
+        ExprKind::Call(func, args) => {
+            expr_nodes(func, nodes);
+            for arg in args {
+                expr_nodes(arg, nodes);
+            }
+        }
+        ExprKind::If(cond, then_expr, else_expr) => {
+            expr_nodes(cond, nodes);
+            expr_nodes(then_expr, nodes);
+            expr_nodes(else_expr, nodes);
+        }
+        ExprKind::Let(bindings, body) => {
+            for binding in bindings {
+                pattern_nodes(&binding.pattern, nodes);
+                expr_nodes(&binding.expr, nodes);
+            }
+            expr_nodes(body, nodes);
+        }
+        ExprKind::Lambda(params, body) => {
+            for param in params {
+                pattern_nodes(param, nodes);
+            }
+            expr_nodes(body, nodes);
+        }
+        ExprKind::Case(scrutinee, arms) => {
+            expr_nodes(scrutinee, nodes);
+            for arm in arms {
+                pattern_nodes(&arm.pattern, nodes);
+                expr_nodes(&arm.body, nodes);
+            }
+        }
+        ExprKind::StructLit(lit) => {
+            for field in &lit.fields {
+                expr_nodes(&field.expr, nodes);
+            }
+        }
+        ExprKind::Tuple(elems) => {
+            for elem in elems {
+                expr_nodes(elem, nodes);
+            }
+        }
+        ExprKind::Template(parts) => {
+            for part in parts {
+                if let TemplatePart::Expr(e) = part {
+                    expr_nodes(e, nodes);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn func_nodes<'a>(func: &'a FuncDecl, nodes: &mut Vec<Node<'a>>) {
+    for param in &func.params {
+        pattern_nodes(&param.pattern, nodes);
+    }
+    expr_nodes(&func.body, nodes);
+}
+
+// Every node of a module, each one before the nodes inside it.
+pub fn module_nodes(module: &Module) -> Vec<Node<'_>> {
+    let mut nodes = vec![];
+    for decl in &module.decls {
+        match &decl.kind {
+            DeclKind::Const(c) => expr_nodes(&c.expr, &mut nodes),
+            DeclKind::Func(func) => func_nodes(func, &mut nodes),
+            DeclKind::Instance(inst) => {
+                for method in &inst.methods {
+                    func_nodes(method, &mut nodes);
+                }
+            }
+            DeclKind::Expr(expr) => expr_nodes(expr, &mut nodes),
+            _ => {}
+        }
+    }
+    nodes
+}
+
+// The innermost node at `offset` accepted by `keep`.
+fn node_at<'a>(nodes: &[Node<'a>], offset: usize, keep: &dyn Fn(Node<'a>) -> bool) -> Option<Node<'a>> {
+    let mut best: Option<Node<'a>> = None;
+    for node in nodes {
+        let span = node.span();
+        if contains(span, offset)
+            && keep(*node)
+            && best.is_none_or(|b| span.end - span.start <= b.span().end - b.span().start)
+        {
+            best = Some(*node);
+        }
+    }
+    best
+}
+
+// The identifier around `offset`.
+fn word_at(src: &str, offset: usize) -> &str {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let start = src[..offset].rfind(|c| !is_ident(c)).map(|i| i + 1).unwrap_or(0);
+    let end = src[offset..]
+        .find(|c| !is_ident(c))
+        .map(|i| offset + i)
+        .unwrap_or(src.len());
+    &src[start..end]
+}
+
+// How a function is declared, up to its body.
+fn func_header<'s>(src: &'s str, decl: &Decl, func: &FuncDecl) -> &'s str {
+    src[decl.span.start..func.body.span.start]
+        .trim_end()
+        .trim_end_matches('=')
+        .trim_end()
+}
+
+fn diagnostic_json(diag: &Diagnostic, src: &str) -> Json {
+    let severity = match diag.level {
+        Level::Error => 1,
+        Level::Warning => 2,
+    };
+    let mut message = diag.msg.clone();
+    for note in &diag.notes {
+        message.push_str(&format!("\nnote: {}", note));
+    }
+    Json::object(vec![
+        ("range", span_range(src, diag.span)),
+        ("severity", Json::Int(severity)),
+        ("source", Json::str("sp")),
+        ("message", Json::Str(message)),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::str("2.0")),
+        ("method", Json::str(method)),
+        ("params", params),
+    ])
+}
+
+fn response(id: Json, result: RpcResult) -> Json {
+    let (key, value) = match result {
+        Ok(result) => ("result", result),
+        Err((code, message)) => (
+            "error",
+            Json::object(vec![("code", Json::Int(code)), ("message", Json::Str(message))]),
+        ),
+    };
+    Json::object(vec![("jsonrpc", Json::str("2.0")), ("id", id), (key, value)])
+}
+
+fn semantic_legend() -> Json {
+    Json::object(vec![
+        (
+            "tokenTypes",
+            Json::Array(TOKEN_TYPES.iter().map(|t| Json::str(*t)).collect()),
+        ),
+        (
+            "tokenModifiers",
+            Json::Array(TOKEN_MODIFIERS.iter().map(|m| Json::str(*m)).collect()),
+        ),
+    ])
+}
+
+fn invalid_params() -> (i64, String) {
+    (INVALID_PARAMS, String::from("Invalid params"))
+}
+
+fn completion(label: &str, kind: i64, detail: Option<String>) -> Json {
+    let mut fields = vec![("label", Json::str(label)), ("kind", Json::Int(kind))];
+    if let Some(detail) = detail {
+        fields.push(("detail", Json::Str(detail)));
+    }
+    Json::object(fields)
+}
+
+fn symbol(name: &str, kind: i64, src: &str, span: Span, name_span: Span, children: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("name", Json::str(name)),
+        ("kind", Json::Int(kind)),
+        ("range", span_range(src, span)),
+        ("selectionRange", span_range(src, name_span)),
+        ("children", Json::Array(children)),
+    ])
+}
+
+// A checked program rooted at an open document.
+pub struct Analysis {
+    pub uri: String,
+    pub graph: ModuleGraph,
+    pub checked: Vec<CheckedModule>,
+}
+
+impl Analysis {
+    pub fn root(&self) -> ModuleId {
+        self.graph.root
+    }
+
+    pub fn src(&self) -> &str {
+        &self.graph.module(self.root()).src
+    }
+
+    fn location(&self, module: ModuleId, span: Span) -> Json {
+        let loaded = self.graph.module(module);
+        let uri = if module == self.root() {
+            self.uri.clone()
+        } else {
+            path_uri(&loaded.path)
+        };
+        Json::object(vec![("uri", Json::Str(uri)), ("range", span_range(&loaded.src, span))])
+    }
+
+    // Follows an import to the definition in the module defining the name.
+    pub fn target(&self, module: ModuleId, def: DefId) -> (ModuleId, DefId) {
+        if let Some((from, name)) = self.checked[module.0].res.imports.get(&def) {
+            if let Some(def) = self.checked[from.0].res.module.get(name) {
+                return (*from, *def);
+            }
+        }
+        (module, def)
+    }
+
+    // The definition named at `offset` of the root, by a use or where it's
+    // defined.
+    fn def_at(&self, offset: usize) -> Option<(ModuleId, DefId)> {
+        let root = self.root();
+        let res = &self.checked[root.0].res;
+        let nodes = module_nodes(&self.graph.module(root).ast);
+        // `foo` in `foo::greet` names the module.
+        for node in &nodes {
+            if let Node::Expr(Expr {
+                kind: ExprKind::Path(module, _),
+                ..
+            }) = node
+            {
+                if contains(module.span, offset) {
+                    return res.module.get(&module.name).map(|def| (root, *def));
+                }
+            }
+        }
+        if let Some(node) = node_at(&nodes, offset, &|node| res.names.contains_key(&node.id())) {
+            return Some((root, res.names[&node.id()]));
+        }
+        let def = res.defs.iter().position(|def| contains(def.name.span, offset))?;
+        Some((root, DefId(def as u32)))
+    }
+
+    // Struct or sum type declared under `name` in `module`.
+    fn type_decl(&self, module: ModuleId, name: &str, interner: &Interner) -> Option<Span> {
+        self.graph
+            .module(module)
+            .ast
+            .decls
+            .iter()
+            .find_map(|decl| match &decl.kind {
+                DeclKind::Struct(s) if interner.get(s.name.name) == name => Some(s.name.span),
+                DeclKind::Type(t) if interner.get(t.name.name) == name => Some(t.name.span),
+                _ => None,
+            })
+    }
+
+    pub fn definition(&self, offset: usize, interner: &Interner) -> Option<Json> {
+        if let Some((module, def)) = self.def_at(offset) {
+            let (module, def) = self.target(module, def);
+            let res = &self.checked[module.0].res;
+            if let Some(imported) = res.modules.get(&def) {
+                return Some(self.location(*imported, Span::new(0, 0)));
+            }
+            return Some(self.location(module, res.def(def).name.span));
+        }
+        // Type names aren't resolved, they're looked up where declared.
+        let word = word_at(self.src(), offset);
+        let root = self.root();
+        if let Some(span) = self.type_decl(root, word, interner) {
+            return Some(self.location(root, span));
+        }
+        let sym = interner.lookup(word)?;
+        let module = *self.checked[root.0].res.imported_types.get(&sym)?;
+        self.type_decl(module, word, interner)
+            .map(|span| self.location(module, span))
+    }
+
+    pub fn references(&self, offset: usize, include_decl: bool) -> Vec<Json> {
+        let target = match self.def_at(offset) {
+            Some((module, def)) => self.target(module, def),
+            None => return vec![],
+        };
+        let site = (target.0, self.checked[target.0 .0].res.def(target.1).name.span);
+        let mut refs = vec![];
+        for (i, loaded) in self.graph.modules.iter().enumerate() {
+            let module = ModuleId(i);
+            let res = &self.checked[i].res;
+            for node in module_nodes(&loaded.ast) {
+                if let Some(def) = res.names.get(&node.id()) {
+                    if self.target(module, *def) == target {
+                        refs.push((module, node.name_span()));
+                    }
+                }
+            }
+        }
+        refs.retain(|r| *r != site);
+        if include_decl {
+            refs.insert(0, site);
+        }
+        refs.into_iter()
+            .map(|(module, span)| self.location(module, span))
+            .collect()
+    }
+
+    pub fn hover(&self, offset: usize, interner: &mut Interner) -> Option<Json> {
+        let root = self.root();
+        let checked = &self.checked[root.0];
+        let src = self.src();
+        let nodes = module_nodes(&self.graph.module(root).ast);
+        let typed = node_at(&nodes, offset, &|node| {
+            checked.types.node_types.contains_key(&node.id())
+        });
+        let (text, span) = match typed {
+            Some(node) => {
+                let ty = name_vars(&checked.types.node_types[&node.id()], &mut vec![], interner);
+                let ty = type_to_string(&ty, interner);
+                let name = match node {
+                    Node::Expr(Expr {
+                        kind: ExprKind::Name(name),
+                        ..
+                    }) => Some(interner.get(*name)),
+                    Node::Expr(Expr {
+                        kind: ExprKind::Path(_, name),
+                        ..
+                    })
+                    | Node::Expr(Expr {
+                        kind: ExprKind::Field(_, name),
+                        ..
+                    }) => Some(interner.get(name.name)),
+                    Node::Pattern(Pattern {
+                        kind: PatternKind::Binding(name),
+                        ..
+                    }) => Some(interner.get(*name)),
+                    _ => None,
+                };
+                match name {
+                    Some(name) => (format!("{}: {}", name, ty), node.span()),
+                    None => (ty, node.span()),
+                }
+            }
+            // The name of a declaration.
+            None => self
+                .graph
+                .module(root)
+                .ast
+                .decls
+                .iter()
+                .find_map(|decl| match &decl.kind {
+                    DeclKind::Func(func) if contains(func.name.span, offset) => {
+                        Some((String::from(func_header(src, decl, func)), func.name.span))
+                    }
+                    DeclKind::Const(c) if contains(c.name.span, offset) => {
+                        let ty = checked.types.node_types.get(&c.expr.id)?;
+                        Some((
+                            format!("{}: {}", interner.get(c.name.name), type_to_string(ty, interner)),
+                            c.name.span,
+                        ))
+                    }
+                    DeclKind::Struct(s) if contains(s.name.span, offset) => {
+                        Some((format!("struct {}", interner.get(s.name.name)), s.name.span))
+                    }
+                    DeclKind::Type(t) if contains(t.name.span, offset) => {
+                        Some((format!("type {}", interner.get(t.name.name)), t.name.span))
+                    }
+                    _ => None,
+                })?,
+        };
+        Some(Json::object(vec![
+            (
+                "contents",
+                Json::object(vec![
+                    ("kind", Json::str("markdown")),
+                    ("value", Json::Str(format!("```sp\n{}\n```", text))),
+                ]),
+            ),
+            ("range", span_range(src, span)),
+        ]))
+    }
+
+    pub fn symbols(&self, interner: &Interner) -> Vec<Json> {
+        let src = self.src();
+        let name = |ident: &Ident| interner.get(ident.name);
+        let mut symbols = vec![];
+        for decl in &self.graph.module(self.root()).ast.decls {
+            match &decl.kind {
+                DeclKind::Func(func) => symbols.push(symbol(
+                    name(&func.name),
+                    SYMBOL_FUNCTION,
+                    src,
+                    decl.span,
+                    func.name.span,
+                    vec![],
+                )),
+                DeclKind::Const(c) => symbols.push(symbol(
+                    name(&c.name),
+                    SYMBOL_CONSTANT,
+                    src,
+                    decl.span,
+                    c.name.span,
+                    vec![],
+                )),
+                DeclKind::Struct(s) => {
+                    let fields = s
+                        .fields
+                        .iter()
+                        .map(|f| symbol(name(&f.name), SYMBOL_FIELD, src, f.name.span, f.name.span, vec![]));
+                    symbols.push(symbol(
+                        name(&s.name),
+                        SYMBOL_STRUCT,
+                        src,
+                        decl.span,
+                        s.name.span,
+                        fields.collect(),
+                    ));
+                }
+                DeclKind::Type(t) => {
+                    let variants = t
+                        .variants
+                        .iter()
+                        .map(|v| symbol(name(&v.name), SYMBOL_ENUM_MEMBER, src, v.name.span, v.name.span, vec![]));
+                    symbols.push(symbol(
+                        name(&t.name),
+                        SYMBOL_ENUM,
+                        src,
+                        decl.span,
+                        t.name.span,
+                        variants.collect(),
+                    ));
+                }
+                DeclKind::Instance(inst) => {
+                    let label = format!(
+                        "instance {} {}",
+                        name(&inst.trait_name),
+                        &src[inst.ty.span.start..inst.ty.span.end]
+                    );
+                    let methods = inst
+                        .methods
+                        .iter()
+                        .map(|m| symbol(name(&m.name), SYMBOL_METHOD, src, m.name.span, m.name.span, vec![]));
+                    symbols.push(symbol(
+                        &label,
+                        SYMBOL_OBJECT,
+                        src,
+                        decl.span,
+                        inst.trait_name.span,
+                        methods.collect(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        symbols
+    }
+
+    // How a top level value of a module is declared or typed.
+    fn detail(&self, module: ModuleId, name: &str, interner: &Interner) -> Option<String> {
+        let loaded = self.graph.module(module);
+        loaded.ast.decls.iter().find_map(|decl| match &decl.kind {
+            DeclKind::Func(func) if interner.get(func.name.name) == name => {
+                Some(String::from(func_header(&loaded.src, decl, func)))
+            }
+            DeclKind::Const(c) if interner.get(c.name.name) == name => {
+                let ty = self.checked[module.0].types.node_types.get(&c.expr.id)?;
+                Some(type_to_string(ty, interner))
+            }
+            _ => None,
+        })
+    }
+
+    // Names visible at `offset`, or the members of the module before `::`.
+    pub fn completions(&self, offset: usize, interner: &Interner) -> Vec<Json> {
+        let root = self.root();
+        let res = &self.checked[root.0].res;
+        let src = self.src();
+        let line = &src[src[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0)..offset];
+        let before = line.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_');
+        let mut items = vec![];
+        if let Some(head) = before.strip_suffix("::") {
+            let module_name = word_at(head, head.len());
+            let module = interner
+                .lookup(module_name)
+                .and_then(|sym| res.module.get(&sym))
+                .and_then(|def| res.modules.get(def));
+            if let Some(module) = module {
+                let exports = &self.checked[module.0].res.exports;
+                for (sym, kind) in &exports.values {
+                    let name = interner.get(*sym);
+                    let kind = match kind {
+                        DefKind::Func => COMPLETION_FUNCTION,
+                        DefKind::Variant => COMPLETION_ENUM_MEMBER,
+                        _ => COMPLETION_CONSTANT,
+                    };
+                    items.push(completion(name, kind, self.detail(*module, name, interner)));
+                }
+                for sym in exports.types.keys() {
+                    items.push(completion(interner.get(*sym), COMPLETION_STRUCT, None));
+                }
+            }
+        } else {
+            // Parameters and locals bound before `offset` in the same declaration.
+            let ast = &self.graph.module(root).ast;
+            if let Some(decl) = ast.decls.iter().find(|decl| contains(decl.span, offset)) {
+                for def in &res.defs {
+                    let local = matches!(def.kind, DefKind::Param | DefKind::Local);
+                    if local && def.name.span.start >= decl.span.start && def.name.span.end <= offset {
+                        items.push(completion(interner.get(def.name.name), COMPLETION_VARIABLE, None));
+                    }
+                }
+            }
+            for (sym, def) in &res.module {
+                let name = interner.get(*sym);
+                let (kind, detail) = match res.def(*def).kind {
+                    DefKind::Func => (COMPLETION_FUNCTION, self.detail(root, name, interner)),
+                    DefKind::Const => (COMPLETION_CONSTANT, self.detail(root, name, interner)),
+                    DefKind::Variant => (COMPLETION_ENUM_MEMBER, None),
+                    DefKind::Module => (COMPLETION_MODULE, None),
+                    _ => match self.target(root, *def) {
+                        (module, _) if module != root => (COMPLETION_FUNCTION, self.detail(module, name, interner)),
+                        _ => (COMPLETION_VARIABLE, None),
+                    },
+                };
+                items.push(completion(name, kind, detail));
+            }
+            for decl in &ast.decls {
+                if let DeclKind::Struct(StructDecl { name, .. }) | DeclKind::Type(TypeDecl { name, .. }) = &decl.kind {
+                    items.push(completion(interner.get(name.name), COMPLETION_STRUCT, None));
+                }
+            }
+            for sym in res.imported_types.keys() {
+                items.push(completion(interner.get(*sym), COMPLETION_STRUCT, None));
+            }
+            for name in BUILTIN_TYPES.iter() {
+                items.push(completion(name, COMPLETION_STRUCT, None));
+            }
+            for keyword in KEYWORDS.iter() {
+                items.push(completion(keyword, COMPLETION_KEYWORD, None));
+            }
+        }
+        let label = |item: &Json| String::from(item.get("label").and_then(Json::as_str).unwrap_or(""));
+        items.sort_by_key(label);
+        items.dedup_by_key(|item| label(item));
+        items
+    }
+}
+
+pub struct Server {
+    // Text of the open documents, by URI.
+    docs: HashMap<String, String>,
+    search_paths: Vec<PathBuf>,
+    interner: Interner,
+    // Last semantic tokens sent for every document, with their result id.
+    semantic: HashMap<String, (String, Vec<i64>)>,
+    next_result_id: u64,
+    shutdown: bool,
+    // Exit code, once asked to exit.
+    pub exit: Option<i32>,
+}
+
+impl Server {
+    pub fn new(search_paths: Vec<PathBuf>) -> Server {
+        Server {
+            docs: HashMap::new(),
+            search_paths,
+            interner: Interner::new(),
+            semantic: HashMap::new(),
+            next_result_id: 0,
+            shutdown: false,
+            exit: None,
+        }
+    }
+
+    pub fn analyse(&mut self, uri: &str) -> Option<Analysis> {
+        let src = self.docs.get(uri)?.clone();
+        let docs = &self.docs;
+        let read = |path: &Path| match docs.iter().find(|(uri, _)| uri_path(uri) == path) {
+            Some((_, text)) => Some(text.clone()),
+            None => read_file(path),
+        };
+        let graph = load_program(&uri_path(uri), src, &self.search_paths, &read, &mut self.interner);
+        let checked = check_program(&graph, &self.interner);
+        Some(Analysis {
+            uri: String::from(uri),
+            graph,
+            checked,
+        })
+    }
+
+    // Diagnostics of every open document, whose imports may have changed.
+    fn publish_diagnostics(&mut self) -> Vec<Json> {
+        let mut uris: Vec<String> = self.docs.keys().cloned().collect();
+        uris.sort();
+        let mut msgs = vec![];
+        for uri in uris {
+            let analysis = self.analyse(&uri).unwrap();
+            let src = analysis.src();
+            let diags = analysis.checked[analysis.root().0]
+                .errors
+                .iter()
+                .map(|d| diagnostic_json(d, src))
+                .collect();
+            msgs.push(notification(
+                "textDocument/publishDiagnostics",
+                Json::object(vec![("uri", Json::Str(uri)), ("diagnostics", Json::Array(diags))]),
+            ));
+        }
+        msgs
+    }
+
+    // Answers a message with the responses and notifications to send back.
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.get("method").and_then(Json::as_str);
+        let params = msg.get("params").cloned().unwrap_or(Json::Null);
+        match (method, msg.get("id")) {
+            (Some(method), Some(id)) => {
+                let result = if self.shutdown {
+                    Err((INVALID_REQUEST, String::from("The server is shutting down")))
+                } else {
+                    self.request(method, &params)
+                };
+                vec![response(id.clone(), result)]
+            }
+            (Some(method), None) => self.notify(method, &params),
+            // Responses, the server sends no requests.
+            (None, _) => vec![],
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let doc = params.get("textDocument");
+        let uri = doc.and_then(|d| d.get("uri")).and_then(Json::as_str).map(String::from);
+        match (method, uri) {
+            ("textDocument/didOpen", Some(uri)) => {
+                let text = doc.and_then(|d| d.get("text")).and_then(Json::as_str).unwrap_or("");
+                self.docs.insert(uri, String::from(text));
+                self.publish_diagnostics()
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|c| c.get("text")).and_then(Json::as_str) {
+                    self.docs.insert(uri, String::from(text));
+                }
+                self.publish_diagnostics()
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.docs.remove(&uri);
+                self.semantic.remove(&uri);
+                let mut msgs = vec![notification(
+                    "textDocument/publishDiagnostics",
+                    Json::object(vec![("uri", Json::Str(uri)), ("diagnostics", Json::Array(vec![]))]),
+                )];
+                msgs.extend(self.publish_diagnostics());
+                msgs
+            }
+            ("exit", _) => {
+                self.exit = Some(if self.shutdown { 0 } else { 1 });
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    // The open document a request is about.
+    fn document(&mut self, params: &Json) -> Result<Analysis, (i64, String)> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|d| d.get("uri"))
+            .and_then(Json::as_str)
+            .ok_or_else(invalid_params)?;
+        self.analyse(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("Unknown document '{}'", uri)))
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> RpcResult {
+        match method {
+            "initialize" => {
+                let capabilities = Json::object(vec![
+                    ("textDocumentSync", Json::Int(1)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                    (
+                        "completionProvider",
+                        Json::object(vec![("triggerCharacters", Json::Array(vec![Json::str(":")]))]),
+                    ),
+                    (
+                        "semanticTokensProvider",
+                        Json::object(vec![
+                            ("legend", semantic_legend()),
+                            ("full", Json::object(vec![("delta", Json::Bool(true))])),
+                        ]),
+                    ),
+                ]);
+                Ok(Json::object(vec![
+                    ("capabilities", capabilities),
+                    ("serverInfo", Json::object(vec![("name", Json::str("sp"))])),
+                ]))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/semanticTokens/full" | "textDocument/semanticTokens/full/delta" => {
+                let analysis = self.document(params)?;
+                let data = encode(analysis.src(), &semantic_tokens(&analysis, &self.interner));
+                self.next_result_id += 1;
+                let result_id = self.next_result_id.to_string();
+                let previous = params.get("previousResultId").and_then(Json::as_str);
+                let old = self
+                    .semantic
+                    .insert(analysis.uri.clone(), (result_id.clone(), data.clone()));
+                let old = old.filter(|(id, _)| method.ends_with("/delta") && previous == Some(id.as_str()));
+                let ints = |data: Vec<i64>| Json::Array(data.into_iter().map(Json::Int).collect());
+                let tokens = match old {
+                    Some((_, old)) => {
+                        let edits = token_edit(&old, &data).map(|(start, delete, data)| {
+                            Json::object(vec![
+                                ("start", Json::Int(start as i64)),
+                                ("deleteCount", Json::Int(delete as i64)),
+                                ("data", ints(data)),
+                            ])
+                        });
+                        ("edits", Json::Array(edits.into_iter().collect()))
+                    }
+                    // Unknown previous tokens are sent again whole.
+                    None => ("data", ints(data)),
+                };
+                Ok(Json::object(vec![("resultId", Json::Str(result_id)), tokens]))
+            }
+            "textDocument/hover"
+            | "textDocument/definition"
+            | "textDocument/references"
+            | "textDocument/documentSymbol"
+            | "textDocument/completion" => {
+                let analysis = self.document(params)?;
+                if method == "textDocument/documentSymbol" {
+                    return Ok(Json::Array(analysis.symbols(&self.interner)));
+                }
+                let position = params.get("position").ok_or_else(invalid_params)?;
+                let offset = position_offset(analysis.src(), position).ok_or_else(invalid_params)?;
+                Ok(match method {
+                    "textDocument/hover" => analysis.hover(offset, &mut self.interner).unwrap_or(Json::Null),
+                    "textDocument/definition" => analysis.definition(offset, &self.interner).unwrap_or(Json::Null),
+                    "textDocument/references" => {
+                        let context = params.get("context");
+                        let include_decl = context
+                            .and_then(|c| c.get("includeDeclaration"))
+                            .and_then(Json::as_bool);
+                        Json::Array(analysis.references(offset, include_decl.unwrap_or(true)))
+                    }
+                    _ => Json::Array(analysis.completions(offset, &self.interner)),
+                })
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+        }
+    }
+}
+
+// Serves messages from `input` until asked to exit, returning the exit code:
+// 0 when the client shut the server down first.
+pub fn serve(input: &mut dyn BufRead, out: &mut dyn Write, search_paths: Vec<PathBuf>) -> Result<i32, String> {
+    let mut server = Server::new(search_paths);
+    while let Some(body) = read_message(input)? {
+        let replies = match Json::parse(&body) {
+            Ok(msg) => server.handle(&msg),
+            Err(err) => vec![response(Json::Null, Err((PARSE_ERROR, err)))],
+        };
+        for reply in &replies {
+            write_message(out, reply).map_err(|err| format!("Cannot write output: {}", err))?;
+        }
+        if let Some(code) = server.exit {
+            return Ok(code);
+        }
+    }
+    Ok(if server.shutdown { 0 } else { 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIB: &str = "type Shape =
+    Circle: float
+    Square: float
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Square a -> a * a
+
+const UNIT = Square 1.0
+
+export = area, Shape, UNIT
+";
+
+    const MAIN: &str = "import lib
+import lib::Shape
+
+double x: int -> int
+    x * 2
+
+total s: Shape -> float
+    lib::area s + lib::area lib::UNIT
+
+double (double 4)
+";
+
+    // A server with `lib.sp` on disk, and the URI of `main.sp` next to it.
+    fn setup(name: &str) -> (Server, String) {
+        let dir = std::env::temp_dir().join(format!("sp_lsp_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.sp"), LIB).unwrap();
+        (Server::new(vec![]), path_uri(&dir.join("main.sp")))
+    }
+
+    fn send(server: &mut Server, msg: &str) -> Vec<Json> {
+        let replies = server.handle(&Json::parse(msg).unwrap());
+        for reply in &replies {
+            println!("{}", reply);
+        }
+        replies
+    }
+
+    fn open(server: &mut Server, uri: &str, text: &str) -> Vec<Json> {
+        let msg = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","languageId":"sp","version":1,"text":{}}}}}}}"#,
+            uri,
+            Json::str(text)
+        );
+        send(server, &msg)
+    }
+
+    // Result of a request about the `nth` occurrence of `needle` in MAIN.
+    fn at(server: &mut Server, method: &str, uri: &str, needle: &str, nth: usize) -> Json {
+        let offset = MAIN.match_indices(needle).nth(nth).unwrap().0;
+        let msg = format!(
+            r#"{{"jsonrpc":"2.0","id":7,"method":"{}","params":{{"textDocument":{{"uri":"{}"}},"position":{},"context":{{"includeDeclaration":true}}}}}}"#,
+            method,
+            uri,
+            offset_position(MAIN, offset)
+        );
+        let replies = send(server, &msg);
+        assert!(replies.len() == 1 && replies[0].get("id") == Some(&Json::Int(7)));
+        replies[0].get("result").unwrap().clone()
+    }
+
+    fn line_of(location: &Json) -> (String, i64) {
+        let uri = location.get("uri").and_then(Json::as_str).unwrap();
+        let line = location
+            .get("range")
+            .and_then(|r| r.get("start"))
+            .and_then(|s| s.get("line"))
+            .and_then(Json::as_i64);
+        (String::from(uri.rsplit('/').next().unwrap()), line.unwrap())
+    }
+
+    fn labels(items: &Json) -> Vec<String> {
+        items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| String::from(i.get("label").and_then(Json::as_str).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_positions() {
+        let src = "a\nb\u{e9}\u{1f600}c\n";
+        let offset = src.find('c').unwrap();
+        let position = offset_position(src, offset);
+        assert!(position.to_string() == r#"{"line":1,"character":4}"#);
+        assert!(position_offset(src, &position) == Some(offset));
+        assert!(uri_path("file:///tmp/a%20b/c.sp") == Path::new("/tmp/a b/c.sp"));
+        assert!(path_uri(Path::new("/tmp/a b/c.sp")) == "file:///tmp/a%20b/c.sp");
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut input = String::new();
+        for body in [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+            r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"workspace/symbol","params":{}}"#,
+            "{oops",
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"textDocument/hover","params":{}}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+        ]
+        .iter()
+        {
+            input.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        }
+        let mut out = vec![];
+        let code = serve(&mut input.as_bytes(), &mut out, vec![]).unwrap();
+        let mut out = String::from_utf8(out).unwrap();
+        println!("{}", out);
+        assert!(code == 0);
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut out.as_bytes()).unwrap() {
+            out = out.split_off(out.find("\r\n\r\n").unwrap() + 4 + body.len());
+            replies.push(Json::parse(&body).unwrap());
+        }
+        assert!(replies.len() == 5);
+        let capabilities = replies[0].get("result").and_then(|r| r.get("capabilities")).unwrap();
+        assert!(capabilities.get("hoverProvider") == Some(&Json::Bool(true)));
+        let code = |reply: &Json| reply.get("error").and_then(|e| e.get("code")).and_then(Json::as_i64);
+        assert!(code(&replies[1]) == Some(METHOD_NOT_FOUND));
+        assert!(code(&replies[2]) == Some(PARSE_ERROR) && replies[2].get("id") == Some(&Json::Null));
+        assert!(replies[3].get("result") == Some(&Json::Null));
+        assert!(code(&replies[4]) == Some(INVALID_REQUEST));
+
+        // Exiting without a shutdown fails.
+        let body = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        assert!(serve(&mut input.as_bytes(), &mut vec![], vec![]) == Ok(1));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (mut server, uri) = setup("diagnostics");
+        let text = MAIN.replace("double (double 4)", "double \"4\"");
+        let replies = open(&mut server, &uri, &text);
+        assert!(replies.len() == 1);
+        let params = replies[0].get("params").unwrap();
+        assert!(params.get("uri").and_then(Json::as_str) == Some(uri.as_str()));
+        let diags = params.get("diagnostics").and_then(Json::as_array).unwrap();
+        assert!(diags.len() == 1);
+        assert!(
+            diags[0].get("range").unwrap().to_string()
+                == r#"{"start":{"line":9,"character":7},"end":{"line":9,"character":10}}"#
+        );
+        assert!(diags[0].get("severity") == Some(&Json::Int(1)));
+
+        let msg = format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"{}","version":2}},"contentChanges":[{{"text":{}}}]}}}}"#,
+            uri,
+            Json::str(MAIN)
+        );
+        let replies = send(&mut server, &msg);
+        assert!(replies[0].get("params").and_then(|p| p.get("diagnostics")) == Some(&Json::Array(vec![])));
+
+        // An open document is read in place of the file on disk.
+        let lib_uri = uri.replace("main.sp", "lib.sp");
+        let replies = open(&mut server, &lib_uri, &LIB.replace("area, ", ""));
+        assert!(replies.len() == 2);
+        let main = replies
+            .iter()
+            .find(|r| r.get("params").and_then(|p| p.get("uri")).and_then(Json::as_str) == Some(uri.as_str()));
+        let diags = main
+            .unwrap()
+            .get("params")
+            .and_then(|p| p.get("diagnostics"))
+            .and_then(Json::as_array)
+            .unwrap();
+        assert!(diags.len() == 2 && diags[0].get("message") == Some(&Json::str("Module 'lib' does not export 'area'")));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let (mut server, uri) = setup("navigation");
+        open(&mut server, &uri, MAIN);
+        let hover = |server: &mut Server, needle, nth| {
+            let result = at(server, "textDocument/hover", &uri, needle, nth);
+            String::from(
+                result
+                    .get("contents")
+                    .and_then(|c| c.get("value"))
+                    .and_then(Json::as_str)
+                    .unwrap(),
+            )
+        };
+        assert!(hover(&mut server, "double 4", 0) == "```sp\ndouble: int -> int\n```");
+        assert!(hover(&mut server, "x * 2", 0) == "```sp\nx: int\n```");
+        assert!(hover(&mut server, "double x", 0) == "```sp\ndouble x: int -> int\n```");
+        assert!(hover(&mut server, "lib::UNIT", 0) == "```sp\nUNIT: Shape\n```");
+        assert!(hover(&mut server, "(double 4)", 0) == "```sp\nint\n```");
+
+        let definition =
+            |server: &mut Server, needle, nth| line_of(&at(server, "textDocument/definition", &uri, needle, nth));
+        assert!(definition(&mut server, "double 4", 0) == ("main.sp".to_string(), 3));
+        assert!(definition(&mut server, "x * 2", 0) == ("main.sp".to_string(), 3));
+        assert!(definition(&mut server, "area s +", 0) == ("lib.sp".to_string(), 4));
+        assert!(definition(&mut server, "UNIT", 0) == ("lib.sp".to_string(), 9));
+        assert!(definition(&mut server, "Shape ->", 0) == ("lib.sp".to_string(), 0));
+        assert!(definition(&mut server, "lib::area s", 0) == ("lib.sp".to_string(), 0));
+
+        let references = |server: &mut Server, needle, nth| {
+            let refs = at(server, "textDocument/references", &uri, needle, nth);
+            refs.as_array()
+                .unwrap()
+                .iter()
+                .map(line_of)
+                .collect::<Vec<(String, i64)>>()
+        };
+        let main = |line| ("main.sp".to_string(), line);
+        assert!(references(&mut server, "double 4", 0) == vec![main(3), main(9), main(9)]);
+        assert!(references(&mut server, "area", 1) == vec![("lib.sp".to_string(), 4), main(7), main(7)]);
+        assert!(references(&mut server, "s: Shape", 0) == vec![main(6), main(7)]);
+        assert!(at(&mut server, "textDocument/references", &uri, "->", 0) == Json::Array(vec![]));
+    }
+
+    #[test]
+    fn test_symbols_and_completion() {
+        let (mut server, uri) = setup("completion");
+        let lib_uri = uri.replace("main.sp", "lib.sp");
+        open(&mut server, &lib_uri, LIB);
+        let msg = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"textDocument/documentSymbol","params":{{"textDocument":{{"uri":"{}"}}}}}}"#,
+            lib_uri
+        );
+        let symbols = send(&mut server, &msg)[0].get("result").unwrap().clone();
+        assert!(labels_of(&symbols, "name") == vec!["Shape", "area", "UNIT"]);
+        let shape = &symbols.as_array().unwrap()[0];
+        assert!(shape.get("kind") == Some(&Json::Int(SYMBOL_ENUM)));
+        assert!(labels_of(shape.get("children").unwrap(), "name") == vec!["Circle", "Square"]);
+
+        open(&mut server, &uri, MAIN);
+        let items = at(&mut server, "textDocument/completion", &uri, "area s +", 0);
+        assert!(labels(&items) == vec!["Circle", "Shape", "Square", "UNIT", "area"]);
+        let area = &items.as_array().unwrap()[4];
+        assert!(area.get("detail") == Some(&Json::str("area s: Shape -> float")));
+        let items = labels(&at(&mut server, "textDocument/completion", &uri, "x * 2", 0));
+        for name in ["x", "double", "total", "lib", "Shape", "int", "case"].iter() {
+            assert!(items.iter().any(|i| i == name));
+        }
+        assert!(!items.iter().any(|i| i == "s" || i == "area"));
+    }
+
+    fn labels_of(items: &Json, key: &str) -> Vec<String> {
+        items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| String::from(i.get(key).and_then(Json::as_str).unwrap()))
+            .collect()
+    }
+}
@@ -1,6 +1,45 @@
 mod lexer;
 mod common;
+mod ast;
+mod parser;
+mod sexpr;
+mod diagnostic;
+mod types;
+mod typeck;
+mod pattern;
+mod resolve;
+mod infer;
+mod prelude;
+mod traits;
+mod consteval;
+mod modules;
+mod interp;
+mod ir;
+mod lower;
+mod opt;
+mod bytecode;
+mod compile;
+mod vm;
+mod spc;
+mod cgen;
+mod x86;
+mod elf;
+mod native;
+mod wasm;
+mod json;
+mod fmt;
+mod repl;
+mod lsp;
+mod semantic;
+mod cli;
+#[cfg(test)]
+mod wasm_run;
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let stdout = std::io::stdout();
+    let stdin = std::io::stdin();
+    let stderr = std::io::stderr();
+    let code = cli::run(&args, &mut stdin.lock(), &mut stdout.lock(), &mut stderr.lock());
+    std::process::exit(code);
 }
-
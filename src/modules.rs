@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{DeclKind, Module, NodeId};
+use crate::common::{fnv1a, Interner, Span, Symbol};
+use crate::consteval::{eval_consts, ConstValue};
+use crate::diagnostic::Diagnostic;
+use crate::parser::parse_module;
+use crate::prelude::PRELUDE;
+use crate::resolve::{resolve_module_with, Exports, ModuleEnv, Resolutions};
+use crate::typeck::{Checker, ModuleInterface, TypeckResults};
+
+// A program is the root file and every module it imports, transitively.
+// `import foo::bar` names the module in `foo/bar.sp`, looked up in the
+// search paths in order, the directory of the root file first. When no such
+// file exists, the import names the member `bar` of the module `foo`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModuleId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportTarget {
+    // `import foo`, whose members are reached through paths.
+    Module(ModuleId),
+    // `import foo::greet`, a single exported name of the module.
+    Member(ModuleId),
+}
+
+pub struct LoadedModule {
+    // Path of the module in imports, such as `foo::bar`.
+    pub name: String,
+    pub path: PathBuf,
+    pub src: String,
+    pub ast: Module,
+    // Module referred to by every import declaration that could be loaded.
+    pub imports: HashMap<NodeId, ImportTarget>,
+    // Parse and load errors.
+    pub errors: Vec<Diagnostic>,
+}
+
+pub struct ModuleGraph {
+    pub modules: Vec<LoadedModule>,
+    // Modules imported by every module.
+    pub deps: Vec<Vec<ModuleId>>,
+    pub root: ModuleId,
+}
+
+impl ModuleGraph {
+    pub fn module(&self, id: ModuleId) -> &LoadedModule {
+        &self.modules[id.0]
+    }
+
+    // Every module after the ones it imports. Cyclic imports were rejected
+    // so the graph has no cycle.
+    pub fn order(&self) -> Vec<ModuleId> {
+        fn visit(graph: &ModuleGraph, id: ModuleId, seen: &mut Vec<bool>, order: &mut Vec<ModuleId>) {
+            if seen[id.0] {
+                return;
+            }
+            seen[id.0] = true;
+            for dep in &graph.deps[id.0] {
+                visit(graph, *dep, seen, order);
+            }
+            order.push(id);
+        }
+        let mut seen = vec![false; self.modules.len()];
+        let mut order = vec![];
+        visit(self, self.root, &mut seen, &mut order);
+        order
+    }
+}
+
+// `foo::bar` is stored in `foo/bar.sp`.
+pub fn module_file(name: &str) -> PathBuf {
+    let mut path: PathBuf = name.split("::").collect();
+    path.set_extension("sp");
+    path
+}
+
+pub fn source_hash(src: &str) -> u64 {
+    fnv1a(src.as_bytes())
+}
+
+pub fn read_file(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+// Path of the built-in prelude, used when no prelude.sp is found.
+pub const PRELUDE_PATH: &str = "<prelude>";
+
+// Reads the file a module was loaded from, the built-in prelude included.
+pub fn read_module_file(path: &Path) -> Option<String> {
+    if path == Path::new(PRELUDE_PATH) {
+        return Some(String::from(PRELUDE));
+    }
+    read_file(path)
+}
+
+pub struct Loader<'a> {
+    search_paths: Vec<PathBuf>,
+    read: &'a dyn Fn(&Path) -> Option<String>,
+    interner: &'a mut Interner,
+    modules: Vec<LoadedModule>,
+    deps: Vec<Vec<ModuleId>>,
+    by_name: HashMap<String, ModuleId>,
+    // Modules being loaded, each one imported by the one before it.
+    stack: Vec<ModuleId>,
+}
+
+impl<'a> Loader<'a> {
+    pub fn new(search_paths: Vec<PathBuf>, read: &'a dyn Fn(&Path) -> Option<String>, interner: &'a mut Interner) -> Loader<'a> {
+        Loader {
+            search_paths,
+            read,
+            interner,
+            modules: vec![],
+            deps: vec![],
+            by_name: HashMap::new(),
+            stack: vec![],
+        }
+    }
+
+    // Parses a module then loads its imports, depth first.
+    fn load(&mut self, name: String, path: PathBuf, src: String) -> ModuleId {
+        let id = ModuleId(self.modules.len());
+        let (ast, mut errors) = parse_module(&src, self.interner);
+        self.modules.push(LoadedModule {
+            name: name.clone(),
+            path,
+            src,
+            ast: Module { decls: vec![] },
+            imports: HashMap::new(),
+            errors: vec![],
+        });
+        self.deps.push(vec![]);
+        self.by_name.insert(name, id);
+        self.stack.push(id);
+        let mut imports = HashMap::new();
+        for decl in &ast.decls {
+            if let DeclKind::Import(import) = &decl.kind {
+                let segments: Vec<String> = import.path.iter().map(|i| String::from(self.interner.get(i.name))).collect();
+                match self.import(&segments, decl.span) {
+                    Ok(target) => {
+                        imports.insert(decl.id, target);
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+        }
+        self.stack.pop();
+        let module = &mut self.modules[id.0];
+        module.ast = ast;
+        module.imports = imports;
+        module.errors = errors;
+        id
+    }
+
+    // The whole path names a module, or all but its last segment do.
+    fn import(&mut self, segments: &[String], span: Span) -> Result<ImportTarget, Diagnostic> {
+        let whole = segments.join("::");
+        if let Some(id) = self.find(&whole, span)? {
+            return Ok(ImportTarget::Module(id));
+        }
+        if segments.len() == 1 {
+            return Err(self.not_found(&whole, span));
+        }
+        let prefix = segments[..segments.len() - 1].join("::");
+        match self.find(&prefix, span)? {
+            Some(id) => Ok(ImportTarget::Member(id)),
+            None => Err(self.not_found(&prefix, span)),
+        }
+    }
+
+    // Loads the module `name` unless it already is, and records that the
+    // module on top of the stack depends on it.
+    fn find(&mut self, name: &str, span: Span) -> Result<Option<ModuleId>, Diagnostic> {
+        let id = match self.by_name.get(name) {
+            Some(id) => *id,
+            None => {
+                let file = module_file(name);
+                let found = self.search_paths.iter().find_map(|dir| {
+                    let path = dir.join(&file);
+                    (self.read)(&path).map(|src| (path, src))
+                });
+                let found = found.or_else(|| {
+                    (name == "prelude").then(|| (PathBuf::from(PRELUDE_PATH), String::from(PRELUDE)))
+                });
+                match found {
+                    Some((path, src)) => self.load(String::from(name), path, src),
+                    None => return Ok(None),
+                }
+            }
+        };
+        if let Some(pos) = self.stack.iter().position(|m| *m == id) {
+            let mut names: Vec<&str> = self.stack[pos..].iter().map(|m| self.modules[m.0].name.as_str()).collect();
+            names.push(name);
+            return Err(Diagnostic::error(span, format!("Cyclic import: {}", names.join(" -> "))));
+        }
+        let importer = &mut self.deps[self.stack.last().unwrap().0];
+        if !importer.contains(&id) {
+            importer.push(id);
+        }
+        Ok(Some(id))
+    }
+
+    fn not_found(&self, name: &str, span: Span) -> Diagnostic {
+        let dirs: Vec<String> = self.search_paths.iter().map(|dir| format!("'{}'", dir.display())).collect();
+        Diagnostic::error(span, format!("Cannot find module '{}'", name))
+            .with_note(format!("looked for {} in {}", module_file(name).display(), dirs.join(", ")))
+    }
+}
+
+// Loads the program rooted at `path`, whose source is `src`. The root module
+// is named after its file.
+pub fn load_program(
+    path: &Path,
+    src: String,
+    search_paths: &[PathBuf],
+    read: &dyn Fn(&Path) -> Option<String>,
+    interner: &mut Interner,
+) -> ModuleGraph {
+    let mut dirs = vec![path.parent().map(PathBuf::from).unwrap_or_default()];
+    dirs.extend(search_paths.iter().cloned());
+    let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut loader = Loader::new(dirs, read, interner);
+    let root = loader.load(name, PathBuf::from(path), src);
+    ModuleGraph {
+        modules: loader.modules,
+        deps: loader.deps,
+        root,
+    }
+}
+
+pub struct CheckedModule {
+    pub res: Resolutions,
+    pub types: TypeckResults,
+    pub consts: HashMap<Symbol, ConstValue>,
+    // Load, parse, resolution and type errors of the module.
+    pub errors: Vec<Diagnostic>,
+}
+
+// Checks every module after the ones it imports, which only see each other
+// through their exports. Indexed by module id.
+pub fn check_program(graph: &ModuleGraph, interner: &Interner) -> Vec<CheckedModule> {
+    let names: Vec<String> = graph.modules.iter().map(|m| m.name.clone()).collect();
+    let mut exports: HashMap<ModuleId, Exports> = HashMap::new();
+    let mut interfaces: HashMap<ModuleId, ModuleInterface> = HashMap::new();
+    let mut checked: Vec<Option<CheckedModule>> = graph.modules.iter().map(|_| None).collect();
+    for id in graph.order() {
+        let module = graph.module(id);
+        let mut errors = module.errors.clone();
+        let env = ModuleEnv {
+            imports: &module.imports,
+            exports: &exports,
+            names: &names,
+        };
+        let (res, res_errors) = resolve_module_with(&module.ast, interner, env);
+        errors.extend(res_errors);
+        let mut checker = Checker::with_interfaces(interner, &res, &interfaces);
+        checker.check_module(&module.ast);
+        let interface = checker.interface(&res.exports);
+        let types = checker.results;
+        errors.extend(checker.errors);
+        let (consts, const_errors) = eval_consts(&module.ast, &res, &types, &errors, interner);
+        errors.extend(const_errors);
+        exports.insert(id, res.exports.clone());
+        interfaces.insert(id, interface);
+        checked[id.0] = Some(CheckedModule { res, types, consts, errors });
+    }
+    checked.into_iter().map(|m| m.unwrap()).collect()
+}
+
+// Loads `src` as `main.sp`, which can import `foo` as `foo.sp`, and checks
+// it, asserting there are no errors. Where the tests of the later stages
+// start from.
+#[cfg(test)]
+pub fn check_test_program(src: &str, foo: &str) -> (ModuleGraph, Vec<CheckedModule>, Interner) {
+    let mut interner = Interner::new();
+    let read = |path: &Path| if path == Path::new("foo.sp") { Some(String::from(foo)) } else { None };
+    let graph = load_program(&PathBuf::from("main.sp"), String::from(src), &[], &read, &mut interner);
+    let checked = check_program(&graph, &interner);
+    for err in checked.iter().flat_map(|m| m.errors.iter()) {
+        println!("{:?}", err);
+    }
+    assert!(checked.iter().all(|m| m.errors.is_empty()));
+    (graph, checked, interner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::type_to_string;
+
+    fn load(files: &[(&str, &str)], interner: &mut Interner) -> ModuleGraph {
+        let files: HashMap<PathBuf, String> = files.iter().map(|(p, s)| (PathBuf::from(p), String::from(*s))).collect();
+        let read = |path: &Path| files.get(path).cloned();
+        let root = PathBuf::from("app/main.sp");
+        load_program(&root, files[&root].clone(), &[PathBuf::from("lib")], &read, interner)
+    }
+
+    fn errors_of(graph: &ModuleGraph, checked: &[CheckedModule]) -> Vec<(String, String)> {
+        let mut errors = vec![];
+        for (module, checked) in graph.modules.iter().zip(checked.iter()) {
+            for err in &checked.errors {
+                errors.push((module.name.clone(), err.msg.clone()));
+            }
+        }
+        println!("{:?}", errors);
+        errors
+    }
+
+    #[test]
+    fn test_module_file() {
+        assert!(module_file("foo") == Path::new("foo.sp"));
+        assert!(module_file("util::text") == Path::new("util/text.sp"));
+    }
+
+    #[test]
+    fn test_load_and_check() {
+        let files = [
+            (
+                "app/main.sp",
+                "
+import foo::greet
+import util::text
+import foo::Shape
+import foo
+
+greet (text::shout \"Silver pancake\")
+foo::area (Circle 2.0)
+foo::UNIT
+",
+            ),
+            (
+                "app/foo.sp",
+                "
+import util::text
+
+type Shape =
+    Circle: float
+    Square: float
+
+greet name: string -> string
+    text::shout \"Hello {name}\"
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Square a -> a * a
+
+secret = 42
+
+const UNIT = Square 1.0
+
+export = greet, area, Shape, UNIT
+",
+            ),
+            (
+                "lib/util/text.sp",
+                "
+shout s: string -> string
+    \"{s}!\"
+
+export = shout
+",
+            ),
+        ];
+        let mut interner = Interner::new();
+        let graph = load(&files, &mut interner);
+        let names: Vec<&str> = graph.modules.iter().map(|m| m.name.as_str()).collect();
+        assert!(names == vec!["main", "foo", "util::text"]);
+        assert!(graph.module(ModuleId(2)).path == Path::new("lib/util/text.sp"));
+        assert!(graph.deps == vec![vec![ModuleId(1), ModuleId(2)], vec![ModuleId(2)], vec![]]);
+        assert!(graph.order() == vec![ModuleId(2), ModuleId(1), ModuleId(0)]);
+        let main = graph.module(graph.root);
+        let targets: Vec<ImportTarget> = main
+            .ast
+            .decls
+            .iter()
+            .filter_map(|decl| main.imports.get(&decl.id).copied())
+            .collect();
+        assert!(
+            targets
+                == vec![
+                    ImportTarget::Member(ModuleId(1)),
+                    ImportTarget::Module(ModuleId(2)),
+                    ImportTarget::Member(ModuleId(1)),
+                    ImportTarget::Module(ModuleId(1)),
+                ]
+        );
+
+        let checked = check_program(&graph, &interner);
+        assert!(errors_of(&graph, &checked).is_empty());
+        let types: Vec<String> = main
+            .ast
+            .decls
+            .iter()
+            .filter_map(|decl| match &decl.kind {
+                DeclKind::Expr(expr) => Some(type_to_string(&checked[0].types.node_types[&expr.id], &interner)),
+                _ => None,
+            })
+            .collect();
+        println!("{:?}", types);
+        assert!(types == vec!["string", "float", "Shape"]);
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let files = [
+            (
+                "app/main.sp",
+                "
+import missing
+import foo::secret
+import foo::Hidden
+import bar
+import foo
+
+local = 1
+
+main = foo::secret 1
+other = local::x
+whole = foo
+center v: Hidden -> int
+    0
+",
+            ),
+            (
+                "app/foo.sp",
+                "
+import bar::helper
+
+struct Hidden =
+    x: int
+
+secret n: int -> int
+    n
+
+make n: int -> Hidden
+    Hidden {n}
+
+export = make, helper
+",
+            ),
+            (
+                "app/bar.sp",
+                "
+import foo
+
+helper = 1
+
+export = helper
+",
+            ),
+        ];
+        let mut interner = Interner::new();
+        let graph = load(&files, &mut interner);
+        assert!(graph.deps == vec![vec![ModuleId(1), ModuleId(2)], vec![ModuleId(2)], vec![]]);
+        let checked = check_program(&graph, &interner);
+        let errors = errors_of(&graph, &checked);
+        let expected = vec![
+            ("main", "Cannot find module 'missing'"),
+            ("main", "Module 'foo' does not export 'secret'"),
+            ("main", "Module 'foo' does not export 'Hidden'"),
+            ("main", "Module 'foo' does not export 'secret'"),
+            ("main", "'local' is not a module"),
+            ("main", "Module 'foo' is not a value"),
+            ("main", "Unknown type 'Hidden'"),
+            ("foo", "Cannot re-export 'helper', it is imported from another module"),
+            ("bar", "Cyclic import: foo -> bar -> foo"),
+        ];
+        let expected: Vec<(String, String)> = expected.iter().map(|(m, e)| (String::from(*m), String::from(*e))).collect();
+        let mut sorted = errors.clone();
+        sorted.sort();
+        let mut expected_sorted = expected;
+        expected_sorted.sort();
+        assert!(sorted == expected_sorted);
+        let missing = &checked[0].errors[0];
+        assert!(missing.notes == vec!["looked for missing.sp in 'app', 'lib'"]);
+    }
+
+    #[test]
+    fn test_prelude() {
+        let main = "
+import prelude::List
+import prelude
+
+prelude::length (Cons 1 (Cons 2 Nil))
+prelude::map (\\x -> x + 1) Nil
+";
+        let mut interner = Interner::new();
+        let graph = load(&[("app/main.sp", main)], &mut interner);
+        assert!(graph.module(ModuleId(1)).path == Path::new(PRELUDE_PATH));
+        let checked = check_program(&graph, &interner);
+        assert!(errors_of(&graph, &checked).is_empty());
+
+        // A prelude of the program's own comes first.
+        let files = [("app/main.sp", "import prelude::answer\nanswer\n"), ("lib/prelude.sp", "answer = 42\nexport = answer\n")];
+        let graph = load(&files, &mut interner);
+        assert!(graph.module(ModuleId(1)).path == Path::new("lib/prelude.sp"));
+        let checked = check_program(&graph, &interner);
+        assert!(errors_of(&graph, &checked).is_empty());
+    }
+}
@@ -0,0 +1,944 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::cgen::{c_ident, mangle_module};
+use crate::diagnostic::Diagnostic;
+use crate::elf::{write_object, Function, Object};
+use crate::interp::MAX_DEPTH;
+use crate::ir::{self, BlockId, Const, Def, FuncKind, Inst as IrInst, Term, Ty};
+use crate::modules::ModuleId;
+use crate::x86::{Alu, Asm, Cond, Label, Operand, Reg, ARG_REGS};
+
+// Compiles top level functions to x86-64 machine code in an ELF object,
+// following the System V calling convention so C and Rust code can call
+// them directly: `foo::count` becomes `sp_foo__count`, like in the C
+// backend. Only functions over int, bool and char values are compiled, as
+// `int64_t`, `bool` and `uint32_t`; the others are reported and left out.
+//
+// Functions are lowered to a linear code over virtual registers, which a
+// linear scan over their live ranges maps to machine registers or stack
+// slots. Values live across calls get callee saved registers. Runtime
+// errors call `sp_runtime_error` with their message. The object defines it
+// weakly to print the message and exit the process, as in the other
+// backends, and the host can define its own instead.
+
+type VReg = u32;
+
+// Messages of the runtime errors, stored in `.rodata`.
+const PANICS: [&str; 4] = [
+    "Integer overflow",
+    "Division by zero",
+    "No case arm matches the value",
+    "Stack overflow: too many nested calls",
+];
+const OVERFLOW: usize = 0;
+const DIVISION: usize = 1;
+const NO_MATCH: usize = 2;
+const STACK: usize = 3;
+
+// Called on runtime errors, must not return.
+const HANDLER: &str = "sp_runtime_error";
+// The call depth, per thread so that threads of the host can call the
+// functions at once.
+const DEPTH: &str = "sp_depth";
+
+// Registers for values not live across calls, then for the others.
+const CALLER_SAVED: [Reg; 6] = [Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9, Reg::R10, Reg::R11];
+const CALLEE_SAVED: [Reg; 5] = [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Inst {
+    Const(VReg, i64),
+    Copy(VReg, VReg),
+    Unary(UnaryOp, VReg, VReg),
+    Binary(BinaryOp, VReg, VReg, VReg),
+    // Direct call of the function with the given index.
+    Call(VReg, usize, Vec<VReg>),
+    Label(u32),
+    Jump(u32),
+    JumpIfFalse(VReg, u32),
+    Return(VReg),
+    Panic(usize),
+}
+
+impl Inst {
+    fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Const(d, _) | Inst::Copy(d, _) | Inst::Unary(_, d, _) | Inst::Binary(_, d, _, _) | Inst::Call(d, _, _) => {
+                Some(*d)
+            }
+            _ => None,
+        }
+    }
+
+    fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Copy(_, a) | Inst::Unary(_, _, a) | Inst::JumpIfFalse(a, _) | Inst::Return(a) => vec![*a],
+            Inst::Binary(_, _, a, b) => vec![*a, *b],
+            Inst::Call(_, _, args) => args.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+// A function compiled to native code.
+struct NativeFunc {
+    index: u32,
+    symbol: String,
+}
+
+struct FnState {
+    code: Vec<Inst>,
+    vregs: u32,
+    labels: u32,
+    params: Vec<VReg>,
+}
+
+impl FnState {
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn label(&mut self) -> u32 {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn push(&mut self, inst: Inst) {
+        self.code.push(inst);
+    }
+
+    // Copies all the values at once, through temporaries since some may be
+    // destinations too.
+    fn copies(&mut self, pairs: Vec<(VReg, VReg)>) {
+        let temps: Vec<VReg> = pairs
+            .iter()
+            .map(|(_, src)| {
+                let temp = self.vreg();
+                self.push(Inst::Copy(temp, *src));
+                temp
+            })
+            .collect();
+        for ((dst, _), temp) in pairs.into_iter().zip(temps) {
+            self.push(Inst::Copy(dst, temp));
+        }
+    }
+}
+
+// Where a virtual register lives, and how the function uses the stack.
+struct Allocation {
+    locs: Vec<Option<Operand>>,
+    saved: Vec<Reg>,
+    slots: usize,
+}
+
+fn is_scalar(ty: &Ty) -> bool {
+    matches!(ty, Ty::Int | Ty::Bool | Ty::Char)
+}
+
+fn c_type(ty: &Ty) -> &'static str {
+    match ty {
+        Ty::Bool => "bool",
+        Ty::Char => "uint32_t",
+        _ => "int64_t",
+    }
+}
+
+pub struct NativeObject {
+    pub object: Vec<u8>,
+    // C declarations of the compiled functions.
+    pub header: String,
+    // Warnings for the functions left out.
+    pub skipped: Vec<(ModuleId, Diagnostic)>,
+}
+
+pub struct Native<'a> {
+    ir: &'a ir::Program,
+    funcs: Vec<NativeFunc>,
+    indices: HashMap<u32, usize>,
+    skipped: Vec<(ModuleId, Diagnostic)>,
+}
+
+impl<'a> Native<'a> {
+    pub fn new(ir: &'a ir::Program) -> Native<'a> {
+        Native {
+            ir,
+            funcs: vec![],
+            indices: HashMap::new(),
+            skipped: vec![],
+        }
+    }
+
+    // Chooses the functions to compile: those over scalar values whose
+    // callees are compiled too.
+    fn collect(&mut self) {
+        let ir = self.ir;
+        let rejected = ir.rejected_funcs(|f, callees| self.check_func(f, callees));
+        for (i, f) in ir.funcs.iter().enumerate() {
+            if f.kind != FuncKind::Func {
+                continue;
+            }
+            if let Some(reason) = rejected.get(&(i as u32)) {
+                let diag = Diagnostic::warning(f.span, format!("Function '{}' can't be compiled to native code", f.name))
+                    .with_note(format!("it {}", reason))
+                    .with_note("native code supports functions of int, bool and char values");
+                self.skipped.push((ModuleId(f.module as usize), diag));
+                continue;
+            }
+            let symbol = format!("sp_{}__{}", mangle_module(&ir.modules[f.module as usize].name), f.name);
+            self.indices.insert(i as u32, self.funcs.len());
+            self.funcs.push(NativeFunc { index: i as u32, symbol });
+        }
+    }
+
+    fn check_func(&self, f: &ir::Function, callees: &mut Vec<u32>) -> Result<(), String> {
+        if f.params.len() > ARG_REGS.len() {
+            return Err(format!("has more than {} parameters", ARG_REGS.len()));
+        }
+        let mut call = |func: u32| match self.ir.funcs[func as usize].kind {
+            FuncKind::Func => {
+                callees.push(func);
+                Ok(())
+            }
+            _ => Err(String::from("calls function values")),
+        };
+        for value in &f.values {
+            if !is_scalar(&value.ty) {
+                return Err(format!("uses values of type {}", self.ir.ty_to_string(&value.ty)));
+            }
+            match &value.def {
+                Def::Inst(IrInst::Call(func, _)) => call(*func)?,
+                Def::Inst(IrInst::CallValue(_, _)) => return Err(String::from("calls function values")),
+                _ => {}
+            }
+        }
+        for block in &f.blocks {
+            match &block.term {
+                Term::TailCall(func, _) => call(*func)?,
+                Term::TailCallValue(_, _) => return Err(String::from("calls function values")),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compile(mut self) -> NativeObject {
+        self.collect();
+        let mut asm = Asm::new();
+        let labels: Vec<Label> = self.funcs.iter().map(|_| asm.label()).collect();
+        let panics: Vec<Label> = PANICS.iter().map(|_| asm.label()).collect();
+        let mut functions = vec![];
+        for (i, func) in self.funcs.iter().enumerate() {
+            let f = self.lower(i);
+            let start = asm.code.len();
+            asm.bind(labels[i]);
+            emit(&mut asm, &self.ir.funcs[func.index as usize], &f, &labels, &panics);
+            functions.push(Function {
+                name: func.symbol.clone(),
+                offset: start,
+                size: asm.code.len() - start,
+                weak: false,
+            });
+        }
+        // The handler may unwind the native frames, so the call depth starts
+        // over.
+        let mut rodata = vec![];
+        for (i, msg) in PANICS.iter().enumerate() {
+            asm.bind(panics[i]);
+            asm.tls_offset(Reg::R11, DEPTH);
+            asm.mov_tls(Reg::R11, 0);
+            asm.lea_symbol(Reg::Rdi, ".rodata", rodata.len() as i64);
+            asm.alu_imm8(Alu::And, Reg::Rsp, -16);
+            asm.call_symbol(HANDLER);
+            asm.ud2();
+            rodata.extend_from_slice(msg.as_bytes());
+            rodata.push(0);
+        }
+        // The default handler prints the message to stderr and exits with
+        // status 1 through libc, which flushes the output of the host.
+        let format = rodata.len();
+        rodata.extend_from_slice(b"error: %s\n\0");
+        let start = asm.code.len();
+        // Aligns the stack for the calls.
+        asm.push(Operand::Reg(Reg::Rbp));
+        asm.mov(Operand::Reg(Reg::Rdx), Operand::Reg(Reg::Rdi));
+        asm.lea_symbol(Reg::Rsi, ".rodata", format as i64);
+        asm.mov_imm32(Reg::Rdi, 2);
+        // No vector arguments to the variadic function.
+        asm.mov_imm32(Reg::Rax, 0);
+        asm.call_symbol("dprintf");
+        asm.mov_imm32(Reg::Rdi, 1);
+        asm.call_symbol("exit");
+        asm.ud2();
+        functions.push(Function {
+            name: String::from(HANDLER),
+            offset: start,
+            size: asm.code.len() - start,
+            weak: true,
+        });
+        asm.finish();
+        let object = Object {
+            text: asm.code,
+            rodata,
+            tls: vec![String::from(DEPTH)],
+            functions,
+            relocs: asm.relocs,
+        };
+        NativeObject {
+            object: write_object(&object),
+            header: self.header(),
+            skipped: self.skipped,
+        }
+    }
+
+    fn header(&self) -> String {
+        let ir = self.ir;
+        let root = &ir.modules[ir.funcs[ir.main as usize].module as usize];
+        let mut out = format!("/* Generated by the Silver Pancake compiler from `{}`. */\n\n", root.name);
+        out.push_str("#include <stdbool.h>\n#include <stdint.h>\n\n");
+        out.push_str("/* Called with the message of a runtime error, must not return. Define it to\n");
+        out.push_str("   replace the default, which prints the message and exits with status 1. */\n");
+        let _ = writeln!(out, "void {}(const char *msg);\n", HANDLER);
+        for func in &self.funcs {
+            let f = &ir.funcs[func.index as usize];
+            let mut params = vec![];
+            for (i, (param, name)) in f.params.iter().zip(&f.param_names).enumerate() {
+                let name = match name {
+                    Some(name) => c_ident(name),
+                    None => format!("p{}", i),
+                };
+                params.push(format!("{} {}", c_type(f.ty(*param)), name));
+            }
+            if params.is_empty() {
+                params.push(String::from("void"));
+            }
+            let _ = writeln!(out, "{} {}({});", c_type(&f.ret), func.symbol, params.join(", "));
+        }
+        out
+    }
+
+    // Every value of the function gets the register of its index and every
+    // block the label of its index. Phis are copies on the edges reaching
+    // them.
+    fn lower(&self, index: usize) -> FnState {
+        let func = &self.funcs[index];
+        let ir_f = &self.ir.funcs[func.index as usize];
+        let mut f = FnState {
+            code: vec![],
+            vregs: ir_f.values.len() as u32,
+            labels: ir_f.blocks.len() as u32,
+            params: ir_f.params.iter().map(|p| p.0).collect(),
+        };
+        let order = ir_f.reverse_postorder();
+        for (i, id) in order.iter().enumerate() {
+            let next = order.get(i + 1).copied();
+            f.push(Inst::Label(id.0));
+            let block = ir_f.block(*id);
+            for value in &block.insts {
+                let dst = value.0;
+                match ir_f.inst(*value).unwrap() {
+                    IrInst::Const(Const::Int(val)) => f.push(Inst::Const(dst, *val)),
+                    IrInst::Const(Const::Char(val)) => f.push(Inst::Const(dst, *val as i64)),
+                    IrInst::Const(Const::Bool(val)) => f.push(Inst::Const(dst, *val as i64)),
+                    IrInst::Unary(op, operand) => f.push(Inst::Unary(*op, dst, operand.0)),
+                    IrInst::Binary(op, l, r) => f.push(Inst::Binary(*op, dst, l.0, r.0)),
+                    IrInst::Call(callee, args) => {
+                        f.push(Inst::Call(dst, self.indices[callee], args.iter().map(|a| a.0).collect()));
+                    }
+                    inst => unreachable!("{:?} in native code", inst),
+                }
+            }
+            match &block.term {
+                Term::Jump(target) => {
+                    self.moves(&mut f, ir_f, *id, *target);
+                    if next != Some(*target) {
+                        f.push(Inst::Jump(target.0));
+                    }
+                }
+                Term::Branch(cond, then_block, else_block) => {
+                    let phis = |b: &BlockId| !ir_f.block(*b).phis.is_empty();
+                    if phis(then_block) || phis(else_block) {
+                        let else_edge = f.label();
+                        f.push(Inst::JumpIfFalse(cond.0, else_edge));
+                        self.moves(&mut f, ir_f, *id, *then_block);
+                        f.push(Inst::Jump(then_block.0));
+                        f.push(Inst::Label(else_edge));
+                        self.moves(&mut f, ir_f, *id, *else_block);
+                        if next != Some(*else_block) {
+                            f.push(Inst::Jump(else_block.0));
+                        }
+                    } else {
+                        f.push(Inst::JumpIfFalse(cond.0, else_block.0));
+                        if next != Some(*then_block) {
+                            f.push(Inst::Jump(then_block.0));
+                        }
+                    }
+                }
+                Term::Return(value) => f.push(Inst::Return(value.0)),
+                // Calls of the function to itself become jumps back to the
+                // entry.
+                Term::TailCall(callee, args) if *callee == func.index => {
+                    let pairs = f.params.clone().into_iter().zip(args.iter().map(|a| a.0)).collect();
+                    f.copies(pairs);
+                    f.push(Inst::Jump(0));
+                }
+                Term::TailCall(callee, args) => {
+                    let dst = f.vreg();
+                    f.push(Inst::Call(dst, self.indices[callee], args.iter().map(|a| a.0).collect()));
+                    f.push(Inst::Return(dst));
+                }
+                Term::MatchFail | Term::Unreachable => f.push(Inst::Panic(NO_MATCH)),
+                Term::TailCallValue(_, _) => unreachable!("function value in native code"),
+            }
+        }
+        f
+    }
+
+    // Copies the values the phis of `to` get coming from `from`.
+    fn moves(&self, f: &mut FnState, ir_f: &ir::Function, from: BlockId, to: BlockId) {
+        let mut pairs = vec![];
+        for phi in &ir_f.block(to).phis {
+            if let Def::Phi(incoming) = ir_f.def(*phi) {
+                let (_, value) = incoming.iter().find(|(b, _)| *b == from).unwrap();
+                pairs.push((phi.0, value.0));
+            }
+        }
+        f.copies(pairs);
+    }
+}
+
+// Live virtual registers before every instruction, as bit sets.
+fn liveness(f: &FnState) -> Vec<Vec<u64>> {
+    let words = (f.vregs as usize).div_ceil(64);
+    let mut positions = vec![0; f.labels as usize];
+    for (i, inst) in f.code.iter().enumerate() {
+        if let Inst::Label(label) = inst {
+            positions[*label as usize] = i;
+        }
+    }
+    let successors = |i: usize| -> Vec<usize> {
+        match &f.code[i] {
+            Inst::Jump(label) => vec![positions[*label as usize]],
+            Inst::JumpIfFalse(_, label) => vec![i + 1, positions[*label as usize]],
+            Inst::Return(_) | Inst::Panic(_) => vec![],
+            _ if i + 1 < f.code.len() => vec![i + 1],
+            _ => vec![],
+        }
+    };
+    let mut live = vec![vec![0u64; words]; f.code.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..f.code.len()).rev() {
+            let mut set = vec![0u64; words];
+            for succ in successors(i) {
+                for (w, bits) in set.iter_mut().zip(&live[succ]) {
+                    *w |= bits;
+                }
+            }
+            if let Some(def) = f.code[i].def() {
+                set[def as usize / 64] &= !(1 << (def % 64));
+            }
+            for used in f.code[i].uses() {
+                set[used as usize / 64] |= 1 << (used % 64);
+            }
+            if set != live[i] {
+                live[i] = set;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+// Linear scan over the range of instructions each register is live or
+// defined in. Spilled registers get a stack slot each.
+fn allocate(f: &FnState) -> Allocation {
+    let live = liveness(f);
+    let is_live = |i: usize, vreg: VReg| live[i][vreg as usize / 64] & 1 << (vreg % 64) != 0;
+    let count = f.vregs as usize;
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; count];
+    let mut crosses = vec![false; count];
+    for (i, inst) in f.code.iter().enumerate() {
+        for vreg in 0..f.vregs {
+            if is_live(i, vreg) || inst.def() == Some(vreg) {
+                let range = ranges[vreg as usize].get_or_insert((i, i));
+                range.1 = i;
+            }
+        }
+        if let Inst::Call(dst, _, _) = inst {
+            for vreg in 0..f.vregs {
+                if vreg != *dst && i + 1 < f.code.len() && is_live(i + 1, vreg) {
+                    crosses[vreg as usize] = true;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..count).filter(|v| ranges[*v].is_some()).collect();
+    order.sort_by_key(|v| ranges[*v].unwrap().0);
+    let mut alloc = Allocation { locs: vec![None; count], saved: vec![], slots: 0 };
+    let mut active: Vec<(usize, Reg)> = vec![];
+    for vreg in order {
+        let (start, end) = ranges[vreg].unwrap();
+        active.retain(|(active_end, _)| *active_end >= start);
+        let free = |reg: &&Reg| !active.iter().any(|(_, r)| r == *reg);
+        let reg = if crosses[vreg] {
+            CALLEE_SAVED.iter().find(free)
+        } else {
+            CALLER_SAVED.iter().chain(CALLEE_SAVED.iter()).find(free)
+        };
+        match reg {
+            Some(reg) => {
+                active.push((end, *reg));
+                if CALLEE_SAVED.contains(reg) && !alloc.saved.contains(reg) {
+                    alloc.saved.push(*reg);
+                }
+                alloc.locs[vreg] = Some(Operand::Reg(*reg));
+            }
+            None => {
+                alloc.locs[vreg] = Some(Operand::Frame(alloc.slots as i32));
+                alloc.slots += 1;
+            }
+        }
+    }
+    // Slots go below the saved registers, now that their number is known.
+    let saved = alloc.saved.len() as i32;
+    for loc in alloc.locs.iter_mut() {
+        if let Some(Operand::Frame(slot)) = loc {
+            *slot = -8 * (saved + *slot + 1);
+        }
+    }
+    alloc
+}
+
+fn emit(asm: &mut Asm, func: &ir::Function, f: &FnState, funcs: &[Label], panics: &[Label]) {
+    let alloc = allocate(f);
+    let loc = |vreg: VReg| alloc.locs[vreg as usize].expect("register without a location");
+    let rax = Operand::Reg(Reg::Rax);
+    let rcx = Operand::Reg(Reg::Rcx);
+    asm.push(Operand::Reg(Reg::Rbp));
+    asm.mov(Operand::Reg(Reg::Rbp), Operand::Reg(Reg::Rsp));
+    for reg in &alloc.saved {
+        asm.push(Operand::Reg(*reg));
+    }
+    // Keeps the stack 16 bytes aligned for calls.
+    let mut slots = alloc.slots;
+    if !(slots + alloc.saved.len()).is_multiple_of(2) {
+        slots += 1;
+    }
+    if slots > 0 {
+        asm.sub_rsp(8 * slots as i32);
+    }
+    // No argument is in r11.
+    asm.tls_offset(Reg::R11, DEPTH);
+    asm.add_tls(Reg::R11, 1);
+    asm.cmp_tls(Reg::R11, MAX_DEPTH as i32);
+    asm.jcc(Cond::G, panics[STACK]);
+    // Only the low bits of bool and char arguments are defined.
+    for (i, param) in func.params.iter().enumerate() {
+        match func.ty(*param) {
+            Ty::Bool => asm.movzx8(ARG_REGS[i], ARG_REGS[i]),
+            Ty::Char => asm.zext32(ARG_REGS[i]),
+            _ => {}
+        }
+    }
+    let params: Vec<(usize, Operand)> =
+        f.params.iter().enumerate().filter_map(|(i, p)| alloc.locs[*p as usize].map(|l| (i, l))).collect();
+    for (i, _) in &params {
+        asm.push(Operand::Reg(ARG_REGS[*i]));
+    }
+    for (_, l) in params.iter().rev() {
+        asm.pop(*l);
+    }
+
+    let labels: Vec<Label> = (0..f.labels).map(|_| asm.label()).collect();
+    let epilogue = asm.label();
+    for inst in &f.code {
+        match inst {
+            Inst::Const(dst, val) => match loc(*dst) {
+                Operand::Reg(reg) => asm.mov_imm(reg, *val),
+                dst => {
+                    asm.mov_imm(Reg::Rax, *val);
+                    asm.mov(dst, rax);
+                }
+            },
+            Inst::Copy(dst, src) => {
+                if loc(*dst) != loc(*src) {
+                    asm.mov(loc(*dst), loc(*src));
+                }
+            }
+            Inst::Unary(op, dst, src) => {
+                asm.mov(rax, loc(*src));
+                match op {
+                    UnaryOp::Neg => {
+                        asm.neg(Reg::Rax);
+                        asm.jcc(Cond::O, panics[OVERFLOW]);
+                    }
+                    UnaryOp::Not => asm.alu_imm8(Alu::Xor, Reg::Rax, 1),
+                    UnaryOp::BitNot => asm.not(Reg::Rax),
+                }
+                asm.mov(loc(*dst), rax);
+            }
+            Inst::Binary(op, dst, l, r) => {
+                asm.mov(rax, loc(*l));
+                asm.mov(rcx, loc(*r));
+                binary(asm, *op, panics);
+                asm.mov(loc(*dst), rax);
+            }
+            Inst::Call(dst, index, args) => {
+                // Through the stack, the arguments may be in argument registers.
+                for arg in args {
+                    asm.push(loc(*arg));
+                }
+                for i in (0..args.len()).rev() {
+                    asm.pop(Operand::Reg(ARG_REGS[i]));
+                }
+                asm.call(funcs[*index]);
+                asm.mov(loc(*dst), rax);
+            }
+            Inst::Label(label) => asm.bind(labels[*label as usize]),
+            Inst::Jump(label) => asm.jmp(labels[*label as usize]),
+            Inst::JumpIfFalse(cond, label) => {
+                asm.mov(rax, loc(*cond));
+                asm.test(Reg::Rax, Reg::Rax);
+                asm.jcc(Cond::E, labels[*label as usize]);
+            }
+            Inst::Return(value) => {
+                asm.mov(rax, loc(*value));
+                asm.jmp(epilogue);
+            }
+            Inst::Panic(panic) => asm.jmp(panics[*panic]),
+        }
+    }
+    asm.bind(epilogue);
+    asm.tls_offset(Reg::R11, DEPTH);
+    asm.add_tls(Reg::R11, -1);
+    asm.lea_rsp(-8 * alloc.saved.len() as i32);
+    for reg in alloc.saved.iter().rev() {
+        asm.pop(Operand::Reg(*reg));
+    }
+    asm.pop(Operand::Reg(Reg::Rbp));
+    asm.ret();
+}
+
+// `rax = rax op rcx`, with the checks of the interpreter.
+fn binary(asm: &mut Asm, op: BinaryOp, panics: &[Label]) {
+    let compare = |asm: &mut Asm, cond: Cond| {
+        asm.alu(Alu::Cmp, Reg::Rax, Reg::Rcx);
+        asm.set(cond, Reg::Rax);
+    };
+    match op {
+        BinaryOp::Add | BinaryOp::Sub => {
+            asm.alu(if op == BinaryOp::Add { Alu::Add } else { Alu::Sub }, Reg::Rax, Reg::Rcx);
+            asm.jcc(Cond::O, panics[OVERFLOW]);
+        }
+        BinaryOp::Mul => {
+            asm.imul(Reg::Rax, Reg::Rcx);
+            asm.jcc(Cond::O, panics[OVERFLOW]);
+        }
+        BinaryOp::Div | BinaryOp::Mod => {
+            asm.test(Reg::Rcx, Reg::Rcx);
+            asm.jcc(Cond::E, panics[DIVISION]);
+            let divide = asm.label();
+            asm.alu_imm8(Alu::Cmp, Reg::Rcx, -1);
+            asm.jcc(Cond::Ne, divide);
+            asm.mov_imm(Reg::Rdx, i64::MIN);
+            asm.alu(Alu::Cmp, Reg::Rax, Reg::Rdx);
+            asm.jcc(Cond::E, panics[OVERFLOW]);
+            asm.bind(divide);
+            asm.cqo();
+            asm.idiv(Reg::Rcx);
+            if op == BinaryOp::Mod {
+                asm.mov(Operand::Reg(Reg::Rax), Operand::Reg(Reg::Rdx));
+            }
+        }
+        BinaryOp::BitAnd => asm.alu(Alu::And, Reg::Rax, Reg::Rcx),
+        BinaryOp::BitOr => asm.alu(Alu::Or, Reg::Rax, Reg::Rcx),
+        BinaryOp::BitXor => asm.alu(Alu::Xor, Reg::Rax, Reg::Rcx),
+        BinaryOp::Shl | BinaryOp::Shr => {
+            // Negative counts are large unsigned ones.
+            asm.alu_imm8(Alu::Cmp, Reg::Rcx, 64);
+            asm.jcc(Cond::Ae, panics[OVERFLOW]);
+            if op == BinaryOp::Shl {
+                asm.shl_cl(Reg::Rax);
+            } else {
+                asm.sar_cl(Reg::Rax);
+            }
+        }
+        // Bools and chars are zero extended, so signed comparisons work for
+        // all scalars.
+        BinaryOp::Eq => compare(asm, Cond::E),
+        BinaryOp::NotEq => compare(asm, Cond::Ne),
+        BinaryOp::Lt => compare(asm, Cond::L),
+        BinaryOp::LtEq => compare(asm, Cond::Le),
+        BinaryOp::Gt => compare(asm, Cond::G),
+        BinaryOp::GtEq => compare(asm, Cond::Ge),
+        BinaryOp::And | BinaryOp::Or => unreachable!("short circuit operators are jumps"),
+    }
+}
+
+pub fn compile_native_ir(program: &ir::Program) -> NativeObject {
+    Native::new(program).compile()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+    use std::process::Command;
+
+    fn compile(src: &str, foo: &str) -> NativeObject {
+        let (graph, checked, interner) = check_test_program(src, foo);
+        compile_native_ir(&lower_program(&graph, &checked, &interner))
+    }
+
+    fn run_c(name: &str, native: &NativeObject, c_main: &str) -> Result<String, String> {
+        run_c_with(name, native, "", c_main)
+    }
+
+    // Links the object with a C program calling it and runs it. Errors are
+    // what the program wrote to stdout, then to stderr.
+    fn run_c_with(name: &str, native: &NativeObject, c_defs: &str, c_main: &str) -> Result<String, String> {
+        let dir = std::env::temp_dir().join(format!("sp_native_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.o"), &native.object).unwrap();
+        std::fs::write(dir.join("main.h"), &native.header).unwrap();
+        let c_src = format!(
+            "#include <inttypes.h>\n#include <stdio.h>\n#include \"main.h\"\n{}\nint main(void) {{\n{}\n    return 0;\n}}\n",
+            c_defs, c_main
+        );
+        std::fs::write(dir.join("harness.c"), c_src).unwrap();
+        let cc = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+        let output = Command::new(&cc)
+            .current_dir(&dir)
+            .args(["-std=c99", "-Wall", "-Werror", "-pthread", "-o", "harness", "harness.c", "main.o"])
+            .output()
+            .unwrap();
+        if !output.status.success() {
+            println!("{}", String::from_utf8_lossy(&output.stderr));
+            panic!("the object doesn't link");
+        }
+        let output = Command::new(dir.join("harness")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let result = if output.status.success() {
+            Ok(stdout)
+        } else {
+            Err(stdout + String::from_utf8_lossy(&output.stderr).trim().trim_start_matches("error: "))
+        };
+        println!("{:?}", result);
+        result
+    }
+
+    #[test]
+    fn test_functions() {
+        let foo = "
+is_vowel c: char -> bool
+    case c of
+        'a' -> true
+        'e' -> true
+        'i' -> true
+        'o' -> true
+        'u' -> true
+        _ -> false
+
+export =
+    is_vowel
+";
+        let src = "
+import foo::is_vowel
+
+const LIMIT = 100
+
+fact n: int -> int
+    if n == 0
+        1
+    else
+        n * fact n-1
+
+count n, acc: int -> int
+    if n == 0
+        acc
+    else
+        count (n - 1) (acc + 1)
+
+gcd a, b: int -> int
+    if b == 0
+        a
+    else
+        gcd b (a % b)
+
+fib n: int -> int
+    case n of
+        0 -> 0
+        1 -> 1
+        _ -> fib (n - 1) + fib (n - 2)
+
+sum7 a, b, c, d, e, f: int -> int
+    let x = a - b, y = c * d in
+    x + y + (e ^ f) + (LIMIT >> 2) + (1 << 4) + ~0
+
+next c: char -> char
+    case c of
+        'z' -> 'a'
+        _ -> 'b'
+
+both x, y: bool -> bool
+    x && !y || (is_vowel 'e' && x == y)
+
+five -> int
+    5
+";
+        let native = compile(src, foo);
+        assert!(native.skipped.is_empty());
+        assert!(native.header.contains("int64_t sp_main__count(int64_t n, int64_t acc);\n"));
+        assert!(native.header.contains("bool sp_foo__is_vowel(uint32_t c);\n"));
+        assert!(native.header.contains("int64_t sp_main__five(void);\n"));
+        let c_main = r#"
+    printf("%" PRId64 " %" PRId64 " %" PRId64 "\n", sp_main__fact(20), sp_main__count(1000000, 0), sp_main__gcd(1071, 462));
+    printf("%" PRId64 " %" PRId64 " %" PRId64 "\n", sp_main__fib(25), sp_main__sum7(9, 4, 3, -7, 12, 10), sp_main__five());
+    printf("%c %c %d %d %d\n", (char)sp_main__next('z'), (char)sp_main__next('q'), sp_main__both(true, false), sp_main__both(true, true), sp_foo__is_vowel('x'));
+"#;
+        let expected = "2432902008176640000 1000000 21\n75025 30 5\na b 1 1 0\n";
+        assert!(run_c("functions", &native, c_main) == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_register_pressure() {
+        // More values live across the calls than there are registers.
+        let src = "
+id x: int -> int
+    x
+
+spill a, b: int -> int
+    let
+        c = id (a + 1)
+        d = id (b + 2)
+        e = id (c * d)
+        f = id (e - a)
+        g = id (f + b)
+        h = id (g + c)
+        i = id (h + d)
+        j = id (i + e)
+    in
+    a + b + c + d + e + f + g + h + i + j
+";
+        let native = compile(src, "");
+        let c_main = "    printf(\"%\" PRId64 \"\\n\", sp_main__spill(3, 4));";
+        // c = 4, d = 6, e = 24, f = 21, g = 25, h = 29, i = 35, j = 59.
+        assert!(run_c("pressure", &native, c_main) == Ok(String::from("210\n")));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+div x, y: int -> int
+    x / y
+
+neg x: int -> int
+    -x
+
+deep n: int -> int
+    1 + deep (n + 1)
+";
+        let native = compile(src, "");
+        // The output of the host is flushed before it exits.
+        let c_main = "    printf(\"%\" PRId64 \"\\n\", sp_main__div(7, 2));\n    sp_main__div(1, 0);";
+        assert!(run_c("division", &native, c_main) == Err(String::from("3\nDivision by zero")));
+        let c_main = "    sp_main__div(INT64_MIN, -1);";
+        assert!(run_c("div_overflow", &native, c_main) == Err(String::from("Integer overflow")));
+        let c_main = "    sp_main__neg(INT64_MIN);";
+        assert!(run_c("neg_overflow", &native, c_main) == Err(String::from("Integer overflow")));
+        let c_main = "    sp_main__deep(0);";
+        assert!(run_c("deep", &native, c_main) == Err(String::from("Stack overflow: too many nested calls")));
+
+        // The host can handle them itself and go on calling functions.
+        let c_defs = "
+#include <setjmp.h>
+
+static jmp_buf recover;
+
+void sp_runtime_error(const char *msg) {
+    printf(\"caught: %s\\n\", msg);
+    longjmp(recover, 1);
+}
+";
+        let c_main = "
+    if (!setjmp(recover)) sp_main__deep(0);
+    if (!setjmp(recover)) sp_main__div(1, 0);
+    printf(\"%\" PRId64 \"\\n\", sp_main__div(7, 2));";
+        let expected = "caught: Stack overflow: too many nested calls\ncaught: Division by zero\n3\n";
+        assert!(run_c_with("handler", &native, c_defs, c_main) == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_threads() {
+        // Each thread has its own call depth: together they go deeper than
+        // the limit.
+        let src = "
+down n: int -> int
+    if n == 0 then 0 else down (n - 1) - 1
+";
+        let native = compile(src, "");
+        let c_defs = "
+#include <pthread.h>
+
+static void *run(void *arg) {
+    int64_t sum = 0;
+    for (int i = 0; i < 100; i++) sum += sp_main__down(9000);
+    *(int64_t *)arg = sum;
+    return NULL;
+}
+";
+        let c_main = "
+    pthread_t threads[2];
+    int64_t sums[2];
+    for (int i = 0; i < 2; i++) pthread_create(&threads[i], NULL, run, &sums[i]);
+    for (int i = 0; i < 2; i++) pthread_join(threads[i], NULL);
+    printf(\"%\" PRId64 \" %\" PRId64 \"\\n\", sums[0], sums[1]);";
+        assert!(run_c_with("threads", &native, c_defs, c_main) == Ok(String::from("-900000 -900000\n")));
+    }
+
+    #[test]
+    fn test_skipped_functions() {
+        let src = "
+area r: float -> float
+    r * r * 3.0
+
+twice_area r: float -> float
+    2.0 * area r
+
+len s: string -> int
+    0
+
+size s: string -> int
+    len s
+
+apply f: (int -> int) x: int -> int
+    f x
+
+square x: int -> int
+    x * x
+";
+        let native = compile(src, "");
+        let skipped: Vec<(&str, &str)> = native
+            .skipped
+            .iter()
+            .map(|(_, diag)| (diag.msg.as_str(), diag.notes[0].as_str()))
+            .collect();
+        assert!(
+            skipped
+                == [
+                    ("Function 'area' can't be compiled to native code", "it uses values of type float"),
+                    ("Function 'twice_area' can't be compiled to native code", "it uses values of type float"),
+                    ("Function 'len' can't be compiled to native code", "it uses values of type string"),
+                    ("Function 'size' can't be compiled to native code", "it uses values of type string"),
+                    ("Function 'apply' can't be compiled to native code", "it uses values of type int -> int"),
+                ]
+        );
+        assert!(native.header.ends_with("\nint64_t sp_main__square(int64_t x);\n"));
+    }
+}
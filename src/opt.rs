@@ -0,0 +1,1211 @@
+use std::collections::HashMap;
+
+use crate::ast::BinaryOp;
+use crate::consteval::{binary_op, unary_op, ConstValue};
+use crate::ir::{debug_verify, Block, BlockId, Const, Def, FuncKind, Function, Inst, Program, Term, Ty, ValueData, ValueId};
+
+// Passes over the IR, run between lowering and the backends. Each one takes
+// a verified program to a verified program computing the same output.
+// Functions only print from `main` and can't observe allocations, so a call
+// with the same arguments gives the same result; passes may however get rid
+// of a call nesting or an overflow that would have stopped the program.
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+}
+
+impl OptLevel {
+    // The digit of `-O0`, `-O1` or `-O2`.
+    pub fn parse(level: &str) -> Option<OptLevel> {
+        match level {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            _ => None,
+        }
+    }
+}
+
+type Pass = fn(&mut Program);
+
+// -O1 cleans up after lowering, -O2 inlines first and turns recursion whose
+// result is only added to or multiplied by into loops too.
+fn passes(level: OptLevel) -> Vec<(&'static str, Pass)> {
+    let mut passes: Vec<(&'static str, Pass)> = vec![];
+    if level >= OptLevel::O2 {
+        passes.push(("inline", inline));
+        passes.push(("tailrec", |program| loop_tail_calls(program, true)));
+    } else if level >= OptLevel::O1 {
+        passes.push(("tailrec", |program| loop_tail_calls(program, false)));
+    }
+    if level >= OptLevel::O1 {
+        passes.push(("constprop", constprop));
+        passes.push(("cse", cse));
+        passes.push(("dce", dce));
+    }
+    passes
+}
+
+// Runs the passes of the level, handing the program to `dump` after each.
+pub fn optimize(program: &mut Program, level: OptLevel, dump: &mut dyn FnMut(&str, &Program)) {
+    for (name, pass) in passes(level) {
+        pass(program);
+        debug_verify(program, name);
+        dump(name, program);
+    }
+}
+
+// Drops the blocks the entry doesn't reach, laying the others out in
+// reverse postorder, and numbers the values still defined in order.
+fn compact(f: &mut Function) {
+    let order = f.reverse_postorder();
+    let mut block_ids = vec![None; f.blocks.len()];
+    for (i, block) in order.iter().enumerate() {
+        block_ids[block.0 as usize] = Some(BlockId(i as u32));
+    }
+    let mut old_blocks: Vec<_> = std::mem::take(&mut f.blocks).into_iter().map(Some).collect();
+    let mut blocks: Vec<_> = order.iter().map(|b| old_blocks[b.0 as usize].take().unwrap()).collect();
+    let mut defined = f.params.clone();
+    for block in &blocks {
+        defined.extend(block.phis.iter().chain(block.insts.iter()));
+    }
+    let mut value_ids = vec![None; f.values.len()];
+    for (i, value) in defined.iter().enumerate() {
+        value_ids[value.0 as usize] = Some(ValueId(i as u32));
+    }
+    let value_id = |v: &mut ValueId| *v = value_ids[v.0 as usize].expect("use of a value no block defines");
+    let block_id = |b: &mut BlockId| *b = block_ids[b.0 as usize].unwrap();
+    let old_values = std::mem::take(&mut f.values);
+    for value in &defined {
+        let mut data = old_values[value.0 as usize].clone();
+        match &mut data.def {
+            Def::Phi(incoming) => {
+                incoming.retain(|(b, _)| block_ids[b.0 as usize].is_some());
+                for (b, v) in incoming.iter_mut() {
+                    block_id(b);
+                    value_id(v);
+                }
+            }
+            Def::Inst(inst) => inst.operands_mut().into_iter().for_each(value_id),
+            Def::Param(_) => {}
+        }
+        f.values.push(data);
+    }
+    for block in &mut blocks {
+        block.phis.iter_mut().chain(block.insts.iter_mut()).for_each(value_id);
+        block.term.operands_mut().into_iter().for_each(value_id);
+        match &mut block.term {
+            Term::Jump(target) => block_id(target),
+            Term::Branch(_, then_block, else_block) => {
+                block_id(then_block);
+                block_id(else_block);
+            }
+            _ => {}
+        }
+    }
+    f.params.iter_mut().for_each(value_id);
+    f.blocks = blocks;
+}
+
+// Makes every use of a value `subst` maps elsewhere use its replacement.
+fn replace_uses(f: &mut Function, subst: &[ValueId]) {
+    let find = |v: &mut ValueId| {
+        while subst[v.0 as usize] != *v {
+            *v = subst[v.0 as usize];
+        }
+    };
+    for data in &mut f.values {
+        match &mut data.def {
+            Def::Phi(incoming) => incoming.iter_mut().for_each(|(_, v)| find(v)),
+            Def::Inst(inst) => inst.operands_mut().into_iter().for_each(find),
+            Def::Param(_) => {}
+        }
+    }
+    for block in &mut f.blocks {
+        block.term.operands_mut().into_iter().for_each(find);
+    }
+}
+
+fn identity(f: &Function) -> Vec<ValueId> {
+    (0..f.values.len() as u32).map(ValueId).collect()
+}
+
+fn new_value(f: &mut Function, data: ValueData) -> ValueId {
+    f.values.push(data);
+    ValueId(f.values.len() as u32 - 1)
+}
+
+// Makes the phis of `block` take from `to` what they took from `from`.
+fn rename_pred(f: &mut Function, block: BlockId, from: BlockId, to: BlockId) {
+    for phi in f.blocks[block.0 as usize].phis.clone() {
+        if let Def::Phi(incoming) = &mut f.values[phi.0 as usize].def {
+            for (pred, _) in incoming.iter_mut() {
+                if *pred == from {
+                    *pred = to;
+                }
+            }
+        }
+    }
+}
+
+fn add_incoming(f: &mut Function, phi: ValueId, pred: BlockId, value: ValueId) {
+    if let Def::Phi(incoming) = &mut f.values[phi.0 as usize].def {
+        incoming.push((pred, value));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Lattice {
+    // Not computed yet, maybe never.
+    Unknown,
+    Known(Const),
+    Varies,
+}
+
+fn scalar(value: &Const) -> Option<ConstValue> {
+    match value {
+        Const::Int(val) => Some(ConstValue::Int(*val)),
+        Const::Float(val) => Some(ConstValue::Float(*val)),
+        Const::Char(val) => Some(ConstValue::Char(*val)),
+        Const::Str(val) => Some(ConstValue::Str(val.clone())),
+        Const::Bool(val) => Some(ConstValue::Bool(*val)),
+        _ => None,
+    }
+}
+
+fn from_scalar(value: ConstValue) -> Const {
+    match value {
+        ConstValue::Int(val) => Const::Int(val),
+        ConstValue::Float(val) => Const::Float(val),
+        ConstValue::Char(val) => Const::Char(val),
+        ConstValue::Str(val) => Const::Str(val),
+        ConstValue::Bool(val) => Const::Bool(val),
+        _ => unreachable!("folding to a non-scalar"),
+    }
+}
+
+// The value of an operator on constants, when it doesn't fail. Operators on
+// other types call the instances of the program.
+fn fold_binary(op: BinaryOp, l: &Const, r: &Const) -> Option<Const> {
+    let ordered = matches!(op, BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq);
+    let ok = match (l, r) {
+        (Const::Int(_), Const::Int(_)) | (Const::Float(_), Const::Float(_)) => op != BinaryOp::And && op != BinaryOp::Or,
+        (Const::Char(_), Const::Char(_)) | (Const::Str(_), Const::Str(_)) => ordered || matches!(op, BinaryOp::Eq | BinaryOp::NotEq),
+        (Const::Bool(_), Const::Bool(_)) => matches!(op, BinaryOp::Eq | BinaryOp::NotEq),
+        _ => false,
+    };
+    if !ok {
+        return None;
+    }
+    binary_op(op, &scalar(l)?, &scalar(r)?).ok().map(from_scalar)
+}
+
+fn fold(lattice: &[Lattice], inst: &Inst) -> Lattice {
+    match inst {
+        Inst::Const(value) => return Lattice::Known(value.clone()),
+        Inst::Unary(_, _) | Inst::Binary(_, _, _) | Inst::Tuple(_) | Inst::Struct(_, _) | Inst::Variant(_, _, _) => {}
+        Inst::Field(_, _) | Inst::IsVariant(_, _) => {}
+        _ => return Lattice::Varies,
+    }
+    let mut values = vec![];
+    for operand in inst.operands() {
+        match &lattice[operand.0 as usize] {
+            Lattice::Known(value) => values.push(value.clone()),
+            Lattice::Unknown => return Lattice::Unknown,
+            Lattice::Varies => return Lattice::Varies,
+        }
+    }
+    let value = match inst {
+        Inst::Unary(op, _) => scalar(&values[0]).and_then(|v| unary_op(*op, &v).ok()).map(from_scalar),
+        Inst::Binary(op, _, _) => fold_binary(*op, &values[0], &values[1]),
+        Inst::Tuple(_) => Some(Const::Tuple(values)),
+        Inst::Struct(ty, _) => Some(Const::Struct(*ty, values)),
+        Inst::Variant(ty, index, _) => Some(Const::Variant(*ty, *index, values)),
+        Inst::Field(_, index) => match values.pop() {
+            Some(Const::Tuple(fields)) | Some(Const::Struct(_, fields)) | Some(Const::Variant(_, _, fields)) => {
+                fields.get(*index as usize).cloned()
+            }
+            _ => None,
+        },
+        Inst::IsVariant(_, index) => match &values[0] {
+            Const::Variant(_, variant, _) => Some(Const::Bool(variant == index)),
+            _ => None,
+        },
+        _ => None,
+    };
+    value.map(Lattice::Known).unwrap_or(Lattice::Varies)
+}
+
+// Successors a block can go on to with what is known of the values.
+fn live_successors(f: &Function, lattice: &[Lattice], block: BlockId) -> Vec<BlockId> {
+    match &f.block(block).term {
+        Term::Branch(cond, then_block, else_block) => match &lattice[cond.0 as usize] {
+            Lattice::Unknown => vec![],
+            Lattice::Known(Const::Bool(true)) => vec![*then_block],
+            Lattice::Known(Const::Bool(false)) => vec![*else_block],
+            _ => vec![*then_block, *else_block],
+        },
+        term => term.successors(),
+    }
+}
+
+// Sparse conditional constant propagation: values are assumed constant
+// until shown otherwise and blocks unreached until a reached one can go to
+// them, so a loop counter that never changes is found constant. Known values
+// become constants, branches on them jumps, and phis taking a single value
+// that value.
+fn constprop(program: &mut Program) {
+    for f in &mut program.funcs {
+        constprop_function(f);
+    }
+}
+
+fn constprop_function(f: &mut Function) {
+    let order = f.reverse_postorder();
+    let mut lattice = vec![Lattice::Unknown; f.values.len()];
+    for param in &f.params {
+        lattice[param.0 as usize] = Lattice::Varies;
+    }
+    let mut reached = vec![false; f.blocks.len()];
+    reached[0] = true;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &order {
+            if !reached[block.0 as usize] {
+                continue;
+            }
+            let b = f.block(*block);
+            for value in b.phis.iter().chain(b.insts.iter()) {
+                let new = match f.def(*value) {
+                    Def::Phi(incoming) => {
+                        let mut new = Lattice::Unknown;
+                        for (pred, v) in incoming {
+                            if !reached[pred.0 as usize] || !live_successors(f, &lattice, *pred).contains(block) {
+                                continue;
+                            }
+                            new = match (new, &lattice[v.0 as usize]) {
+                                (new, Lattice::Unknown) => new,
+                                (Lattice::Unknown, other) => other.clone(),
+                                (Lattice::Known(a), Lattice::Known(b)) if a == *b => Lattice::Known(a),
+                                _ => Lattice::Varies,
+                            };
+                        }
+                        new
+                    }
+                    Def::Inst(inst) => fold(&lattice, inst),
+                    Def::Param(_) => Lattice::Varies,
+                };
+                if lattice[value.0 as usize] != new {
+                    lattice[value.0 as usize] = new;
+                    changed = true;
+                }
+            }
+            for succ in live_successors(f, &lattice, *block) {
+                if !reached[succ.0 as usize] {
+                    reached[succ.0 as usize] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+    let mut subst = identity(f);
+    for block in &order {
+        if !reached[block.0 as usize] {
+            continue;
+        }
+        let b = block.0 as usize;
+        let mut consts = vec![];
+        for value in f.blocks[b].phis.clone() {
+            match &lattice[value.0 as usize] {
+                Lattice::Known(c) => {
+                    f.values[value.0 as usize].def = Def::Inst(Inst::Const(c.clone()));
+                    consts.push(value);
+                }
+                _ => {
+                    if let Def::Phi(incoming) = f.def(value) {
+                        let mut from = incoming
+                            .iter()
+                            .filter(|(pred, v)| *v != value && live_successors(f, &lattice, *pred).contains(block))
+                            .filter(|(pred, _)| reached[pred.0 as usize])
+                            .map(|(_, v)| *v);
+                        if let Some(first) = from.next() {
+                            if from.all(|v| v == first) {
+                                subst[value.0 as usize] = first;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        f.blocks[b].phis.retain(|phi| !consts.contains(phi));
+        for value in f.blocks[b].insts.clone() {
+            if let Lattice::Known(c) = &lattice[value.0 as usize] {
+                f.values[value.0 as usize].def = Def::Inst(Inst::Const(c.clone()));
+            }
+        }
+        consts.append(&mut f.blocks[b].insts);
+        f.blocks[b].insts = consts;
+        if let Term::Branch(_, then_block, else_block) = f.blocks[b].term {
+            let succs = live_successors(f, &lattice, *block);
+            if succs.len() == 1 {
+                let dropped = if succs[0] == then_block { else_block } else { then_block };
+                if dropped != succs[0] {
+                    for phi in f.blocks[dropped.0 as usize].phis.clone() {
+                        if let Def::Phi(incoming) = &mut f.values[phi.0 as usize].def {
+                            incoming.retain(|(pred, _)| pred != block);
+                        }
+                    }
+                }
+                f.blocks[b].term = Term::Jump(succs[0]);
+            }
+        }
+    }
+    // Phis only kept from blocks never reached.
+    for (b, block) in f.blocks.iter_mut().enumerate() {
+        if !reached[b] {
+            block.phis.clear();
+            block.insts.clear();
+            block.term = Term::Unreachable;
+        }
+    }
+    replace_uses(f, &subst);
+    for block in &mut f.blocks {
+        block.phis.retain(|phi| subst[phi.0 as usize] == *phi);
+    }
+    compact(f);
+}
+
+// Common subexpression elimination: an instruction computing what one
+// dominating it already did uses that value instead. Functions are pure, so
+// this covers calls; a failing instruction never gets to the second.
+fn cse(program: &mut Program) {
+    for f in &mut program.funcs {
+        cse_function(f);
+    }
+}
+
+fn cse_function(f: &mut Function) {
+    let idom = f.dominators();
+    let mut children = vec![vec![]; f.blocks.len()];
+    for block in f.reverse_postorder().into_iter().skip(1) {
+        children[idom[block.0 as usize].unwrap().0 as usize].push(block);
+    }
+    let mut subst = identity(f);
+    let mut available = HashMap::new();
+    cse_block(f, &children, BlockId(0), &mut available, &mut subst);
+    replace_uses(f, &subst);
+    for block in &mut f.blocks {
+        block.insts.retain(|value| subst[value.0 as usize] == *value);
+    }
+}
+
+fn cse_block(
+    f: &Function,
+    children: &[Vec<BlockId>],
+    block: BlockId,
+    available: &mut HashMap<String, ValueId>,
+    subst: &mut [ValueId],
+) {
+    let mut added = vec![];
+    for value in &f.block(block).insts {
+        let mut inst = f.inst(*value).unwrap().clone();
+        if let Inst::Print(_) = inst {
+            continue;
+        }
+        for operand in inst.operands_mut() {
+            *operand = subst[operand.0 as usize];
+        }
+        let key = format!("{:?}: {:?}", inst, f.ty(*value));
+        match available.get(&key) {
+            Some(earlier) => subst[value.0 as usize] = *earlier,
+            None => {
+                available.insert(key.clone(), *value);
+                added.push(key);
+            }
+        }
+    }
+    for child in &children[block.0 as usize] {
+        cse_block(f, children, *child, available, subst);
+    }
+    for key in added {
+        available.remove(&key);
+    }
+}
+
+// Dead code elimination: joins blocks to their only predecessor when it
+// jumps to them, then drops the values no effect or result needs.
+fn dce(program: &mut Program) {
+    for f in &mut program.funcs {
+        join_blocks(f);
+        dce_function(f);
+    }
+}
+
+fn join_blocks(f: &mut Function) {
+    let mut subst = identity(f);
+    let mut joined = true;
+    while joined {
+        joined = false;
+        let preds = f.predecessors();
+        for b in f.reverse_postorder() {
+            let succ = match f.block(b).term {
+                Term::Jump(succ) if succ != b && succ.0 != 0 && preds[succ.0 as usize] == [b] => succ,
+                _ => continue,
+            };
+            let span = f.block(succ).span;
+            let empty = Block { phis: vec![], insts: vec![], term: Term::Unreachable, span };
+            let next = std::mem::replace(&mut f.blocks[succ.0 as usize], empty);
+            for phi in &next.phis {
+                if let Def::Phi(incoming) = f.def(*phi) {
+                    subst[phi.0 as usize] = incoming[0].1;
+                }
+            }
+            for target in next.term.successors() {
+                rename_pred(f, target, succ, b);
+            }
+            let block = &mut f.blocks[b.0 as usize];
+            block.insts.extend(next.insts);
+            block.term = next.term;
+            block.span = next.span;
+            joined = true;
+            break;
+        }
+    }
+    replace_uses(f, &subst);
+}
+
+fn dce_function(f: &mut Function) {
+    let mut live = vec![false; f.values.len()];
+    let mut work = vec![];
+    for block in f.reverse_postorder() {
+        let block = f.block(block);
+        for value in &block.insts {
+            if !f.inst(*value).unwrap().is_pure() {
+                work.push(*value);
+            }
+        }
+        work.extend(block.term.operands());
+    }
+    while let Some(value) = work.pop() {
+        if live[value.0 as usize] {
+            continue;
+        }
+        live[value.0 as usize] = true;
+        match f.def(value) {
+            Def::Phi(incoming) => work.extend(incoming.iter().map(|(_, v)| *v)),
+            Def::Inst(inst) => work.extend(inst.operands()),
+            Def::Param(_) => {}
+        }
+    }
+    for block in &mut f.blocks {
+        block.phis.retain(|value| live[value.0 as usize]);
+        block.insts.retain(|value| live[value.0 as usize]);
+    }
+    compact(f);
+}
+
+// Callees with at most this many values are copied into their callers.
+const INLINE_SIZE: usize = 12;
+
+fn has_any(ty: &Ty) -> bool {
+    match ty {
+        Ty::Any | Ty::Param(_) => true,
+        Ty::Tuple(elems) | Ty::Struct(_, elems) | Ty::Sum(_, elems) => elems.iter().any(has_any),
+        Ty::Func(param, ret) => has_any(param) || has_any(ret),
+        _ => false,
+    }
+}
+
+// Small functions calling neither themselves nor generic code, whose values
+// have the same representation once copied into a caller.
+fn inlinable(g: &Function, index: u32) -> bool {
+    let size: usize = g.blocks.iter().map(|b| b.phis.len() + b.insts.len()).sum();
+    let recursive = g.blocks.iter().any(|b| {
+        matches!(b.term, Term::TailCall(func, _) if func == index)
+            || b.insts.iter().any(|v| matches!(g.inst(*v), Some(Inst::Call(func, _)) if *func == index))
+    });
+    matches!(g.kind, FuncKind::Func | FuncKind::Method | FuncKind::Ctor)
+        && g.captures == 0
+        && size <= INLINE_SIZE
+        && !recursive
+        && !has_any(&g.ret)
+        && !g.values.iter().any(|v| has_any(&v.ty))
+}
+
+// Functions called directly by `f`.
+fn callees(f: &Function) -> Vec<u32> {
+    let mut funcs = vec![];
+    for block in &f.blocks {
+        for value in &block.insts {
+            if let Some(Inst::Call(func, _)) = f.inst(*value) {
+                funcs.push(*func);
+            }
+        }
+        if let Term::TailCall(func, _) = block.term {
+            funcs.push(func);
+        }
+    }
+    funcs
+}
+
+// Inlining: calls of small functions become a copy of their blocks, the
+// returns jumping to the rest of the caller. Callees come before their
+// callers, so the copies have their own calls inlined already. Only the
+// calls a function makes before its turn are inlined, which keeps mutual
+// recursion finite.
+fn inline(program: &mut Program) {
+    let mut order = vec![];
+    let mut visited = vec![false; program.funcs.len()];
+    for root in 0..program.funcs.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        // Functions with the callees left to visit.
+        let mut stack = vec![(root, callees(&program.funcs[root]))];
+        while let Some((func, rest)) = stack.last_mut() {
+            match rest.pop() {
+                Some(callee) if !visited[callee as usize] => {
+                    visited[callee as usize] = true;
+                    let next = callees(&program.funcs[callee as usize]);
+                    stack.push((callee as usize, next));
+                }
+                Some(_) => {}
+                None => {
+                    order.push(*func);
+                    stack.pop();
+                }
+            }
+        }
+    }
+    for index in order {
+        let mut f = program.funcs[index].clone();
+        inline_calls(&mut f, index as u32, &program.funcs);
+        program.funcs[index] = f;
+    }
+}
+
+fn inline_calls(f: &mut Function, index: u32, funcs: &[Function]) {
+    let fits = |g: &Function, args: &[ValueId]| args.iter().zip(&g.params).all(|(arg, param)| f.ty(*arg) == g.ty(*param));
+    let mut sites = vec![];
+    for (b, block) in f.blocks.iter().enumerate() {
+        for value in &block.insts {
+            if let Some(Inst::Call(func, args)) = f.inst(*value) {
+                let g = &funcs[*func as usize];
+                if *func != index && inlinable(g, *func) && fits(g, args) && g.ret == *f.ty(*value) {
+                    sites.push((BlockId(b as u32), Some(*value)));
+                }
+            }
+        }
+        if let Term::TailCall(func, args) = &block.term {
+            let g = &funcs[*func as usize];
+            if *func != index && inlinable(g, *func) && fits(g, args) && g.ret == f.ret {
+                sites.push((BlockId(b as u32), None));
+            }
+        }
+    }
+    if sites.is_empty() {
+        return;
+    }
+    let mut subst = identity(f);
+    // Last first, so the sites before stay in their block.
+    for (block, call) in sites.into_iter().rev() {
+        inline_site(f, funcs, block, call, &mut subst);
+    }
+    subst.extend((subst.len() as u32..f.values.len() as u32).map(ValueId));
+    replace_uses(f, &subst);
+    compact(f);
+}
+
+fn inline_site(f: &mut Function, funcs: &[Function], block: BlockId, call: Option<ValueId>, subst: &mut [ValueId]) {
+    let b = block.0 as usize;
+    let (func, args, span) = match call {
+        Some(call) => match f.inst(call) {
+            Some(Inst::Call(func, args)) => (*func, args.clone(), f.values[call.0 as usize].span),
+            _ => unreachable!("inlining a non-call"),
+        },
+        None => match &f.blocks[b].term {
+            Term::TailCall(func, args) => (*func, args.clone(), f.blocks[b].span),
+            _ => unreachable!("inlining a non-call"),
+        },
+    };
+    let g = &funcs[func as usize];
+    // Errors point at the callee's code when it is in the same module.
+    let same_module = g.module == f.module;
+    let span_of = move |s| if same_module { s } else { span };
+    let rest = match call {
+        Some(call) => {
+            let pos = f.blocks[b].insts.iter().position(|v| *v == call).unwrap();
+            let after = f.blocks[b].insts.split_off(pos + 1);
+            f.blocks[b].insts.pop();
+            let rest = f.new_block(f.blocks[b].span);
+            let term = std::mem::replace(&mut f.blocks[b].term, Term::Unreachable);
+            for target in term.successors() {
+                rename_pred(f, target, block, rest);
+            }
+            f.blocks[rest.0 as usize].insts = after;
+            f.blocks[rest.0 as usize].term = term;
+            Some(rest)
+        }
+        None => None,
+    };
+    let mut values = vec![None; g.values.len()];
+    for (param, arg) in g.params.iter().zip(&args) {
+        values[param.0 as usize] = Some(*arg);
+    }
+    let first = f.blocks.len() as u32;
+    for block in &g.blocks {
+        f.new_block(span_of(block.span));
+        for value in block.phis.iter().chain(block.insts.iter()) {
+            let data = &g.values[value.0 as usize];
+            let data = ValueData { ty: data.ty.clone(), def: Def::Param(0), span: span_of(data.span) };
+            values[value.0 as usize] = Some(new_value(f, data));
+        }
+    }
+    let map = |v: &ValueId| values[v.0 as usize].unwrap();
+    let mut returns = vec![];
+    for (i, block) in g.blocks.iter().enumerate() {
+        let copy = BlockId(first + i as u32);
+        for value in block.phis.iter().chain(block.insts.iter()) {
+            let def = match g.def(*value) {
+                Def::Phi(incoming) => Def::Phi(incoming.iter().map(|(b, v)| (BlockId(first + b.0), map(v))).collect()),
+                Def::Inst(inst) => {
+                    let mut inst = inst.clone();
+                    inst.operands_mut().into_iter().for_each(|v| *v = map(v));
+                    Def::Inst(inst)
+                }
+                Def::Param(_) => unreachable!("parameter in a block"),
+            };
+            f.values[map(value).0 as usize].def = def;
+        }
+        let c = copy.0 as usize;
+        f.blocks[c].phis = block.phis.iter().map(map).collect();
+        f.blocks[c].insts = block.insts.iter().map(map).collect();
+        let mut term = block.term.clone();
+        term.operands_mut().into_iter().for_each(|v| *v = map(v));
+        f.blocks[c].term = match (term, rest) {
+            (Term::Jump(target), _) => Term::Jump(BlockId(first + target.0)),
+            (Term::Branch(cond, then_block, else_block), _) => {
+                Term::Branch(cond, BlockId(first + then_block.0), BlockId(first + else_block.0))
+            }
+            (Term::Return(value), Some(rest)) => {
+                returns.push((copy, value));
+                Term::Jump(rest)
+            }
+            // Calls the callee makes last aren't the caller's.
+            (Term::TailCall(func, args), Some(rest)) => {
+                let data = ValueData { ty: g.ret.clone(), def: Def::Inst(Inst::Call(func, args)), span: span_of(block.span) };
+                let value = new_value(f, data);
+                f.blocks[c].insts.push(value);
+                returns.push((copy, value));
+                Term::Jump(rest)
+            }
+            (Term::TailCallValue(callee, args), Some(rest)) => {
+                let data = ValueData { ty: g.ret.clone(), def: Def::Inst(Inst::CallValue(callee, args)), span: span_of(block.span) };
+                let value = new_value(f, data);
+                f.blocks[c].insts.push(value);
+                returns.push((copy, value));
+                Term::Jump(rest)
+            }
+            (term, _) => term,
+        };
+    }
+    f.blocks[b].term = Term::Jump(BlockId(first));
+    if let (Some(call), Some(rest)) = (call, rest) {
+        match returns.as_slice() {
+            [] => {}
+            [(_, value)] => subst[call.0 as usize] = *value,
+            _ => {
+                let ty = f.ty(call).clone();
+                let phi = f.phi(rest, returns, ty, span);
+                subst[call.0 as usize] = phi;
+            }
+        }
+    }
+}
+
+// Tail recursion to loops: the entry moves to a new block heading a loop,
+// with a phi for every parameter, and calls of the function to itself in
+// tail position jump back to it. With `accumulate`, a call whose integer
+// result is only added to or multiplied by an operand and returned also
+// becomes a jump, the phi of an accumulator carrying the partial result
+// that every return then combines: `n * fact_rec n-1` no longer needs a
+// stack.
+//
+// The recursion combines the operands from the innermost call out, the
+// loop from the outermost in, so it only goes on while the order can't
+// matter: the operands so far are at least 0 for a sum and at least 1 for
+// a product, and the partial result fits. Combining it with what the rest
+// of the recursion returns then overflows exactly when the recursion
+// would. Otherwise the loop calls the function for the rest, which reports
+// the same errors in the same order as the recursion.
+fn loop_tail_calls(program: &mut Program, accumulate: bool) {
+    for (index, f) in program.funcs.iter_mut().enumerate() {
+        if matches!(f.kind, FuncKind::Func | FuncKind::Method) {
+            loop_function(f, index as u32, accumulate);
+        }
+    }
+}
+
+// `op v` where v is a call of the function itself, used only there.
+fn accumulation(f: &Function, index: u32, uses: &[u32], value: ValueId) -> Option<(BinaryOp, ValueId, ValueId)> {
+    let (op, l, r) = match f.inst(value) {
+        Some(Inst::Binary(op, l, r)) if matches!(op, BinaryOp::Add | BinaryOp::Mul) && *f.ty(value) == Ty::Int => (*op, *l, *r),
+        _ => return None,
+    };
+    let is_self_call = |v: ValueId| matches!(f.inst(v), Some(Inst::Call(func, _)) if *func == index) && uses[v.0 as usize] == 1;
+    if is_self_call(r) && l != r {
+        Some((op, l, r))
+    } else if is_self_call(l) && l != r {
+        Some((op, r, l))
+    } else {
+        None
+    }
+}
+
+// The block returning an accumulation, when there is only one: its block,
+// value, operand and call. The call and the operation end the block, so the
+// operand is computed before the call, as it is before the jump.
+fn accumulation_site(f: &Function, index: u32) -> Option<(BlockId, ValueId, ValueId, ValueId)> {
+    let uses = f.use_counts();
+    let mut sites = vec![];
+    for block in f.reverse_postorder() {
+        let data = f.block(block);
+        if let Term::Return(value) = data.term {
+            if let Some((_, other, call)) = accumulation(f, index, &uses, value) {
+                if data.insts.ends_with(&[call, value]) && uses[value.0 as usize] == 1 {
+                    sites.push((block, value, other, call));
+                }
+            }
+        }
+    }
+    match sites[..] {
+        [site] => Some(site),
+        _ => None,
+    }
+}
+
+fn loop_function(f: &mut Function, index: u32, accumulate: bool) {
+    let calls_self = f.values.iter().any(|v| matches!(&v.def, Def::Inst(Inst::Call(func, _)) if *func == index));
+    if accumulate && calls_self && f.ret == Ty::Int {
+        return_from_preds(f);
+    }
+    let site = if accumulate { accumulation_site(f, index) } else { None };
+    let order = f.reverse_postorder();
+    let tail_calls = order.iter().any(|b| matches!(f.block(*b).term, Term::TailCall(func, _) if func == index));
+    if site.is_none() && !tail_calls {
+        return;
+    }
+    let span = f.blocks[0].span;
+    let header = f.new_block(span);
+    f.blocks.swap(0, header.0 as usize);
+    let entry = BlockId(0);
+    for target in f.block(header).term.successors() {
+        rename_pred(f, target, entry, header);
+    }
+    let site = site.map(|(b, v, o, c)| (if b == entry { header } else { b }, v, o, c));
+    f.blocks[0].term = Term::Jump(header);
+    let mut subst = identity(f);
+    let mut phis = vec![];
+    for param in f.params.clone() {
+        let data = &f.values[param.0 as usize];
+        let (ty, span) = (data.ty.clone(), data.span);
+        let phi = f.phi(header, vec![], ty, span);
+        subst.push(phi);
+        subst[param.0 as usize] = phi;
+        phis.push(phi);
+    }
+    replace_uses(f, &subst);
+    for (param, phi) in f.params.clone().into_iter().zip(&phis) {
+        add_incoming(f, *phi, entry, param);
+    }
+    // The operator, the accumulator and the span of the operation, which
+    // every combination reports overflows at.
+    let acc = site.map(|(_, value, _, _)| {
+        let op = match f.inst(value) {
+            Some(Inst::Binary(op, _, _)) => *op,
+            _ => unreachable!("accumulating a non-operation"),
+        };
+        let init = f.push(entry, Inst::Const(Const::Int(identity_of(op))), Ty::Int, span);
+        (op, f.phi(header, vec![(entry, init)], Ty::Int, span), f.values[value.0 as usize].span)
+    });
+    for (b, block) in f.blocks.clone().iter().enumerate() {
+        let id = BlockId(b as u32);
+        match (&block.term, site, acc) {
+            (_, Some((site, value, other, call)), Some((op, acc, op_span))) if site == id => {
+                let other = subst[other.0 as usize];
+                let args = match f.inst(call) {
+                    Some(Inst::Call(_, args)) => args.clone(),
+                    _ => unreachable!("accumulating a non-call"),
+                };
+                f.blocks[b].insts.retain(|v| *v != value && *v != call);
+                let (fits, step, rest) = (f.new_block(op_span), f.new_block(op_span), f.new_block(op_span));
+                let least = f.push(id, Inst::Const(Const::Int(identity_of(op))), Ty::Int, op_span);
+                let in_order = f.push(id, Inst::Binary(BinaryOp::GtEq, other, least), Ty::Bool, op_span);
+                f.terminate(id, Term::Branch(in_order, fits, rest), op_span);
+                // `acc op other <= MAX`, without overflowing.
+                let max = f.push(fits, Inst::Const(Const::Int(i64::MAX)), Ty::Int, op_span);
+                let inverse = if op == BinaryOp::Mul { BinaryOp::Div } else { BinaryOp::Sub };
+                let limit = f.push(fits, Inst::Binary(inverse, max, other), Ty::Int, op_span);
+                let fit = f.push(fits, Inst::Binary(BinaryOp::LtEq, acc, limit), Ty::Bool, op_span);
+                f.terminate(fits, Term::Branch(fit, step, rest), op_span);
+                let next = f.push(step, Inst::Binary(op, acc, other), Ty::Int, op_span);
+                for (phi, arg) in phis.iter().zip(&args) {
+                    add_incoming(f, *phi, step, *arg);
+                }
+                add_incoming(f, acc, step, next);
+                f.terminate(step, Term::Jump(header), op_span);
+                let call = f.push(rest, Inst::Call(index, args), Ty::Int, op_span);
+                let inner = f.push(rest, Inst::Binary(op, other, call), Ty::Int, op_span);
+                let result = f.push(rest, Inst::Binary(op, acc, inner), Ty::Int, op_span);
+                f.terminate(rest, Term::Return(result), op_span);
+            }
+            (Term::TailCall(func, args), _, _) if *func == index => {
+                for (phi, arg) in phis.iter().zip(args) {
+                    add_incoming(f, *phi, id, *arg);
+                }
+                if let Some((_, acc, _)) = acc {
+                    add_incoming(f, acc, id, acc);
+                }
+                f.blocks[b].term = Term::Jump(header);
+            }
+            (Term::Return(value), _, Some((op, acc, op_span))) => {
+                let result = f.push(id, Inst::Binary(op, acc, *value), Ty::Int, op_span);
+                f.blocks[b].term = Term::Return(result);
+            }
+            (Term::TailCall(func, args), _, Some((op, acc, op_span))) => {
+                let call = f.push(id, Inst::Call(*func, args.clone()), f.ret.clone(), block.span);
+                let result = f.push(id, Inst::Binary(op, acc, call), Ty::Int, op_span);
+                f.blocks[b].term = Term::Return(result);
+            }
+            (Term::TailCallValue(callee, args), _, Some((op, acc, op_span))) => {
+                let call = f.push(id, Inst::CallValue(*callee, args.clone()), f.ret.clone(), block.span);
+                let result = f.push(id, Inst::Binary(op, acc, call), Ty::Int, op_span);
+                f.blocks[b].term = Term::Return(result);
+            }
+            _ => {}
+        }
+    }
+    compact(f);
+}
+
+// 0 for sums, 1 for products.
+fn identity_of(op: BinaryOp) -> i64 {
+    if op == BinaryOp::Mul {
+        1
+    } else {
+        0
+    }
+}
+
+// Blocks that only return a phi of theirs return its value from the
+// predecessors jumping to them instead, which puts the calls the phi takes
+// the results of in tail position.
+fn return_from_preds(f: &mut Function) {
+    for (b, preds) in f.predecessors().iter().enumerate() {
+        let phi = match (&f.blocks[b].term, f.blocks[b].phis.as_slice()) {
+            (Term::Return(value), [phi]) if value == phi && f.blocks[b].insts.is_empty() => *phi,
+            _ => continue,
+        };
+        let incoming = match f.def(phi) {
+            Def::Phi(incoming) => incoming.clone(),
+            _ => continue,
+        };
+        for pred in preds {
+            if let Term::Jump(_) = f.blocks[pred.0 as usize].term {
+                let (_, value) = incoming.iter().find(|(p, _)| p == pred).unwrap();
+                f.blocks[pred.0 as usize].term = Term::Return(*value);
+                if let Def::Phi(incoming) = &mut f.values[phi.0 as usize].def {
+                    incoming.retain(|(p, _)| p != pred);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile_ir;
+    use crate::ir::verify;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+    use crate::vm::run_vm;
+
+    fn lower(src: &str) -> Program {
+        let (graph, checked, interner) = check_test_program(src, "");
+        lower_program(&graph, &checked, &interner)
+    }
+
+    fn run(program: &Program) -> Result<String, String> {
+        let result = run_vm(&compile_ir(program)).map_err(|err| err.diag.msg);
+        println!("{:?}", result);
+        result
+    }
+
+    // Optimises the program, checking it after every pass.
+    fn optimized(src: &str, level: OptLevel) -> Program {
+        let mut program = lower(src);
+        optimize(&mut program, level, &mut |pass, program| {
+            println!("; after {}\n{}", pass, program.dump());
+            if let Err(errors) = verify(program) {
+                panic!("{}: {:#?}", pass, errors);
+            }
+        });
+        program
+    }
+
+    // The optimised program prints the same as the lowered one.
+    fn same_output(src: &str, level: OptLevel) -> Program {
+        let expected = run(&lower(src));
+        let program = optimized(src, level);
+        assert!(run(&program) == expected);
+        program
+    }
+
+    fn calls(f: &Function, func: u32) -> usize {
+        let insts = f.values.iter().filter(|v| matches!(&v.def, Def::Inst(Inst::Call(g, _)) if *g == func)).count();
+        insts + f.blocks.iter().filter(|b| matches!(b.term, Term::TailCall(g, _) if g == func)).count()
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(OptLevel::parse("2") == Some(OptLevel::O2) && OptLevel::parse("3").is_none());
+        let mut passes = vec![];
+        optimize(&mut lower("1 + 2\n"), OptLevel::O2, &mut |pass, _| passes.push(String::from(pass)));
+        assert!(passes == vec!["inline", "tailrec", "constprop", "cse", "dce"]);
+        passes.clear();
+        optimize(&mut lower("1 + 2\n"), OptLevel::O0, &mut |pass, _| passes.push(String::from(pass)));
+        assert!(passes.is_empty());
+    }
+
+    #[test]
+    fn test_constprop() {
+        let src = "
+seven x: int -> int
+    if 2 * 3 > 5
+        2 * 3 + 1 + x
+    else
+        0
+
+overflow x: int -> int
+    if x > 0
+        9223372036854775807 + 1
+    else
+        1 / 0
+
+seven 1
+overflow 1
+";
+        let program = same_output(src, OptLevel::O1);
+        let expected = "fn 0 main::seven(v0: int) -> int
+  b0:
+    v1: int = const 7
+    v2: int = binary + v1, v0
+    return v2
+";
+        assert!(program.dump_function(0) == expected);
+        // Operations that fail stay for the program to report.
+        assert!(program.dump_function(1).matches("binary").count() == 3);
+    }
+
+    #[test]
+    fn test_cse_and_dce() {
+        let src = "
+square_sum x, y: int -> int
+    let
+        unused = x < y
+        a = x * x + y
+    in
+    a + (x * x + y)
+
+square_sum 3 4
+";
+        let program = same_output(src, OptLevel::O1);
+        let dump = program.dump_function(0);
+        assert!(dump.matches("binary *").count() == 1 && dump.matches("binary +").count() == 2);
+        assert!(!dump.contains("binary <"));
+    }
+
+    #[test]
+    fn test_inline() {
+        let src = "
+multiply x, y: int -> int
+    x * y
+
+square x: int -> int
+    multiply x x
+
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+square (multiply 2 3)
+fact_rec 5
+";
+        let program = same_output(src, OptLevel::O2);
+        let main = &program.funcs[program.main as usize];
+        assert!(calls(&program.funcs[1], 0) == 0 && calls(main, 0) == 0 && calls(main, 1) == 0);
+        // Recursive functions stay calls.
+        assert!(calls(main, 2) == 1);
+    }
+
+    #[test]
+    fn test_tail_recursion() {
+        let src = "
+count n, acc: int -> int
+    if n == 0
+        acc
+    else
+        count (n - 1) (acc + 1)
+
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+sum_to n: int -> int
+    if n == 0
+        0
+    else
+        sum_to (n - 1) + n
+
+count 100 0
+fact_rec 20
+sum_to 100
+";
+        let program = same_output(src, OptLevel::O1);
+        assert!(calls(&program.funcs[0], 0) == 0);
+        assert!(calls(&program.funcs[1], 1) == 1 && calls(&program.funcs[2], 2) == 1);
+        let program = same_output(src, OptLevel::O2);
+        let expected = "fn 1 main::fact_rec(v0: int) -> int
+  b0:
+    v1: int = const 1
+    jump b1
+  b1:  ; preds b0, b5
+    v2: int = phi b0 v0, b5 v7
+    v3: int = phi b0 v1, b5 v12
+    v4: int = const 0
+    v5: bool = binary == v2, v4
+    branch v5 b2 b3
+  b2:  ; preds b1
+    v6: int = binary * v3, v1
+    return v6
+  b3:  ; preds b1
+    v7: int = binary - v2, v1
+    v8: bool = binary >= v2, v1
+    branch v8 b4 b6
+  b4:  ; preds b3
+    v9: int = const 9223372036854775807
+    v10: int = binary / v9, v2
+    v11: bool = binary <= v3, v10
+    branch v11 b5 b6
+  b5:  ; preds b4
+    v12: int = binary * v3, v2
+    jump b1
+  b6:  ; preds b3, b4
+    v13: int = call 1 v7  ; main::fact_rec
+    v14: int = binary * v2, v13
+    v15: int = binary * v3, v14
+    return v15
+";
+        assert!(program.dump_function(1) == expected);
+        // Recursion too deep for the stack runs as a loop.
+        let deep = "sum_to n: int -> int\n    if n == 0\n        0\n    else\n        n + sum_to (n - 1)\n\nsum_to 100000\n";
+        assert!(run(&lower(deep)) == Err(String::from("Stack overflow: too many nested calls")));
+        assert!(run(&optimized(deep, OptLevel::O2)) == Ok(String::from("5000050000\n")));
+
+        // The loop stops where combining in its order could overflow when
+        // the recursion doesn't, or the other way around: MAX + 1 comes
+        // before adding -5, 2 * 2 * ... before multiplying by 0, and -MAX
+        // before MAX + MAX.
+        let overflow = "
+g n: int -> int
+    if n == 3 then -9223372036854775807 else 9223372036854775807
+
+f n: int -> int
+    if n == 0 then 0 else g n + f (n - 1)
+
+h n: int -> int
+    if n == 0 then -5 else (if n == 2 then 9223372036854775807 else 1) + h (n - 1)
+
+doubles n: int -> int
+    if n == 0 then 0 else 2 * doubles (n - 1)
+
+h 2
+doubles 100
+fact_rec 20
+f 3
+";
+        let src = format!("{}{}", &src[..src.find("count 100 0").unwrap()], overflow);
+        let program = same_output(&src, OptLevel::O2);
+        assert!(run(&program) == Err(String::from("Integer overflow")));
+        // At the same operation.
+        let error = |program: &Program| run_vm(&compile_ir(program)).unwrap_err().diag.span;
+        assert!(error(&program) == error(&lower(&src)));
+        let program = same_output(&src.replace("f 3", "fact_rec 21"), OptLevel::O2);
+        assert!(error(&program) == error(&lower(&src.replace("f 3", "fact_rec 21"))));
+    }
+
+    #[test]
+    fn test_programs() {
+        let src = String::from(crate::prelude::PRELUDE)
+            + "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+struct Vector =
+    x, y: float
+
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    sub a, b = Vector {a.x - b.x, a.y - b.y}
+    mul a, b = Vector {a.x * b.x, a.y * b.y}
+    div a, b = Vector {a.x / b.x, a.y / b.y}
+    neg v = Vector {-v.x, -v.y}
+
+instance Ord Vector =
+    lt a, b = a.x < b.x
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Rect w h -> w * h
+        Empty -> 0.0
+
+norm {x, y}: Vector -> float
+    x * x + y * y
+
+adder n: int -> (int -> int)
+    \\x -> x + n
+
+range n: int -> List int
+    if n == 0
+        Nil
+    else
+        Cons n (range (n - 1))
+
+const ORIGIN = Vector {x = 0.0, y = 0.0}
+
+foldl (\\acc x -> acc + x) 0 (range 10)
+map (adder 2) (range 3)
+Vector {1.0, 2.0} + {3.0, 4.0} >= ORIGIN
+-(Vector {1.0, 2.0})
+norm {y = 2.0, x = 1.0} + norm ORIGIN
+map area (Cons (Circle 1.0) (Cons (Rect 2.0 3.0) Nil))
+`${norm ORIGIN} ${1 < 2 || false} ${ORIGIN.x == 0.0}`
+";
+        for level in [OptLevel::O1, OptLevel::O2] {
+            same_output(&src, level);
+        }
+    }
+}
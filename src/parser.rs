@@ -0,0 +1,1784 @@
+use crate::ast::*;
+use crate::common::{Interner, Span};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{escape_to_char, tokenize, Token, TokenKind, TokenVal};
+
+pub struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    prev_end: usize,
+    // Number of INDENT tokens consumed and not yet closed by a DEDENT.
+    indent_depth: usize,
+    interner: &'a mut Interner,
+    next_id: u32,
+    pub errors: Vec<Diagnostic>,
+}
+
+fn token_desc(token: &Token) -> String {
+    match (&token.token_kind, &token.val) {
+        (TokenKind::EOF, _) => String::from("end of file"),
+        (TokenKind::NEWLINE, _) => String::from("end of line"),
+        (TokenKind::INDENT, _) => String::from("indentation"),
+        (TokenKind::DEDENT, _) => String::from("end of block"),
+        (TokenKind::NAME, Some(TokenVal::Str(name))) => format!("name '{}'", name),
+        (TokenKind::KEYWORD, Some(TokenVal::Str(name))) => format!("keyword '{}'", name),
+        (TokenKind::INT, _) | (TokenKind::FLOAT, _) => String::from("number"),
+        (TokenKind::STR, _) | (TokenKind::TEMPLATE, _) => String::from("string"),
+        (TokenKind::CHAR, _) => String::from("char"),
+        (TokenKind::LAST_CHAR(c), _) => format!("'{}'", c),
+        (kind, _) => format!("{:?}", kind),
+    }
+}
+
+fn binary_op(kind: &TokenKind) -> Option<BinaryOp> {
+    let op = match kind {
+        TokenKind::LAST_CHAR('*') => BinaryOp::Mul,
+        TokenKind::LAST_CHAR('/') => BinaryOp::Div,
+        TokenKind::LAST_CHAR('%') => BinaryOp::Mod,
+        TokenKind::LAST_CHAR('&') => BinaryOp::BitAnd,
+        TokenKind::LAST_CHAR('+') => BinaryOp::Add,
+        TokenKind::LAST_CHAR('-') => BinaryOp::Sub,
+        TokenKind::LAST_CHAR('|') => BinaryOp::BitOr,
+        TokenKind::LAST_CHAR('^') => BinaryOp::BitXor,
+        TokenKind::LSHIFT => BinaryOp::Shl,
+        TokenKind::RSHIFT => BinaryOp::Shr,
+        TokenKind::EQ => BinaryOp::Eq,
+        TokenKind::NOTEQ => BinaryOp::NotEq,
+        TokenKind::LAST_CHAR('<') => BinaryOp::Lt,
+        TokenKind::LTEQ => BinaryOp::LtEq,
+        TokenKind::LAST_CHAR('>') => BinaryOp::Gt,
+        TokenKind::GTEQ => BinaryOp::GtEq,
+        TokenKind::AND => BinaryOp::And,
+        TokenKind::OR => BinaryOp::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn unary_op(kind: &TokenKind) -> Option<UnaryOp> {
+    match kind {
+        TokenKind::LAST_CHAR('-') => Some(UnaryOp::Neg),
+        TokenKind::LAST_CHAR('!') => Some(UnaryOp::Not),
+        TokenKind::LAST_CHAR('~') => Some(UnaryOp::BitNot),
+        _ => None,
+    }
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>, interner: &'a mut Interner) -> Parser<'a> {
+        Parser {
+            tokens,
+            pos: 0,
+            prev_end: 0,
+            indent_depth: 0,
+            interner,
+            next_id: 0,
+            errors: vec![],
+        }
+    }
+
+    fn new_id(&mut self) -> NodeId {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn peek(&self) -> &Token {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, n: usize) -> &Token {
+        let i = (self.pos + n).min(self.tokens.len() - 1);
+        &self.tokens[i]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        match token.token_kind {
+            TokenKind::INDENT => self.indent_depth += 1,
+            TokenKind::DEDENT => self.indent_depth -= 1,
+            TokenKind::NEWLINE => {}
+            _ => self.prev_end = token.span.end,
+        }
+        token
+    }
+
+    fn start(&self) -> usize {
+        self.peek().span.start
+    }
+
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.prev_end.max(start))
+    }
+
+    fn is_kind(&self, kind: TokenKind) -> bool {
+        self.peek().token_kind == kind
+    }
+
+    fn is_char(&self, c: char) -> bool {
+        self.peek().token_kind == TokenKind::LAST_CHAR(c)
+    }
+
+    fn is_keyword(&self, name: &str) -> bool {
+        let token = self.peek();
+        token.token_kind == TokenKind::KEYWORD && token.val == Some(TokenVal::Str(String::from(name)))
+    }
+
+    fn match_kind(&mut self, kind: TokenKind) -> bool {
+        if self.is_kind(kind) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn match_char(&mut self, c: char) -> bool {
+        self.match_kind(TokenKind::LAST_CHAR(c))
+    }
+
+    fn match_keyword(&mut self, name: &str) -> bool {
+        if self.is_keyword(name) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn unexpected(&self, msg: &str) -> Diagnostic {
+        Diagnostic::error(self.peek().span, format!("{}, found {}", msg, token_desc(self.peek())))
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T, Diagnostic> {
+        Err(self.unexpected(msg))
+    }
+
+    // Synchronisation: skips the rest of the current line at `depth`, including
+    // any block hanging off it. Stops before the DEDENT closing the enclosing block.
+    fn sync_line(&mut self, depth: usize) {
+        loop {
+            match self.peek().token_kind {
+                TokenKind::EOF => return,
+                TokenKind::NEWLINE if self.indent_depth == depth => {
+                    self.next();
+                    return;
+                }
+                TokenKind::DEDENT if self.indent_depth == depth => return,
+                TokenKind::DEDENT if self.indent_depth == depth + 1 => {
+                    self.next();
+                    return;
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    // Skips to the end of the block opened at `depth`, consuming its DEDENT.
+    fn sync_block(&mut self, depth: usize) {
+        loop {
+            match self.peek().token_kind {
+                TokenKind::EOF => return,
+                TokenKind::DEDENT if self.indent_depth == depth + 1 => {
+                    self.next();
+                    return;
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    fn expect_kind(&mut self, kind: TokenKind, what: &str) -> Result<(), Diagnostic> {
+        if self.match_kind(kind) {
+            Ok(())
+        } else {
+            self.error(&format!("Expected {}", what))
+        }
+    }
+
+    fn expect_char(&mut self, c: char) -> Result<(), Diagnostic> {
+        if self.match_char(c) {
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'", c))
+        }
+    }
+
+    fn expect_keyword(&mut self, name: &str) -> Result<(), Diagnostic> {
+        if self.match_keyword(name) {
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'", name))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<Ident, Diagnostic> {
+        if !self.is_kind(TokenKind::NAME) {
+            return self.error("Expected name");
+        }
+        let token = self.next();
+        let name = match &token.val {
+            Some(TokenVal::Str(name)) => self.interner.intern(name),
+            _ => unreachable!(),
+        };
+        Ok(Ident { name, span: token.span })
+    }
+
+    // A line is over at a NEWLINE, or right after a block that closed it.
+    fn expect_line_end(&mut self, what: &str) -> Result<(), Diagnostic> {
+        if self.match_kind(TokenKind::NEWLINE) {
+            return Ok(());
+        }
+        if self.after_block() {
+            return Ok(());
+        }
+        self.error(&format!("Expected end of line after {}", what))
+    }
+
+    // Whether the last token closed a block, which also ends its line: what
+    // follows is the next line, not an argument or operand.
+    fn after_block(&self) -> bool {
+        self.pos > 0 && self.tokens[self.pos - 1].token_kind == TokenKind::DEDENT
+    }
+
+    // Consumes the DEDENT closing a block. The end of the file closes it
+    // too, so that the block loops can't spin there after an error.
+    fn match_block_end(&mut self) -> bool {
+        self.match_kind(TokenKind::DEDENT) || self.is_kind(TokenKind::EOF)
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.match_kind(TokenKind::NEWLINE) {}
+    }
+
+    fn is_block_start(&self) -> bool {
+        self.is_kind(TokenKind::NEWLINE) && self.peek_at(1).token_kind == TokenKind::INDENT
+    }
+
+    pub fn parse_module(&mut self) -> Module {
+        let mut decls = vec![];
+        self.skip_newlines();
+        while !self.is_kind(TokenKind::EOF) {
+            if self.is_kind(TokenKind::INDENT) {
+                self.errors.push(Diagnostic::error(self.peek().span, "Unexpected indentation at top level"));
+                self.next();
+                self.sync_block(0);
+                self.skip_newlines();
+                continue;
+            }
+            let start = self.start();
+            let decl = self.parse_decl().and_then(|decl| {
+                if !self.is_kind(TokenKind::EOF) {
+                    self.expect_line_end("declaration")?;
+                }
+                Ok(decl)
+            });
+            match decl {
+                Ok(decl) => decls.push(decl),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.sync_line(0);
+                    decls.push(Decl {
+                        id: self.new_id(),
+                        span: self.span_from(start),
+                        kind: DeclKind::Error,
+                    });
+                }
+            }
+            self.skip_newlines();
+        }
+        Module { decls }
+    }
+
+    fn parse_decl(&mut self) -> Result<Decl, Diagnostic> {
+        let start = self.start();
+        let kind = if self.match_keyword("const") {
+            self.parse_const()?
+        } else if self.match_keyword("type") {
+            self.parse_type_decl()?
+        } else if self.match_keyword("struct") {
+            self.parse_struct_decl()?
+        } else if self.match_keyword("instance") {
+            self.parse_instance()?
+        } else if self.match_keyword("import") {
+            self.parse_import()?
+        } else if self.match_keyword("export") {
+            self.parse_export()?
+        } else if self.is_kind(TokenKind::NAME) && self.is_func_decl() {
+            self.parse_func()?
+        } else {
+            DeclKind::Expr(self.parse_expr()?)
+        };
+        Ok(Decl {
+            id: self.new_id(),
+            span: self.span_from(start),
+            kind,
+        })
+    }
+
+    // A line starting with a name declares a function when it carries a signature
+    // (`:` or `->`), an `=`, or is followed by an indented body. Anything else is
+    // an expression statement such as `greet "Silver pancake"`.
+    fn is_func_decl(&self) -> bool {
+        let mut depth = 0;
+        let mut i = self.pos + 1;
+        while i < self.tokens.len() {
+            match &self.tokens[i].token_kind {
+                TokenKind::LAST_CHAR('(') | TokenKind::LAST_CHAR('[') | TokenKind::LAST_CHAR('{') => depth += 1,
+                TokenKind::LAST_CHAR(')') | TokenKind::LAST_CHAR(']') | TokenKind::LAST_CHAR('}') => depth -= 1,
+                TokenKind::LAST_CHAR(':') | TokenKind::LAST_CHAR('=') | TokenKind::ARROW if depth == 0 => return true,
+                TokenKind::LAST_CHAR('?') if depth == 0 => return false,
+                TokenKind::NEWLINE => {
+                    return i + 1 < self.tokens.len() && self.tokens[i + 1].token_kind == TokenKind::INDENT;
+                }
+                TokenKind::EOF => return false,
+                _ => {}
+            }
+            i += 1;
+        }
+        false
+    }
+
+    fn parse_const(&mut self) -> Result<DeclKind, Diagnostic> {
+        let name = self.expect_name()?;
+        let ty = if self.match_char(':') {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        self.expect_char('=')?;
+        let expr = self.parse_block_or_expr()?;
+        Ok(DeclKind::Const(ConstDecl { name, ty, expr }))
+    }
+
+    fn parse_func(&mut self) -> Result<DeclKind, Diagnostic> {
+        Ok(DeclKind::Func(self.parse_func_decl()?))
+    }
+
+    fn parse_func_decl(&mut self) -> Result<FuncDecl, Diagnostic> {
+        let name = self.expect_name()?;
+        let mut params: Vec<Param> = vec![];
+        let mut untyped = 0;
+        let mut ret = None;
+        loop {
+            if self.is_kind(TokenKind::NAME) || self.is_char('{') || self.is_char('(') {
+                // Parameters are irrefutable patterns: `x`, `{a, b}`, `(x, y)`.
+                let pattern = self.parse_pattern_atom()?;
+                params.push(Param {
+                    id: self.new_id(),
+                    pattern,
+                    ty: None,
+                });
+            } else if self.match_char(',') {
+                continue;
+            } else if self.match_char(':') {
+                if untyped == params.len() {
+                    return self.error("Expected parameter name before ':'");
+                }
+                // `x, y: int` annotates every parameter since the previous annotation.
+                let ty = self.parse_type_app(true)?;
+                for param in params[untyped..].iter_mut() {
+                    param.ty = Some(ty.clone());
+                }
+                untyped = params.len();
+            } else if self.match_kind(TokenKind::ARROW) {
+                ret = Some(self.parse_type()?);
+                break;
+            } else {
+                break;
+            }
+        }
+        self.match_char('=');
+        let body = self.parse_block_or_expr()?;
+        Ok(FuncDecl { name, params, ret, body })
+    }
+
+    // `instance Num Vector =` followed by a block of method definitions.
+    fn parse_instance(&mut self) -> Result<DeclKind, Diagnostic> {
+        let trait_name = self.expect_name()?;
+        let ty = self.parse_type_app(false)?;
+        self.expect_char('=')?;
+        if !self.is_block_start() {
+            return self.error("Expected an indented block of methods");
+        }
+        self.next();
+        self.next();
+        let depth = self.indent_depth;
+        let mut methods = vec![];
+        while !self.match_block_end() {
+            let method = self.parse_func_decl().and_then(|method| {
+                self.expect_line_end("method")?;
+                Ok(method)
+            });
+            match method {
+                Ok(method) => methods.push(method),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.sync_line(depth);
+                }
+            }
+            self.skip_newlines();
+        }
+        Ok(DeclKind::Instance(InstanceDecl { trait_name, ty, methods }))
+    }
+
+    // Type parameters of a generic declaration: `struct Pair a b = ...`.
+    fn parse_type_params(&mut self) -> Result<Vec<Ident>, Diagnostic> {
+        let mut params = vec![];
+        while self.is_kind(TokenKind::NAME) {
+            if self.is_constructor_name() {
+                return self.error("Expected a lowercase type parameter");
+            }
+            params.push(self.expect_name()?);
+        }
+        Ok(params)
+    }
+
+    fn parse_struct_decl(&mut self) -> Result<DeclKind, Diagnostic> {
+        let name = self.expect_name()?;
+        let params = self.parse_type_params()?;
+        self.expect_char('=')?;
+        let mut fields = vec![];
+        if self.is_block_start() {
+            self.next();
+            self.next();
+            let depth = self.indent_depth;
+            while !self.match_block_end() {
+                if let Err(err) = self.parse_field_group(&mut fields).and_then(|_| self.expect_line_end("struct field")) {
+                    self.errors.push(err);
+                    self.sync_line(depth);
+                }
+            }
+        } else {
+            self.parse_field_group(&mut fields)?;
+        }
+        Ok(DeclKind::Struct(StructDecl { name, params, fields }))
+    }
+
+    // Parses `x, y: float` style groups, possibly several separated by commas.
+    fn parse_field_group(&mut self, fields: &mut Vec<FieldDecl>) -> Result<(), Diagnostic> {
+        loop {
+            let mut names = vec![self.expect_name()?];
+            while self.match_char(',') {
+                names.push(self.expect_name()?);
+            }
+            self.expect_char(':')?;
+            let ty = self.parse_type()?;
+            for name in names {
+                fields.push(FieldDecl { name, ty: ty.clone() });
+            }
+            if !self.match_char(',') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_type_decl(&mut self) -> Result<DeclKind, Diagnostic> {
+        let name = self.expect_name()?;
+        let params = self.parse_type_params()?;
+        self.expect_char('=')?;
+        let mut variants = vec![];
+        if self.is_block_start() {
+            self.next();
+            self.next();
+            let depth = self.indent_depth;
+            while !self.match_block_end() {
+                if let Err(err) = self.parse_variants(&mut variants).and_then(|_| self.expect_line_end("variant")) {
+                    self.errors.push(err);
+                    self.sync_line(depth);
+                }
+            }
+        } else {
+            self.parse_variants(&mut variants)?;
+        }
+        Ok(DeclKind::Type(TypeDecl { name, params, variants }))
+    }
+
+    fn parse_variants(&mut self, variants: &mut Vec<Variant>) -> Result<(), Diagnostic> {
+        self.match_char('|');
+        loop {
+            let name = self.expect_name()?;
+            let mut fields = vec![];
+            if self.match_char(':') {
+                fields.push(self.parse_type()?);
+                while self.match_char(',') {
+                    fields.push(self.parse_type()?);
+                }
+            }
+            variants.push(Variant {
+                id: self.new_id(),
+                name,
+                fields,
+            });
+            if !self.match_char('|') {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_import(&mut self) -> Result<DeclKind, Diagnostic> {
+        let mut path = vec![self.expect_name()?];
+        while self.match_kind(TokenKind::COLON_COLON) {
+            path.push(self.expect_name()?);
+        }
+        Ok(DeclKind::Import(ImportDecl { path }))
+    }
+
+    fn parse_export(&mut self) -> Result<DeclKind, Diagnostic> {
+        self.expect_char('=')?;
+        let mut names = vec![];
+        if self.is_block_start() {
+            self.next();
+            self.next();
+            let depth = self.indent_depth;
+            while !self.match_block_end() {
+                let name = self.expect_name().and_then(|name| {
+                    if !self.match_char(',') {
+                        self.expect_line_end("exported name")?;
+                    }
+                    Ok(name)
+                });
+                match name {
+                    Ok(name) => names.push(name),
+                    Err(err) => {
+                        self.errors.push(err);
+                        self.sync_line(depth);
+                    }
+                }
+                self.skip_newlines();
+            }
+        } else {
+            names.push(self.expect_name()?);
+            while self.match_char(',') {
+                names.push(self.expect_name()?);
+            }
+        }
+        Ok(DeclKind::Export(ExportDecl { names }))
+    }
+
+    pub fn parse_type(&mut self) -> Result<TypeExpr, Diagnostic> {
+        let start = self.start();
+        let ty = self.parse_type_app(false)?;
+        if self.match_kind(TokenKind::ARROW) {
+            let ret = self.parse_type()?;
+            return Ok(TypeExpr {
+                id: self.new_id(),
+                span: self.span_from(start),
+                kind: TypeExprKind::Func(Box::new(ty), Box::new(ret)),
+            });
+        }
+        Ok(ty)
+    }
+
+    // `List a`, `Maybe (List int)`: a capitalized type name takes the type
+    // atoms after it as arguments. In a parameter list a name followed by `:`
+    // starts the next parameter group instead.
+    fn parse_type_app(&mut self, in_params: bool) -> Result<TypeExpr, Diagnostic> {
+        let start = self.start();
+        if !self.is_constructor_name() {
+            return self.parse_type_atom();
+        }
+        let name = self.expect_name()?;
+        let mut args = vec![];
+        loop {
+            let is_arg = match self.peek().token_kind {
+                TokenKind::LAST_CHAR('(') => true,
+                TokenKind::NAME => !in_params || self.peek_at(1).token_kind != TokenKind::LAST_CHAR(':'),
+                _ => false,
+            };
+            if !is_arg {
+                break;
+            }
+            args.push(self.parse_type_atom()?);
+        }
+        let kind = if args.is_empty() {
+            TypeExprKind::Name(name.name)
+        } else {
+            TypeExprKind::App(name.name, args)
+        };
+        Ok(TypeExpr {
+            id: self.new_id(),
+            span: self.span_from(start),
+            kind,
+        })
+    }
+
+    fn parse_type_atom(&mut self) -> Result<TypeExpr, Diagnostic> {
+        let start = self.start();
+        if self.match_char('(') {
+            let ty = self.parse_type()?;
+            if !self.match_char(',') {
+                self.expect_char(')')?;
+                return Ok(ty);
+            }
+            let mut elems = vec![ty];
+            while !self.is_char(')') {
+                elems.push(self.parse_type()?);
+                if !self.match_char(',') {
+                    break;
+                }
+            }
+            self.expect_char(')')?;
+            return Ok(TypeExpr {
+                id: self.new_id(),
+                span: self.span_from(start),
+                kind: TypeExprKind::Tuple(elems),
+            });
+        }
+        if !self.is_kind(TokenKind::NAME) {
+            return self.error("Expected type");
+        }
+        let name = self.expect_name()?;
+        Ok(TypeExpr {
+            id: self.new_id(),
+            span: self.span_from(start),
+            kind: TypeExprKind::Name(name.name),
+        })
+    }
+
+    // Either an indented block holding a single expression or an inline expression.
+    fn parse_block_or_expr(&mut self) -> Result<Expr, Diagnostic> {
+        if self.is_block_start() {
+            let start = self.peek_at(1).span.start;
+            let depth = self.indent_depth;
+            self.next();
+            self.next();
+            let expr = self.parse_expr().and_then(|expr| {
+                self.skip_newlines();
+                if !self.is_kind(TokenKind::DEDENT) {
+                    return self.error("Expected end of block, a block holds a single expression");
+                }
+                Ok(expr)
+            });
+            return match expr {
+                Ok(expr) => {
+                    self.next();
+                    Ok(expr)
+                }
+                Err(err) => {
+                    // Recover at the dedent: the block becomes an error node and
+                    // parsing resumes after it.
+                    self.errors.push(err);
+                    self.sync_block(depth);
+                    Ok(Expr {
+                        id: self.new_id(),
+                        span: self.span_from(start),
+                        kind: ExprKind::Error,
+                    })
+                }
+            };
+        }
+        self.parse_expr()
+    }
+
+    fn make_expr(&mut self, start: usize, kind: ExprKind) -> Expr {
+        Expr {
+            id: self.new_id(),
+            span: self.span_from(start),
+            kind,
+        }
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        let cond = self.parse_binary(1, false)?;
+        if self.match_char('?') {
+            let then_expr = self.parse_expr()?;
+            self.expect_char(':')?;
+            let else_expr = self.parse_expr()?;
+            return Ok(self.make_expr(start, ExprKind::If(Box::new(cond), Box::new(then_expr), Box::new(else_expr))));
+        }
+        Ok(cond)
+    }
+
+    fn is_tight_op(&self) -> bool {
+        !self.peek().space_before && !self.peek_at(1).space_before
+    }
+
+    // Precedence climbing. In tight mode only operators written without
+    // surrounding spaces are accepted and operands are postfix expressions, so
+    // `fact_rec n-1` applies `fact_rec` to `n-1`.
+    fn parse_binary(&mut self, min_prec: u8, tight: bool) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        let mut lhs = if tight {
+            self.parse_tight_operand()?
+        } else {
+            self.parse_unary()?
+        };
+        while let Some(op) = binary_op(&self.peek().token_kind) {
+            if (tight && !self.is_tight_op()) || self.after_block() {
+                break;
+            }
+            let prec = op.precedence();
+            if prec < min_prec {
+                break;
+            }
+            self.next();
+            let rhs = self.parse_binary(prec + 1, tight)?;
+            lhs = self.make_expr(start, ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        if let Some(op) = unary_op(&self.peek().token_kind) {
+            if self.peek_at(1).space_before {
+                self.next();
+                let expr = self.parse_unary()?;
+                return Ok(self.make_expr(start, ExprKind::Unary(op, Box::new(expr))));
+            }
+        }
+        self.parse_app()
+    }
+
+    fn parse_app(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        let head = self.parse_binary(1, true)?;
+        let mut args = vec![];
+        while self.is_arg_start() {
+            args.push(self.parse_binary(1, true)?);
+        }
+        if args.is_empty() {
+            return Ok(head);
+        }
+        Ok(self.make_expr(start, ExprKind::Call(Box::new(head), args)))
+    }
+
+    fn is_arg_start(&self) -> bool {
+        if self.after_block() {
+            return false;
+        }
+        let token = self.peek();
+        match &token.token_kind {
+            TokenKind::NAME | TokenKind::INT | TokenKind::FLOAT | TokenKind::STR
+            | TokenKind::TEMPLATE | TokenKind::CHAR | TokenKind::LAST_CHAR('(')
+            | TokenKind::LAST_CHAR('{') => true,
+            TokenKind::KEYWORD => self.is_keyword("true") || self.is_keyword("false"),
+            // `f -1` passes a negative number while `f - 1` subtracts.
+            kind => unary_op(kind).is_some() && token.space_before && !self.peek_at(1).space_before,
+        }
+    }
+
+    fn parse_tight_operand(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        if let Some(op) = unary_op(&self.peek().token_kind) {
+            if !self.peek_at(1).space_before {
+                self.next();
+                let expr = self.parse_tight_operand()?;
+                return Ok(self.make_expr(start, ExprKind::Unary(op, Box::new(expr))));
+            }
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        let mut expr = self.parse_primary()?;
+        while self.match_char('.') {
+            let field = self.expect_name()?;
+            expr = self.make_expr(start, ExprKind::Field(Box::new(expr), field));
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.start();
+        let kind = match (&self.peek().token_kind, &self.peek().val) {
+            (TokenKind::INT, Some(TokenVal::Int(val))) => ExprKind::Int(*val),
+            (TokenKind::FLOAT, Some(TokenVal::Float(val))) => ExprKind::Float(*val),
+            (TokenKind::CHAR, Some(TokenVal::Char(val))) => ExprKind::Char(*val),
+            (TokenKind::STR, Some(TokenVal::Str(val))) => ExprKind::Str(val.clone()),
+            (TokenKind::TEMPLATE, _) => return self.parse_template(),
+            (TokenKind::NAME, _) => {
+                let name = self.expect_name()?;
+                // Type names are capitalized, so `Rect {...}` is a struct literal
+                // while `make_vect {...}` applies a function to one.
+                let is_type_name = self.interner.get(name.name).starts_with(|c: char| c.is_ascii_uppercase());
+                if is_type_name && self.is_char('{') {
+                    return self.parse_struct_lit(start, Some(name));
+                }
+                if self.match_kind(TokenKind::COLON_COLON) {
+                    let member = self.expect_name()?;
+                    return Ok(self.make_expr(start, ExprKind::Path(name, member)));
+                }
+                return Ok(self.make_expr(start, ExprKind::Name(name.name)));
+            }
+            (TokenKind::LAST_CHAR('{'), _) => return self.parse_struct_lit(start, None),
+            (TokenKind::LAST_CHAR('\\'), _) => {
+                self.next();
+                let mut params = vec![];
+                while !self.is_kind(TokenKind::ARROW) {
+                    params.push(self.parse_pattern_atom()?);
+                }
+                if params.is_empty() {
+                    return self.error("Expected lambda parameter");
+                }
+                self.next();
+                let body = self.parse_block_or_expr()?;
+                return Ok(self.make_expr(start, ExprKind::Lambda(params, Box::new(body))));
+            }
+            (TokenKind::KEYWORD, _) => {
+                if self.match_keyword("if") {
+                    return self.parse_if(start);
+                } else if self.match_keyword("let") {
+                    return self.parse_let(start);
+                } else if self.match_keyword("case") {
+                    return self.parse_case(start);
+                } else if self.is_keyword("true") || self.is_keyword("false") {
+                    ExprKind::Bool(self.is_keyword("true"))
+                } else {
+                    return self.error("Expected expression");
+                }
+            }
+            (TokenKind::LAST_CHAR('('), _) => {
+                self.next();
+                let mut expr = self.parse_expr()?;
+                if self.match_char(',') {
+                    let mut elems = vec![expr];
+                    while !self.is_char(')') {
+                        elems.push(self.parse_expr()?);
+                        if !self.match_char(',') {
+                            break;
+                        }
+                    }
+                    self.expect_char(')')?;
+                    return Ok(self.make_expr(start, ExprKind::Tuple(elems)));
+                }
+                self.expect_char(')')?;
+                expr.span = self.span_from(start);
+                return Ok(expr);
+            }
+            _ => return self.error("Expected expression"),
+        };
+        self.next();
+        Ok(self.make_expr(start, kind))
+    }
+
+    fn parse_struct_lit(&mut self, start: usize, name: Option<Ident>) -> Result<Expr, Diagnostic> {
+        self.expect_char('{')?;
+        let mut fields: Vec<FieldInit> = vec![];
+        let mut mixed_reported = false;
+        while !self.is_char('}') {
+            let field_start = self.start();
+            let field_name = if self.is_kind(TokenKind::NAME) && self.peek_at(1).token_kind == TokenKind::LAST_CHAR('=') {
+                let field_name = self.expect_name()?;
+                self.next();
+                Some(field_name)
+            } else {
+                None
+            };
+            let expr = self.parse_expr()?;
+            let field = FieldInit {
+                name: field_name,
+                expr,
+                span: self.span_from(field_start),
+            };
+            if !mixed_reported && !fields.is_empty() && fields[0].name.is_some() != field.name.is_some() {
+                self.errors.push(Diagnostic::error(field.span, "Cannot mix named and positional fields in a struct literal"));
+                mixed_reported = true;
+            }
+            fields.push(field);
+            if !self.match_char(',') {
+                break;
+            }
+        }
+        self.expect_char('}')?;
+        Ok(self.make_expr(start, ExprKind::StructLit(StructLit { name, fields })))
+    }
+
+    fn parse_if(&mut self, start: usize) -> Result<Expr, Diagnostic> {
+        let cond = self.parse_expr()?;
+        if !self.match_keyword("then") && !self.is_block_start() {
+            return self.error("Expected 'then' or an indented block after if condition");
+        }
+        let then_expr = self.parse_block_or_expr()?;
+        if self.is_kind(TokenKind::NEWLINE) && self.peek_at(1).token_kind == TokenKind::KEYWORD
+            && self.peek_at(1).val == Some(TokenVal::Str(String::from("else")))
+        {
+            self.next();
+        }
+        self.expect_keyword("else")?;
+        let else_expr = self.parse_block_or_expr()?;
+        Ok(self.make_expr(start, ExprKind::If(Box::new(cond), Box::new(then_expr), Box::new(else_expr))))
+    }
+
+    fn parse_case(&mut self, start: usize) -> Result<Expr, Diagnostic> {
+        let scrutinee = self.parse_expr()?;
+        self.expect_keyword("of")?;
+        if !self.is_block_start() {
+            return self.error("Expected an indented block of case arms");
+        }
+        self.next();
+        self.next();
+        let depth = self.indent_depth;
+        let mut arms = vec![];
+        while !self.match_block_end() {
+            let arm = self.parse_case_arm().and_then(|arm| {
+                self.expect_line_end("case arm")?;
+                Ok(arm)
+            });
+            match arm {
+                Ok(arm) => arms.push(arm),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.sync_line(depth);
+                }
+            }
+        }
+        Ok(self.make_expr(start, ExprKind::Case(Box::new(scrutinee), arms)))
+    }
+
+    fn parse_case_arm(&mut self) -> Result<CaseArm, Diagnostic> {
+        let pattern = self.parse_pattern()?;
+        self.expect_kind(TokenKind::ARROW, "'->' after pattern")?;
+        let body = self.parse_block_or_expr()?;
+        Ok(CaseArm { pattern, body })
+    }
+
+    fn make_pattern(&mut self, start: usize, kind: PatternKind) -> Pattern {
+        Pattern {
+            id: self.new_id(),
+            span: self.span_from(start),
+            kind,
+        }
+    }
+
+    fn is_constructor_name(&self) -> bool {
+        match &self.peek().val {
+            Some(TokenVal::Str(name)) if self.is_kind(TokenKind::NAME) => name.starts_with(|c: char| c.is_ascii_uppercase()),
+            _ => false,
+        }
+    }
+
+    fn is_pattern_start(&self) -> bool {
+        match &self.peek().token_kind {
+            TokenKind::NAME | TokenKind::INT | TokenKind::CHAR | TokenKind::STR
+            | TokenKind::LAST_CHAR('(') | TokenKind::LAST_CHAR('{') | TokenKind::LAST_CHAR('-') => true,
+            TokenKind::KEYWORD => self.is_keyword("true") || self.is_keyword("false"),
+            _ => false,
+        }
+    }
+
+    // Constructors take their fields by juxtaposition: `OTHER c`, `Cons x (Cons y _)`.
+    pub fn parse_pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        let start = self.start();
+        if self.is_constructor_name() && self.peek_at(1).token_kind != TokenKind::LAST_CHAR('{') {
+            let name = self.expect_name()?;
+            let mut args = vec![];
+            while self.is_pattern_start() {
+                args.push(self.parse_pattern_atom()?);
+            }
+            return Ok(self.make_pattern(start, PatternKind::Variant(name, args)));
+        }
+        self.parse_pattern_atom()
+    }
+
+    fn parse_pattern_atom(&mut self) -> Result<Pattern, Diagnostic> {
+        let start = self.start();
+        if self.match_char('(') {
+            let mut pattern = self.parse_pattern()?;
+            if self.match_char(',') {
+                let mut elems = vec![pattern];
+                while !self.is_char(')') {
+                    elems.push(self.parse_pattern()?);
+                    if !self.match_char(',') {
+                        break;
+                    }
+                }
+                self.expect_char(')')?;
+                return Ok(self.make_pattern(start, PatternKind::Tuple(elems)));
+            }
+            self.expect_char(')')?;
+            pattern.span = self.span_from(start);
+            return Ok(pattern);
+        }
+        if self.is_char('{') {
+            return self.parse_struct_pattern(start, None);
+        }
+        if self.is_constructor_name() && self.peek_at(1).token_kind == TokenKind::LAST_CHAR('{') {
+            let name = self.expect_name()?;
+            return self.parse_struct_pattern(start, Some(name));
+        }
+        if self.match_char('-') {
+            return match self.next().val {
+                Some(TokenVal::Int(val)) => Ok(self.make_pattern(start, PatternKind::Int(-(val as i64)))),
+                _ => Err(Diagnostic::error(self.span_from(start), "Expected integer after '-' in pattern")),
+            };
+        }
+        let kind = match (&self.peek().token_kind, &self.peek().val) {
+            (TokenKind::NAME, _) => {
+                let name = self.expect_name()?;
+                let kind = if self.interner.get(name.name) == "_" {
+                    PatternKind::Wildcard
+                } else if self.interner.get(name.name).starts_with(|c: char| c.is_ascii_uppercase()) {
+                    PatternKind::Variant(name, vec![])
+                } else {
+                    PatternKind::Binding(name.name)
+                };
+                return Ok(self.make_pattern(start, kind));
+            }
+            (TokenKind::INT, Some(TokenVal::Int(val))) => PatternKind::Int(*val as i64),
+            (TokenKind::CHAR, Some(TokenVal::Char(val))) => PatternKind::Char(*val),
+            (TokenKind::STR, Some(TokenVal::Str(val))) => PatternKind::Str(val.clone()),
+            (TokenKind::KEYWORD, _) if self.is_keyword("true") => PatternKind::Bool(true),
+            (TokenKind::KEYWORD, _) if self.is_keyword("false") => PatternKind::Bool(false),
+            _ => return self.error("Expected pattern"),
+        };
+        self.next();
+        Ok(self.make_pattern(start, kind))
+    }
+
+    // Mirrors parse_struct_lit: fields are either all positional or all named.
+    fn parse_struct_pattern(&mut self, start: usize, name: Option<Ident>) -> Result<Pattern, Diagnostic> {
+        self.expect_char('{')?;
+        let mut fields: Vec<FieldPattern> = vec![];
+        let mut mixed_reported = false;
+        while !self.is_char('}') {
+            let field_start = self.start();
+            let field_name = if self.is_kind(TokenKind::NAME) && self.peek_at(1).token_kind == TokenKind::LAST_CHAR('=') {
+                let field_name = self.expect_name()?;
+                self.next();
+                Some(field_name)
+            } else {
+                None
+            };
+            let pattern = self.parse_pattern()?;
+            let field = FieldPattern {
+                name: field_name,
+                pattern,
+                span: self.span_from(field_start),
+            };
+            if !mixed_reported && !fields.is_empty() && fields[0].name.is_some() != field.name.is_some() {
+                self.errors.push(Diagnostic::error(field.span, "Cannot mix named and positional fields in a struct pattern"));
+                mixed_reported = true;
+            }
+            fields.push(field);
+            if !self.match_char(',') {
+                break;
+            }
+        }
+        self.expect_char('}')?;
+        Ok(self.make_pattern(start, PatternKind::Struct(name, fields)))
+    }
+
+    fn parse_let(&mut self, start: usize) -> Result<Expr, Diagnostic> {
+        let mut bindings = vec![];
+        if self.is_block_start() {
+            self.next();
+            self.next();
+            let depth = self.indent_depth;
+            while !self.match_block_end() {
+                let binding = self.parse_binding().and_then(|binding| {
+                    self.expect_line_end("let binding")?;
+                    Ok(binding)
+                });
+                match binding {
+                    Ok(binding) => bindings.push(binding),
+                    Err(err) => {
+                        self.errors.push(err);
+                        self.sync_line(depth);
+                    }
+                }
+            }
+        } else {
+            bindings.push(self.parse_binding()?);
+            while self.match_char(',') {
+                bindings.push(self.parse_binding()?);
+            }
+        }
+        self.match_kind(TokenKind::NEWLINE);
+        self.expect_keyword("in")?;
+        if self.is_kind(TokenKind::NEWLINE) && !self.is_block_start() {
+            self.next();
+        }
+        let body = self.parse_block_or_expr()?;
+        Ok(self.make_expr(start, ExprKind::Let(bindings, Box::new(body))))
+    }
+
+    fn parse_binding(&mut self) -> Result<LetBinding, Diagnostic> {
+        let pattern = self.parse_pattern()?;
+        let ty = if self.match_char(':') {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        self.expect_char('=')?;
+        let expr = self.parse_block_or_expr()?;
+        Ok(LetBinding {
+            id: self.new_id(),
+            pattern,
+            ty,
+            expr,
+        })
+    }
+
+    // Splits `Hello ${str}` into literal parts and expressions. Each `${...}`
+    // is lexed and parsed on its own with spans shifted back into the file.
+    // The expression inside `${...}`, which must take all of the tokens.
+    fn parse_template_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.parse_expr()?;
+        self.skip_newlines();
+        if !self.is_kind(TokenKind::EOF) {
+            return self.error("Expected '}' closing template expression");
+        }
+        Ok(expr)
+    }
+
+    fn parse_template(&mut self) -> Result<Expr, Diagnostic> {
+        let token = self.next();
+        let raw = match &token.val {
+            Some(TokenVal::Str(raw)) => raw.clone(),
+            _ => unreachable!(),
+        };
+        let base = token.span.start + 1;
+        let mut parts = vec![];
+        let mut lit = String::new();
+        let mut chars = raw.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c == '\\' {
+                if let Some((_, e)) = chars.next() {
+                    lit.push(escape_to_char(e));
+                }
+            } else if c == '$' && chars.peek().map(|(_, c)| *c) == Some('{') {
+                chars.next();
+                let inner_start = i + 2;
+                let mut depth = 1;
+                let mut inner_end = raw.len();
+                for (j, c) in chars.by_ref() {
+                    if c == '{' {
+                        depth += 1;
+                    } else if c == '}' {
+                        depth -= 1;
+                        if depth == 0 {
+                            inner_end = j;
+                            break;
+                        }
+                    }
+                }
+                if depth != 0 {
+                    return Err(Diagnostic::error(token.span, "Unclosed '${' in template string"));
+                }
+                if !lit.is_empty() {
+                    parts.push(TemplatePart::Str(std::mem::take(&mut lit)));
+                }
+                let mut inner = &raw[inner_start..inner_end];
+                let (mut tokens, mut errors) = tokenize(&mut inner);
+                for t in tokens.iter_mut() {
+                    t.span.start += base + inner_start;
+                    t.span.end += base + inner_start;
+                }
+                for err in errors.iter_mut() {
+                    err.span.start += base + inner_start;
+                    err.span.end += base + inner_start;
+                }
+                self.errors.append(&mut errors);
+                let mut sub = Parser::new(tokens, self.interner);
+                sub.next_id = self.next_id;
+                let expr = sub.parse_template_expr();
+                // Errors the sub-parser recovered from count even when it
+                // stopped at a later one.
+                self.next_id = sub.next_id;
+                self.errors.append(&mut sub.errors);
+                parts.push(TemplatePart::Expr(expr?));
+            } else {
+                lit.push(c);
+            }
+        }
+        if !lit.is_empty() {
+            parts.push(TemplatePart::Str(lit));
+        }
+        Ok(Expr {
+            id: self.new_id(),
+            span: token.span,
+            kind: ExprKind::Template(parts),
+        })
+    }
+}
+
+// Parses a whole file. Syntax errors don't stop the parse: the faulty
+// declarations and blocks are replaced by error nodes and every error is
+// returned, after the lexical ones.
+pub fn parse_module(src: &str, interner: &mut Interner) -> (Module, Vec<Diagnostic>) {
+    let mut stream = src;
+    let (tokens, mut errors) = tokenize(&mut stream);
+    let mut parser = Parser::new(tokens, interner);
+    let module = parser.parse_module();
+    errors.append(&mut parser.errors);
+    (module, errors)
+}
+
+#[cfg(test)]
+pub fn parse_expr_str(src: &str, interner: &mut Interner) -> Result<Expr, Diagnostic> {
+    let mut stream = src;
+    let (tokens, mut errors) = tokenize(&mut stream);
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
+    let mut parser = Parser::new(tokens, interner);
+    let expr = parser.parse_expr()?;
+    parser.skip_newlines();
+    if !parser.is_kind(TokenKind::EOF) {
+        return parser.error("Expected end of expression");
+    }
+    if !parser.errors.is_empty() {
+        return Err(parser.errors.remove(0));
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sexpr::{expr_to_sexpr, module_to_sexpr};
+
+    fn sexpr(src: &str) -> String {
+        let mut interner = Interner::new();
+        let expr = parse_expr_str(src, &mut interner).unwrap();
+        expr_to_sexpr(&expr, &interner)
+    }
+
+    macro_rules! test_expr {
+        ($src:expr, $sexpr:expr) => {
+            let s = sexpr($src);
+            println!("{} => {}", $src, s);
+            assert!(s == $sexpr);
+        };
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        test_expr!("1 + 2 * 3", "(+ 1 (* 2 3))");
+        test_expr!("(1 + 2) * 3", "(* (+ 1 2) 3)");
+        test_expr!("a == b && c < d || e", "(|| (&& (== a b) (< c d)) e)");
+        test_expr!("1 - 2 - 3", "(- (- 1 2) 3)");
+        test_expr!("a | b & c", "(| a (& b c))");
+        test_expr!("c ? 1 : 2", "(if c (then 1) (else 2))");
+    }
+
+    #[test]
+    fn test_application() {
+        test_expr!("greet \"Silver pancake\"", "(greet \"Silver pancake\")");
+        test_expr!("make_vect a b", "(make_vect a b)");
+        test_expr!("f x + g y", "(+ (f x) (g y))");
+        test_expr!("f (g x) y", "(f (g x) y)");
+        test_expr!("f x.y", "(f (field x y))");
+        test_expr!("- f x", "(- (f x))");
+    }
+
+    #[test]
+    fn test_application_tight_operators() {
+        test_expr!("fact_rec n-1", "(fact_rec (- n 1))");
+        test_expr!("n * fact_rec n-1", "(* n (fact_rec (- n 1)))");
+        test_expr!("fact_rec n - 1", "(- (fact_rec n) 1)");
+        test_expr!("f a+b*2 c", "(f (+ a (* b 2)) c)");
+        test_expr!("a+b * c", "(* (+ a b) c)");
+        test_expr!("f -1", "(f (- 1))");
+        test_expr!("f -n-1", "(f (- (- n) 1))");
+    }
+
+    #[test]
+    fn test_flatten_call() {
+        let mut interner = Interner::new();
+        let expr = parse_expr_str("(f a) b", &mut interner).unwrap();
+        let (head, args) = flatten_call(&expr);
+        assert!(head.kind == ExprKind::Name(interner.intern("f")));
+        assert!(args.len() == 2);
+    }
+
+    #[test]
+    fn test_if_and_let() {
+        test_expr!("if a then b else c", "(if a (then b) (else c))");
+        test_expr!("if a then b else if c then d else e", "(if a (then b) (else (if c (then d) (else e))))");
+        test_expr!("let y = 1, z: int = 2 in x + y", "(let ((y 1) (z int 2)) (+ x y))");
+    }
+
+    #[test]
+    fn test_lambda() {
+        test_expr!("\\x -> x + 1", "(lambda (x) (+ x 1))");
+        test_expr!("\\f (a, b) -> f a b", "(lambda (f (tuple a b)) (f a b))");
+        test_expr!("map (\\x -> x * 2) xs", "(map (lambda (x) (* x 2)) xs)");
+    }
+
+    #[test]
+    fn test_template() {
+        let mut interner = Interner::new();
+        let expr = parse_expr_str("`Hello ${str}!`", &mut interner).unwrap();
+        assert!(expr_to_sexpr(&expr, &interner) == "(template \"Hello \" str \"!\")");
+        if let ExprKind::Template(parts) = &expr.kind {
+            if let TemplatePart::Expr(e) = &parts[1] {
+                assert!(e.span == Span::new(9, 12));
+            } else {
+                panic!("expected template expression");
+            }
+        }
+    }
+
+    #[test]
+    fn test_template_errors() {
+        // Both the error the template expression recovered from and the one
+        // it stopped at are reported.
+        let mut interner = Interner::new();
+        let (_, errors) = parse_module("`a ${Vector {x = 1, 2} 3 )}`\n", &mut interner);
+        println!("{:?}", errors);
+        assert!(errors.len() == 2);
+        assert!(errors[0].msg == "Cannot mix named and positional fields in a struct literal");
+        assert!(errors[0].span == Span::new(20, 21));
+        assert!(errors[1].msg.starts_with("Expected '}' closing template expression"));
+        let (_, errors) = parse_module("`a ${Vector {x = 1, 2}}`\n", &mut interner);
+        assert!(errors.len() == 1 && errors[0].span == Span::new(20, 21));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut interner = Interner::new();
+        let err = parse_expr_str("1 + * 2", &mut interner).unwrap_err();
+        println!("{:?}", err);
+        assert!(err.span == Span::new(4, 5));
+        assert!(err.msg == "Expected expression, found '*'");
+    }
+
+    #[test]
+    fn test_parse_module() {
+        let src = "
+const PI = 3.14
+
+type TokenKind =
+    FLOAT
+    INT
+    OTHER: char
+
+struct Vector =
+    x, y: float
+
+multiply x, y: int -> int
+    x * y
+
+add_one x: int -> int
+    let
+        y: int = 1
+    in
+    x + y
+
+import foo
+
+greet \"Silver pancake\"
+
+export =
+    greet
+";
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        assert!(errors.is_empty());
+        let s = module_to_sexpr(&module, &interner);
+        println!("{}", s);
+        assert!(s == "(const PI 3.14)
+(type TokenKind FLOAT INT (OTHER char))
+(struct Vector (x float) (y float))
+(func multiply (x int) (y int) int
+  (return (* x y)))
+(func add_one (x int) int
+  (let ((y int 1))
+    (return (+ x y))))
+(import foo)
+(greet \"Silver pancake\")
+(export greet)
+");
+    }
+
+    #[test]
+    fn test_module_paths() {
+        let src = "
+import foo::greet
+import util::text
+
+text::shout (foo::greet \"Silver pancake\")
+";
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        assert!(errors.is_empty());
+        let s = module_to_sexpr(&module, &interner);
+        println!("{}", s);
+        assert!(s == "(import foo::greet)
+(import util::text)
+(text::shout (foo::greet \"Silver pancake\"))
+");
+        let (_, errors) = parse_with_errors("import foo::\n");
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Expected name, found end of line");
+    }
+
+    #[test]
+    fn test_sexpr_matches_syntax_doc() {
+        let src = "
+fact n: int -> int =
+    if n == 0
+        1
+    else
+        n * fact n-1
+";
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        assert!(errors.is_empty());
+        let s = module_to_sexpr(&module, &interner);
+        println!("{}", s);
+        assert!(s == "(func fact (n int) int
+  (if (== n 0)
+    (then
+      (return 1))
+    (else
+      (return (* n (fact (- n 1)))))))
+");
+    }
+
+    fn parse_with_errors(src: &str) -> (String, Vec<Diagnostic>) {
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        for err in &errors {
+            println!("{:?}", err);
+        }
+        (module_to_sexpr(&module, &interner), errors)
+    }
+
+    #[test]
+    fn test_block_ends_line() {
+        // The next binding or declaration after a block isn't an argument of
+        // the expression the block ends.
+        let src = "
+f x: int -> int
+    let
+        y = case x of
+            0 -> 1
+            _ -> 2
+        z = if x > 0
+            1
+        else
+            2
+        g = \\a ->
+            a
+        h = 3
+    in
+        y + z
+const A = case 1 of
+    _ -> 2
+f 1
+const B = if true
+    1
+else
+    2
+- 1
+const C = \\a ->
+    a
+(1)
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.is_empty());
+        assert!(s == "(func f (x int) int
+  (let ((y (case x (0 1) (_ 2))) (z (if (> x 0) (then 1) (else 2))) (g (lambda (a) a)) (h 3))
+    (return (+ y z))))
+(const A (case 1 (_ 2)))
+(f 1)
+(const B (if true (then 1) (else 2)))
+(- 1)
+(const C (lambda (a) a))
+1
+");
+    }
+
+    #[test]
+    fn test_recover_at_declaration_boundaries() {
+        let src = "
+const A = 1 +
+const B = 2
+
+struct Vector = x y: float
+
+multiply x, y: int -> int
+    x * y
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.len() == 2);
+        assert!(errors[0].msg == "Expected expression, found end of line");
+        assert!(errors[1].msg == "Expected ':', found name 'y'");
+        assert!(s == "(error)
+(const B 2)
+(error)
+(func multiply (x int) (y int) int
+  (return (* x y)))
+");
+    }
+
+    #[test]
+    fn test_recover_at_dedent() {
+        let src = "
+fact_rec n: int -> int
+    if n == 0
+        1 +
+    else
+        n * fact_rec n-1
+
+double x: int -> int
+    x *
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.len() == 2);
+        assert!(s == "(func fact_rec (n int) int
+  (if (== n 0)
+    (then
+      (return (error)))
+    (else
+      (return (* n (fact_rec (- n 1)))))))
+(func double (x int) int
+  (return (error)))
+");
+    }
+
+    #[test]
+    fn test_recover_inside_declaration_blocks() {
+        let src = "
+struct Rect =
+    pos: Vector
+    size Vector
+    angle: float
+
+type Shape =
+    Circle: float
+    Square: 1
+    Triangle
+
+add_one x: int -> int
+    let
+        y: int = 1
+        z: = 2
+    in
+    x + y
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.len() == 3);
+        assert!(s == "(struct Rect (pos Vector) (angle float))
+(type Shape (Circle float) Triangle)
+(func add_one (x int) int
+  (let ((y int 1))
+    (return (+ x y))))
+");
+    }
+
+    #[test]
+    fn test_recover_from_unexpected_indentation() {
+        let src = "
+const A = 1
+    const B = 2
+        nested
+const C = 3
+";
+        let (s, errors) = parse_with_errors(src);
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Unexpected indentation at top level");
+        assert!(s == "(const A 1)\n(const C 3)\n");
+    }
+
+    #[test]
+    fn test_recover_from_inconsistent_dedent() {
+        // Every block kind, with a line dedented between two levels: the line
+        // stays in the inner block and the parse goes on.
+        let cases = [
+            ("struct S =\n    a: int\n   b: int\n", "(struct S (a int) (b int))\n"),
+            ("type T =\n    A\n  | B\n", "(type T A B)\n"),
+            ("export =\n    a,\n  b\n", "(export a b)\n"),
+            ("instance Show int =\n    show x: int -> string\n        \"\"\n   show2 x: int -> string = \"\"\n",
+             "(instance Show int\n  (func show (x int) string\n    (return \"\"))\n  (func show2 (x int) string\n    (return \"\")))\n"),
+            ("f x: int -> int\n    let\n        y = 1\n       z = 2\n    in y\n", "(func f (x int) int\n  (let ((y 1) (z 2))\n    (return y)))\n"),
+            ("f x: int -> int\n    case x of\n        0 -> 1\n       _ -> 2\n", "(func f (x int) int\n  (return (case x (0 1) (_ 2))))\n"),
+        ];
+        for (src, expected) in cases.iter() {
+            let (s, errors) = parse_with_errors(src);
+            println!("{}{:?}", s, errors);
+            assert!(errors.len() == 1);
+            assert!(errors[0].msg == "Inconsistent dedent");
+            assert!(s == *expected);
+        }
+        // Blocks left open also end at the end of the file.
+        let mut interner = Interner::new();
+        let mut src = "struct S =\n    a: int\n    b int\n";
+        let (mut tokens, _) = tokenize(&mut src);
+        tokens.retain(|t| t.token_kind != TokenKind::DEDENT);
+        let mut parser = Parser::new(tokens, &mut interner);
+        let module = parser.parse_module();
+        assert!(module.decls.len() == 1 && parser.errors.len() == 1);
+    }
+
+    #[test]
+    fn test_recover_from_unclosed_bracket() {
+        let src = "
+f x: int -> int
+    g (x + 1
+
+h x: int -> int = [x
+k x: int -> int = x
+const C = {1,
+2}
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.len() == 2);
+        assert!(errors[0].msg == "Expected ')', found end of line");
+        assert!(s == "(func f (x int) int
+  (return (error)))
+(error)
+(func k (x int) int
+  (return x))
+(const C (compound _ 1 2))
+");
+    }
+
+    #[test]
+    fn test_struct_literals() {
+        test_expr!("Vector {1.0, 2.0}", "(compound Vector 1.0 2.0)");
+        test_expr!("Rect {pos = {x = a, y = b}, size = s}", "(compound Rect (pos (compound _ (x a) (y b))) (size s))");
+        test_expr!("Rect {{min.x, min.y}, {max.x - min.x, max.y - min.y}}",
+            "(compound Rect (compound _ (field min x) (field min y)) (compound _ (- (field max x) (field min x)) (- (field max y) (field min y))))");
+        test_expr!("make_vect {a, b} {c, d}", "(make_vect (compound _ a b) (compound _ c d))");
+        test_expr!("Vector {\n    x = 1,\n    y = 2,\n}", "(compound Vector (x 1) (y 2))");
+        test_expr!("{}", "(compound _)");
+    }
+
+    #[test]
+    fn test_struct_literal_mixed_fields() {
+        let mut interner = Interner::new();
+        let err = parse_expr_str("Vector {x = 1, 2}", &mut interner).unwrap_err();
+        assert!(err.msg == "Cannot mix named and positional fields in a struct literal");
+        assert!(err.span == Span::new(15, 16));
+    }
+
+    #[test]
+    fn test_case_expression() {
+        let src = "
+describe k: TokenKind -> string
+    case k of
+        FLOAT -> \"float\"
+        OTHER 'a' -> \"a\"
+        OTHER c -> \"other\"
+        _ -> \"int\"
+
+sum l: List -> int
+    case l of
+        Cons x (Cons y _) -> x + y
+        Cons x Nil ->
+            x
+        Nil -> -1
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.is_empty());
+        assert!(s == "(func describe (k TokenKind) string
+  (return (case k (FLOAT \"float\") ((OTHER 'a') \"a\") ((OTHER c) \"other\") (_ \"int\"))))
+(func sum (l List) int
+  (return (case l ((Cons x (Cons y _)) (+ x y)) ((Cons x Nil) x) (Nil (- 1)))))
+");
+    }
+
+    #[test]
+    fn test_case_arm_recovery() {
+        let src = "
+f k: TokenKind -> int
+    case k of
+        FLOAT 1
+        INT -> 2
+";
+        let (s, errors) = parse_with_errors(src);
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Expected '->' after pattern, found end of line");
+        assert!(s == "(func f (k TokenKind) int\n  (return (case k (INT 2))))\n");
+    }
+
+    #[test]
+    fn test_destructuring_patterns() {
+        let src = "
+make_vect {a, b} {c, d}: Vector -> Vector
+    {c - a, d - b}
+
+swap (x, y): (int, float) -> (float, int) = (y, x)
+
+area r: Rect -> float
+    let
+        Rect {size = {w, h}} = r
+        (s, _) = (1.0, 2)
+    in
+    w * h * s
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.is_empty());
+        assert!(s == "(func make_vect ((compound _ a b) Vector) ((compound _ c d) Vector) Vector
+  (return (compound _ (- c a) (- d b))))
+(func swap ((tuple x y) (tuple int float)) (tuple float int)
+  (return (tuple y x)))
+(func area (r Rect) float
+  (let (((compound Rect (size (compound _ w h))) r) ((tuple s _) (tuple 1.0 2)))
+    (return (* (* w h) s))))
+");
+        let (_, errors) = parse_with_errors("f {x = a, b} = a\n");
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Cannot mix named and positional fields in a struct pattern");
+    }
+
+    #[test]
+    fn test_instance_declaration() {
+        let src = "
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    neg {x, y}: Vector -> Vector
+        {-x, -y}
+f = 1
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.is_empty());
+        assert!(s == "(instance Num Vector
+  (func add a b
+    (return (compound Vector (+ (field a x) (field b x)) (+ (field a y) (field b y)))))
+  (func neg ((compound _ x y) Vector) Vector
+    (return (compound _ (- x) (- y)))))
+(func f
+  (return 1))
+");
+        let (_, errors) = parse_with_errors("instance Eq Vector = eq a, b = true\n");
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Expected an indented block of methods, found name 'eq'");
+    }
+
+    #[test]
+    fn test_generic_declarations() {
+        let src = "
+type List a = Nil | Cons: a, List a
+
+struct Pair a b =
+    first: a
+    second: Maybe (List b)
+
+map f: (a -> b) xs: List a n: int -> List b = Nil
+";
+        let (s, errors) = parse_with_errors(src);
+        println!("{}", s);
+        assert!(errors.is_empty());
+        assert!(s == "(type (List a) Nil (Cons a (List a)))
+(struct (Pair a b) (first a) (second (Maybe (List b))))
+(func map (f (-> a b)) (xs (List a)) (n int) (List b)
+  (return Nil))
+");
+        let (_, errors) = parse_with_errors("type Box A = Box: A\n");
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Expected a lowercase type parameter, found name 'A'");
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+
+use crate::common::{Interner, Symbol};
+
+// Pattern matching analysis on a simplified pattern language: every pattern is
+// either a wildcard (possibly binding a name) or a constructor applied to
+// sub-patterns. Literals are constructors of types with infinitely many values.
+//
+// Exhaustiveness and redundancy use the usefulness algorithm from Maranget's
+// "Warnings for pattern matching"; lowering follows "Compiling pattern matching
+// to good decision trees".
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ctor {
+    // Variant number `index` of sum type `sum`.
+    Variant(Symbol, usize),
+    // Product types have a single constructor holding every field.
+    Tuple(usize),
+    Struct(Symbol, usize),
+    Bool(bool),
+    Int(i64),
+    Char(char),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pat {
+    Wild(Option<Symbol>),
+    Ctor(Ctor, Vec<Pat>),
+}
+
+pub struct VariantInfo {
+    pub name: Symbol,
+    pub arity: usize,
+}
+
+// Variants of every sum type in scope, in declaration order.
+pub type SumEnv = HashMap<Symbol, Vec<VariantInfo>>;
+
+// Path from the scrutinee to a sub-value: each step selects a constructor field.
+pub type Occurrence = Vec<usize>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Fail,
+    Leaf {
+        arm: usize,
+        bindings: Vec<(Symbol, Occurrence)>,
+    },
+    // Tests the value at `occurrence`. For sum types this is a switch on the tag.
+    Switch {
+        occurrence: Occurrence,
+        cases: Vec<(Ctor, Decision)>,
+        default: Option<Box<Decision>>,
+    },
+}
+
+fn arity(ctor: &Ctor, env: &SumEnv) -> usize {
+    match ctor {
+        Ctor::Variant(sum, index) => env.get(sum).map(|v| v[*index].arity).unwrap_or(0),
+        Ctor::Tuple(n) | Ctor::Struct(_, n) => *n,
+        _ => 0,
+    }
+}
+
+fn wilds(n: usize) -> Vec<Pat> {
+    vec![Pat::Wild(None); n]
+}
+
+fn head_ctors(rows: &[Vec<Pat>]) -> Vec<Ctor> {
+    let mut ctors = vec![];
+    for row in rows {
+        if let Some(Pat::Ctor(ctor, _)) = row.first() {
+            if !ctors.contains(ctor) {
+                ctors.push(ctor.clone());
+            }
+        }
+    }
+    ctors
+}
+
+// All constructors of the column's type when `ctors` covers them, None when
+// some value of the type is not matched by any of them.
+fn complete_signature(ctors: &[Ctor], env: &SumEnv) -> Option<Vec<Ctor>> {
+    match ctors.first()? {
+        Ctor::Variant(sum, _) => {
+            let all: Vec<Ctor> = (0..env.get(sum).map(|v| v.len()).unwrap_or(0)).map(|i| Ctor::Variant(*sum, i)).collect();
+            if all.iter().all(|c| ctors.contains(c)) {
+                Some(all)
+            } else {
+                None
+            }
+        }
+        Ctor::Tuple(_) | Ctor::Struct(_, _) => Some(vec![ctors[0].clone()]),
+        Ctor::Bool(_) => {
+            if ctors.contains(&Ctor::Bool(true)) && ctors.contains(&Ctor::Bool(false)) {
+                Some(vec![Ctor::Bool(false), Ctor::Bool(true)])
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn specialize(rows: &[Vec<Pat>], ctor: &Ctor, env: &SumEnv) -> Vec<Vec<Pat>> {
+    let mut result = vec![];
+    for row in rows {
+        match &row[0] {
+            Pat::Ctor(c, args) if c == ctor => {
+                let mut new_row = args.clone();
+                new_row.extend_from_slice(&row[1..]);
+                result.push(new_row);
+            }
+            Pat::Ctor(_, _) => {}
+            Pat::Wild(_) => {
+                let mut new_row = wilds(arity(ctor, env));
+                new_row.extend_from_slice(&row[1..]);
+                result.push(new_row);
+            }
+        }
+    }
+    result
+}
+
+fn default_rows(rows: &[Vec<Pat>]) -> Vec<Vec<Pat>> {
+    rows.iter().filter(|row| matches!(row[0], Pat::Wild(_))).map(|row| row[1..].to_vec()).collect()
+}
+
+// Whether some value matched by `v` is not matched by any row.
+pub fn is_useful(rows: &[Vec<Pat>], v: &[Pat], env: &SumEnv) -> bool {
+    if v.is_empty() {
+        return rows.is_empty();
+    }
+    match &v[0] {
+        Pat::Ctor(ctor, args) => {
+            let mut new_v = args.clone();
+            new_v.extend_from_slice(&v[1..]);
+            is_useful(&specialize(rows, ctor, env), &new_v, env)
+        }
+        Pat::Wild(_) => match complete_signature(&head_ctors(rows), env) {
+            Some(all) => all.iter().any(|ctor| {
+                let mut new_v = wilds(arity(ctor, env));
+                new_v.extend_from_slice(&v[1..]);
+                is_useful(&specialize(rows, ctor, env), &new_v, env)
+            }),
+            None => is_useful(&default_rows(rows), &v[1..], env),
+        },
+    }
+}
+
+// A vector of `n` patterns matching a value that no row matches, if any.
+fn find_missing(rows: &[Vec<Pat>], n: usize, env: &SumEnv) -> Option<Vec<Pat>> {
+    if n == 0 {
+        return if rows.is_empty() { Some(vec![]) } else { None };
+    }
+    let ctors = head_ctors(rows);
+    if let Some(all) = complete_signature(&ctors, env) {
+        for ctor in all {
+            let a = arity(&ctor, env);
+            if let Some(mut witness) = find_missing(&specialize(rows, &ctor, env), a + n - 1, env) {
+                let rest = witness.split_off(a);
+                let mut result = vec![Pat::Ctor(ctor, witness)];
+                result.extend(rest);
+                return Some(result);
+            }
+        }
+        return None;
+    }
+    let rest = find_missing(&default_rows(rows), n - 1, env)?;
+    let head = match ctors.first() {
+        Some(Ctor::Variant(sum, _)) => {
+            let index = (0..env[sum].len()).find(|i| !ctors.contains(&Ctor::Variant(*sum, *i))).unwrap();
+            let ctor = Ctor::Variant(*sum, index);
+            let a = arity(&ctor, env);
+            Pat::Ctor(ctor, wilds(a))
+        }
+        Some(Ctor::Bool(b)) => Pat::Ctor(Ctor::Bool(!b), vec![]),
+        _ => Pat::Wild(None),
+    };
+    let mut result = vec![head];
+    result.extend(rest);
+    Some(result)
+}
+
+pub struct MatchCheck {
+    // A value no arm matches, when the match is not exhaustive.
+    pub missing: Option<Pat>,
+    // Arms that can never be selected.
+    pub redundant: Vec<usize>,
+}
+
+pub fn check_match(arms: &[Pat], env: &SumEnv) -> MatchCheck {
+    let mut rows: Vec<Vec<Pat>> = vec![];
+    let mut redundant = vec![];
+    for (i, arm) in arms.iter().enumerate() {
+        let row = vec![arm.clone()];
+        if !is_useful(&rows, &row, env) {
+            redundant.push(i);
+        }
+        rows.push(row);
+    }
+    let missing = find_missing(&rows, 1, env).map(|mut w| w.remove(0));
+    MatchCheck { missing, redundant }
+}
+
+struct Row {
+    pats: Vec<Pat>,
+    arm: usize,
+    bindings: Vec<(Symbol, Occurrence)>,
+}
+
+fn compile(mut rows: Vec<Row>, occurrences: Vec<Occurrence>, env: &SumEnv) -> Decision {
+    if rows.is_empty() {
+        return Decision::Fail;
+    }
+    let column = match rows[0].pats.iter().position(|p| matches!(p, Pat::Ctor(_, _))) {
+        Some(column) => column,
+        None => {
+            let row = rows.remove(0);
+            let mut bindings = row.bindings;
+            for (pat, occurrence) in row.pats.iter().zip(occurrences.iter()) {
+                if let Pat::Wild(Some(name)) = pat {
+                    bindings.push((*name, occurrence.clone()));
+                }
+            }
+            return Decision::Leaf { arm: row.arm, bindings };
+        }
+    };
+    // Move the tested column first.
+    for row in rows.iter_mut() {
+        let pat = row.pats.remove(column);
+        row.pats.insert(0, pat);
+    }
+    let mut occurrences = occurrences;
+    let occurrence = occurrences.remove(column);
+    let rest = occurrences;
+
+    let column_rows: Vec<Vec<Pat>> = rows.iter().map(|r| r.pats.clone()).collect();
+    let ctors = head_ctors(&column_rows);
+    let complete = complete_signature(&ctors, env).is_some();
+    let mut cases = vec![];
+    for ctor in &ctors {
+        let a = arity(ctor, env);
+        let mut sub_rows = vec![];
+        for row in &rows {
+            let mut bindings = row.bindings.clone();
+            let mut pats = match &row.pats[0] {
+                Pat::Ctor(c, args) if c == ctor => args.clone(),
+                Pat::Ctor(_, _) => continue,
+                Pat::Wild(name) => {
+                    if let Some(name) = name {
+                        bindings.push((*name, occurrence.clone()));
+                    }
+                    wilds(a)
+                }
+            };
+            pats.extend_from_slice(&row.pats[1..]);
+            sub_rows.push(Row { pats, arm: row.arm, bindings });
+        }
+        let mut sub_occurrences: Vec<Occurrence> = (0..a)
+            .map(|i| {
+                let mut o = occurrence.clone();
+                o.push(i);
+                o
+            })
+            .collect();
+        sub_occurrences.extend(rest.iter().cloned());
+        cases.push((ctor.clone(), compile(sub_rows, sub_occurrences, env)));
+    }
+    let default = if complete {
+        None
+    } else {
+        let mut default_rows = vec![];
+        for row in &rows {
+            if let Pat::Wild(name) = &row.pats[0] {
+                let mut bindings = row.bindings.clone();
+                if let Some(name) = name {
+                    bindings.push((*name, occurrence.clone()));
+                }
+                default_rows.push(Row {
+                    pats: row.pats[1..].to_vec(),
+                    arm: row.arm,
+                    bindings,
+                });
+            }
+        }
+        Some(Box::new(compile(default_rows, rest.clone(), env)))
+    };
+    Decision::Switch { occurrence, cases, default }
+}
+
+// Lowers the arms of a case expression to a decision tree that tests each
+// sub-value at most once.
+pub fn compile_match(arms: &[Pat], env: &SumEnv) -> Decision {
+    let rows = arms
+        .iter()
+        .enumerate()
+        .map(|(arm, pat)| Row {
+            pats: vec![pat.clone()],
+            arm,
+            bindings: vec![],
+        })
+        .collect();
+    compile(rows, vec![vec![]], env)
+}
+
+pub fn pat_to_string(pat: &Pat, env: &SumEnv, interner: &Interner) -> String {
+    match pat {
+        Pat::Wild(_) => String::from("_"),
+        Pat::Ctor(Ctor::Tuple(_), args) => {
+            let args: Vec<String> = args.iter().map(|a| pat_to_string(a, env, interner)).collect();
+            format!("({})", args.join(", "))
+        }
+        Pat::Ctor(Ctor::Struct(name, _), args) => {
+            let args: Vec<String> = args.iter().map(|a| pat_to_string(a, env, interner)).collect();
+            format!("{} {{{}}}", interner.get(*name), args.join(", "))
+        }
+        Pat::Ctor(ctor, args) => {
+            let name = match ctor {
+                Ctor::Variant(sum, index) => String::from(interner.get(env[sum][*index].name)),
+                Ctor::Tuple(_) | Ctor::Struct(_, _) => unreachable!(),
+                Ctor::Bool(val) => format!("{}", val),
+                Ctor::Int(val) => format!("{}", val),
+                Ctor::Char(val) => format!("{:?}", val),
+                Ctor::Str(val) => format!("{:?}", val),
+            };
+            let mut s = name;
+            for arg in args {
+                s.push(' ');
+                match arg {
+                    Pat::Ctor(Ctor::Variant(_, _), sub) if !sub.is_empty() => s.push_str(&format!("({})", pat_to_string(arg, env, interner))),
+                    _ => s.push_str(&pat_to_string(arg, env, interner)),
+                }
+            }
+            s
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_env(interner: &mut Interner) -> (SumEnv, Symbol) {
+        let list = interner.intern("List");
+        let mut env = SumEnv::new();
+        env.insert(list, vec![
+            VariantInfo { name: interner.intern("Nil"), arity: 0 },
+            VariantInfo { name: interner.intern("Cons"), arity: 2 },
+        ]);
+        (env, list)
+    }
+
+    fn nil(list: Symbol) -> Pat {
+        Pat::Ctor(Ctor::Variant(list, 0), vec![])
+    }
+
+    fn cons(list: Symbol, head: Pat, tail: Pat) -> Pat {
+        Pat::Ctor(Ctor::Variant(list, 1), vec![head, tail])
+    }
+
+    #[test]
+    fn test_exhaustive_list_match() {
+        let mut interner = Interner::new();
+        let (env, list) = list_env(&mut interner);
+        let arms = vec![nil(list), cons(list, Pat::Wild(None), Pat::Wild(None))];
+        let check = check_match(&arms, &env);
+        assert!(check.missing.is_none());
+        assert!(check.redundant.is_empty());
+    }
+
+    #[test]
+    fn test_missing_nested_pattern() {
+        let mut interner = Interner::new();
+        let (env, list) = list_env(&mut interner);
+        let arms = vec![nil(list), cons(list, Pat::Wild(None), nil(list))];
+        let check = check_match(&arms, &env);
+        let missing = check.missing.unwrap();
+        assert!(pat_to_string(&missing, &env, &interner) == "Cons _ (Cons _ _)");
+    }
+
+    #[test]
+    fn test_redundant_arms() {
+        let mut interner = Interner::new();
+        let (env, list) = list_env(&mut interner);
+        let check = check_match(&[Pat::Wild(None), nil(list)], &env);
+        assert!(check.redundant == vec![1]);
+        let one = Pat::Ctor(Ctor::Int(1), vec![]);
+        let check = check_match(&[one.clone(), one], &env);
+        assert!(check.redundant == vec![1]);
+        assert!(check.missing == Some(Pat::Wild(None)));
+    }
+
+    #[test]
+    fn test_bool_signature_is_complete() {
+        let env = SumEnv::new();
+        let t = Pat::Ctor(Ctor::Bool(true), vec![]);
+        let f = Pat::Ctor(Ctor::Bool(false), vec![]);
+        assert!(check_match(&[t.clone(), f], &env).missing.is_none());
+        assert!(check_match(&[t], &env).missing == Some(Pat::Ctor(Ctor::Bool(false), vec![])));
+    }
+
+    #[test]
+    fn test_compile_to_tag_switch() {
+        let mut interner = Interner::new();
+        let (env, list) = list_env(&mut interner);
+        let x = interner.intern("x");
+        let rest = interner.intern("rest");
+        let arms = vec![
+            cons(list, Pat::Wild(Some(x)), nil(list)),
+            cons(list, Pat::Wild(None), Pat::Wild(Some(rest))),
+            nil(list),
+        ];
+        let tree = compile_match(&arms, &env);
+        println!("{:?}", tree);
+        assert!(tree == Decision::Switch {
+            occurrence: vec![],
+            cases: vec![
+                (Ctor::Variant(list, 1), Decision::Switch {
+                    occurrence: vec![1],
+                    cases: vec![
+                        (Ctor::Variant(list, 0), Decision::Leaf { arm: 0, bindings: vec![(x, vec![0])] }),
+                    ],
+                    default: Some(Box::new(Decision::Leaf { arm: 1, bindings: vec![(rest, vec![1])] })),
+                }),
+                (Ctor::Variant(list, 0), Decision::Leaf { arm: 2, bindings: vec![] }),
+            ],
+            default: None,
+        });
+    }
+
+    #[test]
+    fn test_compile_literals_with_default() {
+        let env = SumEnv::new();
+        let n = Symbol(0);
+        let arms = vec![
+            Pat::Ctor(Ctor::Int(0), vec![]),
+            Pat::Wild(Some(n)),
+        ];
+        let tree = compile_match(&arms, &env);
+        assert!(tree == Decision::Switch {
+            occurrence: vec![],
+            cases: vec![(Ctor::Int(0), Decision::Leaf { arm: 0, bindings: vec![] })],
+            default: Some(Box::new(Decision::Leaf { arm: 1, bindings: vec![(n, vec![])] })),
+        });
+    }
+
+    #[test]
+    fn test_tuple_of_bools() {
+        let env = SumEnv::new();
+        let interner = Interner::new();
+        let b = |val| Pat::Ctor(Ctor::Bool(val), vec![]);
+        let pair = |l, r| Pat::Ctor(Ctor::Tuple(2), vec![l, r]);
+        let irrefutable = check_match(&[pair(Pat::Wild(None), Pat::Wild(None))], &env);
+        assert!(irrefutable.missing.is_none());
+        let check = check_match(&[pair(b(true), Pat::Wild(None)), pair(Pat::Wild(None), b(true))], &env);
+        let missing = check.missing.unwrap();
+        assert!(pat_to_string(&missing, &env, &interner) == "(false, false)");
+    }
+}
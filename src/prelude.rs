@@ -0,0 +1,102 @@
+// Source of the generic standard library, the `prelude` module of every
+// program that doesn't have its own.
+pub const PRELUDE: &str = include_str!("prelude.sp");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::DeclKind;
+    use crate::common::Interner;
+    use crate::parser::parse_module;
+    use crate::resolve::resolve_module;
+    use crate::typeck::check_module;
+    use crate::types::{type_to_string, Type};
+
+    fn check(src: &str) -> (Vec<String>, Vec<String>) {
+        let mut interner = Interner::new();
+        let src = format!("{}{}", PRELUDE, src);
+        let (module, errors) = parse_module(&src, &mut interner);
+        assert!(errors.is_empty());
+        let (res, errors) = resolve_module(&module, &interner);
+        assert!(errors.is_empty());
+        let (results, errors) = check_module(&module, &res, &interner);
+        let errors: Vec<String> = errors.iter().map(|e| e.msg.clone()).collect();
+        println!("{:?}", errors);
+        // Types of the constants, in order.
+        let mut types = vec![];
+        for decl in &module.decls {
+            if let DeclKind::Const(c) = &decl.kind {
+                let ty = results.node_types.get(&c.expr.id).cloned().unwrap_or(Type::Error);
+                types.push(type_to_string(&ty, &interner));
+            }
+        }
+        (types, errors)
+    }
+
+    #[test]
+    fn test_prelude_checks() {
+        let (types, errors) = check("");
+        assert!(errors.is_empty());
+        assert!(types.is_empty());
+    }
+
+    #[test]
+    fn test_instantiation() {
+        let (types, errors) = check("
+const numbers = Cons 1 (Cons 2 (Cons 3 Nil))
+const doubled = map (\\x -> x * 2) numbers
+const halves = map (\\x -> x / 2.0) (Cons 1.0 Nil)
+const names = map (\\x -> if x > 1 then \"big\" else \"small\") numbers
+const total = foldl (\\acc x -> acc + x) 0 numbers
+const first = head (filter (\\x -> x > 1) numbers)
+const pairs = zip numbers names
+const len = length (reverse pairs)
+const swapped = swap (Pair {'a', true})
+const nothing = map_maybe identity Nothing
+");
+        assert!(errors.is_empty());
+        println!("{:?}", types);
+        assert!(types[0] == "List int");
+        assert!(types[1] == "List int");
+        assert!(types[2] == "List float");
+        assert!(types[3] == "List string");
+        assert!(types[4] == "int");
+        assert!(types[5] == "Maybe int");
+        assert!(types[6] == "List (Pair int string)");
+        assert!(types[7] == "int");
+        assert!(types[8] == "Pair bool char");
+        assert!(types[9].starts_with("Maybe t"));
+    }
+
+    #[test]
+    fn test_generic_errors() {
+        let (_, errors) = check("
+const bad = Cons 1 (Cons 'a' Nil)
+
+wrong x: a -> b
+    x
+
+arity m: Maybe -> int
+    0
+
+bad_field p: Pair int -> int
+    p.first
+
+unknown xs: List int -> List int
+    let
+        ys: List c = xs
+    in
+    ys
+
+plus x: a -> a
+    x + x
+");
+        assert!(errors[0] == "Type 'Maybe' expects 1 type argument, found 0");
+        assert!(errors[1] == "Type 'Pair' expects 2 type arguments, found 1");
+        assert!(errors[2] == "Mismatched types: expected List int, found List char");
+        assert!(errors[3] == "Mismatched types: expected b, found a");
+        assert!(errors[4] == "Unknown type 'c'");
+        assert!(errors[5] == "Cannot apply '+' to type a");
+        assert!(errors.len() == 6);
+    }
+}
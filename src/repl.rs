@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Decl, DeclKind, NodeId};
+use crate::common::{Interner, Span};
+use crate::diagnostic::{has_errors, Diagnostic, Level};
+use crate::interp::{Interp, RuntimeError};
+use crate::modules::{check_program, load_program, read_file};
+use crate::parser::parse_module;
+use crate::types::{name_vars, type_to_string};
+
+// Interactive sessions of `sp repl`. Every entry is checked as the last
+// part of a program made of the definitions entered before it, then its
+// expressions are evaluated. A definition replaces an earlier one of the
+// same name, and only entries without errors are kept.
+
+// Path of the entries in diagnostics.
+pub const REPL_PATH: &str = "<repl>";
+
+pub struct Session {
+    search_paths: Vec<PathBuf>,
+    // Source of the definitions entered so far, in order, with the key a
+    // later definition replaces them by.
+    defs: Vec<(String, String)>,
+}
+
+pub struct Reply {
+    // `value : type` for every expression of the entry.
+    pub output: String,
+    // Diagnostics with the path and source they point into.
+    pub diags: Vec<(Diagnostic, PathBuf, String)>,
+}
+
+// Whether an entry can be evaluated, or more lines should be read: its last
+// line is inside an indented block, which an empty line ends, or the parse
+// stopped at the end of the input, such as after an operator or an opening
+// bracket.
+pub fn is_complete(entry: &str, interner: &mut Interner) -> bool {
+    let last = entry.trim_end_matches('\n').rsplit('\n').next().unwrap_or("");
+    if last.starts_with(char::is_whitespace) && !last.trim().is_empty() {
+        return false;
+    }
+    let (_, errors) = parse_module(entry, interner);
+    let end = entry.trim_end().len();
+    !errors.iter().any(|err| err.span.start >= end)
+}
+
+fn decl_key(decl: &Decl, src: &str, interner: &Interner) -> Option<String> {
+    match &decl.kind {
+        DeclKind::Const(c) => Some(String::from(interner.get(c.name.name))),
+        DeclKind::Type(t) => Some(String::from(interner.get(t.name.name))),
+        DeclKind::Struct(s) => Some(String::from(interner.get(s.name.name))),
+        DeclKind::Func(f) => Some(String::from(interner.get(f.name.name))),
+        DeclKind::Instance(i) => Some(format!(
+            "instance {} {}",
+            interner.get(i.trait_name.name),
+            &src[i.ty.span.start..i.ty.span.end]
+        )),
+        DeclKind::Import(i) => {
+            let path: Vec<&str> = i.path.iter().map(|p| interner.get(p.name)).collect();
+            Some(format!("import {}", path.join("::")))
+        }
+        DeclKind::Export(_) => Some(String::from("export")),
+        DeclKind::Expr(_) | DeclKind::Error => None,
+    }
+}
+
+// The whole lines a declaration is written on.
+fn decl_lines(src: &str, span: Span) -> String {
+    let start = src[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let end = src[span.end..].find('\n').map(|i| span.end + i).unwrap_or(src.len());
+    format!("{}\n", &src[start..end])
+}
+
+impl Session {
+    pub fn new(search_paths: Vec<PathBuf>) -> Session {
+        Session {
+            search_paths,
+            defs: vec![],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.defs.clear();
+    }
+
+    pub fn eval(&mut self, entry: &str, interner: &mut Interner) -> Reply {
+        let mut reply = Reply {
+            output: String::new(),
+            diags: vec![],
+        };
+        let (module, errors) = parse_module(entry, interner);
+        if has_errors(&errors) {
+            reply.diags = errors.into_iter().map(|d| (d, PathBuf::from(REPL_PATH), String::from(entry))).collect();
+            return reply;
+        }
+        let mut new_defs = vec![];
+        for decl in &module.decls {
+            if let Some(key) = decl_key(decl, entry, interner) {
+                new_defs.push((key, decl_lines(entry, decl.span)));
+            }
+        }
+        let kept: Vec<(String, String)> =
+            self.defs.iter().filter(|(key, _)| !new_defs.iter().any(|(k, _)| k == key)).cloned().collect();
+        let mut src: String = kept.iter().map(|(_, def)| def.as_str()).collect();
+        let offset = src.len();
+        src.push_str(entry);
+
+        let graph = load_program(Path::new("repl.sp"), src.clone(), &self.search_paths, &read_file, interner);
+        let checked = check_program(&graph, interner);
+        // Diagnostics of the entry point into it, the ones of earlier
+        // definitions into the whole session.
+        let locate = |diag: &Diagnostic| {
+            if diag.span.start >= offset {
+                let span = Span::new(diag.span.start - offset, diag.span.end - offset);
+                (Diagnostic { span, ..diag.clone() }, PathBuf::from(REPL_PATH), String::from(entry))
+            } else {
+                (diag.clone(), PathBuf::from(REPL_PATH), src.clone())
+            }
+        };
+        let mut failed = false;
+        for (i, module) in checked.iter().enumerate() {
+            let loaded = &graph.modules[i];
+            for diag in &module.errors {
+                if i != graph.root.0 {
+                    reply.diags.push((diag.clone(), loaded.path.clone(), loaded.src.clone()));
+                } else if diag.level == Level::Error || diag.span.start >= offset {
+                    reply.diags.push(locate(diag));
+                }
+            }
+            failed |= has_errors(&module.errors);
+        }
+        if failed {
+            return reply;
+        }
+        self.defs = kept;
+        self.defs.extend(new_defs);
+
+        let root = &graph.modules[graph.root.0];
+        let mut types: HashMap<NodeId, String> = HashMap::new();
+        for decl in &root.ast.decls {
+            if let DeclKind::Expr(expr) = &decl.kind {
+                let ty = name_vars(&checked[graph.root.0].types.node_types[&expr.id], &mut vec![], interner);
+                types.insert(expr.id, type_to_string(&ty, interner));
+            }
+        }
+        let mut output = String::new();
+        let result = Interp::new(&graph, &checked, interner).eval_statements(&mut |expr, text| {
+            output.push_str(&format!("{} : {}\n", text, types[&expr.id]));
+            Ok(())
+        });
+        reply.output = output;
+        if let Err(RuntimeError { module, diag }) = result {
+            if module == graph.root {
+                reply.diags.push(locate(&diag));
+            } else {
+                let loaded = graph.module(module);
+                reply.diags.push((diag, loaded.path.clone(), loaded.src.clone()));
+            }
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        let mut interner = Interner::new();
+        let cases = [
+            ("1 + 2\n", true),
+            ("1 +\n", false),
+            ("1 +\n2\n", true),
+            ("1 +\n    2\n", false),
+            ("(1,\n", false),
+            ("g (f (x,\n", false),
+            ("f (x\n", false),
+            ("1 + )\n", true),
+            ("fact n: int -> int\n", false),
+            ("fact n: int -> int =\n", false),
+            ("fact n: int -> int\n    n * 2\n", false),
+            ("let x = 1\n", false),
+            ("let x = 1 in x\n", true),
+            ("if true then\n", false),
+            ("struct Vector =\n", false),
+            ("type Shape = Circle float | Square float\n", true),
+            ("double x: int -> int = x * 2\n", true),
+        ];
+        for (entry, complete) in cases.iter() {
+            println!("{:?}", entry);
+            assert!(is_complete(entry, &mut interner) == *complete);
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let mut interner = Interner::new();
+        let mut session = Session::new(vec![]);
+        let mut eval = |entry: &str| {
+            let reply = session.eval(entry, &mut interner);
+            let diags: Vec<String> = reply
+                .diags
+                .iter()
+                .map(|(d, path, src)| crate::diagnostic::render(d, &path.display().to_string(), src))
+                .collect();
+            println!("{}{}", reply.output, diags.concat());
+            (reply.output, diags.concat())
+        };
+        assert!(eval("1 + 2\n").0 == "3 : int\n");
+        // A line ending with an operator continues on the next one.
+        assert!(eval("1 +\n2\n").0 == "3 : int\n");
+        assert!(eval("1 +\n    2 *\n  3\n").0 == "7 : int\n");
+        assert!(eval("double x: int -> int = x * 2\n").0.is_empty());
+        assert!(eval("double 21\ndouble\n").0 == "42 : int\n<function> : int -> int\n");
+        // Replaced by a later definition.
+        assert!(eval("double x: int -> int = x + x + 1\n").0.is_empty());
+        assert!(eval("double 1\n").0 == "3 : int\n");
+        // Entries with errors are not kept.
+        let (out, err) = eval("triple x: int -> int = x * \"3\"\n");
+        assert!(out.is_empty() && err.contains("<repl>:1:"));
+        assert!(eval("triple 1\n").1.contains("error"));
+        assert!(eval("\\x -> x\n").0.ends_with(" : a -> a\n"));
+        let (out, err) = eval("double 2\n1 / 0\n");
+        assert!(out == "5 : int\n" && err.contains("<repl>:2:1"));
+        // Deep recursion runs out of calls, not of the REPL's stack.
+        eval("count n: int -> int = if n == 0 then 0 else 1 + count (n - 1)\n");
+        assert!(eval("count 9999\n").0 == "9999 : int\n");
+        assert!(eval("count 20000\n").1.contains("Stack overflow"));
+    }
+}
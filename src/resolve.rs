@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::diagnostic::Diagnostic;
+use crate::modules::{ImportTarget, ModuleId};
+
+// Binds every name used in an expression or pattern to the definition it
+// refers to. Top level names are visible in the whole module regardless of
+// order; parameters, let bindings and case arm bindings open nested scopes
+// that shadow the outer ones.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DefId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DefKind {
+    Const,
+    Func,
+    Variant,
+    Import,
+    // `import foo`, only usable as the head of a path.
+    Module,
+    Param,
+    Local,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Def {
+    pub kind: DefKind,
+    pub name: Ident,
+}
+
+#[derive(Debug, Default)]
+pub struct Resolutions {
+    pub defs: Vec<Def>,
+    // Definition referred to by a Name expression, a constructor pattern or
+    // introduced by a binding pattern, keyed by the node id.
+    pub names: HashMap<NodeId, DefId>,
+    // Top level values of the module.
+    pub module: HashMap<Symbol, DefId>,
+    // Module and member name of every imported value, including the ones
+    // reached through a path.
+    pub imports: HashMap<DefId, (ModuleId, Symbol)>,
+    pub modules: HashMap<DefId, ModuleId>,
+    // Types imported by name, with the module defining them.
+    pub imported_types: HashMap<Symbol, ModuleId>,
+    pub exports: Exports,
+}
+
+// Names a module makes visible to its importers. Exporting a sum type
+// exports its variants too.
+#[derive(Debug, Clone, Default)]
+pub struct Exports {
+    pub values: HashMap<Symbol, DefKind>,
+    // Exported struct and sum types, with the variants of the sum types.
+    pub types: HashMap<Symbol, Vec<Symbol>>,
+}
+
+// What the imports of a module refer to, given by the module loader.
+#[derive(Clone, Copy)]
+pub struct ModuleEnv<'a> {
+    pub imports: &'a HashMap<NodeId, ImportTarget>,
+    pub exports: &'a HashMap<ModuleId, Exports>,
+    pub names: &'a [String],
+}
+
+impl Resolutions {
+    pub fn def(&self, id: DefId) -> &Def {
+        &self.defs[id.0 as usize]
+    }
+}
+
+type Scope = HashMap<Symbol, DefId>;
+
+pub struct Resolver<'a> {
+    interner: &'a Interner,
+    env: Option<ModuleEnv<'a>>,
+    // Struct and sum types of the module, with the variants of the sum types.
+    types: HashMap<Symbol, Vec<Symbol>>,
+    scopes: Vec<Scope>,
+    pub res: Resolutions,
+    pub errors: Vec<Diagnostic>,
+}
+
+// Levenshtein distance, counted in chars.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(interner: &'a Interner) -> Resolver<'a> {
+        Resolver {
+            interner,
+            env: None,
+            types: HashMap::new(),
+            scopes: vec![Scope::new()],
+            res: Resolutions::default(),
+            errors: vec![],
+        }
+    }
+
+    fn name(&self, sym: Symbol) -> &'a str {
+        self.interner.get(sym)
+    }
+
+    fn new_def(&mut self, kind: DefKind, name: Ident) -> DefId {
+        let id = DefId(self.res.defs.len() as u32);
+        self.res.defs.push(Def { kind, name });
+        id
+    }
+
+    fn lookup(&self, name: Symbol) -> Option<DefId> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(&name).copied())
+    }
+
+    // The visible name closest to `name`, if one is close enough to be a typo.
+    fn suggest(&self, name: Symbol) -> Option<&'a str> {
+        let target = self.name(name);
+        let max = (target.chars().count() / 3).max(1);
+        let mut best: Option<(usize, &'a str)> = None;
+        for scope in &self.scopes {
+            for sym in scope.keys() {
+                let candidate = self.name(*sym);
+                let dist = edit_distance(target, candidate);
+                if dist > max {
+                    continue;
+                }
+                let better = match best {
+                    Some((best_dist, best_name)) => (dist, candidate) < (best_dist, best_name),
+                    None => true,
+                };
+                if better {
+                    best = Some((dist, candidate));
+                }
+            }
+        }
+        best.map(|(_, name)| name)
+    }
+
+    pub fn with_env(interner: &'a Interner, env: ModuleEnv<'a>) -> Resolver<'a> {
+        Resolver { env: Some(env), ..Resolver::new(interner) }
+    }
+
+    fn define_top(&mut self, kind: DefKind, name: Ident) -> Option<DefId> {
+        if self.scopes[0].contains_key(&name.name) {
+            // Clashing variants are reported by the type checker with their types.
+            if kind != DefKind::Variant {
+                self.errors.push(Diagnostic::error(name.span, format!("Duplicate definition of '{}'", self.name(name.name))));
+            }
+            return None;
+        }
+        let id = self.new_def(kind, name);
+        self.scopes[0].insert(name.name, id);
+        self.res.module.insert(name.name, id);
+        Some(id)
+    }
+
+    fn collect(&mut self, module: &Module) {
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Const(c) => {
+                    self.define_top(DefKind::Const, c.name);
+                }
+                DeclKind::Func(func) => {
+                    self.define_top(DefKind::Func, func.name);
+                }
+                DeclKind::Type(t) => {
+                    for variant in &t.variants {
+                        self.define_top(DefKind::Variant, variant.name);
+                    }
+                    self.types.insert(t.name.name, t.variants.iter().map(|v| v.name.name).collect());
+                }
+                DeclKind::Struct(st) => {
+                    self.types.insert(st.name.name, vec![]);
+                }
+                DeclKind::Import(import) => self.collect_import(decl.id, import),
+                _ => {}
+            }
+        }
+    }
+
+    // Without a module environment imports are placeholders for whatever
+    // they name.
+    fn collect_import(&mut self, id: NodeId, import: &ImportDecl) {
+        let name = match import.path.last() {
+            Some(name) => *name,
+            None => return,
+        };
+        let env = match self.env {
+            Some(env) => env,
+            None => {
+                self.define_top(DefKind::Import, name);
+                return;
+            }
+        };
+        // Modules that failed to load were reported by the loader.
+        let (module, exports) = match env.imports.get(&id) {
+            Some(ImportTarget::Module(module)) => {
+                if let Some(def) = self.define_top(DefKind::Module, name) {
+                    self.res.modules.insert(def, *module);
+                }
+                return;
+            }
+            Some(ImportTarget::Member(module)) => match env.exports.get(module) {
+                Some(exports) => (*module, exports),
+                None => return,
+            },
+            None => return,
+        };
+        let variants = exports.types.get(&name.name).cloned();
+        let exported = exports.values.contains_key(&name.name);
+        if !exported && variants.is_none() {
+            let msg = format!("Module '{}' does not export '{}'", env.names[module.0], self.name(name.name));
+            self.errors.push(Diagnostic::error(name.span, msg));
+            return;
+        }
+        if exported {
+            if let Some(def) = self.define_top(DefKind::Import, name) {
+                self.res.imports.insert(def, (module, name.name));
+            }
+        }
+        // Importing a sum type brings its variants along.
+        if let Some(variants) = variants {
+            self.res.imported_types.insert(name.name, module);
+            for variant in variants {
+                if let Some(def) = self.define_top(DefKind::Import, Ident { name: variant, span: name.span }) {
+                    self.res.imports.insert(def, (module, variant));
+                }
+            }
+        }
+    }
+
+    fn resolve_export(&mut self, export: &ExportDecl) {
+        for name in &export.names {
+            if let Some(variants) = self.types.get(&name.name) {
+                for variant in variants {
+                    self.res.exports.values.insert(*variant, DefKind::Variant);
+                }
+                self.res.exports.types.insert(name.name, variants.clone());
+                continue;
+            }
+            match self.lookup(name.name).map(|id| self.res.def(id).kind) {
+                Some(DefKind::Import) | Some(DefKind::Module) => {
+                    self.errors.push(Diagnostic::error(
+                        name.span,
+                        format!("Cannot re-export '{}', it is imported from another module", self.name(name.name)),
+                    ));
+                }
+                Some(kind) => {
+                    self.res.exports.values.insert(name.name, kind);
+                }
+                None => self.unknown(name.name, name.span),
+            }
+        }
+    }
+
+    // `foo::greet` refers to a member of the module imported as `foo`.
+    fn resolve_path(&mut self, expr: &Expr, module: Ident, member: Ident) {
+        let def = match self.lookup(module.name) {
+            Some(def) => def,
+            None => return self.unknown(module.name, module.span),
+        };
+        let id = match (self.res.def(def).kind, self.res.modules.get(&def)) {
+            (DefKind::Module, Some(id)) => *id,
+            // A module that failed to load, already reported.
+            (DefKind::Module, None) => return,
+            // Placeholder imports, without a module environment.
+            (DefKind::Import, _) if self.env.is_none() => return,
+            _ => {
+                self.errors.push(Diagnostic::error(module.span, format!("'{}' is not a module", self.name(module.name))));
+                return;
+            }
+        };
+        let env = self.env.unwrap();
+        let exported = env.exports.get(&id).is_some_and(|e| e.values.contains_key(&member.name));
+        if !exported {
+            let msg = format!("Module '{}' does not export '{}'", env.names[id.0], self.name(member.name));
+            self.errors.push(Diagnostic::error(member.span, msg));
+            return;
+        }
+        let def = self.new_def(DefKind::Import, member);
+        self.res.imports.insert(def, (id, member.name));
+        self.res.names.insert(expr.id, def);
+    }
+
+    pub fn resolve_module(&mut self, module: &Module) {
+        self.collect(module);
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Const(c) => self.resolve_expr(&c.expr),
+                DeclKind::Func(func) => self.resolve_func(func),
+                // Methods are only reached through operators, not by name.
+                DeclKind::Instance(inst) => {
+                    for method in &inst.methods {
+                        self.resolve_func(method);
+                    }
+                }
+                DeclKind::Export(export) => self.resolve_export(export),
+                DeclKind::Expr(expr) => self.resolve_expr(expr),
+                _ => {}
+            }
+        }
+    }
+
+    fn resolve_func(&mut self, func: &FuncDecl) {
+        self.scopes.push(Scope::new());
+        let mut bound = vec![];
+        for param in &func.params {
+            self.bind_pattern(&param.pattern, DefKind::Param, &mut bound);
+        }
+        self.resolve_expr(&func.body);
+        self.scopes.pop();
+    }
+
+    fn unknown(&mut self, name: Symbol, span: Span) {
+        let mut err = Diagnostic::error(span, format!("Unknown name '{}'", self.name(name)));
+        if let Some(suggestion) = self.suggest(name) {
+            err = err.with_note(format!("did you mean '{}'?", suggestion));
+        }
+        self.errors.push(err);
+    }
+
+    // Defines the names bound by `pattern` in the innermost scope. `bound`
+    // holds the names already bound by the same group of patterns, such as
+    // a parameter list, which may not repeat.
+    fn bind_pattern(&mut self, pattern: &Pattern, kind: DefKind, bound: &mut Vec<Symbol>) {
+        match &pattern.kind {
+            PatternKind::Binding(name) => {
+                if bound.contains(name) {
+                    self.errors.push(Diagnostic::error(
+                        pattern.span,
+                        format!("Identifier '{}' is bound more than once", self.name(*name)),
+                    ));
+                    return;
+                }
+                bound.push(*name);
+                let id = self.new_def(kind, Ident { name: *name, span: pattern.span });
+                self.scopes.last_mut().unwrap().insert(*name, id);
+                self.res.names.insert(pattern.id, id);
+            }
+            PatternKind::Variant(name, args) => {
+                // Unknown constructors are reported by the type checker.
+                if let Some(id) = self.lookup(name.name) {
+                    self.res.names.insert(pattern.id, id);
+                }
+                for arg in args {
+                    self.bind_pattern(arg, kind, bound);
+                }
+            }
+            PatternKind::Tuple(elems) => {
+                for elem in elems {
+                    self.bind_pattern(elem, kind, bound);
+                }
+            }
+            PatternKind::Struct(_, fields) => {
+                for field in fields {
+                    self.bind_pattern(&field.pattern, kind, bound);
+                }
+            }
+            PatternKind::Wildcard | PatternKind::Int(_) | PatternKind::Char(_) | PatternKind::Str(_)
+            | PatternKind::Bool(_) => {}
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Name(name) => match self.lookup(*name) {
+                Some(id) if self.res.def(id).kind == DefKind::Module => {
+                    self.errors.push(
+                        Diagnostic::error(expr.span, format!("Module '{}' is not a value", self.name(*name)))
+                            .with_note(format!("use one of its members, e.g. `{}::name`", self.name(*name))),
+                    );
+                }
+                Some(id) => {
+                    self.res.names.insert(expr.id, id);
+                }
+                None => self.unknown(*name, expr.span),
+            },
+            ExprKind::Path(module, member) => self.resolve_path(expr, *module, *member),
+            ExprKind::Field(e, _) | ExprKind::Unary(_, e) => self.resolve_expr(e),
+            ExprKind::Binary(_, l, r) => {
+                self.resolve_expr(l);
+                self.resolve_expr(r);
+            }
+            ExprKind::Call(func, args) => {
+                self.resolve_expr(func);
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            ExprKind::If(cond, then_expr, else_expr) => {
+                self.resolve_expr(cond);
+                self.resolve_expr(then_expr);
+                self.resolve_expr(else_expr);
+            }
+            // Each binding sees the ones before it but not itself.
+            ExprKind::Let(bindings, body) => {
+                let depth = self.scopes.len();
+                for binding in bindings {
+                    self.resolve_expr(&binding.expr);
+                    self.scopes.push(Scope::new());
+                    self.bind_pattern(&binding.pattern, DefKind::Local, &mut vec![]);
+                }
+                self.resolve_expr(body);
+                self.scopes.truncate(depth);
+            }
+            ExprKind::Lambda(params, body) => {
+                self.scopes.push(Scope::new());
+                let mut bound = vec![];
+                for param in params {
+                    self.bind_pattern(param, DefKind::Param, &mut bound);
+                }
+                self.resolve_expr(body);
+                self.scopes.pop();
+            }
+            ExprKind::Case(scrutinee, arms) => {
+                self.resolve_expr(scrutinee);
+                for arm in arms {
+                    self.scopes.push(Scope::new());
+                    self.bind_pattern(&arm.pattern, DefKind::Local, &mut vec![]);
+                    self.resolve_expr(&arm.body);
+                    self.scopes.pop();
+                }
+            }
+            ExprKind::StructLit(lit) => {
+                for field in &lit.fields {
+                    self.resolve_expr(&field.expr);
+                }
+            }
+            ExprKind::Tuple(elems) => {
+                for elem in elems {
+                    self.resolve_expr(elem);
+                }
+            }
+            ExprKind::Template(parts) => {
+                for part in parts {
+                    if let TemplatePart::Expr(e) = part {
+                        self.resolve_expr(e);
+                    }
+                }
+            }
+            ExprKind::Int(_) | ExprKind::Float(_) | ExprKind::Char(_) | ExprKind::Str(_)
+            | ExprKind::Bool(_) | ExprKind::Error => {}
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn resolve_module(module: &Module, interner: &Interner) -> (Resolutions, Vec<Diagnostic>) {
+    let mut resolver = Resolver::new(interner);
+    resolver.resolve_module(module);
+    (resolver.res, resolver.errors)
+}
+
+pub fn resolve_module_with(module: &Module, interner: &Interner, env: ModuleEnv) -> (Resolutions, Vec<Diagnostic>) {
+    let mut resolver = Resolver::with_env(interner, env);
+    resolver.resolve_module(module);
+    (resolver.res, resolver.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_module;
+
+    fn resolve(src: &str) -> (Module, Resolutions, Vec<Diagnostic>, Interner) {
+        let mut interner = Interner::new();
+        let (module, errors) = parse_module(src, &mut interner);
+        assert!(errors.is_empty());
+        let (res, errors) = resolve_module(&module, &interner);
+        for err in &errors {
+            println!("{:?}", err);
+        }
+        (module, res, errors, interner)
+    }
+
+    // Finds the Name expressions and binding patterns in a function body, in
+    // source order, with the definition each one resolved to.
+    fn names_in(expr: &Expr, res: &Resolutions, out: &mut Vec<(Span, Option<DefId>)>) {
+        match &expr.kind {
+            ExprKind::Name(_) => out.push((expr.span, res.names.get(&expr.id).copied())),
+            ExprKind::Binary(_, l, r) => {
+                names_in(l, res, out);
+                names_in(r, res, out);
+            }
+            ExprKind::Call(f, args) => {
+                names_in(f, res, out);
+                for arg in args {
+                    names_in(arg, res, out);
+                }
+            }
+            ExprKind::Let(bindings, body) => {
+                for binding in bindings {
+                    names_in(&binding.expr, res, out);
+                }
+                names_in(body, res, out);
+            }
+            ExprKind::If(c, t, e) => {
+                names_in(c, res, out);
+                names_in(t, res, out);
+                names_in(e, res, out);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert!(edit_distance("fact_rec", "fact_rec") == 0);
+        assert!(edit_distance("fact_rek", "fact_rec") == 1);
+        assert!(edit_distance("greet", "gret") == 1);
+        assert!(edit_distance("", "abc") == 3);
+        assert!(edit_distance("kitten", "sitting") == 3);
+    }
+
+    #[test]
+    fn test_resolve_params_and_globals() {
+        let src = "
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+import greet
+
+main = greet \"Silver pancake\"
+";
+        let (module, res, errors, interner) = resolve(src);
+        assert!(errors.is_empty());
+        let fact = match &module.decls[0].kind {
+            DeclKind::Func(func) => func,
+            _ => unreachable!(),
+        };
+        let mut names = vec![];
+        names_in(&fact.body, &res, &mut names);
+        let n = res.names[&fact.params[0].pattern.id];
+        let fact_id = res.module[&interner.lookup("fact_rec").unwrap()];
+        let ids: Vec<DefId> = names.iter().map(|(_, id)| id.unwrap()).collect();
+        assert!(ids == vec![n, n, fact_id, n]);
+        assert!(res.def(n).kind == DefKind::Param);
+        let greet = res.module[&interner.lookup("greet").unwrap()];
+        assert!(res.def(greet).kind == DefKind::Import);
+    }
+
+    #[test]
+    fn test_let_scoping_and_shadowing() {
+        let src = "
+f x: int -> int
+    let
+        y = x
+        x = y + 1
+        z = z
+    in
+    x + y
+";
+        let (module, res, errors, _) = resolve(src);
+        assert!(errors.len() == 1);
+        assert!(errors[0].msg == "Unknown name 'z'");
+        let (param, bindings, body) = match &module.decls[0].kind {
+            DeclKind::Func(func) => match &func.body.kind {
+                ExprKind::Let(bindings, body) => (res.names[&func.params[0].pattern.id], bindings, body),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        let y = res.names[&bindings[0].pattern.id];
+        let x = res.names[&bindings[1].pattern.id];
+        let mut names = vec![];
+        names_in(&bindings[0].expr, &res, &mut names);
+        names_in(&bindings[1].expr, &res, &mut names);
+        names_in(body, &res, &mut names);
+        let ids: Vec<DefId> = names.iter().map(|(_, id)| id.unwrap()).collect();
+        assert!(ids == vec![param, y, x, y]);
+    }
+
+    #[test]
+    fn test_case_and_pattern_bindings() {
+        let src = "
+type Shape = Circle: float | Pair: int, int
+
+area s: Shape -> int
+    case s of
+        Circle r -> r
+        Pair a a -> a + r
+
+swap (x, x): (int, int) -> int = x
+";
+        let (_, _, errors, _) = resolve(src);
+        assert!(errors.len() == 3);
+        assert!(errors[0].msg == "Identifier 'a' is bound more than once");
+        assert!(errors[1].msg == "Unknown name 'r'");
+        assert!(errors[2].msg == "Identifier 'x' is bound more than once");
+    }
+
+    #[test]
+    fn test_unknown_names_with_suggestions() {
+        let src = "
+fact_rec n: int -> int = n
+
+multiply x, y: int -> int = x * yy
+
+main = fact_rek 3 + multipyl 1 2 + unrelated
+main = 1
+
+export =
+    fact_rec
+    greet
+";
+        let (_, _, errors, _) = resolve(src);
+        assert!(errors.len() == 6);
+        assert!(errors[0].msg == "Duplicate definition of 'main'");
+        assert!(errors[1].msg == "Unknown name 'yy'");
+        assert!(errors[1].notes == vec![String::from("did you mean 'y'?")]);
+        assert!(errors[2].msg == "Unknown name 'fact_rek'");
+        assert!(errors[2].notes == vec![String::from("did you mean 'fact_rec'?")]);
+        assert!(errors[3].msg == "Unknown name 'multipyl'");
+        assert!(errors[3].notes == vec![String::from("did you mean 'multiply'?")]);
+        assert!(errors[4].msg == "Unknown name 'unrelated'");
+        assert!(errors[4].notes.is_empty());
+        assert!(errors[5].msg == "Unknown name 'greet'");
+    }
+}
@@ -0,0 +1,579 @@
+/* Runtime of programs compiled to C. Values are a kind tag and a payload;
+   tuples, structs and variants are heap objects whose fields follow a
+   common header, so generated code can use the C struct of a user type
+   while the runtime walks the fields of any object. Nothing is freed. */
+
+#include <inttypes.h>
+#include <math.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define SP_MAX_DEPTH 10000
+
+/* Programs only use part of the runtime. */
+#ifdef __GNUC__
+#define SP_UNUSED __attribute__((unused))
+#else
+#define SP_UNUSED
+#endif
+
+enum { SP_INT, SP_FLOAT, SP_CHAR, SP_STR, SP_BOOL, SP_OBJ, SP_FN };
+
+typedef struct sp_str sp_str;
+typedef struct sp_obj sp_obj;
+typedef struct sp_closure sp_closure;
+
+typedef struct sp_value {
+    uint8_t kind;
+    union {
+        int64_t i;
+        double f;
+        uint32_t c;
+        bool b;
+        sp_str *s;
+        sp_obj *o;
+        sp_closure *fn;
+    } as;
+} sp_value;
+
+struct sp_str {
+    size_t len;
+    char data[];
+};
+
+/* Type index, variant index and field count. */
+#define SP_OBJ_HEADER uint32_t type, tag, len;
+#define SP_TUPLE UINT32_MAX
+
+struct sp_obj {
+    SP_OBJ_HEADER
+    sp_value fields[];
+};
+
+typedef sp_value (*sp_code)(sp_value *env, sp_value *args);
+
+/* Captured values then the arguments of a partial application. */
+struct sp_closure {
+    sp_code code;
+    uint32_t arity, ncaptured, nargs;
+    sp_value items[];
+};
+
+enum { SP_ADD, SP_SUB, SP_MUL, SP_DIV, SP_NEG, SP_EQ, SP_LT, SP_SHOW, SP_METHODS };
+
+typedef struct sp_type {
+    const char *name;
+    bool is_struct;
+    uint32_t nctors;
+    const char *const *ctors;
+    const char *const *fields;
+    /* Instance methods, NULL where the type has none. */
+    sp_code methods[SP_METHODS];
+} sp_type;
+
+static const sp_type *sp_types;
+static int sp_depth;
+
+static SP_UNUSED void sp_panic(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "error: %s\n", msg);
+    exit(1);
+}
+
+static SP_UNUSED void *sp_alloc(size_t size) {
+    void *p = malloc(size);
+    if (!p) {
+        sp_panic("Out of memory");
+    }
+    return p;
+}
+
+static SP_UNUSED void sp_enter(void) {
+    if (++sp_depth > SP_MAX_DEPTH) {
+        sp_panic("Stack overflow: too many nested calls");
+    }
+}
+
+static SP_UNUSED sp_value sp_int(int64_t i) {
+    sp_value v;
+    v.kind = SP_INT;
+    v.as.i = i;
+    return v;
+}
+
+/* Ends a function when no case arm matches: never returns, the value only
+   keeps the C compiler quiet. */
+static SP_UNUSED sp_value sp_match_fail(void) {
+    sp_panic("No case arm matches the value");
+    return sp_int(0);
+}
+
+static SP_UNUSED sp_value sp_float(double f) {
+    sp_value v;
+    v.kind = SP_FLOAT;
+    v.as.f = f;
+    return v;
+}
+
+static SP_UNUSED sp_value sp_char(uint32_t c) {
+    sp_value v;
+    v.kind = SP_CHAR;
+    v.as.c = c;
+    return v;
+}
+
+static SP_UNUSED sp_value sp_bool(bool b) {
+    sp_value v;
+    v.kind = SP_BOOL;
+    v.as.b = b;
+    return v;
+}
+
+static SP_UNUSED sp_value sp_str_new(const char *data, size_t len) {
+    sp_value v;
+    v.kind = SP_STR;
+    v.as.s = sp_alloc(sizeof(sp_str) + len + 1);
+    v.as.s->len = len;
+    memcpy(v.as.s->data, data, len);
+    v.as.s->data[len] = 0;
+    return v;
+}
+
+static SP_UNUSED sp_value sp_obj_new(uint32_t type, uint32_t tag, uint32_t len) {
+    sp_value v;
+    v.kind = SP_OBJ;
+    v.as.o = sp_alloc(sizeof(sp_obj) + len * sizeof(sp_value));
+    v.as.o->type = type;
+    v.as.o->tag = tag;
+    v.as.o->len = len;
+    return v;
+}
+
+static SP_UNUSED sp_value sp_tuple(uint32_t len, const sp_value *items) {
+    sp_value v = sp_obj_new(SP_TUPLE, 0, len);
+    memcpy(v.as.o->fields, items, len * sizeof(sp_value));
+    return v;
+}
+
+static SP_UNUSED sp_value sp_closure_new(sp_code code, uint32_t arity, uint32_t ncaptured, const sp_value *captured) {
+    sp_value v;
+    v.kind = SP_FN;
+    v.as.fn = sp_alloc(sizeof(sp_closure) + ncaptured * sizeof(sp_value));
+    v.as.fn->code = code;
+    v.as.fn->arity = arity;
+    v.as.fn->ncaptured = ncaptured;
+    v.as.fn->nargs = 0;
+    memcpy(v.as.fn->items, captured, ncaptured * sizeof(sp_value));
+    return v;
+}
+
+/* Calls with too few arguments build a partial application, the extra ones
+   go to the returned function. */
+static SP_UNUSED sp_value sp_apply(sp_value f, uint32_t argc, const sp_value *argv) {
+    for (;;) {
+        sp_closure *fn = f.as.fn;
+        uint32_t have = fn->nargs + argc;
+        if (have < fn->arity) {
+            sp_value v;
+            uint32_t n = fn->ncaptured + have;
+            v.kind = SP_FN;
+            v.as.fn = sp_alloc(sizeof(sp_closure) + n * sizeof(sp_value));
+            *v.as.fn = *fn;
+            v.as.fn->nargs = have;
+            memcpy(v.as.fn->items, fn->items, (fn->ncaptured + fn->nargs) * sizeof(sp_value));
+            memcpy(v.as.fn->items + fn->ncaptured + fn->nargs, argv, argc * sizeof(sp_value));
+            return v;
+        }
+        uint32_t used = fn->arity - fn->nargs;
+        sp_value *args = sp_alloc((fn->arity + 1) * sizeof(sp_value));
+        memcpy(args, fn->items + fn->ncaptured, fn->nargs * sizeof(sp_value));
+        memcpy(args + fn->nargs, argv, used * sizeof(sp_value));
+        sp_value result = fn->code(fn->items, args);
+        free(args);
+        if (used == argc) {
+            return result;
+        }
+        f = result;
+        argc -= used;
+        argv += used;
+    }
+}
+
+static SP_UNUSED sp_code sp_method(sp_value v, int method) {
+    if (v.kind != SP_OBJ || v.as.o->type == SP_TUPLE) {
+        return NULL;
+    }
+    return sp_types[v.as.o->type].methods[method];
+}
+
+static SP_UNUSED sp_value sp_call_method(sp_code code, sp_value a, sp_value b) {
+    sp_value args[2];
+    args[0] = a;
+    args[1] = b;
+    return code(NULL, args);
+}
+
+static SP_UNUSED sp_value sp_neg(sp_value a) {
+    sp_code method = sp_method(a, SP_NEG);
+    if (method) {
+        return sp_call_method(method, a, a);
+    }
+    if (a.kind == SP_FLOAT) {
+        return sp_float(-a.as.f);
+    }
+    if (a.as.i == INT64_MIN) {
+        sp_panic("Integer overflow");
+    }
+    return sp_int(-a.as.i);
+}
+
+static SP_UNUSED sp_value sp_arith(int method, sp_value a, sp_value b) {
+    sp_code code = sp_method(a, method);
+    if (code) {
+        return sp_call_method(code, a, b);
+    }
+    if (a.kind == SP_FLOAT) {
+        switch (method) {
+        case SP_ADD: return sp_float(a.as.f + b.as.f);
+        case SP_SUB: return sp_float(a.as.f - b.as.f);
+        case SP_MUL: return sp_float(a.as.f * b.as.f);
+        default:
+            if (b.as.f == 0.0) {
+                sp_panic("Division by zero");
+            }
+            return sp_float(a.as.f / b.as.f);
+        }
+    }
+    int64_t x = a.as.i, y = b.as.i;
+    switch (method) {
+    case SP_ADD:
+        if ((y > 0 && x > INT64_MAX - y) || (y < 0 && x < INT64_MIN - y)) {
+            sp_panic("Integer overflow");
+        }
+        return sp_int(x + y);
+    case SP_SUB:
+        if ((y < 0 && x > INT64_MAX + y) || (y > 0 && x < INT64_MIN + y)) {
+            sp_panic("Integer overflow");
+        }
+        return sp_int(x - y);
+    case SP_MUL:
+        if (x > 0 ? (y > 0 ? x > INT64_MAX / y : y < INT64_MIN / x)
+                  : (y > 0 ? x < INT64_MIN / y : x != 0 && y < INT64_MAX / x)) {
+            sp_panic("Integer overflow");
+        }
+        return sp_int(x * y);
+    default:
+        if (y == 0) {
+            sp_panic("Division by zero");
+        }
+        if (x == INT64_MIN && y == -1) {
+            sp_panic("Integer overflow");
+        }
+        return sp_int(x / y);
+    }
+}
+
+static SP_UNUSED sp_value sp_add(sp_value a, sp_value b) { return sp_arith(SP_ADD, a, b); }
+static SP_UNUSED sp_value sp_sub(sp_value a, sp_value b) { return sp_arith(SP_SUB, a, b); }
+static SP_UNUSED sp_value sp_mul(sp_value a, sp_value b) { return sp_arith(SP_MUL, a, b); }
+static SP_UNUSED sp_value sp_div(sp_value a, sp_value b) { return sp_arith(SP_DIV, a, b); }
+
+static SP_UNUSED sp_value sp_mod(sp_value a, sp_value b) {
+    if (a.kind == SP_FLOAT) {
+        if (b.as.f == 0.0) {
+            sp_panic("Division by zero");
+        }
+        return sp_float(fmod(a.as.f, b.as.f));
+    }
+    if (b.as.i == 0) {
+        sp_panic("Division by zero");
+    }
+    if (a.as.i == INT64_MIN && b.as.i == -1) {
+        sp_panic("Integer overflow");
+    }
+    return sp_int(a.as.i % b.as.i);
+}
+
+static SP_UNUSED sp_value sp_shift(sp_value a, sp_value b, bool left) {
+    if (b.as.i < 0 || b.as.i >= 64) {
+        sp_panic("Integer overflow");
+    }
+    if (left) {
+        return sp_int((int64_t)((uint64_t)a.as.i << b.as.i));
+    }
+    return sp_int(a.as.i >> b.as.i);
+}
+
+/* Types without an Eq instance compare field by field. */
+static SP_UNUSED bool sp_eq(sp_value a, sp_value b) {
+    switch (a.kind) {
+    case SP_INT: return a.as.i == b.as.i;
+    case SP_FLOAT: return a.as.f == b.as.f;
+    case SP_CHAR: return a.as.c == b.as.c;
+    case SP_BOOL: return a.as.b == b.as.b;
+    case SP_STR: return a.as.s->len == b.as.s->len && memcmp(a.as.s->data, b.as.s->data, a.as.s->len) == 0;
+    case SP_OBJ: {
+        sp_code method = sp_method(a, SP_EQ);
+        if (method) {
+            return sp_call_method(method, a, b).as.b;
+        }
+        if (a.as.o->tag != b.as.o->tag) {
+            return false;
+        }
+        for (uint32_t i = 0; i < a.as.o->len; i++) {
+            if (!sp_eq(a.as.o->fields[i], b.as.o->fields[i])) {
+                return false;
+            }
+        }
+        return true;
+    }
+    default: return false;
+    }
+}
+
+/* Tuples compare lexicographically. */
+static SP_UNUSED bool sp_lt(sp_value a, sp_value b) {
+    switch (a.kind) {
+    case SP_INT: return a.as.i < b.as.i;
+    case SP_FLOAT: return a.as.f < b.as.f;
+    case SP_CHAR: return a.as.c < b.as.c;
+    case SP_BOOL: return a.as.b < b.as.b;
+    case SP_STR: {
+        size_t n = a.as.s->len < b.as.s->len ? a.as.s->len : b.as.s->len;
+        int cmp = memcmp(a.as.s->data, b.as.s->data, n);
+        return cmp < 0 || (cmp == 0 && a.as.s->len < b.as.s->len);
+    }
+    case SP_OBJ: {
+        sp_code method = sp_method(a, SP_LT);
+        if (method) {
+            return sp_call_method(method, a, b).as.b;
+        }
+        for (uint32_t i = 0; i < a.as.o->len; i++) {
+            if (sp_lt(a.as.o->fields[i], b.as.o->fields[i])) {
+                return true;
+            }
+            if (sp_lt(b.as.o->fields[i], a.as.o->fields[i])) {
+                return false;
+            }
+        }
+        return false;
+    }
+    default: return false;
+    }
+}
+
+/* Growable buffer for building strings. */
+typedef struct sp_buf {
+    char *data;
+    size_t len, cap;
+} sp_buf;
+
+static SP_UNUSED void sp_buf_add(sp_buf *buf, const char *data, size_t len) {
+    if (buf->len + len + 1 > buf->cap) {
+        buf->cap = (buf->len + len + 1) * 2;
+        char *data = sp_alloc(buf->cap);
+        if (buf->data) {
+            memcpy(data, buf->data, buf->len);
+            free(buf->data);
+        }
+        buf->data = data;
+    }
+    memcpy(buf->data + buf->len, data, len);
+    buf->len += len;
+}
+
+static SP_UNUSED void sp_buf_str(sp_buf *buf, const char *s) {
+    sp_buf_add(buf, s, strlen(s));
+}
+
+static SP_UNUSED sp_value sp_buf_finish(sp_buf *buf) {
+    sp_value v = sp_str_new(buf->data ? buf->data : "", buf->len);
+    free(buf->data);
+    return v;
+}
+
+/* Shortest digits that read back as the same float, formatted like the
+   compiler does: plain decimals from 1e-4 up to 1e16, exponents outside. */
+static SP_UNUSED void sp_show_float(sp_buf *buf, double f) {
+    char tmp[64], digits[32];
+    if (f != f) {
+        sp_buf_str(buf, "NaN");
+        return;
+    }
+    if (f == INFINITY || f == -INFINITY) {
+        sp_buf_str(buf, f < 0 ? "-inf" : "inf");
+        return;
+    }
+    if (signbit(f)) {
+        sp_buf_str(buf, "-");
+        f = -f;
+    }
+    if (f == 0.0) {
+        sp_buf_str(buf, "0.0");
+        return;
+    }
+    int precision = 0;
+    for (; precision < 17; precision++) {
+        snprintf(tmp, sizeof tmp, "%.*e", precision, f);
+        if (strtod(tmp, NULL) == f) {
+            break;
+        }
+    }
+    /* tmp is d.ddde[+-]x */
+    char *e = strchr(tmp, 'e');
+    int exp = atoi(e + 1);
+    size_t n = 0;
+    for (char *p = tmp; p < e; p++) {
+        if (*p != '.') {
+            digits[n++] = *p;
+        }
+    }
+    while (n > 1 && digits[n - 1] == '0') {
+        n--;
+    }
+    digits[n] = 0;
+    if (exp < -4 || exp >= 16) {
+        sp_buf_add(buf, digits, 1);
+        if (n > 1) {
+            sp_buf_str(buf, ".");
+            sp_buf_add(buf, digits + 1, n - 1);
+        }
+        snprintf(tmp, sizeof tmp, "e%d", exp);
+        sp_buf_str(buf, tmp);
+    } else if (exp < 0) {
+        sp_buf_str(buf, "0.");
+        for (int i = -1; i > exp; i--) {
+            sp_buf_str(buf, "0");
+        }
+        sp_buf_add(buf, digits, n);
+    } else {
+        for (int i = 0; i <= exp; i++) {
+            sp_buf_add(buf, (size_t)i < n ? digits + i : "0", 1);
+        }
+        sp_buf_str(buf, ".");
+        if ((size_t)exp + 1 < n) {
+            sp_buf_add(buf, digits + exp + 1, n - exp - 1);
+        } else {
+            sp_buf_str(buf, "0");
+        }
+    }
+}
+
+static SP_UNUSED void sp_show_char(sp_buf *buf, uint32_t c) {
+    char s[4];
+    size_t n;
+    if (c < 0x80) {
+        s[0] = (char)c;
+        n = 1;
+    } else if (c < 0x800) {
+        s[0] = (char)(0xc0 | (c >> 6));
+        s[1] = (char)(0x80 | (c & 0x3f));
+        n = 2;
+    } else if (c < 0x10000) {
+        s[0] = (char)(0xe0 | (c >> 12));
+        s[1] = (char)(0x80 | ((c >> 6) & 0x3f));
+        s[2] = (char)(0x80 | (c & 0x3f));
+        n = 3;
+    } else {
+        s[0] = (char)(0xf0 | (c >> 18));
+        s[1] = (char)(0x80 | ((c >> 12) & 0x3f));
+        s[2] = (char)(0x80 | ((c >> 6) & 0x3f));
+        s[3] = (char)(0x80 | (c & 0x3f));
+        n = 4;
+    }
+    sp_buf_add(buf, s, n);
+}
+
+static SP_UNUSED void sp_show_into(sp_buf *buf, sp_value v, bool nested);
+
+/* Values show as in string templates. User types without a Show instance
+   show as they would be written. */
+static SP_UNUSED sp_value sp_show(sp_value v) {
+    if (v.kind == SP_STR) {
+        return v;
+    }
+    sp_buf buf = {NULL, 0, 0};
+    sp_show_into(&buf, v, false);
+    return sp_buf_finish(&buf);
+}
+
+static SP_UNUSED void sp_show_into(sp_buf *buf, sp_value v, bool nested) {
+    char tmp[32];
+    switch (v.kind) {
+    case SP_INT:
+        snprintf(tmp, sizeof tmp, "%" PRId64, v.as.i);
+        sp_buf_str(buf, tmp);
+        return;
+    case SP_FLOAT: sp_show_float(buf, v.as.f); return;
+    case SP_CHAR: sp_show_char(buf, v.as.c); return;
+    case SP_STR: sp_buf_add(buf, v.as.s->data, v.as.s->len); return;
+    case SP_BOOL: sp_buf_str(buf, v.as.b ? "true" : "false"); return;
+    case SP_FN: sp_buf_str(buf, "<function>"); return;
+    }
+    sp_obj *o = v.as.o;
+    sp_code method = sp_method(v, SP_SHOW);
+    if (method) {
+        sp_value s = sp_call_method(method, v, v);
+        sp_buf_add(buf, s.as.s->data, s.as.s->len);
+        return;
+    }
+    if (o->type == SP_TUPLE) {
+        sp_buf_str(buf, "(");
+        for (uint32_t i = 0; i < o->len; i++) {
+            if (i > 0) {
+                sp_buf_str(buf, ", ");
+            }
+            sp_show_into(buf, o->fields[i], false);
+        }
+        sp_buf_str(buf, ")");
+        return;
+    }
+    const sp_type *type = &sp_types[o->type];
+    if (type->is_struct) {
+        sp_buf_str(buf, type->name);
+        sp_buf_str(buf, " {");
+        for (uint32_t i = 0; i < o->len; i++) {
+            if (i > 0) {
+                sp_buf_str(buf, ", ");
+            }
+            sp_buf_str(buf, type->fields[i]);
+            sp_buf_str(buf, " = ");
+            sp_show_into(buf, o->fields[i], true);
+        }
+        sp_buf_str(buf, "}");
+        return;
+    }
+    /* Constructor arguments are parenthesized when they have arguments too. */
+    if (nested && o->len > 0) {
+        sp_buf_str(buf, "(");
+    }
+    sp_buf_str(buf, type->ctors[o->tag]);
+    for (uint32_t i = 0; i < o->len; i++) {
+        sp_buf_str(buf, " ");
+        sp_show_into(buf, o->fields[i], true);
+    }
+    if (nested && o->len > 0) {
+        sp_buf_str(buf, ")");
+    }
+}
+
+static SP_UNUSED sp_value sp_concat(uint32_t n, const sp_value *parts) {
+    sp_buf buf = {NULL, 0, 0};
+    for (uint32_t i = 0; i < n; i++) {
+        sp_buf_add(&buf, parts[i].as.s->data, parts[i].as.s->len);
+    }
+    return sp_buf_finish(&buf);
+}
+
+static SP_UNUSED bool sp_str_is(sp_value v, const char *data, size_t len) {
+    return v.as.s->len == len && memcmp(v.as.s->data, data, len) == 0;
+}
+
+static SP_UNUSED void sp_print(sp_value v) {
+    sp_value s = sp_show(v);
+    fwrite(s.as.s->data, 1, s.as.s->len, stdout);
+    fputc('\n', stdout);
+}
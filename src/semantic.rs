@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::common::{Interner, Span};
+use crate::fmt::comments;
+use crate::lexer::{tokenize, TokenKind, TokenMod};
+use crate::lsp::{module_nodes, Analysis, Node};
+use crate::modules::ModuleId;
+use crate::resolve::{DefId, DefKind};
+use crate::types::builtin_type;
+
+// Semantic tokens of `sp lsp`, for highlighting. Literals, keywords and
+// comments come from the lexer, names are told apart by what they resolve
+// to and by where they're declared.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenType {
+    Namespace,
+    Type,
+    Struct,
+    Enum,
+    Interface,
+    TypeParameter,
+    Parameter,
+    Variable,
+    Property,
+    EnumMember,
+    Function,
+    Method,
+    Keyword,
+    Comment,
+    String,
+    Number,
+}
+
+// Legend of the token types, indexed by TokenType.
+pub const TOKEN_TYPES: [&str; 16] = [
+    "namespace",
+    "type",
+    "struct",
+    "enum",
+    "interface",
+    "typeParameter",
+    "parameter",
+    "variable",
+    "property",
+    "enumMember",
+    "function",
+    "method",
+    "keyword",
+    "comment",
+    "string",
+    "number",
+];
+
+// Legend of the modifiers, bit i of a token's modifiers is TOKEN_MODIFIERS[i].
+pub const TOKEN_MODIFIERS: [&str; 7] = [
+    "declaration",
+    "readonly",
+    "defaultLibrary",
+    "hexadecimal",
+    "binary",
+    "octal",
+    "character",
+];
+pub const DECLARATION: u32 = 1;
+pub const READONLY: u32 = 1 << 1;
+pub const DEFAULT_LIBRARY: u32 = 1 << 2;
+// The base of integer literals, from their TokenMod. Decimal has none.
+pub const HEXADECIMAL: u32 = 1 << 3;
+pub const BINARY: u32 = 1 << 4;
+pub const OCTAL: u32 = 1 << 5;
+pub const CHARACTER: u32 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SemanticToken {
+    pub span: Span,
+    pub ty: TokenType,
+    pub modifiers: u32,
+}
+
+struct Classifier<'a> {
+    analysis: &'a Analysis,
+    interner: &'a Interner,
+    // Type and modifiers of the names, by their start offset.
+    names: HashMap<usize, (TokenType, u32)>,
+    // Spans of the expressions interpolated in every template, by its start.
+    templates: HashMap<usize, Vec<Span>>,
+}
+
+impl<'a> Classifier<'a> {
+    fn name(&mut self, ident: &Ident, ty: TokenType, modifiers: u32) {
+        self.names.insert(ident.span.start, (ty, modifiers));
+    }
+
+    fn def_type(&self, module: ModuleId, def: DefId) -> (TokenType, u32) {
+        let (module, def) = self.analysis.target(module, def);
+        let res = &self.analysis.checked[module.0].res;
+        match res.def(def).kind {
+            DefKind::Func => (TokenType::Function, 0),
+            DefKind::Const => (TokenType::Variable, READONLY),
+            DefKind::Variant => (TokenType::EnumMember, 0),
+            DefKind::Module => (TokenType::Namespace, 0),
+            DefKind::Param => (TokenType::Parameter, 0),
+            DefKind::Local | DefKind::Import => (TokenType::Variable, 0),
+        }
+    }
+
+    // Struct or sum type named `name`, declared in the root or imported.
+    fn type_name(&self, name: &str) -> (TokenType, u32) {
+        if builtin_type(name).is_some() {
+            return (TokenType::Type, DEFAULT_LIBRARY);
+        }
+        if name.starts_with(|c: char| c.is_lowercase()) {
+            return (TokenType::TypeParameter, 0);
+        }
+        let root = self.analysis.root();
+        let imported = self
+            .interner
+            .lookup(name)
+            .and_then(|sym| self.analysis.checked[root.0].res.imported_types.get(&sym));
+        let module = imported.copied().unwrap_or(root);
+        for decl in &self.analysis.graph.module(module).ast.decls {
+            match &decl.kind {
+                DeclKind::Struct(s) if self.interner.get(s.name.name) == name => return (TokenType::Struct, 0),
+                DeclKind::Type(t) if self.interner.get(t.name.name) == name => return (TokenType::Enum, 0),
+                _ => {}
+            }
+        }
+        (TokenType::Type, 0)
+    }
+
+    fn type_expr(&mut self, ty: &TypeExpr) {
+        match &ty.kind {
+            TypeExprKind::Name(name) | TypeExprKind::App(name, _) => {
+                let (token_type, modifiers) = self.type_name(self.interner.get(*name));
+                self.names.insert(ty.span.start, (token_type, modifiers));
+                if let TypeExprKind::App(_, args) = &ty.kind {
+                    for arg in args {
+                        self.type_expr(arg);
+                    }
+                }
+            }
+            TypeExprKind::Func(param, ret) => {
+                self.type_expr(param);
+                self.type_expr(ret);
+            }
+            TypeExprKind::Tuple(elems) => {
+                for elem in elems {
+                    self.type_expr(elem);
+                }
+            }
+        }
+    }
+
+    fn type_params(&mut self, params: &[Ident]) {
+        for param in params {
+            self.name(param, TokenType::TypeParameter, DECLARATION);
+        }
+    }
+
+    fn func(&mut self, func: &FuncDecl, ty: TokenType) {
+        self.name(&func.name, ty, DECLARATION);
+        for param in &func.params {
+            if let Some(ty) = &param.ty {
+                self.type_expr(ty);
+            }
+        }
+        if let Some(ret) = &func.ret {
+            self.type_expr(ret);
+        }
+    }
+
+    fn decl(&mut self, decl: &Decl) {
+        let root = self.analysis.root();
+        let res = &self.analysis.checked[root.0].res;
+        match &decl.kind {
+            DeclKind::Func(func) => self.func(func, TokenType::Function),
+            DeclKind::Const(c) => {
+                self.name(&c.name, TokenType::Variable, DECLARATION | READONLY);
+                if let Some(ty) = &c.ty {
+                    self.type_expr(ty);
+                }
+            }
+            DeclKind::Type(t) => {
+                self.name(&t.name, TokenType::Enum, DECLARATION);
+                self.type_params(&t.params);
+                for variant in &t.variants {
+                    self.name(&variant.name, TokenType::EnumMember, DECLARATION);
+                    for field in &variant.fields {
+                        self.type_expr(field);
+                    }
+                }
+            }
+            DeclKind::Struct(s) => {
+                self.name(&s.name, TokenType::Struct, DECLARATION);
+                self.type_params(&s.params);
+                for field in &s.fields {
+                    self.name(&field.name, TokenType::Property, DECLARATION);
+                    self.type_expr(&field.ty);
+                }
+            }
+            DeclKind::Instance(inst) => {
+                self.name(&inst.trait_name, TokenType::Interface, DEFAULT_LIBRARY);
+                self.type_expr(&inst.ty);
+                for method in &inst.methods {
+                    self.func(method, TokenType::Method);
+                }
+            }
+            // The last name of an import is a module, a type or a value.
+            DeclKind::Import(import) => {
+                for (i, segment) in import.path.iter().enumerate() {
+                    let def = res.defs.iter().position(|def| def.name.span == segment.span);
+                    let class = if i + 1 < import.path.len() {
+                        (TokenType::Namespace, 0)
+                    } else if res.imported_types.contains_key(&segment.name) {
+                        self.type_name(self.interner.get(segment.name))
+                    } else {
+                        match def {
+                            Some(def) => self.def_type(root, DefId(def as u32)),
+                            None => (TokenType::Namespace, 0),
+                        }
+                    };
+                    self.names.insert(segment.span.start, class);
+                }
+            }
+            DeclKind::Export(export) => {
+                for name in &export.names {
+                    let class = match res.module.get(&name.name) {
+                        Some(def) => self.def_type(root, *def),
+                        None => self.type_name(self.interner.get(name.name)),
+                    };
+                    self.names.insert(name.span.start, class);
+                }
+            }
+            DeclKind::Expr(_) | DeclKind::Error => {}
+        }
+    }
+
+    fn node(&mut self, node: Node) {
+        let root = self.analysis.root();
+        let res = &self.analysis.checked[root.0].res;
+        let resolved = res.names.get(&node.id()).map(|def| self.def_type(root, *def));
+        match node {
+            Node::Expr(expr) => match &expr.kind {
+                ExprKind::Name(_) => {
+                    if let Some(class) = resolved {
+                        self.names.insert(expr.span.start, class);
+                    }
+                }
+                ExprKind::Path(module, member) => {
+                    self.name(module, TokenType::Namespace, 0);
+                    if let Some(class) = resolved {
+                        self.names.insert(member.span.start, class);
+                    }
+                }
+                ExprKind::Field(_, field) => self.name(field, TokenType::Property, 0),
+                ExprKind::StructLit(lit) => {
+                    if let Some(name) = &lit.name {
+                        let class = self.type_name(self.interner.get(name.name));
+                        self.names.insert(name.span.start, class);
+                    }
+                    for field in lit.fields.iter().filter_map(|f| f.name.as_ref()) {
+                        self.name(field, TokenType::Property, 0);
+                    }
+                }
+                ExprKind::Let(bindings, _) => {
+                    for ty in bindings.iter().filter_map(|b| b.ty.as_ref()) {
+                        self.type_expr(ty);
+                    }
+                }
+                ExprKind::Template(parts) => {
+                    let spans = parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            TemplatePart::Expr(e) => Some(e.span),
+                            TemplatePart::Str(_) => None,
+                        })
+                        .collect();
+                    self.templates.insert(expr.span.start, spans);
+                }
+                _ => {}
+            },
+            Node::Pattern(pattern) => match &pattern.kind {
+                PatternKind::Binding(_) => {
+                    if let Some((ty, modifiers)) = resolved {
+                        self.names.insert(pattern.span.start, (ty, modifiers | DECLARATION));
+                    }
+                }
+                PatternKind::Variant(name, _) => self.name(name, TokenType::EnumMember, 0),
+                PatternKind::Struct(name, fields) => {
+                    if let Some(name) = name {
+                        let class = self.type_name(self.interner.get(name.name));
+                        self.names.insert(name.span.start, class);
+                    }
+                    for field in fields.iter().filter_map(|f| f.name.as_ref()) {
+                        self.name(field, TokenType::Property, 0);
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    // Tokens of `src[offset..]`, the whole root or an interpolated
+    // expression.
+    fn tokens(&self, src: &str, offset: usize, tokens: &mut Vec<SemanticToken>) {
+        let mut stream = src;
+        for token in tokenize(&mut stream).0 {
+            let span = Span::new(token.span.start + offset, token.span.end + offset);
+            let mut push = |span, ty, modifiers| tokens.push(SemanticToken { span, ty, modifiers });
+            match token.token_kind {
+                TokenKind::KEYWORD => push(span, TokenType::Keyword, 0),
+                TokenKind::NAME => {
+                    if let Some((ty, modifiers)) = self.names.get(&span.start) {
+                        push(span, *ty, *modifiers);
+                    }
+                }
+                TokenKind::INT | TokenKind::FLOAT => {
+                    let modifiers = match token.token_mod {
+                        Some(TokenMod::TOKENMOD_HEX) => HEXADECIMAL,
+                        Some(TokenMod::TOKENMOD_BIN) => BINARY,
+                        Some(TokenMod::TOKENMOD_OCT) => OCTAL,
+                        _ => 0,
+                    };
+                    push(span, TokenType::Number, modifiers)
+                }
+                TokenKind::CHAR => push(span, TokenType::String, CHARACTER),
+                TokenKind::STR => push(span, TokenType::String, 0),
+                // The text around the interpolated expressions is string.
+                TokenKind::TEMPLATE => {
+                    let mut start = span.start;
+                    for inner in self.templates.get(&span.start).cloned().unwrap_or_default() {
+                        tokens.push(SemanticToken {
+                            span: Span::new(start, inner.start),
+                            ty: TokenType::String,
+                            modifiers: 0,
+                        });
+                        let root_src = self.analysis.src();
+                        self.tokens(&root_src[inner.start..inner.end], inner.start, tokens);
+                        start = inner.end;
+                    }
+                    tokens.push(SemanticToken {
+                        span: Span::new(start, span.end),
+                        ty: TokenType::String,
+                        modifiers: 0,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Tokens of the root document of `analysis`, in order.
+pub fn semantic_tokens(analysis: &Analysis, interner: &Interner) -> Vec<SemanticToken> {
+    let mut classifier = Classifier {
+        analysis,
+        interner,
+        names: HashMap::new(),
+        templates: HashMap::new(),
+    };
+    let ast = &analysis.graph.module(analysis.root()).ast;
+    for decl in &ast.decls {
+        classifier.decl(decl);
+    }
+    for node in module_nodes(ast) {
+        classifier.node(node);
+    }
+    let src = analysis.src();
+    let mut tokens = vec![];
+    classifier.tokens(src, 0, &mut tokens);
+    for span in comments(src) {
+        tokens.push(SemanticToken {
+            span,
+            ty: TokenType::Comment,
+            modifiers: 0,
+        });
+    }
+    tokens.retain(|token| token.span.start < token.span.end);
+    tokens.sort_by_key(|token| token.span.start);
+    tokens
+}
+
+// The protocol's encoding: five integers per token, its line and start
+// relative to the token before, its length, type and modifiers. Lengths and
+// starts are in UTF-16 code units, and tokens over several lines are split
+// into one per line.
+pub fn encode(src: &str, tokens: &[SemanticToken]) -> Vec<i64> {
+    let mut data = vec![];
+    let (mut prev_line, mut prev_start) = (0, 0);
+    let mut line = 0;
+    let mut line_start = 0;
+    let mut pos = 0;
+    for token in tokens {
+        let mut start = token.span.start;
+        while start < token.span.end {
+            for (i, c) in src[pos..start].char_indices() {
+                if c == '\n' {
+                    line += 1;
+                    line_start = pos + i + 1;
+                }
+            }
+            pos = start;
+            let end = src[start..token.span.end]
+                .find('\n')
+                .map_or(token.span.end, |i| start + i);
+            let character = src[line_start..start].encode_utf16().count() as i64;
+            let length = src[start..end].encode_utf16().count() as i64;
+            if length > 0 {
+                let delta_start = if line == prev_line {
+                    character - prev_start
+                } else {
+                    character
+                };
+                data.extend_from_slice(&[
+                    line - prev_line,
+                    delta_start,
+                    length,
+                    token.ty as i64,
+                    token.modifiers as i64,
+                ]);
+                prev_line = line;
+                prev_start = character;
+            }
+            start = if end < token.span.end { end + 1 } else { end };
+        }
+    }
+    data
+}
+
+// The single edit turning `old` into `new`: the start, the number of
+// integers deleted and the ones inserted, around the common prefix and
+// suffix. None when they're equal.
+pub fn token_edit(old: &[i64], new: &[i64]) -> Option<(usize, usize, Vec<i64>)> {
+    if old == new {
+        return None;
+    }
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old
+        .iter()
+        .rev()
+        .zip(new.iter().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    Some((
+        prefix,
+        old.len() - prefix - suffix,
+        new[prefix..new.len() - suffix].to_vec(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::render;
+    use crate::json::Json;
+    use crate::lsp::{path_uri, Server};
+    use crate::modules::{check_program, load_program, read_file};
+
+    const SRC: &str = "/* Shapes
+   and sizes */
+import lib
+
+struct Vector =
+    x, y: float
+
+type Shape =
+    Circle: float
+    Square: float
+
+const MASK = 0xff
+
+// Length of a vector
+len v: Vector -> float
+    v.x + v.y
+
+scale s: Shape, k: float -> Shape
+    case s of
+        Circle r -> Circle (r * k)
+        Square a -> Square (a * k)
+
+`mask ${MASK + 0b101} is ${lib::area 2.0}`
+len {x = 1.0, y = 2.0}
+0o17
+'c'
+";
+
+    // The program rooted at `src`, next to a `lib.sp` module on disk.
+    fn analyse(name: &str, src: &str, interner: &mut Interner) -> Analysis {
+        let dir = std::env::temp_dir().join(format!("sp_semantic_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("lib.sp"),
+            "area x: float -> float\n    x * x\n\nexport = area\n",
+        )
+        .unwrap();
+        let path = dir.join("main.sp");
+        let graph = load_program(&path, String::from(src), &[], &read_file, interner);
+        let checked = check_program(&graph, interner);
+        for err in &checked[graph.root.0].errors {
+            println!("{}", render(err, "main.sp", src));
+        }
+        Analysis {
+            uri: path_uri(&path),
+            graph,
+            checked,
+        }
+    }
+
+    // `text:type.modifier...` for every token.
+    fn show(src: &str, tokens: &[SemanticToken]) -> String {
+        let mut shown = vec![];
+        for token in tokens {
+            let mut s = format!(
+                "{}:{}",
+                &src[token.span.start..token.span.end],
+                TOKEN_TYPES[token.ty as usize]
+            );
+            for (i, modifier) in TOKEN_MODIFIERS.iter().enumerate() {
+                if token.modifiers & (1 << i) != 0 {
+                    s.push('.');
+                    s.push_str(modifier);
+                }
+            }
+            shown.push(s);
+        }
+        shown.join(" ")
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut interner = Interner::new();
+        let analysis = analyse("tokens", SRC, &mut interner);
+        assert!(analysis.checked[analysis.root().0].errors.is_empty());
+        let tokens = semantic_tokens(&analysis, &interner);
+        let shown = show(SRC, &tokens);
+        println!("{}", shown);
+        let expected = [
+            "/* Shapes\n   and sizes */:comment import:keyword lib:namespace",
+            "struct:keyword Vector:struct.declaration x:property.declaration y:property.declaration float:type.defaultLibrary",
+            "type:keyword Shape:enum.declaration Circle:enumMember.declaration float:type.defaultLibrary",
+            "Square:enumMember.declaration float:type.defaultLibrary",
+            "const:keyword MASK:variable.declaration.readonly 0xff:number.hexadecimal",
+            "// Length of a vector:comment",
+            "len:function.declaration v:parameter.declaration Vector:struct float:type.defaultLibrary",
+            "v:parameter x:property v:parameter y:property",
+            "scale:function.declaration s:parameter.declaration Shape:enum k:parameter.declaration",
+            "float:type.defaultLibrary Shape:enum case:keyword s:parameter of:keyword",
+            "Circle:enumMember r:variable.declaration Circle:enumMember r:variable k:parameter",
+            "Square:enumMember a:variable.declaration Square:enumMember a:variable k:parameter",
+            "`mask ${:string MASK:variable.readonly 0b101:number.binary } is ${:string lib:namespace area:function",
+            "2.0:number }`:string len:function x:property 1.0:number y:property 2.0:number 0o17:number.octal",
+            "'c':string.character",
+        ];
+        assert!(shown == expected.join(" "));
+    }
+
+    #[test]
+    fn test_encode() {
+        let src = "/* \u{e9}\n\u{1f600} */ f\n  x";
+        let tokens = [
+            SemanticToken {
+                span: Span::new(0, src.find(" f").unwrap()),
+                ty: TokenType::Comment,
+                modifiers: 0,
+            },
+            SemanticToken {
+                span: Span::new(src.find("f\n").unwrap(), src.find("f\n").unwrap() + 1),
+                ty: TokenType::Function,
+                modifiers: DECLARATION,
+            },
+            SemanticToken {
+                span: Span::new(src.len() - 1, src.len()),
+                ty: TokenType::Parameter,
+                modifiers: 0,
+            },
+        ];
+        // The comment is split at its line break, and 😀 is two UTF-16 units.
+        let (comment, function, parameter) =
+            (TokenType::Comment as i64, TokenType::Function as i64, TokenType::Parameter as i64);
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 4, comment, 0,
+            1, 0, 5, comment, 0,
+            0, 6, 1, function, DECLARATION as i64,
+            1, 2, 1, parameter, 0,
+        ];
+        assert!(encode(src, &tokens) == expected);
+
+        assert!(token_edit(&[1, 2, 3, 4], &[1, 2, 3, 4]).is_none());
+        assert!(token_edit(&[1, 2, 3, 4], &[1, 5, 6, 4]) == Some((1, 2, vec![5, 6])));
+        assert!(token_edit(&[1, 2, 3], &[1, 2, 3, 4, 5]) == Some((3, 0, vec![4, 5])));
+        assert!(token_edit(&[1, 1, 1], &[1, 1]) == Some((2, 1, vec![])));
+    }
+
+    #[test]
+    fn test_requests() {
+        let dir = std::env::temp_dir().join(format!("sp_semantic_{}_requests", std::process::id()));
+        let uri = path_uri(&dir.join("main.sp"));
+        let mut server = Server::new(vec![]);
+        let mut send = |msg: String| {
+            let replies = server.handle(&Json::parse(&msg).unwrap());
+            println!("{}", replies.last().unwrap());
+            replies.last().unwrap().clone()
+        };
+        let reply = send(String::from(
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        ));
+        let provider = reply
+            .get("result")
+            .and_then(|r| r.get("capabilities"))
+            .and_then(|c| c.get("semanticTokensProvider"));
+        let legend = provider.and_then(|p| p.get("legend")).unwrap();
+        assert!(legend.get("tokenTypes").and_then(Json::as_array).unwrap().len() == TOKEN_TYPES.len());
+
+        let text = |text: &str| Json::str(text);
+        send(format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","text":{}}}}}}}"#,
+            uri,
+            text("double x: int -> int\n    x * 2\n")
+        ));
+        let full = |id: i64, method: &str, previous: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":{},"method":"textDocument/semanticTokens/{}","params":{{"textDocument":{{"uri":"{}"}}{}}}}}"#,
+                id, method, uri, previous
+            )
+        };
+        let reply = send(full(2, "full", ""));
+        let result = reply.get("result").unwrap();
+        let data = result.get("data").and_then(Json::as_array).unwrap().to_vec();
+        assert!(data.len() == 6 * 5);
+        assert!(result.get("resultId") == Some(&Json::str("1")));
+
+        send(format!(
+            r#"{{"jsonrpc":"2.0","method":"textDocument/didChange","params":{{"textDocument":{{"uri":"{}"}},"contentChanges":[{{"text":{}}}]}}}}"#,
+            uri,
+            text("double x: int -> int\n    x * 0x2\n")
+        ));
+        let reply = send(full(3, "full/delta", r#","previousResultId":"1""#));
+        let result = reply.get("result").unwrap();
+        assert!(result.get("resultId") == Some(&Json::str("2")));
+        let edits = result.get("edits").and_then(Json::as_array).unwrap();
+        // Only the length and modifiers of the last token change.
+        assert!(edits.len() == 1);
+        let number = TokenType::Number as i64;
+        assert!(
+            edits[0].to_string()
+                == format!(
+                    r#"{{"start":27,"deleteCount":3,"data":[3,{},{}]}}"#,
+                    number, HEXADECIMAL
+                )
+        );
+
+        // No edits without changes, and whole tokens for an unknown result.
+        let reply = send(full(4, "full/delta", r#","previousResultId":"2""#));
+        assert!(reply.get("result").and_then(|r| r.get("edits")) == Some(&Json::Array(vec![])));
+        let reply = send(full(5, "full/delta", r#","previousResultId":"1""#));
+        assert!(
+            reply
+                .get("result")
+                .and_then(|r| r.get("data"))
+                .and_then(Json::as_array)
+                .map(|d| d.len())
+                == Some(30)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use crate::ast::*;
+use crate::common::Interner;
+
+// Printer for the AST S-expression format described in docs/syntax.md.
+
+pub fn type_to_sexpr(ty: &TypeExpr, interner: &Interner) -> String {
+    match &ty.kind {
+        TypeExprKind::Name(name) => String::from(interner.get(*name)),
+        TypeExprKind::App(name, args) => {
+            let args: Vec<String> = args.iter().map(|a| type_to_sexpr(a, interner)).collect();
+            format!("({} {})", interner.get(*name), args.join(" "))
+        }
+        TypeExprKind::Func(param, ret) => {
+            format!("(-> {} {})", type_to_sexpr(param, interner), type_to_sexpr(ret, interner))
+        }
+        TypeExprKind::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(|e| type_to_sexpr(e, interner)).collect();
+            format!("(tuple {})", elems.join(" "))
+        }
+    }
+}
+
+fn binding_to_sexpr(binding: &LetBinding, interner: &Interner) -> String {
+    match &binding.ty {
+        Some(ty) => format!(
+            "({} {} {})",
+            pattern_to_sexpr(&binding.pattern, interner),
+            type_to_sexpr(ty, interner),
+            expr_to_sexpr(&binding.expr, interner)
+        ),
+        None => format!("({} {})", pattern_to_sexpr(&binding.pattern, interner), expr_to_sexpr(&binding.expr, interner)),
+    }
+}
+
+fn bindings_to_sexpr(bindings: &[LetBinding], interner: &Interner) -> String {
+    let bindings: Vec<String> = bindings.iter().map(|b| binding_to_sexpr(b, interner)).collect();
+    format!("({})", bindings.join(" "))
+}
+
+pub fn expr_to_sexpr(expr: &Expr, interner: &Interner) -> String {
+    match &expr.kind {
+        ExprKind::Int(val) => format!("{}", val),
+        ExprKind::Float(val) => format!("{:?}", val),
+        ExprKind::Char(val) => format!("{:?}", val),
+        ExprKind::Str(val) => format!("{:?}", val),
+        ExprKind::Bool(val) => format!("{}", val),
+        ExprKind::Template(parts) => {
+            let mut s = String::from("(template");
+            for part in parts {
+                s.push(' ');
+                match part {
+                    TemplatePart::Str(str) => s.push_str(&format!("{:?}", str)),
+                    TemplatePart::Expr(e) => s.push_str(&expr_to_sexpr(e, interner)),
+                }
+            }
+            s.push(')');
+            s
+        }
+        ExprKind::Name(name) => String::from(interner.get(*name)),
+        ExprKind::Path(module, member) => format!("{}::{}", interner.get(module.name), interner.get(member.name)),
+        ExprKind::Field(e, field) => format!("(field {} {})", expr_to_sexpr(e, interner), interner.get(field.name)),
+        ExprKind::Unary(op, e) => format!("({} {})", op.as_str(), expr_to_sexpr(e, interner)),
+        ExprKind::Binary(op, l, r) => format!(
+            "({} {} {})",
+            op.as_str(),
+            expr_to_sexpr(l, interner),
+            expr_to_sexpr(r, interner)
+        ),
+        ExprKind::Call(func, args) => {
+            let mut s = format!("({}", expr_to_sexpr(func, interner));
+            for arg in args {
+                s.push(' ');
+                s.push_str(&expr_to_sexpr(arg, interner));
+            }
+            s.push(')');
+            s
+        }
+        ExprKind::If(cond, then_expr, else_expr) => format!(
+            "(if {} (then {}) (else {}))",
+            expr_to_sexpr(cond, interner),
+            expr_to_sexpr(then_expr, interner),
+            expr_to_sexpr(else_expr, interner)
+        ),
+        ExprKind::Let(bindings, body) => format!(
+            "(let {} {})",
+            bindings_to_sexpr(bindings, interner),
+            expr_to_sexpr(body, interner)
+        ),
+        ExprKind::StructLit(lit) => {
+            let mut s = String::from("(compound ");
+            match &lit.name {
+                Some(name) => s.push_str(interner.get(name.name)),
+                None => s.push('_'),
+            }
+            for field in &lit.fields {
+                s.push(' ');
+                match &field.name {
+                    Some(name) => s.push_str(&format!("({} {})", interner.get(name.name), expr_to_sexpr(&field.expr, interner))),
+                    None => s.push_str(&expr_to_sexpr(&field.expr, interner)),
+                }
+            }
+            s.push(')');
+            s
+        }
+        ExprKind::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(|e| expr_to_sexpr(e, interner)).collect();
+            format!("(tuple {})", elems.join(" "))
+        }
+        ExprKind::Lambda(params, body) => {
+            let params: Vec<String> = params.iter().map(|p| pattern_to_sexpr(p, interner)).collect();
+            format!("(lambda ({}) {})", params.join(" "), expr_to_sexpr(body, interner))
+        }
+        ExprKind::Case(scrutinee, arms) => {
+            let mut s = format!("(case {}", expr_to_sexpr(scrutinee, interner));
+            for arm in arms {
+                s.push_str(&format!(" ({} {})", pattern_to_sexpr(&arm.pattern, interner), expr_to_sexpr(&arm.body, interner)));
+            }
+            s.push(')');
+            s
+        }
+        ExprKind::Error => String::from("(error)"),
+    }
+}
+
+pub fn pattern_to_sexpr(pattern: &Pattern, interner: &Interner) -> String {
+    match &pattern.kind {
+        PatternKind::Wildcard => String::from("_"),
+        PatternKind::Binding(name) => String::from(interner.get(*name)),
+        PatternKind::Int(val) => format!("{}", val),
+        PatternKind::Char(val) => format!("{:?}", val),
+        PatternKind::Str(val) => format!("{:?}", val),
+        PatternKind::Bool(val) => format!("{}", val),
+        PatternKind::Variant(name, args) if args.is_empty() => String::from(interner.get(name.name)),
+        PatternKind::Variant(name, args) => {
+            let args: Vec<String> = args.iter().map(|a| pattern_to_sexpr(a, interner)).collect();
+            format!("({} {})", interner.get(name.name), args.join(" "))
+        }
+        PatternKind::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(|e| pattern_to_sexpr(e, interner)).collect();
+            format!("(tuple {})", elems.join(" "))
+        }
+        PatternKind::Struct(name, fields) => {
+            let mut s = String::from("(compound ");
+            match name {
+                Some(name) => s.push_str(interner.get(name.name)),
+                None => s.push('_'),
+            }
+            for field in fields {
+                s.push(' ');
+                match &field.name {
+                    Some(name) => s.push_str(&format!("({} {})", interner.get(name.name), pattern_to_sexpr(&field.pattern, interner))),
+                    None => s.push_str(&pattern_to_sexpr(&field.pattern, interner)),
+                }
+            }
+            s.push(')');
+            s
+        }
+    }
+}
+
+// Function bodies are printed as statements: control flow goes on its own
+// lines and values in tail position are wrapped in `return`.
+fn body_to_sexpr(expr: &Expr, indent: usize, interner: &Interner) -> String {
+    let pad = " ".repeat(indent);
+    match &expr.kind {
+        ExprKind::If(cond, then_expr, else_expr) => format!(
+            "{}(if {}\n{}  (then\n{})\n{}  (else\n{}))",
+            pad,
+            expr_to_sexpr(cond, interner),
+            pad,
+            body_to_sexpr(then_expr, indent + 4, interner),
+            pad,
+            body_to_sexpr(else_expr, indent + 4, interner)
+        ),
+        ExprKind::Let(bindings, body) => format!(
+            "{}(let {}\n{})",
+            pad,
+            bindings_to_sexpr(bindings, interner),
+            body_to_sexpr(body, indent + 2, interner)
+        ),
+        _ => format!("{}(return {})", pad, expr_to_sexpr(expr, interner)),
+    }
+}
+
+fn type_head_to_sexpr(name: &Ident, params: &[Ident], interner: &Interner) -> String {
+    if params.is_empty() {
+        return String::from(interner.get(name.name));
+    }
+    let params: Vec<&str> = params.iter().map(|p| interner.get(p.name)).collect();
+    format!("({} {})", interner.get(name.name), params.join(" "))
+}
+
+fn func_to_sexpr(func: &FuncDecl, indent: usize, interner: &Interner) -> String {
+    let mut s = format!("(func {}", interner.get(func.name.name));
+    for param in &func.params {
+        match &param.ty {
+            Some(ty) => s.push_str(&format!(" ({} {})", pattern_to_sexpr(&param.pattern, interner), type_to_sexpr(ty, interner))),
+            None => s.push_str(&format!(" {}", pattern_to_sexpr(&param.pattern, interner))),
+        }
+    }
+    if let Some(ret) = &func.ret {
+        s.push(' ');
+        s.push_str(&type_to_sexpr(ret, interner));
+    }
+    s.push('\n');
+    s.push_str(&body_to_sexpr(&func.body, indent + 2, interner));
+    s.push(')');
+    s
+}
+
+pub fn decl_to_sexpr(decl: &Decl, interner: &Interner) -> String {
+    match &decl.kind {
+        DeclKind::Const(c) => format!("(const {} {})", interner.get(c.name.name), expr_to_sexpr(&c.expr, interner)),
+        DeclKind::Type(t) => {
+            let mut s = format!("(type {}", type_head_to_sexpr(&t.name, &t.params, interner));
+            for variant in &t.variants {
+                s.push(' ');
+                if variant.fields.is_empty() {
+                    s.push_str(interner.get(variant.name.name));
+                } else {
+                    s.push('(');
+                    s.push_str(interner.get(variant.name.name));
+                    for field in &variant.fields {
+                        s.push(' ');
+                        s.push_str(&type_to_sexpr(field, interner));
+                    }
+                    s.push(')');
+                }
+            }
+            s.push(')');
+            s
+        }
+        DeclKind::Struct(st) => {
+            let mut s = format!("(struct {}", type_head_to_sexpr(&st.name, &st.params, interner));
+            for field in &st.fields {
+                s.push_str(&format!(" ({} {})", interner.get(field.name.name), type_to_sexpr(&field.ty, interner)));
+            }
+            s.push(')');
+            s
+        }
+        DeclKind::Func(func) => func_to_sexpr(func, 0, interner),
+        DeclKind::Instance(inst) => {
+            let mut s = format!("(instance {} {}", interner.get(inst.trait_name.name), type_to_sexpr(&inst.ty, interner));
+            for method in &inst.methods {
+                s.push_str("\n  ");
+                s.push_str(&func_to_sexpr(method, 2, interner));
+            }
+            s.push(')');
+            s
+        }
+        DeclKind::Import(import) => {
+            let path: Vec<&str> = import.path.iter().map(|p| interner.get(p.name)).collect();
+            format!("(import {})", path.join("::"))
+        }
+        DeclKind::Export(export) => {
+            let names: Vec<&str> = export.names.iter().map(|n| interner.get(n.name)).collect();
+            format!("(export {})", names.join(" "))
+        }
+        DeclKind::Expr(expr) => expr_to_sexpr(expr, interner),
+        DeclKind::Error => String::from("(error)"),
+    }
+}
+
+pub fn module_to_sexpr(module: &Module, interner: &Interner) -> String {
+    let mut s = String::new();
+    for decl in &module.decls {
+        s.push_str(&decl_to_sexpr(decl, interner));
+        s.push('\n');
+    }
+    s
+}
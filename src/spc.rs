@@ -0,0 +1,741 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::bytecode::{Closure, Function, ModuleInfo, Op, Program, TypeInfo, Value};
+use crate::common::{fnv1a, Span};
+use crate::modules::{source_hash, ModuleId};
+
+// Compiled programs saved to disk as `.spc` files, loaded back without
+// parsing or checking the sources again. The layout is:
+//
+//   magic `SPC\0`, version (u32, little endian), FNV-1a checksum of the
+//   rest of the file (u64, little endian)
+//   string table: every name and string constant, referred to by index
+//   modules: name, source hash and export table of each
+//   types, constant pool, function table with the debug spans of every
+//   instruction, then the main function
+//
+// Integers are LEB128 varints, signed ones zigzag encoded, and floats their
+// 8 little endian bytes. A file only loads with the version it was written
+// with, and with its checksum: the machine trusts the code it runs.
+
+pub const MAGIC: &[u8; 4] = b"SPC\0";
+pub const VERSION: u32 = 3;
+const HEADER_LEN: usize = 16;
+
+const UNARY_OPS: [UnaryOp; 3] = [UnaryOp::Neg, UnaryOp::Not, UnaryOp::BitNot];
+const BINARY_OPS: [BinaryOp; 18] = [
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Mod,
+    BinaryOp::BitAnd,
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::BitOr,
+    BinaryOp::BitXor,
+    BinaryOp::Shl,
+    BinaryOp::Shr,
+    BinaryOp::Eq,
+    BinaryOp::NotEq,
+    BinaryOp::Lt,
+    BinaryOp::LtEq,
+    BinaryOp::Gt,
+    BinaryOp::GtEq,
+    BinaryOp::And,
+    BinaryOp::Or,
+];
+
+// Path of the compiled file of a source file, next to it.
+pub fn spc_file(path: &Path) -> PathBuf {
+    path.with_extension("spc")
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, u32>,
+}
+
+impl Writer {
+    fn u32(&mut self, val: u32) {
+        self.u64(val as u64);
+    }
+
+    fn u64(&mut self, mut val: u64) {
+        loop {
+            let byte = (val & 0x7f) as u8;
+            val >>= 7;
+            if val == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    fn i64(&mut self, val: i64) {
+        self.u64(((val << 1) ^ (val >> 63)) as u64);
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    // Strings go to the table, written before everything else.
+    fn str(&mut self, s: &str) {
+        let id = match self.string_ids.get(s) {
+            Some(id) => *id,
+            None => {
+                let id = self.strings.len() as u32;
+                self.strings.push(String::from(s));
+                self.string_ids.insert(String::from(s), id);
+                id
+            }
+        };
+        self.u32(id);
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Int(val) => {
+                self.bytes.push(0);
+                self.i64(*val);
+            }
+            Value::Float(val) => {
+                self.bytes.push(1);
+                self.bytes.extend_from_slice(&val.to_le_bytes());
+            }
+            Value::Char(val) => {
+                self.bytes.push(2);
+                self.u32(*val as u32);
+            }
+            Value::Str(val) => {
+                self.bytes.push(3);
+                self.str(val);
+            }
+            Value::Bool(val) => {
+                self.bytes.push(4);
+                self.bytes.push(*val as u8);
+            }
+            Value::Tuple(elems) => {
+                self.bytes.push(5);
+                self.values(elems);
+            }
+            Value::Struct(ty, fields) => {
+                self.bytes.push(6);
+                self.u32(*ty);
+                self.values(fields);
+            }
+            Value::Variant(ty, index, fields) => {
+                self.bytes.push(7);
+                self.u32(*ty);
+                self.u32(*index);
+                self.values(fields);
+            }
+            Value::Closure(closure) => {
+                self.bytes.push(8);
+                self.u32(closure.func);
+                self.values(&closure.captured);
+                self.values(&closure.args);
+            }
+        }
+    }
+
+    fn values(&mut self, values: &[Value]) {
+        self.len(values.len());
+        for value in values {
+            self.value(value);
+        }
+    }
+
+    fn op(&mut self, op: &Op) {
+        let (code, operands): (u8, &[u32]) = match op {
+            Op::Const(c) => (0, &[*c]),
+            Op::Load(slot) => (1, &[*slot]),
+            Op::Store(slot) => (2, &[*slot]),
+            Op::Dup => (3, &[]),
+            Op::Pop => (4, &[]),
+            Op::Closure(func, n) => (5, &[*func, *n]),
+            Op::Call(argc) => (6, &[*argc]),
+            Op::TailCall(argc) => (7, &[*argc]),
+            Op::CallFunc(func, argc) => (8, &[*func, *argc]),
+            Op::TailCallFunc(func, argc) => (9, &[*func, *argc]),
+            Op::Return => (10, &[]),
+            Op::Jump(target) => (11, &[*target]),
+            Op::JumpIfFalse(target) => (12, &[*target]),
+            Op::Unary(op) => (13, &[UNARY_OPS.iter().position(|o| o == op).unwrap() as u32]),
+            Op::Binary(op) => (14, &[BINARY_OPS.iter().position(|o| o == op).unwrap() as u32]),
+            Op::Tuple(n) => (15, &[*n]),
+            Op::Struct(ty, n) => (16, &[*ty, *n]),
+            Op::Variant(ty, index, n) => (17, &[*ty, *index, *n]),
+            Op::Field(index) => (18, &[*index]),
+            Op::TestTag(index) => (19, &[*index]),
+            Op::Show => (20, &[]),
+            Op::Concat(n) => (21, &[*n]),
+            Op::Print => (22, &[]),
+            Op::MatchFail => (23, &[]),
+        };
+        self.bytes.push(code);
+        for operand in operands {
+            self.u32(*operand);
+        }
+    }
+}
+
+pub fn write_program(program: &Program) -> Vec<u8> {
+    let mut w = Writer {
+        bytes: vec![],
+        strings: vec![],
+        string_ids: HashMap::new(),
+    };
+    w.len(program.modules.len());
+    for module in &program.modules {
+        w.str(&module.name);
+        w.str(&module.path.to_string_lossy());
+        w.bytes.extend_from_slice(&module.hash.to_le_bytes());
+        w.len(module.exports.len());
+        for (name, func) in &module.exports {
+            w.str(name);
+            w.u32(*func);
+        }
+    }
+    w.len(program.types.len());
+    for ty in &program.types {
+        w.str(&ty.name);
+        w.bytes.push(ty.is_struct as u8);
+        w.len(ty.ctors.len());
+        for (name, arity) in &ty.ctors {
+            w.str(name);
+            w.u32(*arity);
+        }
+        w.len(ty.fields.len());
+        for field in &ty.fields {
+            w.str(field);
+        }
+        w.len(ty.methods.len());
+        for (name, func) in &ty.methods {
+            w.str(name);
+            w.u32(*func);
+        }
+    }
+    w.values(&program.constants);
+    w.len(program.functions.len());
+    for func in &program.functions {
+        w.str(&func.name);
+        w.u32(func.module);
+        w.u32(func.arity);
+        w.u32(func.captures);
+        w.u32(func.locals);
+        w.len(func.code.len());
+        for op in &func.code {
+            w.op(op);
+        }
+        for span in &func.spans {
+            w.len(span.start);
+            w.len(span.end - span.start);
+        }
+    }
+    w.u32(program.main);
+
+    let body = std::mem::take(&mut w.bytes);
+    let strings = std::mem::take(&mut w.strings);
+    w.len(strings.len());
+    for s in &strings {
+        w.len(s.len());
+        w.bytes.extend_from_slice(s.as_bytes());
+    }
+    w.bytes.extend(body);
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&fnv1a(&w.bytes).to_le_bytes());
+    bytes.extend(w.bytes);
+    bytes
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    strings: Vec<Rc<str>>,
+}
+
+type Read<T> = Result<T, String>;
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Read<u8> {
+        let byte = *self.bytes.get(self.pos).ok_or("Unexpected end of file")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, n: usize) -> Read<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(String::from("Unexpected end of file"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn u64(&mut self) -> Read<u64> {
+        let mut val: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(String::from("Invalid integer"))
+    }
+
+    fn u32(&mut self) -> Read<u32> {
+        let val = self.u64()?;
+        if val > u32::MAX as u64 {
+            return Err(String::from("Invalid integer"));
+        }
+        Ok(val as u32)
+    }
+
+    fn i64(&mut self) -> Read<i64> {
+        let val = self.u64()?;
+        Ok((val >> 1) as i64 ^ -((val & 1) as i64))
+    }
+
+    fn u64_le(&mut self) -> Read<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // Counts are bounded by the bytes left, so a corrupt file can't make
+    // the reader allocate much more than its size.
+    fn len(&mut self) -> Read<usize> {
+        let len = self.u32()? as usize;
+        if len > self.bytes.len() - self.pos {
+            return Err(String::from("Unexpected end of file"));
+        }
+        Ok(len)
+    }
+
+    fn rc_str(&mut self) -> Read<Rc<str>> {
+        let id = self.u32()? as usize;
+        match self.strings.get(id) {
+            Some(s) => Ok(s.clone()),
+            None => Err(format!("Invalid string index {}", id)),
+        }
+    }
+
+    fn str(&mut self) -> Read<String> {
+        Ok(String::from(&*self.rc_str()?))
+    }
+
+    fn value(&mut self) -> Read<Value> {
+        let value = match self.byte()? {
+            0 => Value::Int(self.i64()?),
+            1 => Value::Float(f64::from_bits(self.u64_le()?)),
+            2 => {
+                let code = self.u32()?;
+                Value::Char(std::char::from_u32(code).ok_or_else(|| format!("Invalid char {}", code))?)
+            }
+            3 => Value::Str(self.rc_str()?),
+            4 => Value::Bool(self.byte()? != 0),
+            5 => Value::Tuple(Rc::new(self.values()?)),
+            6 => Value::Struct(self.u32()?, Rc::new(self.values()?)),
+            7 => Value::Variant(self.u32()?, self.u32()?, Rc::new(self.values()?)),
+            8 => Value::Closure(Rc::new(Closure {
+                func: self.u32()?,
+                captured: self.values()?,
+                args: self.values()?,
+            })),
+            tag => return Err(format!("Invalid constant tag {}", tag)),
+        };
+        Ok(value)
+    }
+
+    fn values(&mut self) -> Read<Vec<Value>> {
+        let len = self.len()?;
+        let mut values = Vec::with_capacity(len);
+        for _ in 0..len {
+            values.push(self.value()?);
+        }
+        Ok(values)
+    }
+
+    fn op(&mut self) -> Read<Op> {
+        let op = match self.byte()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Load(self.u32()?),
+            2 => Op::Store(self.u32()?),
+            3 => Op::Dup,
+            4 => Op::Pop,
+            5 => Op::Closure(self.u32()?, self.u32()?),
+            6 => Op::Call(self.u32()?),
+            7 => Op::TailCall(self.u32()?),
+            8 => Op::CallFunc(self.u32()?, self.u32()?),
+            9 => Op::TailCallFunc(self.u32()?, self.u32()?),
+            10 => Op::Return,
+            11 => Op::Jump(self.u32()?),
+            12 => Op::JumpIfFalse(self.u32()?),
+            13 => Op::Unary(*UNARY_OPS.get(self.u32()? as usize).ok_or("Invalid operator")?),
+            14 => Op::Binary(*BINARY_OPS.get(self.u32()? as usize).ok_or("Invalid operator")?),
+            15 => Op::Tuple(self.u32()?),
+            16 => Op::Struct(self.u32()?, self.u32()?),
+            17 => Op::Variant(self.u32()?, self.u32()?, self.u32()?),
+            18 => Op::Field(self.u32()?),
+            19 => Op::TestTag(self.u32()?),
+            20 => Op::Show,
+            21 => Op::Concat(self.u32()?),
+            22 => Op::Print,
+            23 => Op::MatchFail,
+            code => return Err(format!("Invalid opcode {}", code)),
+        };
+        Ok(op)
+    }
+
+    fn pairs(&mut self) -> Read<Vec<(String, u32)>> {
+        let len = self.len()?;
+        let mut pairs = Vec::with_capacity(len);
+        for _ in 0..len {
+            pairs.push((self.str()?, self.u32()?));
+        }
+        Ok(pairs)
+    }
+}
+
+pub fn read_program(bytes: &[u8]) -> Result<Program, String> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(String::from("Not a compiled Silver Pancake file"));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&bytes[4..8]);
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {}, expected {}", version, VERSION));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(String::from("Unexpected end of file"));
+    }
+    let mut checksum = [0; 8];
+    checksum.copy_from_slice(&bytes[8..HEADER_LEN]);
+    if u64::from_le_bytes(checksum) != fnv1a(&bytes[HEADER_LEN..]) {
+        return Err(String::from("Corrupted file, the checksum doesn't match"));
+    }
+    let mut r = Reader {
+        bytes,
+        pos: HEADER_LEN,
+        strings: vec![],
+    };
+    for _ in 0..r.len()? {
+        let len = r.len()?;
+        let s = std::str::from_utf8(r.take(len)?).map_err(|_| String::from("Invalid string"))?;
+        r.strings.push(Rc::from(s));
+    }
+    let mut modules = vec![];
+    for _ in 0..r.len()? {
+        modules.push(ModuleInfo {
+            name: r.str()?,
+            path: PathBuf::from(r.str()?),
+            hash: r.u64_le()?,
+            exports: r.pairs()?,
+        });
+    }
+    let mut types = vec![];
+    for _ in 0..r.len()? {
+        let name = r.str()?;
+        let is_struct = r.byte()? != 0;
+        let ctors = r.pairs()?;
+        let mut fields = vec![];
+        for _ in 0..r.len()? {
+            fields.push(r.str()?);
+        }
+        types.push(TypeInfo {
+            name,
+            is_struct,
+            ctors,
+            fields,
+            methods: r.pairs()?,
+        });
+    }
+    let constants = r.values()?;
+    let mut functions = vec![];
+    for _ in 0..r.len()? {
+        let name = r.str()?;
+        let (module, arity, captures, locals) = (r.u32()?, r.u32()?, r.u32()?, r.u32()?);
+        let len = r.len()?;
+        let mut code = Vec::with_capacity(len);
+        for _ in 0..len {
+            code.push(r.op()?);
+        }
+        let mut spans = Vec::with_capacity(len);
+        for _ in 0..len {
+            let start = r.u32()? as usize;
+            spans.push(Span::new(start, start + r.u32()? as usize));
+        }
+        functions.push(Function {
+            name,
+            module,
+            arity,
+            captures,
+            locals,
+            code,
+            spans,
+        });
+    }
+    let main = r.u32()?;
+    if r.pos != bytes.len() {
+        return Err(String::from("Unexpected data after the program"));
+    }
+    let program = Program {
+        functions,
+        constants,
+        types,
+        modules,
+        main,
+    };
+    validate(&program)?;
+    Ok(program)
+}
+
+// Indices must be in range, and the stack balanced, for the machine to run
+// the program safely.
+fn validate(program: &Program) -> Result<(), String> {
+    let funcs = program.functions.len() as u32;
+    let types = program.types.len() as u32;
+    let check = |ok: bool, what: &str| if ok { Ok(()) } else { Err(format!("Invalid {} index", what)) };
+    fn check_value(program: &Program, value: &Value) -> Result<(), String> {
+        let (ok, fields): (bool, &[Value]) = match value {
+            Value::Tuple(elems) => (true, elems),
+            Value::Struct(ty, fields) => (program.types.get(*ty as usize).is_some_and(|t| t.is_struct), fields),
+            Value::Variant(ty, index, fields) => (
+                program.types.get(*ty as usize).is_some_and(|t| !t.is_struct && (*index as usize) < t.ctors.len()),
+                fields,
+            ),
+            Value::Closure(closure) => ((closure.func as usize) < program.functions.len(), &[]),
+            _ => (true, &[]),
+        };
+        if !ok {
+            return Err(String::from("Invalid constant"));
+        }
+        fields.iter().try_for_each(|f| check_value(program, f))
+    }
+    for value in &program.constants {
+        check_value(program, value)?;
+    }
+    for ty in &program.types {
+        for (_, func) in &ty.methods {
+            check(*func < funcs, "function")?;
+        }
+    }
+    for module in &program.modules {
+        for (_, func) in &module.exports {
+            check(*func < funcs, "function")?;
+        }
+    }
+    check(program.main < funcs, "function")?;
+    for func in &program.functions {
+        check((func.module as usize) < program.modules.len(), "module")?;
+        check(func.captures + func.arity <= func.locals, "local")?;
+        let len = func.code.len() as u32;
+        for op in &func.code {
+            match *op {
+                Op::Const(c) => check((c as usize) < program.constants.len(), "constant")?,
+                Op::Load(slot) | Op::Store(slot) => check(slot < func.locals, "local")?,
+                Op::Closure(f, n) => check(f < funcs && n == program.functions[f as usize].captures, "function")?,
+                Op::CallFunc(f, n) | Op::TailCallFunc(f, n) => {
+                    check(f < funcs && n == program.functions[f as usize].arity, "function")?
+                }
+                Op::Jump(target) | Op::JumpIfFalse(target) => check(target < len, "jump")?,
+                Op::Struct(ty, _) => check(ty < types && program.types[ty as usize].is_struct, "type")?,
+                Op::Variant(ty, index, _) => check(
+                    ty < types && (index as usize) < program.types[ty as usize].ctors.len(),
+                    "type",
+                )?,
+                _ => {}
+            }
+        }
+        check_stack(func)?;
+    }
+    Ok(())
+}
+
+// Values an instruction pops, and pushes.
+fn stack_effect(op: Op) -> (u32, u32) {
+    match op {
+        Op::Const(_) | Op::Load(_) => (0, 1),
+        Op::Store(_) | Op::Pop | Op::JumpIfFalse(_) | Op::Print => (1, 0),
+        Op::Dup => (1, 2),
+        Op::Call(n) | Op::TailCall(n) => (n + 1, 1),
+        Op::Closure(_, n)
+        | Op::CallFunc(_, n)
+        | Op::TailCallFunc(_, n)
+        | Op::Tuple(n)
+        | Op::Struct(_, n)
+        | Op::Variant(_, _, n)
+        | Op::Concat(n) => (n, 1),
+        Op::Return | Op::Unary(_) | Op::Field(_) | Op::TestTag(_) | Op::Show => (1, 1),
+        Op::Binary(_) => (2, 1),
+        Op::Jump(_) | Op::MatchFail => (0, 0),
+    }
+}
+
+// Every instruction must find the values it pops, at the same depth along
+// every path reaching it, and no path may run past the end of the code.
+fn check_stack(func: &Function) -> Result<(), String> {
+    let mut depths = vec![None; func.code.len()];
+    let mut todo = vec![(0, 0)];
+    while let Some((ip, depth)) = todo.pop() {
+        let op = match func.code.get(ip) {
+            Some(op) => *op,
+            None => return Err(format!("Function '{}' runs past its end", func.name)),
+        };
+        let (pops, pushes) = stack_effect(op);
+        match depths[ip] {
+            Some(seen) if seen == depth => continue,
+            None if depth >= pops => depths[ip] = Some(depth),
+            _ => return Err(format!("Unbalanced stack in function '{}'", func.name)),
+        }
+        let depth = depth - pops + pushes;
+        match op {
+            Op::Return | Op::TailCall(_) | Op::TailCallFunc(..) | Op::MatchFail => {}
+            Op::Jump(target) => todo.push((target as usize, depth)),
+            Op::JumpIfFalse(target) => {
+                todo.push((target as usize, depth));
+                todo.push((ip + 1, depth));
+            }
+            _ => todo.push((ip + 1, depth)),
+        }
+    }
+    Ok(())
+}
+
+// Modules whose file changed or is gone since the program was compiled. The
+// saved program can run as is when there are none.
+pub fn changed_modules(program: &Program, read: &dyn Fn(&Path) -> Option<String>) -> Vec<ModuleId> {
+    let mut changed = vec![];
+    for (i, module) in program.modules.iter().enumerate() {
+        if read(&module.path).is_none_or(|src| source_hash(&src) != module.hash) {
+            changed.push(ModuleId(i));
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile_ir;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+    use crate::vm::run_vm;
+
+    fn compile(src: &str, foo: &str) -> Program {
+        let (graph, checked, interner) = check_test_program(src, foo);
+        compile_ir(&lower_program(&graph, &checked, &interner))
+    }
+
+    const FOO: &str = "
+type Shape =
+    Circle: float
+    Rect: float, float
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Rect w h -> w * h
+
+export =
+    area
+    Shape
+";
+
+    const SRC: &str = "
+import foo::area
+import foo::Shape
+
+struct Point =
+    x, y: int
+
+const ORIGIN = (Point {0, 0}, 'c', -3, 2.5)
+
+scale k: int -> (int -> int)
+    \\x -> x * k
+
+area (Rect 2.0 3.0)
+ORIGIN
+scale 3 14
+`${\"string\"} ${1 < 2}`
+";
+
+    #[test]
+    fn test_round_trip() {
+        let program = compile(SRC, FOO);
+        let bytes = write_program(&program);
+        assert!(&bytes[..4] == MAGIC);
+        let loaded = read_program(&bytes).unwrap();
+        assert!(loaded == program);
+        let expected = "6.0\n(Point {x = 0, y = 0}, c, -3, 2.5)\n42\nstring true\n";
+        assert!(run_vm(&loaded) == Ok(String::from(expected)));
+
+        let foo = &loaded.modules.iter().find(|m| m.name == "foo").unwrap();
+        assert!(foo.exports.len() == 1 && loaded.functions[foo.exports[0].1 as usize].name == "area");
+        assert!(spc_file(Path::new("app/main.sp")) == Path::new("app/main.spc"));
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let bytes = write_program(&compile(SRC, FOO));
+        assert!(read_program(b"\x7fELF\x02\x01\x01\x00").unwrap_err() == "Not a compiled Silver Pancake file");
+
+        let mut newer = bytes.clone();
+        newer[4] = 4;
+        assert!(read_program(&newer).unwrap_err() == "Unsupported bytecode version 4, expected 3");
+
+        assert!(read_program(&bytes[..12]).unwrap_err() == "Unexpected end of file");
+        for len in [20, bytes.len() / 2, bytes.len() - 1] {
+            assert!(read_program(&bytes[..len]).unwrap_err() == "Corrupted file, the checksum doesn't match");
+        }
+        // Any flipped byte is caught before the machine runs the code.
+        for i in 0..bytes.len() {
+            let mut flipped = bytes.clone();
+            flipped[i] ^= 0x10;
+            assert!(read_program(&flipped).is_err());
+        }
+    }
+
+    #[test]
+    fn test_malformed_code() {
+        let program = compile(SRC, FOO);
+        let main = program.main as usize;
+        // Rewrites the code of main and saves the program with a valid
+        // checksum.
+        let load = |edit: &dyn Fn(&mut Vec<Op>)| {
+            let mut program = program.clone();
+            let func = &mut program.functions[main];
+            edit(&mut func.code);
+            func.spans.resize(func.code.len(), Span::default());
+            read_program(&write_program(&program))
+        };
+        assert!(load(&|_| {}).is_ok());
+        assert!(load(&|code| code.insert(0, Op::Pop)).unwrap_err() == "Unbalanced stack in function '<main>'");
+        assert!(load(&|code| code.insert(0, Op::Binary(BinaryOp::Add))).is_err());
+        assert!(load(&|code| code.insert(0, Op::Jump(2))).is_err());
+        assert!(load(&|code| *code.last_mut().unwrap() = Op::Pop).unwrap_err() == "Function '<main>' runs past its end");
+        assert!(load(&|code| code.clear()).is_err());
+        let area = program.functions.iter().position(|f| f.name == "area").unwrap() as u32;
+        assert!(load(&|code| code.insert(0, Op::CallFunc(area, 2))).unwrap_err() == "Invalid function index");
+    }
+
+    #[test]
+    fn test_changed_modules() {
+        let program = compile(SRC, FOO);
+        let paths: Vec<&Path> = program.modules.iter().map(|m| m.path.as_path()).collect();
+        assert!(paths == [Path::new("main.sp"), Path::new("foo.sp")]);
+        let files = |foo: Option<String>| {
+            move |path: &Path| if path == Path::new("main.sp") { Some(String::from(SRC)) } else { foo.clone() }
+        };
+        assert!(changed_modules(&program, &files(Some(String::from(FOO)))).is_empty());
+        assert!(changed_modules(&program, &files(Some(FOO.replace("3.0", "3.14")))) == vec![ModuleId(1)]);
+        assert!(changed_modules(&program, &files(None)) == vec![ModuleId(1)]);
+    }
+}
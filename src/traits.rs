@@ -0,0 +1,75 @@
+use crate::types::Type;
+
+// Builtin traits behind the overloaded operators. User types get them through
+// `instance` declarations, the builtin types have fixed instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trait {
+    // `+ - * /` and unary `-`.
+    Num,
+    // `==` and `!=`.
+    Eq,
+    // `< <= > >=`, all derived from `lt`.
+    Ord,
+    // Interpolation in string templates.
+    Show,
+}
+
+pub const TRAITS: [Trait; 4] = [Trait::Num, Trait::Eq, Trait::Ord, Trait::Show];
+
+impl Trait {
+    pub fn from_name(name: &str) -> Option<Trait> {
+        TRAITS.iter().copied().find(|t| t.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Trait::Num => "Num",
+            Trait::Eq => "Eq",
+            Trait::Ord => "Ord",
+            Trait::Show => "Show",
+        }
+    }
+
+    // Methods every instance defines.
+    pub fn methods(self) -> &'static [&'static str] {
+        match self {
+            Trait::Num => &["add", "sub", "mul", "div", "neg"],
+            Trait::Eq => &["eq"],
+            Trait::Ord => &["lt"],
+            Trait::Show => &["show"],
+        }
+    }
+
+    // Type of a method in the instance for `ty`.
+    pub fn method_type(self, method: &str, ty: &Type) -> Type {
+        match (self, method) {
+            (Trait::Num, "neg") => Type::func(vec![ty.clone()], ty.clone()),
+            (Trait::Num, _) => Type::func(vec![ty.clone(), ty.clone()], ty.clone()),
+            (Trait::Eq, _) | (Trait::Ord, _) => Type::func(vec![ty.clone(), ty.clone()], Type::Bool),
+            (Trait::Show, _) => Type::func(vec![ty.clone()], Type::Str),
+        }
+    }
+
+    // Instances of the builtin types, besides the ones derived for tuples.
+    pub fn builtin(self, ty: &Type) -> bool {
+        match self {
+            Trait::Num => matches!(ty, Type::Int | Type::Float),
+            Trait::Eq | Trait::Show => matches!(ty, Type::Int | Type::Float | Type::Char | Type::Str | Type::Bool),
+            Trait::Ord => matches!(ty, Type::Int | Type::Float | Type::Char | Type::Str),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traits() {
+        assert!(Trait::from_name("Ord") == Some(Trait::Ord));
+        assert!(Trait::from_name("Monoid").is_none());
+        assert!(Trait::Num.method_type("neg", &Type::Float) == Type::func(vec![Type::Float], Type::Float));
+        assert!(Trait::Eq.method_type("eq", &Type::Char) == Type::func(vec![Type::Char, Type::Char], Type::Bool));
+        assert!(Trait::Ord.builtin(&Type::Str) && !Trait::Ord.builtin(&Type::Bool));
+    }
+}
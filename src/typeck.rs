@@ -0,0 +1,2003 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::common::{Interner, Span, Symbol};
+use crate::diagnostic::Diagnostic;
+use crate::infer::InferCtx;
+use crate::modules::ModuleId;
+use crate::pattern::{check_match, compile_match, pat_to_string, Ctor, Decision, Pat, SumEnv, VariantInfo};
+use crate::resolve::{DefId, DefKind, Exports, Resolutions};
+use crate::traits::{Trait, TRAITS};
+use crate::types::{builtin_type, subst_params, type_to_string, Scheme, Type};
+
+#[derive(Clone)]
+pub struct StructDef {
+    pub name: Ident,
+    pub params: Vec<Symbol>,
+    pub fields: Vec<(Ident, Type)>,
+}
+
+#[derive(Clone)]
+pub struct SumDef {
+    pub params: Vec<Symbol>,
+    pub variants: Vec<(Ident, Vec<Type>)>,
+}
+
+#[derive(Clone)]
+pub struct FuncSig {
+    // Lowercase type names of the signature, instantiated at every use.
+    pub ty_params: Vec<Symbol>,
+    pub params: Vec<Option<Type>>,
+    pub ret: Option<Type>,
+}
+
+// What importers of a module see: the closed types of its exported values,
+// and the types and instances their values may carry.
+#[derive(Default)]
+pub struct ModuleInterface {
+    pub values: HashMap<Symbol, Scheme>,
+    pub structs: HashMap<Symbol, StructDef>,
+    pub sums: HashMap<Symbol, SumDef>,
+    pub instances: Vec<(Trait, Symbol)>,
+}
+
+#[derive(Default)]
+pub struct TypeckResults {
+    // Struct type chosen for every struct literal and struct pattern,
+    // including the untyped ones.
+    pub struct_lits: HashMap<NodeId, Symbol>,
+    // Decision tree of every case expression.
+    pub case_trees: HashMap<NodeId, Decision>,
+    // Type of every expression and binding pattern, for tooling.
+    pub node_types: HashMap<NodeId, Type>,
+    // Instance declaration of every trait implemented by a user type.
+    pub instances: HashMap<(Trait, Symbol), NodeId>,
+    // Operators and interpolations dispatched to an instance: the trait and
+    // the type implementing it.
+    pub overloads: HashMap<NodeId, (Trait, Symbol)>,
+}
+
+// What an overloaded operator needs from its operand type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OpClass {
+    Trait(Trait),
+    // `%` only works on the builtin numbers.
+    Remainder,
+}
+
+pub struct Checker<'a> {
+    interner: &'a Interner,
+    pub structs: HashMap<Symbol, StructDef>,
+    pub sums: HashMap<Symbol, SumDef>,
+    // Variant name to its sum type and index.
+    variants: HashMap<Symbol, (Symbol, usize)>,
+    funcs: HashMap<Symbol, FuncSig>,
+    consts: HashMap<Symbol, Scheme>,
+    // Types of parameters and local bindings.
+    locals: HashMap<DefId, Scheme>,
+    infer: InferCtx,
+    // Type parameters in scope when lowering type expressions. Function
+    // signatures declare them implicitly by using them.
+    ty_params: Vec<Symbol>,
+    implicit_params: bool,
+    // Operators applied to a type still unknown, checked once the enclosing
+    // declaration is done.
+    pending: Vec<(Span, NodeId, &'static str, Type, OpClass)>,
+    // Trait and type of every valid instance declaration.
+    instance_heads: HashMap<NodeId, (Trait, Type)>,
+    // Interfaces of the modules checked before this one.
+    interfaces: Option<&'a HashMap<ModuleId, ModuleInterface>>,
+    // Types defined by other modules, and their instances.
+    foreign_types: HashSet<Symbol>,
+    foreign_instances: HashSet<(Trait, Symbol)>,
+    res: &'a Resolutions,
+    pub results: TypeckResults,
+    pub errors: Vec<Diagnostic>,
+}
+
+// An error type tells nothing about what to expect.
+fn known(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::Error => None,
+        ty => Some(ty),
+    }
+}
+
+fn quote_names(names: &[&str]) -> String {
+    let names: Vec<String> = names.iter().map(|n| format!("'{}'", n)).collect();
+    names.join(", ")
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(interner: &'a Interner, res: &'a Resolutions) -> Checker<'a> {
+        Checker {
+            interner,
+            structs: HashMap::new(),
+            sums: HashMap::new(),
+            variants: HashMap::new(),
+            funcs: HashMap::new(),
+            consts: HashMap::new(),
+            locals: HashMap::new(),
+            infer: InferCtx::new(),
+            ty_params: vec![],
+            implicit_params: false,
+            pending: vec![],
+            instance_heads: HashMap::new(),
+            interfaces: None,
+            foreign_types: HashSet::new(),
+            foreign_instances: HashSet::new(),
+            res,
+            results: TypeckResults::default(),
+            errors: vec![],
+        }
+    }
+
+    pub fn with_interfaces(
+        interner: &'a Interner,
+        res: &'a Resolutions,
+        interfaces: &'a HashMap<ModuleId, ModuleInterface>,
+    ) -> Checker<'a> {
+        Checker { interfaces: Some(interfaces), ..Checker::new(interner, res) }
+    }
+
+    fn name(&self, sym: Symbol) -> &'a str {
+        self.interner.get(sym)
+    }
+
+    fn type_str(&self, ty: &Type) -> String {
+        type_to_string(&self.infer.zonk(ty), self.interner)
+    }
+
+    pub fn lower_type(&mut self, ty: &TypeExpr) -> Type {
+        match &ty.kind {
+            TypeExprKind::Name(name) => {
+                let is_lower = self.name(*name).starts_with(|c: char| c.is_lowercase());
+                if let Some(ty) = builtin_type(self.name(*name)) {
+                    ty
+                } else if self.ty_params.contains(name) {
+                    Type::Param(*name)
+                } else if is_lower && self.implicit_params {
+                    self.ty_params.push(*name);
+                    Type::Param(*name)
+                } else {
+                    self.lower_named_type(ty, *name, vec![])
+                }
+            }
+            TypeExprKind::App(name, args) => {
+                let args = args.iter().map(|arg| self.lower_type(arg)).collect();
+                self.lower_named_type(ty, *name, args)
+            }
+            TypeExprKind::Func(param, ret) => {
+                let param = self.lower_type(param);
+                let ret = self.lower_type(ret);
+                Type::Func(Box::new(param), Box::new(ret))
+            }
+            TypeExprKind::Tuple(elems) => Type::Tuple(elems.iter().map(|e| self.lower_type(e)).collect()),
+        }
+    }
+
+    fn lower_named_type(&mut self, ty: &TypeExpr, name: Symbol, args: Vec<Type>) -> Type {
+        // Types of other modules are only named once imported.
+        let hidden = self.foreign_types.contains(&name) && !self.res.imported_types.contains_key(&name);
+        let (params, is_struct) = match (self.structs.get(&name), self.sums.get(&name)) {
+            _ if hidden => {
+                self.errors.push(
+                    Diagnostic::error(ty.span, format!("Unknown type '{}'", self.name(name)))
+                        .with_note(format!("'{}' is defined in another module, import it by name", self.name(name))),
+                );
+                return Type::Error;
+            }
+            (Some(st), _) => (st.params.len(), true),
+            (None, Some(sum)) => (sum.params.len(), false),
+            (None, None) => {
+                self.errors.push(Diagnostic::error(ty.span, format!("Unknown type '{}'", self.name(name))));
+                return Type::Error;
+            }
+        };
+        if args.len() != params {
+            let plural = if params == 1 { "" } else { "s" };
+            self.errors.push(Diagnostic::error(
+                ty.span,
+                format!("Type '{}' expects {} type argument{}, found {}", self.name(name), params, plural, args.len()),
+            ));
+            return Type::Error;
+        }
+        if is_struct {
+            Type::Struct(name, args)
+        } else {
+            Type::Sum(name, args)
+        }
+    }
+
+    fn check_type_params(&mut self, params: &[Ident]) -> Vec<Symbol> {
+        let mut syms = vec![];
+        for param in params {
+            if syms.contains(&param.name) {
+                self.errors.push(Diagnostic::error(
+                    param.span,
+                    format!("Duplicate type parameter '{}'", self.name(param.name)),
+                ));
+            } else {
+                syms.push(param.name);
+            }
+        }
+        syms
+    }
+
+    // Parameters of a generic struct or sum type.
+    fn params_of(&self, name: Symbol) -> &[Symbol] {
+        match (self.structs.get(&name), self.sums.get(&name)) {
+            (Some(st), _) => &st.params,
+            (None, Some(sum)) => &sum.params,
+            (None, None) => &[],
+        }
+    }
+
+    // Arguments `ty` gives to the generic type `name`, errors if it isn't an
+    // instance of it.
+    fn type_args(&self, ty: Option<&Type>, name: Symbol) -> Vec<Type> {
+        match ty {
+            Some(Type::Struct(n, args)) | Some(Type::Sum(n, args)) if *n == name => args.clone(),
+            _ => self.params_of(name).iter().map(|_| Type::Error).collect(),
+        }
+    }
+
+    fn fresh_args(&mut self, name: Symbol) -> Vec<Type> {
+        (0..self.params_of(name).len()).map(|_| self.infer.fresh()).collect()
+    }
+
+    // Field types of a struct instance.
+    fn struct_fields(&self, name: Symbol, args: &[Type]) -> Vec<(Ident, Type)> {
+        let st = &self.structs[&name];
+        st.fields.iter().map(|(f, ty)| (*f, subst_params(ty, &st.params, args))).collect()
+    }
+
+    // Field types of a variant of a sum type instance.
+    fn variant_fields(&self, sum: Symbol, index: usize, args: &[Type]) -> Vec<Type> {
+        let sum = &self.sums[&sum];
+        sum.variants[index].1.iter().map(|ty| subst_params(ty, &sum.params, args)).collect()
+    }
+
+    // Type names are registered before any type expression is lowered so
+    // declarations can refer to each other in any order.
+    fn collect(&mut self, module: &Module) {
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Struct(st) => {
+                    let params = self.check_type_params(&st.params);
+                    self.structs.insert(st.name.name, StructDef { name: st.name, params, fields: vec![] });
+                }
+                DeclKind::Type(t) => {
+                    let params = self.check_type_params(&t.params);
+                    self.sums.insert(t.name.name, SumDef { params, variants: vec![] });
+                }
+                _ => {}
+            }
+        }
+        self.merge_foreign_types();
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Struct(st) => {
+                    self.ty_params = self.structs[&st.name.name].params.clone();
+                    let mut fields: Vec<(Ident, Type)> = vec![];
+                    for field in &st.fields {
+                        if fields.iter().any(|(f, _)| f.name == field.name.name) {
+                            self.errors.push(Diagnostic::error(
+                                field.name.span,
+                                format!("Duplicate field '{}' in struct '{}'", self.name(field.name.name), self.name(st.name.name)),
+                            ));
+                            continue;
+                        }
+                        let ty = self.lower_type(&field.ty);
+                        fields.push((field.name, ty));
+                    }
+                    self.structs.get_mut(&st.name.name).unwrap().fields = fields;
+                }
+                DeclKind::Type(t) => {
+                    self.ty_params = self.sums[&t.name.name].params.clone();
+                    let mut variants = vec![];
+                    for variant in &t.variants {
+                        if let Some((sum, _)) = self.variants.get(&variant.name.name) {
+                            self.errors.push(Diagnostic::error(
+                                variant.name.span,
+                                format!("Variant '{}' is already defined in type '{}'", self.name(variant.name.name), self.name(*sum)),
+                            ));
+                            continue;
+                        }
+                        let fields = variant.fields.iter().map(|ty| self.lower_type(ty)).collect();
+                        self.variants.insert(variant.name.name, (t.name.name, variants.len()));
+                        variants.push((variant.name, fields));
+                    }
+                    self.sums.get_mut(&t.name.name).unwrap().variants = variants;
+                }
+                DeclKind::Func(func) => {
+                    self.ty_params = vec![];
+                    self.implicit_params = true;
+                    let params = func.params.iter().map(|p| p.ty.as_ref().map(|ty| self.lower_type(ty))).collect();
+                    // A missing return type is inferred from the body.
+                    let ret = match &func.ret {
+                        Some(ty) => self.lower_type(ty),
+                        None => self.infer.fresh(),
+                    };
+                    let ret = Some(ret);
+                    self.implicit_params = false;
+                    let ty_params = std::mem::take(&mut self.ty_params);
+                    self.funcs.insert(func.name.name, FuncSig { ty_params, params, ret });
+                }
+                DeclKind::Instance(inst) => self.collect_instance(decl.id, inst),
+                _ => {}
+            }
+        }
+        self.ty_params = vec![];
+        self.merge_foreign_variants();
+    }
+
+    // Values of imported modules carry their types, so the definitions are
+    // known even when the names aren't imported. Local types win.
+    fn merge_foreign_types(&mut self) {
+        let interfaces = match self.interfaces {
+            Some(interfaces) => interfaces,
+            None => return,
+        };
+        for interface in interfaces.values() {
+            for (name, st) in &interface.structs {
+                if !self.structs.contains_key(name) && !self.sums.contains_key(name) {
+                    self.structs.insert(*name, st.clone());
+                    self.foreign_types.insert(*name);
+                }
+            }
+            for (name, sum) in &interface.sums {
+                if !self.structs.contains_key(name) && !self.sums.contains_key(name) {
+                    self.sums.insert(*name, sum.clone());
+                    self.foreign_types.insert(*name);
+                }
+            }
+            self.foreign_instances.extend(interface.instances.iter().copied());
+        }
+    }
+
+    fn merge_foreign_variants(&mut self) {
+        for (name, sum) in &self.sums {
+            if !self.foreign_types.contains(name) {
+                continue;
+            }
+            for (index, (variant, _)) in sum.variants.iter().enumerate() {
+                self.variants.entry(variant.name).or_insert((*name, index));
+            }
+        }
+    }
+
+    // The exported values of the module, with types that don't depend on
+    // this checker's variables.
+    pub fn interface(&self, exports: &Exports) -> ModuleInterface {
+        let mut interface = ModuleInterface::default();
+        for (name, kind) in &exports.values {
+            let scheme = match kind {
+                DefKind::Func => match self.funcs.get(name) {
+                    Some(sig) => {
+                        let params = sig.params.iter().map(|p| p.clone().unwrap_or(Type::Error)).collect();
+                        let ty = Type::func(params, sig.ret.clone().unwrap_or(Type::Error));
+                        self.close(&ty, &sig.ty_params, &[])
+                    }
+                    None => continue,
+                },
+                DefKind::Const => match self.consts.get(name) {
+                    Some(scheme) => self.close(&scheme.ty, &[], &scheme.vars),
+                    None => continue,
+                },
+                DefKind::Variant => match self.variants.get(name) {
+                    Some(&(sum, index)) => {
+                        let params = self.sums[&sum].params.clone();
+                        let args: Vec<Type> = params.iter().map(|p| Type::Param(*p)).collect();
+                        let ty = Type::func(self.variant_fields(sum, index, &args), Type::Sum(sum, args));
+                        self.close(&ty, &params, &[])
+                    }
+                    None => continue,
+                },
+                _ => continue,
+            };
+            interface.values.insert(*name, scheme);
+        }
+        for (name, st) in &self.structs {
+            if !self.foreign_types.contains(name) {
+                interface.structs.insert(*name, st.clone());
+            }
+        }
+        for (name, sum) in &self.sums {
+            if !self.foreign_types.contains(name) {
+                interface.sums.insert(*name, sum.clone());
+            }
+        }
+        interface.instances = self.results.instances.keys().copied().collect();
+        interface
+    }
+
+    // Numbers the type parameters then the quantified variables of `ty` from
+    // zero. Variables left unsolved become errors.
+    fn close(&self, ty: &Type, params: &[Symbol], vars: &[u32]) -> Scheme {
+        fn go(ty: &Type, params: &[Symbol], vars: &[u32]) -> Type {
+            match ty {
+                Type::Param(p) => match params.iter().position(|q| q == p) {
+                    Some(i) => Type::Var(i as u32),
+                    None => ty.clone(),
+                },
+                Type::Var(v) => match vars.iter().position(|w| w == v) {
+                    Some(i) => Type::Var((params.len() + i) as u32),
+                    None => Type::Error,
+                },
+                Type::Func(param, ret) => Type::Func(Box::new(go(param, params, vars)), Box::new(go(ret, params, vars))),
+                Type::Tuple(elems) => Type::Tuple(elems.iter().map(|e| go(e, params, vars)).collect()),
+                Type::Struct(name, args) => Type::Struct(*name, args.iter().map(|a| go(a, params, vars)).collect()),
+                Type::Sum(name, args) => Type::Sum(*name, args.iter().map(|a| go(a, params, vars)).collect()),
+                ty => ty.clone(),
+            }
+        }
+        let ty = go(&self.infer.zonk(ty), params, vars);
+        Scheme { vars: (0..(params.len() + vars.len()) as u32).collect(), ty }
+    }
+
+    // Checks the head of an instance: a builtin trait and a user type that
+    // doesn't implement it yet.
+    fn collect_instance(&mut self, id: NodeId, inst: &InstanceDecl) {
+        let name = self.name(inst.trait_name.name);
+        let trait_ = match Trait::from_name(name) {
+            Some(trait_) => trait_,
+            None => {
+                let names: Vec<&str> = TRAITS.iter().map(|t| t.name()).collect();
+                self.errors.push(
+                    Diagnostic::error(inst.trait_name.span, format!("Unknown trait '{}'", name))
+                        .with_note(format!("the traits are {}", names.join(", "))),
+                );
+                return;
+            }
+        };
+        let ty = self.lower_type(&inst.ty);
+        let type_name = match &ty {
+            Type::Struct(name, args) | Type::Sum(name, args) if args.is_empty() => *name,
+            Type::Error => return,
+            _ => {
+                self.errors.push(Diagnostic::error(
+                    inst.ty.span,
+                    format!("Instances can only be declared for non-generic struct and sum types, found {}", self.type_str(&ty)),
+                ));
+                return;
+            }
+        };
+        if self.foreign_types.contains(&type_name) {
+            self.errors.push(Diagnostic::error(
+                inst.ty.span,
+                format!("Instances of type {} must be declared in the module defining it", self.name(type_name)),
+            ));
+            return;
+        }
+        if self.results.instances.contains_key(&(trait_, type_name)) {
+            self.errors.push(Diagnostic::error(
+                inst.ty.span,
+                format!("Conflicting instances of {} for type {}", name, self.name(type_name)),
+            ));
+            return;
+        }
+        self.results.instances.insert((trait_, type_name), id);
+        self.instance_heads.insert(id, (trait_, ty));
+    }
+
+    pub fn check_module(&mut self, module: &Module) {
+        self.collect(module);
+        // Constants first, in order, so functions see their types.
+        for decl in &module.decls {
+            if let DeclKind::Const(c) = &decl.kind {
+                let scheme = match &c.ty {
+                    Some(ty) => {
+                        let ty = self.lower_type(ty);
+                        self.check_expr(&c.expr, Some(&ty));
+                        self.finish_decl();
+                        Scheme::mono(ty)
+                    }
+                    None => {
+                        self.infer.level += 1;
+                        let ty = self.check_expr(&c.expr, None);
+                        self.infer.level -= 1;
+                        self.finish_decl();
+                        self.infer.generalize(&ty, &[])
+                    }
+                };
+                self.consts.insert(c.name.name, scheme);
+            }
+        }
+        for decl in &module.decls {
+            match &decl.kind {
+                DeclKind::Func(func) => {
+                    let sig = &self.funcs[&func.name.name];
+                    let (params, ret) = (sig.params.clone(), sig.ret.clone());
+                    // Inside the body the type parameters are rigid.
+                    self.ty_params = sig.ty_params.clone();
+                    self.check_func(func, &params, ret.as_ref());
+                    self.ty_params = vec![];
+                }
+                DeclKind::Instance(inst) => self.check_instance(decl.id, inst),
+                DeclKind::Expr(expr) => {
+                    self.check_expr(expr, None);
+                    self.finish_decl();
+                }
+                _ => {}
+            }
+        }
+        for ty in self.results.node_types.values_mut() {
+            *ty = self.infer.zonk(ty);
+        }
+    }
+
+    fn check_func(&mut self, func: &FuncDecl, params: &[Option<Type>], ret: Option<&Type>) {
+        for (param, ty) in func.params.iter().zip(params.iter()) {
+            match ty {
+                Some(ty) => {
+                    self.check_irrefutable(&param.pattern, Some(ty), "function parameter");
+                    self.bind_pattern(&param.pattern, ty);
+                }
+                None => {
+                    self.errors.push(
+                        Diagnostic::error(param.pattern.span, "Missing type annotation for parameter")
+                            .with_note("function signatures are mandatory, e.g. `multiply x, y: int -> int`"),
+                    );
+                    self.bind_pattern(&param.pattern, &Type::Error);
+                }
+            }
+        }
+        self.check_expr(&func.body, ret);
+        self.finish_decl();
+    }
+
+    // Methods take their signature from the trait, so their annotations are
+    // optional but have to agree with it.
+    fn check_instance(&mut self, id: NodeId, inst: &InstanceDecl) {
+        let head = self.instance_heads.get(&id).cloned();
+        let mut defined: Vec<Symbol> = vec![];
+        for method in &inst.methods {
+            let name = self.name(method.name.name);
+            if defined.contains(&method.name.name) {
+                self.errors.push(Diagnostic::error(method.name.span, format!("Duplicate method '{}'", name)));
+            }
+            defined.push(method.name.name);
+            let mut expected = match &head {
+                Some((trait_, ty)) if trait_.methods().contains(&name) => trait_.method_type(name, ty),
+                Some((trait_, _)) => {
+                    self.errors.push(Diagnostic::error(
+                        method.name.span,
+                        format!("Method '{}' is not part of trait {}", name, trait_.name()),
+                    ));
+                    Type::Error
+                }
+                None => Type::Error,
+            };
+            let mut params = vec![];
+            for param in &method.params {
+                let ty = match expected {
+                    Type::Func(param, ret) => {
+                        expected = *ret;
+                        *param
+                    }
+                    Type::Error => Type::Error,
+                    _ => {
+                        let arity = params.len();
+                        let plural = if arity == 1 { "" } else { "s" };
+                        self.errors.push(Diagnostic::error(
+                            param.pattern.span,
+                            format!("Method '{}' takes {} parameter{}, found {}", name, arity, plural, method.params.len()),
+                        ));
+                        expected = Type::Error;
+                        Type::Error
+                    }
+                };
+                if let Some(annotation) = &param.ty {
+                    let found = self.lower_type(annotation);
+                    self.expect_type(annotation.span, &ty, &found);
+                }
+                params.push(Some(ty));
+            }
+            if let Type::Func(_, _) = expected {
+                let mut arity = method.params.len();
+                let mut ty = &expected;
+                while let Type::Func(_, ret) = ty {
+                    arity += 1;
+                    ty = ret;
+                }
+                let plural = if arity == 1 { "" } else { "s" };
+                self.errors.push(Diagnostic::error(
+                    method.name.span,
+                    format!("Method '{}' takes {} parameter{}, found {}", name, arity, plural, method.params.len()),
+                ));
+                expected = Type::Error;
+            }
+            if let Some(annotation) = &method.ret {
+                let found = self.lower_type(annotation);
+                self.expect_type(annotation.span, &expected, &found);
+            }
+            self.check_func(method, &params, Some(&expected));
+        }
+        if let Some((trait_, ty)) = &head {
+            for method in trait_.methods() {
+                if !defined.iter().any(|d| self.name(*d) == *method) {
+                    self.errors.push(Diagnostic::error(
+                        inst.trait_name.span,
+                        format!("Missing method '{}' in instance {} {}", method, trait_.name(), self.type_str(ty)),
+                    ));
+                }
+            }
+        }
+    }
+
+    // Whether `ty` has the operations of `class`, through a builtin instance,
+    // a user instance, or one derived for tuples.
+    fn has_instance(&self, trait_: Trait, name: Symbol) -> bool {
+        self.results.instances.contains_key(&(trait_, name)) || self.foreign_instances.contains(&(trait_, name))
+    }
+
+    fn satisfies(&self, ty: &Type, class: OpClass) -> bool {
+        let trait_ = match class {
+            OpClass::Remainder => return matches!(ty, Type::Int | Type::Float | Type::Error | Type::Var(_)),
+            OpClass::Trait(trait_) => trait_,
+        };
+        match ty {
+            Type::Error | Type::Var(_) => true,
+            // Types without an Eq instance compare structurally.
+            _ if trait_ == Trait::Eq => !matches!(ty, Type::Func(_, _)),
+            Type::Struct(name, _) | Type::Sum(name, _) => self.has_instance(trait_, *name),
+            Type::Tuple(elems) if trait_ != Trait::Num => elems.iter().all(|e| self.satisfies(e, class)),
+            ty => trait_.builtin(ty),
+        }
+    }
+
+    // Checks a constraint on a known type and records the instance the
+    // operation dispatches to.
+    fn settle(&mut self, span: Span, node: NodeId, op: &str, ty: &Type, class: OpClass) -> bool {
+        if !self.satisfies(ty, class) {
+            let msg = match class {
+                OpClass::Trait(Trait::Show) => format!("Cannot interpolate a value of type {}", self.type_str(ty)),
+                _ => format!("Cannot apply '{}' to type {}", op, self.type_str(ty)),
+            };
+            let mut err = Diagnostic::error(span, msg);
+            if let (OpClass::Trait(trait_), Type::Struct(name, args) | Type::Sum(name, args)) = (class, ty) {
+                if args.is_empty() {
+                    err = err.with_note(format!("add an instance: `instance {} {}`", trait_.name(), self.name(*name)));
+                }
+            }
+            self.errors.push(err);
+            return false;
+        }
+        if let (OpClass::Trait(trait_), Type::Struct(name, _) | Type::Sum(name, _)) = (class, ty) {
+            if self.has_instance(trait_, *name) {
+                self.results.overloads.insert(node, (trait_, *name));
+            }
+        }
+        true
+    }
+
+    // Settles the operators whose operand type was unknown when they were
+    // checked. Numeric and ordered operands still unknown default to int.
+    fn finish_decl(&mut self) {
+        for (span, node, op, ty, class) in std::mem::take(&mut self.pending) {
+            let ty = self.infer.zonk(&ty);
+            if let Type::Var(_) = ty {
+                if matches!(class, OpClass::Remainder | OpClass::Trait(Trait::Num) | OpClass::Trait(Trait::Ord)) {
+                    self.infer.unify(&ty, &Type::Int);
+                }
+            } else {
+                self.settle(span, node, op, &ty, class);
+            }
+        }
+    }
+
+    fn pending_vars(&self) -> Vec<u32> {
+        let mut vars = vec![];
+        for (_, _, _, ty, _) in &self.pending {
+            self.infer.free_vars(ty, &mut vars);
+        }
+        vars
+    }
+
+    // `op` needs an operand of exactly type `want`.
+    fn require(&mut self, span: Span, op: &str, ty: &Type, want: &Type) -> bool {
+        if self.infer.unify(ty, want) {
+            return true;
+        }
+        self.errors.push(Diagnostic::error(span, format!("Cannot apply '{}' to type {}", op, self.type_str(ty))));
+        false
+    }
+
+    // `op` needs an operand type of the given class, which is checked later
+    // if the type isn't known yet.
+    fn constrain(&mut self, span: Span, node: NodeId, op: &'static str, ty: &Type, class: OpClass) -> bool {
+        let ty = self.infer.zonk(ty);
+        if let Type::Var(_) = ty {
+            self.pending.push((span, node, op, ty, class));
+            return true;
+        }
+        self.settle(span, node, op, &ty, class)
+    }
+
+    fn expect_type(&mut self, span: Span, expected: &Type, found: &Type) {
+        if !self.infer.unify(expected, found) {
+            self.errors.push(Diagnostic::error(
+                span,
+                format!("Mismatched types: expected {}, found {}", self.type_str(expected), self.type_str(found)),
+            ));
+        }
+    }
+
+    // Type of an expression given the type its context expects, if known. The
+    // expected type is what gives untyped `{...}` literals their struct type;
+    // control flow passes it down to its branches, which report mismatches
+    // themselves.
+    fn check_expr(&mut self, expr: &Expr, expected: Option<&Type>) -> Type {
+        let expected = expected.map(|ty| self.infer.zonk(ty));
+        // An unsolved variable tells nothing about the expression's shape.
+        let hint = match &expected {
+            Some(Type::Var(_)) => None,
+            expected => expected.as_ref(),
+        };
+        let ty = self.synth_expr(expr, hint);
+        if let Some(expected) = &expected {
+            let propagated = hint.is_some()
+                && matches!(
+                    expr.kind,
+                    ExprKind::If(_, _, _) | ExprKind::Let(_, _) | ExprKind::Case(_, _)
+                        | ExprKind::StructLit(_) | ExprKind::Tuple(_)
+                );
+            if !propagated {
+                self.expect_type(expr.span, expected, &ty);
+            }
+        }
+        self.results.node_types.insert(expr.id, ty.clone());
+        ty
+    }
+
+    fn synth_expr(&mut self, expr: &Expr, expected: Option<&Type>) -> Type {
+        match &expr.kind {
+            ExprKind::Int(val) => {
+                if *val > i64::MAX as u64 {
+                    self.errors.push(
+                        Diagnostic::error(expr.span, "Integer literal out of range")
+                            .with_note("an int is between -9223372036854775808 and 9223372036854775807"),
+                    );
+                }
+                Type::Int
+            }
+            ExprKind::Float(_) => Type::Float,
+            ExprKind::Char(_) => Type::Char,
+            ExprKind::Str(_) => Type::Str,
+            ExprKind::Bool(_) => Type::Bool,
+            ExprKind::Template(parts) => {
+                for part in parts {
+                    if let TemplatePart::Expr(e) = part {
+                        let ty = self.check_expr(e, None);
+                        self.constrain(e.span, e.id, "${}", &ty, OpClass::Trait(Trait::Show));
+                    }
+                }
+                Type::Str
+            }
+            ExprKind::Name(_) | ExprKind::Path(_, _) => self.name_type(expr),
+            ExprKind::StructLit(lit) => self.check_struct_lit(expr, lit, expected),
+            ExprKind::Tuple(elems) => {
+                let tys = elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, elem)| {
+                        let ty = match expected {
+                            Some(Type::Tuple(tys)) if tys.len() == elems.len() => Some(&tys[i]),
+                            _ => None,
+                        };
+                        self.check_expr(elem, ty)
+                    })
+                    .collect();
+                let ty = Type::Tuple(tys);
+                match expected {
+                    Some(Type::Tuple(tys)) if tys.len() == elems.len() => {}
+                    Some(expected) => self.expect_type(expr.span, expected, &ty),
+                    None => {}
+                }
+                ty
+            }
+            ExprKind::Field(e, field) => {
+                let ty = self.check_expr(e, None);
+                let mut ty = self.infer.shallow(&ty);
+                if let Type::Var(_) = ty {
+                    // Fall back to the only struct having such a field.
+                    let owners: Vec<Symbol> = self
+                        .structs
+                        .values()
+                        .filter(|s| s.fields.iter().any(|(f, _)| f.name == field.name))
+                        .map(|s| s.name.name)
+                        .collect();
+                    if owners.len() != 1 {
+                        self.errors.push(
+                            Diagnostic::error(e.span, "Cannot infer the type of this value")
+                                .with_note("add a type annotation to the binding it comes from"),
+                        );
+                        return Type::Error;
+                    }
+                    let owner = Type::Struct(owners[0], self.fresh_args(owners[0]));
+                    self.infer.unify(&ty, &owner);
+                    ty = owner;
+                }
+                match &ty {
+                    Type::Struct(name, args) => {
+                        let fields = self.struct_fields(*name, args);
+                        match fields.iter().find(|(f, _)| f.name == field.name) {
+                            Some((_, ty)) => ty.clone(),
+                            None => {
+                                self.errors.push(Diagnostic::error(
+                                    field.span,
+                                    format!("No field '{}' on type {}", self.name(field.name), self.name(*name)),
+                                ));
+                                Type::Error
+                            }
+                        }
+                    }
+                    Type::Error => Type::Error,
+                    _ => {
+                        self.errors.push(Diagnostic::error(
+                            field.span,
+                            format!("No field '{}' on type {}", self.name(field.name), self.type_str(&ty)),
+                        ));
+                        Type::Error
+                    }
+                }
+            }
+            ExprKind::Unary(op, e) => {
+                let ty = if expr.is_min_int() {
+                    self.results.node_types.insert(e.id, Type::Int);
+                    Type::Int
+                } else {
+                    self.check_expr(e, None)
+                };
+                let ok = match op {
+                    UnaryOp::Neg => self.constrain(expr.span, expr.id, op.as_str(), &ty, OpClass::Trait(Trait::Num)),
+                    UnaryOp::Not => self.require(expr.span, op.as_str(), &ty, &Type::Bool),
+                    UnaryOp::BitNot => self.require(expr.span, op.as_str(), &ty, &Type::Int),
+                };
+                if ok {
+                    ty
+                } else {
+                    Type::Error
+                }
+            }
+            ExprKind::Binary(op, l, r) => self.check_binary(expr, *op, l, r),
+            ExprKind::Call(func, args) => {
+                let mut ty = self.check_expr(func, None);
+                let mut taken = 0;
+                for arg in args {
+                    ty = self.infer.shallow(&ty);
+                    if let Type::Var(_) = ty {
+                        let func = Type::Func(Box::new(self.infer.fresh()), Box::new(self.infer.fresh()));
+                        self.infer.unify(&ty, &func);
+                        ty = func;
+                    }
+                    match ty {
+                        Type::Func(param, ret) => {
+                            self.check_expr(arg, Some(&param));
+                            ty = *ret;
+                            taken += 1;
+                        }
+                        Type::Error => {
+                            self.check_expr(arg, None);
+                        }
+                        _ => {
+                            let msg = if taken == 0 {
+                                format!("Cannot call a value of type {}", self.type_str(&ty))
+                            } else {
+                                format!("Too many arguments: expected {}, found {}", taken, args.len())
+                            };
+                            self.errors.push(Diagnostic::error(arg.span, msg));
+                            ty = Type::Error;
+                            self.check_expr(arg, None);
+                        }
+                    }
+                }
+                ty
+            }
+            ExprKind::If(cond, then_expr, else_expr) => {
+                self.check_expr(cond, Some(&Type::Bool));
+                match expected {
+                    Some(expected) => {
+                        self.check_expr(then_expr, Some(expected));
+                        self.check_expr(else_expr, Some(expected));
+                        expected.clone()
+                    }
+                    None => {
+                        let ty = self.check_expr(then_expr, None);
+                        let else_ty = self.check_expr(else_expr, known(&ty));
+                        if ty == Type::Error {
+                            else_ty
+                        } else {
+                            ty
+                        }
+                    }
+                }
+            }
+            ExprKind::Let(bindings, body) => {
+                for binding in bindings {
+                    let ty = match &binding.ty {
+                        Some(ty) => {
+                            let ty = self.lower_type(ty);
+                            self.check_expr(&binding.expr, Some(&ty));
+                            ty
+                        }
+                        None => {
+                            self.infer.level += 1;
+                            let ty = self.check_expr(&binding.expr, None);
+                            self.infer.level -= 1;
+                            ty
+                        }
+                    };
+                    self.check_irrefutable(&binding.pattern, Some(&ty), "let binding");
+                    match (&binding.pattern.kind, &binding.ty) {
+                        // An unannotated name is polymorphic in whatever its
+                        // expression left open: `let id = \x -> x`.
+                        (PatternKind::Binding(_), None) => {
+                            let fixed = self.pending_vars();
+                            let scheme = self.infer.generalize(&ty, &fixed);
+                            self.bind_scheme(&binding.pattern, scheme);
+                        }
+                        _ => self.bind_pattern(&binding.pattern, &ty),
+                    }
+                }
+                self.check_expr(body, expected)
+            }
+            ExprKind::Lambda(params, body) => {
+                let mut expected = expected.cloned();
+                let mut param_tys = vec![];
+                for param in params {
+                    let ty = match expected.as_ref().map(|ty| self.infer.shallow(ty)) {
+                        Some(Type::Func(param, ret)) => {
+                            expected = Some(*ret);
+                            *param
+                        }
+                        _ => {
+                            expected = None;
+                            self.infer.fresh()
+                        }
+                    };
+                    self.check_irrefutable(param, Some(&ty), "lambda parameter");
+                    self.bind_pattern(param, &ty);
+                    param_tys.push(ty);
+                }
+                let ret = match expected {
+                    Some(ret) => {
+                        self.check_expr(body, Some(&ret));
+                        ret
+                    }
+                    None => self.check_expr(body, None),
+                };
+                Type::func(param_tys, ret)
+            }
+            ExprKind::Case(scrutinee, arms) => {
+                let ty = self.check_expr(scrutinee, None);
+                let ty = self.check_case(expr, arms, ty);
+                let mut result = expected.cloned();
+                for arm in arms {
+                    self.bind_pattern(&arm.pattern, &ty);
+                    let arm_ty = self.check_expr(&arm.body, result.as_ref());
+                    if result.is_none() || result == Some(Type::Error) {
+                        result = Some(arm_ty);
+                    }
+                }
+                result.unwrap_or(Type::Error)
+            }
+            ExprKind::Error => Type::Error,
+        }
+    }
+
+    fn name_type(&mut self, expr: &Expr) -> Type {
+        let id = match self.res.names.get(&expr.id) {
+            Some(id) => *id,
+            // Unknown names were reported by the resolver.
+            None => return Type::Error,
+        };
+        if let Some(scheme) = self.locals.get(&id).cloned() {
+            return self.infer.instantiate(&scheme);
+        }
+        let def = self.res.def(id);
+        match def.kind {
+            DefKind::Func => match self.funcs.get(&def.name.name) {
+                Some(sig) => {
+                    let params = sig.params.iter().map(|p| p.clone().unwrap_or(Type::Error)).collect();
+                    let ty = self.infer.zonk(&Type::func(params, sig.ret.clone().unwrap_or(Type::Error)));
+                    let ty_params = sig.ty_params.clone();
+                    let args: Vec<Type> = ty_params.iter().map(|_| self.infer.fresh()).collect();
+                    subst_params(&ty, &ty_params, &args)
+                }
+                None => Type::Error,
+            },
+            DefKind::Const => match self.consts.get(&def.name.name).cloned() {
+                Some(scheme) => self.infer.instantiate(&scheme),
+                None => Type::Error,
+            },
+            DefKind::Variant => match self.variants.get(&def.name.name) {
+                Some(&(sum, index)) => {
+                    let args = self.fresh_args(sum);
+                    Type::func(self.variant_fields(sum, index, &args), Type::Sum(sum, args))
+                }
+                None => Type::Error,
+            },
+            // Without loaded modules imports are placeholders.
+            DefKind::Import => {
+                let scheme = self.res.imports.get(&id).and_then(|(module, name)| self.interfaces?.get(module)?.values.get(name));
+                match scheme {
+                    Some(scheme) => self.infer.instantiate_foreign(scheme),
+                    None => Type::Error,
+                }
+            }
+            DefKind::Module | DefKind::Param | DefKind::Local => Type::Error,
+        }
+    }
+
+    fn check_binary(&mut self, expr: &Expr, op: BinaryOp, l: &Expr, r: &Expr) -> Type {
+        let ty = self.check_expr(l, None);
+        self.check_expr(r, known(&ty));
+        let span = expr.span;
+        let ok = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                self.constrain(span, expr.id, op.as_str(), &ty, OpClass::Trait(Trait::Num))
+            }
+            BinaryOp::Mod => self.constrain(span, expr.id, op.as_str(), &ty, OpClass::Remainder),
+            BinaryOp::BitAnd | BinaryOp::BitOr | BinaryOp::BitXor | BinaryOp::Shl | BinaryOp::Shr => {
+                self.require(span, op.as_str(), &ty, &Type::Int)
+            }
+            BinaryOp::Eq | BinaryOp::NotEq => self.constrain(span, expr.id, op.as_str(), &ty, OpClass::Trait(Trait::Eq)),
+            BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq => {
+                self.constrain(span, expr.id, op.as_str(), &ty, OpClass::Trait(Trait::Ord))
+            }
+            BinaryOp::And | BinaryOp::Or => self.require(span, op.as_str(), &ty, &Type::Bool),
+        };
+        match op {
+            BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq
+            | BinaryOp::And | BinaryOp::Or => Type::Bool,
+            _ if ok => ty,
+            _ => Type::Error,
+        }
+    }
+
+    // Records the type of every name bound by a pattern matching a value of
+    // type `ty`. Mismatches were reported when the pattern was checked.
+    fn bind_pattern(&mut self, pattern: &Pattern, ty: &Type) {
+        let ty = &self.infer.zonk(ty);
+        match &pattern.kind {
+            PatternKind::Binding(_) => self.bind_scheme(pattern, Scheme::mono(ty.clone())),
+            PatternKind::Tuple(elems) => {
+                for (i, elem) in elems.iter().enumerate() {
+                    let ty = match ty {
+                        Type::Tuple(tys) if tys.len() == elems.len() => tys[i].clone(),
+                        _ => Type::Error,
+                    };
+                    self.bind_pattern(elem, &ty);
+                }
+            }
+            PatternKind::Struct(name, fields) => {
+                let name = match (name, ty) {
+                    (Some(name), _) => Some(name.name),
+                    (None, Type::Struct(name, _)) => Some(*name),
+                    _ => None,
+                };
+                let struct_fields = match name {
+                    Some(name) if self.structs.contains_key(&name) => self.struct_fields(name, &self.type_args(Some(ty), name)),
+                    _ => vec![],
+                };
+                for (i, field) in fields.iter().enumerate() {
+                    let ty = match &field.name {
+                        Some(field_name) => struct_fields.iter().find(|(f, _)| f.name == field_name.name),
+                        None => struct_fields.get(i),
+                    };
+                    let ty = ty.map(|(_, ty)| ty.clone()).unwrap_or(Type::Error);
+                    self.bind_pattern(&field.pattern, &ty);
+                }
+            }
+            PatternKind::Variant(name, args) => {
+                let fields = match self.variants.get(&name.name) {
+                    Some(&(sum, index)) => self.variant_fields(sum, index, &self.type_args(Some(ty), sum)),
+                    None => vec![],
+                };
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = if fields.len() == args.len() { fields[i].clone() } else { Type::Error };
+                    self.bind_pattern(arg, &ty);
+                }
+            }
+            PatternKind::Wildcard | PatternKind::Int(_) | PatternKind::Char(_) | PatternKind::Str(_)
+            | PatternKind::Bool(_) => {}
+        }
+    }
+
+    // Returns the type of the literal, whose type arguments come from the
+    // expected type or are inferred from the fields.
+    fn check_struct_lit(&mut self, expr: &Expr, lit: &StructLit, expected: Option<&Type>) -> Type {
+        let (name, args) = match (&lit.name, expected) {
+            (Some(name), _) => {
+                if !self.structs.contains_key(&name.name) {
+                    self.errors.push(Diagnostic::error(name.span, format!("'{}' is not a struct", self.name(name.name))));
+                    self.check_fields_unexpected(lit);
+                    return Type::Error;
+                }
+                let args = self.fresh_args(name.name);
+                let ty = Type::Struct(name.name, args.clone());
+                if let Some(expected) = expected {
+                    if !self.infer.unify(expected, &ty) {
+                        self.errors.push(Diagnostic::error(
+                            expr.span,
+                            format!("Mismatched types: expected {}, found {}", self.type_str(expected), self.type_str(&ty)),
+                        ));
+                    }
+                }
+                (name.name, args)
+            }
+            (None, Some(Type::Struct(name, args))) => (*name, args.clone()),
+            (None, Some(Type::Error)) => {
+                self.check_fields_unexpected(lit);
+                return Type::Error;
+            }
+            (None, Some(expected)) => {
+                self.errors.push(Diagnostic::error(
+                    expr.span,
+                    format!("Mismatched types: expected {}, found a struct literal", self.type_str(expected)),
+                ));
+                self.check_fields_unexpected(lit);
+                return Type::Error;
+            }
+            (None, None) => {
+                self.errors.push(
+                    Diagnostic::error(expr.span, "Cannot infer the type of this struct literal")
+                        .with_note("name the struct in front of the braces, e.g. `Vector {x, y}`"),
+                );
+                self.check_fields_unexpected(lit);
+                return Type::Error;
+            }
+        };
+        self.results.struct_lits.insert(expr.id, name);
+        let fields = self.struct_fields(name, &args);
+        let struct_name = self.name(name);
+
+        if lit.is_positional() {
+            for (i, field) in lit.fields.iter().enumerate() {
+                self.check_expr(&field.expr, fields.get(i).map(|(_, ty)| ty));
+            }
+            if lit.fields.len() < fields.len() {
+                let missing: Vec<&str> = fields[lit.fields.len()..].iter().map(|(f, _)| self.name(f.name)).collect();
+                self.errors.push(self.missing_fields(expr, &missing, struct_name));
+            } else if lit.fields.len() > fields.len() {
+                self.errors.push(Diagnostic::error(
+                    lit.fields[fields.len()].span,
+                    format!(
+                        "Too many fields in struct literal of type '{}': expected {}, found {}",
+                        struct_name,
+                        fields.len(),
+                        lit.fields.len()
+                    ),
+                ));
+            }
+        } else if lit.is_named() {
+            let mut seen: Vec<(Symbol, FieldInit)> = vec![];
+            for field in &lit.fields {
+                let field_name = field.name.unwrap();
+                let ty = fields.iter().find(|(f, _)| f.name == field_name.name).map(|(_, ty)| ty.clone());
+                self.check_expr(&field.expr, ty.as_ref());
+                if seen.iter().any(|(s, _)| *s == field_name.name) {
+                    self.errors.push(Diagnostic::error(
+                        field_name.span,
+                        format!("Field '{}' specified more than once", self.name(field_name.name)),
+                    ));
+                } else if ty.is_none() {
+                    self.errors.push(Diagnostic::error(
+                        field_name.span,
+                        format!("Struct '{}' has no field named '{}'", struct_name, self.name(field_name.name)),
+                    ));
+                } else {
+                    seen.push((field_name.name, field.clone()));
+                }
+            }
+            let missing: Vec<&str> = fields
+                .iter()
+                .filter(|(f, _)| !seen.iter().any(|(s, _)| *s == f.name))
+                .map(|(f, _)| self.name(f.name))
+                .collect();
+            if !missing.is_empty() {
+                self.errors.push(self.missing_fields(expr, &missing, struct_name));
+            }
+        } else {
+            // Mixed forms were already reported by the parser.
+            self.check_fields_unexpected(lit);
+        }
+        Type::Struct(name, args)
+    }
+
+    pub fn sum_env(&self) -> SumEnv {
+        let mut env = SumEnv::new();
+        for (name, sum) in &self.sums {
+            let variants = sum
+                .variants
+                .iter()
+                .map(|(v, fields)| VariantInfo { name: v.name, arity: fields.len() })
+                .collect();
+            env.insert(*name, variants);
+        }
+        env
+    }
+
+    // Type a pattern matches, with fresh arguments for generic types.
+    fn pattern_type(&mut self, pattern: &Pattern) -> Option<Type> {
+        match &pattern.kind {
+            PatternKind::Wildcard | PatternKind::Binding(_) | PatternKind::Tuple(_) => None,
+            PatternKind::Struct(None, _) => None,
+            PatternKind::Struct(Some(name), _) if self.structs.contains_key(&name.name) => {
+                Some(Type::Struct(name.name, self.fresh_args(name.name)))
+            }
+            PatternKind::Struct(Some(_), _) => None,
+            PatternKind::Int(_) => Some(Type::Int),
+            PatternKind::Char(_) => Some(Type::Char),
+            PatternKind::Str(_) => Some(Type::Str),
+            PatternKind::Bool(_) => Some(Type::Bool),
+            PatternKind::Variant(name, _) => {
+                let sum = self.variants.get(&name.name)?.0;
+                Some(Type::Sum(sum, self.fresh_args(sum)))
+            }
+        }
+    }
+
+    fn arms_type(&mut self, arms: &[CaseArm]) -> Option<Type> {
+        for arm in arms {
+            if let Some(ty) = self.pattern_type(&arm.pattern) {
+                return Some(ty);
+            }
+        }
+        None
+    }
+
+    fn bind_scheme(&mut self, pattern: &Pattern, scheme: Scheme) {
+        self.results.node_types.insert(pattern.id, scheme.ty.clone());
+        if let Some(id) = self.res.names.get(&pattern.id) {
+            self.locals.insert(*id, scheme);
+        }
+    }
+
+    // Checks a pattern against the type of the value it matches and converts it
+    // for the match analysis. Ill-typed patterns become wildcards once reported.
+    fn lower_pattern(&mut self, pattern: &Pattern, expected: Option<&Type>) -> Pat {
+        let mut expected = expected.map(|ty| self.infer.zonk(ty));
+        if let Some(var @ Type::Var(_)) = &expected {
+            // The pattern itself tells the type of an unknown value.
+            let ty = match &pattern.kind {
+                PatternKind::Tuple(elems) => Some(Type::Tuple(elems.iter().map(|_| self.infer.fresh()).collect())),
+                _ => self.pattern_type(pattern),
+            };
+            if let Some(ty) = &ty {
+                self.infer.unify(var, ty);
+            }
+            expected = ty;
+        }
+        let expected = expected.as_ref();
+        let found = self.pattern_type(pattern);
+        if let (Some(expected), Some(found)) = (expected, &found) {
+            if !self.infer.unify(expected, found) {
+                self.errors.push(Diagnostic::error(
+                    pattern.span,
+                    format!("Mismatched types: expected {}, found pattern of type {}", self.type_str(expected), self.type_str(found)),
+                ));
+                return Pat::Wild(None);
+            }
+        }
+        match &pattern.kind {
+            PatternKind::Wildcard => Pat::Wild(None),
+            PatternKind::Binding(name) => Pat::Wild(Some(*name)),
+            PatternKind::Int(val) => Pat::Ctor(Ctor::Int(*val), vec![]),
+            PatternKind::Char(val) => Pat::Ctor(Ctor::Char(*val), vec![]),
+            PatternKind::Str(val) => Pat::Ctor(Ctor::Str(val.clone()), vec![]),
+            PatternKind::Bool(val) => Pat::Ctor(Ctor::Bool(*val), vec![]),
+            PatternKind::Variant(name, args) => {
+                let (sum, index) = match self.variants.get(&name.name) {
+                    Some(variant) => *variant,
+                    None => {
+                        self.errors.push(Diagnostic::error(name.span, format!("Unknown constructor '{}'", self.name(name.name))));
+                        return Pat::Wild(None);
+                    }
+                };
+                let fields = self.variant_fields(sum, index, &self.type_args(expected, sum));
+                if fields.len() != args.len() {
+                    let plural = if fields.len() == 1 { "" } else { "s" };
+                    self.errors.push(Diagnostic::error(
+                        pattern.span,
+                        format!(
+                            "Constructor '{}' has {} field{} but the pattern has {}",
+                            self.name(name.name),
+                            fields.len(),
+                            plural,
+                            args.len()
+                        ),
+                    ));
+                    return Pat::Wild(None);
+                }
+                let args = args.iter().zip(fields.iter()).map(|(arg, ty)| self.lower_pattern(arg, Some(ty))).collect();
+                Pat::Ctor(Ctor::Variant(sum, index), args)
+            }
+            PatternKind::Tuple(elems) => {
+                let tys = match expected {
+                    Some(Type::Tuple(tys)) if tys.len() == elems.len() => Some(tys.clone()),
+                    None | Some(Type::Error) => None,
+                    Some(expected @ Type::Tuple(_)) => {
+                        self.errors.push(Diagnostic::error(
+                            pattern.span,
+                            format!("Mismatched types: expected {}, found a tuple of {} elements", self.type_str(expected), elems.len()),
+                        ));
+                        return Pat::Wild(None);
+                    }
+                    Some(expected) => {
+                        self.errors.push(Diagnostic::error(
+                            pattern.span,
+                            format!("Mismatched types: expected {}, found a tuple pattern", self.type_str(expected)),
+                        ));
+                        return Pat::Wild(None);
+                    }
+                };
+                let args = elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, elem)| self.lower_pattern(elem, tys.as_ref().map(|tys| &tys[i])))
+                    .collect();
+                Pat::Ctor(Ctor::Tuple(elems.len()), args)
+            }
+            PatternKind::Struct(name, fields) => self.lower_struct_pattern(pattern, name, fields, expected),
+        }
+    }
+
+    fn lower_struct_pattern(&mut self, pattern: &Pattern, name: &Option<Ident>, fields: &[FieldPattern], expected: Option<&Type>) -> Pat {
+        let name = match (name, expected) {
+            (Some(name), _) => {
+                if !self.structs.contains_key(&name.name) {
+                    self.errors.push(Diagnostic::error(name.span, format!("'{}' is not a struct", self.name(name.name))));
+                    return Pat::Wild(None);
+                }
+                name.name
+            }
+            (None, Some(Type::Struct(name, _))) => *name,
+            (None, Some(Type::Error)) => return Pat::Wild(None),
+            (None, Some(expected)) => {
+                self.errors.push(Diagnostic::error(
+                    pattern.span,
+                    format!("Mismatched types: expected {}, found a struct pattern", self.type_str(expected)),
+                ));
+                return Pat::Wild(None);
+            }
+            (None, None) => {
+                self.errors.push(
+                    Diagnostic::error(pattern.span, "Cannot infer the type of this struct pattern")
+                        .with_note("name the struct in front of the braces, e.g. `Vector {x, y}`"),
+                );
+                return Pat::Wild(None);
+            }
+        };
+        self.results.struct_lits.insert(pattern.id, name);
+        let struct_fields = self.struct_fields(name, &self.type_args(expected, name));
+        let struct_name = self.name(name);
+        let mut args = vec![Pat::Wild(None); struct_fields.len()];
+        if fields.iter().all(|f| f.name.is_none()) {
+            if fields.len() != struct_fields.len() {
+                self.errors.push(Diagnostic::error(
+                    pattern.span,
+                    format!("Struct '{}' has {} fields but the pattern has {}", struct_name, struct_fields.len(), fields.len()),
+                ));
+                return Pat::Wild(None);
+            }
+            for (i, field) in fields.iter().enumerate() {
+                args[i] = self.lower_pattern(&field.pattern, Some(&struct_fields[i].1));
+            }
+        } else if fields.iter().all(|f| f.name.is_some()) {
+            // Fields left out of a named pattern match anything.
+            let mut seen: Vec<Symbol> = vec![];
+            for field in fields {
+                let field_name = field.name.unwrap();
+                let index = struct_fields.iter().position(|(f, _)| f.name == field_name.name);
+                if seen.contains(&field_name.name) {
+                    self.errors.push(Diagnostic::error(
+                        field_name.span,
+                        format!("Field '{}' specified more than once", self.name(field_name.name)),
+                    ));
+                } else if let Some(index) = index {
+                    seen.push(field_name.name);
+                    args[index] = self.lower_pattern(&field.pattern, Some(&struct_fields[index].1));
+                } else {
+                    self.errors.push(Diagnostic::error(
+                        field_name.span,
+                        format!("Struct '{}' has no field named '{}'", struct_name, self.name(field_name.name)),
+                    ));
+                }
+            }
+        } else {
+            // Mixed forms were already reported by the parser.
+            return Pat::Wild(None);
+        }
+        Pat::Ctor(Ctor::Struct(name, struct_fields.len()), args)
+    }
+
+    // Parameters and let bindings can't fail to match: their pattern has to
+    // cover every value of the declared type.
+    fn check_irrefutable(&mut self, pattern: &Pattern, ty: Option<&Type>, what: &str) {
+        if ty.is_none() && matches!(pattern.kind, PatternKind::Struct(None, _)) {
+            return;
+        }
+        let error_count = self.errors.len();
+        let pat = self.lower_pattern(pattern, ty);
+        if self.errors.len() != error_count {
+            return;
+        }
+        let env = self.sum_env();
+        if let Some(missing) = check_match(&[pat], &env).missing {
+            self.errors.push(Diagnostic::error(
+                pattern.span,
+                format!("Refutable pattern in {}: `{}` not covered", what, pat_to_string(&missing, &env, self.interner)),
+            ));
+        }
+    }
+
+    // Checks the arm patterns against the scrutinee type and returns the type
+    // they match. An ill-typed scrutinee takes the type of the first pattern.
+    fn check_case(&mut self, expr: &Expr, arms: &[CaseArm], scrutinee_type: Type) -> Type {
+        let scrutinee_type = match self.infer.zonk(&scrutinee_type) {
+            Type::Error => self.arms_type(arms).unwrap_or(Type::Error),
+            ty @ Type::Var(_) => match self.arms_type(arms) {
+                Some(found) => {
+                    self.infer.unify(&ty, &found);
+                    found
+                }
+                None => ty,
+            },
+            ty => ty,
+        };
+        let error_count = self.errors.len();
+        let pats: Vec<Pat> = arms.iter().map(|arm| self.lower_pattern(&arm.pattern, Some(&scrutinee_type))).collect();
+        // Ill-typed patterns were replaced by wildcards, which would only
+        // produce bogus reachability warnings.
+        if self.errors.len() != error_count {
+            return scrutinee_type;
+        }
+        let env = self.sum_env();
+        let check = check_match(&pats, &env);
+        for i in check.redundant {
+            self.errors.push(Diagnostic::warning(arms[i].pattern.span, "Unreachable pattern"));
+        }
+        if let Some(missing) = check.missing {
+            self.errors.push(Diagnostic::error(
+                expr.span,
+                format!("Non-exhaustive patterns: `{}` not covered", pat_to_string(&missing, &env, self.interner)),
+            ));
+        }
+        self.results.case_trees.insert(expr.id, compile_match(&pats, &env));
+        scrutinee_type
+    }
+
+    fn missing_fields(&self, expr: &Expr, missing: &[&str], struct_name: &str) -> Diagnostic {
+        let plural = if missing.len() > 1 { "s" } else { "" };
+        Diagnostic::error(
+            expr.span,
+            format!("Missing field{} {} in struct literal of type '{}'", plural, quote_names(missing), struct_name),
+        )
+    }
+
+    fn check_fields_unexpected(&mut self, lit: &StructLit) {
+        for field in &lit.fields {
+            self.check_expr(&field.expr, None);
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn check_module(module: &Module, res: &Resolutions, interner: &Interner) -> (TypeckResults, Vec<Diagnostic>) {
+    let mut checker = Checker::new(interner, res);
+    checker.check_module(module);
+    (checker.results, checker.errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_module;
+    use crate::resolve::resolve_module;
+
+    const STRUCTS: &str = "
+struct Vector =
+    x, y: float
+
+struct Rect =
+    pos, size: Vector
+";
+
+    fn check(src: &str) -> (TypeckResults, Vec<Diagnostic>, Interner) {
+        let mut interner = Interner::new();
+        let src = format!("{}{}", STRUCTS, src);
+        let (module, errors) = parse_module(&src, &mut interner);
+        assert!(errors.is_empty());
+        let (res, errors) = resolve_module(&module, &interner);
+        assert!(errors.is_empty());
+        let (results, errors) = check_module(&module, &res, &interner);
+        for err in &errors {
+            println!("{:?}", err);
+        }
+        (results, errors, interner)
+    }
+
+    #[test]
+    fn test_positional_and_named_forms() {
+        let (results, errors, interner) = check("
+make_rect min, max: Vector -> Rect
+    Rect {{min.x, min.y}, {max.x - min.x, max.y - min.y}}
+
+make_rect2 min, max: Vector -> Rect
+    Rect {pos = {x = min.x, y = min.y}, size = {x = max.x - min.x, y = max.y - min.y}}
+");
+        assert!(errors.is_empty());
+        let vector = interner.lookup("Vector").unwrap();
+        let rect = interner.lookup("Rect").unwrap();
+        assert!(results.struct_lits.values().filter(|s| **s == vector).count() == 4);
+        assert!(results.struct_lits.values().filter(|s| **s == rect).count() == 2);
+    }
+
+    #[test]
+    fn test_contextual_typing() {
+        let (results, errors, _) = check("
+origin -> Vector
+    {0.0, 0.0}
+
+length v: Vector -> float
+    v.x
+
+const UNIT: Rect = {{0.0, 0.0}, {1.0, 1.0}}
+
+far -> float
+    let
+        v: Vector = {x = 1.0, y = 1.0}
+    in
+    length {2.0, 2.0}
+");
+        assert!(errors.is_empty());
+        assert!(results.struct_lits.len() == 6);
+    }
+
+    #[test]
+    fn test_missing_and_extra_fields() {
+        let (_, errors, _) = check("
+a -> Vector
+    Vector {1.0}
+
+b -> Vector
+    Vector {1.0, 2.0, 3.0}
+
+c -> Rect
+    Rect {pos = {x = 1.0}}
+");
+        assert!(errors.len() == 4);
+        assert!(errors[0].msg == "Missing field 'y' in struct literal of type 'Vector'");
+        assert!(errors[1].msg == "Too many fields in struct literal of type 'Vector': expected 2, found 3");
+        assert!(errors[2].msg == "Missing field 'y' in struct literal of type 'Vector'");
+        assert!(errors[3].msg == "Missing field 'size' in struct literal of type 'Rect'");
+    }
+
+    #[test]
+    fn test_duplicate_and_unknown_fields() {
+        let (_, errors, _) = check("
+a -> Vector
+    Vector {x = 1.0, x = 2.0, z = 3.0}
+");
+        assert!(errors.len() == 3);
+        assert!(errors[0].msg == "Field 'x' specified more than once");
+        assert!(errors[1].msg == "Struct 'Vector' has no field named 'z'");
+        assert!(errors[2].msg == "Missing field 'y' in struct literal of type 'Vector'");
+    }
+
+    #[test]
+    fn test_uninferable_and_mismatched_literals() {
+        let (_, errors, _) = check("
+const A = {1.0, 2.0}
+
+b -> Rect
+    Vector {1.0, 2.0}
+
+c -> float
+    {1.0}
+
+d -> Vector
+    Foo {1.0}
+");
+        assert!(errors.len() == 4);
+        assert!(errors[0].msg == "Cannot infer the type of this struct literal");
+        assert!(errors[1].msg == "Mismatched types: expected Rect, found Vector");
+        assert!(errors[2].msg == "Mismatched types: expected float, found a struct literal");
+        assert!(errors[3].msg == "'Foo' is not a struct");
+    }
+
+    const TOKENS: &str = "
+type TokenKind = FLOAT | INT | OTHER: char
+
+type Shape =
+    Circle: float
+    Pair: TokenKind, TokenKind
+";
+
+    #[test]
+    fn test_exhaustive_case() {
+        let (results, errors, _) = check(&format!("{}{}", TOKENS, "
+describe k: TokenKind -> string
+    case k of
+        FLOAT -> \"float\"
+        INT -> \"int\"
+        OTHER 'a' -> \"a\"
+        OTHER c -> \"other\"
+"));
+        assert!(errors.is_empty());
+        assert!(results.case_trees.len() == 1);
+    }
+
+    #[test]
+    fn test_non_exhaustive_and_redundant_case() {
+        let (_, errors, _) = check(&format!("{}{}", TOKENS, "
+a k: TokenKind -> int
+    case k of
+        FLOAT -> 1
+        OTHER _ -> 2
+
+b s: Shape -> int
+    case s of
+        Pair FLOAT _ -> 1
+        Pair _ INT -> 2
+        Pair FLOAT INT -> 3
+        Circle _ -> 4
+
+c n: int -> int
+    case n of
+        0 -> 1
+        -1 -> 2
+"));
+        assert!(errors.len() == 4);
+        assert!(errors[0].msg == "Non-exhaustive patterns: `INT` not covered");
+        assert!(errors[1].msg == "Unreachable pattern");
+        assert!(errors[1].level == crate::diagnostic::Level::Warning);
+        assert!(errors[2].msg == "Non-exhaustive patterns: `Pair INT FLOAT` not covered");
+        assert!(errors[3].msg == "Non-exhaustive patterns: `_` not covered");
+    }
+
+    #[test]
+    fn test_ill_typed_patterns() {
+        let (_, errors, _) = check(&format!("{}{}", TOKENS, "
+a k: TokenKind -> int
+    case k of
+        FLOAT -> 1
+        Circle 1 -> 2
+        OTHER -> 3
+        OTHER 1 -> 4
+        Unknown -> 5
+        _ -> 6
+"));
+        assert!(errors.len() == 4);
+        assert!(errors[0].msg == "Mismatched types: expected TokenKind, found pattern of type Shape");
+        assert!(errors[1].msg == "Constructor 'OTHER' has 1 field but the pattern has 0");
+        assert!(errors[2].msg == "Mismatched types: expected char, found pattern of type int");
+        assert!(errors[3].msg == "Unknown constructor 'Unknown'");
+    }
+
+    #[test]
+    fn test_destructuring_patterns() {
+        let (_, errors, _) = check(&format!("{}{}", TOKENS, "
+make_vect {a, b} {c, d}: Vector -> Vector
+    {c - a, d - b}
+
+corner Rect {pos = {x, y}}: Rect -> float = x
+
+swap (x, y): (int, float) -> (float, int) = (y, x)
+
+first (x, _, _): (int, int) -> int = x
+
+height {x, y, z}: Vector -> float = z
+
+kind FLOAT: TokenKind -> int = 1
+
+nested (p, Circle r): (int, Shape) -> float = r
+
+sum r: Rect -> float
+    let
+        {w, h}: Vector = r.size
+        (i, OTHER c): (int, TokenKind) = (1, OTHER 'a')
+        Vector {y = v, y = u}: Vector = r.pos
+    in
+    w + h
+"));
+        assert!(errors.len() == 6);
+        assert!(errors[0].msg == "Mismatched types: expected (int, int), found a tuple of 3 elements");
+        assert!(errors[1].msg == "Struct 'Vector' has 2 fields but the pattern has 3");
+        assert!(errors[2].msg == "Refutable pattern in function parameter: `INT` not covered");
+        assert!(errors[3].msg == "Refutable pattern in function parameter: `(_, Pair _ _)` not covered");
+        assert!(errors[4].msg == "Refutable pattern in let binding: `(_, FLOAT)` not covered");
+        assert!(errors[5].msg == "Field 'y' specified more than once");
+    }
+
+    #[test]
+    fn test_function_signatures() {
+        let (_, errors, _) = check("
+const PI = 3.14
+
+multiply x, y: int -> int
+    x * y
+
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+add_one x: int -> int
+    let
+        y: int = 1
+    in
+    x + y
+
+area r: float -> float = PI * r * r
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+double -> int -> int = multiply 2
+
+quad x: int -> int = twice double x
+");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_type_mismatches() {
+        let (_, errors, _) = check("
+const PI = 3.14
+
+a x, y: int -> int
+    x * PI
+
+b x: int -> bool
+    if x then 1 else 2
+
+c x: int -> string
+    multiply x 2.0
+
+d x: int -> int
+    multiply x 2 3
+
+e v: Vector -> float
+    v.z + -true
+
+f x = x
+
+multiply x, y: int -> int = x * y
+
+g n: int -> int
+    let
+        s = `n is ${n}`
+    in
+    s
+");
+        assert!(errors.len() == 11);
+        assert!(errors[0].msg == "Mismatched types: expected int, found float");
+        assert!(errors[1].msg == "Mismatched types: expected bool, found int");
+        assert!(errors[2].msg == "Mismatched types: expected bool, found int");
+        assert!(errors[3].msg == "Mismatched types: expected bool, found int");
+        assert!(errors[4].msg == "Mismatched types: expected int, found float");
+        assert!(errors[5].msg == "Mismatched types: expected string, found int");
+        assert!(errors[6].msg == "Too many arguments: expected 2, found 3");
+        assert!(errors[7].msg == "No field 'z' on type Vector");
+        assert!(errors[8].msg == "Cannot apply '-' to type bool");
+        assert!(errors[9].msg == "Missing type annotation for parameter");
+        assert!(errors[10].msg == "Mismatched types: expected int, found string");
+    }
+
+    fn binding_type(module: &Module, results: &TypeckResults, func: usize) -> Type {
+        let body = match &module.decls[func].kind {
+            DeclKind::Func(func) => &func.body,
+            _ => unreachable!(),
+        };
+        match &body.kind {
+            ExprKind::Let(bindings, _) => results.node_types[&bindings[0].pattern.id].clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_int_literal_range() {
+        let (_, errors, _) = check("
+a -> int
+    9223372036854775807 + -9223372036854775808
+
+b -> int
+    - 9223372036854775808
+");
+        assert!(errors.is_empty());
+        let (_, errors, _) = check("
+f x: int -> int
+    x
+
+a -> int
+    f 9223372036854775808
+
+b -> int
+    f 18446744073709551615
+");
+        assert!(errors.len() == 2);
+        assert!(errors.iter().all(|e| e.msg == "Integer literal out of range"));
+        assert!(errors[0].notes == vec!["an int is between -9223372036854775808 and 9223372036854775807"]);
+    }
+
+    #[test]
+    fn test_infer_let_bindings_and_lambdas() {
+        let mut interner = Interner::new();
+        let src = "
+add_one x: int -> int
+    let
+        y = 1
+    in
+    x + y
+
+poly x: int -> int
+    let
+        id = \\a -> a
+    in
+    if id true then id x else 0
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+six -> int = twice (\\x -> x * 2) 1
+
+corner -> float
+    let
+        pos_x = \\r -> r.pos.x
+    in
+    pos_x (Rect {{1.0, 2.0}, {3.0, 4.0}})
+
+main = six + 1
+";
+        let src = format!("{}{}", STRUCTS, src);
+        let (module, errors) = parse_module(&src, &mut interner);
+        assert!(errors.is_empty());
+        let (res, errors) = resolve_module(&module, &interner);
+        assert!(errors.is_empty());
+        let (results, errors) = check_module(&module, &res, &interner);
+        println!("{:?}", errors);
+        assert!(errors.is_empty());
+        assert!(binding_type(&module, &results, 2) == Type::Int);
+        let id = binding_type(&module, &results, 3);
+        match &id {
+            Type::Func(a, b) => assert!(matches!(**a, Type::Var(_)) && a == b),
+            _ => panic!("{:?}", id),
+        }
+        let pos_x = binding_type(&module, &results, 6);
+        assert!(pos_x == Type::func(vec![Type::Struct(interner.lookup("Rect").unwrap(), vec![])], Type::Float));
+        match &module.decls[7].kind {
+            DeclKind::Func(main) => assert!(results.node_types[&main.body.id] == Type::Int),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_inference_errors() {
+        let (_, errors, _) = check("
+a -> int
+    let
+        inc = \\x -> x + 1
+    in
+    inc 1.0
+
+b -> int
+    let
+        pair = \\f -> (f 1, f true)
+    in
+    0
+
+c -> int
+    let
+        loop = \\x -> x x
+    in
+    0
+
+d -> bool
+    let
+        neg = \\x -> -x
+    in
+    neg true
+
+e -> int
+    let
+        get = \\v -> v.foo
+    in
+    0
+");
+        assert!(errors.len() == 5);
+        assert!(errors[0].msg == "Mismatched types: expected int, found float");
+        assert!(errors[1].msg == "Mismatched types: expected int, found bool");
+        assert!(errors[2].msg.starts_with("Mismatched types"));
+        assert!(errors[3].msg == "Cannot apply '-' to type bool");
+        assert!(errors[4].msg == "Cannot infer the type of this value");
+    }
+
+    #[test]
+    fn test_operator_instances() {
+        let (results, errors, interner) = check("
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    sub a, b = Vector {a.x - b.x, a.y - b.y}
+    mul a, b: Vector -> Vector = Vector {a.x * b.x, a.y * b.y}
+    div a, b = Vector {a.x / b.x, a.y / b.y}
+    neg v = Vector {-v.x, -v.y}
+
+instance Ord Vector =
+    lt a, b = a.x < b.x || a.x == b.x && a.y < b.y
+
+instance Show Vector =
+    show v = `(${v.x}, ${v.y})`
+
+midpoint v1, v2: Vector -> Vector
+    (v1 + v2) / {2.0, 2.0}
+
+closest v1, v2: Vector -> string
+    let
+        d = \\v -> -v
+    in
+    if v1 < v2 && v1 != d v2 then `${v1}` else `${(v2, 1)}`
+");
+        assert!(errors.is_empty());
+        let vector = interner.lookup("Vector").unwrap();
+        assert!(results.instances.len() == 3);
+        let dispatched = |t: Trait| results.overloads.values().filter(|o| **o == (t, vector)).count();
+        // `+ /` in midpoint, `-` in the lambda.
+        assert!(dispatched(Trait::Num) == 3);
+        assert!(dispatched(Trait::Ord) == 1);
+        // `${v1}`; the tuple has a derived instance.
+        assert!(dispatched(Trait::Show) == 1);
+        // `!=` and the ones on floats use builtin instances.
+        assert!(dispatched(Trait::Eq) == 0);
+    }
+
+    #[test]
+    fn test_instance_errors() {
+        let (_, errors, _) = check(&format!("{}{}", TOKENS, "
+instance Num Vector =
+    add a, b = a
+    sub a: int, b = a
+    mul a = a
+    neg v = v
+    neg v = v
+    dot a, b = 0.0
+
+instance Num Vector =
+    neg v = v
+
+instance Eq int =
+    eq a, b = true
+
+instance Monoid Vector =
+    add a, b = a
+
+f t: TokenKind, v: Rect -> string
+    `${t}` + `${v < v}`
+
+g v: Vector -> float
+    let
+        s = v % v
+    in
+    0.0
+"));
+        assert!(errors[0].msg == "Conflicting instances of Num for type Vector");
+        assert!(errors[1].msg == "Instances can only be declared for non-generic struct and sum types, found int");
+        assert!(errors[2].msg == "Unknown trait 'Monoid'");
+        assert!(errors[3].msg == "Mismatched types: expected Vector, found int");
+        assert!(errors[4].msg == "Method 'mul' takes 2 parameters, found 1");
+        assert!(errors[5].msg == "Duplicate method 'neg'");
+        assert!(errors[6].msg == "Method 'dot' is not part of trait Num");
+        assert!(errors[7].msg == "Missing method 'div' in instance Num Vector");
+        assert!(errors[8].msg == "Cannot interpolate a value of type TokenKind");
+        assert!(errors[8].notes[0] == "add an instance: `instance Show TokenKind`");
+        assert!(errors[9].msg == "Cannot apply '<' to type Rect");
+        assert!(errors[10].msg == "Cannot apply '+' to type string");
+        assert!(errors[11].msg == "Cannot apply '%' to type Vector");
+        assert!(errors.len() == 12);
+    }
+
+}
@@ -0,0 +1,160 @@
+use crate::common::{Interner, Symbol};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Char,
+    Str,
+    Bool,
+    // Named types with their type arguments, empty unless generic.
+    Struct(Symbol, Vec<Type>),
+    Sum(Symbol, Vec<Type>),
+    // Type parameter of the generic declaration being checked. Unlike
+    // inference variables it only equals itself.
+    Param(Symbol),
+    Func(Box<Type>, Box<Type>),
+    Tuple(Vec<Type>),
+    // Inference variable, solved by unification.
+    Var(u32),
+    // Produced after an error has been reported, compatible with everything so
+    // one mistake doesn't cascade.
+    Error,
+}
+
+// A type generalized over some of its variables, as given to let-bound
+// names: `let id = \x -> x` has the scheme `forall t0. t0 -> t0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheme {
+    pub vars: Vec<u32>,
+    pub ty: Type,
+}
+
+impl Scheme {
+    pub fn mono(ty: Type) -> Scheme {
+        Scheme { vars: vec![], ty }
+    }
+}
+
+impl Type {
+    pub fn func(params: Vec<Type>, ret: Type) -> Type {
+        params.into_iter().rev().fold(ret, |ret, param| Type::Func(Box::new(param), Box::new(ret)))
+    }
+}
+
+// Replaces type parameters by the matching arguments.
+pub fn subst_params(ty: &Type, params: &[Symbol], args: &[Type]) -> Type {
+    match ty {
+        Type::Param(name) => match params.iter().position(|p| p == name) {
+            Some(i) => args[i].clone(),
+            None => ty.clone(),
+        },
+        Type::Struct(name, tys) => Type::Struct(*name, tys.iter().map(|t| subst_params(t, params, args)).collect()),
+        Type::Sum(name, tys) => Type::Sum(*name, tys.iter().map(|t| subst_params(t, params, args)).collect()),
+        Type::Func(param, ret) => Type::Func(
+            Box::new(subst_params(param, params, args)),
+            Box::new(subst_params(ret, params, args)),
+        ),
+        Type::Tuple(elems) => Type::Tuple(elems.iter().map(|t| subst_params(t, params, args)).collect()),
+        _ => ty.clone(),
+    }
+}
+
+// Names the inference variables left in a type after type parameters, `a`,
+// `b`, ... in order of appearance, for showing it to users.
+pub fn name_vars(ty: &Type, vars: &mut Vec<u32>, interner: &mut Interner) -> Type {
+    match ty {
+        Type::Var(n) => {
+            let i = vars.iter().position(|v| v == n).unwrap_or_else(|| {
+                vars.push(*n);
+                vars.len() - 1
+            });
+            let name = if i < 26 { ((b'a' + i as u8) as char).to_string() } else { format!("a{}", i) };
+            Type::Param(interner.intern(&name))
+        }
+        Type::Struct(name, args) => Type::Struct(*name, args.iter().map(|t| name_vars(t, vars, interner)).collect()),
+        Type::Sum(name, args) => Type::Sum(*name, args.iter().map(|t| name_vars(t, vars, interner)).collect()),
+        Type::Func(param, ret) => Type::Func(
+            Box::new(name_vars(param, vars, interner)),
+            Box::new(name_vars(ret, vars, interner)),
+        ),
+        Type::Tuple(elems) => Type::Tuple(elems.iter().map(|t| name_vars(t, vars, interner)).collect()),
+        _ => ty.clone(),
+    }
+}
+
+pub fn builtin_type(name: &str) -> Option<Type> {
+    match name {
+        "int" => Some(Type::Int),
+        "float" => Some(Type::Float),
+        "char" => Some(Type::Char),
+        "string" => Some(Type::Str),
+        "bool" => Some(Type::Bool),
+        _ => None,
+    }
+}
+
+fn is_atom(ty: &Type) -> bool {
+    match ty {
+        Type::Struct(_, args) | Type::Sum(_, args) => args.is_empty(),
+        Type::Func(_, _) => false,
+        _ => true,
+    }
+}
+
+pub fn type_to_string(ty: &Type, interner: &Interner) -> String {
+    match ty {
+        Type::Int => String::from("int"),
+        Type::Float => String::from("float"),
+        Type::Char => String::from("char"),
+        Type::Str => String::from("string"),
+        Type::Bool => String::from("bool"),
+        Type::Struct(name, args) | Type::Sum(name, args) => {
+            let mut s = String::from(interner.get(*name));
+            for arg in args {
+                s.push(' ');
+                match arg {
+                    Type::Func(_, _) | Type::Struct(_, _) | Type::Sum(_, _) if !is_atom(arg) => {
+                        s.push_str(&format!("({})", type_to_string(arg, interner)))
+                    }
+                    _ => s.push_str(&type_to_string(arg, interner)),
+                }
+            }
+            s
+        }
+        Type::Param(name) => String::from(interner.get(*name)),
+        Type::Func(param, ret) => {
+            let param = match **param {
+                Type::Func(_, _) => format!("({})", type_to_string(param, interner)),
+                _ => type_to_string(param, interner),
+            };
+            format!("{} -> {}", param, type_to_string(ret, interner))
+        }
+        Type::Tuple(elems) => {
+            let elems: Vec<String> = elems.iter().map(|e| type_to_string(e, interner)).collect();
+            format!("({})", elems.join(", "))
+        }
+        Type::Var(n) => format!("t{}", n),
+        Type::Error => String::from("{error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_to_string() {
+        let mut interner = Interner::new();
+        let vector = Type::Struct(interner.intern("Vector"), vec![]);
+        let ty = Type::func(vec![Type::func(vec![Type::Int], Type::Int), vector], Type::Bool);
+        assert!(type_to_string(&ty, &interner) == "(int -> int) -> Vector -> bool");
+        let ty = Type::Tuple(vec![Type::Int, Type::func(vec![Type::Char], Type::Str)]);
+        assert!(type_to_string(&ty, &interner) == "(int, char -> string)");
+        let a = Type::Param(interner.intern("a"));
+        let list_sym = interner.intern("List");
+        let list = |ty| Type::Sum(list_sym, vec![ty]);
+        let ty = Type::func(vec![list(a.clone())], list(list(Type::func(vec![a], Type::Int))));
+        assert!(type_to_string(&ty, &interner) == "List a -> List (List (a -> int))");
+    }
+}
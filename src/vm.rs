@@ -0,0 +1,674 @@
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::ast::BinaryOp;
+use crate::bytecode::{Closure, Op, Program, Value};
+use crate::consteval::{binary_op, show_value, unary_op, ConstValue};
+use crate::diagnostic::Diagnostic;
+use crate::interp::{RuntimeError, MAX_DEPTH};
+use crate::modules::ModuleId;
+
+// Stack machine running compiled programs. Every call pushes a frame whose
+// slots live on the shared value stack; tail calls reuse the frame of the
+// caller, so only non-tail calls count towards `max_frames`. The output is
+// the same as the interpreter's for the same program.
+
+struct Frame {
+    func: u32,
+    ip: usize,
+    base: usize,
+    // Arguments beyond the arity of the function, applied to its result.
+    pending: Vec<Value>,
+}
+
+pub struct Vm<'a> {
+    program: &'a Program,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    pub max_frames: usize,
+}
+
+type Exec<T> = Result<T, RuntimeError>;
+
+// What calling a value with some arguments amounts to.
+enum Callee {
+    Enter(u32, Vec<Value>, Vec<Value>),
+    Partial(Value),
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a Program) -> Vm<'a> {
+        Vm {
+            program,
+            stack: vec![],
+            frames: vec![],
+            max_frames: MAX_DEPTH,
+        }
+    }
+
+    // Runs the main function, writing the program output to `out`.
+    pub fn run(&mut self, out: &mut dyn Write) -> Exec<()> {
+        self.call_sync(out, self.program.main, vec![])?;
+        Ok(())
+    }
+
+    fn error(&self, msg: &str) -> RuntimeError {
+        let (module, span) = match self.frames.last() {
+            Some(frame) => {
+                let func = &self.program.functions[frame.func as usize];
+                (func.module as usize, func.spans[frame.ip.saturating_sub(1)])
+            }
+            None => (0, Default::default()),
+        };
+        RuntimeError {
+            module: ModuleId(module),
+            diag: Diagnostic::error(span, msg),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn pop_n(&mut self, n: u32) -> Vec<Value> {
+        let at = self.stack.len() - n as usize;
+        self.stack.split_off(at)
+    }
+
+    // Slots of a function: the captured values, the arguments, then room
+    // for its bindings.
+    fn push_slots(&mut self, func: u32, args: Vec<Value>) {
+        let locals = self.program.functions[func as usize].locals as usize;
+        let base = self.stack.len();
+        self.stack.extend(args);
+        self.stack.resize(base + locals, Value::Bool(false));
+    }
+
+    fn push_frame(&mut self, func: u32, args: Vec<Value>, pending: Vec<Value>) -> Exec<()> {
+        if self.frames.len() >= self.max_frames {
+            return Err(self.error("Stack overflow: too many nested calls"));
+        }
+        let base = self.stack.len();
+        self.push_slots(func, args);
+        self.frames.push(Frame { func, ip: 0, base, pending });
+        Ok(())
+    }
+
+    fn resolve(&self, callee: Value, mut args: Vec<Value>) -> Callee {
+        let closure = match callee {
+            Value::Closure(closure) => closure,
+            _ => unreachable!("calling a non-function value"),
+        };
+        let arity = self.program.functions[closure.func as usize].arity as usize;
+        let mut all = closure.args.clone();
+        all.append(&mut args);
+        if all.len() < arity {
+            return Callee::Partial(Value::Closure(Rc::new(Closure {
+                func: closure.func,
+                captured: closure.captured.clone(),
+                args: all,
+            })));
+        }
+        let rest = all.split_off(arity);
+        let mut slots = closure.captured.clone();
+        slots.append(&mut all);
+        Callee::Enter(closure.func, slots, rest)
+    }
+
+    // Calls a value, either entering its function or pushing the partial
+    // application.
+    fn call(&mut self, callee: Value, args: Vec<Value>) -> Exec<()> {
+        match self.resolve(callee, args) {
+            Callee::Enter(func, slots, rest) => self.push_frame(func, slots, rest),
+            Callee::Partial(value) => {
+                self.stack.push(value);
+                Ok(())
+            }
+        }
+    }
+
+    // Replaces the current frame by a call of `func`. Arguments left over by
+    // the replaced frame apply to the result after the new ones.
+    fn tail_call(&mut self, func: u32, slots: Vec<Value>, mut rest: Vec<Value>) {
+        let frame = self.frames.last_mut().unwrap();
+        rest.append(&mut frame.pending);
+        frame.func = func;
+        frame.ip = 0;
+        frame.pending = rest;
+        let base = frame.base;
+        self.stack.truncate(base);
+        self.push_slots(func, slots);
+    }
+
+    // Returns `value` from the current frame.
+    fn ret(&mut self, value: Value) -> Exec<()> {
+        let frame = self.frames.pop().unwrap();
+        self.stack.truncate(frame.base);
+        if frame.pending.is_empty() {
+            self.stack.push(value);
+            return Ok(());
+        }
+        self.call(value, frame.pending)
+    }
+
+    // Calls a function to completion, for the main function and for the
+    // instance methods implementing operators.
+    fn call_sync(&mut self, out: &mut dyn Write, func: u32, args: Vec<Value>) -> Exec<Value> {
+        let depth = self.frames.len();
+        self.push_frame(func, args, vec![])?;
+        self.execute(out, depth)?;
+        Ok(self.pop())
+    }
+
+    // Runs until the frame count falls back to `depth`.
+    fn execute(&mut self, out: &mut dyn Write, depth: usize) -> Exec<()> {
+        while self.frames.len() > depth {
+            let frame = self.frames.last_mut().unwrap();
+            let op = self.program.functions[frame.func as usize].code[frame.ip];
+            frame.ip += 1;
+            let base = frame.base;
+            match op {
+                Op::Const(c) => self.stack.push(self.program.constants[c as usize].clone()),
+                Op::Load(slot) => self.stack.push(self.stack[base + slot as usize].clone()),
+                Op::Store(slot) => {
+                    let value = self.pop();
+                    self.stack[base + slot as usize] = value;
+                }
+                Op::Dup => self.stack.push(self.stack.last().unwrap().clone()),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Closure(func, n) => {
+                    let captured = self.pop_n(n);
+                    self.stack.push(Value::Closure(Rc::new(Closure { func, captured, args: vec![] })));
+                }
+                Op::Call(argc) => {
+                    let args = self.pop_n(argc);
+                    let callee = self.pop();
+                    self.call(callee, args)?;
+                }
+                Op::TailCall(argc) => {
+                    let args = self.pop_n(argc);
+                    let callee = self.pop();
+                    match self.resolve(callee, args) {
+                        Callee::Enter(func, slots, rest) => self.tail_call(func, slots, rest),
+                        Callee::Partial(value) => self.ret(value)?,
+                    }
+                }
+                Op::CallFunc(func, argc) => {
+                    let args = self.pop_n(argc);
+                    self.push_frame(func, args, vec![])?;
+                }
+                Op::TailCallFunc(func, argc) => {
+                    let args = self.pop_n(argc);
+                    self.tail_call(func, args, vec![]);
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    self.ret(value)?;
+                }
+                Op::Jump(target) => self.frames.last_mut().unwrap().ip = target as usize,
+                Op::JumpIfFalse(target) => {
+                    if let Value::Bool(false) = self.pop() {
+                        self.frames.last_mut().unwrap().ip = target as usize;
+                    }
+                }
+                Op::Unary(op) => {
+                    let value = self.pop();
+                    let result = match self.method(&value, "neg") {
+                        Some(method) => self.call_sync(out, method, vec![value])?,
+                        None => match unary_op(op, &to_const(&value)) {
+                            Ok(value) => from_const(value),
+                            Err(msg) => return Err(self.error(msg)),
+                        },
+                    };
+                    self.stack.push(result);
+                }
+                Op::Binary(op) => {
+                    let r = self.pop();
+                    let l = self.pop();
+                    let result = self.binary(out, op, l, r)?;
+                    self.stack.push(result);
+                }
+                Op::Tuple(n) => {
+                    let elems = self.pop_n(n);
+                    self.stack.push(Value::Tuple(Rc::new(elems)));
+                }
+                Op::Struct(ty, n) => {
+                    let fields = self.pop_n(n);
+                    self.stack.push(Value::Struct(ty, Rc::new(fields)));
+                }
+                Op::Variant(ty, index, n) => {
+                    let fields = self.pop_n(n);
+                    self.stack.push(Value::Variant(ty, index, Rc::new(fields)));
+                }
+                Op::Field(index) => {
+                    let value = match self.pop() {
+                        Value::Tuple(fields) | Value::Struct(_, fields) | Value::Variant(_, _, fields) => {
+                            fields[index as usize].clone()
+                        }
+                        _ => unreachable!("field of a value without fields"),
+                    };
+                    self.stack.push(value);
+                }
+                Op::TestTag(index) => {
+                    let value = match self.pop() {
+                        Value::Variant(_, tag, _) => tag == index,
+                        _ => unreachable!("testing the tag of a non-variant value"),
+                    };
+                    self.stack.push(Value::Bool(value));
+                }
+                Op::Show => {
+                    let value = self.pop();
+                    let s = self.show(out, &value)?;
+                    self.stack.push(Value::Str(Rc::from(s)));
+                }
+                Op::Concat(n) => {
+                    let mut s = String::new();
+                    for part in self.pop_n(n) {
+                        if let Value::Str(part) = part {
+                            s.push_str(&part);
+                        }
+                    }
+                    self.stack.push(Value::Str(Rc::from(s)));
+                }
+                Op::Print => {
+                    if let Value::Str(s) = self.pop() {
+                        if writeln!(out, "{}", s).is_err() {
+                            return Err(self.error("Cannot write the program output"));
+                        }
+                    }
+                }
+                Op::MatchFail => return Err(self.error("No case arm matches the value")),
+            }
+        }
+        Ok(())
+    }
+
+    // The instance method implementing an operation on a user type value.
+    fn method(&self, value: &Value, name: &str) -> Option<u32> {
+        match value {
+            Value::Struct(ty, _) | Value::Variant(ty, _, _) => self.program.types[*ty as usize].method(name),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, out: &mut dyn Write, op: BinaryOp, l: Value, r: Value) -> Exec<Value> {
+        let value = match op {
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                let name = match op {
+                    BinaryOp::Add => "add",
+                    BinaryOp::Sub => "sub",
+                    BinaryOp::Mul => "mul",
+                    _ => "div",
+                };
+                match self.method(&l, name) {
+                    Some(method) => return self.call_sync(out, method, vec![l, r]),
+                    None => self.primitive(op, &l, &r)?,
+                }
+            }
+            BinaryOp::Eq => Value::Bool(self.equal(out, &l, &r)?),
+            BinaryOp::NotEq => Value::Bool(!self.equal(out, &l, &r)?),
+            BinaryOp::Lt => Value::Bool(self.less(out, &l, &r)?),
+            BinaryOp::LtEq => Value::Bool(!self.less(out, &r, &l)?),
+            BinaryOp::Gt => Value::Bool(self.less(out, &r, &l)?),
+            BinaryOp::GtEq => Value::Bool(!self.less(out, &l, &r)?),
+            _ => self.primitive(op, &l, &r)?,
+        };
+        Ok(value)
+    }
+
+    fn primitive(&self, op: BinaryOp, l: &Value, r: &Value) -> Exec<Value> {
+        match binary_op(op, &to_const(l), &to_const(r)) {
+            Ok(value) => Ok(from_const(value)),
+            Err(msg) => Err(self.error(msg)),
+        }
+    }
+
+    // Types without an Eq instance compare field by field.
+    fn equal(&mut self, out: &mut dyn Write, l: &Value, r: &Value) -> Exec<bool> {
+        if let Some(method) = self.method(l, "eq") {
+            let result = self.call_sync(out, method, vec![l.clone(), r.clone()])?;
+            return Ok(matches!(result, Value::Bool(true)));
+        }
+        let (a, b) = match (l, r) {
+            (Value::Tuple(a), Value::Tuple(b)) | (Value::Struct(_, a), Value::Struct(_, b)) => (a, b),
+            (Value::Variant(_, i, a), Value::Variant(_, j, b)) if i == j => (a, b),
+            (Value::Variant(_, _, _), Value::Variant(_, _, _)) => return Ok(false),
+            _ => return Ok(matches!(self.primitive(BinaryOp::Eq, l, r)?, Value::Bool(true))),
+        };
+        for (x, y) in a.iter().zip(b.iter()) {
+            if !self.equal(out, x, y)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Tuples compare lexicographically.
+    fn less(&mut self, out: &mut dyn Write, l: &Value, r: &Value) -> Exec<bool> {
+        if let Some(method) = self.method(l, "lt") {
+            let result = self.call_sync(out, method, vec![l.clone(), r.clone()])?;
+            return Ok(matches!(result, Value::Bool(true)));
+        }
+        if let (Value::Tuple(a), Value::Tuple(b)) = (l, r) {
+            for (x, y) in a.iter().zip(b.iter()) {
+                if self.less(out, x, y)? {
+                    return Ok(true);
+                }
+                if self.less(out, y, x)? {
+                    return Ok(false);
+                }
+            }
+            return Ok(false);
+        }
+        Ok(matches!(self.primitive(BinaryOp::Lt, l, r)?, Value::Bool(true)))
+    }
+
+    // Same formatting as the interpreter.
+    fn show(&mut self, out: &mut dyn Write, value: &Value) -> Exec<String> {
+        if let Some(method) = self.method(value, "show") {
+            return match self.call_sync(out, method, vec![value.clone()])? {
+                Value::Str(s) => Ok(String::from(&*s)),
+                _ => unreachable!("show returns a string"),
+            };
+        }
+        let program = self.program;
+        let s = match value {
+            Value::Tuple(elems) => {
+                let mut parts = vec![];
+                for elem in elems.iter() {
+                    parts.push(self.show(out, elem)?);
+                }
+                format!("({})", parts.join(", "))
+            }
+            Value::Struct(ty, values) => {
+                let info = &program.types[*ty as usize];
+                let mut parts = vec![];
+                for (field, value) in info.fields.iter().zip(values.iter()) {
+                    parts.push(format!("{} = {}", field, self.show_nested(out, value)?));
+                }
+                format!("{} {{{}}}", info.name, parts.join(", "))
+            }
+            Value::Variant(ty, index, values) => {
+                let mut s = program.types[*ty as usize].ctors[*index as usize].0.clone();
+                for value in values.iter() {
+                    s.push(' ');
+                    s.push_str(&self.show_nested(out, value)?);
+                }
+                s
+            }
+            Value::Closure(_) => String::from("<function>"),
+            value => show_value(&to_const(value)),
+        };
+        Ok(s)
+    }
+
+    // Constructor arguments are parenthesized when they have arguments too.
+    fn show_nested(&mut self, out: &mut dyn Write, value: &Value) -> Exec<String> {
+        let s = self.show(out, value)?;
+        match value {
+            Value::Variant(_, _, values) if !values.is_empty() && self.method(value, "show").is_none() => {
+                Ok(format!("({})", s))
+            }
+            _ => Ok(s),
+        }
+    }
+}
+
+fn to_const(value: &Value) -> ConstValue {
+    match value {
+        Value::Int(val) => ConstValue::Int(*val),
+        Value::Float(val) => ConstValue::Float(*val),
+        Value::Char(val) => ConstValue::Char(*val),
+        Value::Str(val) => ConstValue::Str(String::from(&**val)),
+        Value::Bool(val) => ConstValue::Bool(*val),
+        _ => unreachable!("builtin operator on a user value"),
+    }
+}
+
+fn from_const(value: ConstValue) -> Value {
+    match value {
+        ConstValue::Int(val) => Value::Int(val),
+        ConstValue::Float(val) => Value::Float(val),
+        ConstValue::Char(val) => Value::Char(val),
+        ConstValue::Str(val) => Value::Str(Rc::from(val)),
+        ConstValue::Bool(val) => Value::Bool(val),
+        _ => unreachable!("user values are built by the machine"),
+    }
+}
+
+// Runs a compiled program, returning what it wrote.
+#[cfg(test)]
+pub fn run_vm(program: &Program) -> Result<String, RuntimeError> {
+    let mut out = vec![];
+    Vm::new(program).run(&mut out)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::disassemble;
+    use crate::compile::compile_ir;
+    use crate::interp::run_program;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+
+    fn compile(src: &str) -> Program {
+        let (graph, checked, interner) = check_test_program(src, "");
+        compile_ir(&lower_program(&graph, &checked, &interner))
+    }
+
+    // Runs a program on the machine, checking the interpreter agrees.
+    fn run(src: &str) -> Result<String, String> {
+        let (graph, checked, interner) = check_test_program(src, "");
+        let program = compile_ir(&lower_program(&graph, &checked, &interner));
+        println!("{}", disassemble(&program));
+        let result = run_vm(&program);
+        println!("{:?}", result);
+        assert!(result == run_program(&graph, &checked, &interner));
+        result.map_err(|err| err.diag.msg)
+    }
+
+    #[test]
+    fn test_functions_and_closures() {
+        let src = "
+fact_rec n: int -> int
+    if n == 0
+        1
+    else
+        n * fact_rec n-1
+
+multiply x, y: int -> int
+    x * y
+
+add_one x: int -> int
+    let
+        y: int = 1
+    in
+    x + y
+
+twice f: (int -> int) x: int -> int
+    f (f x)
+
+adder n: int -> (int -> int)
+    \\x -> x + n
+
+fact_rec 10
+multiply 6 7
+twice add_one 5
+twice (multiply 3) 2
+(\\x -> x * 2.5) 2.0
+adder 1 2
+twice (adder 10) 1
+`${fact_rec 3}, ${1 < 2 && 2 < 3}, ${'c'}`
+";
+        assert!(run(src) == Ok(String::from("3628800\n42\n7\n18\n5.0\n3\n21\n6, true, c\n")));
+    }
+
+    #[test]
+    fn test_structs_sum_types_and_instances() {
+        let src = "
+type Shape =
+    Circle: float
+    Rect: float, float
+    Empty
+
+struct Vector =
+    x, y: float
+
+instance Num Vector =
+    add a, b = Vector {a.x + b.x, a.y + b.y}
+    sub a, b = Vector {a.x - b.x, a.y - b.y}
+    mul a, b = Vector {a.x * b.x, a.y * b.y}
+    div a, b = Vector {a.x / b.x, a.y / b.y}
+    neg v = Vector {-v.x, -v.y}
+
+instance Show Vector =
+    show v = `<${v.x}, ${v.y}>`
+
+area s: Shape -> float
+    case s of
+        Circle r -> r * r * 3.0
+        Rect w h -> w * h
+        Empty -> 0.0
+
+length {x, y}: Vector -> float
+    x * x + y * y
+
+const ORIGIN = Vector {x = 0.0, y = 0.0}
+
+area (Circle 1.0)
+area (Rect 2.0 3.0)
+area Empty
+Rect 1.0
+Vector {1.0, 2.0} + {3.0, 4.0}
+-(Vector {1.0, 2.0})
+length {y = 2.0, x = 1.0}
+ORIGIN == Vector {0.0, 0.0}
+(Empty, Circle 2.0)
+";
+        let expected = "3.0\n6.0\n0.0\n<function>\n<4.0, 6.0>\n<-1.0, -2.0>\n5.0\ntrue\n(Empty, Circle 2.0)\n";
+        assert!(run(src) == Ok(String::from(expected)));
+    }
+
+    #[test]
+    fn test_generic_functions() {
+        let src = format!(
+            "{}
+range n: int -> List int
+    if n == 0
+        Nil
+    else
+        Cons n (range (n - 1))
+
+sum xs: List int -> int
+    foldl (\\acc x -> acc + x) 0 xs
+
+sum (range 10)
+length (filter (\\x -> x % 2 == 0) (range 10))
+with_default 0 (head (reverse (range 3)))
+map (\\x -> x * x) (range 3)
+",
+            crate::prelude::PRELUDE
+        );
+        assert!(run(&src) == Ok(String::from("55\n5\n1\nCons 9 (Cons 4 (Cons 1 Nil))\n")));
+    }
+
+    #[test]
+    fn test_modules() {
+        let foo = "
+type Answer =
+    Yes
+    No
+
+greet str: string -> string
+    `Hello ${str}`
+
+export =
+    greet
+    Answer
+";
+        let src = "
+import foo::greet
+import foo::Answer
+import foo
+
+greet \"Silver pancake\"
+foo::greet \"again\"
+(Yes, No)
+";
+        let (graph, checked, interner) = check_test_program(src, foo);
+        let program = compile_ir(&lower_program(&graph, &checked, &interner));
+        assert!(run_vm(&program) == Ok(String::from("Hello Silver pancake\nHello again\n(Yes, No)\n")));
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        // Far deeper than the frame limit, so only runs in constant space.
+        let src = "
+count n, acc: int -> int
+    if n == 0
+        acc
+    else
+        count (n - 1) (acc + 1)
+
+even n: int -> bool
+    case n of
+        0 -> true
+        _ -> odd (n - 1)
+
+odd n: int -> bool
+    case n of
+        0 -> false
+        _ -> even (n - 1)
+
+count 1000000 0
+even 100001
+";
+        let program = compile(src);
+        assert!(run_vm(&program) == Ok(String::from("1000000\nfalse\n")));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+div x, y: int -> int
+    x / y
+
+div 7 2
+div 1 0
+";
+        assert!(run(src) == Err(String::from("Division by zero")));
+        assert!(run("9223372036854775807 + 1\n") == Err(String::from("Integer overflow")));
+        assert!(run("-9223372036854775808\n") == Ok(String::from("-9223372036854775808\n")));
+
+        let program = compile("deep n: int -> int\n    1 + deep (n + 1)\n\ndeep 0\n");
+        let mut vm = Vm::new(&program);
+        vm.max_frames = 100;
+        let err = vm.run(&mut vec![]).unwrap_err();
+        assert!(err.diag.msg == "Stack overflow: too many nested calls");
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = compile("inc n: int -> int\n    n + 1\n\ninc 41\n");
+        let expected = "fn 0 inc (arity 1, captures 0, locals 1)
+  0000  load 0
+  0001  const 0  ; 1
+  0002  binary +
+  0003  return
+fn 1 <main> (arity 0, captures 0, locals 0)
+  0000  const 1  ; 41
+  0001  call_func 0 1  ; inc
+  0002  show
+  0003  print
+  0004  tuple 0
+  0005  return
+";
+        let listing = disassemble(&program);
+        println!("{}", listing);
+        assert!(listing == expected);
+    }
+}
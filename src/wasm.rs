@@ -0,0 +1,1625 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::ast::{BinaryOp, UnaryOp};
+use crate::diagnostic::Diagnostic;
+use crate::interp::MAX_DEPTH;
+use crate::ir::{self, dominates, BlockId, Const, Def, FuncKind, Inst, Term, Ty, ValueId};
+use crate::modules::ModuleId;
+
+// Compiles the IR of a program to a WebAssembly module, in the binary
+// format or as text for debugging. `int` and `float` are i64 and f64, `bool`
+// and `char` i32. Structs, tuples and variants live in linear memory,
+// allocated by bumping a pointer: one 8 byte slot per field, after a slot
+// holding the tag for variants. Functions listed in the root module's
+// `export =` are exported under their name, along with the memory.
+//
+// Runtime errors trap after storing their code, an index into ERRORS plus
+// one, in the exported global `sp_error`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+    I64,
+    F64,
+}
+
+impl ValType {
+    pub fn byte(self) -> u8 {
+        match self {
+            ValType::I32 => 0x7f,
+            ValType::I64 => 0x7e,
+            ValType::F64 => 0x7c,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_byte(byte: u8) -> Option<ValType> {
+        match byte {
+            0x7f => Some(ValType::I32),
+            0x7e => Some(ValType::I64),
+            0x7c => Some(ValType::F64),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ValType::I32 => "i32",
+            ValType::I64 => "i64",
+            ValType::F64 => "f64",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Unreachable,
+    // Blocks produce no value or a single one.
+    Block(Option<ValType>),
+    Loop(Option<ValType>),
+    If(Option<ValType>),
+    Else,
+    End,
+    Br(u32),
+    Call(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // Offset from the address on the stack.
+    Load(ValType, u32),
+    Store(ValType, u32),
+    MemorySize,
+    MemoryGrow,
+    I32Const(i32),
+    I64Const(i64),
+    F64Const(f64),
+    // Numeric instructions without immediates, by opcode.
+    Op(u8),
+}
+
+pub const I32_EQZ: u8 = 0x45;
+pub const I32_EQ: u8 = 0x46;
+pub const I32_NE: u8 = 0x47;
+pub const I32_LT_U: u8 = 0x49;
+pub const I32_GT_S: u8 = 0x4a;
+pub const I32_GT_U: u8 = 0x4b;
+pub const I32_LE_U: u8 = 0x4d;
+pub const I32_GE_U: u8 = 0x4f;
+pub const I64_EQZ: u8 = 0x50;
+pub const I64_EQ: u8 = 0x51;
+pub const I64_NE: u8 = 0x52;
+pub const I64_LT_S: u8 = 0x53;
+pub const I64_GT_S: u8 = 0x55;
+pub const I64_LE_S: u8 = 0x57;
+pub const I64_GE_S: u8 = 0x59;
+pub const I64_GE_U: u8 = 0x5a;
+pub const F64_EQ: u8 = 0x61;
+pub const F64_NE: u8 = 0x62;
+pub const F64_LT: u8 = 0x63;
+pub const F64_GT: u8 = 0x64;
+pub const I32_ADD: u8 = 0x6a;
+pub const I32_SUB: u8 = 0x6b;
+pub const I32_AND: u8 = 0x71;
+pub const I32_SHL: u8 = 0x74;
+pub const I32_SHR_U: u8 = 0x76;
+pub const I64_ADD: u8 = 0x7c;
+pub const I64_SUB: u8 = 0x7d;
+pub const I64_MUL: u8 = 0x7e;
+pub const I64_DIV_S: u8 = 0x7f;
+pub const I64_REM_S: u8 = 0x81;
+pub const I64_AND: u8 = 0x83;
+pub const I64_OR: u8 = 0x84;
+pub const I64_XOR: u8 = 0x85;
+pub const I64_SHL: u8 = 0x86;
+pub const I64_SHR_S: u8 = 0x87;
+pub const F64_NEG: u8 = 0x9a;
+pub const F64_ADD: u8 = 0xa0;
+pub const F64_SUB: u8 = 0xa1;
+pub const F64_MUL: u8 = 0xa2;
+pub const F64_DIV: u8 = 0xa3;
+
+// Text names of the numeric instructions.
+pub const OPS: &[(u8, &str)] = &[
+    (I32_EQZ, "i32.eqz"),
+    (I32_EQ, "i32.eq"),
+    (I32_NE, "i32.ne"),
+    (I32_LT_U, "i32.lt_u"),
+    (I32_GT_S, "i32.gt_s"),
+    (I32_GT_U, "i32.gt_u"),
+    (I32_LE_U, "i32.le_u"),
+    (I32_GE_U, "i32.ge_u"),
+    (I64_EQZ, "i64.eqz"),
+    (I64_EQ, "i64.eq"),
+    (I64_NE, "i64.ne"),
+    (I64_LT_S, "i64.lt_s"),
+    (I64_GT_S, "i64.gt_s"),
+    (I64_LE_S, "i64.le_s"),
+    (I64_GE_S, "i64.ge_s"),
+    (I64_GE_U, "i64.ge_u"),
+    (F64_EQ, "f64.eq"),
+    (F64_NE, "f64.ne"),
+    (F64_LT, "f64.lt"),
+    (F64_GT, "f64.gt"),
+    (I32_ADD, "i32.add"),
+    (I32_SUB, "i32.sub"),
+    (I32_AND, "i32.and"),
+    (I32_SHL, "i32.shl"),
+    (I32_SHR_U, "i32.shr_u"),
+    (I64_ADD, "i64.add"),
+    (I64_SUB, "i64.sub"),
+    (I64_MUL, "i64.mul"),
+    (I64_DIV_S, "i64.div_s"),
+    (I64_REM_S, "i64.rem_s"),
+    (I64_AND, "i64.and"),
+    (I64_OR, "i64.or"),
+    (I64_XOR, "i64.xor"),
+    (I64_SHL, "i64.shl"),
+    (I64_SHR_S, "i64.shr_s"),
+    (F64_NEG, "f64.neg"),
+    (F64_ADD, "f64.add"),
+    (F64_SUB, "f64.sub"),
+    (F64_MUL, "f64.mul"),
+    (F64_DIV, "f64.div"),
+];
+
+#[cfg(test)]
+pub const ERRORS: [&str; 5] = [
+    "Integer overflow",
+    "Division by zero",
+    "No case arm matches the value",
+    "Stack overflow: too many nested calls",
+    "Out of memory",
+];
+const OVERFLOW: i32 = 1;
+const DIVISION: i32 = 2;
+const NO_MATCH: i32 = 3;
+const STACK: i32 = 4;
+const MEMORY: i32 = 5;
+
+// Globals: the error code, the allocation pointer and the call depth, then
+// the exported constants.
+const ERROR: u32 = 0;
+const HEAP: u32 = 1;
+const DEPTH: u32 = 2;
+
+// Functions of the runtime, before the compiled ones.
+const PANIC: u32 = 0;
+const ALLOC: u32 = 1;
+const ADD: u32 = 2;
+const SUB: u32 = 3;
+const MUL: u32 = 4;
+const DIV: u32 = 5;
+const REM: u32 = 6;
+const NEG: u32 = 7;
+const SHL: u32 = 8;
+const SHR: u32 = 9;
+const FDIV: u32 = 10;
+const RUNTIME: usize = 11;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasmFunc {
+    pub name: String,
+    pub export: Option<String>,
+    pub params: Vec<ValType>,
+    pub result: Option<ValType>,
+    // Locals after the parameters.
+    pub locals: Vec<ValType>,
+    // Without the final `end`.
+    pub body: Vec<Instr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasmGlobal {
+    pub name: String,
+    pub export: Option<String>,
+    pub ty: ValType,
+    pub mutable: bool,
+    pub init: Instr,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WasmModule {
+    pub funcs: Vec<WasmFunc>,
+    pub globals: Vec<WasmGlobal>,
+}
+
+fn uleb(out: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut val: i64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+fn block_type(out: &mut Vec<u8>, ty: Option<ValType>) {
+    out.push(ty.map_or(0x40, ValType::byte));
+}
+
+// log2 of the natural alignment of loads and stores.
+fn align(ty: ValType) -> u64 {
+    if ty == ValType::I32 {
+        2
+    } else {
+        3
+    }
+}
+
+pub fn encode_instr(out: &mut Vec<u8>, instr: &Instr) {
+    match instr {
+        Instr::Unreachable => out.push(0x00),
+        Instr::Block(ty) => {
+            out.push(0x02);
+            block_type(out, *ty);
+        }
+        Instr::Loop(ty) => {
+            out.push(0x03);
+            block_type(out, *ty);
+        }
+        Instr::If(ty) => {
+            out.push(0x04);
+            block_type(out, *ty);
+        }
+        Instr::Else => out.push(0x05),
+        Instr::End => out.push(0x0b),
+        Instr::Br(depth) => {
+            out.push(0x0c);
+            uleb(out, *depth as u64);
+        }
+        Instr::Call(func) => {
+            out.push(0x10);
+            uleb(out, *func as u64);
+        }
+        Instr::Drop => out.push(0x1a),
+        Instr::LocalGet(index) | Instr::LocalSet(index) | Instr::GlobalGet(index) | Instr::GlobalSet(index) => {
+            out.push(match instr {
+                Instr::LocalGet(_) => 0x20,
+                Instr::LocalSet(_) => 0x21,
+                Instr::GlobalGet(_) => 0x23,
+                _ => 0x24,
+            });
+            uleb(out, *index as u64);
+        }
+        Instr::Load(ty, offset) | Instr::Store(ty, offset) => {
+            let load = matches!(instr, Instr::Load(_, _));
+            out.push(match ty {
+                ValType::I32 if load => 0x28,
+                ValType::I64 if load => 0x29,
+                ValType::F64 if load => 0x2b,
+                ValType::I32 => 0x36,
+                ValType::I64 => 0x37,
+                ValType::F64 => 0x39,
+            });
+            uleb(out, align(*ty));
+            uleb(out, *offset as u64);
+        }
+        Instr::MemorySize => out.extend_from_slice(&[0x3f, 0x00]),
+        Instr::MemoryGrow => out.extend_from_slice(&[0x40, 0x00]),
+        Instr::I32Const(val) => {
+            out.push(0x41);
+            sleb(out, *val as i64);
+        }
+        Instr::I64Const(val) => {
+            out.push(0x42);
+            sleb(out, *val);
+        }
+        Instr::F64Const(val) => {
+            out.push(0x44);
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+        Instr::Op(op) => out.push(*op),
+    }
+}
+
+// WAT spelling of a float constant.
+fn wat_float(val: f64) -> String {
+    if val.is_nan() {
+        String::from("nan")
+    } else if val.is_infinite() {
+        String::from(if val > 0.0 { "inf" } else { "-inf" })
+    } else {
+        format!("{:?}", val)
+    }
+}
+
+fn wat_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            b' '..=b'~' => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\{:02x}", byte);
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl WasmModule {
+    fn signature(func: &WasmFunc) -> (Vec<ValType>, Option<ValType>) {
+        (func.params.clone(), func.result)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&[1, 0, 0, 0]);
+
+        let mut sigs: Vec<(Vec<ValType>, Option<ValType>)> = vec![];
+        let mut func_types = vec![];
+        for func in &self.funcs {
+            let sig = WasmModule::signature(func);
+            let index = match sigs.iter().position(|s| *s == sig) {
+                Some(index) => index,
+                None => {
+                    sigs.push(sig);
+                    sigs.len() - 1
+                }
+            };
+            func_types.push(index);
+        }
+        let mut types = vec![];
+        uleb(&mut types, sigs.len() as u64);
+        for (params, result) in &sigs {
+            types.push(0x60);
+            uleb(&mut types, params.len() as u64);
+            types.extend(params.iter().map(|t| t.byte()));
+            uleb(&mut types, result.is_some() as u64);
+            types.extend(result.iter().map(|t| t.byte()));
+        }
+        section(&mut out, 1, &types);
+
+        let mut funcs = vec![];
+        uleb(&mut funcs, func_types.len() as u64);
+        for index in func_types {
+            uleb(&mut funcs, index as u64);
+        }
+        section(&mut out, 3, &funcs);
+
+        // A single memory of at least one page.
+        section(&mut out, 5, &[1, 0x00, 1]);
+
+        let mut globals = vec![];
+        uleb(&mut globals, self.globals.len() as u64);
+        for global in &self.globals {
+            globals.push(global.ty.byte());
+            globals.push(global.mutable as u8);
+            encode_instr(&mut globals, &global.init);
+            encode_instr(&mut globals, &Instr::End);
+        }
+        section(&mut out, 6, &globals);
+
+        let mut exports = vec![(String::from("memory"), 2, 0)];
+        for (i, global) in self.globals.iter().enumerate() {
+            if let Some(export) = &global.export {
+                exports.push((export.clone(), 3, i));
+            }
+        }
+        for (i, func) in self.funcs.iter().enumerate() {
+            if let Some(export) = &func.export {
+                exports.push((export.clone(), 0, i));
+            }
+        }
+        let mut export_section = vec![];
+        uleb(&mut export_section, exports.len() as u64);
+        for (export, kind, index) in exports {
+            name(&mut export_section, &export);
+            export_section.push(kind);
+            uleb(&mut export_section, index as u64);
+        }
+        section(&mut out, 7, &export_section);
+
+        let mut code = vec![];
+        uleb(&mut code, self.funcs.len() as u64);
+        for func in &self.funcs {
+            let mut body = vec![];
+            // Runs of locals of the same type.
+            let mut runs: Vec<(u32, ValType)> = vec![];
+            for ty in &func.locals {
+                match runs.last_mut() {
+                    Some((count, last)) if last == ty => *count += 1,
+                    _ => runs.push((1, *ty)),
+                }
+            }
+            uleb(&mut body, runs.len() as u64);
+            for (count, ty) in runs {
+                uleb(&mut body, count as u64);
+                body.push(ty.byte());
+            }
+            for instr in &func.body {
+                encode_instr(&mut body, instr);
+            }
+            encode_instr(&mut body, &Instr::End);
+            uleb(&mut code, body.len() as u64);
+            code.extend_from_slice(&body);
+        }
+        section(&mut out, 10, &code);
+        out
+    }
+
+    // The WebAssembly text format, one instruction per line.
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("(module\n  (memory (export \"memory\") 1)\n");
+        for global in &self.globals {
+            let export = match &global.export {
+                Some(export) => format!(" (export {})", wat_string(export)),
+                None => String::new(),
+            };
+            let ty = if global.mutable {
+                format!("(mut {})", global.ty.as_str())
+            } else {
+                String::from(global.ty.as_str())
+            };
+            let _ = writeln!(out, "  (global ${}{} {} ({}))", global.name, export, ty, self.instr_text(&global.init));
+        }
+        for func in &self.funcs {
+            let _ = write!(out, "  (func ${}", func.name);
+            if let Some(export) = &func.export {
+                let _ = write!(out, " (export {})", wat_string(export));
+            }
+            for (list, types) in [("param", &func.params), ("local", &func.locals)] {
+                if !types.is_empty() {
+                    let names: Vec<&str> = types.iter().map(|t| t.as_str()).collect();
+                    let _ = write!(out, " ({} {})", list, names.join(" "));
+                }
+                if list == "param" {
+                    if let Some(result) = func.result {
+                        let _ = write!(out, " (result {})", result.as_str());
+                    }
+                }
+            }
+            out.push('\n');
+            let mut indent = 2;
+            for instr in &func.body {
+                if matches!(instr, Instr::End | Instr::Else) {
+                    indent -= 1;
+                }
+                let _ = writeln!(out, "{}{}", "  ".repeat(indent), self.instr_text(instr));
+                if matches!(instr, Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::Else) {
+                    indent += 1;
+                }
+            }
+            out.push_str("  )\n");
+        }
+        out.push_str(")\n");
+        out
+    }
+
+    fn instr_text(&self, instr: &Instr) -> String {
+        let block = |keyword: &str, ty: &Option<ValType>| match ty {
+            Some(ty) => format!("{} (result {})", keyword, ty.as_str()),
+            None => String::from(keyword),
+        };
+        match instr {
+            Instr::Unreachable => String::from("unreachable"),
+            Instr::Block(ty) => block("block", ty),
+            Instr::Loop(ty) => block("loop", ty),
+            Instr::If(ty) => block("if", ty),
+            Instr::Else => String::from("else"),
+            Instr::End => String::from("end"),
+            Instr::Br(depth) => format!("br {}", depth),
+            Instr::Call(func) => format!("call ${}", self.funcs[*func as usize].name),
+            Instr::Drop => String::from("drop"),
+            Instr::LocalGet(index) => format!("local.get {}", index),
+            Instr::LocalSet(index) => format!("local.set {}", index),
+            Instr::GlobalGet(index) => format!("global.get ${}", self.globals[*index as usize].name),
+            Instr::GlobalSet(index) => format!("global.set ${}", self.globals[*index as usize].name),
+            Instr::Load(ty, offset) | Instr::Store(ty, offset) => {
+                let op = if let Instr::Load(_, _) = instr { "load" } else { "store" };
+                if *offset == 0 {
+                    format!("{}.{}", ty.as_str(), op)
+                } else {
+                    format!("{}.{} offset={}", ty.as_str(), op, offset)
+                }
+            }
+            Instr::MemorySize => String::from("memory.size"),
+            Instr::MemoryGrow => String::from("memory.grow"),
+            Instr::I32Const(val) => format!("i32.const {}", val),
+            Instr::I64Const(val) => format!("i64.const {}", val),
+            Instr::F64Const(val) => format!("f64.const {}", wat_float(*val)),
+            Instr::Op(op) => String::from(OPS.iter().find(|(code, _)| code == op).expect("unknown opcode").1),
+        }
+    }
+}
+
+fn val_type(ty: &Ty) -> ValType {
+    match ty {
+        Ty::Int => ValType::I64,
+        Ty::Float => ValType::F64,
+        _ => ValType::I32,
+    }
+}
+
+fn const_type(value: &Const) -> ValType {
+    match value {
+        Const::Int(_) => ValType::I64,
+        Const::Float(_) => ValType::F64,
+        _ => ValType::I32,
+    }
+}
+
+// Types with a representation: scalars, and data types made of them.
+// Strings and functions have none.
+fn is_supported(ty: &Ty) -> bool {
+    match ty {
+        Ty::Int | Ty::Float | Ty::Bool | Ty::Char => true,
+        Ty::Struct(_, args) | Ty::Sum(_, args) | Ty::Tuple(args) => args.iter().all(is_supported),
+        _ => false,
+    }
+}
+
+// Blocks of the code being generated, the targets of branches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Label {
+    // Holds the result of the function.
+    Exit,
+    // Self tail calls jump back to its start.
+    Loop,
+    If,
+    // Ends right before the code of the block.
+    Before(BlockId),
+    // Starts right before the code of a block jumped back to.
+    Header(BlockId),
+}
+
+struct FnState<'p> {
+    f: &'p ir::Function,
+    index: u32,
+    // Types of the parameters, then of the other locals.
+    locals: Vec<ValType>,
+    params: u32,
+    // Local of every value not left on the stack.
+    slots: Vec<Option<u32>>,
+    stacked: Vec<bool>,
+    uses: Vec<u32>,
+    // Blocks reached from several others ahead of them, blocks jumped back
+    // to, and the children of every block in the dominator tree, in reverse
+    // postorder.
+    merges: Vec<bool>,
+    headers: Vec<bool>,
+    idom: Vec<Option<BlockId>>,
+    children: Vec<Vec<BlockId>>,
+    labels: Vec<Label>,
+    code: Vec<Instr>,
+}
+
+impl<'p> FnState<'p> {
+    fn emit(&mut self, instr: Instr) {
+        self.code.push(instr);
+    }
+
+    fn local(&mut self, ty: ValType) -> u32 {
+        self.locals.push(ty);
+        self.locals.len() as u32 - 1
+    }
+
+    fn slot(&mut self, value: ValueId) -> u32 {
+        if let Some(slot) = self.slots[value.0 as usize] {
+            return slot;
+        }
+        let slot = self.local(val_type(self.f.ty(value)));
+        self.slots[value.0 as usize] = Some(slot);
+        slot
+    }
+
+    fn open(&mut self, instr: Instr, label: Label) {
+        self.emit(instr);
+        self.labels.push(label);
+    }
+
+    fn close(&mut self) {
+        self.emit(Instr::End);
+        self.labels.pop();
+    }
+
+    fn br(&mut self, label: Label) {
+        let depth = self.labels.iter().rev().position(|l| *l == label).expect("branch out of its block");
+        self.emit(Instr::Br(depth as u32));
+    }
+
+    fn panic(&mut self, code: i32) {
+        self.emit(Instr::I32Const(code));
+        self.emit(Instr::Call(PANIC));
+        self.emit(Instr::Unreachable);
+    }
+}
+
+// Runtime functions, written directly in WebAssembly.
+fn runtime() -> Vec<WasmFunc> {
+    use Instr::*;
+    let func = |name: &str, params: Vec<ValType>, result: Option<ValType>, locals: Vec<ValType>, body: Vec<Instr>| WasmFunc {
+        name: String::from(name),
+        export: None,
+        params,
+        result,
+        locals,
+        body,
+    };
+    let i64_pair = vec![ValType::I64, ValType::I64];
+    // Traps with `code` when the condition on the stack holds.
+    let check = |code: i32| vec![If(None), I32Const(code), Call(PANIC), End];
+    let checked = |op: u8, overflow: Vec<Instr>| {
+        let mut body = vec![LocalGet(0), LocalGet(1), Op(op), LocalSet(2)];
+        body.extend(overflow);
+        body.extend(check(OVERFLOW));
+        body.push(LocalGet(2));
+        body
+    };
+    let division = |op: u8| {
+        let mut body = vec![LocalGet(1), Op(I64_EQZ)];
+        body.extend(check(DIVISION));
+        body.extend([LocalGet(0), I64Const(i64::MIN), Op(I64_EQ), LocalGet(1), I64Const(-1), Op(I64_EQ), Op(I32_AND)]);
+        body.extend(check(OVERFLOW));
+        body.extend([LocalGet(0), LocalGet(1), Op(op)]);
+        body
+    };
+    let shift = |op: u8| {
+        let mut body = vec![LocalGet(1), I64Const(64), Op(I64_GE_U)];
+        body.extend(check(OVERFLOW));
+        body.extend([LocalGet(0), LocalGet(1), Op(op)]);
+        body
+    };
+    let mut alloc = vec![
+        GlobalGet(HEAP),
+        LocalSet(1),
+        GlobalGet(HEAP),
+        LocalGet(0),
+        Op(I32_ADD),
+        GlobalSet(HEAP),
+        // Grows the memory by the missing pages when the heap outgrows it.
+        GlobalGet(HEAP),
+        MemorySize,
+        I32Const(16),
+        Op(I32_SHL),
+        Op(I32_GT_U),
+        If(None),
+        GlobalGet(HEAP),
+        MemorySize,
+        I32Const(16),
+        Op(I32_SHL),
+        Op(I32_SUB),
+        I32Const(16),
+        Op(I32_SHR_U),
+        I32Const(1),
+        Op(I32_ADD),
+        MemoryGrow,
+        I32Const(-1),
+        Op(I32_EQ),
+    ];
+    alloc.extend(check(MEMORY));
+    alloc.extend([End, LocalGet(1)]);
+    let mut mul = vec![
+        // -1 * MIN is the only overflow division can't detect.
+        LocalGet(0),
+        I64Const(-1),
+        Op(I64_EQ),
+        If(Some(ValType::I32)),
+        LocalGet(1),
+        I64Const(i64::MIN),
+        Op(I64_EQ),
+        Else,
+        LocalGet(0),
+        Op(I64_EQZ),
+        If(Some(ValType::I32)),
+        I32Const(0),
+        Else,
+        LocalGet(0),
+        LocalGet(1),
+        Op(I64_MUL),
+        LocalGet(0),
+        Op(I64_DIV_S),
+        LocalGet(1),
+        Op(I64_NE),
+        End,
+        End,
+    ];
+    mul.extend(check(OVERFLOW));
+    mul.extend([LocalGet(0), LocalGet(1), Op(I64_MUL)]);
+    let mut neg = vec![LocalGet(0), I64Const(i64::MIN), Op(I64_EQ)];
+    neg.extend(check(OVERFLOW));
+    neg.extend([I64Const(0), LocalGet(0), Op(I64_SUB)]);
+    let mut fdiv = vec![LocalGet(1), F64Const(0.0), Op(F64_EQ)];
+    fdiv.extend(check(DIVISION));
+    fdiv.extend([LocalGet(0), LocalGet(1), Op(F64_DIV)]);
+    vec![
+        func("sp_panic", vec![ValType::I32], None, vec![], vec![LocalGet(0), GlobalSet(ERROR), Unreachable]),
+        func("sp_alloc", vec![ValType::I32], Some(ValType::I32), vec![ValType::I32], alloc),
+        // Overflow when both operands have a sign the result doesn't.
+        func(
+            "sp_add",
+            i64_pair.clone(),
+            Some(ValType::I64),
+            vec![ValType::I64],
+            checked(I64_ADD, vec![LocalGet(0), LocalGet(2), Op(I64_XOR), LocalGet(1), LocalGet(2), Op(I64_XOR), Op(I64_AND), I64Const(0), Op(I64_LT_S)]),
+        ),
+        func(
+            "sp_sub",
+            i64_pair.clone(),
+            Some(ValType::I64),
+            vec![ValType::I64],
+            checked(I64_SUB, vec![LocalGet(0), LocalGet(1), Op(I64_XOR), LocalGet(0), LocalGet(2), Op(I64_XOR), Op(I64_AND), I64Const(0), Op(I64_LT_S)]),
+        ),
+        func("sp_mul", i64_pair.clone(), Some(ValType::I64), vec![], mul),
+        func("sp_div", i64_pair.clone(), Some(ValType::I64), vec![], division(I64_DIV_S)),
+        func("sp_rem", i64_pair.clone(), Some(ValType::I64), vec![], division(I64_REM_S)),
+        func("sp_neg", vec![ValType::I64], Some(ValType::I64), vec![], neg),
+        func("sp_shl", i64_pair.clone(), Some(ValType::I64), vec![], shift(I64_SHL)),
+        func("sp_shr", i64_pair, Some(ValType::I64), vec![], shift(I64_SHR_S)),
+        func("sp_fdiv", vec![ValType::F64, ValType::F64], Some(ValType::F64), vec![], fdiv),
+    ]
+}
+
+
+pub struct WasmOutput {
+    pub module: WasmModule,
+    // Warnings for the functions left out.
+    pub skipped: Vec<(ModuleId, Diagnostic)>,
+}
+
+pub struct WasmGen<'a> {
+    ir: &'a ir::Program,
+    // Functions of the program compiled, and their index in the module.
+    compiled: Vec<u32>,
+    indices: HashMap<u32, u32>,
+    skipped: Vec<(ModuleId, Diagnostic)>,
+    runtime: Vec<WasmFunc>,
+}
+
+impl<'a> WasmGen<'a> {
+    pub fn new(ir: &'a ir::Program) -> WasmGen<'a> {
+        WasmGen {
+            ir,
+            compiled: vec![],
+            indices: HashMap::new(),
+            skipped: vec![],
+            runtime: runtime(),
+        }
+    }
+
+    // Chooses the functions to compile: those over supported types whose
+    // callees are compiled too.
+    fn collect(&mut self) {
+        let ir = self.ir;
+        let rejected = ir.rejected_funcs(|f, callees| self.check_func(f, callees));
+        for (i, f) in ir.funcs.iter().enumerate() {
+            if f.kind != FuncKind::Func {
+                continue;
+            }
+            if let Some(reason) = rejected.get(&(i as u32)) {
+                let diag = Diagnostic::warning(f.span, format!("Function '{}' can't be compiled to WebAssembly", f.name))
+                    .with_note(format!("it {}", reason));
+                self.skipped.push((ModuleId(f.module as usize), diag));
+                continue;
+            }
+            self.indices.insert(i as u32, (RUNTIME + self.compiled.len()) as u32);
+            self.compiled.push(i as u32);
+        }
+    }
+
+    fn check_func(&self, f: &ir::Function, callees: &mut Vec<u32>) -> Result<(), String> {
+        let mut call = |func: u32| match self.ir.funcs[func as usize].kind {
+            FuncKind::Func => {
+                callees.push(func);
+                Ok(())
+            }
+            FuncKind::Ctor => Ok(()),
+            FuncKind::Method => Err(String::from("uses trait instances")),
+            _ => Err(String::from("calls function values")),
+        };
+        for value in &f.values {
+            if !is_supported(&value.ty) {
+                return Err(format!("uses values of type {}", self.ir.ty_to_string(&value.ty)));
+            }
+            match &value.def {
+                Def::Inst(Inst::Binary(op, l, _)) => {
+                    let operand = f.ty(*l);
+                    let compares = matches!(
+                        op,
+                        BinaryOp::Eq | BinaryOp::NotEq | BinaryOp::Lt | BinaryOp::LtEq | BinaryOp::Gt | BinaryOp::GtEq
+                    );
+                    if compares && !operand.is_scalar() {
+                        return Err(String::from("compares structured values"));
+                    }
+                    if *op == BinaryOp::Mod && *operand == Ty::Float {
+                        return Err(String::from("takes the remainder of floats"));
+                    }
+                }
+                Def::Inst(Inst::Call(func, _)) => call(*func)?,
+                Def::Inst(Inst::CallValue(_, _)) | Def::Inst(Inst::Closure(_, _)) => {
+                    return Err(String::from("calls function values"));
+                }
+                _ => {}
+            }
+        }
+        for block in &f.blocks {
+            match &block.term {
+                Term::TailCall(func, _) => call(*func)?,
+                Term::TailCallValue(_, _) => return Err(String::from("calls function values")),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn generate(mut self) -> WasmOutput {
+        self.collect();
+        let mut module = WasmModule::default();
+        for (name, init) in [("sp_error", 0), ("sp_heap", 8), ("sp_depth", 0)] {
+            module.globals.push(WasmGlobal {
+                name: String::from(name),
+                export: if name == "sp_error" { Some(String::from(name)) } else { None },
+                ty: ValType::I32,
+                mutable: true,
+                init: Instr::I32Const(init),
+            });
+        }
+        module.funcs = std::mem::take(&mut self.runtime);
+        for func in &self.compiled {
+            module.funcs.push(self.function(*func));
+        }
+        // The root module's exports.
+        let ir = self.ir;
+        let root = &ir.modules[ir.funcs[ir.main as usize].module as usize];
+        for (label, func) in &root.exports {
+            if let Some(index) = self.indices.get(func) {
+                module.funcs[*index as usize].export = Some(label.clone());
+            }
+        }
+        for (label, value) in &root.consts {
+            let init = match value {
+                Const::Int(val) => Instr::I64Const(*val),
+                Const::Float(val) => Instr::F64Const(*val),
+                Const::Char(val) => Instr::I32Const(*val as i32),
+                Const::Bool(val) => Instr::I32Const(*val as i32),
+                // Data in memory has no constant address.
+                _ => continue,
+            };
+            module.globals.push(WasmGlobal {
+                name: label.clone(),
+                export: Some(label.clone()),
+                ty: const_type(value),
+                mutable: false,
+                init,
+            });
+        }
+        WasmOutput { module, skipped: self.skipped }
+    }
+
+    // The blocks of the function nest following its dominator tree: a block
+    // reached from several others comes right after a wasm block its
+    // predecessors branch out of, the other blocks are generated where their
+    // only predecessor branches to them. A block jumped back to starts a wasm
+    // loop holding the blocks it dominates.
+    fn function(&self, index: u32) -> WasmFunc {
+        let f = &self.ir.funcs[index as usize];
+        let params: Vec<ValType> = f.params.iter().map(|p| val_type(f.ty(*p))).collect();
+        let result = val_type(&f.ret);
+        let idom = f.dominators();
+        let mut children = vec![vec![]; f.blocks.len()];
+        for block in f.reverse_postorder() {
+            match idom[block.0 as usize] {
+                Some(parent) if parent != block => children[parent.0 as usize].push(block),
+                _ => {}
+            }
+        }
+        let preds = f.predecessors();
+        let back = |block: usize, pred: &BlockId| dominates(&idom, BlockId(block as u32), *pred);
+        let mut s = FnState {
+            f,
+            index,
+            locals: params.clone(),
+            params: params.len() as u32,
+            slots: vec![None; f.values.len()],
+            stacked: f.stacked_values(),
+            uses: f.use_counts(),
+            merges: preds.iter().enumerate().map(|(b, preds)| preds.iter().filter(|p| !back(b, p)).count() > 1).collect(),
+            headers: preds.iter().enumerate().map(|(b, preds)| preds.iter().any(|p| back(b, p))).collect(),
+            idom: idom.clone(),
+            children,
+            labels: vec![],
+            code: vec![],
+        };
+        for (i, param) in f.params.iter().enumerate() {
+            s.slots[param.0 as usize] = Some(i as u32);
+        }
+        // Counts the call depth like the other backends.
+        s.emit(Instr::GlobalGet(DEPTH));
+        s.emit(Instr::I32Const(1));
+        s.emit(Instr::Op(I32_ADD));
+        s.emit(Instr::GlobalSet(DEPTH));
+        s.emit(Instr::GlobalGet(DEPTH));
+        s.emit(Instr::I32Const(MAX_DEPTH as i32));
+        s.emit(Instr::Op(I32_GT_S));
+        s.emit(Instr::If(None));
+        s.panic(STACK);
+        s.emit(Instr::End);
+        let loops = f.blocks.iter().any(|b| matches!(b.term, Term::TailCall(func, _) if func == index));
+        s.open(Instr::Block(Some(result)), Label::Exit);
+        if loops {
+            s.open(Instr::Loop(None), Label::Loop);
+        }
+        self.tree(&mut s, BlockId(0));
+        if loops {
+            s.close();
+            s.emit(Instr::Unreachable);
+        }
+        s.close();
+        s.emit(Instr::GlobalGet(DEPTH));
+        s.emit(Instr::I32Const(1));
+        s.emit(Instr::Op(I32_SUB));
+        s.emit(Instr::GlobalSet(DEPTH));
+        WasmFunc {
+            name: self.ir.func_name(index),
+            export: None,
+            params,
+            result: Some(result),
+            locals: s.locals.split_off(s.params as usize),
+            body: s.code,
+        }
+    }
+
+    // Code of `block` and the blocks it dominates, in a wasm loop when
+    // some of them jump back to it.
+    fn tree(&self, s: &mut FnState, block: BlockId) {
+        let merges: Vec<BlockId> = s.children[block.0 as usize].iter().filter(|b| s.merges[b.0 as usize]).copied().collect();
+        if s.headers[block.0 as usize] {
+            s.open(Instr::Loop(None), Label::Header(block));
+            self.within(s, block, &merges);
+            s.close();
+            // Nothing falls out of the loop.
+            s.emit(Instr::Unreachable);
+        } else {
+            self.within(s, block, &merges);
+        }
+    }
+
+    // The last merge block comes after the outermost wasm block.
+    fn within(&self, s: &mut FnState, block: BlockId, merges: &[BlockId]) {
+        match merges.split_last() {
+            Some((last, rest)) => {
+                s.open(Instr::Block(None), Label::Before(*last));
+                self.within(s, block, rest);
+                s.close();
+                self.tree(s, *last);
+            }
+            None => self.block(s, block),
+        }
+    }
+
+    fn block(&self, s: &mut FnState, id: BlockId) {
+        let f = s.f;
+        let block = f.block(id);
+        for value in &block.insts {
+            if s.stacked[value.0 as usize] {
+                continue;
+            }
+            self.value(s, *value);
+            if s.uses[value.0 as usize] > 0 {
+                let slot = s.slot(*value);
+                s.emit(Instr::LocalSet(slot));
+            } else {
+                s.emit(Instr::Drop);
+            }
+        }
+        match &block.term {
+            Term::Jump(target) => self.branch(s, id, *target),
+            Term::Branch(cond, then_block, else_block) => {
+                self.operand(s, *cond);
+                s.open(Instr::If(None), Label::If);
+                self.branch(s, id, *then_block);
+                s.emit(Instr::Else);
+                self.branch(s, id, *else_block);
+                s.close();
+                // Both branches leave, but the function's block wants its
+                // result.
+                if s.labels.last() == Some(&Label::Exit) {
+                    s.emit(Instr::Unreachable);
+                }
+            }
+            Term::Return(value) => {
+                self.operand(s, *value);
+                self.ret(s);
+            }
+            // Calls of the function to itself set the parameters, last
+            // first, and jump back to the start.
+            Term::TailCall(func, args) if *func == s.index => {
+                for arg in args {
+                    self.operand(s, *arg);
+                }
+                for param in (0..s.params).rev() {
+                    s.emit(Instr::LocalSet(param));
+                }
+                s.br(Label::Loop);
+            }
+            Term::TailCall(func, args) => {
+                self.call(s, *func, args);
+                self.ret(s);
+            }
+            Term::MatchFail | Term::Unreachable => s.panic(NO_MATCH),
+            Term::TailCallValue(_, _) => unreachable!("function value in WebAssembly"),
+        }
+    }
+
+    // The result is the last value of the function's block.
+    fn ret(&self, s: &mut FnState) {
+        if s.labels.len() > 1 {
+            s.br(Label::Exit);
+        }
+    }
+
+    fn branch(&self, s: &mut FnState, from: BlockId, to: BlockId) {
+        let f = s.f;
+        let phis = &f.block(to).phis;
+        for phi in phis {
+            if let Def::Phi(incoming) = f.def(*phi) {
+                let (_, value) = incoming.iter().find(|(b, _)| *b == from).unwrap();
+                let slot = s.slot(*value);
+                s.emit(Instr::LocalGet(slot));
+            }
+        }
+        for phi in phis.iter().rev() {
+            let slot = s.slot(*phi);
+            s.emit(Instr::LocalSet(slot));
+        }
+        if s.headers[to.0 as usize] && dominates(&s.idom, to, from) {
+            s.br(Label::Header(to));
+        } else if s.merges[to.0 as usize] {
+            s.br(Label::Before(to));
+        } else {
+            self.tree(s, to);
+        }
+    }
+
+    fn operand(&self, s: &mut FnState, value: ValueId) {
+        if s.stacked[value.0 as usize] {
+            self.value(s, value);
+        } else {
+            let slot = s.slot(value);
+            s.emit(Instr::LocalGet(slot));
+        }
+    }
+
+    // Leaves the value of an instruction on the stack.
+    fn value(&self, s: &mut FnState, value: ValueId) {
+        let f = s.f;
+        let ty = f.ty(value);
+        match f.inst(value).unwrap() {
+            Inst::Const(value) => self.const_value(s, value),
+            Inst::Unary(op, operand) => {
+                self.operand(s, *operand);
+                match op {
+                    UnaryOp::Neg if *ty == Ty::Float => s.emit(Instr::Op(F64_NEG)),
+                    UnaryOp::Neg => s.emit(Instr::Call(NEG)),
+                    UnaryOp::Not => s.emit(Instr::Op(I32_EQZ)),
+                    UnaryOp::BitNot => {
+                        s.emit(Instr::I64Const(-1));
+                        s.emit(Instr::Op(I64_XOR));
+                    }
+                }
+            }
+            Inst::Binary(op, l, r) => {
+                self.operand(s, *l);
+                self.operand(s, *r);
+                for instr in binary(*op, f.ty(*l)) {
+                    s.emit(instr);
+                }
+            }
+            Inst::Call(func, args) => self.call(s, *func, args),
+            Inst::Tuple(elems) if elems.is_empty() => s.emit(Instr::I32Const(0)),
+            Inst::Tuple(elems) | Inst::Struct(_, elems) => self.construct(s, elems, None),
+            Inst::Variant(_, index, elems) => self.construct(s, elems, Some(*index)),
+            Inst::Field(operand, index) => {
+                let first = matches!(f.ty(*operand), Ty::Sum(_, _)) as u32;
+                self.operand(s, *operand);
+                s.emit(Instr::Load(val_type(ty), 8 * (first + index)));
+            }
+            Inst::IsVariant(operand, index) => {
+                self.operand(s, *operand);
+                s.emit(Instr::Load(ValType::I32, 0));
+                s.emit(Instr::I32Const(*index as i32));
+                s.emit(Instr::Op(I32_EQ));
+            }
+            inst => unreachable!("{:?} in WebAssembly", inst),
+        }
+    }
+
+    fn call(&self, s: &mut FnState, func: u32, args: &[ValueId]) {
+        let callee = &self.ir.funcs[func as usize];
+        if callee.kind == FuncKind::Ctor {
+            let index = callee.values.iter().find_map(|value| match value.def {
+                Def::Inst(Inst::Variant(_, index, _)) => Some(index),
+                _ => None,
+            });
+            return self.construct(s, args, index);
+        }
+        for arg in args {
+            self.operand(s, *arg);
+        }
+        s.emit(Instr::Call(self.indices[&func]));
+    }
+
+    // Allocates an object of `slots` slots, storing the tag of variants,
+    // in a new local. The caller stores the fields.
+    fn alloc(&self, s: &mut FnState, slots: usize, tag: Option<u32>) -> u32 {
+        let local = s.local(ValType::I32);
+        s.emit(Instr::I32Const(8 * slots as i32));
+        s.emit(Instr::Call(ALLOC));
+        s.emit(Instr::LocalSet(local));
+        if let Some(tag) = tag {
+            s.emit(Instr::LocalGet(local));
+            s.emit(Instr::I32Const(tag as i32));
+            s.emit(Instr::Store(ValType::I32, 0));
+        }
+        local
+    }
+
+    fn const_value(&self, s: &mut FnState, value: &Const) {
+        let (elems, tag) = match value {
+            Const::Int(val) => return s.emit(Instr::I64Const(*val)),
+            Const::Float(val) => return s.emit(Instr::F64Const(*val)),
+            Const::Char(val) => return s.emit(Instr::I32Const(*val as i32)),
+            Const::Bool(val) => return s.emit(Instr::I32Const(*val as i32)),
+            Const::Str(_) => unreachable!("string constant in WebAssembly"),
+            Const::Tuple(elems) if elems.is_empty() => return s.emit(Instr::I32Const(0)),
+            Const::Tuple(elems) | Const::Struct(_, elems) => (elems, None),
+            Const::Variant(_, index, elems) => (elems, Some(*index)),
+        };
+        let first = tag.is_some() as usize;
+        let local = self.alloc(s, first + elems.len(), tag);
+        for (i, elem) in elems.iter().enumerate() {
+            s.emit(Instr::LocalGet(local));
+            self.const_value(s, elem);
+            s.emit(Instr::Store(const_type(elem), 8 * (first + i) as u32));
+        }
+        s.emit(Instr::LocalGet(local));
+    }
+
+    // Stores the values in a new object.
+    fn construct(&self, s: &mut FnState, values: &[ValueId], tag: Option<u32>) {
+        let first = tag.is_some() as usize;
+        let local = self.alloc(s, first + values.len(), tag);
+        for (i, value) in values.iter().enumerate() {
+            s.emit(Instr::LocalGet(local));
+            self.operand(s, *value);
+            s.emit(Instr::Store(val_type(s.f.ty(*value)), 8 * (first + i) as u32));
+        }
+        s.emit(Instr::LocalGet(local));
+    }
+}
+
+// Instructions of an operator on two values of type `operand`. Integer
+// arithmetic goes through the runtime for its checks.
+fn binary(op: BinaryOp, operand: &Ty) -> Vec<Instr> {
+    use Instr::*;
+    let (lt, gt, le, ge, eq, ne) = match operand {
+        Ty::Int => (I64_LT_S, I64_GT_S, I64_LE_S, I64_GE_S, I64_EQ, I64_NE),
+        // Chars and bools are unsigned, floats compare like the
+        // interpreter, which negates `<` for `<=` and `>=`.
+        Ty::Float => (F64_LT, F64_GT, 0, 0, F64_EQ, F64_NE),
+        _ => (I32_LT_U, I32_GT_U, I32_LE_U, I32_GE_U, I32_EQ, I32_NE),
+    };
+    let float = *operand == Ty::Float;
+    match op {
+        BinaryOp::Add if float => vec![Op(F64_ADD)],
+        BinaryOp::Sub if float => vec![Op(F64_SUB)],
+        BinaryOp::Mul if float => vec![Op(F64_MUL)],
+        BinaryOp::Div if float => vec![Call(FDIV)],
+        BinaryOp::Add => vec![Call(ADD)],
+        BinaryOp::Sub => vec![Call(SUB)],
+        BinaryOp::Mul => vec![Call(MUL)],
+        BinaryOp::Div => vec![Call(DIV)],
+        BinaryOp::Mod => vec![Call(REM)],
+        BinaryOp::Shl => vec![Call(SHL)],
+        BinaryOp::Shr => vec![Call(SHR)],
+        BinaryOp::BitAnd => vec![Op(I64_AND)],
+        BinaryOp::BitOr => vec![Op(I64_OR)],
+        BinaryOp::BitXor => vec![Op(I64_XOR)],
+        BinaryOp::Eq => vec![Op(eq)],
+        BinaryOp::NotEq => vec![Op(ne)],
+        BinaryOp::Lt => vec![Op(lt)],
+        BinaryOp::Gt => vec![Op(gt)],
+        BinaryOp::LtEq if float => vec![Op(F64_GT), Op(I32_EQZ)],
+        BinaryOp::GtEq if float => vec![Op(F64_LT), Op(I32_EQZ)],
+        BinaryOp::LtEq => vec![Op(le)],
+        BinaryOp::GtEq => vec![Op(ge)],
+        BinaryOp::And | BinaryOp::Or => unreachable!("short circuit operators are branches"),
+    }
+}
+
+pub fn compile_wasm_ir(program: &ir::Program) -> WasmOutput {
+    WasmGen::new(program).generate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lower::lower_program;
+    use crate::modules::check_test_program;
+    use crate::opt::{optimize, OptLevel};
+    use crate::wasm_run::{decode, Instance, Val};
+
+    fn lower(src: &str, foo: &str) -> ir::Program {
+        let (graph, checked, interner) = check_test_program(src, foo);
+        lower_program(&graph, &checked, &interner)
+    }
+
+    fn compile(src: &str, foo: &str) -> WasmOutput {
+        compile_wasm_ir(&lower(src, foo))
+    }
+
+    // Runs the module from its binary encoding. Errors are the message of the
+    // runtime error the module trapped with.
+    fn instantiate(output: &WasmOutput) -> Instance {
+        Instance::new(decode(&output.module.encode()).unwrap())
+    }
+
+    fn call(instance: &mut Instance, name: &str, args: &[Val]) -> Result<Val, String> {
+        let result = match instance.call(name, args) {
+            Ok(result) => Ok(result.unwrap()),
+            Err(trap) => match instance.global("sp_error") {
+                Some(Val::I32(code)) if code > 0 => Err(String::from(ERRORS[code as usize - 1])),
+                _ => Err(trap),
+            },
+        };
+        println!("{} {:?}", name, result);
+        result
+    }
+
+    #[test]
+    fn test_scalars() {
+        let foo = "
+is_vowel c: char -> bool
+    case c of
+        'a' -> true
+        'e' -> true
+        'i' -> true
+        'o' -> true
+        'u' -> true
+        _ -> false
+
+export =
+    is_vowel
+";
+        let src = "
+import foo::is_vowel
+
+fact n: int -> int
+    if n == 0
+        1
+    else
+        n * fact n-1
+
+count n, acc: int -> int
+    if n == 0
+        acc
+    else
+        count (n - 1) (acc + 1)
+
+gcd a, b: int -> int
+    if b == 0
+        a
+    else
+        gcd b (a % b)
+
+fib n: int -> int
+    case n of
+        0 -> 0
+        1 -> 1
+        _ -> fib (n - 1) + fib (n - 2)
+
+bits a, b: int -> int
+    (a ^ b) + (a >> 2) + (1 << 4) + ~0 - (a & b | 1)
+
+mean x, y: float -> float
+    (x + y) / 2.0 - -0.5
+
+at_most x, y: float -> bool
+    x <= y
+
+vowel c: char -> bool
+    is_vowel c && c > 'b' || !(c != 'a')
+
+five -> int
+    5
+
+export =
+    fact
+    count
+    gcd
+    fib
+    bits
+    mean
+    at_most
+    vowel
+    five
+";
+        let output = compile(src, foo);
+        assert!(output.skipped.is_empty());
+        let mut instance = instantiate(&output);
+        let mut call = |name: &str, args: &[Val]| call(&mut instance, name, args).unwrap();
+        assert!(call("fact", &[Val::I64(20)]) == Val::I64(2432902008176640000));
+        assert!(call("count", &[Val::I64(1000000), Val::I64(0)]) == Val::I64(1000000));
+        assert!(call("gcd", &[Val::I64(1071), Val::I64(462)]) == Val::I64(21));
+        assert!(call("fib", &[Val::I64(20)]) == Val::I64(6765));
+        assert!(call("bits", &[Val::I64(12), Val::I64(10)]) == Val::I64(6 + 3 + 16 - 1 - 9));
+        assert!(call("mean", &[Val::F64(1.0), Val::F64(2.0)]) == Val::F64(2.0));
+        assert!(call("at_most", &[Val::F64(1.0), Val::F64(1.0)]) == Val::I32(1));
+        assert!(call("at_most", &[Val::F64(2.0), Val::F64(1.0)]) == Val::I32(0));
+        assert!(call("vowel", &[Val::I32('e' as i32)]) == Val::I32(1));
+        assert!(call("vowel", &[Val::I32('a' as i32)]) == Val::I32(1));
+        assert!(call("vowel", &[Val::I32('x' as i32)]) == Val::I32(0));
+        assert!(call("five", &[]) == Val::I64(5));
+    }
+
+    #[test]
+    fn test_data() {
+        let src = "
+struct Point =
+    x: float
+    y: float
+
+type Shape = Circle: Point, float | Square: Point, float | Dot
+
+type Ints = Empty | More: int, Ints
+
+const ORIGIN = Point {0.0, 0.0}
+const UNIT = Square ORIGIN 1.0
+const LIMIT = 42
+
+area s: Shape -> float
+    case s of
+        Circle _ r -> 3.0 * r * r
+        Square p side -> side * side + x_of p
+        Dot -> 0.0
+
+shapes n: int -> float
+    let c = Circle (Point {1.0, 2.0}) 1.0, p = Point {y = 5.0, x = 2.0} in
+    area c + area (Square p 2.0) + area UNIT + area Dot + p.y - float_of n
+
+x_of {x = x}: Point -> float
+    x
+
+float_of n: int -> float
+    if n == 0
+        0.0
+    else
+        1.0 + float_of (n - 1)
+
+build n: int -> Ints
+    if n == 0
+        Empty
+    else
+        More n (build (n - 1))
+
+total xs: Ints -> int
+    case xs of
+        Empty -> 0
+        More 1 rest -> 100 + total rest
+        More x rest -> x + total rest
+
+swap (a, b): (int, bool) -> (bool, int)
+    (b, a)
+
+pairs n: int -> int
+    let (flag, m) = swap (n, n > 2) in
+    if flag
+        m
+    else
+        -m
+
+export =
+    shapes
+    build
+    total
+    pairs
+    LIMIT
+    ORIGIN
+";
+        let output = compile(src, "");
+        assert!(output.skipped.is_empty());
+        let mut instance = instantiate(&output);
+        assert!(instance.global("LIMIT") == Some(Val::I64(42)));
+        assert!(instance.global("ORIGIN").is_none());
+        let result = call(&mut instance, "shapes", &[Val::I64(3)]);
+        assert!(result == Ok(Val::F64(3.0 + 6.0 + 1.0 + 0.0 + 5.0 - 3.0)));
+        let list = call(&mut instance, "build", &[Val::I64(10)]).unwrap();
+        assert!(call(&mut instance, "total", &[list]) == Ok(Val::I64(154)));
+        assert!(call(&mut instance, "pairs", &[Val::I64(7)]) == Ok(Val::I64(7)));
+        assert!(call(&mut instance, "pairs", &[Val::I64(1)]) == Ok(Val::I64(-1)));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+div x, y: int -> int
+    x / y
+
+fdiv x, y: float -> float
+    x / y
+
+neg x: int -> int
+    -x
+
+shift x, y: int -> int
+    x << y
+
+deep n: int -> int
+    1 + deep (n + 1)
+
+export =
+    div
+    fdiv
+    neg
+    shift
+    deep
+";
+        let output = compile(src, "");
+        let mut instance = instantiate(&output);
+        assert!(call(&mut instance, "div", &[Val::I64(7), Val::I64(2)]) == Ok(Val::I64(3)));
+        let cases = [
+            ("div", [Val::I64(1), Val::I64(0)], "Division by zero"),
+            ("div", [Val::I64(i64::MIN), Val::I64(-1)], "Integer overflow"),
+            ("fdiv", [Val::F64(1.0), Val::F64(0.0)], "Division by zero"),
+            ("shift", [Val::I64(1), Val::I64(64)], "Integer overflow"),
+        ];
+        for (name, args, message) in cases.iter() {
+            let mut instance = instantiate(&output);
+            assert!(call(&mut instance, name, args) == Err(String::from(*message)));
+        }
+        let mut instance = instantiate(&output);
+        assert!(call(&mut instance, "neg", &[Val::I64(i64::MIN)]) == Err(String::from("Integer overflow")));
+        let mut instance = instantiate(&output);
+        assert!(call(&mut instance, "deep", &[Val::I64(0)]) == Err(String::from("Stack overflow: too many nested calls")));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let src = "
+inc n: int -> int
+    n + 1
+
+export =
+    inc
+";
+        let output = compile(src, "");
+        let bytes = output.module.encode();
+        assert!(bytes[..8] == *b"\0asm\x01\0\0\0");
+        // Decoding gives back the same functions, but for their names.
+        let decoded = decode(&bytes).unwrap();
+        assert!(decoded.funcs.len() == output.module.funcs.len());
+        for (func, original) in decoded.funcs.iter().zip(&output.module.funcs) {
+            assert!(func.params == original.params && func.result == original.result);
+            assert!(func.locals == original.locals && func.body == original.body);
+            assert!(func.export == original.export);
+        }
+        let text = output.module.to_text();
+        println!("{}", text);
+        assert!(text.starts_with("(module\n  (memory (export \"memory\") 1)\n"));
+        assert!(text.contains("  (global $sp_error (export \"sp_error\") (mut i32) (i32.const 0))\n"));
+        let inc = "  (func $main::inc (export \"inc\") (param i64) (result i64)\n";
+        assert!(text.contains(inc));
+        assert!(text.contains("    block (result i64)\n      local.get 0\n      i64.const 1\n      call $sp_add\n    end\n"));
+    }
+
+    #[test]
+    fn test_skipped_functions() {
+        let src = "
+area r: float -> float
+    r * r * 3.0
+
+len s: string -> int
+    0
+
+size s: string -> int
+    len s
+
+apply f: (int -> int) x: int -> int
+    f x
+
+square x: int -> int
+    apply (\\y -> y * y) x
+
+same a, b: (int, int) -> bool
+    a == b
+
+rem x, y: float -> float
+    x % y
+
+wrap x: float -> float
+    rem x 1.0
+";
+        let output = compile(src, "");
+        let mut skipped: Vec<(String, Vec<String>)> = output
+            .skipped
+            .iter()
+            .map(|(_, diag)| (diag.msg.clone(), diag.notes.clone()))
+            .collect();
+        skipped.sort();
+        let expected = [
+            ("apply", "uses values of type int -> int"),
+            ("len", "uses values of type string"),
+            ("rem", "takes the remainder of floats"),
+            ("same", "compares structured values"),
+            ("size", "uses values of type string"),
+            ("square", "uses values of type int -> int"),
+            ("wrap", "calls 'rem', which isn't compiled"),
+        ];
+        assert!(skipped.len() == expected.len());
+        for ((message, notes), (name, reason)) in skipped.iter().zip(expected.iter()) {
+            assert!(*message == format!("Function '{}' can't be compiled to WebAssembly", name));
+            assert!(*notes == vec![format!("it {}", reason)]);
+        }
+        assert!(output.module.funcs.iter().any(|f| f.name == "main::area"));
+    }
+
+    #[test]
+    fn test_loops() {
+        // Optimised functions jump back to the blocks heading their loops.
+        let src = "
+fact n: int -> int
+    if n == 0
+        1
+    else
+        n * fact n-1
+
+next n: int -> int
+    if n % 2 == 0
+        n / 2
+    else
+        3 * n + 1
+
+steps n, acc: int -> int
+    if n == 1
+        acc
+    else
+        steps (next n) (acc + 1)
+
+export =
+    fact
+    steps
+";
+        let mut program = lower(src, "");
+        optimize(&mut program, OptLevel::O2, &mut |_, _| {});
+        let output = compile_wasm_ir(&program);
+        println!("{}", output.module.to_text());
+        assert!(output.skipped.is_empty());
+        let mut instance = instantiate(&output);
+        let mut call = |name: &str, args: &[Val]| call(&mut instance, name, args);
+        assert!(call("fact", &[Val::I64(20)]) == Ok(Val::I64(2432902008176640000)));
+        assert!(call("fact", &[Val::I64(21)]) == Err(String::from("Integer overflow")));
+        assert!(call("steps", &[Val::I64(27), Val::I64(0)]) == Ok(Val::I64(111)));
+    }
+}
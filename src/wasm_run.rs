@@ -0,0 +1,545 @@
+use std::collections::HashMap;
+
+use crate::wasm::*;
+
+// A small WebAssembly interpreter for the tests of the backend. It decodes
+// the binary format back into instructions, so running a module also
+// checks its encoding, and only knows the instructions the backend emits.
+
+const PAGE: usize = 65536;
+// Stops runaway programs before they take all the memory of the tests.
+const MAX_PAGES: usize = 1024;
+const MAX_FRAMES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Val {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+}
+
+impl Val {
+    fn zero(ty: ValType) -> Val {
+        match ty {
+            ValType::I32 => Val::I32(0),
+            ValType::I64 => Val::I64(0),
+            ValType::F64 => Val::F64(0.0),
+        }
+    }
+
+    fn i32(self) -> i32 {
+        match self {
+            Val::I32(val) => val,
+            val => panic!("expected an i32, got {:?}", val),
+        }
+    }
+
+    fn i64(self) -> i64 {
+        match self {
+            Val::I64(val) => val,
+            val => panic!("expected an i64, got {:?}", val),
+        }
+    }
+
+    fn f64(self) -> f64 {
+        match self {
+            Val::F64(val) => val,
+            val => panic!("expected an f64, got {:?}", val),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("unexpected end of module")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let (mut val, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let (mut val, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.byte()?;
+            val |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.uleb()? as u32)
+    }
+
+    fn val_type(&mut self) -> Result<ValType, String> {
+        let byte = self.byte()?;
+        ValType::from_byte(byte).ok_or_else(|| format!("unknown value type 0x{:02x}", byte))
+    }
+
+    fn block_type(&mut self) -> Result<Option<ValType>, String> {
+        if self.bytes.get(self.pos) == Some(&0x40) {
+            self.pos += 1;
+            return Ok(None);
+        }
+        self.val_type().map(Some)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.uleb()? as usize;
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or("unexpected end of module")?;
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("invalid name"))
+    }
+
+    fn instr(&mut self) -> Result<Instr, String> {
+        let op = self.byte()?;
+        let load = |ty, r: &mut Reader| -> Result<(ValType, u32), String> {
+            r.uleb()?;
+            Ok((ty, r.u32()?))
+        };
+        Ok(match op {
+            0x00 => Instr::Unreachable,
+            0x02 => Instr::Block(self.block_type()?),
+            0x03 => Instr::Loop(self.block_type()?),
+            0x04 => Instr::If(self.block_type()?),
+            0x05 => Instr::Else,
+            0x0b => Instr::End,
+            0x0c => Instr::Br(self.u32()?),
+            0x10 => Instr::Call(self.u32()?),
+            0x1a => Instr::Drop,
+            0x20 => Instr::LocalGet(self.u32()?),
+            0x21 => Instr::LocalSet(self.u32()?),
+            0x23 => Instr::GlobalGet(self.u32()?),
+            0x24 => Instr::GlobalSet(self.u32()?),
+            0x28 | 0x29 | 0x2b => {
+                let ty = [ValType::I32, ValType::I64, ValType::F64][[0x28, 0x29, 0x2b].iter().position(|b| *b == op).unwrap()];
+                let (ty, offset) = load(ty, self)?;
+                Instr::Load(ty, offset)
+            }
+            0x36 | 0x37 | 0x39 => {
+                let ty = [ValType::I32, ValType::I64, ValType::F64][[0x36, 0x37, 0x39].iter().position(|b| *b == op).unwrap()];
+                let (ty, offset) = load(ty, self)?;
+                Instr::Store(ty, offset)
+            }
+            0x3f => {
+                self.byte()?;
+                Instr::MemorySize
+            }
+            0x40 => {
+                self.byte()?;
+                Instr::MemoryGrow
+            }
+            0x41 => Instr::I32Const(self.sleb()? as i32),
+            0x42 => Instr::I64Const(self.sleb()?),
+            0x44 => {
+                let bytes = self.bytes.get(self.pos..self.pos + 8).ok_or("unexpected end of module")?;
+                self.pos += 8;
+                let mut raw = [0; 8];
+                raw.copy_from_slice(bytes);
+                Instr::F64Const(f64::from_le_bytes(raw))
+            }
+            op if OPS.iter().any(|(code, _)| *code == op) => Instr::Op(op),
+            op => return Err(format!("unknown opcode 0x{:02x}", op)),
+        })
+    }
+
+    // Instructions up to the `end` closing the expression, left out.
+    fn expr(&mut self) -> Result<Vec<Instr>, String> {
+        let mut body = vec![];
+        let mut depth = 0;
+        loop {
+            let instr = self.instr()?;
+            match instr {
+                Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => depth += 1,
+                Instr::End if depth == 0 => return Ok(body),
+                Instr::End => depth -= 1,
+                _ => {}
+            }
+            body.push(instr);
+        }
+    }
+}
+
+// Decodes a module written by `WasmModule::encode`. Names are lost, exported
+// functions and globals get their export name.
+pub fn decode(bytes: &[u8]) -> Result<WasmModule, String> {
+    if bytes.len() < 8 || bytes[..4] != *b"\0asm" || bytes[4..8] != [1, 0, 0, 0] {
+        return Err(String::from("not a WebAssembly module"));
+    }
+    let mut r = Reader { bytes, pos: 8 };
+    let mut sigs = vec![];
+    let mut module = WasmModule::default();
+    while r.pos < bytes.len() {
+        let id = r.byte()?;
+        let size = r.uleb()? as usize;
+        let end = r.pos + size;
+        match id {
+            1 => {
+                for _ in 0..r.uleb()? {
+                    if r.byte()? != 0x60 {
+                        return Err(String::from("expected a function type"));
+                    }
+                    let params = (0..r.uleb()?).map(|_| r.val_type()).collect::<Result<Vec<_>, _>>()?;
+                    let results = (0..r.uleb()?).map(|_| r.val_type()).collect::<Result<Vec<_>, _>>()?;
+                    sigs.push((params, results.first().copied()));
+                }
+            }
+            3 => {
+                for i in 0..r.uleb()? {
+                    let (params, result) = sigs.get(r.uleb()? as usize).cloned().ok_or("unknown type index")?;
+                    module.funcs.push(WasmFunc {
+                        name: format!("f{}", i),
+                        export: None,
+                        params,
+                        result,
+                        locals: vec![],
+                        body: vec![],
+                    });
+                }
+            }
+            6 => {
+                for i in 0..r.uleb()? {
+                    let ty = r.val_type()?;
+                    let mutable = r.byte()? == 1;
+                    let init = r.expr()?;
+                    if init.len() != 1 {
+                        return Err(String::from("global initializers are a single constant"));
+                    }
+                    module.globals.push(WasmGlobal { name: format!("g{}", i), export: None, ty, mutable, init: init[0].clone() });
+                }
+            }
+            7 => {
+                for _ in 0..r.uleb()? {
+                    let name = r.name()?;
+                    let kind = r.byte()?;
+                    let index = r.uleb()? as usize;
+                    match kind {
+                        0 => module.funcs[index].export = Some(name),
+                        3 => module.globals[index].export = Some(name),
+                        _ => {}
+                    }
+                }
+            }
+            10 => {
+                for i in 0..r.uleb()? as usize {
+                    let size = r.uleb()? as usize;
+                    let func_end = r.pos + size;
+                    let func = module.funcs.get_mut(i).ok_or("more bodies than functions")?;
+                    for _ in 0..r.uleb()? {
+                        let count = r.uleb()?;
+                        let ty = r.val_type()?;
+                        func.locals.extend((0..count).map(|_| ty));
+                    }
+                    func.body = r.expr()?;
+                    if r.pos != func_end {
+                        return Err(format!("size mismatch in the body of function {}", i));
+                    }
+                }
+            }
+            // The memory is always a single growable one.
+            _ => r.pos = end,
+        }
+        if r.pos != end {
+            return Err(format!("size mismatch in section {}", id));
+        }
+    }
+    Ok(module)
+}
+
+struct Label {
+    // Where `br` goes: after the `end` of blocks, back into loops.
+    target: usize,
+    is_loop: bool,
+    arity: usize,
+    height: usize,
+}
+
+struct Frame {
+    func: usize,
+    pc: usize,
+    locals: Vec<Val>,
+    labels: Vec<Label>,
+    height: usize,
+}
+
+pub struct Instance {
+    module: WasmModule,
+    // Position of the matching `end` of every block, of the `else` of ifs
+    // and of the `end` after every `else`.
+    ends: Vec<HashMap<usize, usize>>,
+    elses: Vec<HashMap<usize, usize>>,
+    skips: Vec<HashMap<usize, usize>>,
+    pub globals: Vec<Val>,
+    pub memory: Vec<u8>,
+}
+
+fn const_val(instr: &Instr) -> Val {
+    match instr {
+        Instr::I32Const(val) => Val::I32(*val),
+        Instr::I64Const(val) => Val::I64(*val),
+        Instr::F64Const(val) => Val::F64(*val),
+        instr => panic!("{:?} is not a constant", instr),
+    }
+}
+
+impl Instance {
+    pub fn new(module: WasmModule) -> Instance {
+        let mut ends = vec![];
+        let mut elses = vec![];
+        let mut skips = vec![];
+        for func in &module.funcs {
+            let (mut func_ends, mut func_elses, mut func_skips) = (HashMap::new(), HashMap::new(), HashMap::new());
+            let mut open = vec![];
+            for (pc, instr) in func.body.iter().enumerate() {
+                match instr {
+                    Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => open.push(pc),
+                    Instr::Else => {
+                        func_elses.insert(*open.last().unwrap(), pc);
+                    }
+                    Instr::End => {
+                        let start = open.pop().unwrap();
+                        func_ends.insert(start, pc);
+                        if let Some(at) = func_elses.get(&start) {
+                            func_skips.insert(*at, pc);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            ends.push(func_ends);
+            elses.push(func_elses);
+            skips.push(func_skips);
+        }
+        let globals = module.globals.iter().map(|g| const_val(&g.init)).collect();
+        Instance { module, ends, elses, skips, globals, memory: vec![0; PAGE] }
+    }
+
+    pub fn global(&self, name: &str) -> Option<Val> {
+        let index = self.module.globals.iter().position(|g| g.export.as_deref() == Some(name))?;
+        Some(self.globals[index])
+    }
+
+    fn address(&self, base: i32, offset: u32, size: usize) -> Result<usize, String> {
+        let at = base as u32 as usize + offset as usize;
+        if at + size > self.memory.len() {
+            return Err(String::from("out of bounds memory access"));
+        }
+        Ok(at)
+    }
+
+    fn frame(&self, func: usize, args: Vec<Val>, height: usize) -> Frame {
+        let mut locals = args;
+        locals.extend(self.module.funcs[func].locals.iter().map(|ty| Val::zero(*ty)));
+        Frame { func, pc: 0, locals, labels: vec![], height }
+    }
+
+    // Calls an exported function, traps are errors.
+    pub fn call(&mut self, name: &str, args: &[Val]) -> Result<Option<Val>, String> {
+        let func = match self.module.funcs.iter().position(|f| f.export.as_deref() == Some(name)) {
+            Some(func) => func,
+            None => return Err(format!("no function '{}' is exported", name)),
+        };
+        let mut stack: Vec<Val> = vec![];
+        let mut frames = vec![self.frame(func, args.to_vec(), 0)];
+        loop {
+            let frame = frames.last_mut().unwrap();
+            let func = &self.module.funcs[frame.func];
+            if frame.pc == func.body.len() {
+                // Returns from the function.
+                let result = if func.result.is_some() { stack.pop() } else { None };
+                stack.truncate(frame.height);
+                frames.pop();
+                if frames.is_empty() {
+                    return Ok(result);
+                }
+                stack.extend(result);
+                continue;
+            }
+            let instr = &func.body[frame.pc];
+            frame.pc += 1;
+            let pc = frame.pc - 1;
+            let mut branch = None;
+            let mut call = None;
+            match instr {
+                Instr::Unreachable => return Err(String::from("unreachable")),
+                Instr::Block(ty) | Instr::Loop(ty) => {
+                    let is_loop = matches!(instr, Instr::Loop(_));
+                    frame.labels.push(Label {
+                        target: if is_loop { pc + 1 } else { self.ends[frame.func][&pc] + 1 },
+                        is_loop,
+                        arity: if is_loop { 0 } else { ty.is_some() as usize },
+                        height: stack.len(),
+                    });
+                }
+                Instr::If(ty) => {
+                    let end = self.ends[frame.func][&pc];
+                    let cond = stack.pop().unwrap().i32();
+                    frame.labels.push(Label { target: end + 1, is_loop: false, arity: ty.is_some() as usize, height: stack.len() });
+                    if cond == 0 {
+                        // The `end` pops the label.
+                        frame.pc = match self.elses[frame.func].get(&pc) {
+                            Some(at) => at + 1,
+                            None => end,
+                        };
+                    }
+                }
+                Instr::Else => {
+                    // The end of the then branch, skip the else one.
+                    frame.pc = self.skips[frame.func][&pc];
+                }
+                Instr::End => {
+                    frame.labels.pop();
+                }
+                Instr::Br(depth) => branch = Some(*depth),
+                Instr::Call(callee) => call = Some(*callee as usize),
+                Instr::Drop => {
+                    stack.pop();
+                }
+                Instr::LocalGet(index) => stack.push(frame.locals[*index as usize]),
+                Instr::LocalSet(index) => frame.locals[*index as usize] = stack.pop().unwrap(),
+                Instr::GlobalGet(index) => stack.push(self.globals[*index as usize]),
+                Instr::GlobalSet(index) => self.globals[*index as usize] = stack.pop().unwrap(),
+                Instr::Load(ty, offset) => {
+                    let base = stack.pop().unwrap().i32();
+                    let size = if *ty == ValType::I32 { 4 } else { 8 };
+                    let at = self.address(base, *offset, size)?;
+                    let mut raw = [0; 8];
+                    raw[..size].copy_from_slice(&self.memory[at..at + size]);
+                    stack.push(match ty {
+                        ValType::I32 => Val::I32(u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as i32),
+                        ValType::I64 => Val::I64(i64::from_le_bytes(raw)),
+                        ValType::F64 => Val::F64(f64::from_le_bytes(raw)),
+                    });
+                }
+                Instr::Store(_, offset) => {
+                    let val = stack.pop().unwrap();
+                    let base = stack.pop().unwrap().i32();
+                    let bytes = match val {
+                        Val::I32(val) => val.to_le_bytes().to_vec(),
+                        Val::I64(val) => val.to_le_bytes().to_vec(),
+                        Val::F64(val) => val.to_le_bytes().to_vec(),
+                    };
+                    let at = self.address(base, *offset, bytes.len())?;
+                    self.memory[at..at + bytes.len()].copy_from_slice(&bytes);
+                }
+                Instr::MemorySize => stack.push(Val::I32((self.memory.len() / PAGE) as i32)),
+                Instr::MemoryGrow => {
+                    let pages = self.memory.len() / PAGE;
+                    let delta = stack.pop().unwrap().i32() as u32 as usize;
+                    if pages + delta > MAX_PAGES {
+                        stack.push(Val::I32(-1));
+                    } else {
+                        self.memory.resize((pages + delta) * PAGE, 0);
+                        stack.push(Val::I32(pages as i32));
+                    }
+                }
+                Instr::I32Const(_) | Instr::I64Const(_) | Instr::F64Const(_) => stack.push(const_val(instr)),
+                Instr::Op(op) => numeric(*op, &mut stack)?,
+            }
+            if let Some(depth) = branch {
+                let label = &frame.labels[frame.labels.len() - 1 - depth as usize];
+                let (target, is_loop, arity, height) = (label.target, label.is_loop, label.arity, label.height);
+                let results = stack.split_off(stack.len() - arity);
+                stack.truncate(height);
+                stack.extend(results);
+                // A loop keeps its label, blocks are left.
+                let keep = frame.labels.len() - depth as usize - !is_loop as usize;
+                frame.labels.truncate(keep);
+                frame.pc = target;
+            }
+            if let Some(callee) = call {
+                if frames.len() == MAX_FRAMES {
+                    return Err(String::from("call stack exhausted"));
+                }
+                let args = stack.split_off(stack.len() - self.module.funcs[callee].params.len());
+                let frame = self.frame(callee, args, stack.len());
+                frames.push(frame);
+            }
+        }
+    }
+}
+
+fn numeric(op: u8, stack: &mut Vec<Val>) -> Result<(), String> {
+    let bool = |b: bool| Val::I32(b as i32);
+    let val = match op {
+        I32_EQZ => bool(stack.pop().unwrap().i32() == 0),
+        I64_EQZ => bool(stack.pop().unwrap().i64() == 0),
+        F64_NEG => Val::F64(-stack.pop().unwrap().f64()),
+        _ => {
+            let r = stack.pop().unwrap();
+            let l = stack.pop().unwrap();
+            match op {
+                I32_EQ => bool(l.i32() == r.i32()),
+                I32_NE => bool(l.i32() != r.i32()),
+                I32_LT_U => bool((l.i32() as u32) < r.i32() as u32),
+                I32_GT_S => bool(l.i32() > r.i32()),
+                I32_GT_U => bool(l.i32() as u32 > r.i32() as u32),
+                I32_LE_U => bool(l.i32() as u32 <= r.i32() as u32),
+                I32_GE_U => bool(l.i32() as u32 >= r.i32() as u32),
+                I32_ADD => Val::I32(l.i32().wrapping_add(r.i32())),
+                I32_SUB => Val::I32(l.i32().wrapping_sub(r.i32())),
+                I32_AND => Val::I32(l.i32() & r.i32()),
+                I32_SHL => Val::I32(l.i32().wrapping_shl(r.i32() as u32)),
+                I32_SHR_U => Val::I32((l.i32() as u32).wrapping_shr(r.i32() as u32) as i32),
+                I64_EQ => bool(l.i64() == r.i64()),
+                I64_NE => bool(l.i64() != r.i64()),
+                I64_LT_S => bool(l.i64() < r.i64()),
+                I64_GT_S => bool(l.i64() > r.i64()),
+                I64_LE_S => bool(l.i64() <= r.i64()),
+                I64_GE_S => bool(l.i64() >= r.i64()),
+                I64_GE_U => bool(l.i64() as u64 >= r.i64() as u64),
+                I64_ADD => Val::I64(l.i64().wrapping_add(r.i64())),
+                I64_SUB => Val::I64(l.i64().wrapping_sub(r.i64())),
+                I64_MUL => Val::I64(l.i64().wrapping_mul(r.i64())),
+                I64_DIV_S | I64_REM_S => {
+                    let (l, r) = (l.i64(), r.i64());
+                    if r == 0 {
+                        return Err(String::from("integer divide by zero"));
+                    }
+                    if op == I64_DIV_S {
+                        Val::I64(l.checked_div(r).ok_or("integer overflow")?)
+                    } else {
+                        Val::I64(l.wrapping_rem(r))
+                    }
+                }
+                I64_AND => Val::I64(l.i64() & r.i64()),
+                I64_OR => Val::I64(l.i64() | r.i64()),
+                I64_XOR => Val::I64(l.i64() ^ r.i64()),
+                I64_SHL => Val::I64(l.i64().wrapping_shl(r.i64() as u32)),
+                I64_SHR_S => Val::I64(l.i64().wrapping_shr(r.i64() as u32)),
+                F64_EQ => bool(l.f64() == r.f64()),
+                F64_NE => bool(l.f64() != r.f64()),
+                F64_LT => bool(l.f64() < r.f64()),
+                F64_GT => bool(l.f64() > r.f64()),
+                F64_ADD => Val::F64(l.f64() + r.f64()),
+                F64_SUB => Val::F64(l.f64() - r.f64()),
+                F64_MUL => Val::F64(l.f64() * r.f64()),
+                F64_DIV => Val::F64(l.f64() / r.f64()),
+                op => return Err(format!("unknown opcode 0x{:02x}", op)),
+            }
+        }
+    };
+    stack.push(val);
+    Ok(())
+}
@@ -0,0 +1,430 @@
+// Encoder for the x86-64 instructions the native backend uses. Jumps and
+// calls go to labels, patched once the code is complete; references to
+// data are left to the object file as relocations.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    Rax = 0,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn high(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+// Registers of the first six integer arguments in the System V ABI.
+pub const ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    // `[rbp + disp]`, for stack slots.
+    Frame(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    O = 0x0,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    L = 0xc,
+    Ge = 0xd,
+    Le = 0xe,
+    G = 0xf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(pub u32);
+
+// A 32-bit field of the code referring to a symbol of the object file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloc {
+    pub offset: usize,
+    pub symbol: String,
+    pub addend: i64,
+}
+
+#[derive(Default)]
+pub struct Asm {
+    pub code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // Offsets of the rel32 fields jumping to each label.
+    fixups: Vec<(usize, Label)>,
+    pub relocs: Vec<Reloc>,
+}
+
+impl Asm {
+    pub fn new() -> Asm {
+        Asm::default()
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() as u32 - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0 as usize] = Some(self.code.len());
+    }
+
+    // Resolves the jumps, every label must be bound.
+    pub fn finish(&mut self) {
+        for (at, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0 as usize].expect("jump to an unbound label");
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn imm32(&mut self, val: i32) {
+        self.code.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.imm32(0);
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, rm: Operand, force: bool) {
+        let r = reg >= 8;
+        let b = matches!(rm, Operand::Reg(rm) if rm.high());
+        if w || r || b || force {
+            self.byte(0x40 | (w as u8) << 3 | (r as u8) << 2 | b as u8);
+        }
+    }
+
+    fn modrm(&mut self, reg: u8, rm: Operand) {
+        match rm {
+            Operand::Reg(rm) => self.byte(0xc0 | (reg & 7) << 3 | rm.low()),
+            Operand::Frame(disp) => {
+                self.byte(0x80 | (reg & 7) << 3 | Reg::Rbp.low());
+                self.imm32(disp);
+            }
+        }
+    }
+
+    // `op reg, rm` or `op rm, reg` depending on the opcode, 64-bit.
+    fn op(&mut self, opcode: &[u8], reg: u8, rm: Operand) {
+        self.rex(true, reg, rm, false);
+        self.code.extend_from_slice(opcode);
+        self.modrm(reg, rm);
+    }
+
+    pub fn mov(&mut self, dst: Operand, src: Operand) {
+        match (dst, src) {
+            (dst, Operand::Reg(src)) => self.op(&[0x89], src as u8, dst),
+            (Operand::Reg(dst), src) => self.op(&[0x8b], dst as u8, src),
+            (dst, src) => {
+                self.mov(Operand::Reg(Reg::Rax), src);
+                self.mov(dst, Operand::Reg(Reg::Rax));
+            }
+        }
+    }
+
+    pub fn mov_imm(&mut self, dst: Reg, val: i64) {
+        if val == val as i32 as i64 {
+            // Sign extended.
+            self.op(&[0xc7], 0, Operand::Reg(dst));
+            self.imm32(val as i32);
+        } else {
+            self.rex(true, 0, Operand::Reg(dst), false);
+            self.byte(0xb8 + dst.low());
+            self.code.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    // `mov r32, imm32`, zero extended.
+    pub fn mov_imm32(&mut self, dst: Reg, val: u32) {
+        self.rex(false, 0, Operand::Reg(dst), false);
+        self.byte(0xb8 + dst.low());
+        self.code.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.op(&[op as u8], src as u8, Operand::Reg(dst));
+    }
+
+    // `op dst, imm8`, sign extended.
+    pub fn alu_imm8(&mut self, op: Alu, dst: Reg, val: i8) {
+        let ext = match op {
+            Alu::Add => 0,
+            Alu::Or => 1,
+            Alu::And => 4,
+            Alu::Sub => 5,
+            Alu::Xor => 6,
+            Alu::Cmp => 7,
+        };
+        self.op(&[0x83], ext, Operand::Reg(dst));
+        self.byte(val as u8);
+    }
+
+    pub fn sub_rsp(&mut self, val: i32) {
+        self.op(&[0x81], 5, Operand::Reg(Reg::Rsp));
+        self.imm32(val);
+    }
+
+    pub fn imul(&mut self, dst: Reg, src: Reg) {
+        self.op(&[0x0f, 0xaf], dst as u8, Operand::Reg(src));
+    }
+
+    pub fn neg(&mut self, reg: Reg) {
+        self.op(&[0xf7], 3, Operand::Reg(reg));
+    }
+
+    pub fn not(&mut self, reg: Reg) {
+        self.op(&[0xf7], 2, Operand::Reg(reg));
+    }
+
+    // Signed division of rdx:rax.
+    pub fn idiv(&mut self, reg: Reg) {
+        self.op(&[0xf7], 7, Operand::Reg(reg));
+    }
+
+    pub fn cqo(&mut self) {
+        self.code.extend_from_slice(&[0x48, 0x99]);
+    }
+
+    pub fn shl_cl(&mut self, reg: Reg) {
+        self.op(&[0xd3], 4, Operand::Reg(reg));
+    }
+
+    pub fn sar_cl(&mut self, reg: Reg) {
+        self.op(&[0xd3], 7, Operand::Reg(reg));
+    }
+
+    pub fn test(&mut self, a: Reg, b: Reg) {
+        self.op(&[0x85], b as u8, Operand::Reg(a));
+    }
+
+    // Sets `reg` to 1 if the condition holds, 0 otherwise.
+    pub fn set(&mut self, cond: Cond, reg: Reg) {
+        self.rex(false, 0, Operand::Reg(reg), reg as u8 >= 4);
+        self.code.extend_from_slice(&[0x0f, 0x90 | cond as u8]);
+        self.modrm(0, Operand::Reg(reg));
+        self.movzx8(reg, reg);
+    }
+
+    // Zero extends the low byte of `src`.
+    pub fn movzx8(&mut self, dst: Reg, src: Reg) {
+        self.rex(false, dst as u8, Operand::Reg(src), src as u8 >= 4);
+        self.code.extend_from_slice(&[0x0f, 0xb6]);
+        self.modrm(dst as u8, Operand::Reg(src));
+    }
+
+    // `mov r32, r32`, clearing the upper half.
+    pub fn zext32(&mut self, reg: Reg) {
+        self.rex(false, reg as u8, Operand::Reg(reg), false);
+        self.byte(0x89);
+        self.modrm(reg as u8, Operand::Reg(reg));
+    }
+
+    pub fn push(&mut self, src: Operand) {
+        match src {
+            Operand::Reg(reg) => {
+                self.rex(false, 0, src, false);
+                self.byte(0x50 + reg.low());
+            }
+            Operand::Frame(_) => {
+                self.byte(0xff);
+                self.modrm(6, src);
+            }
+        }
+    }
+
+    pub fn pop(&mut self, dst: Operand) {
+        match dst {
+            Operand::Reg(reg) => {
+                self.rex(false, 0, dst, false);
+                self.byte(0x58 + reg.low());
+            }
+            Operand::Frame(_) => {
+                self.byte(0x8f);
+                self.modrm(0, dst);
+            }
+        }
+    }
+
+    // `lea rsp, [rbp + disp]`.
+    pub fn lea_rsp(&mut self, disp: i32) {
+        self.op(&[0x8d], Reg::Rsp as u8, Operand::Frame(disp));
+    }
+
+    // `lea reg, [rip + symbol + addend]`.
+    pub fn lea_symbol(&mut self, reg: Reg, symbol: &str, addend: i64) {
+        self.rip(&[0x8d], reg as u8, symbol, addend, 0);
+    }
+
+    // `mov reg, [rip + symbol@gottpoff]`: the offset of a thread-local
+    // variable from the thread pointer.
+    pub fn tls_offset(&mut self, reg: Reg, symbol: &str) {
+        self.rip(&[0x8b], reg as u8, symbol, 0, 0);
+    }
+
+    // `add qword fs:[base], imm8`.
+    pub fn add_tls(&mut self, base: Reg, val: i8) {
+        self.fs(&[0x83], 0, base);
+        self.byte(val as u8);
+    }
+
+    // `mov qword fs:[base], imm32`.
+    pub fn mov_tls(&mut self, base: Reg, val: i32) {
+        self.fs(&[0xc7], 0, base);
+        self.imm32(val);
+    }
+
+    // `cmp qword fs:[base], imm32`.
+    pub fn cmp_tls(&mut self, base: Reg, val: i32) {
+        self.fs(&[0x81], 7, base);
+        self.imm32(val);
+    }
+
+    // A thread-local operand at the offset in `base`, which can't need a SIB
+    // byte or a displacement.
+    fn fs(&mut self, opcode: &[u8], reg: u8, base: Reg) {
+        assert!(base.low() != Reg::Rsp.low() && base.low() != Reg::Rbp.low());
+        self.byte(0x64);
+        self.rex(true, reg, Operand::Reg(base), false);
+        self.code.extend_from_slice(opcode);
+        self.byte((reg & 7) << 3 | base.low());
+    }
+
+    // A RIP relative operand, `trailing` bytes of immediate follow it.
+    fn rip(&mut self, opcode: &[u8], reg: u8, symbol: &str, addend: i64, trailing: i64) {
+        self.rex(true, reg, Operand::Reg(Reg::Rax), false);
+        self.code.extend_from_slice(opcode);
+        self.byte((reg & 7) << 3 | 0b101);
+        // The displacement is relative to the end of the instruction.
+        self.relocs.push(Reloc {
+            offset: self.code.len(),
+            symbol: String::from(symbol),
+            addend: addend - 4 - trailing,
+        });
+        self.imm32(0);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.byte(0xe9);
+        self.rel32(label);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.code.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.rel32(label);
+    }
+
+    pub fn call(&mut self, label: Label) {
+        self.byte(0xe8);
+        self.rel32(label);
+    }
+
+    // Call of a function the linker resolves.
+    pub fn call_symbol(&mut self, symbol: &str) {
+        self.byte(0xe8);
+        self.relocs.push(Reloc {
+            offset: self.code.len(),
+            symbol: String::from(symbol),
+            addend: -4,
+        });
+        self.imm32(0);
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+
+    pub fn ud2(&mut self) {
+        self.code.extend_from_slice(&[0x0f, 0x0b]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(f: impl FnOnce(&mut Asm)) -> Vec<u8> {
+        let mut asm = Asm::new();
+        f(&mut asm);
+        asm.finish();
+        asm.code
+    }
+
+    #[test]
+    fn test_encoding() {
+        // Checked against the GNU assembler.
+        assert!(encode(|a| a.mov(Operand::Reg(Reg::Rbp), Operand::Reg(Reg::Rsp))) == [0x48, 0x89, 0xe5]);
+        assert!(encode(|a| a.mov(Operand::Reg(Reg::R12), Operand::Reg(Reg::Rdi))) == [0x49, 0x89, 0xfc]);
+        assert!(encode(|a| a.mov(Operand::Frame(-8), Operand::Reg(Reg::Rax))) == [0x48, 0x89, 0x85, 0xf8, 0xff, 0xff, 0xff]);
+        assert!(encode(|a| a.mov(Operand::Reg(Reg::R9), Operand::Frame(-16))) == [0x4c, 0x8b, 0x8d, 0xf0, 0xff, 0xff, 0xff]);
+        assert!(encode(|a| a.mov_imm(Reg::Rax, -1)) == [0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]);
+        assert!(encode(|a| a.mov_imm(Reg::R10, i64::MIN)) == [0x49, 0xba, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert!(encode(|a| a.alu(Alu::Add, Reg::Rax, Reg::Rcx)) == [0x48, 0x01, 0xc8]);
+        assert!(encode(|a| a.alu_imm8(Alu::Cmp, Reg::Rcx, -1)) == [0x48, 0x83, 0xf9, 0xff]);
+        assert!(encode(|a| a.imul(Reg::Rax, Reg::Rcx)) == [0x48, 0x0f, 0xaf, 0xc1]);
+        assert!(encode(|a| a.set(Cond::L, Reg::Rax)) == [0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0]);
+        assert!(encode(|a| a.movzx8(Reg::Rsi, Reg::Rsi)) == [0x40, 0x0f, 0xb6, 0xf6]);
+        assert!(encode(|a| a.zext32(Reg::R8)) == [0x45, 0x89, 0xc0]);
+        assert!(encode(|a| a.push(Operand::Reg(Reg::R15))) == [0x41, 0x57]);
+        assert!(encode(|a| a.pop(Operand::Frame(-24))) == [0x8f, 0x85, 0xe8, 0xff, 0xff, 0xff]);
+        assert!(encode(|a| a.shl_cl(Reg::Rax)) == [0x48, 0xd3, 0xe0]);
+        let code = encode(|a| {
+            let label = a.label();
+            a.bind(label);
+            a.cqo();
+            a.jcc(Cond::E, label);
+        });
+        assert!(code == [0x48, 0x99, 0x0f, 0x84, 0xf8, 0xff, 0xff, 0xff]);
+        let mut asm = Asm::new();
+        asm.tls_offset(Reg::R11, "depth");
+        asm.add_tls(Reg::R11, 1);
+        asm.cmp_tls(Reg::R11, 10000);
+        asm.mov_tls(Reg::R11, 0);
+        asm.lea_symbol(Reg::Rsi, ".rodata", 16);
+        asm.call_symbol("exit");
+        let code = [
+            [0x4c, 0x8b, 0x1d, 0, 0, 0, 0].as_slice(),
+            &[0x64, 0x49, 0x83, 0x03, 0x01],
+            &[0x64, 0x49, 0x81, 0x3b, 0x10, 0x27, 0, 0],
+            &[0x64, 0x49, 0xc7, 0x03, 0, 0, 0, 0],
+            &[0x48, 0x8d, 0x35, 0, 0, 0, 0],
+            &[0xe8, 0, 0, 0, 0],
+        ];
+        assert!(asm.code == code.concat());
+        let relocs = [(3, "depth", -4), (31, ".rodata", 12), (36, "exit", -4)];
+        assert!(asm.relocs.iter().map(|r| (r.offset, r.symbol.as_str(), r.addend)).eq(relocs));
+    }
+}